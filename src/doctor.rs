@@ -0,0 +1,483 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use fastembed::TextEmbedding;
+use hf_hub::{Cache, Repo};
+use itertools::Itertools;
+use miette::Result;
+use url::Url;
+
+use crate::{
+    CustomModelConfig, FuzzyConfig, McpConfig, QueryExpansionConfig, SemanticConfig,
+    TemplatesConfig, Workspace, export_download_proxy, is_remote_store, list_models,
+    load_templates, model_file_problem, model_files, model_from_str,
+    repositories::embedding::STUB_MODEL,
+    retrieve_model,
+    services::{self, Ruleset, excludes::exclusions, expansion::QueryExpansions},
+    validate_config,
+};
+
+/// Outcome of one check of the startup environment
+pub struct Check {
+    pub name: &'static str,
+    /// The pipeline can't start if the check fails
+    pub critical: bool,
+    /// Details of the passed check or the failure with its remediation in the help
+    pub result: Result<String>,
+}
+
+/// Runs every check against the config without starting the pipeline, the checks don't depend
+/// on each other, so an invalid config doesn't hide the problems of the environment
+pub async fn run_checks(config: &McpConfig) -> Vec<Check> {
+    export_download_proxy(&config.search.semantic);
+    let semantic = &config.search.semantic;
+    let workspaces = config.workspaces();
+    vec![
+        Check {
+            name: "config",
+            critical: true,
+            result: validate_config(config).map(|_| "valid".to_string()),
+        },
+        Check {
+            name: "lsp_server",
+            critical: true,
+            result: check_workspaces(&workspaces, |it| check_lsp_server(&it.fuzzy)),
+        },
+        Check {
+            name: "workspace",
+            critical: true,
+            result: check_workspaces(&workspaces, |it| check_workspace(&it.fuzzy)),
+        },
+        Check {
+            name: "exclusions",
+            critical: false,
+            result: check_workspaces(&workspaces, |it| Ok(describe_exclusions(it))),
+        },
+        Check {
+            name: "model",
+            critical: true,
+            result: check_model(semantic).await,
+        },
+        Check {
+            name: "local_models",
+            critical: false,
+            result: Ok(describe_local_models(semantic)),
+        },
+        Check {
+            name: "templates",
+            critical: true,
+            result: check_templates(&config.templates, config),
+        },
+        Check {
+            name: "rules",
+            critical: true,
+            result: check_rules(&config.rules),
+        },
+        Check {
+            name: "query_expansion",
+            critical: false,
+            result: check_query_expansion(config.query_expansion.as_ref()),
+        },
+        Check {
+            name: "lancedb_store",
+            critical: true,
+            result: check_store(
+                semantic,
+                &workspaces
+                    .iter()
+                    .map(|it| it.semantic.table.name.clone())
+                    .collect_vec(),
+            )
+            .await,
+        },
+        Check {
+            name: "log_dir",
+            critical: false,
+            result: check_log_dir(&config.log_dir, config.create_dirs),
+        },
+    ]
+}
+
+/// Runs the check for every workspace, the details are prefixed by the workspace names if
+/// several workspaces are configured. The first failure is reported
+fn check_workspaces(
+    workspaces: &[Workspace],
+    check: impl Fn(&Workspace) -> Result<String>,
+) -> Result<String> {
+    let details = workspaces
+        .iter()
+        .map(|workspace| check(workspace).map(|details| (&workspace.name, details)))
+        .collect::<Result<Vec<_>>>()?;
+    if let [(_, details)] = details.as_slice() {
+        return Ok(details.clone());
+    }
+    Ok(details
+        .into_iter()
+        .map(|(name, details)| format!("{}: {}", name, details))
+        .join(", "))
+}
+
+/// Prints the table of the checks with the remediation of the failed ones, fails if a critical
+/// check failed
+pub fn print_checks(checks: &[Check]) -> Result<()> {
+    let width = checks
+        .iter()
+        .map(|it| it.name.len())
+        .max()
+        .unwrap_or_default();
+    for check in checks {
+        match &check.result {
+            Ok(details) => println!("{:width$}  PASS  {}", check.name, details),
+            Err(e) => {
+                let status = if check.critical { "FAIL" } else { "WARN" };
+                println!("{:width$}  {}  {}", check.name, status, e);
+                if let Some(help) = e.help() {
+                    println!("{:width$}        hint: {}", "", help);
+                }
+            }
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|it| it.critical && it.result.is_err())
+        .map(|it| it.name)
+        .collect_vec();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(miette::miette!("Failed checks: {}", failed.join(", ")))
+    }
+}
+
+/// Starts the LSP server with `--version`, a server without the flag still passes if it starts
+pub fn check_lsp_server(fuzzy: &FuzzyConfig) -> Result<String> {
+    let output = Command::new(&fuzzy.lsp_server)
+        .arg("--version")
+        .output()
+        .map_err(|e| {
+            miette::miette!(
+                help = format!(
+                    "install {} or set search.fuzzy.lsp_server to the path of its binary",
+                    fuzzy.lsp_server
+                ),
+                "Failed to start LSP server {}: {}",
+                fuzzy.lsp_server,
+                e
+            )
+        })?;
+    let version = String::from_utf8_lossy(&output.stdout);
+    Ok(version
+        .lines()
+        .next()
+        .filter(|it| !it.trim().is_empty())
+        .map(|it| it.trim().to_string())
+        .unwrap_or_else(|| format!("{} started", fuzzy.lsp_server)))
+}
+
+/// Resolves the workspace URI to an existing directory
+pub fn check_workspace(fuzzy: &FuzzyConfig) -> Result<String> {
+    let help = "set search.fuzzy.workspace_uri to a file URL of the workspace or pass --workspace";
+    let url = Url::parse(&fuzzy.workspace_uri).map_err(|e| {
+        miette::miette!(
+            help = help,
+            "Invalid workspace URI {}: {}",
+            fuzzy.workspace_uri,
+            e
+        )
+    })?;
+    let path = url
+        .to_file_path()
+        .ok()
+        .filter(|_| url.scheme() == "file")
+        .ok_or_else(|| miette::miette!(help = help, "Not a file URL: {}", url))?;
+    if !path.is_dir() {
+        return Err(miette::miette!(
+            help = help,
+            "Workspace is not an existing directory: {}",
+            path.display()
+        ));
+    }
+    Ok(path.display().to_string())
+}
+
+/// Checks the files of the model, a model which is not downloaded yet passes if Hugging Face is
+/// reachable
+pub async fn check_model(semantic: &SemanticConfig) -> Result<String> {
+    if let Some(custom_model) = &semantic.custom_model {
+        return check_custom_model(custom_model);
+    }
+    if semantic.model == STUB_MODEL {
+        return Ok("stub model without files".to_string());
+    }
+    let model = model_from_str(&semantic.model)?;
+    let model_info = TextEmbedding::get_model_info(&model)
+        .map_err(|e| miette::miette!("No info of model {:?}: {}", model, e))?;
+    let files = model_files(model_info);
+    if !semantic.download_model {
+        let model_dir = semantic.models_dir.join(model.to_string());
+        let paths = files.map(|file| model_dir.join(file));
+        check_model_files(&paths)?;
+        return Ok(format!("{} in {}", semantic.model, model_dir.display()));
+    }
+    let cache_repo = Cache::new(semantic.models_dir.clone()).model(model.to_string());
+    let cache_dir = semantic
+        .models_dir
+        .join(Repo::model(model.to_string()).folder_name());
+    let (paths, missing): (Vec<_>, Vec<_>) = files
+        .iter()
+        .map(|file| cache_repo.get(file).ok_or(*file))
+        .partition_result();
+    if missing.is_empty() {
+        check_model_files(&paths)?;
+        return Ok(format!(
+            "{} cached in {}",
+            semantic.model,
+            cache_dir.display()
+        ));
+    }
+    if semantic.offline {
+        return Err(miette::miette!(
+            help = "download the model once without search.semantic.offline or copy the files into the cache",
+            "Model files {} are missing in the cache {}",
+            missing.join(", "),
+            cache_dir.display()
+        ));
+    }
+    retrieve_model(model.clone(), semantic.models_dir.clone())?
+        .info()
+        .await
+        .map_err(|e| {
+            miette::miette!(
+                help = "check the network, set search.semantic.proxy or copy the model files into search.semantic.models_dir",
+                "Hugging Face is not reachable to download {}: {}",
+                semantic.model,
+                e
+            )
+        })?;
+    Ok(format!(
+        "{} will download {} into {}",
+        semantic.model,
+        missing.join(", "),
+        cache_dir.display()
+    ))
+}
+
+/// Models of the catalog with their files in `models_dir`, any of them can be configured without
+/// a download
+fn describe_local_models(semantic: &SemanticConfig) -> String {
+    let models = list_models(semantic);
+    let local = models
+        .iter()
+        .filter(|it| it.local)
+        .map(|it| format!("{} ({} dims)", it.name, it.dims))
+        .collect_vec();
+    if local.is_empty() {
+        return format!(
+            "none of {} supported models in {}",
+            models.len(),
+            semantic.models_dir.display()
+        );
+    }
+    format!(
+        "{} of {} supported models: {}",
+        local.len(),
+        models.len(),
+        local.join(", ")
+    )
+}
+
+/// Effective exclusions of the workspace with the server options they are inherited from
+fn describe_exclusions(workspace: &Workspace) -> String {
+    let exclusions = exclusions(workspace);
+    if exclusions.is_empty() {
+        return "none".to_string();
+    }
+    format!(
+        "{} globs: {}",
+        exclusions.len(),
+        exclusions.iter().join(", ")
+    )
+}
+
+fn check_custom_model(custom_model: &CustomModelConfig) -> Result<String> {
+    check_model_files(&[
+        custom_model.model_file.clone(),
+        custom_model.tokenizer_file.clone(),
+        custom_model.config_file.clone(),
+        custom_model.special_tokens_map_file.clone(),
+        custom_model.tokenizer_config_file.clone(),
+    ])?;
+    Ok(format!(
+        "custom model {} with {} dimensions",
+        custom_model.model_file.display(),
+        custom_model.dims
+    ))
+}
+
+/// Fails with the problems of all unusable files at once
+fn check_model_files(paths: &[PathBuf]) -> Result<()> {
+    let problems = paths
+        .iter()
+        .filter_map(|path| model_file_problem(path).map(|it| format!("{} {}", path.display(), it)))
+        .collect_vec();
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(miette::miette!(
+            help = "copy complete model files or set search.semantic.download_model: true",
+            "Model files are unusable: {}",
+            problems.join("; ")
+        ))
+    }
+}
+
+/// Loads the templates and renders the configured ones in the strict mode of Tera, where an
+/// unknown variable fails. The descriptions and the prompts get a dummy context of their
+/// variables, the templates of the tool results are only looked up
+pub fn check_templates(templates: &TemplatesConfig, config: &McpConfig) -> Result<String> {
+    let tera = load_templates(&templates.templates_path).map_err(|e| {
+        miette::miette!(
+            help = "set templates.templates_path to a glob of the template files",
+            "Failed to load templates {}: {:?}",
+            templates.templates_path,
+            e
+        )
+    })?;
+    let prompts = &templates.prompts;
+    let result_templates = [
+        &prompts.index_status,
+        &prompts.semantic_search,
+        &prompts.fuzzy_search,
+        &prompts.symbol_code,
+        &prompts.references,
+        &prompts.duplicates,
+        &prompts.fuzzy_search_implemented_stuff,
+        &prompts.rules_preview,
+        &prompts.workspace_overview,
+        &prompts.similar_code,
+        &prompts.enrich_symbols,
+    ];
+    let missing = result_templates
+        .iter()
+        .filter(|it| tera.get_template(it).is_err())
+        .collect_vec();
+    if !missing.is_empty() {
+        return Err(miette::miette!(
+            help = "add the templates or change their names in templates.prompts",
+            "Templates {} are missing in {}",
+            missing.iter().join(", "),
+            templates.templates_path
+        ));
+    }
+
+    services::templates::check_templates(&tera, templates, &config.search.semantic.model)?;
+    Ok(format!(
+        "{} templates in {}",
+        tera.get_template_names().count(),
+        templates.templates_path
+    ))
+}
+
+/// Parses the ruleset of the rules file
+pub fn check_rules(path: &Path) -> Result<String> {
+    let help = "set rules to the path of a valid rules YAML file";
+    let rules = std::fs::File::open(path).map_err(|e| {
+        miette::miette!(
+            help = help,
+            "Failed to open rules file {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let ruleset = serde_yaml::from_reader::<_, Ruleset>(rules).map_err(|e| {
+        miette::miette!(
+            help = help,
+            "Failed to parse rules file {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    Ok(format!(
+        "{} common rules, {} symbol rulesets",
+        ruleset.common.len(),
+        ruleset.depends_on.len()
+    ))
+}
+
+/// Parses the expansion file, the search goes on without the expansion if it fails
+pub fn check_query_expansion(config: Option<&QueryExpansionConfig>) -> Result<String> {
+    let Some(config) = config else {
+        return Ok("disabled".to_string());
+    };
+    let expansions = QueryExpansions::load(&config.file).map_err(|e| {
+        miette::miette!(
+            help = "set query_expansion.file to the path of a YAML map of a term to its expansions",
+            "{}",
+            e
+        )
+    })?;
+    Ok(format!(
+        "{} terms, up to {} variants of a query",
+        expansions.len(),
+        config.max_variants
+    ))
+}
+
+/// Opens the store and finds the tables of the workspaces, a local store is created if it doesn't
+/// exist
+pub async fn check_store(semantic: &SemanticConfig, tables: &[String]) -> Result<String> {
+    let store = &semantic.lancedb_store;
+    let db = lancedb::connect(store)
+        .storage_options(semantic.storage_options.clone())
+        .execute()
+        .await
+        .map_err(|e| {
+            miette::miette!(
+                help = "check search.semantic.lancedb_store and search.semantic.storage_options",
+                "Failed to open LanceDB store {}: {}",
+                store,
+                e
+            )
+        })?;
+    let table_names = db.table_names().execute().await.map_err(|e| {
+        miette::miette!(
+            help = if is_remote_store(store) {
+                "check the credentials in search.semantic.storage_options"
+            } else {
+                "check the permissions of search.semantic.lancedb_store"
+            },
+            "Failed to list tables of LanceDB store {}: {}",
+            store,
+            e
+        )
+    })?;
+    let tables = tables
+        .iter()
+        .map(|table| {
+            let state = if table_names.contains(table) {
+                "is indexed"
+            } else {
+                "will be created"
+            };
+            format!("table {} {}", table, state)
+        })
+        .join(", ");
+    Ok(format!("{}, {}", store, tables))
+}
+
+/// The log directory is created at startup if `create_dirs` is set
+pub fn check_log_dir(log_dir: &Path, create_dirs: bool) -> Result<String> {
+    if log_dir.is_dir() {
+        return Ok(log_dir.display().to_string());
+    }
+    if create_dirs {
+        return Ok(format!("{} will be created", log_dir.display()));
+    }
+    Err(miette::miette!(
+        help = "create the directory, change log_dir or set create_dirs: true",
+        "log_dir is not an existing directory: {}",
+        log_dir.display()
+    ))
+}
@@ -0,0 +1,752 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use clap::Parser;
+use futures::future::join_all;
+use lancedb::Connection;
+use miette::{IntoDiagnostic, Result};
+use tokio::{runtime::Handle, sync::watch, task::JoinHandle};
+use tokio_graceful_shutdown::{IntoSubsystem, SubsystemBuilder, SubsystemHandle, Toplevel};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    Args, LiveConfig, McpConfig, NAME, RouteModel, Workspace, config_path,
+    errors::SemantrixError,
+    init_config, init_model, init_previous_index, init_route_models, init_routes, init_store,
+    init_table,
+    repositories::{
+        backend::{RouteTable, RoutedBackend, VectorBackend},
+        embedding::IndexEmbeddingModel,
+    },
+    services::{
+        ChunksPage, SemanticHit, SymbolInfo, SymbolKindFilter, SymbolsPage,
+        excludes::{delete_excluded_chunks, log_exclusions},
+        get_fuzzy_symbols,
+        scratch::ScratchIndex,
+        search_chunks,
+    },
+    subsystems::{
+        WorkspacePipeline,
+        chunker::{
+            ChunkGenerations, ChunkerSubsystem, EmbeddingText, READ_RETRY_DELAY, ReadRetries,
+            StripSections,
+        },
+        config::ConfigWatcherSubsystem,
+        health::HealthSubsystem,
+        indexer::{IndexErrors, IndexTimestamps, IndexerSubsystem, TableRecovery},
+        lsp::LspServerSubsystem,
+        mcp::McpServerSubsystem,
+        metrics::{MetricsSubsystem, install_recorder},
+        states::{SubsystemStates, SubsystemStatesSubsystem},
+        watcher::{WatcherStats, WatcherSubsystem},
+    },
+    validate_config, warm_up,
+};
+
+/// Seconds between the progress lines of the index mode
+const INDEX_PROGRESS_SEC: u64 = 5;
+/// Seconds between the checks of the table written by another process in the read-only mode
+const READ_ONLY_POLL_SEC: u64 = 5;
+
+/// Subsystems started by [`SemantrixBuilder::build`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RunMode {
+    /// Indexing and the LSP server used by the [`SearchHandle`]
+    #[default]
+    Embedded,
+    /// Embedded subsystems with the config watcher and the MCP server
+    Serve,
+    /// Indexing only, stopped after the first index scan with the progress printed
+    Index,
+    /// LSP server only, the index is searched as it is without indexing
+    Query,
+}
+
+/// Builder of the [`Semantrix`] pipeline, the config file is used if no config is given
+#[derive(Default)]
+pub struct SemantrixBuilder {
+    config: Option<Arc<McpConfig>>,
+    args: Option<Arc<Args>>,
+    runtime: Option<Handle>,
+    mode: RunMode,
+    catch_signals: bool,
+}
+
+impl SemantrixBuilder {
+    /// Config used instead of the config file, it is validated by [`SemantrixBuilder::build`]
+    pub fn config(mut self, config: impl Into<Arc<McpConfig>>) -> Self {
+        self.config = Some(config.into());
+        self
+    }
+
+    /// Arguments the config file is found and overridden by, the defaults by default so the
+    /// arguments of the embedding application aren't parsed
+    pub fn args(mut self, args: impl Into<Arc<Args>>) -> Self {
+        self.args = Some(args.into());
+        self
+    }
+
+    /// Runtime of the subsystems, the current one by default
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    pub fn mode(mut self, mode: RunMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Shut the subsystems down on SIGINT and SIGTERM, the embedding application handles them
+    /// by default
+    pub fn catch_signals(mut self, catch_signals: bool) -> Self {
+        self.catch_signals = catch_signals;
+        self
+    }
+
+    /// Opens the index and starts the subsystems of the mode
+    pub async fn build(self) -> Result<Semantrix> {
+        let args = self
+            .args
+            .unwrap_or_else(|| Arc::new(Args::parse_from([NAME])));
+        let config = match self.config {
+            Some(config) => {
+                validate_config(&config)?;
+                config
+            }
+            None => init_config(&args)?,
+        };
+        let live_config = LiveConfig::new(config.clone())?;
+        if config.read_only && self.mode == RunMode::Index {
+            return Err(
+                SemantrixError::config("Index mode can't run with read_only: true")
+                    .with_help("index the store by a process without read_only")
+                    .into(),
+            );
+        }
+
+        // one model and one connection are shared by the pipelines of the workspaces
+        let (embedding_model, ndims, model_id) = init_model(&config).await?;
+        let route_models = init_route_models(&config.search.semantic).await?;
+        let db = init_store(&config).await?;
+
+        let debounce = Duration::from_secs(config.debounce_sec);
+
+        let workspaces = config
+            .workspaces()
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+        let mut pipelines = Vec::with_capacity(workspaces.len());
+        let mut workspace_subsystems = Vec::with_capacity(workspaces.len());
+        for workspace in &workspaces {
+            log_exclusions(workspace);
+            let (pipeline, subsystems) = build_workspace(
+                workspace.clone(),
+                &live_config,
+                &db,
+                &embedding_model,
+                ndims,
+                &model_id,
+                &route_models,
+            )
+            .await?;
+            pipelines.push(pipeline);
+            workspace_subsystems.push(subsystems);
+        }
+        if config.warmup {
+            warm_up(&live_config, &embedding_model, &pipelines).await;
+        }
+
+        let mcp_server = McpServerSubsystem {
+            config: config.clone(),
+            live_config: live_config.clone(),
+            embedding_model: embedding_model.clone(),
+            ndims,
+            pipelines: pipelines.clone(),
+            scratch: Arc::new(ScratchIndex::new(
+                db.clone(),
+                &workspaces[0],
+                embedding_model.clone(),
+                ndims,
+                model_id.clone(),
+                config.scratch.clone(),
+            )),
+        };
+
+        let metrics = config
+            .metrics
+            .bind
+            .map(|bind| {
+                Ok::<_, miette::Report>(MetricsSubsystem {
+                    bind,
+                    path: config.metrics.path.clone(),
+                    handle: install_recorder()?,
+                    pipelines: pipelines.clone(),
+                })
+            })
+            .transpose()?;
+
+        let health = config.health.bind.map(|bind| HealthSubsystem {
+            bind,
+            policy: config.health.policy,
+            pipelines: pipelines.clone(),
+        });
+
+        let states_config = &config.subsystem_states;
+        let subsystem_states = (states_config.log_interval_sec > 0 || states_config.stuck_sec > 0)
+            .then(|| SubsystemStatesSubsystem {
+                config: states_config.clone(),
+                pipelines: pipelines.clone(),
+            });
+
+        let search = SearchHandle {
+            pipeline: pipelines[0].clone(),
+            pipelines: Arc::new(pipelines.clone()),
+            live_config: live_config.clone(),
+        };
+        let shutdown = CancellationToken::new();
+        let shutdown_requested = shutdown.clone();
+        let mode = self.mode;
+        let several = workspace_subsystems.len() > 1;
+        let read_only = config.read_only;
+        let shutdown_timeout = config.shutdown_backstop();
+        let toplevel = Toplevel::new(
+            move |s: SubsystemHandle<Box<dyn std::error::Error + Send + Sync>>| async move {
+                s.start(SubsystemBuilder::new(
+                    "Shutdown trigger",
+                    move |subsys: SubsystemHandle| shutdown_trigger(subsys, shutdown_requested),
+                ));
+                for subsystems in workspace_subsystems {
+                    let workspace = subsystems.workspace;
+                    let name = |subsystem: &str| {
+                        if several {
+                            format!("{} {}", subsystem, workspace.name)
+                        } else {
+                            subsystem.to_string()
+                        }
+                    };
+                    if mode == RunMode::Query || read_only {
+                        if read_only {
+                            let read_only_index = subsystems.read_only_index;
+                            s.start(SubsystemBuilder::new(
+                                name("Read-only index"),
+                                move |subsys: SubsystemHandle| read_only_index.run(subsys),
+                            ));
+                        }
+                        let lsp_server = subsystems.lsp_server;
+                        s.start(SubsystemBuilder::new(
+                            name("LSP server"),
+                            move |subsys: SubsystemHandle| lsp_server.run(subsys),
+                        ));
+                        continue;
+                    }
+                    let (watcher, chunker, indexer) =
+                        (subsystems.watcher, subsystems.chunker, subsystems.indexer);
+                    s.start(SubsystemBuilder::new(
+                        name("Watcher"),
+                        move |subsys: SubsystemHandle| watcher.run(subsys),
+                    ));
+                    s.start(SubsystemBuilder::new(
+                        name("Chunker"),
+                        move |subsys: SubsystemHandle| chunker.run(subsys),
+                    ));
+                    s.start(SubsystemBuilder::new(
+                        name("Indexer"),
+                        move |subsys: SubsystemHandle| indexer.run(subsys),
+                    ));
+                    if mode != RunMode::Index {
+                        let lsp_server = subsystems.lsp_server;
+                        s.start(SubsystemBuilder::new(
+                            name("LSP server"),
+                            move |subsys: SubsystemHandle| lsp_server.run(subsys),
+                        ));
+                    }
+                }
+                if let Some(subsystem_states) = subsystem_states {
+                    s.start(SubsystemBuilder::new(
+                        "Subsystem states",
+                        subsystem_states.into_subsystem(),
+                    ));
+                }
+                if mode == RunMode::Query {
+                    return;
+                }
+                if let Some(metrics) = metrics {
+                    s.start(SubsystemBuilder::new("Metrics", metrics.into_subsystem()));
+                }
+                if let Some(health) = health {
+                    s.start(SubsystemBuilder::new("Health", health.into_subsystem()));
+                }
+                if mode == RunMode::Index {
+                    s.start(SubsystemBuilder::new(
+                        "Index progress",
+                        move |subsys: SubsystemHandle| index_progress(subsys, pipelines),
+                    ));
+                    return;
+                }
+                if mode == RunMode::Serve {
+                    s.start(SubsystemBuilder::new(
+                        "Config watcher",
+                        ConfigWatcherSubsystem {
+                            config: config.clone(),
+                            config_path: config_path(&args),
+                            args,
+                            live_config,
+                            debounce,
+                        }
+                        .into_subsystem(),
+                    ));
+                    s.start(SubsystemBuilder::new(
+                        "MCP server",
+                        mcp_server.into_subsystem(),
+                    ));
+                }
+            },
+        );
+        let toplevel = if self.catch_signals {
+            toplevel.catch_signals()
+        } else {
+            toplevel
+        };
+        let runtime = self.runtime.unwrap_or_else(Handle::current);
+        let finished = runtime.spawn(async move {
+            toplevel
+                .handle_shutdown_requests(shutdown_timeout)
+                .await
+                .map_err(Into::into)
+        });
+
+        Ok(Semantrix {
+            search,
+            shutdown,
+            finished,
+        })
+    }
+}
+
+/// Subsystems of the pipeline of one workspace
+struct WorkspaceSubsystems {
+    workspace: Arc<Workspace>,
+    watcher: WatcherSubsystem,
+    chunker: ChunkerSubsystem,
+    indexer: IndexerSubsystem,
+    lsp_server: LspServerSubsystem,
+    /// Started instead of the watcher, the chunker and the indexer in the read-only mode
+    read_only_index: ReadOnlyIndex,
+}
+
+/// Opens the table of the workspace and connects the subsystems of its pipeline by the channels.
+/// The live config is still the startup one while the instance is built, the watcher keeps it
+/// to read the reloaded values
+async fn build_workspace(
+    workspace: Arc<Workspace>,
+    live_config: &LiveConfig,
+    db: &Connection,
+    embedding_model: &IndexEmbeddingModel,
+    ndims: usize,
+    model_id: &str,
+    route_models: &[RouteModel],
+) -> Result<(WorkspacePipeline, WorkspaceSubsystems)> {
+    let config = live_config.get();
+    let (lsp_server_tx, lsp_server_rx) = watch::channel(None);
+    let (path_event_tx, path_event_rx) = tokio::sync::mpsc::channel(config.channel_size);
+    let (chunks_tx, chunks_rx) = tokio::sync::mpsc::channel(config.channel_size);
+    let (path_changes_tx, path_changes_rx) = tokio::sync::broadcast::channel(config.channel_size);
+
+    let (table, vector_store) = init_table(
+        db,
+        &workspace.semantic,
+        embedding_model,
+        ndims,
+        model_id,
+        workspace.path_case,
+        config.read_only,
+    )
+    .await?;
+    let default = RouteTable {
+        model: workspace.semantic.model.clone(),
+        embedding_model: embedding_model.clone(),
+        ndims,
+        table: table.clone(),
+        vector_store,
+    };
+    let routes =
+        Arc::new(init_routes(db, &workspace, default, route_models, config.read_only).await?);
+    let vector_store: Arc<dyn VectorBackend> = routes.clone();
+    // the chunks of the files excluded since the last run are not seen by the walks, the
+    // read-only mode leaves them to the process which writes the index
+    if !config.read_only {
+        let deleted = delete_excluded_chunks(&workspace, &table, routes.as_ref()).await;
+        if let Err(e) = deleted {
+            warn!("Failed to delete chunks of excluded files: {}", e);
+        }
+    }
+    // the search goes on without the previous model if it can't be served, the read-only mode
+    // doesn't touch the backups of the process which writes the index
+    let previous_index = if config.read_only {
+        None
+    } else {
+        init_previous_index(db, &workspace.semantic, workspace.path_case)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to open backup table of the previous model: {}", e);
+                None
+            })
+    };
+    let (previous_index_tx, previous_index_rx) = watch::channel(previous_index.map(Arc::new));
+
+    let first_path_scan = Arc::new(AtomicBool::new(false));
+    let first_chunks_scan = Arc::new(AtomicBool::new(false));
+    let (first_index_scan_tx, first_index_scan_rx) = watch::channel(false);
+    let first_index_scan_tx = Arc::new(first_index_scan_tx);
+    let index_timestamps = Arc::new(IndexTimestamps::default());
+    let index_errors = Arc::new(IndexErrors::default());
+    let changed_paths = Arc::new(AtomicUsize::new(0));
+    let watcher_stats = Arc::new(WatcherStats::default());
+    let states = Arc::new(SubsystemStates::default());
+    let generations = Arc::new(ChunkGenerations::default());
+    let recovery = Arc::new(TableRecovery::new(
+        db.clone(),
+        routes.clone(),
+        workspace.clone(),
+        config.read_only,
+        first_chunks_scan.clone(),
+        first_index_scan_tx.clone(),
+        path_event_tx.clone(),
+    ));
+
+    let watcher = WatcherSubsystem {
+        workspace: workspace.clone(),
+        debounce: Duration::from_secs(config.debounce_sec),
+        live_config: live_config.clone(),
+        path_event_tx: path_event_tx.clone(),
+        path_changes_tx,
+        first_path_scan: first_path_scan.clone(),
+        changed_paths: changed_paths.clone(),
+        stats: watcher_stats.clone(),
+        states: states.clone(),
+        channel_size: config.channel_size,
+        shutdown_timeout: Duration::from_millis(config.shutdown.default_ms),
+    };
+    let chunker = ChunkerSubsystem {
+        workspace: workspace.clone(),
+        embedding_text: EmbeddingText::of_workspace(&workspace)?,
+        vector_store: vector_store.clone(),
+        path_event_rx,
+        chunks_tx,
+        generations: generations.clone(),
+        first_path_scan: first_path_scan.clone(),
+        first_chunks_scan: first_chunks_scan.clone(),
+        index_errors: index_errors.clone(),
+        recovery: recovery.clone(),
+        strip_sections: StripSections::new(&workspace.semantic.strip_sections)?,
+        states: states.clone(),
+        channel_size: config.channel_size,
+        read_retries: ReadRetries::new(path_event_tx.clone(), READ_RETRY_DELAY),
+    };
+    let indexer = IndexerSubsystem {
+        workspace: workspace.clone(),
+        hooks: config.hooks.clone(),
+        chunks_rx,
+        generations,
+        routes: routes.clone(),
+        first_chunks_scan: first_chunks_scan.clone(),
+        first_index_scan: first_index_scan_tx.clone(),
+        recovery: recovery.clone(),
+        previous_index: previous_index_tx,
+        index_timestamps: index_timestamps.clone(),
+        index_errors: index_errors.clone(),
+        states: states.clone(),
+        batches: AtomicU64::new(0),
+        shutdown_timeout: Duration::from_millis(config.shutdown.indexer_ms),
+    };
+    let lsp_server = LspServerSubsystem {
+        workspace: workspace.clone(),
+        lsp_server_tx,
+        path_changes_rx,
+        states: states.clone(),
+        max_symbol_code_bytes: config.memory.max_symbol_code_bytes,
+        shutdown_timeout: Duration::from_millis(config.shutdown.lsp_ms),
+    };
+
+    let pipeline = WorkspacePipeline {
+        workspace: workspace.clone(),
+        vector_store,
+        table,
+        routes,
+        lsp_server_rx,
+        first_path_scan,
+        first_chunks_scan,
+        first_index_scan: first_index_scan_rx,
+        previous_index: previous_index_rx,
+        index_timestamps,
+        index_errors,
+        changed_paths,
+        path_event_tx,
+        recovery,
+        watcher_stats,
+        states,
+        read_only: config.read_only,
+    };
+    let read_only_index = ReadOnlyIndex {
+        routes: pipeline.routes.clone(),
+        first_path_scan: pipeline.first_path_scan.clone(),
+        first_chunks_scan: pipeline.first_chunks_scan.clone(),
+        first_index_scan: first_index_scan_tx,
+    };
+    let subsystems = WorkspaceSubsystems {
+        workspace,
+        watcher,
+        chunker,
+        indexer,
+        lsp_server,
+        read_only_index,
+    };
+    Ok((pipeline, subsystems))
+}
+
+/// Running pipeline of the semantic index and the LSP server
+pub struct Semantrix {
+    search: SearchHandle,
+    shutdown: CancellationToken,
+    finished: JoinHandle<Result<()>>,
+}
+
+impl Semantrix {
+    pub fn builder() -> SemantrixBuilder {
+        SemantrixBuilder::default()
+    }
+
+    /// Handle of the searches, it can be cloned and used after the shutdown is requested
+    pub fn search(&self) -> SearchHandle {
+        self.search.clone()
+    }
+
+    /// Requests the shutdown of the subsystems and waits until they are stopped
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.cancel();
+        self.wait().await
+    }
+
+    /// Waits until the subsystems are stopped by a signal, an error or the end of the index mode
+    pub async fn wait(self) -> Result<()> {
+        self.finished.await.into_diagnostic()?
+    }
+}
+
+/// Searches of the embedded pipeline without the MCP server, they run on the first workspace
+/// unless another one is selected by [`SearchHandle::workspace`]
+#[derive(Clone)]
+pub struct SearchHandle {
+    pipeline: WorkspacePipeline,
+    pipelines: Arc<Vec<WorkspacePipeline>>,
+    live_config: LiveConfig,
+}
+
+impl SearchHandle {
+    /// Handle of the searches of the named workspace, None if it is not served
+    pub fn workspace(&self, name: &str) -> Option<SearchHandle> {
+        self.pipelines
+            .iter()
+            .find(|pipeline| pipeline.workspace.name == name)
+            .map(|pipeline| SearchHandle {
+                pipeline: pipeline.clone(),
+                pipelines: self.pipelines.clone(),
+                live_config: self.live_config.clone(),
+            })
+    }
+
+    /// Names of the served workspaces in the order of the config
+    pub fn workspace_names(&self) -> Vec<String> {
+        self.pipelines
+            .iter()
+            .map(|pipeline| pipeline.workspace.name.clone())
+            .collect()
+    }
+
+    /// Pipeline of the workspace for the services the handle doesn't wrap, like
+    /// [`symbols_placer`](crate::services::search::symbols_placer)
+    pub fn pipeline(&self) -> &WorkspacePipeline {
+        &self.pipeline
+    }
+
+    /// True after the first index scan, the semantic search sees a partial index before it
+    pub fn is_index_ready(&self) -> bool {
+        *self.pipeline.first_index_scan.borrow()
+    }
+
+    /// Waits for the first index scan
+    pub async fn wait_index(&self) -> Result<()> {
+        let mut first_index_scan = self.pipeline.first_index_scan.clone();
+        first_index_scan
+            .wait_for(|ready| *ready)
+            .await
+            .into_diagnostic()?;
+        Ok(())
+    }
+
+    /// Chunks most similar to the queries with their text, the glob is matched against the
+    /// workspace relative paths
+    pub async fn semantic_search(
+        &self,
+        queries: Vec<String>,
+        limit: usize,
+        path_glob: Option<&str>,
+    ) -> Result<Vec<SemanticHit>> {
+        let workspace = &self.pipeline.workspace;
+        let path_glob = path_glob
+            .map(|it| workspace.path_case.glob(it))
+            .transpose()
+            .into_diagnostic()?;
+        let config = self.live_config.get();
+        search_chunks(
+            &config,
+            workspace,
+            queries,
+            path_glob.as_ref(),
+            config.search.semantic.search_mode,
+            self.pipeline.vector_store.clone(),
+            ChunksPage {
+                limit,
+                max_per_file: config.search.max_results_per_file,
+            },
+        )
+        .await
+    }
+
+    /// Workspace symbols matched by the name patterns and the kinds, the kinds are exact names or
+    /// regexes of [`McpSymbolKind`](crate::enums::McpSymbolKind). The LSP server is waited for
+    pub async fn fuzzy_search(
+        &self,
+        name_patterns: Vec<String>,
+        kinds: Vec<String>,
+        need_code: bool,
+    ) -> Result<Vec<SymbolInfo>> {
+        let kinds = SymbolKindFilter::try_from(kinds)?;
+        let mut lsp_server_rx = self.pipeline.lsp_server_rx.clone();
+        let lsp_server = lsp_server_rx
+            .wait_for(Option::is_some)
+            .await
+            .into_diagnostic()?
+            .clone()
+            .ok_or_else(|| SemantrixError::lsp_transport("LSP server is not started"))?;
+        let config = self.live_config.get();
+        let (symbols, _) = get_fuzzy_symbols(
+            &lsp_server,
+            &config,
+            name_patterns,
+            kinds,
+            need_code,
+            true,
+            SymbolsPage {
+                offset: 0,
+                max_per_file: config.search.max_results_per_file,
+            },
+        )
+        .await?;
+        Ok(symbols)
+    }
+}
+
+/// Requests the shutdown of the subsystems when the token is cancelled
+async fn shutdown_trigger(subsys: SubsystemHandle, shutdown: CancellationToken) -> Result<()> {
+    tokio::select! {
+        _ = shutdown.cancelled() => subsys.request_shutdown(),
+        _ = subsys.on_shutdown_requested() => {}
+    }
+    Ok(())
+}
+
+/// Prints the number of indexed chunks until the first index scans of all workspaces are
+/// finished, then shuts down
+async fn index_progress(subsys: SubsystemHandle, pipelines: Vec<WorkspacePipeline>) -> Result<()> {
+    let mut progress = tokio::time::interval(Duration::from_secs(INDEX_PROGRESS_SEC));
+    let all_indexed = join_all(pipelines.iter().map(|pipeline| {
+        let mut first_index_scan = pipeline.first_index_scan.clone();
+        async move {
+            first_index_scan
+                .wait_for(|ready| *ready)
+                .await
+                .map(|_| ())
+                .into_diagnostic()
+        }
+    }));
+    tokio::pin!(all_indexed);
+    loop {
+        tokio::select! {
+            ready = &mut all_indexed => {
+                ready.into_iter().collect::<Result<Vec<_>>>()?;
+                break;
+            }
+            _ = progress.tick() => {
+                for pipeline in &pipelines {
+                    println!(
+                        "Indexed chunks{}: {}",
+                        workspace_label(&pipeline.workspace, pipelines.len()),
+                        pipeline.routes.count_chunks().await?
+                    );
+                }
+            }
+        }
+    }
+    for pipeline in &pipelines {
+        println!(
+            "Index{} is built: {} chunks of {} files",
+            workspace_label(&pipeline.workspace, pipelines.len()),
+            pipeline.routes.count_chunks().await?,
+            pipeline
+                .routes
+                .count_indexed_files(&pipeline.workspace.semantic.table)
+                .await?
+        );
+    }
+    subsys.request_shutdown();
+    Ok(())
+}
+
+/// First index scan of the read-only mode, the scans are done once the table written by
+/// another process has chunks, an empty table is checked again every [`READ_ONLY_POLL_SEC`]
+struct ReadOnlyIndex {
+    routes: Arc<RoutedBackend>,
+    first_path_scan: Arc<AtomicBool>,
+    first_chunks_scan: Arc<AtomicBool>,
+    first_index_scan: Arc<watch::Sender<bool>>,
+}
+
+impl ReadOnlyIndex {
+    async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        let mut poll = tokio::time::interval(Duration::from_secs(READ_ONLY_POLL_SEC));
+        loop {
+            tokio::select! {
+                _ = subsys.on_shutdown_requested() => return Ok(()),
+                _ = poll.tick() => match self.routes.count_chunks().await {
+                    Ok(0) => debug!("Read-only index is empty, waiting for the indexing process"),
+                    Ok(chunks) => {
+                        info!("Read-only index has {} chunks, the index is ready", chunks);
+                        self.first_path_scan.store(true, Ordering::Relaxed);
+                        self.first_chunks_scan.store(true, Ordering::Relaxed);
+                        self.first_index_scan.send_replace(true);
+                        return Ok(());
+                    }
+                    Err(e) => warn!("Failed to count chunks of the read-only index: {}", e),
+                },
+            }
+        }
+    }
+}
+
+/// Name of the workspace printed by the index mode if several workspaces are indexed
+fn workspace_label(workspace: &Workspace, workspaces: usize) -> String {
+    if workspaces > 1 {
+        format!(" of {}", workspace.name)
+    } else {
+        String::new()
+    }
+}
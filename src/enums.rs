@@ -1,6 +1,10 @@
+use lsp_types::{PositionEncodingKind, SymbolKind};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumIter, EnumString};
 
+/// Known progress tokens, only the defaults of the readiness tokens of the LSP server are taken
+/// from them, see `search.fuzzy.readiness_tokens`
 #[derive(Debug, strum_macros::Display)]
 pub enum McpProgressToken {
     #[strum(serialize = "mcpLspBridge/symbol")]
@@ -13,13 +17,13 @@ pub enum McpProgressToken {
     LoadingProcMacros,
 }
 
-#[derive(Debug, strum_macros::Display, strum_macros::VariantNames)]
+#[derive(Debug, Copy, Clone, strum_macros::Display, strum_macros::VariantNames, EnumIter)]
 pub enum McpPromptName {
     #[strum(serialize = "Fuzzy search implemented stuff by documentation and names parts")]
     FuzzySearchImplementedStuff,
 }
 
-#[derive(Debug, strum_macros::Display, strum_macros::VariantNames)]
+#[derive(Debug, Copy, Clone, strum_macros::Display, strum_macros::VariantNames, EnumIter)]
 pub enum McpPromptArgument {
     #[strum(serialize = "short_description")]
     ShortDescription,
@@ -27,9 +31,274 @@ pub enum McpPromptArgument {
     PossibleNames,
 }
 
+/// Position strategy used to request the hover of a symbol
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug, Display)]
+pub enum HoverPosition {
+    /// Position of the symbol name found in the code
+    Name,
+    /// Start of the symbol range
+    RangeStart,
+    /// First non whitespace character of the first symbol line
+    FirstNonWhitespace,
+}
+
+/// How the readiness tokens of the LSP server are waited for
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, Debug, Display, Default)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ReadinessTokensMode {
+    /// The server is ready when the work of any token is done
+    #[default]
+    Any,
+    /// The server is ready when the work of every token is done
+    All,
+}
+
+/// How the paths of the globs, the rules and the table are compared
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, Debug, Display, Default)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PathCaseMode {
+    /// Case insensitive on Windows and macOS, whose default filesystems ignore the case
+    #[default]
+    Auto,
+    Sensitive,
+    Insensitive,
+}
+
+impl PathCaseMode {
+    /// True if the paths are compared ignoring their case, [`PathCaseMode::Auto`] ignores it on
+    /// the platforms whose default filesystems do
+    pub fn is_insensitive(self) -> bool {
+        match self {
+            PathCaseMode::Auto => cfg!(any(windows, target_os = "macos")),
+            PathCaseMode::Sensitive => false,
+            PathCaseMode::Insensitive => true,
+        }
+    }
+}
+
+/// Unit of the characters of the LSP positions negotiated with the server in the initialize
+/// request, UTF-16 if the server chose none
+#[derive(
+    Eq, PartialEq, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug, Display, Default,
+)]
+pub enum PositionEncoding {
+    #[serde(rename = "utf-8")]
+    #[strum(serialize = "utf-8")]
+    Utf8,
+    #[default]
+    #[serde(rename = "utf-16")]
+    #[strum(serialize = "utf-16")]
+    Utf16,
+    #[serde(rename = "utf-32")]
+    #[strum(serialize = "utf-32")]
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Encoding of the `positionEncoding` of the server capabilities, the unknown ones are
+    /// taken for UTF-16 as the servers not negotiating it use it
+    pub fn of(kind: Option<&PositionEncodingKind>) -> Self {
+        match kind {
+            Some(kind) if *kind == PositionEncodingKind::UTF8 => PositionEncoding::Utf8,
+            Some(kind) if *kind == PositionEncodingKind::UTF32 => PositionEncoding::Utf32,
+            _ => PositionEncoding::Utf16,
+        }
+    }
+
+    /// Character of the position at the byte offset of the line, the offset inside of a char is
+    /// moved to its start
+    pub fn column(&self, line: &str, byte: usize) -> u32 {
+        let mut byte = byte.min(line.len());
+        while !line.is_char_boundary(byte) {
+            byte -= 1;
+        }
+        let prefix = &line[..byte];
+        let column = match self {
+            PositionEncoding::Utf8 => prefix.len(),
+            PositionEncoding::Utf16 => prefix.encode_utf16().count(),
+            PositionEncoding::Utf32 => prefix.chars().count(),
+        };
+        column as u32
+    }
+
+    /// Byte offset of the line at the character of the position, the character inside of a char
+    /// is moved to its start and the one after the line to its end
+    pub fn byte_offset(&self, line: &str, column: u32) -> usize {
+        let column = column as usize;
+        let mut units = 0;
+        for (byte, char) in line.char_indices() {
+            let next = units
+                + match self {
+                    PositionEncoding::Utf8 => char.len_utf8(),
+                    PositionEncoding::Utf16 => char.len_utf16(),
+                    PositionEncoding::Utf32 => 1,
+                };
+            if next > column {
+                return byte;
+            }
+            units = next;
+        }
+        line.len()
+    }
+}
+
+/// Part of the file a section of `search.semantic.strip_sections` is stripped from, the
+/// sections in the middle of a file are not stripped, so the lines of every chunk stay the lines
+/// of the file between its start and end lines
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, Debug, Display, Default)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum StripScope {
+    /// Match preceded only by whitespace, as a license header
+    #[default]
+    Leading,
+    /// Match followed only by whitespace, as a generated table
+    Trailing,
+}
+
+/// Names of the MCP tools used in the configuration
+#[derive(
+    Eq, PartialEq, Hash, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug, Display, EnumIter,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum McpToolName {
+    IndexStatus,
+    ReindexPath,
+    ListIndexErrors,
+    ListModels,
+    IndexScratch,
+    SemanticSearch,
+    FuzzySearch,
+    GetSymbolCode,
+    EnrichSymbols,
+    FindReferences,
+    FindDuplicates,
+    RulesPreview,
+    SymbolsPlacer,
+    CodeReuseSearch,
+    WorkspaceOverview,
+    SimilarCode,
+    GetResultSchema,
+}
+
+/// Readiness of the server reported by tools, `Ready` is reported only by the status tool
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ReadinessStatus {
+    LspInitializing,
+    IndexInitializing,
+    Ready,
+}
+
+impl ReadinessStatus {
+    /// Status of the server by the started LSP server and the finished first index scan
+    pub fn of(lsp_server_ready: bool, first_index_scan: bool) -> Self {
+        if !lsp_server_ready {
+            ReadinessStatus::LspInitializing
+        } else if !first_index_scan {
+            ReadinessStatus::IndexInitializing
+        } else {
+            ReadinessStatus::Ready
+        }
+    }
+}
+
+/// What the readiness probe of the health endpoint waits for
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, Debug, Display, Default)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum HealthPolicy {
+    /// Ready after the first index scan with the LSP server started
+    #[default]
+    Full,
+    /// Ready after the first index scan, the fuzzy tools are degraded until the LSP server starts
+    IndexOnly,
+}
+
+/// Retrieval of the chunks by the semantic_search tool
+#[derive(
+    Eq, PartialEq, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug, Display, Default,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SearchMode {
+    /// Nearest embeddings of the queries
+    #[default]
+    Vector,
+    /// BM25 ranking of the full-text index, it finds exact identifiers and rare words
+    Fts,
+    /// Both of them fused by the reciprocal ranks
+    Hybrid,
+}
+
+/// Confidence of a placement suggestion, ordered from the least to the most defensible
+#[derive(
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Copy,
+    Clone,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Debug,
+    Display,
+    Default,
+)]
+pub enum PlaceConfidence {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+/// Detail a symbol is cut down to by the response budget, see `search.response_budget_chars`
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SymbolDetail {
+    /// First line of the code without the hover
+    Signature,
+    /// Name and location without the code and the hover
+    Name,
+}
+
+/// What the matched lines of a snippet are, see [`crate::services::comments`]
+#[derive(Eq, PartialEq, Copy, Clone, Serialize, Deserialize, JsonSchema, Debug, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum MatchOrigin {
+    /// Some of the lines have code
+    Code,
+    /// Comments only, some of them are not doc comments
+    Comment,
+    /// Doc comments or docstrings only
+    DocComment,
+}
+
+/// Kind of the LSP symbol, the names are the ones of the LSP specification and are used by the
+/// kind filters and in the responses
 #[repr(i32)]
 #[derive(
-    Eq, PartialEq, Copy, Clone, Serialize, Deserialize, Debug, EnumString, EnumIter, Display,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Copy,
+    Clone,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Debug,
+    EnumString,
+    EnumIter,
+    Display,
 )]
 pub enum McpSymbolKind {
     File = 1,
@@ -59,3 +328,73 @@ pub enum McpSymbolKind {
     Operator = 25,
     TypeParameter = 26,
 }
+
+/// Fails with the kind itself if it is not known by the LSP specification
+impl TryFrom<SymbolKind> for McpSymbolKind {
+    type Error = SymbolKind;
+
+    fn try_from(kind: SymbolKind) -> Result<Self, Self::Error> {
+        Ok(match kind {
+            SymbolKind::FILE => McpSymbolKind::File,
+            SymbolKind::MODULE => McpSymbolKind::Module,
+            SymbolKind::NAMESPACE => McpSymbolKind::Namespace,
+            SymbolKind::PACKAGE => McpSymbolKind::Package,
+            SymbolKind::CLASS => McpSymbolKind::Class,
+            SymbolKind::METHOD => McpSymbolKind::Method,
+            SymbolKind::PROPERTY => McpSymbolKind::Property,
+            SymbolKind::FIELD => McpSymbolKind::Field,
+            SymbolKind::CONSTRUCTOR => McpSymbolKind::Constructor,
+            SymbolKind::ENUM => McpSymbolKind::Enum,
+            SymbolKind::INTERFACE => McpSymbolKind::Interface,
+            SymbolKind::FUNCTION => McpSymbolKind::Function,
+            SymbolKind::VARIABLE => McpSymbolKind::Variable,
+            SymbolKind::CONSTANT => McpSymbolKind::Constant,
+            SymbolKind::STRING => McpSymbolKind::String,
+            SymbolKind::NUMBER => McpSymbolKind::Number,
+            SymbolKind::BOOLEAN => McpSymbolKind::Boolean,
+            SymbolKind::ARRAY => McpSymbolKind::Array,
+            SymbolKind::OBJECT => McpSymbolKind::Object,
+            SymbolKind::KEY => McpSymbolKind::Key,
+            SymbolKind::NULL => McpSymbolKind::Null,
+            SymbolKind::ENUM_MEMBER => McpSymbolKind::EnumMember,
+            SymbolKind::STRUCT => McpSymbolKind::Struct,
+            SymbolKind::EVENT => McpSymbolKind::Event,
+            SymbolKind::OPERATOR => McpSymbolKind::Operator,
+            SymbolKind::TYPE_PARAMETER => McpSymbolKind::TypeParameter,
+            kind => return Err(kind),
+        })
+    }
+}
+
+impl From<McpSymbolKind> for SymbolKind {
+    fn from(kind: McpSymbolKind) -> Self {
+        match kind {
+            McpSymbolKind::File => SymbolKind::FILE,
+            McpSymbolKind::Module => SymbolKind::MODULE,
+            McpSymbolKind::Namespace => SymbolKind::NAMESPACE,
+            McpSymbolKind::Package => SymbolKind::PACKAGE,
+            McpSymbolKind::Class => SymbolKind::CLASS,
+            McpSymbolKind::Method => SymbolKind::METHOD,
+            McpSymbolKind::Property => SymbolKind::PROPERTY,
+            McpSymbolKind::Field => SymbolKind::FIELD,
+            McpSymbolKind::Constructor => SymbolKind::CONSTRUCTOR,
+            McpSymbolKind::Enum => SymbolKind::ENUM,
+            McpSymbolKind::Interface => SymbolKind::INTERFACE,
+            McpSymbolKind::Function => SymbolKind::FUNCTION,
+            McpSymbolKind::Variable => SymbolKind::VARIABLE,
+            McpSymbolKind::Constant => SymbolKind::CONSTANT,
+            McpSymbolKind::String => SymbolKind::STRING,
+            McpSymbolKind::Number => SymbolKind::NUMBER,
+            McpSymbolKind::Boolean => SymbolKind::BOOLEAN,
+            McpSymbolKind::Array => SymbolKind::ARRAY,
+            McpSymbolKind::Object => SymbolKind::OBJECT,
+            McpSymbolKind::Key => SymbolKind::KEY,
+            McpSymbolKind::Null => SymbolKind::NULL,
+            McpSymbolKind::EnumMember => SymbolKind::ENUM_MEMBER,
+            McpSymbolKind::Struct => SymbolKind::STRUCT,
+            McpSymbolKind::Event => SymbolKind::EVENT,
+            McpSymbolKind::Operator => SymbolKind::OPERATOR,
+            McpSymbolKind::TypeParameter => SymbolKind::TYPE_PARAMETER,
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::path::{Path, PathBuf};
+
+use miette::Diagnostic;
+use thiserror::Error;
+
+/// Failures of semantrix by their category, the MCP tools report the category with the message,
+/// so a client can tell the failures worth a retry from the ones to fix
+#[derive(Debug, Error, Diagnostic)]
+pub enum SemantrixError {
+    /// Invalid or unreadable config file, it fails the same way until it is fixed
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::config))]
+    Config {
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// Embedding or reranker model which can't be downloaded, read or created
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::model_load))]
+    ModelLoad {
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// Chunks table which can't be opened, read or written
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::lancedb))]
+    LanceDb {
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// Request to the LSP server which failed on its way or on the server
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::lsp_transport))]
+    LspTransport {
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// Request to the LSP server without the response in time
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::lsp_timeout))]
+    LspTimeout {
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// Template which can't be loaded or rendered
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::template))]
+    Template {
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// Rules file which can't be read or parsed
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::ruleset))]
+    Ruleset {
+        path: PathBuf,
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// File of the workspace or of the data which can't be read or written
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::io))]
+    Io {
+        path: PathBuf,
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+    /// Arguments of a search which break its constraints, the MCP tools report them as invalid
+    /// params
+    #[error("{message}")]
+    #[diagnostic(code(semantrix::invalid_request))]
+    InvalidRequest {
+        message: String,
+        #[help]
+        help: Option<String>,
+    },
+}
+
+impl SemantrixError {
+    pub fn config(message: impl Into<String>) -> Self {
+        Self::Config {
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn model_load(message: impl Into<String>) -> Self {
+        Self::ModelLoad {
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn lancedb(message: impl Into<String>) -> Self {
+        Self::LanceDb {
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn lsp_transport(message: impl Into<String>) -> Self {
+        Self::LspTransport {
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn lsp_timeout(message: impl Into<String>) -> Self {
+        Self::LspTimeout {
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn template(message: impl Into<String>) -> Self {
+        Self::Template {
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn ruleset(path: &Path, message: impl Into<String>) -> Self {
+        Self::Ruleset {
+            path: path.to_path_buf(),
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn io(path: &Path, message: impl Into<String>) -> Self {
+        Self::Io {
+            path: path.to_path_buf(),
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::InvalidRequest {
+            message: message.into(),
+            help: None,
+        }
+    }
+
+    /// The error with the help text shown under its message
+    pub fn with_help(mut self, text: impl Into<String>) -> Self {
+        match &mut self {
+            Self::Config { help, .. }
+            | Self::ModelLoad { help, .. }
+            | Self::LanceDb { help, .. }
+            | Self::LspTransport { help, .. }
+            | Self::LspTimeout { help, .. }
+            | Self::Template { help, .. }
+            | Self::Ruleset { help, .. }
+            | Self::Io { help, .. }
+            | Self::InvalidRequest { help, .. } => *help = Some(text.into()),
+        }
+        self
+    }
+
+    /// Name of the category reported by the MCP tools
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::Config { .. } => "config",
+            Self::ModelLoad { .. } => "model_load",
+            Self::LanceDb { .. } => "lancedb",
+            Self::LspTransport { .. } => "lsp_transport",
+            Self::LspTimeout { .. } => "lsp_timeout",
+            Self::Template { .. } => "template",
+            Self::Ruleset { .. } => "ruleset",
+            Self::Io { .. } => "io",
+            Self::InvalidRequest { .. } => "invalid_request",
+        }
+    }
+
+    /// True if the same call may succeed later, the config, the models, the templates, the
+    /// rules and the invalid requests fail until they are fixed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::LanceDb { .. }
+                | Self::LspTransport { .. }
+                | Self::LspTimeout { .. }
+                | Self::Io { .. }
+        )
+    }
+}
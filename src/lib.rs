@@ -1,550 +1,4005 @@
+pub mod doctor;
+pub mod embedded;
 pub mod enums;
+pub mod errors;
+pub mod query;
 pub mod repositories;
 pub mod services;
 pub mod subsystems;
+pub mod transfer;
+
+pub use embedded::{RunMode, SearchHandle, Semantrix, SemantrixBuilder};
 
 use ::time::format_description;
-use clap::Parser;
+use arrow_array::RecordBatchIterator;
+use chrono::Utc;
+use clap::{Parser, Subcommand, ValueEnum};
 use config::{Config, Environment, File, FileFormat};
 use convert_case::Casing;
 use fastembed::ModelInfo;
 use fastembed::Pooling;
 use fastembed::TokenizerFiles;
 use fastembed::read_file_to_bytes;
-use fastembed::{EmbeddingModel, TextEmbedding, UserDefinedEmbeddingModel};
-use hf_hub::Cache;
+use fastembed::{
+    EmbeddingModel, RerankInitOptionsUserDefined, TextEmbedding, TextRerank,
+    UserDefinedEmbeddingModel, UserDefinedRerankingModel,
+};
+use futures::TryStreamExt;
 use hf_hub::api::tokio::ApiBuilder;
 use hf_hub::api::tokio::ApiRepo;
+use hf_hub::api::tokio::Progress;
+use hf_hub::{Cache, Repo};
+use itertools::Itertools;
 use lancedb::arrow::arrow_schema::DataType;
 use lancedb::{
-    Connection, Table,
+    Connection, DistanceType, Table,
     index::vector::IvfPqIndexBuilder,
+    query::ExecutableQuery,
     table::{OptimizeAction, OptimizeOptions},
 };
 use miette::{IntoDiagnostic, Result};
-use once_cell::sync::Lazy;
-use regex::Regex;
+use once_cell::sync::OnceCell;
+use regex::{Captures, Regex};
+use rig::embeddings::EmbeddingModel as _;
 use rig_lancedb::{LanceDbVectorIndex, SearchParams};
-use serde::Deserialize;
-use serde::Deserializer;
-use serde::de::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
 use std::panic;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tera::Tera;
-use tracing::{Level, error, info};
+use tokio::sync::watch;
+use tracing::{Level, Subscriber, debug, error, info, warn};
 use tracing_appender::{
     non_blocking::WorkerGuard,
     rolling::{RollingFileAppender, Rotation},
 };
 use tracing_subscriber::{
     EnvFilter, Layer,
-    fmt::{self, time::UtcTime, writer::MakeWriterExt},
+    filter::Targets,
+    fmt::{
+        self, MakeWriter,
+        time::{FormatTime, UtcTime},
+        writer::MakeWriterExt,
+    },
     layer::SubscriberExt,
+    registry::LookupSpan,
     util::SubscriberInitExt,
 };
+use url::Url;
+use wax::Glob;
 
-use crate::subsystems::indexer::schema;
+use crate::enums::{
+    HealthPolicy, McpProgressToken, McpSymbolKind, McpToolName, PathCaseMode, PlaceConfidence,
+    ReadinessTokensMode, SearchMode, StripScope,
+};
+use crate::errors::SemantrixError;
+use crate::repositories::{
+    backend::{LanceDbBackend, RouteTable, RoutedBackend, VectorBackend},
+    count_indexed_files, create_fts_index,
+    embedding::{IndexEmbeddingModel, STUB_MODEL, STUB_MODEL_DIMS, StubEmbeddingModel},
+    fts_index_name, has_fts_index,
+};
+use crate::services::{
+    Ruleset, SymbolKindFilter, audit::AUDIT_TARGET, edit_distance, file_cache::FileCache,
+    rerank::Reranker, templates::check_templates, url_to_path,
+};
+use crate::subsystems::{
+    WorkspacePipeline,
+    chunker::{ChunkBudget, TextChunk, embedding_text_tera},
+    indexer::schema,
+};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const NAME: &str = env!("CARGO_PKG_NAME");
 pub const LOG_DIR: &str = "logs";
 pub const DEFAULT_CHUNKS_TABLE_NAME: &str = "chunks";
+/// Suffix of the chunks table name followed by the UTC timestamp of its backup
+pub const CHUNKS_BACKUP_TABLE_SUFFIX: &str = "_backup_";
+/// File of the lancedb store with the identity of the model the chunks are embedded by
+pub const MODEL_ID_FILE: &str = "model_id";
+/// File of the lancedb store with the identity of the model of the backup table searched while
+/// the table is rebuilt for another model
+pub const PREVIOUS_MODEL_ID_FILE: &str = "previous_model_id";
+/// Separator of the model identity and the hash of the embedding text template
+const EMBEDDING_TEXT_ID_SEPARATOR: char = '#';
+/// Schemes of the `search.semantic.lancedb_store` URIs supported by LanceDB, other values are
+/// local paths
+pub const STORE_SCHEMES: [&str; 6] = ["s3", "s3+ddb", "gs", "az", "file", "memory"];
+/// Text embedded and searched by the warm-up, see [`warm_up`]
+pub const WARMUP_TEXT: &str = "warm-up";
+/// Placeholder of `search.semantic.lancedb_store` replaced by the workspace path
+pub const WORKSPACE_PLACEHOLDER: &str = "${workspace}";
+/// Name of the workspace of `search` served when no `workspaces` are configured
+pub const DEFAULT_WORKSPACE: &str = "default";
 pub const DEFAULT_CHUNKS_ID_FIELD: &str = "id";
 pub const DEFAULT_CHUNKS_PATH_FIELD: &str = "path";
 pub const DEFAULT_CHUNKS_START_LINE_FIELD: &str = "start_line";
 pub const DEFAULT_CHUNKS_END_LINE_FIELD: &str = "end_line";
 pub const DEFAULT_CHUNKS_EMBEDDING_FIELD: &str = "embedding";
+pub const DEFAULT_CHUNKS_TEXT_FIELD: &str = "text";
+pub const DEFAULT_CHUNKS_HASH_FIELD: &str = "hash";
+pub const DEFAULT_CHUNKS_INDEXED_AT_FIELD: &str = "indexed_at";
+pub const DEFAULT_CHUNKS_CHUNK_INDEX_FIELD: &str = "chunk_index";
+pub const DEFAULT_CHUNKS_TOTAL_CHUNKS_FIELD: &str = "total_chunks";
 
-pub static ARGS: Lazy<Arc<Args>> = Lazy::new(|| {
-    let args = Args::parse();
-    Arc::new(args)
-});
-
+/// Arguments of the binary, the embedding application has its own ones so the defaults are
+/// used with the embedded config, see [`SemantrixBuilder::args`]
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
     /// The path to the config file
-    #[arg(short, long, value_name = "CONFIG_PATH", default_value = "config.yml")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "CONFIG_PATH",
+        default_value = "config.yml"
+    )]
     pub config_path: String,
+    /// Format of the config file, detected by its extension if not set
+    #[arg(long, global = true, value_enum, value_name = "CONFIG_FORMAT")]
+    pub config_format: Option<ConfigFormat>,
+    /// Workspace path or file URL, overrides `search.fuzzy.workspace_uri`
+    #[arg(long, global = true, value_name = "PATH_OR_URL")]
+    pub workspace: Option<String>,
+    /// Embedding model name, overrides `search.semantic.model`
+    #[arg(long, global = true, value_name = "NAME")]
+    pub model: Option<String>,
+    /// Drop the chunks table without backing it up when it has to be rebuilt
+    #[arg(long, global = true)]
+    pub no_backup: bool,
+    /// Log to the console at the debug level, overrides `debug`
+    #[arg(long, global = true)]
+    pub debug: bool,
+    /// Directory of the log files, overrides `log_dir`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_dir: Option<PathBuf>,
+    /// Level or `EnvFilter` directives of the logs, overrides `logging.level`
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Command {
+    /// Serve the MCP server (default)
+    #[default]
+    Serve,
+    /// Check the config file with its templates and rules, exit with an error if they are invalid
+    Validate,
+    /// Build the index of the workspace without the LSP and MCP servers and exit
+    Index,
+    /// Check the LSP server, the model, the templates, the rules and the store without starting
+    /// the servers, exit with an error if a critical check fails
+    Doctor,
+    /// Search the built index once and print the hits without the MCP server
+    Query {
+        /// Text of the semantic query
+        query: String,
+        /// Max number of the printed hits
+        #[arg(long, default_value_t = 5)]
+        limit: usize,
+        /// Name pattern of the fuzzy search, the LSP server is started for it
+        #[arg(long, value_name = "NAME")]
+        fuzzy: Vec<String>,
+    },
+    /// Write the chunks of the index with their embeddings to a JSON lines file
+    Export {
+        /// Path of the written file
+        #[arg(long, value_name = "PATH")]
+        out: PathBuf,
+    },
+    /// Add the chunks of an exported file to the index without embedding them, the file must be
+    /// exported with the model and dimensions of the config
+    Import {
+        /// Path of the exported file
+        #[arg(long = "in", value_name = "PATH")]
+        input: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl From<ConfigFormat> for FileFormat {
+    fn from(value: ConfigFormat) -> Self {
+        match value {
+            ConfigFormat::Yaml => FileFormat::Yaml,
+            ConfigFormat::Toml => FileFormat::Toml,
+            ConfigFormat::Json => FileFormat::Json,
+        }
+    }
 }
 
-pub static CONFIG: Lazy<Arc<McpConfig>> = Lazy::new(|| {
+/// Format of the config file, the argument takes precedence over the extension
+fn config_format(path: &str, args: &Args) -> Result<FileFormat> {
+    if let Some(format) = args.config_format {
+        return Ok(format.into());
+    }
+    match PathBuf::from(path)
+        .extension()
+        .and_then(|it| it.to_str())
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        Some("yml" | "yaml") => Ok(FileFormat::Yaml),
+        Some("toml") => Ok(FileFormat::Toml),
+        Some("json") => Ok(FileFormat::Json),
+        _ => Err(
+            SemantrixError::config(format!("Unknown format of the config file: {}", path))
+                .with_help("use a .yml, .yaml, .toml or .json file or set --config-format")
+                .into(),
+        ),
+    }
+}
+
+/// Loads the config file of the arguments, the binary passes it to the commands and to
+/// [`Semantrix::builder`]
+pub fn init_config(args: &Args) -> Result<Arc<McpConfig>> {
+    let path = config_path(args);
+    let config = load_config(&path, args).map_err(|e| {
+        SemantrixError::config(format!("Failed to load config {}: {:?}", path, e)).with_help(
+            "set the config file by --config-path or the SEMANTRIX_CONFIG_PATH environment variable",
+        )
+    })?;
+    Ok(Arc::new(config))
+}
+
+/// Path of the config file, the environment variable takes precedence over the argument
+pub fn config_path(args: &Args) -> String {
     let config_path_env =
         (NAME.to_owned() + "_CONFIG_PATH").to_case(convert_case::Case::UpperSnake);
     info!(
         "Try loading config from {} environment variable",
         config_path_env
     );
-    let config_path = std::env::var(config_path_env).unwrap_or_else(|_| ARGS.config_path.clone());
-    let config = load_config(&config_path).expect("Failed to load config");
-    Arc::new(config)
-});
+    std::env::var(config_path_env).unwrap_or_else(|_| args.config_path.clone())
+}
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct SemanticConfig {
     pub download_model: bool,
+    /// Take the downloaded model from the cache of `models_dir` only, without network requests
+    #[serde(default)]
+    pub offline: bool,
+    /// HTTP(S) proxy of the model downloads, `HTTPS_PROXY` and `HTTP_PROXY` are used if unset
+    #[serde(default)]
+    pub proxy: Option<String>,
     pub models_dir: PathBuf,
     pub lancedb_store: String,
+    /// Options of the object store of `lancedb_store`, as `region`, `endpoint` or credentials
+    #[serde(default)]
+    pub storage_options: HashMap<String, String>,
+    #[serde(default)]
+    pub optimize: OptimizeConfig,
     pub model: String,
     pub chunk_size: usize,
     pub overlap_size: usize,
     pub pattern: String,
+    /// Globs of the workspace relative paths left out of the index, a glob matching a folder
+    /// leaves out its files
+    #[serde(default)]
+    pub exclude_globs: Vec<String>,
+    /// Adds the exclusions of the LSP server found in its `server_options` to `exclude_globs`,
+    /// as `files.excludeDirs` of rust-analyzer, see [`services::excludes`]
+    #[serde(default)]
+    pub inherit_server_excludes: bool,
     pub batch_size: usize,
     pub search_limit: usize,
     pub index_embeddings: bool,
+    /// Creates the full-text index of the chunk text for the fts and hybrid search modes
+    #[serde(default)]
+    pub index_fts: bool,
+    /// Mode of the semantic_search tool if the call doesn't choose one
+    #[serde(default)]
+    pub search_mode: SearchMode,
+    /// Number of lines above and below a matched chunk returned when the chunk
+    /// can't be mapped to any LSP symbol
+    #[serde(default = "default_context_lines")]
+    pub context_lines: usize,
+    /// Fraction of the shorter line range two hits of one file overlap by to be merged into one
+    /// hit spanning both, 1 switches the merge off
+    #[serde(default = "default_merge_overlap")]
+    pub merge_overlap: f64,
+    /// Max number of chunks compared with their neighbors by one duplicates search
+    #[serde(default = "default_duplicates_max_chunks")]
+    pub duplicates_max_chunks: usize,
+    /// Number of nearest chunks of other places requested for every compared chunk
+    #[serde(default = "default_duplicates_neighbors")]
+    pub duplicates_neighbors: usize,
+    /// Max number of kept backups of the chunks table made before it is rebuilt, 0 disables them
+    #[serde(default = "default_max_table_backups")]
+    pub max_table_backups: usize,
+    /// Search the backup table of the previous model while the table is rebuilt for another
+    /// model, the previous model is kept loaded until the rebuild is done
+    #[serde(default)]
+    pub serve_previous_model: bool,
+    /// Pooling of the token embeddings, the one the model is trained for if unset
+    #[serde(default)]
+    pub pooling: Option<PoolingConfig>,
+    /// Files of a model outside of the fastembed catalog, used instead of `model` if set
+    #[serde(default)]
+    pub custom_model: Option<CustomModelConfig>,
+    /// Models of the chunks of the files matching the globs, the first matched route wins and
+    /// the other files are embedded by `model`. Every model has its own chunks table
+    #[serde(default)]
+    pub model_routes: Vec<ModelRouteConfig>,
+    /// Cross-encoder rescoring the best chunks of the semantic symbols search, off if unset
+    #[serde(default)]
+    pub reranker: Option<RerankerConfig>,
+    #[serde(default)]
+    pub table: TableConfig,
+    #[serde(default)]
+    pub search_params: SearchParamsConfig,
+    /// Folders outside of the workspace indexed with it, as the docs kept in another
+    /// repository. The LSP server never sees them, so their chunks are returned as snippets
+    #[serde(default)]
+    pub extra_roots: Vec<ExtraRootConfig>,
+    /// Leading and trailing sections of the files left out of the chunks, as the license headers
+    /// and the generated tables
+    #[serde(default)]
+    pub strip_sections: Vec<StripSectionConfig>,
+    /// Tera template of the text embedded for every chunk with the variables `path`,
+    /// `relative_path`, `language`, `start_line`, `end_line` and `text`, the lines of the chunk
+    /// are embedded as they are if unset. The template is a part of the model identity, so its
+    /// change rebuilds the tables like a change of the model
+    #[serde(default)]
+    pub embedding_text_template: Option<String>,
 }
-#[derive(Clone, Debug, Deserialize)]
 
-pub struct SearchConfig {
-    pub semantic: SemanticConfig,
-    pub fuzzy: FuzzyConfig,
+/// Optimization of the chunks table after the initial index scan, remote stores pay for every
+/// round trip of it
+#[derive(Clone, Debug, Deserialize)]
+pub struct OptimizeConfig {
+    /// Min seconds between the optimizations after the writes, 0 optimizes only after the
+    /// initial index scan and the deletion of folders
+    #[serde(default)]
+    pub interval_sec: u64,
+    /// Compact the data files and prune the old versions too, not only the indices
+    #[serde(default)]
+    pub compact: bool,
+    /// Min seconds between the vacuums which compact the data files and remove the old
+    /// versions when the indexer is idle, 0 switches them off
+    #[serde(default = "default_vacuum_interval_sec")]
+    pub vacuum_interval_sec: u64,
+    /// Age in seconds of the versions removed by the vacuum
+    #[serde(default = "default_vacuum_retention_sec")]
+    pub vacuum_retention_sec: u64,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl Default for OptimizeConfig {
+    fn default() -> Self {
+        Self {
+            interval_sec: 0,
+            compact: false,
+            vacuum_interval_sec: default_vacuum_interval_sec(),
+            vacuum_retention_sec: default_vacuum_retention_sec(),
+        }
+    }
+}
 
-pub struct FuzzyConfig {
-    pub lsp_server: String,
-    pub server_args: Vec<String>,
-    pub workspace_uri: String,
-    pub server_options: Value,
-    pub parallelizm: usize,
+fn default_vacuum_interval_sec() -> u64 {
+    86400
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
-pub enum ResponseType {
-    Prompt,
-    Json,
+fn default_vacuum_retention_sec() -> u64 {
+    3600
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct McpConfig {
-    pub debug: bool,
-    pub shutdown_timeout: u64,
-    pub channel_size: usize,
-    pub debounce_sec: u64,
-    pub response: ResponseType,
-    pub search: SearchConfig,
-    pub templates: TemplatesConfig,
-    pub placer: PlacerConfig,
-    pub log_dir: PathBuf,
-    pub rules: PathBuf,
+impl OptimizeConfig {
+    pub fn action(&self) -> OptimizeAction {
+        if self.compact {
+            OptimizeAction::All
+        } else {
+            OptimizeAction::Index(OptimizeOptions::default())
+        }
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct PlacerConfig {
-    #[serde(deserialize_with = "deserialize_regex_vec")]
-    pub prefetch_symbol_kinds: Vec<Regex>,
-    #[serde(deserialize_with = "deserialize_regex_vec")]
-    pub final_symbol_kinds: Vec<Regex>,
-    /// If true, use max distance to find the best place to place the symbol
-    /// If false, use min distance to find the best place to place the symbol
-    pub use_max_distance: bool,
+/// True if the store is an object store URI, not a local path
+pub fn is_remote_store(store: &str) -> bool {
+    store.contains("://")
 }
 
-pub fn deserialize_regex_vec<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let raw_patterns: Vec<String> = Vec::deserialize(deserializer)?;
-    raw_patterns
-        .into_iter()
-        .map(|s| Regex::new(&s).map_err(D::Error::custom))
-        .collect()
+/// Parameters of the vector search by the semantic queries, unset ones are LanceDB defaults.
+/// See [LanceDB vector search](https://lancedb.github.io/lancedb/search/) for more information
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct SearchParamsConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance_type: Option<DistanceTypeConfig>,
+    /// Number of IVF partitions probed by the search of the embeddings index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nprobes: Option<usize>,
+    /// Multiplier of the candidates re-ranked by the full vectors after the embeddings index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refine_factor: Option<u32>,
+    /// Filter the results after the vector search instead of before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_filter: Option<bool>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct TemplatesConfig {
-    pub templates_path: String,
-    pub prompts: PromptTemplates,
-    pub description: DescriptionConfig,
+impl SearchParamsConfig {
+    /// Search params of the embedding column
+    pub fn search_params(&self, column: &str) -> SearchParams {
+        let mut params = SearchParams::default().column(column);
+        if let Some(distance_type) = self.distance_type {
+            params = params.distance_type(distance_type.into());
+        }
+        if let Some(nprobes) = self.nprobes {
+            params = params.nprobes(nprobes);
+        }
+        if let Some(refine_factor) = self.refine_factor {
+            params = params.refine_factor(refine_factor);
+        }
+        if let Some(post_filter) = self.post_filter {
+            params = params.post_filter(post_filter);
+        }
+        params
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct PromptTemplates {
-    pub searcher: String,
-    pub placer: String,
+/// Distance between the embeddings of the vector search
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, Eq, PartialEq)]
+pub enum DistanceTypeConfig {
+    L2,
+    Cosine,
+    Dot,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct DescriptionConfig {
-    pub server: String,
-    pub fuzzy_query: String,
-    pub semantic_query: String,
+impl From<DistanceTypeConfig> for DistanceType {
+    fn from(distance_type: DistanceTypeConfig) -> Self {
+        match distance_type {
+            DistanceTypeConfig::L2 => DistanceType::L2,
+            DistanceTypeConfig::Cosine => DistanceType::Cosine,
+            DistanceTypeConfig::Dot => DistanceType::Dot,
+        }
+    }
 }
 
-pub static TERA: Lazy<Tera> = Lazy::new(|| {
-    Tera::new(&CONFIG.templates.templates_path)
-        .inspect(|tera| {
-            info!(
-                "Loaded templates: {:?}",
-                tera.get_template_names().collect::<Vec<_>>()
-            )
-        })
-        .expect("Failed to create Tera instance")
-});
+/// Names of the chunks table and its fields, instances sharing one store need distinct tables
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct TableConfig {
+    #[serde(default = "default_table_name")]
+    pub name: String,
+    #[serde(default = "default_id_field")]
+    pub id_field: String,
+    #[serde(default = "default_path_field")]
+    pub path_field: String,
+    #[serde(default = "default_start_line_field")]
+    pub start_line_field: String,
+    #[serde(default = "default_end_line_field")]
+    pub end_line_field: String,
+    #[serde(default = "default_embedding_field")]
+    pub embedding_field: String,
+    /// Text of the chunk, searched by the full-text index
+    #[serde(default = "default_text_field")]
+    pub text_field: String,
+    /// Hash of the text of the chunk, compared with the file at query time to find stale chunks
+    #[serde(default = "default_hash_field")]
+    pub hash_field: String,
+    /// Milliseconds since the epoch the chunk was written at, the same for all chunks of a file
+    /// written by one batch
+    #[serde(default = "default_indexed_at_field")]
+    pub indexed_at_field: String,
+    /// Zero based position of the chunk in the chunks of its file
+    #[serde(default = "default_chunk_index_field")]
+    pub chunk_index_field: String,
+    /// Number of the chunks of the file of the chunk when it was written
+    #[serde(default = "default_total_chunks_field")]
+    pub total_chunks_field: String,
+}
 
-pub fn load_config(path: &str) -> Result<McpConfig> {
-    info!("Loading configuration from file: {}", path);
+impl Default for TableConfig {
+    fn default() -> Self {
+        Self {
+            name: default_table_name(),
+            id_field: default_id_field(),
+            path_field: default_path_field(),
+            start_line_field: default_start_line_field(),
+            end_line_field: default_end_line_field(),
+            embedding_field: default_embedding_field(),
+            text_field: default_text_field(),
+            hash_field: default_hash_field(),
+            indexed_at_field: default_indexed_at_field(),
+            chunk_index_field: default_chunk_index_field(),
+            total_chunks_field: default_total_chunks_field(),
+        }
+    }
+}
 
-    let config = Config::builder()
-        .add_source(
-            Environment::default()
-                .prefix(&NAME.to_uppercase())
-                .separator("_")
-                .ignore_empty(true),
-        )
-        .add_source(File::new(path, FileFormat::Yaml))
-        .build()
-        .into_diagnostic()?;
+impl TableConfig {
+    /// Prefix of the backup tables of the table
+    pub fn backup_prefix(&self) -> String {
+        format!("{}{}", self.name, CHUNKS_BACKUP_TABLE_SUFFIX)
+    }
 
-    let app_config: McpConfig = config
-        .try_deserialize()
-        .inspect_err(|e| error!("Failed to deserialize configuration: {}", e))
-        .into_diagnostic()?;
+    /// File of the store with the model identity of the table, the default table keeps the
+    /// file of the stores created before the table name was configurable
+    pub fn model_id_file(&self) -> String {
+        if self.name == DEFAULT_CHUNKS_TABLE_NAME {
+            MODEL_ID_FILE.to_string()
+        } else {
+            format!("{}_{}", self.name, MODEL_ID_FILE)
+        }
+    }
 
-    if app_config.search.semantic.chunk_size < 1 {
-        return Err(miette::miette!(
-            "chunk_size must be greater than 0, but got {}",
-            app_config.search.semantic.chunk_size
-        ));
+    /// File of the store with the model identity of the backup table searched during the
+    /// rebuild, it is removed when the rebuild is done
+    pub fn previous_model_id_file(&self) -> String {
+        format!("{}_{}", self.name, PREVIOUS_MODEL_ID_FILE)
     }
 
-    if app_config.search.semantic.overlap_size > app_config.search.semantic.chunk_size - 1 {
-        return Err(miette::miette!(
-            "overlap_size must be less or equal to chunk_size - 1, but got {} > {}",
-            app_config.search.semantic.overlap_size,
-            app_config.search.semantic.chunk_size
-        ));
+    /// Field names in the order of the table schema
+    pub fn fields(&self) -> [&str; 10] {
+        [
+            &self.id_field,
+            &self.path_field,
+            &self.start_line_field,
+            &self.end_line_field,
+            &self.embedding_field,
+            &self.text_field,
+            &self.hash_field,
+            &self.indexed_at_field,
+            &self.chunk_index_field,
+            &self.total_chunks_field,
+        ]
     }
+}
 
-    Ok(app_config)
+fn default_table_name() -> String {
+    DEFAULT_CHUNKS_TABLE_NAME.to_string()
 }
 
-pub fn init_logger() -> Result<WorkerGuard> {
-    let time_format = format_description::parse_borrowed::<2>(
-        "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z",
-    )
-    .expect("format string should be valid!");
-    let timer = UtcTime::new(time_format);
+fn default_id_field() -> String {
+    DEFAULT_CHUNKS_ID_FIELD.to_string()
+}
 
-    let file_appender = RollingFileAppender::builder()
-        .rotation(Rotation::DAILY)
-        .filename_prefix(NAME)
-        .filename_suffix("log")
-        .max_log_files(3)
-        .build(CONFIG.log_dir.clone())
-        .expect("failed to create log file appender");
+fn default_path_field() -> String {
+    DEFAULT_CHUNKS_PATH_FIELD.to_string()
+}
 
-    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+fn default_start_line_field() -> String {
+    DEFAULT_CHUNKS_START_LINE_FIELD.to_string()
+}
 
-    let console_filter = if !CONFIG.debug {
-        Some(
-            EnvFilter::new("debug")
-                .add_directive("lance=off".parse().unwrap())
-                .add_directive("ort=info".parse().unwrap())
-                .add_directive("tokio=info".parse().unwrap())
-                .add_directive("runtime=info".parse().unwrap())
-                .add_directive("mcp-lsp-bridge=debug".parse().unwrap())
-                .add_directive("lance_linalg=info".parse().unwrap())
-                .add_directive("lance_file=info".parse().unwrap())
-                .add_directive("sqlparser=info".parse().unwrap())
-                .add_directive("datafusion_physical_plan=info".parse().unwrap())
-                .add_directive("hyper_util=info".parse().unwrap()),
-        )
-    } else {
-        None
-    };
+fn default_end_line_field() -> String {
+    DEFAULT_CHUNKS_END_LINE_FIELD.to_string()
+}
 
-    let file_filter = if !CONFIG.debug {
-        Some(
-            EnvFilter::new("debug")
-                .add_directive("lance=off".parse().unwrap())
-                .add_directive("ort=info".parse().unwrap())
-                .add_directive("tokio=info".parse().unwrap())
-                .add_directive("runtime=info".parse().unwrap())
-                .add_directive("mcp-lsp-bridge=debug".parse().unwrap())
-                .add_directive("lance_linalg=info".parse().unwrap())
-                .add_directive("lance_file=info".parse().unwrap())
-                .add_directive("sqlparser=info".parse().unwrap())
-                .add_directive("datafusion_physical_plan=info".parse().unwrap())
-                .add_directive("hyper_util=info".parse().unwrap()),
-        )
-    } else {
-        None
-    };
+fn default_embedding_field() -> String {
+    DEFAULT_CHUNKS_EMBEDDING_FIELD.to_string()
+}
 
-    let stderr_layer = if CONFIG.debug {
-        Some(
-            fmt::layer()
-                .pretty()
-                .with_ansi(false)
-                .with_file(true)
-                .with_line_number(true)
-                .with_thread_ids(true)
-                .with_timer(timer.clone())
-                .with_writer(std::io::stderr.with_max_level(Level::DEBUG))
-                .with_filter(console_filter),
-        )
-    } else {
-        None
-    };
+fn default_text_field() -> String {
+    DEFAULT_CHUNKS_TEXT_FIELD.to_string()
+}
 
-    let file_layer = fmt::layer()
-        .pretty()
-        .with_ansi(false)
-        .with_file(true)
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_timer(timer)
-        .with_writer(non_blocking.with_max_level(Level::DEBUG))
-        .with_filter(file_filter);
+fn default_hash_field() -> String {
+    DEFAULT_CHUNKS_HASH_FIELD.to_string()
+}
 
-    let tokio_console_layer = if CONFIG.debug {
-        Some(console_subscriber::spawn())
-    } else {
-        None
-    };
+fn default_indexed_at_field() -> String {
+    DEFAULT_CHUNKS_INDEXED_AT_FIELD.to_string()
+}
 
-    tracing_subscriber::registry()
-        .with(stderr_layer)
-        .with(file_layer)
-        .with(tokio_console_layer)
-        .init();
+fn default_chunk_index_field() -> String {
+    DEFAULT_CHUNKS_CHUNK_INDEX_FIELD.to_string()
+}
 
-    info!("Tracing initialized successfully");
+fn default_total_chunks_field() -> String {
+    DEFAULT_CHUNKS_TOTAL_CHUNKS_FIELD.to_string()
+}
 
-    panic::set_hook(Box::new(|info| {
-        error!("Panic occurred: {}", info);
-        error!("Backtrace:\n{:?}", Backtrace::force_capture());
-    }));
+/// Root of `search.semantic.extra_roots`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct ExtraRootConfig {
+    /// Absolute path of the folder
+    pub path: PathBuf,
+    /// Glob of the indexed files relative to the root, `search.semantic.pattern` if unset
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
 
-    info!("Configuration loaded successfully: {:#?}", CONFIG);
-
-    Ok(guard)
-}
-
-pub fn model_from_str(value: &str) -> EmbeddingModel {
-    match value {
-        "all-mini-lm-l6-v2" => EmbeddingModel::AllMiniLML6V2,
-        "all-mini-lm-l6-v2-q" => EmbeddingModel::AllMiniLML6V2Q,
-        "all-mini-lm-l12-v2" => EmbeddingModel::AllMiniLML12V2,
-        "all-mini-lm-l12-v2-q" => EmbeddingModel::AllMiniLML12V2Q,
-        "bge-base-en-v1.5" => EmbeddingModel::BGEBaseENV15,
-        "bge-base-en-v1.5-q" => EmbeddingModel::BGEBaseENV15Q,
-        "bge-large-en-v1.5" => EmbeddingModel::BGELargeENV15,
-        "bge-large-en-v1.5-q" => EmbeddingModel::BGELargeENV15Q,
-        "bge-small-en-v1.5" => EmbeddingModel::BGESmallENV15,
-        "bge-small-en-v1.5-q" => EmbeddingModel::BGESmallENV15Q,
-        "nomic-embed-text-v1" => EmbeddingModel::NomicEmbedTextV1,
-        "nomic-embed-text-v1.5" => EmbeddingModel::NomicEmbedTextV15,
-        "nomic-embed-text-v1.5-q" => EmbeddingModel::NomicEmbedTextV15Q,
-        "paraphrase-mini-lm-l12-v2" => EmbeddingModel::ParaphraseMLMiniLML12V2,
-        "paraphrase-mini-lm-l12-v2-q" => EmbeddingModel::ParaphraseMLMiniLML12V2Q,
-        "paraphrase-mpnet-base-v2" => EmbeddingModel::ParaphraseMLMpnetBaseV2,
-        "bge-small-zh-v1.5" => EmbeddingModel::BGESmallZHV15,
-        "multilingual-e5-small" => EmbeddingModel::MultilingualE5Small,
-        "multilingual-e5-base" => EmbeddingModel::MultilingualE5Base,
-        "multilingual-e5-large" => EmbeddingModel::MultilingualE5Large,
-        "mxbai-embed-large-v1" => EmbeddingModel::MxbaiEmbedLargeV1,
-        "mxbai-embed-large-v1-q" => EmbeddingModel::MxbaiEmbedLargeV1Q,
-        "gte-base-en-v1.5" => EmbeddingModel::GTEBaseENV15,
-        "gte-base-en-v1.5-q" => EmbeddingModel::GTEBaseENV15Q,
-        "gte-large-en-v1.5" => EmbeddingModel::GTELargeENV15,
-        "gte-large-en-v1.5-q" => EmbeddingModel::GTELargeENV15Q,
-        "clip-vit-b-32-text" => EmbeddingModel::ClipVitB32,
-        "jina-embeddings-v2-base-code" => EmbeddingModel::JinaEmbeddingsV2BaseCode,
-        _ => EmbeddingModel::AllMiniLML6V2,
-    }
+/// Section of `search.semantic.strip_sections`
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct StripSectionConfig {
+    /// Regex matched against the content of the file, every line the match touches is stripped
+    pub pattern: String,
+    #[serde(default)]
+    pub scope: StripScope,
 }
-pub fn retrieve_model(model: EmbeddingModel, cache_dir: PathBuf) -> Result<ApiRepo> {
-    let cache = Cache::new(cache_dir);
-    let api = ApiBuilder::from_cache(cache)
-        .with_progress(false)
-        .build()
-        .into_diagnostic()?;
 
-    let model_id = model.to_string();
-    info!("Retrieving model from Hugging Face: {}", model_id);
-    let repo = api.model(model_id);
-    Ok(repo)
+/// Route of `search.semantic.model_routes`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelRouteConfig {
+    /// Glob of the routed files relative to the workspace
+    pub pattern: String,
+    /// Catalog model of the routed files, it is loaded like `search.semantic.model`
+    pub model: String,
 }
 
-pub async fn get_or_create_table(db: &Connection, ndims: usize) -> Result<Table> {
-    let table = if db
-        .table_names()
-        .execute()
-        .await
-        .into_diagnostic()?
-        .contains(&DEFAULT_CHUNKS_TABLE_NAME.to_string())
-    {
-        let table = db
-            .open_table(DEFAULT_CHUNKS_TABLE_NAME)
-            .execute()
-            .await
-            .into_diagnostic()?;
-        let current_schema = table.schema().await.into_diagnostic()?;
-        info!("Schema: {:?}", current_schema);
-        let embedding_field = current_schema
-            .field_with_name(DEFAULT_CHUNKS_EMBEDDING_FIELD)
-            .into_diagnostic()?;
-        let new_table = if let DataType::FixedSizeList(_, dims) = embedding_field.data_type() {
-            if *dims != ndims as i32 {
-                info!(
-                    "Embedding field data type size is not equal to ndims of current model, dropping table: {} != {}",
-                    *dims, ndims
-                );
-                db.drop_table(DEFAULT_CHUNKS_TABLE_NAME)
-                    .await
-                    .into_diagnostic()?;
-                let new_schema = schema(ndims);
-                info!("Creating new table with schema: {:?}", new_schema);
-                Some(
-                    db.create_empty_table(DEFAULT_CHUNKS_TABLE_NAME, Arc::new(new_schema))
-                        .execute()
-                        .await
-                        .into_diagnostic()?,
-                )
-            } else {
-                None
-            }
-        } else {
-            return Err(miette::miette!(
-                "Embedding field is not a FixedSizeList: {:?}",
-                embedding_field.data_type()
-            ));
-        };
-        new_table.unwrap_or(table)
-    } else {
-        db.create_empty_table(DEFAULT_CHUNKS_TABLE_NAME, Arc::new(schema(ndims)))
-            .execute()
-            .await
-            .into_diagnostic()?
-    };
+/// User provided ONNX embedding model with its tokenizer files
+#[derive(Clone, Debug, Deserialize)]
+pub struct CustomModelConfig {
+    pub model_file: PathBuf,
+    pub tokenizer_file: PathBuf,
+    pub config_file: PathBuf,
+    pub special_tokens_map_file: PathBuf,
+    pub tokenizer_config_file: PathBuf,
+    /// Number of dimensions of the embeddings produced by the model
+    pub dims: usize,
+    #[serde(default)]
+    pub pooling: PoolingConfig,
+}
 
-    Ok(table)
+/// Files of a cross-encoder ONNX model scoring the pairs of the query and the chunk text
+#[derive(Clone, Debug, Deserialize)]
+pub struct RerankerConfig {
+    pub model_file: PathBuf,
+    pub tokenizer_file: PathBuf,
+    pub config_file: PathBuf,
+    pub special_tokens_map_file: PathBuf,
+    pub tokenizer_config_file: PathBuf,
+    /// Number of the best chunks of every query rescored by the reranker, the others follow them
+    #[serde(default = "default_reranker_candidates")]
+    pub candidates: usize,
 }
 
-pub async fn get_or_download_model(
-    model: EmbeddingModel,
-    model_info: &ModelInfo<EmbeddingModel>,
-) -> Result<(PathBuf, TokenizerFiles)> {
-    let model = if CONFIG.search.semantic.download_model {
-        info!(
-            "Downloading model from Hugging Face to {:?}",
-            CONFIG.search.semantic.models_dir
-        );
-        let model_repo =
-            retrieve_model(model.to_owned(), CONFIG.search.semantic.models_dir.clone())?;
-        info!("Model repo: {:?}", model_repo);
-        let model_path = model_repo
-            .get(&model_info.model_file)
-            .await
-            .into_diagnostic()?;
-        let tokenizer_files = TokenizerFiles {
-            tokenizer_file: read_file_to_bytes(
-                &model_repo.get("tokenizer.json").await.into_diagnostic()?,
-            )
-            .map_err(|e| miette::miette!("Failed to read tokenizer.json: {}", e))?,
-            config_file: read_file_to_bytes(
-                &model_repo.get("config.json").await.into_diagnostic()?,
-            )
-            .map_err(|e| miette::miette!("Failed to read config.json: {}", e))?,
-            special_tokens_map_file: read_file_to_bytes(
-                &model_repo
-                    .get("special_tokens_map.json")
-                    .await
-                    .into_diagnostic()?,
-            )
-            .map_err(|e| miette::miette!("Failed to read special_tokens_map.json: {}", e))?,
-            tokenizer_config_file: read_file_to_bytes(
-                &model_repo
-                    .get("tokenizer_config.json")
-                    .await
-                    .into_diagnostic()?,
-            )
-            .map_err(|e| miette::miette!("Failed to read tokenizer_config.json: {}", e))?,
-        };
-        (model_path, tokenizer_files)
-    } else {
-        info!(
-            "Loading model from local directory {:?}",
-            CONFIG.search.semantic.models_dir
-        );
-        let model_dir = CONFIG.search.semantic.models_dir.join(model.to_string());
-        info!("Model directory: {:?}", model_dir);
-        let model_path = model_dir.join(&model_info.model_file);
-        info!("Model path: {:?}", model_path);
-
-        let tokenizer_files = TokenizerFiles {
-            tokenizer_file: read_file_to_bytes(&model_dir.join("tokenizer.json"))
-                .map_err(|e| miette::miette!("Failed to read tokenizer.json: {}", e))?,
-            config_file: read_file_to_bytes(&model_dir.join("config.json"))
-                .map_err(|e| miette::miette!("Failed to read config.json: {}", e))?,
-            special_tokens_map_file: read_file_to_bytes(&model_dir.join("special_tokens_map.json"))
-                .map_err(|e| miette::miette!("Failed to read special_tokens_map.json: {}", e))?,
-            tokenizer_config_file: read_file_to_bytes(&model_dir.join("tokenizer_config.json"))
-                .map_err(|e| miette::miette!("Failed to read tokenizer_config.json: {}", e))?,
-        };
+fn default_reranker_candidates() -> usize {
+    40
+}
 
-        (model_path, tokenizer_files)
-    };
+/// Pooling of the token embeddings into the embedding of the text
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Default)]
+pub enum PoolingConfig {
+    Cls,
+    #[default]
+    Mean,
+}
 
-    Ok(model)
+impl PoolingConfig {
+    /// Pooling the catalog model is trained for
+    fn of_model(model: &EmbeddingModel) -> Self {
+        match model {
+            EmbeddingModel::BGEBaseENV15
+            | EmbeddingModel::BGEBaseENV15Q
+            | EmbeddingModel::BGELargeENV15
+            | EmbeddingModel::BGELargeENV15Q
+            | EmbeddingModel::BGESmallENV15
+            | EmbeddingModel::BGESmallENV15Q
+            | EmbeddingModel::BGESmallZHV15
+            | EmbeddingModel::GTEBaseENV15
+            | EmbeddingModel::GTEBaseENV15Q
+            | EmbeddingModel::GTELargeENV15
+            | EmbeddingModel::GTELargeENV15Q
+            | EmbeddingModel::MxbaiEmbedLargeV1
+            | EmbeddingModel::MxbaiEmbedLargeV1Q => PoolingConfig::Cls,
+            _ => PoolingConfig::Mean,
+        }
+    }
 }
 
-pub async fn init_db() -> Result<(
-    usize,
-    Table,
-    rig_fastembed::EmbeddingModel,
-    Arc<LanceDbVectorIndex<rig_fastembed::EmbeddingModel>>,
-)> {
-    let db: Connection = lancedb::connect(&CONFIG.search.semantic.lancedb_store)
-        .execute()
-        .await
-        .into_diagnostic()?;
+impl From<PoolingConfig> for Pooling {
+    fn from(value: PoolingConfig) -> Self {
+        match value {
+            PoolingConfig::Cls => Pooling::Cls,
+            PoolingConfig::Mean => Pooling::Mean,
+        }
+    }
+}
 
-    let model = model_from_str(&CONFIG.search.semantic.model);
-    let model_info = TextEmbedding::get_model_info(&model).map_err(|e| {
-        miette::miette!(
-            "Failed to get model info for model: {:?}, error: {}",
-            model,
-            e
-        )
-    })?;
-    info!("Model info: {:?}", model_info);
-    let (model_path, tokenizer_files) = get_or_download_model(model.clone(), model_info).await?;
-    info!("Reading model.onnx file from {:?}", model_path);
-    let onnx_file = read_file_to_bytes(&model_path).expect("Could not read model.onnx file");
-    info!("Creating embedding model");
-    let user_defined_model =
-        UserDefinedEmbeddingModel::new(onnx_file, tokenizer_files).with_pooling(Pooling::Mean);
+fn default_context_lines() -> usize {
+    2
+}
 
-    let ndims = model_info.dim;
+fn default_merge_overlap() -> f64 {
+    0.5
+}
 
-    let embedding_model =
-        rig_fastembed::EmbeddingModel::new_from_user_defined(user_defined_model, ndims, model_info);
+fn default_duplicates_max_chunks() -> usize {
+    500
+}
 
-    let table: Table = get_or_create_table(&db, ndims).await?;
+fn default_duplicates_neighbors() -> usize {
+    5
+}
 
-    if table
-        .index_stats(DEFAULT_CHUNKS_PATH_FIELD)
-        .await
+fn default_max_table_backups() -> usize {
+    2
+}
+#[derive(Clone, Debug, Deserialize)]
+
+pub struct SearchConfig {
+    pub semantic: SemanticConfig,
+    pub fuzzy: FuzzyConfig,
+    #[serde(default)]
+    pub fusion: FusionConfig,
+    /// Max number of results of one file in every result list of the search, the next files
+    /// take the places of the dropped results. Unlimited if not set
+    #[serde(default)]
+    pub max_results_per_file: Option<usize>,
+    /// Max chars of the code and the hovers of the symbols of one response, the symbols past it
+    /// are cut down to their signatures and then to their names. Unlimited if not set
+    #[serde(default)]
+    pub response_budget_chars: Option<usize>,
+    /// Weights the fuzzy and the fused scores of the symbols of a kind are multiplied by, the
+    /// kinds which are not listed weigh 1
+    #[serde(default = "default_kind_weights")]
+    pub kind_weights: HashMap<McpSymbolKind, f64>,
+    /// Last commit, author and date of the lines of the results looked up by git at query time
+    #[serde(default)]
+    pub git_metadata: bool,
+    /// Time one search looks the git metadata up for, the results without it by then are
+    /// returned without it
+    #[serde(default = "default_git_metadata_budget_ms")]
+    pub git_metadata_budget_ms: u64,
+}
+
+impl SearchConfig {
+    /// Weight of the symbols of the kind, see [`Self::kind_weights`]
+    pub fn kind_weight(&self, kind: McpSymbolKind) -> f64 {
+        self.kind_weights.get(&kind).copied().unwrap_or(1.0)
+    }
+}
+
+fn default_git_metadata_budget_ms() -> u64 {
+    500
+}
+
+fn default_kind_weights() -> HashMap<McpSymbolKind, f64> {
+    HashMap::from([
+        (McpSymbolKind::Function, 1.5),
+        (McpSymbolKind::Method, 1.5),
+        (McpSymbolKind::Struct, 1.25),
+        (McpSymbolKind::Class, 1.25),
+    ])
+}
+
+/// Reciprocal rank fusion of the fuzzy and semantic symbols of `code_reuse_search`
+#[derive(Clone, Debug, Deserialize)]
+pub struct FusionConfig {
+    /// Constant added to the ranks, the larger it is the less the top ranks dominate
+    #[serde(default = "default_fusion_k")]
+    pub k: usize,
+    /// Return the fused symbols only, the separate fuzzy and semantic lists are empty
+    #[serde(default)]
+    pub fused_only: bool,
+}
+
+impl Default for FusionConfig {
+    fn default() -> Self {
+        Self {
+            k: default_fusion_k(),
+            fused_only: false,
+        }
+    }
+}
+
+fn default_fusion_k() -> usize {
+    60
+}
+
+#[derive(Clone, Debug, Deserialize)]
+
+pub struct FuzzyConfig {
+    pub lsp_server: String,
+    pub server_args: Vec<String>,
+    pub workspace_uri: String,
+    pub server_options: Value,
+    pub parallelizm: usize,
+    /// Max number of symbols taken from the response of the empty workspace symbol query
+    #[serde(default = "default_empty_query_limit")]
+    pub empty_query_limit: usize,
+    /// Words of a query longer than this are used as separate query variants
+    /// when the query itself finds nothing
+    #[serde(default = "default_variant_min_word_len")]
+    pub variant_min_word_len: usize,
+    /// Max number of variants derived from a query which finds nothing
+    #[serde(default = "default_max_query_variants")]
+    pub max_query_variants: usize,
+    /// Max number of best scored fuzzy symbols enriched with code and hover
+    #[serde(default = "default_fuzzy_limit")]
+    pub fuzzy_limit: usize,
+    /// Max number of references returned for one symbol
+    #[serde(default = "default_references_limit")]
+    pub references_limit: usize,
+    /// Prefixes or regexes of the `$/progress` tokens whose work must be done before the LSP
+    /// server is used, nothing is waited for if empty
+    #[serde(default = "default_readiness_tokens")]
+    pub readiness_tokens: Vec<String>,
+    /// Whether the work of any readiness token or of all of them must be done
+    #[serde(default)]
+    pub readiness_mode: ReadinessTokensMode,
+    #[serde(default)]
+    pub hover_cache: HoverCacheConfig,
+    /// Max number of the files whose symbols are cached until the file is changed, the cache is
+    /// disabled if 0
+    #[serde(default = "default_document_symbols_cache_capacity")]
+    pub document_symbols_cache_capacity: usize,
+    /// Time the document symbols request waits for the final response, the partial results
+    /// received before it are returned after it. Unset waits for the final response
+    #[serde(default)]
+    pub document_symbols_timeout_ms: Option<u64>,
+    /// Time every LSP request waits for its response before it fails. Unset waits for the
+    /// response
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    /// Max number of the hovers requested by one fuzzy search, the symbols after them are
+    /// returned without the hover. Unset requests the hover of every symbol
+    #[serde(default)]
+    pub max_hovers: Option<usize>,
+    /// Time the hovers of one fuzzy search are requested for, the symbols without the hover by
+    /// then are returned without it. Unset waits for every hover
+    #[serde(default)]
+    pub hover_budget_ms: Option<u64>,
+}
+
+/// Cache of the hover responses, the entries of a file are dropped when the file is changed
+#[derive(Clone, Debug, Deserialize)]
+pub struct HoverCacheConfig {
+    /// Max number of cached responses, the cache is disabled if 0
+    #[serde(default = "default_hover_cache_capacity")]
+    pub capacity: usize,
+    /// Time a response is reused for
+    #[serde(default = "default_hover_cache_ttl_sec")]
+    pub ttl_sec: u64,
+}
+
+impl Default for HoverCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_hover_cache_capacity(),
+            ttl_sec: default_hover_cache_ttl_sec(),
+        }
+    }
+}
+
+fn default_document_symbols_cache_capacity() -> usize {
+    500
+}
+
+fn default_hover_cache_capacity() -> usize {
+    1000
+}
+
+fn default_hover_cache_ttl_sec() -> u64 {
+    300
+}
+
+fn default_empty_query_limit() -> usize {
+    1000
+}
+
+fn default_variant_min_word_len() -> usize {
+    3
+}
+
+fn default_max_query_variants() -> usize {
+    8
+}
+
+fn default_fuzzy_limit() -> usize {
+    50
+}
+
+fn default_references_limit() -> usize {
+    200
+}
+
+fn default_readiness_tokens() -> Vec<String> {
+    vec![McpProgressToken::RootsScanned.to_string()]
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+pub enum ResponseType {
+    Prompt,
+    Json,
+    /// Rendered prompt followed by the JSON contents
+    Both,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Default)]
+pub enum OutputPaths {
+    #[default]
+    Absolute,
+    WorkspaceRelative,
+}
+
+/// Transport the MCP server is served over, SSE serves every connected client with one index
+#[derive(Clone, Debug, Default, Deserialize)]
+pub enum TransportConfig {
+    #[default]
+    Stdio,
+    Sse {
+        bind: SocketAddr,
+        #[serde(default = "default_sse_path")]
+        sse_path: String,
+        #[serde(default = "default_post_path")]
+        post_path: String,
+    },
+}
+
+/// Authentication of the network transports, stdio is not checked
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuthConfig {
+    /// Bearer token required in the `Authorization` header, set by `SEMANTRIX_AUTH_TOKEN` too
+    pub token: Option<String>,
+}
+
+/// Prometheus endpoint of the operational metrics, it is served apart from the MCP transport
+#[derive(Clone, Debug, Deserialize)]
+pub struct MetricsConfig {
+    /// Address of the metrics listener, the metrics are not recorded without it
+    pub bind: Option<SocketAddr>,
+    #[serde(default = "default_metrics_path")]
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            path: default_metrics_path(),
+        }
+    }
+}
+
+/// Liveness and readiness probes served apart from the MCP transport
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HealthConfig {
+    /// Address of the probes listener, the probes are not served without it
+    pub bind: Option<SocketAddr>,
+    #[serde(default)]
+    pub policy: HealthPolicy,
+}
+
+/// Diagnostics of the states of the subsystems reported by the status tool and the probes
+#[derive(Clone, Debug, Deserialize)]
+pub struct SubsystemStatesConfig {
+    /// Seconds between the debug log lines of the states, 0 switches them off
+    #[serde(default = "default_states_log_interval_sec")]
+    pub log_interval_sec: u64,
+    /// Seconds a subsystem stays in a transient state, as processing one file, before it is
+    /// warned about, 0 switches the warnings off
+    #[serde(default = "default_stuck_sec")]
+    pub stuck_sec: u64,
+}
+
+impl Default for SubsystemStatesConfig {
+    fn default() -> Self {
+        Self {
+            log_interval_sec: default_states_log_interval_sec(),
+            stuck_sec: default_stuck_sec(),
+        }
+    }
+}
+
+fn default_states_log_interval_sec() -> u64 {
+    60
+}
+
+fn default_stuck_sec() -> u64 {
+    300
+}
+
+/// Commands run by the pipeline on its events, their failures are logged only
+#[derive(Clone, Debug, Deserialize)]
+pub struct HooksConfig {
+    /// Command and its arguments run after the first index scan and the large reindexes, the
+    /// `SEMANTRIX_*` variables of its environment describe the index
+    #[serde(default)]
+    pub on_index_complete: Vec<String>,
+    /// Number of the chunks written since the previous completion which makes a later
+    /// completion a large reindex, the edits of a few files don't run the hook
+    #[serde(default = "default_min_reindex_chunks")]
+    pub min_reindex_chunks: usize,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            on_index_complete: Vec::new(),
+            min_reindex_chunks: default_min_reindex_chunks(),
+        }
+    }
+}
+
+/// Workspace of `workspaces`, the settings it doesn't give are the ones of `search`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WorkspaceConfig {
+    /// Name of the `workspace` argument of the tools, it suffixes the name of the chunks table
+    pub name: String,
+    pub workspace_uri: String,
+    /// Glob of the indexed files, `search.semantic.pattern` if unset
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// LSP server of the workspace, the one of `search.fuzzy` if unset
+    #[serde(default)]
+    pub lsp: Option<WorkspaceLspConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WorkspaceLspConfig {
+    pub lsp_server: String,
+    #[serde(default)]
+    pub server_args: Vec<String>,
+    #[serde(default)]
+    pub server_options: Value,
+}
+
+/// Workspace with the semantic and fuzzy configs of its pipeline
+#[derive(Clone, Debug)]
+pub struct Workspace {
+    pub name: String,
+    pub semantic: SemanticConfig,
+    pub fuzzy: FuzzyConfig,
+    /// How the paths of the workspace are compared, `path_case` of the config
+    pub path_case: PathCaseMode,
+    /// Exclusions compiled on the first use, see
+    /// [`exclusion_globs`](crate::services::excludes::exclusion_globs)
+    pub(crate) exclusion_globs: OnceCell<Arc<Vec<Glob<'static>>>>,
+    /// Lines of the read files and the scratch buffers, shared with the other workspaces of the
+    /// config
+    pub file_cache: Arc<FileCache>,
+    /// Bytes of the chunks in flight, shared with the other workspaces of the config
+    pub chunk_budget: Arc<ChunkBudget>,
+    /// Reranker of `search.semantic.reranker`, shared with the other workspaces of the config
+    pub reranker: Arc<Reranker>,
+}
+
+fn default_min_reindex_chunks() -> usize {
+    1000
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_string()
+}
+
+fn default_sse_path() -> String {
+    "/sse".to_string()
+}
+
+fn default_post_path() -> String {
+    "/message".to_string()
+}
+
+fn default_tool_timeout_ms() -> u64 {
+    60000
+}
+
+fn default_readiness_wait_ms() -> u64 {
+    5000
+}
+
+fn default_tool_concurrency() -> usize {
+    2
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct McpConfig {
+    pub debug: bool,
+    /// Backstop of the whole shutdown, raised to the sum of the `shutdown` budgets if it is less
+    pub shutdown_timeout: u64,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    pub channel_size: usize,
+    pub debounce_sec: u64,
+    #[serde(default)]
+    pub watcher: WatcherConfig,
+    /// Deadline of one tool call, the tool returns results gathered before it
+    #[serde(default = "default_tool_timeout_ms")]
+    pub tool_timeout_ms: u64,
+    /// Time a tool waits for the LSP server and the index before it returns the initializing status
+    #[serde(default = "default_readiness_wait_ms")]
+    pub readiness_wait_ms: u64,
+    /// Max number of concurrent calls of one tool, the excess calls wait for their turn
+    #[serde(default = "default_tool_concurrency")]
+    pub tool_concurrency: usize,
+    #[serde(default)]
+    pub transport: TransportConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub subsystem_states: SubsystemStatesConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    pub response: ResponseType,
+    /// Response types of the tools which differ from `response`
+    #[serde(default)]
+    pub response_overrides: HashMap<McpToolName, ResponseType>,
+    /// Tools switched off by `false`, not listed tools are enabled
+    #[serde(default)]
+    pub tools: HashMap<McpToolName, bool>,
+    #[serde(default)]
+    pub output_paths: OutputPaths,
+    /// Serve the tables written by another process without the watcher, the chunker and the
+    /// indexer, the tables are opened as they are and the mutating tools are rejected
+    #[serde(default)]
+    pub read_only: bool,
+    pub search: SearchConfig,
+    /// Workspaces served by one process with their own tables, watchers and LSP servers, the
+    /// workspace of `search` is served alone if empty
+    #[serde(default)]
+    pub workspaces: Vec<WorkspaceConfig>,
+    pub templates: TemplatesConfig,
+    pub placer: PlacerConfig,
+    #[serde(default)]
+    pub overview: OverviewConfig,
+    #[serde(default)]
+    pub query_cache: QueryCacheConfig,
+    pub log_dir: PathBuf,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Create the missing `log_dir`, `models_dir` and parent of `lancedb_store` at startup
+    #[serde(default = "default_create_dirs")]
+    pub create_dirs: bool,
+    pub rules: PathBuf,
+    /// Synonyms and abbreviations added to the queries of the code reuse search, off if unset
+    #[serde(default)]
+    pub query_expansion: Option<QueryExpansionConfig>,
+    #[serde(default)]
+    pub file_cache: FileCacheConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    #[serde(default)]
+    pub scratch: ScratchConfig,
+    /// Case of the paths matched by the globs and the rules and of the deleted chunks, so the
+    /// differently cased paths of one file are the same path on the case insensitive filesystems
+    #[serde(default)]
+    pub path_case: PathCaseMode,
+    /// Embed a text, search the tables and render the templates at startup, so the first tool
+    /// call doesn't pay for the model session, the index load and the templates
+    #[serde(default)]
+    pub warmup: bool,
+}
+
+/// YAML map of a term to its expansions, read on every search like the rules file
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct QueryExpansionConfig {
+    pub file: PathBuf,
+    /// Max number of the expanded variants of one query, the query itself is not counted
+    #[serde(default = "default_max_expansion_variants")]
+    pub max_variants: usize,
+}
+
+fn default_max_expansion_variants() -> usize {
+    4
+}
+
+/// Lines of the files shared by the chunker and the symbol enrichment, the policies of reading
+/// the files apply to both of them
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct FileCacheConfig {
+    /// Max size of the cached lines of all files
+    #[serde(default = "default_file_cache_budget_bytes")]
+    pub budget_bytes: usize,
+    /// Larger files are neither chunked nor read for the symbol code
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// If true, not UTF-8 bytes are replaced, otherwise the file is read up to the first not
+    /// UTF-8 line
+    #[serde(default)]
+    pub lossy_utf8: bool,
+    /// If true, the entries of the zip archives are indexed under the virtual paths
+    /// `<archive>!<entry>`, otherwise an archive is read as its plain text
+    #[serde(default)]
+    pub archives: bool,
+}
+
+impl Default for FileCacheConfig {
+    fn default() -> Self {
+        Self {
+            budget_bytes: default_file_cache_budget_bytes(),
+            max_file_bytes: default_max_file_bytes(),
+            lossy_utf8: false,
+            archives: false,
+        }
+    }
+}
+
+/// Memory guards of the indexing and the symbol enrichment of very large files
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MemoryConfig {
+    /// Max bytes of the chunks sent by the chunkers of all workspaces and not written by the
+    /// indexers yet, the chunkers wait for the indexers above it
+    #[serde(default = "default_chunk_bytes_in_flight")]
+    pub chunk_bytes_in_flight: usize,
+    /// Max bytes of the code of one symbol, the longer code is cut after its last whole line
+    /// within them
+    #[serde(default = "default_max_symbol_code_bytes")]
+    pub max_symbol_code_bytes: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            chunk_bytes_in_flight: default_chunk_bytes_in_flight(),
+            max_symbol_code_bytes: default_max_symbol_code_bytes(),
+        }
+    }
+}
+
+/// Buffers sent by the clients with the index_scratch tool, they are indexed into their own
+/// table, which is dropped on shutdown
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ScratchConfig {
+    /// Seconds a buffer is searched after it is indexed, the expired buffers are deleted by the
+    /// next scratch call
+    #[serde(default = "default_scratch_ttl_sec")]
+    pub ttl_sec: u64,
+    /// Max bytes of the content of one buffer
+    #[serde(default = "default_scratch_max_bytes")]
+    pub max_bytes: usize,
+}
+
+impl Default for ScratchConfig {
+    fn default() -> Self {
+        Self {
+            ttl_sec: default_scratch_ttl_sec(),
+            max_bytes: default_scratch_max_bytes(),
+        }
+    }
+}
+
+fn default_scratch_ttl_sec() -> u64 {
+    3600
+}
+
+fn default_scratch_max_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_chunk_bytes_in_flight() -> usize {
+    32 * 1024 * 1024
+}
+
+fn default_max_symbol_code_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_file_cache_budget_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_max_file_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+/// Budgets of the cleanup of the subsystems after the shutdown request, the cleanup which
+/// exceeds its budget is abandoned
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ShutdownConfig {
+    /// Shutdown and exit requests of the LSP server
+    #[serde(default = "default_shutdown_lsp_ms")]
+    pub lsp_ms: u64,
+    /// Write of the pending batch of the indexer
+    #[serde(default = "default_shutdown_indexer_ms")]
+    pub indexer_ms: u64,
+    /// Cleanup of the other subsystems like the teardown of the file watcher
+    #[serde(default = "default_shutdown_default_ms")]
+    pub default_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            lsp_ms: default_shutdown_lsp_ms(),
+            indexer_ms: default_shutdown_indexer_ms(),
+            default_ms: default_shutdown_default_ms(),
+        }
+    }
+}
+
+fn default_shutdown_lsp_ms() -> u64 {
+    2000
+}
+
+fn default_shutdown_indexer_ms() -> u64 {
+    5000
+}
+
+fn default_shutdown_default_ms() -> u64 {
+    1000
+}
+
+/// Checks of the timings of the file watcher flushes, read on every flush, so the changes are
+/// applied without restart
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WatcherConfig {
+    /// A flush holding its events longer than `debounce_sec` times the factor or a wall clock
+    /// jump larger than it between the flushes is reported as a clock anomaly
+    #[serde(default = "default_watcher_anomaly_factor")]
+    pub anomaly_factor: u32,
+    /// Recreate the debouncer of the file watcher after a clock anomaly
+    #[serde(default)]
+    pub recreate_on_anomaly: bool,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        Self {
+            anomaly_factor: default_watcher_anomaly_factor(),
+            recreate_on_anomaly: false,
+        }
+    }
+}
+
+fn default_watcher_anomaly_factor() -> u32 {
+    10
+}
+
+/// Time the shutdown backstop exceeds the sum of the budgets, so the budgets run out first
+const SHUTDOWN_BACKSTOP_MARGIN_MS: u64 = 500;
+
+/// Log files of `log_dir` and the debug outputs
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Number of the kept rotated files
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Level or `EnvFilter` directives of the project logs, noisy dependencies are quieter
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// `EnvFilter` directives of the dependencies added after the level, `RUST_LOG` ones follow
+    #[serde(default = "default_log_directives")]
+    pub directives: Vec<String>,
+    /// Logs to stderr, on by default in debug mode
+    #[serde(default)]
+    pub stderr: Option<bool>,
+    /// Format of the stderr logs, they are pretty unless it is set
+    #[serde(default)]
+    pub stderr_format: Option<LogFormat>,
+    /// Server of tokio-console, on by default in debug mode
+    #[serde(default)]
+    pub tokio_console: Option<bool>,
+    #[serde(default)]
+    pub audit: AuditConfig,
+}
+
+/// Records of the tool calls written as JSON lines to their own files of `log_dir`
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Write the arguments of the calls, only their sizes are written otherwise
+    #[serde(default)]
+    pub include_text: bool,
+    /// Number of the kept rotated audit files
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            include_text: false,
+            max_files: default_log_max_files(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            rotation: LogRotation::default(),
+            max_files: default_log_max_files(),
+            format: LogFormat::default(),
+            level: default_log_level(),
+            directives: default_log_directives(),
+            stderr: None,
+            stderr_format: None,
+            tokio_console: None,
+            audit: AuditConfig::default(),
+        }
+    }
+}
+
+fn default_log_max_files() -> usize {
+    3
+}
+
+fn default_create_dirs() -> bool {
+    true
+}
+
+fn default_log_level() -> String {
+    "debug".to_string()
+}
+
+fn default_log_directives() -> Vec<String> {
+    [
+        "lance=off",
+        "ort=info",
+        "tokio=info",
+        "runtime=info",
+        "mcp-lsp-bridge=debug",
+        "lance_linalg=info",
+        "lance_file=info",
+        "sqlparser=info",
+        "datafusion_physical_plan=info",
+        "hyper_util=info",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Default)]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(value: LogRotation) -> Self {
+        match value {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Compact,
+    /// One JSON object per line
+    Json,
+}
+
+impl McpConfig {
+    /// Response type of the tool with its override applied
+    pub fn response_for(&self, tool: McpToolName) -> ResponseType {
+        self.response_overrides
+            .get(&tool)
+            .copied()
+            .unwrap_or(self.response)
+    }
+
+    pub fn tool_enabled(&self, tool: McpToolName) -> bool {
+        self.tools.get(&tool).copied().unwrap_or(true)
+    }
+
+    /// Workspaces of the pipelines in the order of the config, the workspace of `search` named
+    /// [`DEFAULT_WORKSPACE`] if `workspaces` is empty. The table of a configured workspace is
+    /// `search.semantic.table.name` suffixed by its name, so the workspaces share the store only
+    pub fn workspaces(&self) -> Vec<Workspace> {
+        let file_cache = Arc::new(FileCache::from(&self.file_cache));
+        let chunk_budget = Arc::new(ChunkBudget::from(&self.memory));
+        let reranker = Arc::new(Reranker::new(self.search.semantic.reranker.clone()));
+        if self.workspaces.is_empty() {
+            return vec![Workspace {
+                name: DEFAULT_WORKSPACE.to_string(),
+                semantic: self.search.semantic.clone(),
+                fuzzy: self.search.fuzzy.clone(),
+                path_case: self.path_case,
+                exclusion_globs: OnceCell::new(),
+                file_cache,
+                chunk_budget,
+                reranker,
+            }];
+        }
+        self.workspaces
+            .iter()
+            .map(|workspace| {
+                let mut semantic = self.search.semantic.clone();
+                semantic.table.name = format!("{}_{}", semantic.table.name, workspace.name);
+                if let Some(pattern) = &workspace.pattern {
+                    semantic.pattern = pattern.clone();
+                }
+                let mut fuzzy = self.search.fuzzy.clone();
+                fuzzy.workspace_uri = workspace.workspace_uri.clone();
+                if let Some(lsp) = &workspace.lsp {
+                    fuzzy.lsp_server = lsp.lsp_server.clone();
+                    fuzzy.server_args = lsp.server_args.clone();
+                    fuzzy.server_options = lsp.server_options.clone();
+                }
+                Workspace {
+                    name: workspace.name.clone(),
+                    semantic,
+                    fuzzy,
+                    path_case: self.path_case,
+                    exclusion_globs: OnceCell::new(),
+                    file_cache: file_cache.clone(),
+                    chunk_budget: chunk_budget.clone(),
+                    reranker: reranker.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Timeout of the whole shutdown, it is slightly larger than the sum of the budgets of the
+    /// subsystems unless `shutdown_timeout` is larger
+    pub fn shutdown_backstop(&self) -> Duration {
+        let budgets = self.shutdown.lsp_ms + self.shutdown.indexer_ms + self.shutdown.default_ms;
+        Duration::from_millis(
+            self.shutdown_timeout
+                .max(budgets + SHUTDOWN_BACKSTOP_MARGIN_MS),
+        )
+    }
+}
+
+/// Options of the workspace overview tool
+#[derive(Clone, Debug, Deserialize)]
+pub struct OverviewConfig {
+    /// Seconds the built overview is reused for
+    #[serde(default = "default_overview_ttl_sec")]
+    pub ttl_sec: u64,
+    /// Number of paths changed by the watcher after which the overview is rebuilt before the TTL
+    #[serde(default = "default_overview_churn_paths")]
+    pub churn_paths: usize,
+    /// Number of the largest files in the overview
+    #[serde(default = "default_overview_largest_files")]
+    pub largest_files: usize,
+    /// Max number of workspace symbols grouped by kind in the overview
+    #[serde(default = "default_overview_symbols_limit")]
+    pub symbols_limit: usize,
+}
+
+impl Default for OverviewConfig {
+    fn default() -> Self {
+        Self {
+            ttl_sec: default_overview_ttl_sec(),
+            churn_paths: default_overview_churn_paths(),
+            largest_files: default_overview_largest_files(),
+            symbols_limit: default_overview_symbols_limit(),
+        }
+    }
+}
+
+fn default_overview_ttl_sec() -> u64 {
+    600
+}
+
+fn default_overview_churn_paths() -> usize {
+    50
+}
+
+fn default_overview_largest_files() -> usize {
+    10
+}
+
+fn default_overview_symbols_limit() -> usize {
+    500
+}
+
+/// Results of the semantic tools reused for identical repeated calls
+#[derive(Clone, Debug, Deserialize)]
+pub struct QueryCacheConfig {
+    /// Seconds a result is reused for unless the index or the workspace changes, 0 disables
+    /// the cache
+    #[serde(default = "default_query_cache_ttl_sec")]
+    pub ttl_sec: u64,
+    /// Max number of cached results
+    #[serde(default = "default_query_cache_capacity")]
+    pub capacity: usize,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_sec: default_query_cache_ttl_sec(),
+            capacity: default_query_cache_capacity(),
+        }
+    }
+}
+
+fn default_query_cache_ttl_sec() -> u64 {
+    30
+}
+
+fn default_query_cache_capacity() -> usize {
+    100
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlacerConfig {
+    pub prefetch_symbol_kinds: SymbolKindFilter,
+    pub final_symbol_kinds: SymbolKindFilter,
+    /// If true, use max distance to find the best place to place the symbol
+    /// If false, use min distance to find the best place to place the symbol
+    pub use_max_distance: bool,
+    /// If true, not common path components are weighted by their depth, so divergence close
+    /// to the leaves is cheaper than divergence at the top of the tree
+    #[serde(default)]
+    pub depth_weighted_distance: bool,
+    /// Glob patterns (relative to the workspace) of references which are not used as placement candidates
+    #[serde(default = "default_ignore_reference_globs")]
+    pub ignore_reference_globs: Vec<String>,
+    /// Suggestions with lower confidence are not returned unless requested, the symbols without
+    /// a better placement are always returned
+    #[serde(default)]
+    pub min_confidence: PlaceConfidence,
+    /// Max number of the symbols whose references are requested by one run, the ones matching
+    /// the priority patterns of the request first, then the ones of the larger files. Unset
+    /// analyzes every symbol
+    #[serde(default)]
+    pub max_symbols: Option<usize>,
+    /// Time the references of one run are requested for, the symbols without them by then are
+    /// skipped. Unset waits for every symbol
+    #[serde(default)]
+    pub budget_ms: Option<u64>,
+    /// Max number of the references requests of one run at a time. Unset uses
+    /// `search.fuzzy.parallelizm` - 1
+    #[serde(default)]
+    pub reference_concurrency: Option<usize>,
+}
+
+impl PlacerConfig {
+    /// References requests of one run at a time, kept below `parallelizm` if it allows more
+    /// than one request, so the other tools still get the LSP server during the run
+    pub fn reference_concurrency(&self, parallelizm: usize) -> usize {
+        let limit = parallelizm.saturating_sub(1).max(1);
+        self.reference_concurrency.unwrap_or(limit).clamp(1, limit)
+    }
+}
+
+fn default_ignore_reference_globs() -> Vec<String> {
+    vec![
+        "**/tests/**".to_string(),
+        "**/benches/**".to_string(),
+        "**/*_test.*".to_string(),
+        "**/*_tests.*".to_string(),
+    ]
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TemplatesConfig {
+    pub templates_path: String,
+    pub prompts: PromptTemplates,
+    pub description: DescriptionConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PromptTemplates {
+    pub searcher: String,
+    pub placer: String,
+    #[serde(default = "default_index_status_template")]
+    pub index_status: String,
+    #[serde(default = "default_semantic_search_template")]
+    pub semantic_search: String,
+    #[serde(default = "default_fuzzy_search_template")]
+    pub fuzzy_search: String,
+    #[serde(default = "default_symbol_code_template")]
+    pub symbol_code: String,
+    #[serde(default = "default_references_template")]
+    pub references: String,
+    #[serde(default = "default_duplicates_template")]
+    pub duplicates: String,
+    #[serde(default = "default_fuzzy_search_implemented_stuff_template")]
+    pub fuzzy_search_implemented_stuff: String,
+    #[serde(default = "default_rules_preview_template")]
+    pub rules_preview: String,
+    #[serde(default = "default_workspace_overview_template")]
+    pub workspace_overview: String,
+    #[serde(default = "default_similar_code_template")]
+    pub similar_code: String,
+    #[serde(default = "default_enrich_symbols_template")]
+    pub enrich_symbols: String,
+}
+
+fn default_index_status_template() -> String {
+    "index_status.md".to_string()
+}
+
+fn default_semantic_search_template() -> String {
+    "semantic_search.md".to_string()
+}
+
+fn default_fuzzy_search_template() -> String {
+    "fuzzy_search.md".to_string()
+}
+
+fn default_symbol_code_template() -> String {
+    "symbol_code.md".to_string()
+}
+
+fn default_references_template() -> String {
+    "references.md".to_string()
+}
+
+fn default_duplicates_template() -> String {
+    "duplicates.md".to_string()
+}
+
+fn default_fuzzy_search_implemented_stuff_template() -> String {
+    "prompts/fuzzy_search_implemented_stuff.md".to_string()
+}
+
+fn default_rules_preview_template() -> String {
+    "rules_preview.md".to_string()
+}
+
+fn default_workspace_overview_template() -> String {
+    "workspace_overview.md".to_string()
+}
+
+fn default_similar_code_template() -> String {
+    "similar_code.md".to_string()
+}
+
+fn default_enrich_symbols_template() -> String {
+    "enrich_symbols.md".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DescriptionConfig {
+    pub server: String,
+    pub fuzzy_query: String,
+    pub semantic_query: String,
+}
+
+/// Config of one [`Semantrix`] with the values reloaded from the changed config file and the
+/// templates it refers to. Only the values which can be applied without restart are read from
+/// it, the other ones are given to the pipeline at startup. The clones share the values
+#[derive(Clone)]
+pub struct LiveConfig {
+    config: Arc<watch::Sender<Arc<McpConfig>>>,
+    tera: Arc<watch::Sender<Arc<Tera>>>,
+}
+
+impl LiveConfig {
+    /// Live config starting with the startup config and its templates
+    pub fn new(config: Arc<McpConfig>) -> Result<Self> {
+        let templates_path = &config.templates.templates_path;
+        let tera = load_templates(templates_path).map_err(|e| {
+            SemantrixError::template(format!(
+                "Failed to load templates {}: {:?}",
+                templates_path, e
+            ))
+            .with_help("set templates.templates_path to a glob of the template files")
+        })?;
+        Ok(Self {
+            config: Arc::new(watch::channel(config).0),
+            tera: Arc::new(watch::channel(Arc::new(tera)).0),
+        })
+    }
+
+    /// Current reloadable config
+    pub fn get(&self) -> Arc<McpConfig> {
+        self.config.borrow().clone()
+    }
+
+    /// Current templates
+    pub fn tera(&self) -> Arc<Tera> {
+        self.tera.borrow().clone()
+    }
+
+    /// Loads the changed config file, applies the reloadable values and warns about the changed
+    /// values of the startup config which are applied only after restart, templates failing to
+    /// render refuse the reload
+    pub fn reload(&self, path: &str, args: &Args, startup: &McpConfig) -> Result<()> {
+        let config = load_config(path, args)?;
+        let tera = load_templates(&config.templates.templates_path)?;
+        check_templates(&tera, &config.templates, &config.search.semantic.model)?;
+        for field in restart_required_changes(startup, &config) {
+            warn!("Changed config value {} requires restart", field);
+        }
+        self.tera.send_replace(Arc::new(tera));
+        self.config.send_replace(Arc::new(config));
+        info!("Configuration reloaded from {}", path);
+        Ok(())
+    }
+}
+
+pub(crate) fn load_templates(templates_path: &str) -> Result<Tera> {
+    Tera::new(templates_path)
+        .inspect(|tera| {
+            info!(
+                "Loaded templates: {:?}",
+                tera.get_template_names().collect::<Vec<_>>()
+            )
+        })
+        .into_diagnostic()
+}
+
+/// Checks the config file with the templates and the rules it refers to without starting anything
+pub fn validate_config_file(path: &str, args: &Args) -> Result<()> {
+    let config = load_config(path, args)?;
+    let tera = load_templates(&config.templates.templates_path)?;
+    check_templates(&tera, &config.templates, &config.search.semantic.model)?;
+    let rules = std::fs::File::open(&config.rules).map_err(|e| {
+        SemantrixError::ruleset(
+            &config.rules,
+            format!("Failed to open rules file {:?}: {}", config.rules, e),
+        )
+    })?;
+    serde_yaml::from_reader::<_, Ruleset>(rules).map_err(|e| {
+        SemantrixError::ruleset(
+            &config.rules,
+            format!("Failed to parse rules file {:?}: {}", config.rules, e),
+        )
+    })?;
+    Ok(())
+}
+
+/// Names of the changed values which are read from the startup config only
+fn restart_required_changes(old: &McpConfig, new: &McpConfig) -> Vec<&'static str> {
+    let (old_semantic, new_semantic) = (&old.search.semantic, &new.search.semantic);
+    let (old_fuzzy, new_fuzzy) = (&old.search.fuzzy, &new.search.fuzzy);
+    let values: &[(&'static str, &dyn Debug, &dyn Debug)] = &[
+        ("debug", &old.debug, &new.debug),
+        (
+            "shutdown_timeout",
+            &old.shutdown_timeout,
+            &new.shutdown_timeout,
+        ),
+        ("shutdown", &old.shutdown, &new.shutdown),
+        ("channel_size", &old.channel_size, &new.channel_size),
+        ("debounce_sec", &old.debounce_sec, &new.debounce_sec),
+        ("workspaces", &old.workspaces, &new.workspaces),
+        (
+            "tool_timeout_ms",
+            &old.tool_timeout_ms,
+            &new.tool_timeout_ms,
+        ),
+        (
+            "readiness_wait_ms",
+            &old.readiness_wait_ms,
+            &new.readiness_wait_ms,
+        ),
+        (
+            "tool_concurrency",
+            &old.tool_concurrency,
+            &new.tool_concurrency,
+        ),
+        ("transport", &old.transport, &new.transport),
+        ("auth", &old.auth, &new.auth),
+        ("metrics", &old.metrics, &new.metrics),
+        ("health", &old.health, &new.health),
+        ("hooks", &old.hooks, &new.hooks),
+        (
+            "subsystem_states",
+            &old.subsystem_states,
+            &new.subsystem_states,
+        ),
+        ("log_dir", &old.log_dir, &new.log_dir),
+        ("logging", &old.logging, &new.logging),
+        ("create_dirs", &old.create_dirs, &new.create_dirs),
+        ("warmup", &old.warmup, &new.warmup),
+        ("read_only", &old.read_only, &new.read_only),
+        ("file_cache", &old.file_cache, &new.file_cache),
+        ("scratch", &old.scratch, &new.scratch),
+        ("path_case", &old.path_case, &new.path_case),
+        (
+            "memory.chunk_bytes_in_flight",
+            &old.memory.chunk_bytes_in_flight,
+            &new.memory.chunk_bytes_in_flight,
+        ),
+        (
+            "search.semantic.download_model",
+            &old_semantic.download_model,
+            &new_semantic.download_model,
+        ),
+        (
+            "search.semantic.offline",
+            &old_semantic.offline,
+            &new_semantic.offline,
+        ),
+        (
+            "search.semantic.proxy",
+            &old_semantic.proxy,
+            &new_semantic.proxy,
+        ),
+        (
+            "search.semantic.models_dir",
+            &old_semantic.models_dir,
+            &new_semantic.models_dir,
+        ),
+        (
+            "search.semantic.lancedb_store",
+            &old_semantic.lancedb_store,
+            &new_semantic.lancedb_store,
+        ),
+        (
+            "search.semantic.storage_options",
+            &old_semantic.storage_options.iter().sorted().collect_vec(),
+            &new_semantic.storage_options.iter().sorted().collect_vec(),
+        ),
+        (
+            "search.semantic.optimize",
+            &old_semantic.optimize,
+            &new_semantic.optimize,
+        ),
+        (
+            "search.semantic.model",
+            &old_semantic.model,
+            &new_semantic.model,
+        ),
+        (
+            "search.semantic.pooling",
+            &old_semantic.pooling,
+            &new_semantic.pooling,
+        ),
+        (
+            "search.semantic.custom_model",
+            &old_semantic.custom_model,
+            &new_semantic.custom_model,
+        ),
+        (
+            "search.semantic.model_routes",
+            &old_semantic.model_routes,
+            &new_semantic.model_routes,
+        ),
+        (
+            "search.semantic.reranker",
+            &old_semantic.reranker,
+            &new_semantic.reranker,
+        ),
+        (
+            "search.semantic.chunk_size",
+            &old_semantic.chunk_size,
+            &new_semantic.chunk_size,
+        ),
+        (
+            "search.semantic.overlap_size",
+            &old_semantic.overlap_size,
+            &new_semantic.overlap_size,
+        ),
+        (
+            "search.semantic.pattern",
+            &old_semantic.pattern,
+            &new_semantic.pattern,
+        ),
+        (
+            "search.semantic.exclude_globs",
+            &old_semantic.exclude_globs,
+            &new_semantic.exclude_globs,
+        ),
+        (
+            "search.semantic.inherit_server_excludes",
+            &old_semantic.inherit_server_excludes,
+            &new_semantic.inherit_server_excludes,
+        ),
+        (
+            "search.semantic.batch_size",
+            &old_semantic.batch_size,
+            &new_semantic.batch_size,
+        ),
+        (
+            "search.semantic.index_embeddings",
+            &old_semantic.index_embeddings,
+            &new_semantic.index_embeddings,
+        ),
+        (
+            "search.semantic.index_fts",
+            &old_semantic.index_fts,
+            &new_semantic.index_fts,
+        ),
+        (
+            "search.semantic.max_table_backups",
+            &old_semantic.max_table_backups,
+            &new_semantic.max_table_backups,
+        ),
+        (
+            "search.semantic.serve_previous_model",
+            &old_semantic.serve_previous_model,
+            &new_semantic.serve_previous_model,
+        ),
+        (
+            "search.semantic.table",
+            &old_semantic.table,
+            &new_semantic.table,
+        ),
+        (
+            "search.semantic.extra_roots",
+            &old_semantic.extra_roots,
+            &new_semantic.extra_roots,
+        ),
+        (
+            "search.semantic.embedding_text_template",
+            &old_semantic.embedding_text_template,
+            &new_semantic.embedding_text_template,
+        ),
+        (
+            "search.semantic.strip_sections",
+            &old_semantic.strip_sections,
+            &new_semantic.strip_sections,
+        ),
+        (
+            "search.semantic.search_params",
+            &old_semantic.search_params,
+            &new_semantic.search_params,
+        ),
+        (
+            "search.fuzzy.lsp_server",
+            &old_fuzzy.lsp_server,
+            &new_fuzzy.lsp_server,
+        ),
+        (
+            "search.fuzzy.server_args",
+            &old_fuzzy.server_args,
+            &new_fuzzy.server_args,
+        ),
+        (
+            "search.fuzzy.workspace_uri",
+            &old_fuzzy.workspace_uri,
+            &new_fuzzy.workspace_uri,
+        ),
+        (
+            "search.fuzzy.server_options",
+            &old_fuzzy.server_options,
+            &new_fuzzy.server_options,
+        ),
+        (
+            "search.fuzzy.parallelizm",
+            &old_fuzzy.parallelizm,
+            &new_fuzzy.parallelizm,
+        ),
+        (
+            "search.fuzzy.readiness_tokens",
+            &old_fuzzy.readiness_tokens,
+            &new_fuzzy.readiness_tokens,
+        ),
+        (
+            "search.fuzzy.readiness_mode",
+            &old_fuzzy.readiness_mode,
+            &new_fuzzy.readiness_mode,
+        ),
+        (
+            "search.fuzzy.hover_cache",
+            &old_fuzzy.hover_cache,
+            &new_fuzzy.hover_cache,
+        ),
+        (
+            "search.fuzzy.document_symbols_cache_capacity",
+            &old_fuzzy.document_symbols_cache_capacity,
+            &new_fuzzy.document_symbols_cache_capacity,
+        ),
+        (
+            "search.fuzzy.document_symbols_timeout_ms",
+            &old_fuzzy.document_symbols_timeout_ms,
+            &new_fuzzy.document_symbols_timeout_ms,
+        ),
+        (
+            "search.fuzzy.request_timeout_ms",
+            &old_fuzzy.request_timeout_ms,
+            &new_fuzzy.request_timeout_ms,
+        ),
+    ];
+    let mut changes = values
+        .iter()
+        .filter(|(_, old, new)| format!("{:?}", old) != format!("{:?}", new))
+        .map(|(name, _, _)| *name)
+        .collect::<Vec<_>>();
+    // HashMap is compared by value, its debug output depends on the order of the keys
+    if old.tools != new.tools {
+        changes.push("tools");
+    }
+    changes
+}
+
+pub fn load_config(path: &str, args: &Args) -> Result<McpConfig> {
+    let config = read_config(path, args)?;
+    validate_config(&config)?;
+    Ok(config)
+}
+
+/// Config merged from the files and the overrides without the checks of [`load_config`]
+pub fn read_config(path: &str, args: &Args) -> Result<McpConfig> {
+    info!("Loading configuration from file: {}", path);
+
+    // Later sources take precedence: command line flags > environment variables > file > the
+    // files it extends
+    let mut builder = Config::builder();
+    for layer in config_layers(path, args)? {
+        let layer = layer.to_string_lossy();
+        builder = builder.add_source(File::new(&layer, config_format(&layer, args)?));
+    }
+    let config = builder
+        .add_source(
+            Environment::default()
+                .prefix(&NAME.to_uppercase())
+                .separator("_")
+                .ignore_empty(true),
+        )
+        .build()
+        .into_diagnostic()?;
+
+    let mut app_config: McpConfig = config
+        .try_deserialize()
+        .inspect_err(|e| error!("Failed to deserialize configuration: {}", e))
+        .into_diagnostic()?;
+    expand_paths(&mut app_config)?;
+    apply_args(&mut app_config, args)?;
+
+    log_merged_config(&app_config);
+
+    Ok(app_config)
+}
+
+/// Overrides the values of the config by the flags of the arguments, then replaces the
+/// `${workspace}` placeholder of `lancedb_store` by the resulting workspace path
+fn apply_args(config: &mut McpConfig, args: &Args) -> Result<()> {
+    if let Some(workspace) = &args.workspace {
+        config.search.fuzzy.workspace_uri = workspace_uri(workspace)?;
+    }
+    if let Some(model) = &args.model {
+        config.search.semantic.model = model.clone();
+    }
+    if args.debug {
+        config.debug = true;
+    }
+    if args.no_backup {
+        config.search.semantic.max_table_backups = 0;
+    }
+    if let Some(log_dir) = &args.log_dir {
+        config.log_dir = log_dir.clone();
+    }
+    if let Some(log_level) = &args.log_level {
+        config.logging.level = log_level.clone();
+    }
+    if let Ok(url) = Url::parse(&config.search.fuzzy.workspace_uri)
+        && let Ok(workspace) = url.to_file_path()
+    {
+        config.search.semantic.lancedb_store = config
+            .search
+            .semantic
+            .lancedb_store
+            .replace(WORKSPACE_PLACEHOLDER, &workspace.to_string_lossy());
+    }
+    Ok(())
+}
+
+/// Logs the config merged from the files and the overrides without the auth token, the config
+/// loaded before the logger is initialized is logged by [`init_logger`] again
+fn log_merged_config(config: &McpConfig) {
+    let mut config = config.clone();
+    if config.auth.token.is_some() {
+        config.auth.token = Some("***".to_string());
+    }
+    debug!("Merged configuration: {:?}", config);
+}
+
+/// Config files of the `extends` chain from the base one to the given one, relative `extends`
+/// paths are resolved against the directory of the extending file
+fn config_layers(path: &str, args: &Args) -> Result<Vec<PathBuf>> {
+    let mut layers: Vec<PathBuf> = Vec::new();
+    let mut next = Some(PathBuf::from(path));
+    while let Some(path) = next {
+        let canonical = std::fs::canonicalize(&path).map_err(|e| {
+            SemantrixError::config(format!(
+                "Config file {} can't be read: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        if layers.contains(&canonical) {
+            return Err(SemantrixError::config(format!(
+                "Config files extend each other in a cycle: {}",
+                layers
+                    .iter()
+                    .chain([&canonical])
+                    .map(|it| it.display())
+                    .join(" -> ")
+            ))
+            .with_help("remove extends from one of the files")
+            .into());
+        }
+        let layer = canonical.to_string_lossy();
+        let extends = Config::builder()
+            .add_source(File::new(&layer, config_format(&layer, args)?))
+            .build()
+            .into_diagnostic()?
+            .get_string("extends")
+            .ok();
+        next = extends.map(|extends| canonical.parent().unwrap_or(Path::new(".")).join(extends));
+        layers.push(canonical);
+    }
+    layers.reverse();
+    Ok(layers)
+}
+
+/// Expands the home directory and the environment variables of the path values
+fn expand_paths(config: &mut McpConfig) -> Result<()> {
+    let expand_path_buf = |key: &str, path: &PathBuf| -> Result<PathBuf> {
+        expand_path(key, &path.to_string_lossy()).map(PathBuf::from)
+    };
+    let semantic = &mut config.search.semantic;
+    semantic.models_dir = expand_path_buf("search.semantic.models_dir", &semantic.models_dir)?;
+    semantic.lancedb_store = expand_path("search.semantic.lancedb_store", &semantic.lancedb_store)?;
+    config.search.fuzzy.workspace_uri = expand_path(
+        "search.fuzzy.workspace_uri",
+        &config.search.fuzzy.workspace_uri,
+    )?;
+    for (index, root) in semantic.extra_roots.iter_mut().enumerate() {
+        root.path = expand_path_buf(
+            &format!("search.semantic.extra_roots[{}].path", index),
+            &root.path,
+        )?;
+    }
+    for (index, workspace) in config.workspaces.iter_mut().enumerate() {
+        workspace.workspace_uri = expand_path(
+            &format!("workspaces[{}].workspace_uri", index),
+            &workspace.workspace_uri,
+        )?;
+    }
+    config.log_dir = expand_path_buf("log_dir", &config.log_dir)?;
+    config.rules = expand_path_buf("rules", &config.rules)?;
+    if let Some(query_expansion) = config.query_expansion.as_mut() {
+        query_expansion.file = expand_path_buf("query_expansion.file", &query_expansion.file)?;
+    }
+    config.templates.templates_path =
+        expand_path("templates.templates_path", &config.templates.templates_path)?;
+    Ok(())
+}
+
+/// Path with the leading `~` replaced by the home directory and `${VAR}` or `$VAR` replaced by
+/// the environment variables, the workspace placeholder is kept as is
+fn expand_path(key: &str, path: &str) -> Result<String> {
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            let home = std::env::var("HOME")
+                .or_else(|_| std::env::var("USERPROFILE"))
+                .map_err(|_| {
+                    SemantrixError::config(format!(
+                        "{} starts with ~, but the home directory is unknown: {}",
+                        key, path
+                    ))
+                    .with_help("set the HOME environment variable or use an absolute path")
+                })?;
+            format!("{}{}", home, rest)
+        }
+        _ => path.to_string(),
+    };
+
+    let variable = Regex::new(r"\$\{(\w+)\}|\$(\w+)").into_diagnostic()?;
+    let mut unset = Vec::new();
+    let expanded = variable.replace_all(&path, |captures: &Captures| {
+        if &captures[0] == WORKSPACE_PLACEHOLDER {
+            return captures[0].to_string();
+        }
+        let name = captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .map_or("", |it| it.as_str());
+        std::env::var(name).unwrap_or_else(|_| {
+            unset.push(name.to_string());
+            String::new()
+        })
+    });
+    if !unset.is_empty() {
+        return Err(SemantrixError::config(format!(
+            "{} references unset environment variables {}: {}",
+            key,
+            unset.join(", "),
+            path
+        ))
+        .with_help("set the environment variables or remove them from the value")
+        .into());
+    }
+    Ok(expanded.into_owned())
+}
+
+/// URL of the workspace given as a URL or as a path
+fn workspace_uri(workspace: &str) -> Result<String> {
+    if let Ok(url) = Url::parse(workspace)
+        && (url.scheme() != "file" || url.to_file_path().is_ok())
+    {
+        return Ok(url.to_string());
+    }
+    let path = std::path::absolute(workspace).into_diagnostic()?;
+    Url::from_directory_path(&path)
+        .map(|url| url.to_string())
+        .map_err(|_| {
+            SemantrixError::config(format!("Invalid workspace path: {}", path.display())).into()
+        })
+}
+
+/// Checks the values which otherwise fail later in the subsystems, every problem is reported
+/// with the key path of the offending value
+pub(crate) fn validate_config(config: &McpConfig) -> Result<()> {
+    let mut problems = Vec::new();
+    let semantic = &config.search.semantic;
+    let fuzzy = &config.search.fuzzy;
+
+    let positive: [(&str, usize); 19] = [
+        ("channel_size", config.channel_size),
+        (
+            "watcher.anomaly_factor",
+            config.watcher.anomaly_factor as usize,
+        ),
+        ("tool_concurrency", config.tool_concurrency),
+        ("tool_timeout_ms", config.tool_timeout_ms as usize),
+        ("search.semantic.chunk_size", semantic.chunk_size),
+        ("search.semantic.batch_size", semantic.batch_size),
+        ("search.semantic.search_limit", semantic.search_limit),
+        (
+            "search.semantic.duplicates_max_chunks",
+            semantic.duplicates_max_chunks,
+        ),
+        (
+            "search.semantic.duplicates_neighbors",
+            semantic.duplicates_neighbors,
+        ),
+        ("search.fuzzy.parallelizm", fuzzy.parallelizm),
+        ("search.fuzzy.empty_query_limit", fuzzy.empty_query_limit),
+        ("search.fuzzy.fuzzy_limit", fuzzy.fuzzy_limit),
+        ("search.fuzzy.references_limit", fuzzy.references_limit),
+        (
+            "search.semantic.custom_model.dims",
+            semantic.custom_model.as_ref().map_or(1, |it| it.dims),
+        ),
+        (
+            "memory.chunk_bytes_in_flight",
+            config.memory.chunk_bytes_in_flight,
+        ),
+        (
+            "memory.max_symbol_code_bytes",
+            config.memory.max_symbol_code_bytes,
+        ),
+        ("scratch.ttl_sec", config.scratch.ttl_sec as usize),
+        ("scratch.max_bytes", config.scratch.max_bytes),
+        (
+            "search.git_metadata_budget_ms",
+            config.search.git_metadata_budget_ms as usize,
+        ),
+    ];
+    problems.extend(
+        positive
+            .into_iter()
+            .filter(|(_, value)| *value == 0)
+            .map(|(key, _)| format!("{} must be greater than 0", key)),
+    );
+
+    if config.logging.max_files == 0 {
+        problems.push("logging.max_files must be greater than 0".to_string());
+    }
+    if let Err(e) = EnvFilter::try_new(&config.logging.level) {
+        problems.push(format!(
+            "logging.level is not a valid level or filter: {}: {}",
+            config.logging.level, e
+        ));
+    }
+
+    if semantic.chunk_size > 0 && semantic.overlap_size > semantic.chunk_size - 1 {
+        problems.push(format!(
+            "search.semantic.overlap_size must be less or equal to chunk_size - 1, but got {} > {}",
+            semantic.overlap_size, semantic.chunk_size
+        ));
+    }
+
+    if let Some(proxy) = &semantic.proxy
+        && let Err(e) = Url::parse(proxy)
+    {
+        problems.push(format!(
+            "search.semantic.proxy is not a valid URL: {}: {}",
+            proxy, e
+        ));
+    }
+
+    let search_params = &semantic.search_params;
+    if search_params.nprobes == Some(0) {
+        problems.push("search.semantic.search_params.nprobes must be greater than 0".to_string());
+    }
+    if search_params.refine_factor == Some(0) {
+        problems
+            .push("search.semantic.search_params.refine_factor must be greater than 0".to_string());
+    }
+    if fuzzy.document_symbols_timeout_ms == Some(0) {
+        problems
+            .push("search.fuzzy.document_symbols_timeout_ms must be greater than 0".to_string());
+    }
+    if fuzzy.request_timeout_ms == Some(0) {
+        problems.push("search.fuzzy.request_timeout_ms must be greater than 0".to_string());
+    }
+    if fuzzy.max_hovers == Some(0) {
+        problems.push("search.fuzzy.max_hovers must be greater than 0".to_string());
+    }
+    if fuzzy.hover_budget_ms == Some(0) {
+        problems.push("search.fuzzy.hover_budget_ms must be greater than 0".to_string());
+    }
+    if config.placer.max_symbols == Some(0) {
+        problems.push("placer.max_symbols must be greater than 0".to_string());
+    }
+    if config.placer.budget_ms == Some(0) {
+        problems.push("placer.budget_ms must be greater than 0".to_string());
+    }
+    if config.placer.reference_concurrency == Some(0) {
+        problems.push("placer.reference_concurrency must be greater than 0".to_string());
+    }
+    if !(0.0..=1.0).contains(&semantic.merge_overlap) {
+        problems.push(format!(
+            "search.semantic.merge_overlap must be between 0 and 1, but got {}",
+            semantic.merge_overlap
+        ));
+    }
+    if config.search.max_results_per_file == Some(0) {
+        problems.push("search.max_results_per_file must be greater than 0".to_string());
+    }
+    if config.search.response_budget_chars == Some(0) {
+        problems.push("search.response_budget_chars must be greater than 0".to_string());
+    }
+    problems.extend(
+        config
+            .search
+            .kind_weights
+            .iter()
+            .filter(|(_, weight)| !weight.is_finite() || **weight <= 0.0)
+            .map(|(kind, weight)| {
+                format!(
+                    "search.kind_weights.{} must be greater than 0, but got {}",
+                    kind, weight
+                )
+            }),
+    );
+
+    let table = &semantic.table;
+    let identifier = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").into_diagnostic()?;
+    problems.extend(
+        [
+            ("name", &table.name),
+            ("id_field", &table.id_field),
+            ("path_field", &table.path_field),
+            ("start_line_field", &table.start_line_field),
+            ("end_line_field", &table.end_line_field),
+            ("embedding_field", &table.embedding_field),
+            ("text_field", &table.text_field),
+            ("hash_field", &table.hash_field),
+            ("indexed_at_field", &table.indexed_at_field),
+            ("chunk_index_field", &table.chunk_index_field),
+            ("total_chunks_field", &table.total_chunks_field),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !identifier.is_match(value))
+            .map(|(key, value)| {
+                format!(
+                    "search.semantic.table.{} must be a letter or underscore followed by letters, digits or underscores, but got {:?}",
+                    key, value
+                )
+            }),
+    );
+    if !table.fields().iter().all_unique() {
+        problems.push(format!(
+            "search.semantic.table field names must be distinct, but got {:?}",
+            table.fields()
+        ));
+    }
+
+    if let Err(e) = Glob::new(&semantic.pattern) {
+        problems.push(format!(
+            "search.semantic.pattern is not a valid glob: {}: {}",
+            semantic.pattern, e
+        ));
+    }
+    for (index, route) in semantic.model_routes.iter().enumerate() {
+        if let Err(e) = Glob::new(&route.pattern) {
+            problems.push(format!(
+                "search.semantic.model_routes[{}].pattern is not a valid glob: {}: {}",
+                index, route.pattern, e
+            ));
+        }
+        if route.model != STUB_MODEL
+            && let Err(e) = model_from_str(&route.model)
+        {
+            problems.push(format!(
+                "search.semantic.model_routes[{}].model is not a catalog model: {}",
+                index, e
+            ));
+        }
+    }
+    let workspace_paths = std::iter::once(&fuzzy.workspace_uri)
+        .chain(config.workspaces.iter().map(|it| &it.workspace_uri))
+        .filter_map(|uri| Url::parse(uri).ok()?.to_file_path().ok())
+        .collect_vec();
+    for (index, root) in semantic.extra_roots.iter().enumerate() {
+        if !root.path.is_absolute() || !root.path.is_dir() {
+            problems.push(format!(
+                "search.semantic.extra_roots[{}].path must be an absolute path of an existing directory, but got {}",
+                index,
+                root.path.display()
+            ));
+        }
+        // the files of a root inside a workspace would be chunked twice
+        if let Some(workspace) = workspace_paths
+            .iter()
+            .find(|it| root.path.starts_with(it) || it.starts_with(&root.path))
+        {
+            problems.push(format!(
+                "search.semantic.extra_roots[{}].path {} overlaps workspace {}",
+                index,
+                root.path.display(),
+                workspace.display()
+            ));
+        }
+        if let Some(pattern) = &root.pattern
+            && let Err(e) = Glob::new(pattern)
+        {
+            problems.push(format!(
+                "search.semantic.extra_roots[{}].pattern is not a valid glob: {}: {}",
+                index, pattern, e
+            ));
+        }
+    }
+    for (index, glob) in semantic.exclude_globs.iter().enumerate() {
+        if let Err(e) = Glob::new(glob) {
+            problems.push(format!(
+                "search.semantic.exclude_globs[{}] is not a valid glob: {}: {}",
+                index, glob, e
+            ));
+        }
+    }
+    if let Some(template) = &semantic.embedding_text_template {
+        let mut chunk = TextChunk::new(Arc::new(PathBuf::from("src/sample.rs")), 0, 2);
+        chunk.push_line("fn sample() {".to_string());
+        chunk.push_line("}".to_string());
+        if let Err(e) =
+            embedding_text_tera(template).and_then(|tera| chunk.embedding_text(&tera, None))
+        {
+            problems.push(format!(
+                "search.semantic.embedding_text_template fails to render a sample chunk: {:?}",
+                e
+            ));
+        }
+    }
+    for (index, section) in semantic.strip_sections.iter().enumerate() {
+        if let Err(e) = Regex::new(&section.pattern) {
+            problems.push(format!(
+                "search.semantic.strip_sections[{}].pattern is not a valid regex: {}: {}",
+                index, section.pattern, e
+            ));
+        }
+    }
+    for (index, pattern) in config.placer.ignore_reference_globs.iter().enumerate() {
+        if let Err(e) = Glob::new(pattern) {
+            problems.push(format!(
+                "placer.ignore_reference_globs[{}] is not a valid glob: {}: {}",
+                index, pattern, e
+            ));
+        }
+    }
+
+    problems.extend(workspace_uri_problem(
+        "search.fuzzy.workspace_uri",
+        &fuzzy.workspace_uri,
+    ));
+    let workspace_name = Regex::new(r"^[A-Za-z0-9_]+$").into_diagnostic()?;
+    for (index, workspace) in config.workspaces.iter().enumerate() {
+        if !workspace_name.is_match(&workspace.name) {
+            problems.push(format!(
+                "workspaces[{}].name must be letters, digits or underscores, but got {:?}",
+                index, workspace.name
+            ));
+        }
+        problems.extend(workspace_uri_problem(
+            &format!("workspaces[{}].workspace_uri", index),
+            &workspace.workspace_uri,
+        ));
+        if let Some(pattern) = &workspace.pattern
+            && let Err(e) = Glob::new(pattern)
+        {
+            problems.push(format!(
+                "workspaces[{}].pattern is not a valid glob: {}: {}",
+                index, pattern, e
+            ));
+        }
+    }
+    if !config.workspaces.iter().map(|it| &it.name).all_unique() {
+        problems.push(format!(
+            "workspaces names must be distinct, but got {:?}",
+            config.workspaces.iter().map(|it| &it.name).collect_vec()
+        ));
+    }
+
+    // Remote stores like s3:// are checked by lancedb itself
+    if let Some((scheme, _)) = semantic.lancedb_store.split_once("://") {
+        if !STORE_SCHEMES.contains(&scheme) {
+            problems.push(format!(
+                "search.semantic.lancedb_store has unsupported scheme {}, expected one of {}",
+                scheme,
+                STORE_SCHEMES.join(", ")
+            ));
+        }
+    } else if PathBuf::from(&semantic.lancedb_store).is_file() {
+        problems.push(format!(
+            "search.semantic.lancedb_store must be a directory, but it is a file: {}",
+            semantic.lancedb_store
+        ));
+    }
+
+    let templates_dir = templates_dir(&config.templates.templates_path);
+    if !templates_dir.is_dir() {
+        problems.push(format!(
+            "templates.templates_path has no existing directory: {}",
+            templates_dir.display()
+        ));
+    }
+    if !config.rules.is_file() {
+        problems.push(format!(
+            "rules is not an existing file: {}",
+            config.rules.display()
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(SemantrixError::config(format!(
+            "Invalid configuration:\n  - {}",
+            problems.join("\n  - ")
+        ))
+        .with_help("fix the listed values in the config file or the environment variables")
+        .into())
+    }
+}
+
+/// Problem of the workspace URL of the config key, it must be a readable directory
+fn workspace_uri_problem(key: &str, uri: &str) -> Option<String> {
+    match Url::parse(uri) {
+        Ok(url) if url.scheme() != "file" => {
+            Some(format!("{} must be a file URL, but got {}", key, url))
+        }
+        Ok(url) => match url.to_file_path() {
+            Ok(path) if !path.is_dir() => Some(format!(
+                "{} is not an existing directory: {}",
+                key,
+                path.display()
+            )),
+            Ok(path) => std::fs::read_dir(&path).err().map(|e| {
+                format!(
+                    "{} is not a readable directory: {}: {}",
+                    key,
+                    path.display(),
+                    e
+                )
+            }),
+            Err(_) => Some(format!("{} is not a valid file URL: {}", key, url)),
+        },
+        Err(e) => Some(format!("{} is not a valid URL: {}: {}", key, uri, e)),
+    }
+}
+
+/// Directory part of the templates glob before its first wildcard
+fn templates_dir(templates_path: &str) -> PathBuf {
+    let literal = templates_path
+        .split(['*', '?', '[', '{'])
+        .next()
+        .unwrap_or_default();
+    match literal.rfind('/') {
+        Some(end) => PathBuf::from(&literal[..end.max(1)]),
+        None => PathBuf::from("."),
+    }
+}
+
+/// Installs the logger and the panic hook of the config loaded by [`init_config`], so the
+/// command line overrides of the logging are applied
+pub fn init_logger(config: &McpConfig) -> Result<Vec<WorkerGuard>> {
+    let logging = &config.logging;
+    let time_format = format_description::parse_borrowed::<2>(
+        "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z",
+    )
+    .into_diagnostic()?;
+    let timer = UtcTime::new(time_format);
+
+    ensure_dir("log_dir", &config.log_dir, config.create_dirs)?;
+    let file_appender = rolling_appender(config, NAME, logging.max_files)?;
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+    let mut guards = vec![guard];
+
+    let (filter, skipped_directives) = log_filter(logging)?;
+    let console_filter = if !config.debug {
+        Some(log_filter(logging)?.0)
+    } else {
+        None
+    };
+    let file_filter = (!config.debug).then_some(filter);
+
+    let stderr_layer = if logging.stderr.unwrap_or(config.debug) {
+        Some(
+            fmt_layer(
+                logging.stderr_format.unwrap_or(LogFormat::Pretty),
+                timer.clone(),
+                std::io::stderr.with_max_level(Level::DEBUG),
+            )
+            .with_filter(console_filter),
+        )
+    } else {
+        None
+    };
+
+    let file_layer = fmt_layer(
+        logging.format,
+        timer.clone(),
+        non_blocking.with_max_level(Level::DEBUG),
+    )
+    .with_filter(file_filter);
+
+    let audit_layer = if logging.audit.enabled {
+        let audit_appender =
+            rolling_appender(config, &format!("{}-audit", NAME), logging.audit.max_files)?;
+        let (audit_non_blocking, audit_guard) = tracing_appender::non_blocking(audit_appender);
+        guards.push(audit_guard);
+        Some(
+            fmt_layer(LogFormat::Json, timer.clone(), audit_non_blocking)
+                .with_filter(Targets::new().with_target(AUDIT_TARGET, Level::INFO)),
+        )
+    } else {
+        None
+    };
+
+    let tokio_console_layer = if logging.tokio_console.unwrap_or(config.debug) {
+        Some(console_subscriber::spawn())
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(stderr_layer)
+        .with(file_layer)
+        .with(audit_layer)
+        .with(tokio_console_layer)
+        .init();
+
+    info!("Tracing initialized successfully");
+    log_merged_config(config);
+    for directive in skipped_directives {
+        warn!("Skipped invalid log directive {}", directive);
+    }
+
+    panic::set_hook(Box::new(|info| {
+        error!("Panic occurred: {}", info);
+        error!("Backtrace:\n{:?}", Backtrace::force_capture());
+    }));
+
+    info!("Configuration loaded successfully: {:#?}", config);
+
+    Ok(guards)
+}
+
+/// Rolling appender of the log files with the prefix in `log_dir`
+fn rolling_appender(
+    config: &McpConfig,
+    prefix: &str,
+    max_files: usize,
+) -> Result<RollingFileAppender> {
+    RollingFileAppender::builder()
+        .rotation(config.logging.rotation.into())
+        .filename_prefix(prefix)
+        .filename_suffix("log")
+        .max_log_files(max_files)
+        .build(config.log_dir.clone())
+        .map_err(|e| {
+            SemantrixError::io(
+                &config.log_dir,
+                format!(
+                    "Failed to create log file appender in {:?}: {}",
+                    config.log_dir, e
+                ),
+            )
+            .with_help("check the permissions of the directory or change log_dir")
+            .into()
+        })
+}
+
+/// Filter of the configured level and directives followed by the `RUST_LOG` ones, the invalid
+/// directives are skipped and returned to be logged after the logger is initialized
+fn log_filter(logging: &LoggingConfig) -> Result<(EnvFilter, Vec<String>)> {
+    let rust_log = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+    // the audit records go to their own files only
+    let mut filter = EnvFilter::try_new(&logging.level)
+        .into_diagnostic()?
+        .add_directive(format!("{}=off", AUDIT_TARGET).parse().into_diagnostic()?);
+    let mut skipped = Vec::new();
+    for directive in logging
+        .directives
+        .iter()
+        .map(String::as_str)
+        .chain(rust_log.split(',').filter(|it| !it.trim().is_empty()))
+    {
+        match directive.trim().parse() {
+            Ok(directive) => filter = filter.add_directive(directive),
+            Err(e) => skipped.push(format!("{}: {}", directive, e)),
+        }
+    }
+    Ok((filter, skipped))
+}
+
+/// Log layer of the format with the common fields
+fn fmt_layer<S, T, W>(format: LogFormat, timer: T, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    T: FormatTime + Send + Sync + 'static,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
+        .with_ansi(false)
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_timer(timer)
+        .with_writer(writer);
+    match format {
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// Accepted names of the embedding models in the configuration
+const MODEL_NAMES: [(&str, EmbeddingModel); 28] = [
+    ("all-mini-lm-l6-v2", EmbeddingModel::AllMiniLML6V2),
+    ("all-mini-lm-l6-v2-q", EmbeddingModel::AllMiniLML6V2Q),
+    ("all-mini-lm-l12-v2", EmbeddingModel::AllMiniLML12V2),
+    ("all-mini-lm-l12-v2-q", EmbeddingModel::AllMiniLML12V2Q),
+    ("bge-base-en-v1.5", EmbeddingModel::BGEBaseENV15),
+    ("bge-base-en-v1.5-q", EmbeddingModel::BGEBaseENV15Q),
+    ("bge-large-en-v1.5", EmbeddingModel::BGELargeENV15),
+    ("bge-large-en-v1.5-q", EmbeddingModel::BGELargeENV15Q),
+    ("bge-small-en-v1.5", EmbeddingModel::BGESmallENV15),
+    ("bge-small-en-v1.5-q", EmbeddingModel::BGESmallENV15Q),
+    ("nomic-embed-text-v1", EmbeddingModel::NomicEmbedTextV1),
+    ("nomic-embed-text-v1.5", EmbeddingModel::NomicEmbedTextV15),
+    (
+        "nomic-embed-text-v1.5-q",
+        EmbeddingModel::NomicEmbedTextV15Q,
+    ),
+    (
+        "paraphrase-mini-lm-l12-v2",
+        EmbeddingModel::ParaphraseMLMiniLML12V2,
+    ),
+    (
+        "paraphrase-mini-lm-l12-v2-q",
+        EmbeddingModel::ParaphraseMLMiniLML12V2Q,
+    ),
+    (
+        "paraphrase-mpnet-base-v2",
+        EmbeddingModel::ParaphraseMLMpnetBaseV2,
+    ),
+    ("bge-small-zh-v1.5", EmbeddingModel::BGESmallZHV15),
+    ("multilingual-e5-small", EmbeddingModel::MultilingualE5Small),
+    ("multilingual-e5-base", EmbeddingModel::MultilingualE5Base),
+    ("multilingual-e5-large", EmbeddingModel::MultilingualE5Large),
+    ("mxbai-embed-large-v1", EmbeddingModel::MxbaiEmbedLargeV1),
+    ("mxbai-embed-large-v1-q", EmbeddingModel::MxbaiEmbedLargeV1Q),
+    ("gte-base-en-v1.5", EmbeddingModel::GTEBaseENV15),
+    ("gte-base-en-v1.5-q", EmbeddingModel::GTEBaseENV15Q),
+    ("gte-large-en-v1.5", EmbeddingModel::GTELargeENV15),
+    ("gte-large-en-v1.5-q", EmbeddingModel::GTELargeENV15Q),
+    ("clip-vit-b-32-text", EmbeddingModel::ClipVitB32),
+    (
+        "jina-embeddings-v2-base-code",
+        EmbeddingModel::JinaEmbeddingsV2BaseCode,
+    ),
+];
+
+/// Model of the configured name, an unknown name fails with the closest accepted one
+pub fn model_from_str(value: &str) -> Result<EmbeddingModel> {
+    if let Some((_, model)) = MODEL_NAMES.iter().find(|(name, _)| *name == value) {
+        return Ok(model.clone());
+    }
+    let closest = MODEL_NAMES
+        .iter()
+        .map(|(name, _)| *name)
+        .min_by_key(|name| edit_distance(value, name))
+        .unwrap_or_default();
+    Err(
+        SemantrixError::config(format!("Unknown embedding model: {}", value))
+            .with_help(format!(
+                "did you mean `{}`? Accepted names: {}",
+                closest,
+                MODEL_NAMES.iter().map(|(name, _)| *name).join(", ")
+            ))
+            .into(),
+    )
+}
+
+/// Embedding model of the fastembed catalog accepted by `search.semantic.model`
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelSummary {
+    pub name: &'static str,
+    pub dims: usize,
+    pub description: String,
+    /// Quantized variant of a model, smaller and faster for a bit of precision
+    pub quantized: bool,
+    /// The model of `search.semantic.model`, never set if a custom model is configured
+    pub configured: bool,
+    /// Every file of the model is in `models_dir`, so it is loaded without a download
+    pub local: bool,
+}
+
+/// Models of the catalog in the order of their names with their dimensions and whether their
+/// files are found in `models_dir`
+pub fn list_models(semantic: &SemanticConfig) -> Vec<ModelSummary> {
+    MODEL_NAMES
+        .iter()
+        .filter_map(|(name, model)| {
+            let model_info = TextEmbedding::get_model_info(model)
+                .inspect_err(|e| warn!("Failed to get model info for model {}: {}", name, e))
+                .ok()?;
+            Some(ModelSummary {
+                name,
+                dims: model_info.dim,
+                description: model_info.description.clone(),
+                quantized: name.ends_with("-q"),
+                configured: semantic.custom_model.is_none() && semantic.model == *name,
+                local: has_model_files(semantic, model, model_info),
+            })
+        })
+        .collect()
+}
+
+/// True if every file of the model is in the local directory of the model or in the Hugging Face
+/// cache of `models_dir` if the model is downloaded
+fn has_model_files(
+    semantic: &SemanticConfig,
+    model: &EmbeddingModel,
+    model_info: &ModelInfo<EmbeddingModel>,
+) -> bool {
+    let files = model_files(model_info);
+    if semantic.download_model {
+        let cache_repo = Cache::new(semantic.models_dir.clone()).model(model.to_string());
+        files.iter().all(|file| cache_repo.get(file).is_some())
+    } else {
+        let model_dir = semantic.models_dir.join(model.to_string());
+        files.iter().all(|file| model_dir.join(file).is_file())
+    }
+}
+
+pub fn retrieve_model(model: EmbeddingModel, cache_dir: PathBuf) -> Result<ApiRepo> {
+    let cache = Cache::new(cache_dir);
+    // progress bars would be written into the stdio transport, the progress is logged instead
+    let api = ApiBuilder::from_cache(cache)
+        .with_progress(false)
+        .build()
+        .into_diagnostic()?;
+
+    let model_id = model.to_string();
+    info!("Retrieving model from Hugging Face: {}", model_id);
+    let repo = api.model(model_id);
+    Ok(repo)
+}
+
+/// Exports the configured proxy of the model downloads, the HTTP client of hf-hub reads it from
+/// the environment only
+pub fn export_download_proxy(semantic: &SemanticConfig) {
+    if let Some(proxy) = &semantic.proxy {
+        info!("Using proxy {} for the model downloads", proxy);
+        for var in ["HTTPS_PROXY", "HTTP_PROXY"] {
+            // SAFETY: called at startup before the subsystems which may read the environment
+            unsafe { std::env::set_var(var, proxy) };
+        }
+    }
+}
+
+/// Logs the download progress of one file every `DOWNLOAD_PROGRESS_STEP` percent, the clones
+/// share the counter as the file is downloaded by parallel chunks
+#[derive(Clone, Default)]
+struct DownloadProgress {
+    filename: Arc<std::sync::Mutex<String>>,
+    size: Arc<AtomicUsize>,
+    downloaded: Arc<AtomicUsize>,
+}
+
+const DOWNLOAD_PROGRESS_STEP: usize = 10;
+
+impl Progress for DownloadProgress {
+    async fn init(&mut self, size: usize, filename: &str) {
+        info!("Downloading {} ({} bytes)", filename, size);
+        if let Ok(mut name) = self.filename.lock() {
+            *name = filename.to_string();
+        }
+        self.size.store(size, Ordering::Relaxed);
+    }
+
+    async fn update(&mut self, size: usize) {
+        let total = self.size.load(Ordering::Relaxed).max(1);
+        let downloaded = self.downloaded.fetch_add(size, Ordering::Relaxed) + size;
+        let percent = downloaded * 100 / total;
+        if (downloaded - size) * 100 / total / DOWNLOAD_PROGRESS_STEP
+            != percent / DOWNLOAD_PROGRESS_STEP
+        {
+            let filename = self
+                .filename
+                .lock()
+                .map(|it| it.clone())
+                .unwrap_or_default();
+            info!(
+                "Downloaded {}% of {} ({} of {} bytes)",
+                percent.min(100),
+                filename,
+                downloaded,
+                total
+            );
+        }
+    }
+
+    async fn finish(&mut self) {
+        let filename = self
+            .filename
+            .lock()
+            .map(|it| it.clone())
+            .unwrap_or_default();
+        info!("Downloaded {}", filename);
+    }
+}
+
+/// Paths of the model files, the ones missing in the cache are downloaded with the progress
+async fn download_model_files(
+    model: &EmbeddingModel,
+    files: &[&str],
+    models_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    info!("Downloading model from Hugging Face to {:?}", models_dir);
+    let cache_repo = Cache::new(models_dir.to_path_buf()).model(model.to_string());
+    let model_repo = retrieve_model(model.to_owned(), models_dir.to_path_buf())?;
+    info!("Model repo: {:?}", model_repo);
+    let mut paths = Vec::new();
+    for file in files {
+        let mut path = match cache_repo.get(file) {
+            Some(path) => path,
+            None => download_model_file(&model_repo, file).await?,
+        };
+        if let Some(problem) = model_file_problem(&path) {
+            warn!(
+                "Model file {} {}, downloading it again",
+                path.display(),
+                problem
+            );
+            remove_cached_file(&path)?;
+            path = download_model_file(&model_repo, file).await?;
+            check_model_file(&path)?;
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+async fn download_model_file(model_repo: &ApiRepo, file: &str) -> Result<PathBuf> {
+    model_repo
+        .download_with_progress(file, DownloadProgress::default())
+        .await
+        .map_err(|e| {
+            SemantrixError::model_load(format!("Failed to download {}: {}", file, e)).into()
+        })
+}
+
+/// Problem of the model file which makes it unusable, as a truncated download. The sha256 is
+/// checked if the file is a blob of the hf-hub cache named by its sha256
+pub(crate) fn model_file_problem(path: &Path) -> Option<String> {
+    let size = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return Some(format!("can't be read: {}", e)),
+    };
+    if size == 0 {
+        return Some("is empty".to_string());
+    }
+    if path.extension().is_some_and(|it| it == "json") {
+        let parsed = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|it| serde_json::from_slice::<Value>(&it).map_err(|e| e.to_string()));
+        if let Err(e) = parsed {
+            return Some(format!("is not valid JSON: {}", e));
+        }
+    }
+    let blob = std::fs::canonicalize(path).ok()?;
+    let expected = blob.file_name()?.to_str()?;
+    if expected.len() != 64 || !expected.chars().all(|it| it.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    let hashed =
+        std::fs::File::open(&blob).and_then(|mut file| std::io::copy(&mut file, &mut hasher));
+    if let Err(e) = hashed {
+        return Some(format!("can't be read: {}", e));
+    }
+    let actual = format!("{:x}", hasher.finalize());
+    (actual != expected).then(|| format!("has sha256 {} instead of {}", actual, expected))
+}
+
+/// Fails with the problem of the model file, see [`model_file_problem`]
+fn check_model_file(path: &Path) -> Result<()> {
+    match model_file_problem(path) {
+        Some(problem) => {
+            Err(
+                SemantrixError::model_load(format!("Model file {} {}", path.display(), problem))
+                    .with_help("delete the file to download it again or copy a complete one")
+                    .into(),
+            )
+        }
+        None => Ok(()),
+    }
+}
+
+/// Removes the cached file with the blob it links to, so the next request downloads it
+fn remove_cached_file(path: &Path) -> Result<()> {
+    if let Ok(blob) = std::fs::canonicalize(path)
+        && blob != path
+    {
+        std::fs::remove_file(&blob).into_diagnostic()?;
+    }
+    std::fs::remove_file(path).into_diagnostic()
+}
+
+/// Paths of the model files in the cache of the previous downloads, fails with all missing ones
+fn cached_model_files(
+    model: &EmbeddingModel,
+    files: &[&str],
+    models_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    info!(
+        "Loading downloaded model from cache {:?} offline",
+        models_dir
+    );
+    let cache_repo = Cache::new(models_dir.to_path_buf()).model(model.to_string());
+    let (paths, missing): (Vec<_>, Vec<_>) = files
+        .iter()
+        .map(|file| cache_repo.get(file).ok_or(*file))
+        .partition_result();
+    if !missing.is_empty() {
+        return Err(SemantrixError::model_load(format!(
+            "Model files {} are missing in the cache {}",
+            missing.join(", "),
+            models_dir
+                .join(Repo::model(model.to_string()).folder_name())
+                .display()
+        ))
+        .with_help(
+            "download the model once without search.semantic.offline or copy the files into the cache",
+        )
+        .into());
+    }
+    for path in paths.iter() {
+        check_model_file(path)?;
+    }
+    Ok(paths)
+}
+
+pub async fn get_or_create_table(
+    db: &Connection,
+    ndims: usize,
+    semantic: &SemanticConfig,
+) -> Result<Table> {
+    let table_config = &semantic.table;
+    let table = if db
+        .table_names()
+        .execute()
+        .await
+        .into_diagnostic()?
+        .contains(&table_config.name)
+    {
+        let table = db
+            .open_table(&table_config.name)
+            .execute()
+            .await
+            .into_diagnostic()?;
+        let current_schema = table.schema().await.into_diagnostic()?;
+        info!("Schema: {:?}", current_schema);
+        let embedding_field = current_schema
+            .field_with_name(&table_config.embedding_field)
+            .into_diagnostic()?;
+        let reason = match embedding_field.data_type() {
+            DataType::FixedSizeList(_, dims) if *dims != ndims as i32 => Some(format!(
+                "embedding field data type size is not equal to ndims of current model: {} != {}",
+                *dims, ndims
+            )),
+            // the tables written before the text, its hash and the write time were stored are
+            // rebuilt to fill them
+            DataType::FixedSizeList(..)
+                if current_schema
+                    .field_with_name(&table_config.text_field)
+                    .is_err() =>
+            {
+                Some(format!(
+                    "table has no text field {}",
+                    table_config.text_field
+                ))
+            }
+            DataType::FixedSizeList(..)
+                if current_schema
+                    .field_with_name(&table_config.hash_field)
+                    .is_err() =>
+            {
+                Some(format!(
+                    "table has no hash field {}",
+                    table_config.hash_field
+                ))
+            }
+            DataType::FixedSizeList(..)
+                if current_schema
+                    .field_with_name(&table_config.indexed_at_field)
+                    .is_err() =>
+            {
+                Some(format!(
+                    "table has no indexed at field {}",
+                    table_config.indexed_at_field
+                ))
+            }
+            DataType::FixedSizeList(..)
+                if current_schema
+                    .field_with_name(&table_config.chunk_index_field)
+                    .is_err()
+                    || current_schema
+                        .field_with_name(&table_config.total_chunks_field)
+                        .is_err() =>
+            {
+                Some(format!(
+                    "table has no chunk index field {} or total chunks field {}",
+                    table_config.chunk_index_field, table_config.total_chunks_field
+                ))
+            }
+            DataType::FixedSizeList(..) => None,
+            data_type => {
+                return Err(SemantrixError::lancedb(format!(
+                    "Embedding field is not a FixedSizeList: {:?}",
+                    data_type
+                ))
+                .into());
+            }
+        };
+        let new_table = match reason {
+            Some(reason) => {
+                backup_and_drop_table(db, semantic, &reason).await?;
+                let new_schema = schema(ndims, table_config);
+                info!("Creating new table with schema: {:?}", new_schema);
+                Some(
+                    db.create_empty_table(&table_config.name, Arc::new(new_schema))
+                        .execute()
+                        .await
+                        .into_diagnostic()?,
+                )
+            }
+            None => None,
+        };
+        new_table.unwrap_or(table)
+    } else {
+        db.create_empty_table(&table_config.name, Arc::new(schema(ndims, table_config)))
+            .execute()
+            .await
+            .into_diagnostic()?
+    };
+
+    Ok(table)
+}
+
+/// Opens the table written by another process in the read-only mode, the table is neither
+/// created nor migrated, so a missing table or a table of other dimensions or another model is
+/// an error instead of a rebuild
+async fn open_table(
+    db: &Connection,
+    ndims: usize,
+    model_id: &str,
+    semantic: &SemanticConfig,
+) -> Result<Table> {
+    let table_config = &semantic.table;
+    if !db
+        .table_names()
+        .execute()
+        .await
+        .into_diagnostic()?
+        .contains(&table_config.name)
+    {
+        return Err(SemantrixError::config(format!(
+            "Table {} doesn't exist in the read-only mode",
+            table_config.name
+        ))
+        .with_help("start the indexing process of the store first or set read_only: false")
+        .into());
+    }
+    let table = db
+        .open_table(&table_config.name)
+        .execute()
+        .await
+        .into_diagnostic()?;
+    let schema = table.schema().await.into_diagnostic()?;
+    let embedding_field = schema
+        .field_with_name(&table_config.embedding_field)
+        .into_diagnostic()?;
+    match embedding_field.data_type() {
+        DataType::FixedSizeList(_, dims) if *dims == ndims as i32 => {}
+        data_type => {
+            return Err(SemantrixError::config(format!(
+                "Embedding field of read-only table {} is {:?}, not a list of {} dimensions of \
+                the current model",
+                table_config.name, data_type, ndims
+            ))
+            .into());
+        }
+    }
+    match indexed_model_id(semantic) {
+        Some(indexed_model_id) if indexed_model_id != model_id => {
+            Err(SemantrixError::config(format!(
+                "Read-only table {} is indexed by model {}, not by model {}",
+                table_config.name, indexed_model_id, model_id
+            ))
+            .into())
+        }
+        _ => Ok(table),
+    }
+}
+
+/// Copies the chunks table into a timestamped backup table before dropping it, only the
+/// newest `max_table_backups` backups are kept
+async fn backup_and_drop_table(
+    db: &Connection,
+    semantic: &SemanticConfig,
+    reason: &str,
+) -> Result<()> {
+    let table_config = &semantic.table;
+    let max_backups = semantic.max_table_backups;
+    if max_backups == 0 {
+        warn!("Dropping table without backup, {}", reason);
+    } else {
+        let table = db
+            .open_table(&table_config.name)
+            .execute()
+            .await
+            .into_diagnostic()?;
+        let rows = table.count_rows(None).await.into_diagnostic()?;
+        if rows == 0 {
+            info!("Dropping empty table, {}", reason);
+        } else {
+            let backup_prefix = table_config.backup_prefix();
+            let backup_name = format!("{}{}", backup_prefix, Utc::now().format("%Y%m%d%H%M%S"));
+            let schema = table.schema().await.into_diagnostic()?;
+            let batches = table
+                .query()
+                .execute()
+                .await
+                .into_diagnostic()?
+                .try_collect::<Vec<_>>()
+                .await
+                .into_diagnostic()?;
+            db.create_table(
+                &backup_name,
+                RecordBatchIterator::new(batches.into_iter().map(Ok), schema),
+            )
+            .execute()
+            .await
+            .into_diagnostic()?;
+            warn!(
+                "Backed up table with {} rows to {}/{}.lance before dropping it, {}",
+                rows, semantic.lancedb_store, backup_name, reason
+            );
+            let backups = db
+                .table_names()
+                .execute()
+                .await
+                .into_diagnostic()?
+                .into_iter()
+                .filter(|it| it.starts_with(&backup_prefix))
+                .sorted()
+                .collect_vec();
+            for old_backup in &backups[..backups.len().saturating_sub(max_backups)] {
+                info!("Dropping old backup table {}", old_backup);
+                db.drop_table(old_backup).await.into_diagnostic()?;
+            }
+        }
+    }
+    db.drop_table(&table_config.name).await.into_diagnostic()
+}
+
+/// Files of the catalog model, the ONNX file first and the tokenizer files in the order of
+/// [`TokenizerFiles`]
+pub(crate) fn model_files(model_info: &ModelInfo<EmbeddingModel>) -> [&str; 5] {
+    [
+        model_info.model_file.as_str(),
+        "tokenizer.json",
+        "config.json",
+        "special_tokens_map.json",
+        "tokenizer_config.json",
+    ]
+}
+
+pub async fn get_or_download_model(
+    model: EmbeddingModel,
+    model_info: &ModelInfo<EmbeddingModel>,
+    semantic: &SemanticConfig,
+) -> Result<(PathBuf, TokenizerFiles)> {
+    let files = model_files(model_info);
+    let models_dir = &semantic.models_dir;
+    let paths = if !semantic.download_model {
+        info!("Loading model from local directory {:?}", models_dir);
+        let model_dir = models_dir.join(model.to_string());
+        info!("Model directory: {:?}", model_dir);
+        let paths = files.map(|file| model_dir.join(file)).to_vec();
+        for path in paths.iter() {
+            check_model_file(path)?;
+        }
+        paths
+    } else if semantic.offline {
+        cached_model_files(&model, &files, models_dir)?
+    } else {
+        download_model_files(&model, &files, models_dir).await?
+    };
+    info!("Model path: {:?}", paths[0]);
+
+    let read = |index: usize| {
+        read_file_to_bytes(&paths[index]).map_err(|e| {
+            SemantrixError::model_load(format!("Failed to read {}: {}", files[index], e))
+        })
+    };
+    let tokenizer_files = TokenizerFiles {
+        tokenizer_file: read(1)?,
+        config_file: read(2)?,
+        special_tokens_map_file: read(3)?,
+        tokenizer_config_file: read(4)?,
+    };
+
+    Ok((paths[0].clone(), tokenizer_files))
+}
+
+/// Model of the fastembed catalog configured by name, its identity is the name and the pooling
+async fn load_catalog_model(
+    name: &str,
+    pooling: Option<PoolingConfig>,
+    semantic: &SemanticConfig,
+) -> Result<(IndexEmbeddingModel, usize, String)> {
+    let model = model_from_str(name)?;
+    let model_info = TextEmbedding::get_model_info(&model).map_err(|e| {
+        SemantrixError::model_load(format!(
+            "Failed to get model info for model: {:?}, error: {}",
+            model, e
+        ))
+    })?;
+    info!("Model info: {:?}", model_info);
+    let (model_path, tokenizer_files) =
+        get_or_download_model(model.clone(), model_info, semantic).await?;
+    info!("Reading model.onnx file from {:?}", model_path);
+    let onnx_file = read_file_to_bytes(&model_path).map_err(|e| {
+        SemantrixError::model_load(format!(
+            "Failed to read model file {}: {}",
+            model_path.display(),
+            e
+        ))
+        .with_help("check search.semantic.models_dir and search.semantic.download_model")
+    })?;
+    let pooling = pooling.unwrap_or_else(|| PoolingConfig::of_model(&model));
+    info!("Creating embedding model with {:?} pooling", pooling);
+    let user_defined_model =
+        UserDefinedEmbeddingModel::new(onnx_file, tokenizer_files).with_pooling(pooling.into());
+
+    let ndims = model_info.dim;
+
+    let embedding_model =
+        rig_fastembed::EmbeddingModel::new_from_user_defined(user_defined_model, ndims, model_info);
+
+    let model_id = format!("{}:{:?}", name, pooling);
+    Ok((
+        IndexEmbeddingModel::Fastembed(embedding_model),
+        ndims,
+        model_id,
+    ))
+}
+
+/// Model of the user provided files, its identity is the hash of the ONNX file and the pooling,
+/// so a retrained model with the same path is detected too
+fn load_custom_model(
+    custom_model: &CustomModelConfig,
+) -> Result<(IndexEmbeddingModel, usize, String)> {
+    info!(
+        "Reading custom model file from {:?}",
+        custom_model.model_file
+    );
+    let onnx_file = read_file_to_bytes(&custom_model.model_file).map_err(|e| {
+        SemantrixError::model_load(format!(
+            "Failed to read {:?}: {}",
+            custom_model.model_file, e
+        ))
+    })?;
+    // the id is stored with the index, so its hash must not depend on the toolchain
+    let digest = format!("{:x}", Sha256::digest(&onnx_file));
+    let model_id = format!("custom-{}:{:?}", &digest[..16], custom_model.pooling);
+
+    let read = |path: &PathBuf| {
+        read_file_to_bytes(path)
+            .map_err(|e| SemantrixError::model_load(format!("Failed to read {:?}: {}", path, e)))
+    };
+    let tokenizer_files = TokenizerFiles {
+        tokenizer_file: read(&custom_model.tokenizer_file)?,
+        config_file: read(&custom_model.config_file)?,
+        special_tokens_map_file: read(&custom_model.special_tokens_map_file)?,
+        tokenizer_config_file: read(&custom_model.tokenizer_config_file)?,
+    };
+    info!("Creating custom embedding model {}", model_id);
+    let user_defined_model = UserDefinedEmbeddingModel::new(onnx_file, tokenizer_files)
+        .with_pooling(custom_model.pooling.into());
+
+    // rig_fastembed takes the info of a catalog model, only the dimensions and names are used
+    let model_info = ModelInfo {
+        model: EmbeddingModel::AllMiniLML6V2,
+        dim: custom_model.dims,
+        description: "User provided embedding model".to_string(),
+        model_code: model_id.clone(),
+        model_file: custom_model.model_file.display().to_string(),
+        additional_files: vec![],
+    };
+    let embedding_model = rig_fastembed::EmbeddingModel::new_from_user_defined(
+        user_defined_model,
+        custom_model.dims,
+        &model_info,
+    );
+    Ok((
+        IndexEmbeddingModel::Fastembed(embedding_model),
+        custom_model.dims,
+        model_id,
+    ))
+}
+
+/// Cross-encoder of the reranker files, read like the files of the custom embedding model
+pub(crate) fn load_reranker(reranker: &RerankerConfig) -> Result<TextRerank> {
+    info!("Reading reranker model file from {:?}", reranker.model_file);
+    let read = |path: &PathBuf| {
+        read_file_to_bytes(path)
+            .map_err(|e| SemantrixError::model_load(format!("Failed to read {:?}: {}", path, e)))
+    };
+    let tokenizer_files = TokenizerFiles {
+        tokenizer_file: read(&reranker.tokenizer_file)?,
+        config_file: read(&reranker.config_file)?,
+        special_tokens_map_file: read(&reranker.special_tokens_map_file)?,
+        tokenizer_config_file: read(&reranker.tokenizer_config_file)?,
+    };
+    let model = UserDefinedRerankingModel::new(read(&reranker.model_file)?, tokenizer_files);
+    TextRerank::try_new_from_user_defined(model, RerankInitOptionsUserDefined::default())
+        .map_err(|e| SemantrixError::model_load(format!("Failed to create reranker: {}", e)).into())
+}
+
+/// Recreates the table if it was indexed by another model with the same dimensions, the
+/// identity of the model is kept next to the store
+async fn drop_table_of_other_model(
+    db: &Connection,
+    table: Table,
+    ndims: usize,
+    model_id: &str,
+    semantic: &SemanticConfig,
+) -> Result<Table> {
+    if is_remote_store(&semantic.lancedb_store) {
+        warn!(
+            "Model identity is not kept in remote store {}, only dimensions of the table are checked",
+            semantic.lancedb_store
+        );
+        return Ok(table);
+    }
+    let table_config = &semantic.table;
+    let model_id_path = PathBuf::from(&semantic.lancedb_store).join(table_config.model_id_file());
+    let table = match indexed_model_id(semantic) {
+        Some(indexed_model_id) if indexed_model_id != model_id => {
+            let reason = format!(
+                "table is indexed by model {}, not by model {}",
+                indexed_model_id, model_id
+            );
+            backup_and_drop_table(db, semantic, &reason).await?;
+            if semantic.serve_previous_model {
+                let previous_model_id_path = PathBuf::from(&semantic.lancedb_store)
+                    .join(table_config.previous_model_id_file());
+                std::fs::write(previous_model_id_path, &indexed_model_id).into_diagnostic()?;
+            }
+            get_or_create_table(db, ndims, semantic).await?
+        }
+        _ => table,
+    };
+    std::fs::write(&model_id_path, model_id).into_diagnostic()?;
+    Ok(table)
+}
+
+/// Identity of the model the table is indexed by, None for the remote stores which don't keep it
+pub(crate) fn indexed_model_id(semantic: &SemanticConfig) -> Option<String> {
+    if is_remote_store(&semantic.lancedb_store) {
+        return None;
+    }
+    let model_id_path = PathBuf::from(&semantic.lancedb_store).join(semantic.table.model_id_file());
+    std::fs::read_to_string(model_id_path)
+        .ok()
+        .map(|it| it.trim().to_string())
+}
+
+/// Creates the missing directory of the config key if `create_dirs` is set
+fn ensure_dir(key: &str, path: &Path, create_dirs: bool) -> Result<()> {
+    if path.as_os_str().is_empty() || path.is_dir() {
+        return Ok(());
+    }
+    if !create_dirs {
+        return Err(SemantrixError::io(
+            path,
+            format!("{} is not an existing directory: {}", key, path.display()),
+        )
+        .with_help(format!(
+            "create the directory, change {} or set create_dirs: true",
+            key
+        ))
+        .into());
+    }
+    info!("Creating directory {} of {}", path.display(), key);
+    std::fs::create_dir_all(path).map_err(|e| {
+        SemantrixError::io(
+            path,
+            format!(
+                "Failed to create directory {} of {}: {}",
+                path.display(),
+                key,
+                e
+            ),
+        )
+        .with_help(format!(
+            "check the permissions of the parent directory or change {}",
+            key
+        ))
+        .into()
+    })
+}
+
+/// Connection of `search.semantic.lancedb_store` with its storage options
+pub(crate) async fn connect_store(semantic: &SemanticConfig) -> Result<Connection> {
+    lancedb::connect(&semantic.lancedb_store)
+        .storage_options(semantic.storage_options.clone())
+        .execute()
+        .await
+        .into_diagnostic()
+}
+
+/// Table of the index as it is, without the checks and rebuilds of [`init_db`], fails if the
+/// index is not built
+pub(crate) async fn open_index_table(semantic: &SemanticConfig) -> Result<Table> {
+    let table_config = &semantic.table;
+    connect_store(semantic)
+        .await?
+        .open_table(&table_config.name)
+        .execute()
+        .await
+        .map_err(|e| {
+            SemantrixError::lancedb(format!(
+                "Failed to open table {} of store {}: {}",
+                table_config.name, semantic.lancedb_store, e
+            ))
+            .with_help("build the index with the index command first")
+            .into()
+        })
+}
+
+/// Embedding model of the config with its dimensions and identity, it is loaded once and shared
+/// by the tables of all workspaces
+pub async fn init_model(config: &McpConfig) -> Result<(IndexEmbeddingModel, usize, String)> {
+    let semantic = &config.search.semantic;
+    if semantic.download_model {
+        ensure_dir(
+            "search.semantic.models_dir",
+            &semantic.models_dir,
+            config.create_dirs,
+        )?;
+    }
+    let (embedding_model, ndims, model_id) = match &semantic.custom_model {
+        Some(custom_model) => load_custom_model(custom_model)?,
+        None if semantic.model == STUB_MODEL => stub_model(),
+        None => load_catalog_model(&semantic.model, semantic.pooling, semantic).await?,
+    };
+    Ok((
+        embedding_model,
+        ndims,
+        with_embedding_text_id(model_id, semantic),
+    ))
+}
+
+/// Identity of the model suffixed by the hash of `search.semantic.embedding_text_template`, the
+/// chunks embedded by another template are rebuilt like the chunks of another model
+fn with_embedding_text_id(model_id: String, semantic: &SemanticConfig) -> String {
+    match &semantic.embedding_text_template {
+        Some(template) => {
+            let digest = format!("{:x}", Sha256::digest(template.as_bytes()));
+            format!(
+                "{}{}{}",
+                model_id,
+                EMBEDDING_TEXT_ID_SEPARATOR,
+                &digest[..16]
+            )
+        }
+        None => model_id,
+    }
+}
+
+fn stub_model() -> (IndexEmbeddingModel, usize, String) {
+    warn!("Using the stub embedding model, the semantic search matches the words only");
+    (
+        IndexEmbeddingModel::Stub(StubEmbeddingModel {
+            ndims: STUB_MODEL_DIMS,
+        }),
+        STUB_MODEL_DIMS,
+        STUB_MODEL.to_string(),
+    )
+}
+
+/// Loaded model of `search.semantic.model_routes`
+#[derive(Clone)]
+pub struct RouteModel {
+    pub model: String,
+    pub embedding_model: IndexEmbeddingModel,
+    pub ndims: usize,
+    pub model_id: String,
+}
+
+/// Models of the routes in the order of their first routes, a model of several routes is loaded
+/// once. The models take the pooling they are trained for, the routes of `search.semantic.model`
+/// need no other model
+pub async fn init_route_models(semantic: &SemanticConfig) -> Result<Vec<RouteModel>> {
+    let mut models: Vec<RouteModel> = Vec::new();
+    for route in semantic.model_routes.iter() {
+        // the routes of the default model use its table
+        let default = semantic.custom_model.is_none() && route.model == semantic.model;
+        if default || models.iter().any(|it| it.model == route.model) {
+            continue;
+        }
+        let (embedding_model, ndims, model_id) = if route.model == STUB_MODEL {
+            stub_model()
+        } else {
+            load_catalog_model(&route.model, None, semantic).await?
+        };
+        info!(
+            "Model {} of the routes is loaded with {} dimensions",
+            route.model, ndims
+        );
+        models.push(RouteModel {
+            model: route.model.clone(),
+            embedding_model,
+            ndims,
+            model_id: with_embedding_text_id(model_id, semantic),
+        });
+    }
+    Ok(models)
+}
+
+/// Vector store over the table of the default model and the tables of the route models, the
+/// table of a route model is the workspace table suffixed by the model name
+pub async fn init_routes(
+    db: &Connection,
+    workspace: &Workspace,
+    default: RouteTable,
+    route_models: &[RouteModel],
+    read_only: bool,
+) -> Result<RoutedBackend> {
+    let mut tables = vec![default];
+    for route_model in route_models {
+        let mut semantic = workspace.semantic.clone();
+        semantic.table.name = format!(
+            "{}_{}",
+            semantic.table.name,
+            route_model
+                .model
+                .replace(|it: char| !it.is_alphanumeric(), "_")
+        );
+        let (table, vector_store) = init_table(
+            db,
+            &semantic,
+            &route_model.embedding_model,
+            route_model.ndims,
+            &route_model.model_id,
+            workspace.path_case,
+            read_only,
+        )
+        .await?;
+        tables.push(RouteTable {
+            model: route_model.model.clone(),
+            embedding_model: route_model.embedding_model.clone(),
+            ndims: route_model.ndims,
+            table,
+            vector_store,
+        });
+    }
+    let workspace_uri = Url::parse(&workspace.fuzzy.workspace_uri).into_diagnostic()?;
+    let workspace_path = url_to_path(&workspace_uri)
+        .ok_or_else(|| SemantrixError::config(format!("Invalid file URL: {}", workspace_uri)))?;
+    RoutedBackend::new(
+        &workspace.semantic.model_routes,
+        tables,
+        workspace_path,
+        workspace.path_case,
+    )
+}
+
+/// Backup table of the previous model searched by the semantic search while the table is rebuilt
+/// for another model, see `search.semantic.serve_previous_model`
+pub struct PreviousIndex {
+    /// Identity of the previous model
+    pub model_id: String,
+    pub table_name: String,
+    /// Number of the files of the backup table, the rebuild is compared with it
+    pub files: usize,
+    /// Searches the backup table by the embeddings of the previous model
+    pub vector_store: Arc<dyn VectorBackend>,
+}
+
+/// Newest backup table of the workspace table with the previous model loaded, None if the table
+/// is not rebuilt for another model, the previous model can't be loaded again or nothing was
+/// backed up
+pub async fn init_previous_index(
+    db: &Connection,
+    semantic: &SemanticConfig,
+    path_case: PathCaseMode,
+) -> Result<Option<PreviousIndex>> {
+    let table_config = &semantic.table;
+    let previous_model_id_path =
+        PathBuf::from(&semantic.lancedb_store).join(table_config.previous_model_id_file());
+    let Ok(model_id) = std::fs::read_to_string(&previous_model_id_path) else {
+        return Ok(None);
+    };
+    let model_id = model_id.trim().to_string();
+    if !semantic.serve_previous_model {
+        remove_previous_index(semantic);
+        return Ok(None);
+    }
+    let backup_prefix = table_config.backup_prefix();
+    let Some(table_name) = db
+        .table_names()
+        .execute()
+        .await
+        .into_diagnostic()?
+        .into_iter()
+        .filter(|it| it.starts_with(&backup_prefix))
+        .max()
+    else {
+        warn!(
+            "Table {} of model {} has no backup, the semantic search waits for the rebuild",
+            table_config.name, model_id
+        );
+        remove_previous_index(semantic);
+        return Ok(None);
+    };
+    let Some((embedding_model, ndims, _)) = load_indexed_model(&model_id, semantic).await? else {
+        warn!(
+            "Model {} of backup table {} can't be loaded again, the semantic search waits for \
+            the rebuild",
+            model_id, table_name
+        );
+        return Ok(None);
+    };
+    let table = db
+        .open_table(&table_name)
+        .execute()
+        .await
+        .into_diagnostic()?;
+    let schema = table.schema().await.into_diagnostic()?;
+    if schema.field_with_name(&table_config.hash_field).is_err()
+        || schema
+            .field_with_name(&table_config.indexed_at_field)
+            .is_err()
+        || schema
+            .field_with_name(&table_config.chunk_index_field)
+            .is_err()
+        || schema
+            .field_with_name(&table_config.total_chunks_field)
+            .is_err()
+    {
+        warn!(
+            "Backup table {} has an old schema, the semantic search waits for the rebuild",
+            table_name
+        );
+        return Ok(None);
+    }
+    let files = count_indexed_files(&table, table_config).await?;
+    let mut previous = semantic.clone();
+    previous.table.name = table_name.clone();
+    let vector_store =
+        lance_backend(db, &table, &previous, &embedding_model, ndims, path_case).await?;
+    info!(
+        "Serving backup table {} of model {} with {} files until the rebuild is done",
+        table_name, model_id, files
+    );
+    Ok(Some(PreviousIndex {
+        model_id,
+        table_name,
+        files,
+        vector_store,
+    }))
+}
+
+/// Forgets the previous model of the workspace table once the rebuild is done, the backup table
+/// is kept with the other backups
+pub fn remove_previous_index(semantic: &SemanticConfig) {
+    let previous_model_id_path =
+        PathBuf::from(&semantic.lancedb_store).join(semantic.table.previous_model_id_file());
+    if let Err(e) = std::fs::remove_file(&previous_model_id_path) {
+        warn!(
+            "Failed to remove {}: {}",
+            previous_model_id_path.display(),
+            e
+        );
+    }
+}
+
+/// Model of the identity written to the model id file, None for the custom models whose files
+/// may be changed since
+async fn load_indexed_model(
+    model_id: &str,
+    semantic: &SemanticConfig,
+) -> Result<Option<(IndexEmbeddingModel, usize, String)>> {
+    // the previous table is searched by the queries, which are embedded without the template
+    let model_id = model_id
+        .split_once(EMBEDDING_TEXT_ID_SEPARATOR)
+        .map_or(model_id, |(model_id, _)| model_id);
+    if model_id == STUB_MODEL {
+        return Ok(Some(stub_model()));
+    }
+    let Some((name, pooling)) = model_id.rsplit_once(':') else {
+        return Ok(None);
+    };
+    let pooling = match pooling {
+        "Cls" => PoolingConfig::Cls,
+        "Mean" => PoolingConfig::Mean,
+        _ => return Ok(None),
+    };
+    if name.starts_with("custom-") {
+        return Ok(None);
+    }
+    load_catalog_model(name, Some(pooling), semantic)
+        .await
+        .map(Some)
+}
+
+/// Connection of the store shared by the tables of all workspaces
+pub async fn init_store(config: &McpConfig) -> Result<Connection> {
+    let semantic = &config.search.semantic;
+    if !is_remote_store(&semantic.lancedb_store)
+        && let Some(parent) = Path::new(&semantic.lancedb_store).parent()
+    {
+        ensure_dir("search.semantic.lancedb_store", parent, config.create_dirs)?;
+    }
+    connect_store(semantic).await
+}
+
+/// Chunks table of the workspace with its indexes and the vector store over it, the table of
+/// another model is rebuilt. The table is opened as it is in the read-only mode
+pub async fn init_table(
+    db: &Connection,
+    semantic: &SemanticConfig,
+    embedding_model: &IndexEmbeddingModel,
+    ndims: usize,
+    model_id: &str,
+    path_case: PathCaseMode,
+    read_only: bool,
+) -> Result<(Table, Arc<dyn VectorBackend>)> {
+    let table_config = &semantic.table;
+    if read_only {
+        let table = open_table(db, ndims, model_id, semantic).await?;
+        info!(
+            "Read-only table: {:?}",
+            table.schema().await.into_diagnostic()?
+        );
+        let vector_store =
+            lance_backend(db, &table, semantic, embedding_model, ndims, path_case).await?;
+        return Ok((table, vector_store));
+    }
+    let table: Table = get_or_create_table(db, ndims, semantic).await?;
+    let table = drop_table_of_other_model(db, table, ndims, model_id, semantic).await?;
+
+    if table
+        .index_stats(&table_config.path_field)
+        .await
         .into_diagnostic()?
         .is_none()
     {
         table
-            .create_index(&[DEFAULT_CHUNKS_PATH_FIELD], lancedb::index::Index::Auto)
+            .create_index(&[&table_config.path_field], lancedb::index::Index::Auto)
             .execute()
             .await
             .into_diagnostic()?;
     }
 
-    if CONFIG.search.semantic.index_embeddings
+    if semantic.index_embeddings
         && table
-            .index_stats(DEFAULT_CHUNKS_EMBEDDING_FIELD)
+            .index_stats(&table_config.embedding_field)
             .await
             .into_diagnostic()?
             .is_none()
@@ -552,21 +4007,21 @@ pub async fn init_db() -> Result<(
         // See [LanceDB indexing](https://lancedb.github.io/lancedb/concepts/index_ivfpq/#product-quantization) for more information
         table
             .create_index(
-                &[DEFAULT_CHUNKS_EMBEDDING_FIELD],
+                &[&table_config.embedding_field],
                 lancedb::index::Index::IvfPq(IvfPqIndexBuilder::default()),
             )
             .execute()
             .await
             .into_diagnostic()?;
-    } else if !CONFIG.search.semantic.index_embeddings
+    } else if !semantic.index_embeddings
         && table
-            .index_stats(DEFAULT_CHUNKS_EMBEDDING_FIELD)
+            .index_stats(&table_config.embedding_field)
             .await
             .into_diagnostic()?
             .is_some()
     {
         table
-            .drop_index(DEFAULT_CHUNKS_EMBEDDING_FIELD)
+            .drop_index(&table_config.embedding_field)
             .await
             .into_diagnostic()?;
         table
@@ -575,20 +4030,460 @@ pub async fn init_db() -> Result<(
             .into_diagnostic()?;
     }
 
+    if semantic.index_fts {
+        create_fts_index(&table, table_config).await?;
+    } else if has_fts_index(&table, table_config).await? {
+        table
+            .drop_index(&fts_index_name(table_config))
+            .await
+            .into_diagnostic()?;
+    }
+
     info!("Table: {:?}", table.schema().await.into_diagnostic()?);
 
-    let search_params = SearchParams::default();
+    let vector_store =
+        lance_backend(db, &table, semantic, embedding_model, ndims, path_case).await?;
+    Ok((table, vector_store))
+}
+
+/// Vector store over the table with the search params of the config
+async fn lance_backend(
+    db: &Connection,
+    table: &Table,
+    semantic: &SemanticConfig,
+    embedding_model: &IndexEmbeddingModel,
+    ndims: usize,
+    path_case: PathCaseMode,
+) -> Result<Arc<dyn VectorBackend>> {
+    let table_config = &semantic.table;
+    let search_params_config = &semantic.search_params;
+    info!("Search params: {:?}", search_params_config);
+    let search_params = search_params_config.search_params(&table_config.embedding_field);
+
+    let index = LanceDbVectorIndex::new(
+        table.clone(),
+        embedding_model.clone(),
+        &table_config.id_field,
+        search_params,
+    )
+    .await
+    .into_diagnostic()?;
+    Ok(Arc::new(LanceDbBackend {
+        db: db.clone(),
+        table: table.clone(),
+        index,
+        embedding_model: embedding_model.clone(),
+        ndims,
+        table_config: table_config.clone(),
+        search_params: search_params_config.clone(),
+        path_case,
+        snapshot: Mutex::default(),
+    }))
+}
+
+/// Model, table and vector store of the first workspace, used by the commands which don't run
+/// the pipelines
+pub async fn init_db(
+    config: &McpConfig,
+) -> Result<(usize, Table, IndexEmbeddingModel, Arc<dyn VectorBackend>)> {
+    let (embedding_model, ndims, model_id) = init_model(config).await?;
+    let db = init_store(config).await?;
+    let workspace = &config.workspaces()[0];
+    let (table, vector_store) = init_table(
+        &db,
+        &workspace.semantic,
+        &embedding_model,
+        ndims,
+        &model_id,
+        workspace.path_case,
+        config.read_only,
+    )
+    .await?;
+    Ok((ndims, table, embedding_model, vector_store))
+}
+
+/// Runs the model, the tables of the pipelines and the templates once before the first tool
+/// call and logs the time of every step. The search of an empty index is skipped, a failed step
+/// is logged without failing the startup
+pub async fn warm_up(
+    live_config: &LiveConfig,
+    embedding_model: &IndexEmbeddingModel,
+    pipelines: &[WorkspacePipeline],
+) {
+    let started = Instant::now();
+    if let Err(e) = embedding_model.embed_texts([WARMUP_TEXT.to_string()]).await {
+        warn!("Warm-up embedding failed: {}", e);
+    }
+    let embedding_ms = started.elapsed().as_millis();
+
+    let search_started = Instant::now();
+    for pipeline in pipelines {
+        let name = &pipeline.workspace.name;
+        match pipeline.routes.count_chunks().await {
+            Ok(0) => debug!(
+                "Warm-up search of workspace {} is skipped, its index is empty",
+                name
+            ),
+            Ok(_) => {
+                if let Err(e) = pipeline.vector_store.top_n(WARMUP_TEXT, 1).await {
+                    warn!("Warm-up search of workspace {} failed: {}", name, e);
+                }
+            }
+            Err(e) => warn!(
+                "Warm-up of workspace {} can't count its chunks: {}",
+                name, e
+            ),
+        }
+    }
+    let search_ms = search_started.elapsed().as_millis();
+
+    let templates_started = Instant::now();
+    let config = live_config.get();
+    if let Err(e) = check_templates(
+        &live_config.tera(),
+        &config.templates,
+        &config.search.semantic.model,
+    ) {
+        warn!("Warm-up rendering of the templates failed: {}", e);
+    }
+    let templates_ms = templates_started.elapsed().as_millis();
+
+    info!(
+        "Warm-up took {} ms: embedding {} ms, search {} ms, templates {} ms",
+        started.elapsed().as_millis(),
+        embedding_ms,
+        search_ms,
+        templates_ms
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Arguments of the embedding application, the ones of the test binary aren't parsed
+    fn default_args() -> Args {
+        Args::parse_from([NAME])
+    }
+
+    /// Config of the repository with the crate as its workspace, it passes the validation
+    fn valid_config() -> McpConfig {
+        let mut config = read_config("config.yml", &default_args()).unwrap();
+        config.search.fuzzy.workspace_uri = Url::from_directory_path(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .to_string();
+        config
+    }
+
+    /// Problems reported by the validation of the config changed by `change`
+    fn problems(change: impl FnOnce(&mut McpConfig)) -> String {
+        let mut config = valid_config();
+        change(&mut config);
+        validate_config(&config).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn model_names_round_trip() {
+        for (name, model) in MODEL_NAMES.iter() {
+            assert_eq!(model_from_str(name).unwrap(), *model, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_model_suggests_closest_name() {
+        let cases = [
+            ("bge-smal-en-v1.5", "bge-small-en-v1.5"),
+            ("all-minilm-l6-v2", "all-mini-lm-l6-v2"),
+            ("jina-embeddings-v2-base", "jina-embeddings-v2-base-code"),
+        ];
+        for (value, closest) in cases {
+            let report = model_from_str(value).unwrap_err();
+            let help = report.help().map(|it| it.to_string()).unwrap_or_default();
+
+            assert!(report.to_string().contains(value), "{}", report);
+            assert!(
+                help.starts_with(&format!("did you mean `{}`?", closest)),
+                "{}",
+                help
+            );
+        }
+    }
+
+    #[test]
+    fn repository_config_is_valid() {
+        validate_config(&valid_config()).unwrap();
+    }
+
+    /// Change of the valid config which makes it invalid
+    type ConfigBreak = Box<dyn FnOnce(&mut McpConfig)>;
+
+    #[test]
+    fn invalid_values_are_reported_by_their_key_paths() {
+        let cases: [(&str, ConfigBreak); 6] = [
+            (
+                "search.semantic.chunk_size must be greater than 0",
+                Box::new(|config| config.search.semantic.chunk_size = 0),
+            ),
+            (
+                "search.semantic.overlap_size must be less or equal to chunk_size - 1",
+                Box::new(|config| {
+                    config.search.semantic.chunk_size = 10;
+                    config.search.semantic.overlap_size = 10;
+                }),
+            ),
+            (
+                "search.semantic.pattern is not a valid glob",
+                Box::new(|config| config.search.semantic.pattern = "**/*.{rs".to_string()),
+            ),
+            (
+                "search.semantic.exclude_globs[1] is not a valid glob",
+                Box::new(|config| {
+                    config.search.semantic.exclude_globs =
+                        vec!["target/**".to_string(), "**/*.{rs".to_string()];
+                }),
+            ),
+            (
+                "search.fuzzy.workspace_uri must be a file URL",
+                Box::new(|config| {
+                    config.search.fuzzy.workspace_uri = "https://example.com/repo".to_string();
+                }),
+            ),
+            (
+                "rules is not an existing file",
+                Box::new(|config| config.rules = PathBuf::from("./resources/missing-rules.yml")),
+            ),
+        ];
+        for (problem, change) in cases {
+            let problems = problems(change);
+            assert!(
+                problems.contains(problem),
+                "{} not in {}",
+                problem,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let problems = problems(|config| {
+            config.search.semantic.chunk_size = 0;
+            config.rules = PathBuf::from("./resources/missing-rules.yml");
+        });
+
+        assert!(
+            problems.contains("search.semantic.chunk_size"),
+            "{}",
+            problems
+        );
+        assert!(
+            problems.contains("rules is not an existing file"),
+            "{}",
+            problems
+        );
+    }
+
+    #[test]
+    fn config_formats_deserialize_to_same_config() {
+        let dir =
+            std::env::temp_dir().join(format!("semantrix-config-formats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // the fixtures extend the repository config, so only their own values differ from it
+        let base = std::fs::canonicalize("config.yml").unwrap();
+        let base = base.to_string_lossy();
+        let fixtures = [
+            (
+                "config.yml",
+                format!(
+                    "extends: {}\ndebug: true\nsearch:\n  semantic:\n    chunk_size: 7\n",
+                    serde_json::to_string(&base).unwrap()
+                ),
+            ),
+            (
+                "config.toml",
+                format!(
+                    "extends = '{}'\ndebug = true\n\n[search.semantic]\nchunk_size = 7\n",
+                    base
+                ),
+            ),
+            (
+                "config.json",
+                serde_json::json!({
+                    "extends": base,
+                    "debug": true,
+                    "search": { "semantic": { "chunk_size": 7 } },
+                })
+                .to_string(),
+            ),
+        ];
+
+        let configs = fixtures
+            .iter()
+            .map(|(name, content)| {
+                let path = dir.join(name);
+                std::fs::write(&path, content).unwrap();
+                read_config(&path.to_string_lossy(), &default_args()).unwrap()
+            })
+            .collect_vec();
 
-    let vector_store = Arc::new(
-        LanceDbVectorIndex::new(
-            table.clone(),
-            embedding_model.clone(),
-            DEFAULT_CHUNKS_ID_FIELD,
-            search_params,
+        let yaml = &configs[0];
+        assert!(yaml.debug);
+        assert_eq!(yaml.search.semantic.chunk_size, 7);
+        for config in &configs[1..] {
+            assert_eq!(format!("{:?}", config), format!("{:?}", yaml));
+        }
+    }
+
+    #[test]
+    fn args_take_precedence_over_config_file() {
+        let mut config = valid_config();
+        config.search.semantic.lancedb_store = "${workspace}/.semantrix/lancedb".to_string();
+        let file_model = config.search.semantic.model.clone();
+        let workspace = std::env::temp_dir();
+
+        let mut kept = config.clone();
+        apply_args(&mut kept, &Args::parse_from([NAME])).unwrap();
+        assert_eq!(kept.search.semantic.model, file_model);
+        assert_eq!(
+            kept.search.fuzzy.workspace_uri,
+            config.search.fuzzy.workspace_uri
+        );
+
+        let args = Args::parse_from([
+            NAME,
+            "--workspace",
+            &workspace.to_string_lossy(),
+            "--model",
+            "all-mini-lm-l6-v2",
+        ]);
+        apply_args(&mut config, &args).unwrap();
+        assert_eq!(
+            config.search.fuzzy.workspace_uri,
+            Url::from_directory_path(&workspace).unwrap().to_string()
+        );
+        assert_eq!(config.search.semantic.model, "all-mini-lm-l6-v2");
+        // the placeholder is replaced by the workspace of the flag, not the one of the file
+        assert_eq!(
+            Path::new(&config.search.semantic.lancedb_store),
+            workspace.join(".semantrix/lancedb")
+        );
+    }
+
+    #[test]
+    fn config_problems_are_config_errors() {
+        let mut config = valid_config();
+        config.search.semantic.chunk_size = 0;
+        let report = validate_config(&config).unwrap_err();
+        let error = report.downcast_ref::<SemantrixError>().unwrap();
+
+        assert!(
+            matches!(error, SemantrixError::Config { .. }),
+            "{:?}",
+            error
+        );
+        assert!(!error.is_retryable());
+
+        let report = config_format("config.ini", &default_args()).unwrap_err();
+        let error = report.downcast_ref::<SemantrixError>().unwrap();
+        assert!(
+            matches!(error, SemantrixError::Config { help: Some(_), .. }),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn unparsable_rules_file_is_ruleset_error() {
+        let dir = std::env::temp_dir().join(format!("semantrix-bad-rules-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rules = dir.join("rules.yml");
+        std::fs::write(&rules, "depends_on: 7\n").unwrap();
+        let base = std::fs::canonicalize("config.yml").unwrap();
+        let workspace = Url::from_directory_path(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let config = dir.join("config.json");
+        let content = serde_json::json!({
+            "extends": base,
+            "rules": rules,
+            "search": { "fuzzy": { "workspace_uri": workspace } },
+        });
+        std::fs::write(&config, content.to_string()).unwrap();
+
+        let report = validate_config_file(&config.to_string_lossy(), &default_args()).unwrap_err();
+
+        match report.downcast_ref::<SemantrixError>() {
+            Some(SemantrixError::Ruleset { path, .. }) => assert_eq!(path, &rules),
+            error => panic!("{:?}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilt_table_is_backed_up_with_its_rows() {
+        use arrow_array::{
+            FixedSizeListArray, Int64Array, RecordBatch, StringArray, types::Float64Type,
+        };
+        use lancedb::arrow::arrow_schema::Schema;
+
+        let dir =
+            std::env::temp_dir().join(format!("semantrix-table-backup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut semantic = valid_config().search.semantic;
+        semantic.lancedb_store = dir.to_string_lossy().to_string();
+        let db = lancedb::connect(&semantic.lancedb_store)
+            .execute()
+            .await
+            .unwrap();
+        // the table written before the text and the hashes were stored, so it is rebuilt
+        let ndims = 2;
+        let rows: i64 = 3;
+        let full_schema = schema(ndims, &semantic.table);
+        let old_schema = Arc::new(Schema::new(full_schema.fields()[..5].to_vec()));
+        let batch = RecordBatch::try_new(
+            old_schema.clone(),
+            vec![
+                Arc::new(StringArray::from_iter_values(
+                    (0..rows).map(|it| format!("main.rs:{}:{}", it, it + 1)),
+                )),
+                Arc::new(StringArray::from_iter_values((0..rows).map(|_| "main.rs"))),
+                Arc::new(Int64Array::from_iter_values(0..rows)),
+                Arc::new(Int64Array::from_iter_values(1..rows + 1)),
+                Arc::new(
+                    FixedSizeListArray::from_iter_primitive::<Float64Type, _, _>(
+                        (0..rows).map(|_| Some(vec![Some(0.0); ndims])),
+                        ndims as i32,
+                    ),
+                ),
+            ],
+        )
+        .unwrap();
+        db.create_table(
+            &semantic.table.name,
+            RecordBatchIterator::new(vec![Ok(batch)], old_schema),
         )
+        .execute()
         .await
-        .into_diagnostic()?,
-    );
+        .unwrap();
 
-    Ok((ndims, table, embedding_model, vector_store))
+        let table = get_or_create_table(&db, ndims, &semantic).await.unwrap();
+
+        assert_eq!(table.count_rows(None).await.unwrap(), 0);
+        assert!(
+            table
+                .schema()
+                .await
+                .unwrap()
+                .field_with_name(&semantic.table.text_field)
+                .is_ok()
+        );
+        let backups = db
+            .table_names()
+            .execute()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|it| it.starts_with(&semantic.table.backup_prefix()))
+            .collect_vec();
+        assert_eq!(backups.len(), 1, "{:?}", backups);
+        let backup = db.open_table(&backups[0]).execute().await.unwrap();
+        assert_eq!(backup.count_rows(None).await.unwrap(), rows as usize);
+    }
 }
@@ -0,0 +1,107 @@
+use std::{path::Path, sync::Arc};
+
+use miette::Result;
+
+use crate::{
+    McpConfig, RunMode, Semantrix, init_db, open_index_table,
+    repositories::count_chunks,
+    services::{ChunksPage, SemanticHit, SymbolInfo, search_chunks, workspace_root},
+};
+
+/// Number of the first lines of the hit printed under it
+const SNIPPET_LINES: usize = 5;
+
+/// Searches the built index of the first workspace by the query and prints the hits, the fuzzy search by the names
+/// starts the LSP server and waits for it. Fails if the index is not built or is empty
+pub async fn run_query(
+    config: &Arc<McpConfig>,
+    query: String,
+    limit: usize,
+    fuzzy: Vec<String>,
+) -> Result<()> {
+    let workspace = &config.workspaces()[0];
+    let semantic = &workspace.semantic;
+    let table = open_index_table(semantic).await?;
+    if count_chunks(&table).await? == 0 {
+        return Err(miette::miette!(
+            help = "build the index with the index command first",
+            "Index of store {} is empty",
+            semantic.lancedb_store
+        ));
+    }
+
+    let (hits, symbols) = if fuzzy.is_empty() {
+        let (_, _, _, vector_store) = init_db(config).await?;
+        let hits = search_chunks(
+            config,
+            workspace,
+            vec![query],
+            None,
+            semantic.search_mode,
+            vector_store,
+            ChunksPage {
+                limit,
+                max_per_file: config.search.max_results_per_file,
+            },
+        )
+        .await?;
+        (hits, None)
+    } else {
+        let semantrix = Semantrix::builder()
+            .config(config.clone())
+            .mode(RunMode::Query)
+            .build()
+            .await?;
+        let search = semantrix.search();
+        let hits = search.semantic_search(vec![query], limit, None).await;
+        let symbols = search.fuzzy_search(fuzzy, Vec::new(), false).await;
+        semantrix.shutdown().await?;
+        (hits?, Some(symbols?))
+    };
+
+    print_hits(&hits, &workspace_root(workspace)?);
+    if let Some(symbols) = symbols {
+        print_symbols(&symbols, limit);
+    }
+    Ok(())
+}
+
+fn print_hits(hits: &[SemanticHit], workspace_path: &Path) {
+    if hits.is_empty() {
+        println!("No semantic hits found");
+        return;
+    }
+    for hit in hits {
+        let path = &hit.snippet.path;
+        println!(
+            "{}:{}-{} score {:.4}",
+            path.strip_prefix(workspace_path)
+                .unwrap_or(path.as_path())
+                .display(),
+            hit.snippet.start_line + 1,
+            hit.snippet.end_line,
+            hit.score
+        );
+        for line in hit.snippet.text.lines().take(SNIPPET_LINES) {
+            println!("    {}", line);
+        }
+    }
+}
+
+fn print_symbols(symbols: &[SymbolInfo], limit: usize) {
+    if symbols.is_empty() {
+        println!("No fuzzy symbols found");
+        return;
+    }
+    for symbol in symbols.iter().take(limit) {
+        let start = symbol.location.range.start;
+        println!(
+            "{:?} {} {}:{}:{}",
+            symbol.kind,
+            symbol.name,
+            symbol.location.uri,
+            start.line + 1,
+            start.character + 1
+        );
+    }
+}
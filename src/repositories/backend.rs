@@ -0,0 +1,612 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use arrow_array::RecordBatchIterator;
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use itertools::Itertools;
+use lancedb::{Connection, Table};
+use metrics::histogram;
+use miette::Result;
+use rig::{
+    OneOrMany,
+    embeddings::{Embedding, EmbeddingModel as _},
+    vector_store::VectorStoreIndexDyn,
+};
+use rig_lancedb::LanceDbVectorIndex;
+use serde_json::{Map, Value, json};
+use wax::{Glob, Pattern};
+
+use crate::{
+    ModelRouteConfig, SearchParamsConfig, TableConfig,
+    enums::PathCaseMode,
+    errors::SemantrixError,
+    repositories::{
+        StoredChunk, count_chunks, count_indexed_files, delete_by_paths,
+        embedding::IndexEmbeddingModel, quote_literal, search_by_embedding, search_by_text,
+    },
+    services::native_path,
+    subsystems::{
+        chunker::ArcTextChunk,
+        indexer::{as_record_batch, schema},
+        metrics::VECTOR_SEARCH_SECONDS,
+    },
+};
+
+/// Store of the chunk embeddings written by the indexer and searched by the semantic tools,
+/// the maintenance of the store like the optimization stays with the store itself
+#[async_trait]
+pub trait VectorBackend: Send + Sync {
+    /// Replaces the stored chunks of the same ids by the embedded ones
+    async fn upsert(&self, chunks: Vec<(ArcTextChunk, OneOrMany<Embedding>)>) -> Result<()>;
+
+    /// Deletes the chunks of the ids, returns number of deleted chunks
+    async fn delete_by_ids(&self, ids: &[String]) -> Result<usize>;
+
+    /// Deletes chunks of the files and all files of the folders, returns number of deleted chunks
+    async fn delete_by_paths(&self, paths: &[PathBuf]) -> Result<usize>;
+
+    /// Chunks most similar to the query with their scores, the payload has the fields of
+    /// [`ChunkId`](crate::subsystems::chunker::ChunkId)
+    async fn top_n(&self, query: &str, n: usize) -> Result<Vec<(f64, Value)>>;
+
+    /// Chunks of the best full-text scores of every query in the order of the queries, the
+    /// scores are higher for the better matches
+    async fn text_top_n_many(
+        &self,
+        _queries: &[String],
+        _n: usize,
+    ) -> Result<Vec<Vec<(f64, Value)>>> {
+        Err(SemantrixError::lancedb("Full-text search is not supported by the store").into())
+    }
+
+    /// Chunks most similar to every query in the order of the queries, see [`Self::top_n`]
+    async fn top_n_many(&self, queries: &[String], n: usize) -> Result<Vec<Vec<(f64, Value)>>> {
+        let mut results = Vec::with_capacity(queries.len());
+        for query in queries.iter() {
+            results.push(self.top_n(query, n).await?);
+        }
+        Ok(results)
+    }
+
+    /// Backend searching the current versions of the tables until it is dropped, so the
+    /// searches of one tool call don't see the tables half updated by the indexer. None if the
+    /// store can't pin its versions, the searches read the current versions then
+    async fn snapshot(&self) -> Result<Option<BackendSnapshot>> {
+        Ok(None)
+    }
+}
+
+/// Backend pinned to the versions of its tables, see [`VectorBackend::snapshot`]
+#[derive(Clone)]
+pub struct BackendSnapshot {
+    /// Versions of the searched tables in the order of the tables
+    pub versions: Vec<u64>,
+    pub backend: Arc<dyn VectorBackend>,
+}
+
+/// Chunks table of LanceDB searched by its vector index
+pub struct LanceDbBackend {
+    pub db: Connection,
+    pub table: Table,
+    pub index: LanceDbVectorIndex<IndexEmbeddingModel>,
+    pub embedding_model: IndexEmbeddingModel,
+    pub ndims: usize,
+    pub table_config: TableConfig,
+    pub search_params: SearchParamsConfig,
+    /// How the paths of the deleted chunks are compared
+    pub path_case: PathCaseMode,
+    /// Last snapshot of the table, reused while the table has its version
+    pub snapshot: Mutex<Option<BackendSnapshot>>,
+}
+
+#[async_trait]
+impl VectorBackend for LanceDbBackend {
+    async fn upsert(&self, chunks: Vec<(ArcTextChunk, OneOrMany<Embedding>)>) -> Result<()> {
+        let ids = chunks
+            .iter()
+            .map(|(chunk, _)| chunk.id.to_hash())
+            .collect_vec();
+        self.delete_by_ids(&ids).await?;
+
+        let count = chunks.len();
+        let records_batch =
+            as_record_batch(chunks, self.ndims, &self.table_config).map_err(|e| {
+                SemantrixError::lancedb(format!(
+                    "Failed to build record batch of {} chunks for table {}: {}",
+                    count,
+                    self.table.name(),
+                    e
+                ))
+                .with_help("check the model files, the model returns embeddings of another size")
+            })?;
+        let record_batch_iter = RecordBatchIterator::new(
+            vec![Ok(records_batch)],
+            Arc::new(schema(self.ndims, &self.table_config)),
+        );
+        self.table
+            .add(record_batch_iter)
+            .execute()
+            .await
+            .map_err(|e| SemantrixError::lancedb(format!("Failed to add chunks: {}", e)))?;
+        Ok(())
+    }
+
+    async fn delete_by_ids(&self, ids: &[String]) -> Result<usize> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let filter = format!(
+            "{} IN ({})",
+            self.table_config.id_field,
+            ids.iter()
+                .map(|it| format!("'{}'", quote_literal(it)))
+                .join(", ")
+        );
+        let deleted = self
+            .table
+            .count_rows(Some(filter.clone()))
+            .await
+            .map_err(|e| {
+                SemantrixError::lancedb(format!("Failed to count chunks of ids: {}", e))
+            })?;
+        if deleted > 0 {
+            self.table.delete(&filter).await.map_err(|e| {
+                SemantrixError::lancedb(format!("Failed to delete chunks of ids: {}", e))
+            })?;
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_by_paths(&self, paths: &[PathBuf]) -> Result<usize> {
+        delete_by_paths(&self.table, paths, &self.table_config, self.path_case).await
+    }
+
+    async fn top_n(&self, query: &str, n: usize) -> Result<Vec<(f64, Value)>> {
+        let started = Instant::now();
+        let rows = self.index.top_n(query, n).await.map_err(|e| {
+            SemantrixError::lancedb(format!("Failed to search vector index: {}", e))
+        })?;
+        histogram!(VECTOR_SEARCH_SECONDS).record(started.elapsed().as_secs_f64());
+        Ok(rows
+            .into_iter()
+            .map(|(score, _, row)| (score, chunk_payload(row, &self.table_config)))
+            .collect())
+    }
+
+    /// Embeds the queries in one batch, then searches every embedding
+    async fn top_n_many(&self, queries: &[String], n: usize) -> Result<Vec<Vec<(f64, Value)>>> {
+        if queries.is_empty() {
+            return Ok(vec![]);
+        }
+        let embeddings = self
+            .embedding_model
+            .embed_texts(queries.iter().cloned())
+            .await
+            .map_err(|e| SemantrixError::lancedb(format!("Failed to embed queries: {}", e)))?;
+        try_join_all(embeddings.iter().map(|embedding| async move {
+            let started = Instant::now();
+            let nearest = search_by_embedding(
+                &self.table,
+                &embedding.vec,
+                n,
+                &self.table_config,
+                &self.search_params,
+            )
+            .await?;
+            histogram!(VECTOR_SEARCH_SECONDS).record(started.elapsed().as_secs_f64());
+            Ok::<_, miette::Report>(
+                nearest
+                    .into_iter()
+                    .map(|(chunk, distance)| (distance as f64, stored_payload(chunk)))
+                    .collect(),
+            )
+        }))
+        .await
+    }
+
+    async fn text_top_n_many(
+        &self,
+        queries: &[String],
+        n: usize,
+    ) -> Result<Vec<Vec<(f64, Value)>>> {
+        try_join_all(queries.iter().map(|query| async move {
+            let found = search_by_text(&self.table, query, n, &self.table_config).await?;
+            Ok::<_, miette::Report>(
+                found
+                    .into_iter()
+                    .map(|(chunk, score)| (score as f64, stored_payload(chunk)))
+                    .collect(),
+            )
+        }))
+        .await
+    }
+
+    /// Reopens the table checked out at its current version, the separate handle keeps the
+    /// table of the indexer at the latest version
+    async fn snapshot(&self) -> Result<Option<BackendSnapshot>> {
+        let version =
+            self.table.version().await.map_err(|e| {
+                SemantrixError::lancedb(format!("Failed to read table version: {}", e))
+            })?;
+        let cached = self
+            .snapshot
+            .lock()
+            .ok()
+            .and_then(|it| it.clone())
+            .filter(|it| it.versions == [version]);
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        let table = self
+            .db
+            .open_table(self.table.name())
+            .execute()
+            .await
+            .map_err(|e| {
+                SemantrixError::lancedb(format!(
+                    "Failed to open table {} at version {}: {}",
+                    self.table.name(),
+                    version,
+                    e
+                ))
+            })?;
+        table.checkout(version).await.map_err(|e| {
+            SemantrixError::lancedb(format!("Failed to check out table version: {}", e))
+        })?;
+        let index = LanceDbVectorIndex::new(
+            table.clone(),
+            self.embedding_model.clone(),
+            &self.table_config.id_field,
+            self.search_params
+                .search_params(&self.table_config.embedding_field),
+        )
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to create vector index: {}", e)))?;
+        let snapshot = BackendSnapshot {
+            versions: vec![version],
+            backend: Arc::new(LanceDbBackend {
+                db: self.db.clone(),
+                table,
+                index,
+                embedding_model: self.embedding_model.clone(),
+                ndims: self.ndims,
+                table_config: self.table_config.clone(),
+                search_params: self.search_params.clone(),
+                path_case: self.path_case,
+                snapshot: Mutex::default(),
+            }),
+        };
+        if let Ok(mut cached) = self.snapshot.lock() {
+            *cached = Some(snapshot.clone());
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+/// Chunks table of one model of [`RoutedBackend`]
+#[derive(Clone)]
+pub struct RouteTable {
+    pub model: String,
+    pub embedding_model: IndexEmbeddingModel,
+    /// Dimensions of the embeddings of the model, the embeddings of another length are not written
+    pub ndims: usize,
+    pub table: Table,
+    pub vector_store: Arc<dyn VectorBackend>,
+}
+
+/// Tables of the models of `search.semantic.model_routes`, the chunks of a file are stored in
+/// the table of the model of its first matched route or in the one of the default model. The
+/// searches query every searched table and merge the results by their scores normalized per
+/// model, a single table returns its scores as they are
+#[derive(Clone)]
+pub struct RoutedBackend {
+    /// Globs of the routes relative to the workspace with the indexes of their tables
+    routes: Arc<Vec<(Glob<'static>, usize)>>,
+    /// Table of the default model first, then the ones of the route models
+    tables: Vec<RouteTable>,
+    /// Indexes of the tables the searches query
+    searched: Vec<usize>,
+    workspace_path: PathBuf,
+}
+
+impl RoutedBackend {
+    pub fn new(
+        routes: &[ModelRouteConfig],
+        tables: Vec<RouteTable>,
+        workspace_path: PathBuf,
+        path_case: PathCaseMode,
+    ) -> Result<Self> {
+        let routes = routes
+            .iter()
+            .map(|route| {
+                let glob = path_case
+                    .glob(&route.pattern)
+                    .map_err(|e| SemantrixError::config(format!("Invalid route pattern: {}", e)))?;
+                let index = tables
+                    .iter()
+                    .position(|it| it.model == route.model)
+                    .ok_or_else(|| {
+                        SemantrixError::config(format!("Model {} has no table", route.model))
+                    })?;
+                Ok((glob, index))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            routes: Arc::new(routes),
+            searched: (0..tables.len()).collect(),
+            tables,
+            workspace_path,
+        })
+    }
+
+    pub fn tables(&self) -> &[RouteTable] {
+        &self.tables
+    }
+
+    /// Table of the chunks of the file
+    pub fn table_of(&self, path: &Path) -> &RouteTable {
+        let relative = path.strip_prefix(&self.workspace_path).unwrap_or(path);
+        let index = self
+            .routes
+            .iter()
+            .find(|(glob, _)| glob.is_match(relative))
+            .map_or(0, |(_, index)| *index);
+        &self.tables[index]
+    }
+
+    /// Number of chunks of the tables of all models
+    pub async fn count_chunks(&self) -> Result<usize> {
+        let counts = try_join_all(self.tables.iter().map(|it| count_chunks(&it.table))).await?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Number of files of the tables of all models, a file is stored in the table of one model
+    pub async fn count_indexed_files(&self, table_config: &TableConfig) -> Result<usize> {
+        let counts = try_join_all(
+            self.tables
+                .iter()
+                .map(|it| count_indexed_files(&it.table, table_config)),
+        )
+        .await?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Names of the models of the tables, the default one first
+    pub fn models(&self) -> Vec<String> {
+        self.tables.iter().map(|it| it.model.clone()).collect()
+    }
+
+    /// Backend searching the tables of the models only, the writes still go to every table
+    pub fn only(&self, models: &[String]) -> Result<Self> {
+        let searched = models
+            .iter()
+            .map(|model| {
+                self.tables
+                    .iter()
+                    .position(|it| it.model == *model)
+                    .ok_or_else(|| {
+                        SemantrixError::config(format!(
+                            "Unknown model {}, expected one of {}",
+                            model,
+                            self.models().join(", ")
+                        ))
+                        .into()
+                    })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            searched: searched.into_iter().unique().collect(),
+            ..self.clone()
+        })
+    }
+
+    /// Results of every searched table merged by the search, see [`merge_rankings`]
+    async fn search_all<'a, F, Fut>(
+        &'a self,
+        queries: &'a [String],
+        higher_is_better: bool,
+        search: F,
+    ) -> Result<Vec<Vec<(f64, Value)>>>
+    where
+        F: Fn(&'a RouteTable) -> Fut,
+        Fut: Future<Output = Result<Vec<Vec<(f64, Value)>>>>,
+    {
+        let results = try_join_all(
+            self.searched
+                .iter()
+                .map(|index| search(&self.tables[*index])),
+        )
+        .await?;
+        if results.len() == 1 {
+            return Ok(results.into_iter().next().unwrap_or_default());
+        }
+        let mut results = results.into_iter().map(Vec::into_iter).collect_vec();
+        Ok(queries
+            .iter()
+            .map(|_| {
+                merge_rankings(
+                    results
+                        .iter_mut()
+                        .map(|it| it.next().unwrap_or_default())
+                        .collect(),
+                    higher_is_better,
+                )
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl VectorBackend for RoutedBackend {
+    /// Upserts the chunks into the tables of their routes, the chunks must be embedded by the
+    /// models of their routes
+    async fn upsert(&self, chunks: Vec<(ArcTextChunk, OneOrMany<Embedding>)>) -> Result<()> {
+        let mut groups = chunks
+            .into_iter()
+            .into_group_map_by(|(chunk, _)| self.table_of(&chunk.path).model.clone());
+        for table in self.tables.iter() {
+            if let Some(chunks) = groups.remove(&table.model) {
+                table.vector_store.upsert(chunks).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_by_ids(&self, ids: &[String]) -> Result<usize> {
+        let mut deleted = 0;
+        for table in self.tables.iter() {
+            deleted += table.vector_store.delete_by_ids(ids).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_by_paths(&self, paths: &[PathBuf]) -> Result<usize> {
+        let mut deleted = 0;
+        for table in self.tables.iter() {
+            deleted += table.vector_store.delete_by_paths(paths).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn top_n(&self, query: &str, n: usize) -> Result<Vec<(f64, Value)>> {
+        let queries = [query.to_string()];
+        Ok(self
+            .top_n_many(&queries, n)
+            .await?
+            .into_iter()
+            .next()
+            .unwrap_or_default())
+    }
+
+    async fn text_top_n_many(
+        &self,
+        queries: &[String],
+        n: usize,
+    ) -> Result<Vec<Vec<(f64, Value)>>> {
+        let results = self
+            .search_all(queries, true, |table| {
+                table.vector_store.text_top_n_many(queries, n)
+            })
+            .await?;
+        Ok(results
+            .into_iter()
+            .map(|it| it.into_iter().take(n).collect())
+            .collect())
+    }
+
+    async fn top_n_many(&self, queries: &[String], n: usize) -> Result<Vec<Vec<(f64, Value)>>> {
+        let results = self
+            .search_all(queries, false, |table| {
+                table.vector_store.top_n_many(queries, n)
+            })
+            .await?;
+        Ok(results
+            .into_iter()
+            .map(|it| it.into_iter().take(n).collect())
+            .collect())
+    }
+
+    /// Snapshots of every searched table, None if a table can't be pinned
+    async fn snapshot(&self) -> Result<Option<BackendSnapshot>> {
+        let snapshots = try_join_all(
+            self.searched
+                .iter()
+                .map(|index| self.tables[*index].vector_store.snapshot()),
+        )
+        .await?;
+        let Some(snapshots) = snapshots.into_iter().collect::<Option<Vec<_>>>() else {
+            return Ok(None);
+        };
+        let mut tables = self.tables.clone();
+        let mut versions = Vec::with_capacity(snapshots.len());
+        for (index, snapshot) in self.searched.iter().zip(snapshots) {
+            versions.extend(snapshot.versions);
+            tables[*index].vector_store = snapshot.backend;
+        }
+        Ok(Some(BackendSnapshot {
+            versions,
+            backend: Arc::new(Self {
+                tables,
+                ..self.clone()
+            }),
+        }))
+    }
+}
+
+/// Results of one query found in the tables of several models, the scores of every table are
+/// scaled to the 0..1 range of its results, since the distances of the models are not
+/// comparable. The best results come first
+fn merge_rankings(rankings: Vec<Vec<(f64, Value)>>, higher_is_better: bool) -> Vec<(f64, Value)> {
+    rankings
+        .into_iter()
+        .flat_map(|ranking| {
+            let (min, max) = ranking
+                .iter()
+                .map(|(score, _)| *score)
+                .minmax()
+                .into_option()
+                .unwrap_or_default();
+            ranking.into_iter().map(move |(score, value)| {
+                let scaled = if max > min {
+                    (score - min) / (max - min)
+                } else if higher_is_better {
+                    1.0
+                } else {
+                    0.0
+                };
+                (scaled, value)
+            })
+        })
+        .sorted_by(|a, b| {
+            let order = a.0.total_cmp(&b.0);
+            if higher_is_better {
+                order.reverse()
+            } else {
+                order
+            }
+        })
+        .collect()
+}
+
+/// Fields of [`ChunkId`](crate::subsystems::chunker::ChunkId) of the stored chunk
+fn stored_payload(chunk: StoredChunk) -> Value {
+    json!({
+        "id": chunk.id,
+        "path": chunk.path,
+        "start_line": chunk.start_line,
+        "end_line": chunk.end_line,
+        "hash": chunk.hash,
+        "indexed_at": chunk.indexed_at.map(|it| it.timestamp_millis()),
+    })
+}
+
+/// Row found by the vector index with the configured field names renamed to the ones of
+/// [`ChunkId`](crate::subsystems::chunker::ChunkId)
+fn chunk_payload(mut row: Value, table_config: &TableConfig) -> Value {
+    let fields = [
+        ("id", &table_config.id_field),
+        ("path", &table_config.path_field),
+        ("start_line", &table_config.start_line_field),
+        ("end_line", &table_config.end_line_field),
+        ("hash", &table_config.hash_field),
+        ("indexed_at", &table_config.indexed_at_field),
+    ]
+    .into_iter()
+    .map(|(name, field)| {
+        let value = row.get_mut(field.as_str()).map(Value::take);
+        let value = match value {
+            Some(Value::String(path)) if name == "path" => Some(Value::String(
+                native_path(&path).to_string_lossy().to_string(),
+            )),
+            value => value,
+        };
+        (name.to_string(), value.unwrap_or_default())
+    })
+    .collect::<Map<_, _>>();
+    Value::Object(fields)
+}
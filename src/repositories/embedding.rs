@@ -0,0 +1,80 @@
+use rig::embeddings::{Embedding, EmbeddingError, EmbeddingModel};
+use sha2::{Digest, Sha256};
+
+/// Name of `search.semantic.model` selecting the [`StubEmbeddingModel`]
+pub const STUB_MODEL: &str = "stub";
+/// Dimensions of the vectors of the [`StubEmbeddingModel`]
+pub const STUB_MODEL_DIMS: usize = 256;
+
+/// Embedding model of the index, the stub one needs no model files and no ONNX runtime
+#[derive(Clone)]
+pub enum IndexEmbeddingModel {
+    Fastembed(rig_fastembed::EmbeddingModel),
+    Stub(StubEmbeddingModel),
+}
+
+impl IndexEmbeddingModel {
+    pub fn name(&self) -> String {
+        match self {
+            IndexEmbeddingModel::Fastembed(model) => model.model.to_string(),
+            IndexEmbeddingModel::Stub(_) => STUB_MODEL.to_string(),
+        }
+    }
+}
+
+impl EmbeddingModel for IndexEmbeddingModel {
+    const MAX_DOCUMENTS: usize = rig_fastembed::EmbeddingModel::MAX_DOCUMENTS;
+
+    fn ndims(&self) -> usize {
+        match self {
+            IndexEmbeddingModel::Fastembed(model) => model.ndims(),
+            IndexEmbeddingModel::Stub(model) => model.ndims,
+        }
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        match self {
+            IndexEmbeddingModel::Fastembed(model) => model.embed_texts(texts).await,
+            IndexEmbeddingModel::Stub(model) => Ok(texts
+                .into_iter()
+                .map(|document| Embedding {
+                    vec: model.embed(&document),
+                    document,
+                })
+                .collect()),
+        }
+    }
+}
+
+/// Deterministic model hashing the words of the text into the buckets of the vector, the texts
+/// sharing more words are closer, so the index and the search work without a real model
+#[derive(Clone, Debug)]
+pub struct StubEmbeddingModel {
+    pub ndims: usize,
+}
+
+impl StubEmbeddingModel {
+    /// Unit vector of the signed counts of the hashed lowercase words, zero for a text without
+    /// words
+    pub fn embed(&self, text: &str) -> Vec<f64> {
+        let mut vec = vec![0.0; self.ndims];
+        for word in text
+            .split(|it: char| !it.is_alphanumeric())
+            .filter(|it| !it.is_empty())
+        {
+            let digest = Sha256::digest(word.to_lowercase().as_bytes());
+            let mut bucket = [0; 8];
+            bucket.copy_from_slice(&digest[..8]);
+            let index = (u64::from_le_bytes(bucket) % self.ndims as u64) as usize;
+            vec[index] += if digest[8] & 1 == 0 { 1.0 } else { -1.0 };
+        }
+        let norm = vec.iter().map(|it| it * it).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            vec.iter_mut().for_each(|it| *it /= norm);
+        }
+        vec
+    }
+}
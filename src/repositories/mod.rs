@@ -1,46 +1,1328 @@
+pub mod backend;
+pub mod embedding;
+
+use arrow_array::{
+    Array, FixedSizeListArray, Float32Array, Float64Array, Int64Array, RecordBatch, StringArray,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{Stream, TryStreamExt};
+use itertools::Itertools;
 use lancedb::{
-    Table,
-    table::{OptimizeAction, OptimizeOptions},
+    DistanceType, Table,
+    arrow::arrow_schema::DataType,
+    index::{
+        Index,
+        scalar::{FtsIndexBuilder, FullTextSearchQuery},
+    },
+    query::{ExecutableQuery, QueryBase, Select},
+    table::{CompactionOptions, OptimizeAction, OptimizeOptions},
+};
+use miette::Result;
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
 };
-use miette::{IntoDiagnostic, Result};
-use std::path::Path;
 use tracing::{info, trace};
 
-use crate::DEFAULT_CHUNKS_PATH_FIELD;
+use crate::{
+    SearchParamsConfig, TableConfig,
+    enums::PathCaseMode,
+    errors::SemantrixError,
+    services::{
+        extractors::{ARCHIVE_ENTRY_SEPARATOR, is_archive},
+        native_path, normalize_path, storage_path,
+    },
+};
+
+const DISTANCE_FIELD: &str = "_distance";
+const SCORE_FIELD: &str = "_score";
+
+/// Number of the paths with the most chunks in [`TableStats`]
+const TOP_PATHS: usize = 10;
+
+/// Time the stats of a table are reused for, so they can be read on every status request
+const TABLE_STATS_TTL: Duration = Duration::from_secs(5);
+
+static TABLE_STATS: Lazy<Mutex<HashMap<String, (Instant, TableStats)>>> =
+    Lazy::new(Default::default);
+
+static CHUNKS_COUNTS: Lazy<Mutex<HashMap<String, (Instant, usize)>>> = Lazy::new(Default::default);
+
+/// Size and indexes of the chunks table
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct TableStats {
+    pub rows: usize,
+    pub paths: usize,
+    /// Paths with the most chunks, the most chunked first
+    pub top_paths: Vec<PathRows>,
+    /// Paths written last, the most recent first
+    pub recently_indexed: Vec<PathRows>,
+    /// Size of the table files, None for the remote stores
+    pub disk_bytes: Option<u64>,
+    pub path_index: bool,
+    pub embeddings_index: bool,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct PathRows {
+    pub path: PathBuf,
+    pub rows: usize,
+    /// Last write of the chunks of the path, None for the chunks imported without it
+    #[schemars(with = "Option<String>")]
+    pub indexed_at: Option<DateTime<Utc>>,
+}
+
+/// Chunk row of the table, the id, the embedding and the text are empty if they were not
+/// selected
+#[derive(Debug, Clone)]
+pub struct StoredChunk {
+    /// Hash of the path and the lines of the chunk, see [`ChunkId::to_hash`](crate::subsystems::chunker::ChunkId::to_hash)
+    pub id: String,
+    pub path: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub embedding: Vec<f64>,
+    pub text: Option<String>,
+    /// Hash of the text, see [`TextChunk::text_hash`](crate::subsystems::chunker::TextChunk::text_hash)
+    pub hash: Option<String>,
+    /// Write time of the chunk, None if it was not selected
+    pub indexed_at: Option<DateTime<Utc>>,
+    /// Position of the chunk in its file, None if it was not selected
+    pub chunk_index: Option<usize>,
+    /// Number of the chunks of the file, None if it was not selected
+    pub total_chunks: Option<usize>,
+}
+
+fn stored_chunks(batch: &RecordBatch, table_config: &TableConfig) -> Vec<StoredChunk> {
+    let ids = batch
+        .column_by_name(&table_config.id_field)
+        .and_then(|it| it.as_any().downcast_ref::<StringArray>());
+    let paths = batch
+        .column_by_name(&table_config.path_field)
+        .and_then(|it| it.as_any().downcast_ref::<StringArray>());
+    let start_lines = batch
+        .column_by_name(&table_config.start_line_field)
+        .and_then(|it| it.as_any().downcast_ref::<Int64Array>());
+    let end_lines = batch
+        .column_by_name(&table_config.end_line_field)
+        .and_then(|it| it.as_any().downcast_ref::<Int64Array>());
+    let embeddings = batch
+        .column_by_name(&table_config.embedding_field)
+        .and_then(|it| it.as_any().downcast_ref::<FixedSizeListArray>());
+    let texts = batch
+        .column_by_name(&table_config.text_field)
+        .and_then(|it| it.as_any().downcast_ref::<StringArray>());
+    let hashes = batch
+        .column_by_name(&table_config.hash_field)
+        .and_then(|it| it.as_any().downcast_ref::<StringArray>());
+    let indexed_ats = batch
+        .column_by_name(&table_config.indexed_at_field)
+        .and_then(|it| it.as_any().downcast_ref::<Int64Array>());
+    let chunk_indexes = batch
+        .column_by_name(&table_config.chunk_index_field)
+        .and_then(|it| it.as_any().downcast_ref::<Int64Array>());
+    let total_chunks = batch
+        .column_by_name(&table_config.total_chunks_field)
+        .and_then(|it| it.as_any().downcast_ref::<Int64Array>());
+
+    let (Some(paths), Some(start_lines), Some(end_lines)) = (paths, start_lines, end_lines) else {
+        return vec![];
+    };
+
+    (0..batch.num_rows())
+        .map(|row| StoredChunk {
+            id: ids.map(|it| it.value(row).to_string()).unwrap_or_default(),
+            path: native_path(paths.value(row)),
+            start_line: start_lines.value(row) as usize,
+            end_line: end_lines.value(row) as usize,
+            embedding: embeddings
+                .and_then(|it| {
+                    it.value(row)
+                        .as_any()
+                        .downcast_ref::<Float64Array>()
+                        .map(|it| it.values().to_vec())
+                })
+                .unwrap_or_default(),
+            text: texts
+                .filter(|it| it.is_valid(row))
+                .map(|it| it.value(row).to_string()),
+            hash: hashes
+                .filter(|it| it.is_valid(row))
+                .map(|it| it.value(row).to_string()),
+            indexed_at: indexed_ats
+                .filter(|it| it.is_valid(row))
+                .and_then(|it| DateTime::from_timestamp_millis(it.value(row))),
+            chunk_index: chunk_indexes
+                .filter(|it| it.is_valid(row))
+                .map(|it| it.value(row) as usize),
+            total_chunks: total_chunks
+                .filter(|it| it.is_valid(row))
+                .map(|it| it.value(row) as usize),
+        })
+        .collect()
+}
+
+/// Last write of the chunks of a path, see [`chunks_for_path`]
+pub fn last_indexed_at(chunks: &[StoredChunk]) -> Option<DateTime<Utc>> {
+    chunks.iter().filter_map(|it| it.indexed_at).max()
+}
 
 pub async fn optimize_index(table: &Table) -> Result<()> {
     table
         .optimize(OptimizeAction::Index(OptimizeOptions::default()))
         .await
-        .into_diagnostic()?;
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to optimize indexes: {}", e)))?;
     Ok(())
 }
 
-pub async fn delete_by_path(table: &Table, path: &Path) -> Result<()> {
-    if path.is_dir() {
-        info!("Deleting all chunks for folder: {}", path.display());
+/// Space reclaimed by [`vacuum`]
+#[derive(Clone, Debug, Default)]
+pub struct VacuumStats {
+    pub bytes_removed: u64,
+    pub old_versions: u64,
+    pub fragments_removed: usize,
+}
+
+/// Compacts the data files with their deleted rows and removes the versions older than the
+/// retention, the table must not be written meanwhile
+pub async fn vacuum(table: &Table, older_than: Duration) -> Result<VacuumStats> {
+    let compaction = table
+        .optimize(OptimizeAction::Compact {
+            options: CompactionOptions::default(),
+            remap_options: None,
+        })
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to compact table: {}", e)))?
+        .compaction;
+    let prune =
         table
-            .delete(&format!(
-                r#"{} LIKE '{}%'"#,
-                DEFAULT_CHUNKS_PATH_FIELD,
-                path.to_string_lossy()
-            ))
+            .optimize(OptimizeAction::Prune {
+                older_than: Some(TimeDelta::from_std(older_than).map_err(|e| {
+                    SemantrixError::config(format!("Invalid vacuum retention: {}", e))
+                })?),
+                delete_unverified: Some(false),
+                error_if_tagged_old_versions: Some(false),
+            })
             .await
-            .into_diagnostic()?;
-        optimize_index(table).await?;
+            .map_err(|e| SemantrixError::lancedb(format!("Failed to prune table versions: {}", e)))?
+            .prune;
+    Ok(VacuumStats {
+        bytes_removed: prune.as_ref().map_or(0, |it| it.bytes_removed),
+        old_versions: prune.as_ref().map_or(0, |it| it.old_versions),
+        fragments_removed: compaction.map_or(0, |it| it.fragments_removed),
+    })
+}
+
+/// Max number of paths in one delete statement, longer statements are split
+const MAX_PATHS_PER_FILTER: usize = 100;
+
+/// Filter of the chunks of the files of the directory
+fn path_prefix_filter(path: &Path, table_config: &TableConfig, path_case: PathCaseMode) -> String {
+    // the trailing separator keeps siblings like `src_gen` of `src` out of the match
+    let mut prefix = filter_path(path, path_case);
+    if !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    format!(
+        r#"{} LIKE '{}%' ESCAPE '\'"#,
+        path_column(table_config, path_case),
+        escape_like(&prefix)
+    )
+}
+
+/// Filter of the chunks of the entries of the archive, see
+/// [`entry_path`](crate::services::extractors::entry_path)
+fn archive_entries_filter(
+    path: &Path,
+    table_config: &TableConfig,
+    path_case: PathCaseMode,
+) -> String {
+    let mut prefix = filter_path(path, path_case);
+    prefix.push(ARCHIVE_ENTRY_SEPARATOR);
+    format!(
+        r#"{} LIKE '{}%' ESCAPE '\'"#,
+        path_column(table_config, path_case),
+        escape_like(&prefix)
+    )
+}
+
+fn path_equals_filter(path: &Path, table_config: &TableConfig, path_case: PathCaseMode) -> String {
+    format!(
+        "{} = '{}'",
+        path_column(table_config, path_case),
+        quote_literal(&filter_path(path, path_case))
+    )
+}
+
+/// Path column of the filters, it is folded to the lower case if the paths are case
+/// insensitive, so the chunks of every casing of a file are matched
+fn path_column(table_config: &TableConfig, path_case: PathCaseMode) -> String {
+    if path_case.is_insensitive() {
+        format!("lower({})", table_config.path_field)
     } else {
-        trace!("Deleting chunk for file: {}", path.display());
+        table_config.path_field.clone()
+    }
+}
+
+/// Stored path of the filters folded like [`path_column`]
+fn filter_path(path: &Path, path_case: PathCaseMode) -> String {
+    if path_case.is_insensitive() {
+        storage_path(path).to_lowercase()
+    } else {
+        storage_path(path)
+    }
+}
+
+pub(crate) fn quote_literal(literal: &str) -> String {
+    literal.replace('\'', "''")
+}
+
+/// Filters of the chunks of the paths, the files are matched by `IN` and the directories and
+/// the entries of the archives by OR'd prefixes, each filter has at most
+/// [`MAX_PATHS_PER_FILTER`] paths. A path which doesn't exist anymore is matched as both, it may
+/// be a removed directory
+fn paths_filters(
+    paths: &[PathBuf],
+    table_config: &TableConfig,
+    path_case: PathCaseMode,
+) -> Vec<String> {
+    let files = paths.iter().filter(|it| !it.is_dir()).collect_vec();
+    let dirs = paths.iter().filter(|it| !it.is_file()).collect_vec();
+    let file_filters = files.chunks(MAX_PATHS_PER_FILTER).map(|chunk| {
+        format!(
+            "{} IN ({})",
+            path_column(table_config, path_case),
+            chunk
+                .iter()
+                .map(|it| format!("'{}'", quote_literal(&filter_path(it, path_case))))
+                .join(", ")
+        )
+    });
+    let dir_filters = dirs.chunks(MAX_PATHS_PER_FILTER).map(|chunk| {
+        chunk
+            .iter()
+            .map(|it| path_prefix_filter(it, table_config, path_case))
+            .join(" OR ")
+    });
+    let archives = paths.iter().filter(|it| is_archive(it)).collect_vec();
+    let archive_filters = archives.chunks(MAX_PATHS_PER_FILTER).map(|chunk| {
+        chunk
+            .iter()
+            .map(|it| archive_entries_filter(it, table_config, path_case))
+            .join(" OR ")
+    });
+    file_filters
+        .chain(dir_filters)
+        .chain(archive_filters)
+        .collect()
+}
+
+/// Literal of the LIKE pattern, its wildcards `%` and `_` match only themselves
+fn escape_like(literal: &str) -> String {
+    literal
+        .replace('\\', r"\\")
+        .replace('%', r"\%")
+        .replace('_', r"\_")
+        .replace('\'', "''")
+}
+
+/// Deletes chunks of the file or all files of the folder, returns number of deleted chunks
+pub async fn delete_by_path(
+    table: &Table,
+    path: &Path,
+    table_config: &TableConfig,
+    path_case: PathCaseMode,
+) -> Result<usize> {
+    delete_by_paths(table, &[path.to_path_buf()], table_config, path_case).await
+}
+
+/// Deletes chunks of the files and all files of the folders with a few statements, the index
+/// is optimized once if a folder was deleted, returns number of deleted chunks
+pub async fn delete_by_paths(
+    table: &Table,
+    paths: &[PathBuf],
+    table_config: &TableConfig,
+    path_case: PathCaseMode,
+) -> Result<usize> {
+    let paths = paths.iter().map(|it| normalize_path(it)).collect_vec();
+    let mut deleted = 0;
+    for filter in paths_filters(&paths, table_config, path_case) {
+        let rows = table.count_rows(Some(filter.clone())).await.map_err(|e| {
+            SemantrixError::lancedb(format!("Failed to count chunks of paths: {}", e))
+        })?;
+        if rows > 0 {
+            table.delete(&filter).await.map_err(|e| {
+                SemantrixError::lancedb(format!("Failed to delete chunks of paths: {}", e))
+            })?;
+            deleted += rows;
+        }
+    }
+    trace!("Deleted {} chunks of {} paths", deleted, paths.len());
+    if deleted > 0 && paths.iter().any(|it| it.is_dir()) {
+        info!("Optimizing index after deleting folders");
+        optimize_index(table).await?;
+    }
+    Ok(deleted)
+}
+
+pub async fn count_chunks(table: &Table) -> Result<usize> {
+    table
+        .count_rows(None)
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to count chunks: {}", e)).into())
+}
+
+/// Number of chunks of the table, it is cached for [`TABLE_STATS_TTL`] by the table URI, so it
+/// can be checked on every search
+pub async fn cached_count_chunks(table: &Table) -> Result<usize> {
+    let uri = table.dataset_uri().to_string();
+    if let Ok(cache) = CHUNKS_COUNTS.lock()
+        && let Some((read_at, count)) = cache.get(&uri)
+        && read_at.elapsed() < TABLE_STATS_TTL
+    {
+        return Ok(*count);
+    }
+
+    let count = count_chunks(table).await?;
+    if let Ok(mut cache) = CHUNKS_COUNTS.lock() {
+        cache.insert(uri, (Instant::now(), count));
+    }
+    Ok(count)
+}
+
+pub async fn count_indexed_files(table: &Table, table_config: &TableConfig) -> Result<usize> {
+    Ok(distinct_paths(table, table_config).await?.len())
+}
+
+/// Stats of the table, they are cached for [`TABLE_STATS_TTL`] by the table URI
+pub async fn table_stats(table: &Table, table_config: &TableConfig) -> Result<TableStats> {
+    let uri = table.dataset_uri().to_string();
+    if let Ok(cache) = TABLE_STATS.lock()
+        && let Some((read_at, stats)) = cache.get(&uri)
+        && read_at.elapsed() < TABLE_STATS_TTL
+    {
+        return Ok(stats.clone());
+    }
+
+    let batches = table
+        .query()
+        .select(Select::columns(&[
+            &table_config.path_field,
+            &table_config.indexed_at_field,
+        ]))
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query table stats: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read table stats: {}", e)))?;
+    // rows and the last write of every path
+    let mut path_rows: HashMap<&str, (usize, Option<i64>)> = HashMap::new();
+    for batch in batches.iter() {
+        let Some(paths) = batch
+            .column_by_name(&table_config.path_field)
+            .and_then(|it| it.as_any().downcast_ref::<StringArray>())
+        else {
+            continue;
+        };
+        let indexed_ats = batch
+            .column_by_name(&table_config.indexed_at_field)
+            .and_then(|it| it.as_any().downcast_ref::<Int64Array>());
+        for row in (0..batch.num_rows()).filter(|row| paths.is_valid(*row)) {
+            let entry = path_rows.entry(paths.value(row)).or_default();
+            entry.0 += 1;
+            entry.1 = entry.1.max(
+                indexed_ats
+                    .filter(|it| it.is_valid(row))
+                    .map(|it| it.value(row)),
+            );
+        }
+    }
+    let path_rows = path_rows
+        .into_iter()
+        .map(|(path, (rows, indexed_at))| PathRows {
+            path: native_path(path),
+            rows,
+            indexed_at: indexed_at.and_then(DateTime::from_timestamp_millis),
+        })
+        .collect_vec();
+
+    let stats = TableStats {
+        rows: path_rows.iter().map(|it| it.rows).sum(),
+        paths: path_rows.len(),
+        top_paths: path_rows
+            .iter()
+            .sorted_by(|a, b| b.rows.cmp(&a.rows).then_with(|| a.path.cmp(&b.path)))
+            .take(TOP_PATHS)
+            .cloned()
+            .collect(),
+        recently_indexed: path_rows
+            .iter()
+            .filter(|it| it.indexed_at.is_some())
+            .sorted_by(|a, b| {
+                b.indexed_at
+                    .cmp(&a.indexed_at)
+                    .then_with(|| a.path.cmp(&b.path))
+            })
+            .take(TOP_PATHS)
+            .cloned()
+            .collect(),
+        disk_bytes: (!uri.contains("://")).then(|| dir_size(Path::new(&uri))),
+        path_index: table
+            .index_stats(&table_config.path_field)
+            .await
+            .map_err(|e| {
+                SemantrixError::lancedb(format!("Failed to read path index stats: {}", e))
+            })?
+            .is_some(),
+        embeddings_index: has_embeddings_index(table, table_config).await?,
+    };
+    if let Ok(mut cache) = TABLE_STATS.lock() {
+        cache.insert(uri, (Instant::now(), stats.clone()));
+    }
+    Ok(stats)
+}
+
+/// Total size of the files of the directory, unreadable entries are skipped
+fn dir_size(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|it| it.ok())
+                .map(|entry| match entry.metadata() {
+                    Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+                    Ok(metadata) => metadata.len(),
+                    Err(_) => 0,
+                })
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+/// Sorted paths of all indexed files
+pub async fn distinct_paths(table: &Table, table_config: &TableConfig) -> Result<Vec<PathBuf>> {
+    let batches = table
+        .query()
+        .select(Select::columns(&[&table_config.path_field]))
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query paths: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read paths: {}", e)))?;
+
+    let mut paths = BTreeSet::new();
+    for batch in batches.iter() {
+        if let Some(column) = batch
+            .column_by_name(&table_config.path_field)
+            .and_then(|it| it.as_any().downcast_ref::<StringArray>())
+        {
+            paths.extend(column.iter().flatten());
+        }
+    }
+    Ok(paths.into_iter().map(native_path).collect())
+}
+
+/// Chunks stored for the file ordered by their start lines with their write times, embeddings
+/// are not selected
+pub async fn chunks_for_path(
+    table: &Table,
+    path: &Path,
+    table_config: &TableConfig,
+    path_case: PathCaseMode,
+) -> Result<Vec<StoredChunk>> {
+    let batches = table
+        .query()
+        .only_if(path_equals_filter(
+            &normalize_path(path),
+            table_config,
+            path_case,
+        ))
+        .select(Select::columns(&[
+            &table_config.id_field,
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+            &table_config.indexed_at_field,
+        ]))
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query chunks of path: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read chunks of path: {}", e)))?;
+
+    Ok(batches
+        .iter()
+        .flat_map(|batch| stored_chunks(batch, table_config))
+        .sorted_by_key(|it| (it.start_line, it.end_line))
+        .collect())
+}
+
+/// Chunk of the id with its position in the file, None if it is not stored. The embedding and
+/// the text are not selected
+pub async fn chunk_by_id(
+    table: &Table,
+    id: &str,
+    table_config: &TableConfig,
+) -> Result<Option<StoredChunk>> {
+    let batches = table
+        .query()
+        .only_if(format!(
+            "{} = '{}'",
+            table_config.id_field,
+            quote_literal(id)
+        ))
+        .select(Select::columns(&[
+            &table_config.id_field,
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+            &table_config.hash_field,
+            &table_config.indexed_at_field,
+            &table_config.chunk_index_field,
+            &table_config.total_chunks_field,
+        ]))
+        .limit(1)
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query chunk: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read chunk: {}", e)))?;
+
+    Ok(batches
+        .iter()
+        .flat_map(|batch| stored_chunks(batch, table_config))
+        .next())
+}
+
+/// Chunks of the file whose positions are in the range ordered by their positions, the
+/// embeddings and the texts are not selected
+pub async fn chunks_by_index(
+    table: &Table,
+    path: &Path,
+    indexes: RangeInclusive<usize>,
+    table_config: &TableConfig,
+    path_case: PathCaseMode,
+) -> Result<Vec<StoredChunk>> {
+    let batches = table
+        .query()
+        .only_if(format!(
+            "{} AND {} BETWEEN {} AND {}",
+            path_equals_filter(&normalize_path(path), table_config, path_case),
+            table_config.chunk_index_field,
+            indexes.start(),
+            indexes.end()
+        ))
+        .select(Select::columns(&[
+            &table_config.id_field,
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+            &table_config.hash_field,
+            &table_config.indexed_at_field,
+            &table_config.chunk_index_field,
+            &table_config.total_chunks_field,
+        ]))
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query chunks by index: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read chunks by index: {}", e)))?;
+
+    Ok(batches
+        .iter()
+        .flat_map(|batch| stored_chunks(batch, table_config))
+        .sorted_by_key(|it| it.chunk_index)
+        .collect())
+}
+
+/// Number of lines of every indexed file, taken as the end line of its last chunk
+pub async fn file_line_counts(
+    table: &Table,
+    table_config: &TableConfig,
+) -> Result<HashMap<PathBuf, usize>> {
+    let batches = table
+        .query()
+        .select(Select::columns(&[
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+        ]))
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query line counts: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read line counts: {}", e)))?;
+
+    let mut line_counts: HashMap<PathBuf, usize> = HashMap::new();
+    for chunk in batches
+        .iter()
+        .flat_map(|batch| stored_chunks(batch, table_config))
+    {
+        let lines = line_counts.entry(chunk.path).or_default();
+        *lines = (*lines).max(chunk.end_line);
+    }
+    Ok(line_counts)
+}
+
+pub async fn has_embeddings_index(table: &Table, table_config: &TableConfig) -> Result<bool> {
+    Ok(table
+        .index_stats(&table_config.embedding_field)
+        .await
+        .map_err(|e| {
+            SemantrixError::lancedb(format!("Failed to read embeddings index stats: {}", e))
+        })?
+        .is_some())
+}
+
+/// Name LanceDB gives to the full-text index of the text field
+pub fn fts_index_name(table_config: &TableConfig) -> String {
+    format!("{}_idx", table_config.text_field)
+}
+
+pub async fn has_fts_index(table: &Table, table_config: &TableConfig) -> Result<bool> {
+    Ok(table
+        .index_stats(&fts_index_name(table_config))
+        .await
+        .map_err(|e| {
+            SemantrixError::lancedb(format!("Failed to read full-text index stats: {}", e))
+        })?
+        .is_some())
+}
+
+/// Creates the missing full-text index of the chunk text, an empty table is skipped, so the
+/// index is created after the first index scan
+pub async fn create_fts_index(table: &Table, table_config: &TableConfig) -> Result<()> {
+    if has_fts_index(table, table_config).await? {
+        return Ok(());
+    }
+    if table
+        .count_rows(None)
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to count chunks: {}", e)))?
+        == 0
+    {
+        info!("Full-text index is created after the first chunks are indexed");
+        return Ok(());
+    }
+    info!("Creating full-text index of {}", table_config.text_field);
+    table
+        .create_index(
+            &[&table_config.text_field],
+            Index::FTS(FtsIndexBuilder::default()),
+        )
+        .execute()
+        .await
+        .map_err(|e| {
+            SemantrixError::lancedb(format!("Failed to create full-text index: {}", e)).into()
+        })
+}
+
+/// Chunks of the best BM25 scores of the query in the full-text index, higher is better
+pub async fn search_by_text(
+    table: &Table,
+    query: &str,
+    limit: usize,
+    table_config: &TableConfig,
+) -> Result<Vec<(StoredChunk, f32)>> {
+    let batches = table
+        .query()
+        .full_text_search(
+            FullTextSearchQuery::new(query.to_string())
+                .columns(Some(vec![table_config.text_field.clone()])),
+        )
+        .select(Select::columns(&[
+            &table_config.id_field,
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+            &table_config.hash_field,
+            &table_config.indexed_at_field,
+        ]))
+        .limit(limit)
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query full-text search: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read full-text search: {}", e)))?;
+
+    let mut found = Vec::new();
+    for batch in batches.iter() {
+        if let Some(scores) = batch
+            .column_by_name(SCORE_FIELD)
+            .and_then(|it| it.as_any().downcast_ref::<Float32Array>())
+        {
+            found.extend(
+                stored_chunks(batch, table_config)
+                    .into_iter()
+                    .zip(scores.values().iter().copied()),
+            );
+        }
+    }
+    Ok(found)
+}
+
+/// Reads up to `limit` chunks with embeddings whose path is accepted by the filter
+pub async fn read_chunks<F>(
+    table: &Table,
+    filter: F,
+    limit: usize,
+    table_config: &TableConfig,
+) -> Result<Vec<StoredChunk>>
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut batches = table
+        .query()
+        .select(Select::columns(&[
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+            &table_config.embedding_field,
+        ]))
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query chunks: {}", e)))?;
+
+    let mut chunks = Vec::new();
+    while let Some(batch) = batches
+        .try_next()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read chunks: {}", e)))?
+    {
+        chunks.extend(
+            stored_chunks(&batch, table_config)
+                .into_iter()
+                .filter(|chunk| filter(&chunk.path)),
+        );
+        if chunks.len() >= limit {
+            chunks.truncate(limit);
+            break;
+        }
+    }
+    Ok(chunks)
+}
+
+/// Every chunk with its embedding and text batch by batch as the table is read, the text is
+/// empty for the tables written before it was stored
+pub async fn stream_chunks(
+    table: &Table,
+    table_config: &TableConfig,
+) -> Result<impl Stream<Item = Result<Vec<StoredChunk>>>> {
+    let schema = table
+        .schema()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read table schema: {}", e)))?;
+    let columns = table_config
+        .fields()
+        .into_iter()
+        .filter(|field| schema.field_with_name(field).is_ok())
+        .collect_vec();
+    let batches = table
+        .query()
+        .select(Select::columns(&columns))
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query chunks: {}", e)))?;
+    let table_config = table_config.clone();
+    Ok(batches
+        .map_ok(move |batch| stored_chunks(&batch, &table_config))
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read chunks: {}", e)).into()))
+}
+
+/// Number of the dimensions of the embedding field of the table
+pub async fn embedding_dims(table: &Table, table_config: &TableConfig) -> Result<usize> {
+    let schema = table
+        .schema()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read table schema: {}", e)))?;
+    match schema
+        .field_with_name(&table_config.embedding_field)
+        .map_err(|e| SemantrixError::lancedb(format!("Missing embedding field: {}", e)))?
+        .data_type()
+    {
+        DataType::FixedSizeList(_, dims) => Ok(*dims as usize),
+        data_type => Err(SemantrixError::lancedb(format!(
+            "Embedding field {} is not a fixed size list but {}",
+            table_config.embedding_field, data_type
+        ))
+        .into()),
+    }
+}
+
+/// Chunks found by the vector search of the embedding with the search params of the semantic
+/// search and their distances, embeddings are not selected
+pub async fn search_by_embedding(
+    table: &Table,
+    embedding: &[f64],
+    limit: usize,
+    table_config: &TableConfig,
+    search_params: &SearchParamsConfig,
+) -> Result<Vec<(StoredChunk, f32)>> {
+    let mut query = table
+        .query()
+        .nearest_to(embedding)
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to build vector search: {}", e)))?
+        .column(&table_config.embedding_field)
+        .select(Select::columns(&[
+            &table_config.id_field,
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+            &table_config.hash_field,
+            &table_config.indexed_at_field,
+        ]))
+        .limit(limit);
+    if let Some(distance_type) = search_params.distance_type {
+        query = query.distance_type(distance_type.into());
+    }
+    if let Some(nprobes) = search_params.nprobes {
+        query = query.nprobes(nprobes);
+    }
+    if let Some(refine_factor) = search_params.refine_factor {
+        query = query.refine_factor(refine_factor);
+    }
+    if search_params.post_filter == Some(true) {
+        query = query.postfilter();
+    }
+    let batches = query
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query vector search: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read vector search: {}", e)))?;
+
+    Ok(with_distances(&batches, table_config))
+}
+
+/// Chunks of the batches of a vector search zipped with their distances
+fn with_distances(batches: &[RecordBatch], table_config: &TableConfig) -> Vec<(StoredChunk, f32)> {
+    let mut nearest = Vec::new();
+    for batch in batches.iter() {
+        if let Some(distances) = batch
+            .column_by_name(DISTANCE_FIELD)
+            .and_then(|it| it.as_any().downcast_ref::<Float32Array>())
+        {
+            nearest.extend(
+                stored_chunks(batch, table_config)
+                    .into_iter()
+                    .zip(distances.values().iter().copied()),
+            );
+        }
+    }
+    nearest
+}
+
+/// Nearest chunks to the embedding with their cosine distances, embeddings are not selected
+pub async fn nearest_chunks(
+    table: &Table,
+    embedding: &[f64],
+    limit: usize,
+    table_config: &TableConfig,
+) -> Result<Vec<(StoredChunk, f32)>> {
+    let batches = table
+        .query()
+        .nearest_to(embedding)
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to build vector search: {}", e)))?
+        .column(&table_config.embedding_field)
+        .distance_type(DistanceType::Cosine)
+        .select(Select::columns(&[
+            &table_config.path_field,
+            &table_config.start_line_field,
+            &table_config.end_line_field,
+        ]))
+        .limit(limit)
+        .execute()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to query nearest chunks: {}", e)))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| SemantrixError::lancedb(format!("Failed to read nearest chunks: {}", e)))?;
+
+    Ok(with_distances(&batches, table_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow_array::{ArrayRef, RecordBatchIterator, new_null_array, types::Float64Type};
+
+    use super::*;
+    use crate::subsystems::indexer::schema;
+
+    /// Dimensions of the embeddings of the test tables
+    const NDIMS: usize = 2;
+
+    /// Empty folder of the test in the temp dir, its path is normalized like the stored paths
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "semantrix-repositories-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        normalize_path(&dir)
+    }
+
+    /// Chunks table in the store of the folder with a chunk of every path and its lines, the
+    /// fields which are not read by the queries are empty
+    async fn chunks_table(dir: &Path, chunks: &[(&Path, usize, usize)]) -> Table {
+        let table_config = TableConfig::default();
+        let schema = Arc::new(schema(NDIMS, &table_config));
+        let db = lancedb::connect(&storage_path(&dir.join("store")))
+            .execute()
+            .await
+            .unwrap();
+        let table = db
+            .create_empty_table(&table_config.name, schema.clone())
+            .execute()
+            .await
+            .unwrap();
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(chunks.iter().map(
+                |(path, start_line, end_line)| {
+                    format!("{}:{}:{}", storage_path(path), start_line, end_line)
+                },
+            ))),
+            Arc::new(StringArray::from_iter_values(
+                chunks.iter().map(|(path, _, _)| storage_path(path)),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                chunks.iter().map(|(_, start_line, _)| *start_line as i64),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                chunks.iter().map(|(_, _, end_line)| *end_line as i64),
+            )),
+            Arc::new(
+                FixedSizeListArray::from_iter_primitive::<Float64Type, _, _>(
+                    chunks.iter().map(|_| Some(vec![Some(0.0); NDIMS])),
+                    NDIMS as i32,
+                ),
+            ),
+        ];
+        columns.extend(
+            schema.fields()[columns.len()..]
+                .iter()
+                .map(|it| new_null_array(it.data_type(), chunks.len())),
+        );
+        let batch = RecordBatch::try_new(schema.clone(), columns).unwrap();
         table
-            .delete(
-                format!(
-                    r#"{} = "{}""#,
-                    DEFAULT_CHUNKS_PATH_FIELD,
-                    path.to_string_lossy()
-                )
-                .as_str(),
-            )
+            .add(RecordBatchIterator::new(vec![Ok(batch)], schema))
+            .execute()
             .await
-            .into_diagnostic()?;
+            .unwrap();
+        table
+    }
+
+    /// Pattern of the LIKE filter with its SQL quotes unescaped
+    fn like_pattern(filter: &str) -> String {
+        let start = filter.find("LIKE '").unwrap() + "LIKE '".len();
+        let end = filter.rfind("' ESCAPE").unwrap();
+        filter[start..end].replace("''", "'")
+    }
+
+    /// LIKE of the filters with `\` escaping, enough to check what the patterns match
+    fn is_like(value: &str, pattern: &str) -> bool {
+        let value = value.chars().collect_vec();
+        let pattern = pattern.chars().collect_vec();
+        fn matches(value: &[char], pattern: &[char]) -> bool {
+            match pattern {
+                [] => value.is_empty(),
+                ['%', rest @ ..] => (0..=value.len()).any(|skip| matches(&value[skip..], rest)),
+                ['_', rest @ ..] => !value.is_empty() && matches(&value[1..], rest),
+                ['\\', escaped, rest @ ..] => {
+                    value.first() == Some(escaped) && matches(&value[1..], rest)
+                }
+                [literal, rest @ ..] => {
+                    value.first() == Some(literal) && matches(&value[1..], rest)
+                }
+            }
+        }
+        matches(&value, &pattern)
+    }
+
+    #[test]
+    fn like_wildcards_and_quotes_are_escaped() {
+        let cases = [
+            ("plain/path.rs", "plain/path.rs"),
+            ("src_gen", r"src\_gen"),
+            ("100%", r"100\%"),
+            ("it's", "it''s"),
+            (r"a\b", r"a\\b"),
+        ];
+        for (literal, expected) in cases {
+            assert_eq!(escape_like(literal), expected, "{}", literal);
+        }
+    }
+
+    #[test]
+    fn folder_filter_does_not_match_siblings() {
+        let pattern = like_pattern(&path_prefix_filter(
+            Path::new("/ws/src"),
+            &TableConfig::default(),
+            PathCaseMode::Sensitive,
+        ));
+
+        assert!(is_like("/ws/src/lib.rs", &pattern));
+        assert!(is_like("/ws/src/services/mod.rs", &pattern));
+        assert!(!is_like("/ws/src_gen/lib.rs", &pattern));
+        assert!(!is_like("/ws/src.rs", &pattern));
+    }
+
+    #[test]
+    fn folder_filter_matches_wildcards_literally() {
+        let cases = [
+            ("/ws/src_gen", "/ws/src_gen/a.rs", "/ws/srcxgen/a.rs"),
+            ("/ws/100%", "/ws/100%/a.rs", "/ws/100abc/a.rs"),
+            ("/ws/it's", "/ws/it's/a.rs", "/ws/its/a.rs"),
+        ];
+        for (folder, inside, outside) in cases {
+            let pattern = like_pattern(&path_prefix_filter(
+                Path::new(folder),
+                &TableConfig::default(),
+                PathCaseMode::Sensitive,
+            ));
+
+            assert!(is_like(inside, &pattern), "{} {}", inside, pattern);
+            assert!(!is_like(outside, &pattern), "{} {}", outside, pattern);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_for_path_reads_the_rows_of_one_file() {
+        let dir = temp_dir("chunks-for-path");
+        let client = dir.join("src/client.rs");
+        let quoted = dir.join("src/it's.rs");
+        let table = chunks_table(
+            &dir,
+            &[
+                (&client, 10, 19),
+                (&quoted, 1, 5),
+                (&client, 1, 9),
+                (&dir.join("src/client.rs.bak"), 1, 9),
+            ],
+        )
+        .await;
+        let table_config = TableConfig::default();
+
+        let chunks = chunks_for_path(&table, &client, &table_config, PathCaseMode::Sensitive)
+            .await
+            .unwrap();
+        let lines = chunks
+            .iter()
+            .map(|it| (it.start_line, it.end_line))
+            .collect_vec();
+        assert_eq!(lines, [(1, 9), (10, 19)]);
+        assert!(
+            chunks
+                .iter()
+                .all(|it| it.path == client && !it.id.is_empty())
+        );
+
+        let chunks = chunks_for_path(&table, &quoted, &table_config, PathCaseMode::Sensitive)
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn distinct_paths_lists_every_file_once() {
+        let dir = temp_dir("distinct-paths");
+        let client = dir.join("src/client.rs");
+        let lib = dir.join("src/lib.rs");
+        let table = chunks_table(&dir, &[(&lib, 1, 9), (&client, 1, 9), (&client, 10, 19)]).await;
+
+        let paths = distinct_paths(&table, &TableConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(paths, [client, lib]);
+    }
+
+    #[test]
+    fn paths_filters_match_files_and_folders_apart() {
+        let dir = temp_dir("paths-filters");
+        let folder = dir.join("src");
+        let file = dir.join("lib.rs");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(&file, "").unwrap();
+        let removed = dir.join("removed");
+
+        let filters = paths_filters(
+            &[folder.clone(), file.clone(), removed.clone()],
+            &TableConfig::default(),
+            PathCaseMode::Sensitive,
+        );
+
+        // the removed path may have been either, so it is in both filters
+        assert_eq!(filters.len(), 2, "{:?}", filters);
+        let (files, folders) = (&filters[0], &filters[1]);
+        assert!(files.starts_with("path IN ("), "{}", files);
+        assert!(files.contains(&storage_path(&file)), "{}", files);
+        assert!(files.contains(&storage_path(&removed)), "{}", files);
+        assert!(!files.contains("LIKE"), "{}", files);
+        assert_eq!(folders.matches(" LIKE ").count(), 2, "{}", folders);
+        assert!(folders.contains(" OR "), "{}", folders);
+        assert!(!folders.contains(&storage_path(&file)), "{}", folders);
+    }
+
+    #[test]
+    fn paths_filters_are_split_by_max_paths() {
+        let paths = (0..MAX_PATHS_PER_FILTER * 2 + 1)
+            .map(|it| PathBuf::from(format!("/removed/{}.rs", it)))
+            .collect_vec();
+
+        let filters = paths_filters(&paths, &TableConfig::default(), PathCaseMode::Sensitive);
+
+        let (files, folders): (Vec<_>, Vec<_>) =
+            filters.iter().partition(|it| it.starts_with("path IN ("));
+        let paths_of = |filters: &[&String], separator: &str| {
+            filters
+                .iter()
+                .map(|it| it.matches(separator).count() + 1)
+                .collect_vec()
+        };
+        assert_eq!(
+            paths_of(&files, "', '"),
+            [MAX_PATHS_PER_FILTER, MAX_PATHS_PER_FILTER, 1]
+        );
+        assert_eq!(
+            paths_of(&folders, " OR "),
+            [MAX_PATHS_PER_FILTER, MAX_PATHS_PER_FILTER, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_by_paths_deletes_files_and_folders() {
+        let dir = temp_dir("delete-by-paths");
+        let generated = dir.join("src/gen");
+        std::fs::create_dir_all(&generated).unwrap();
+        let lib = dir.join("src/lib.rs");
+        std::fs::write(&lib, "").unwrap();
+        let client = dir.join("src/client.rs");
+        let sibling = dir.join("src/gen_old/a.rs");
+        let table = chunks_table(
+            &dir,
+            &[
+                (&lib, 1, 9),
+                (&lib, 10, 19),
+                (&generated.join("a.rs"), 1, 9),
+                (&generated.join("b/c.rs"), 1, 9),
+                (&client, 1, 9),
+                (&sibling, 1, 9),
+            ],
+        )
+        .await;
+        let table_config = TableConfig::default();
+
+        let deleted = delete_by_paths(
+            &table,
+            &[lib, generated],
+            &table_config,
+            PathCaseMode::Sensitive,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(deleted, 4);
+        let paths = distinct_paths(&table, &table_config).await.unwrap();
+        assert_eq!(paths, [client, sibling]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn chunks_stored_by_symlink_are_deleted_by_real_path() {
+        let dir = temp_dir("symlinked-path");
+        let real = dir.join("real");
+        std::fs::create_dir_all(&real).unwrap();
+        std::fs::write(real.join("a.rs"), "").unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        // the chunker stores the normalized path of the file it was given
+        let stored = normalize_path(&link.join("src/../a.rs"));
+        assert_eq!(stored, real.join("a.rs"));
+        let table = chunks_table(&dir, &[(&stored, 1, 9)]).await;
+        let table_config = TableConfig::default();
+
+        let chunks = chunks_for_path(
+            &table,
+            &link.join("./a.rs"),
+            &table_config,
+            PathCaseMode::Sensitive,
+        )
+        .await
+        .unwrap();
+        assert_eq!(chunks.len(), 1);
+
+        std::fs::remove_file(real.join("a.rs")).unwrap();
+        let deleted = delete_by_paths(
+            &table,
+            &[real.join("a.rs")],
+            &table_config,
+            PathCaseMode::Sensitive,
+        )
+        .await
+        .unwrap();
+        assert_eq!(deleted, 1);
+        assert_eq!(count_chunks(&table).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn case_insensitive_filters_match_every_casing() {
+        let dir = temp_dir("path-case");
+        let file = dir.join("SRC/Client.RS");
+        let other = dir.join("SRC/other.rs");
+        let table = chunks_table(&dir, &[(&file, 1, 9), (&file, 10, 19), (&other, 1, 9)]).await;
+        let table_config = TableConfig::default();
+        let lower_file = dir.join("src/client.rs");
+        let count = |filter: String| {
+            let table = table.clone();
+            async move { table.count_rows(Some(filter)).await.unwrap() }
+        };
+
+        let insensitive = PathCaseMode::Insensitive;
+        assert_eq!(
+            count(path_equals_filter(&lower_file, &table_config, insensitive)).await,
+            2
+        );
+        assert_eq!(
+            count(path_prefix_filter(
+                &dir.join("src"),
+                &table_config,
+                insensitive
+            ))
+            .await,
+            3
+        );
+        for filter in paths_filters(
+            std::slice::from_ref(&lower_file),
+            &table_config,
+            insensitive,
+        ) {
+            assert!(filter.contains("lower(path)"), "{}", filter);
+        }
+
+        let sensitive = PathCaseMode::Sensitive;
+        assert_eq!(
+            count(path_equals_filter(&lower_file, &table_config, sensitive)).await,
+            0
+        );
+        assert_eq!(
+            count(path_equals_filter(&file, &table_config, sensitive)).await,
+            2
+        );
     }
-    Ok(())
 }
@@ -0,0 +1,117 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::{Map, Value};
+use tracing::info;
+
+/// Target of the audit events, `logging.audit` routes it to its own files
+pub const AUDIT_TARGET: &str = "semantrix::audit";
+
+/// Milliseconds spent in the stages of one tool call, the durations of a stage run several times
+/// or concurrently are summed
+#[derive(Debug, Default)]
+pub struct StageTimings(Mutex<BTreeMap<&'static str, u64>>);
+
+impl StageTimings {
+    fn add(&self, stage: &'static str, elapsed: Duration) {
+        if let Ok(mut stages) = self.0.lock() {
+            *stages.entry(stage).or_default() += elapsed.as_millis() as u64;
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        self.0
+            .lock()
+            .map(|stages| {
+                stages
+                    .iter()
+                    .map(|(stage, ms)| (stage.to_string(), Value::from(*ms)))
+                    .collect::<Map<_, _>>()
+            })
+            .map(Value::Object)
+            .unwrap_or_default()
+    }
+}
+
+tokio::task_local! {
+    /// Timings of the tool call running in the task, not set outside of the tool calls
+    pub static STAGE_TIMINGS: Arc<StageTimings>;
+}
+
+/// Adds the duration of the stage to the timings of the tool call of the task
+pub fn record_stage(stage: &'static str, elapsed: Duration) {
+    let _ = STAGE_TIMINGS.try_with(|timings| timings.add(stage, elapsed));
+}
+
+/// Awaits the stage and adds its duration to the timings of the tool call of the task
+pub async fn timed_stage<F: Future>(stage: &'static str, stage_future: F) -> F::Output {
+    let started = Instant::now();
+    let output = stage_future.await;
+    record_stage(stage, started.elapsed());
+    output
+}
+
+/// Milliseconds of the stages of the tool call of the task so far, the ones the audit record of
+/// the call gets at its end. Empty outside of the tool calls
+pub fn current_stage_timings() -> Value {
+    STAGE_TIMINGS
+        .try_with(|timings| timings.to_value())
+        .unwrap_or_else(|_| Value::Object(Map::new()))
+}
+
+/// Arguments of the tool call without their text unless `include_text` is set, the lists are
+/// replaced by their lengths and the strings by their lengths in chars
+pub fn request_summary(arguments: Option<&Map<String, Value>>, include_text: bool) -> Value {
+    let arguments = arguments.cloned().unwrap_or_default();
+    if include_text {
+        return Value::Object(arguments);
+    }
+    Value::Object(
+        arguments
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::Array(items) => Value::from(items.len()),
+                    Value::String(text) => Value::from(text.chars().count()),
+                    Value::Object(fields) => Value::from(fields.len()),
+                    value => value,
+                };
+                (name, value)
+            })
+            .collect(),
+    )
+}
+
+/// Record of one finished tool call
+#[derive(Debug)]
+pub struct ToolAudit<'a> {
+    pub tool: &'a str,
+    pub request_id: &'a str,
+    pub request: Value,
+    pub duration: Duration,
+    pub stages: Value,
+    pub result_bytes: usize,
+    pub outcome: &'static str,
+    pub cached: bool,
+}
+
+impl ToolAudit<'_> {
+    /// Emits the record on the audit target
+    pub fn emit(&self) {
+        info!(
+            target: AUDIT_TARGET,
+            tool = self.tool,
+            request_id = self.request_id,
+            request = %self.request,
+            duration_ms = self.duration.as_millis() as u64,
+            stages = %self.stages,
+            result_bytes = self.result_bytes,
+            outcome = self.outcome,
+            cached = self.cached,
+            "Tool call finished"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::{ops::Range, path::Path};
+
+use crate::enums::MatchOrigin;
+
+/// Comment markers of the languages of some extensions, the doc markers are checked before the
+/// plain ones, so `///` is not taken for `//`
+struct CommentSyntax {
+    /// Lowercase extensions of the files of the languages
+    extensions: &'static [&'static str],
+    doc_line: &'static [&'static str],
+    line: &'static [&'static str],
+    doc_block: &'static [(&'static str, &'static str)],
+    block: &'static [(&'static str, &'static str)],
+}
+
+static SYNTAXES: [CommentSyntax; 7] = [
+    CommentSyntax {
+        extensions: &[
+            "rs", "c", "h", "cc", "cpp", "cxx", "hpp", "hh", "java", "kt", "kts", "scala", "go",
+            "js", "jsx", "mjs", "cjs", "ts", "tsx", "cs", "swift", "dart", "groovy", "proto",
+        ],
+        doc_line: &["///", "//!"],
+        line: &["//"],
+        doc_block: &[("/**", "*/"), ("/*!", "*/")],
+        block: &[("/*", "*/")],
+    },
+    CommentSyntax {
+        extensions: &["py", "pyi"],
+        doc_line: &[],
+        line: &["#"],
+        // docstrings are the string literals of their own lines
+        doc_block: &[("\"\"\"", "\"\"\""), ("'''", "'''")],
+        block: &[],
+    },
+    CommentSyntax {
+        extensions: &[
+            "sh", "bash", "zsh", "rb", "pl", "pm", "r", "yml", "yaml", "toml", "cmake", "tf", "ex",
+            "exs", "nix", "conf",
+        ],
+        doc_line: &[],
+        line: &["#"],
+        doc_block: &[],
+        block: &[],
+    },
+    CommentSyntax {
+        extensions: &["sql"],
+        doc_line: &[],
+        line: &["--"],
+        doc_block: &[],
+        block: &[("/*", "*/")],
+    },
+    CommentSyntax {
+        extensions: &["lua"],
+        doc_line: &["---"],
+        line: &["--"],
+        doc_block: &[],
+        block: &[("--[[", "]]")],
+    },
+    CommentSyntax {
+        extensions: &["hs"],
+        doc_line: &["-- |", "-- ^"],
+        line: &["--"],
+        doc_block: &[("{-|", "-}")],
+        block: &[("{-", "-}")],
+    },
+    CommentSyntax {
+        extensions: &["html", "htm", "xml", "xhtml", "svg", "vue"],
+        doc_line: &[],
+        line: &[],
+        doc_block: &[],
+        block: &[("<!--", "-->")],
+    },
+];
+
+/// Code and comments found on a line
+#[derive(Debug, Default)]
+struct LineParts {
+    code: bool,
+    comment: bool,
+    doc: bool,
+}
+
+/// Comment marker starting at the start of the text
+struct Marker {
+    open: &'static str,
+    /// End of the block, None for the line comments
+    close: Option<&'static str>,
+    doc: bool,
+}
+
+impl CommentSyntax {
+    fn of(path: &Path) -> Option<&'static CommentSyntax> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        SYNTAXES
+            .iter()
+            .find(|it| it.extensions.contains(&extension.as_str()))
+    }
+
+    fn marker(&self, text: &str) -> Option<Marker> {
+        let lines = self
+            .doc_line
+            .iter()
+            .map(|open| (*open, None, true))
+            .chain(self.line.iter().map(|open| (*open, None, false)));
+        let blocks = self
+            .doc_block
+            .iter()
+            .map(|(open, close)| (*open, Some(*close), true))
+            .chain(
+                self.block
+                    .iter()
+                    .map(|(open, close)| (*open, Some(*close), false)),
+            );
+        // the longest marker wins, so `/**` is a doc block and not a block
+        lines
+            .chain(blocks)
+            .filter(|(open, _, _)| text.starts_with(open))
+            .max_by_key(|(open, _, doc)| (open.len(), *doc))
+            .map(|(open, close, doc)| Marker { open, close, doc })
+    }
+
+    /// Parts of the lines of the range, the lines before the range are read for the blocks
+    /// opened above it
+    fn line_parts(&self, lines: &[String], range: Range<usize>) -> Vec<LineParts> {
+        let mut open_block: Option<(&'static str, bool)> = None;
+        let mut parts = Vec::new();
+        for (index, line) in lines.iter().enumerate().take(range.end) {
+            let mut line_parts = LineParts::default();
+            let mut rest = line.as_str();
+            loop {
+                if let Some((close, doc)) = open_block {
+                    line_parts.comment |= !doc;
+                    line_parts.doc |= doc;
+                    match rest.find(close) {
+                        Some(end) => {
+                            rest = &rest[end + close.len()..];
+                            open_block = None;
+                        }
+                        None => break,
+                    }
+                }
+                rest = rest.trim_start();
+                let Some((start, marker)) = rest
+                    .char_indices()
+                    .find_map(|(start, _)| self.marker(&rest[start..]).map(|it| (start, it)))
+                else {
+                    line_parts.code |= !rest.is_empty();
+                    break;
+                };
+                line_parts.code |= !rest[..start].trim().is_empty();
+                match marker.close {
+                    Some(close) => {
+                        rest = &rest[start + marker.open.len()..];
+                        open_block = Some((close, marker.doc));
+                    }
+                    None => {
+                        line_parts.comment |= !marker.doc;
+                        line_parts.doc |= marker.doc;
+                        break;
+                    }
+                }
+            }
+            if index >= range.start {
+                parts.push(line_parts);
+            }
+        }
+        parts
+    }
+}
+
+/// Whether the lines of the range of the file are code, comments or doc comments, None if the
+/// language of the file is unknown or the lines are blank. The comment markers are found by the
+/// extension without parsing the file, so the markers in the string literals are taken for
+/// comments
+pub fn match_origin(path: &Path, lines: &[String], range: Range<usize>) -> Option<MatchOrigin> {
+    let syntax = CommentSyntax::of(path)?;
+    let parts = syntax.line_parts(lines, range);
+    if parts.iter().any(|it| it.code) {
+        Some(MatchOrigin::Code)
+    } else if parts.iter().any(|it| it.comment) {
+        Some(MatchOrigin::Comment)
+    } else if parts.iter().any(|it| it.doc) {
+        Some(MatchOrigin::DocComment)
+    } else {
+        None
+    }
+}
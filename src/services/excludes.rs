@@ -0,0 +1,267 @@
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use itertools::Itertools;
+use lancedb::Table;
+use miette::Result;
+use serde_json::Value;
+use tracing::{info, warn};
+use url::Url;
+use wax::Glob;
+
+use crate::{
+    Workspace,
+    repositories::{backend::VectorBackend, distinct_paths},
+    services::{is_matched_path, url_to_path, workspace_root},
+};
+
+/// Source of the globs of `search.semantic.exclude_globs`
+const CONFIG_SOURCE: &str = "search.semantic.exclude_globs";
+
+/// Shape of the value of an exclusion key of the server options
+#[derive(Debug, Clone, Copy)]
+enum ExcludeShape {
+    /// Folders relative to the workspace, all their files are excluded
+    Dirs,
+    /// Globs relative to the workspace
+    Globs,
+    /// Globs mapped to true if they are excluded, as `files.exclude` of VS Code
+    GlobMap,
+    /// Directory filters of gopls, the excluded folders are prefixed by `-`
+    DirectoryFilters,
+}
+
+/// Key of the server options holding the exclusions of some LSP servers
+struct ServerExcludes {
+    /// File names of the LSP server commands without their extensions, all servers if empty
+    servers: &'static [&'static str],
+    /// Dotted key, it is looked up as a nested key and as a flat key of the options
+    key: &'static str,
+    shape: ExcludeShape,
+}
+
+/// Keys inherited if `search.semantic.inherit_server_excludes` is set, a new server needs its
+/// entry here only
+static SERVER_EXCLUDES: [ServerExcludes; 4] = [
+    ServerExcludes {
+        servers: &["rust-analyzer"],
+        key: "files.excludeDirs",
+        shape: ExcludeShape::Dirs,
+    },
+    ServerExcludes {
+        servers: &["typescript-language-server", "vtsls"],
+        key: "files.exclude",
+        shape: ExcludeShape::GlobMap,
+    },
+    ServerExcludes {
+        servers: &["gopls"],
+        key: "directoryFilters",
+        shape: ExcludeShape::DirectoryFilters,
+    },
+    ServerExcludes {
+        servers: &[],
+        key: "files.excludePatterns",
+        shape: ExcludeShape::Globs,
+    },
+];
+
+/// Glob of the workspace relative paths left out of the index with its origin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exclusion {
+    pub glob: String,
+    /// `search.semantic.exclude_globs` or the server option the glob is inherited from
+    pub source: String,
+}
+
+impl Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.glob, self.source)
+    }
+}
+
+impl ServerExcludes {
+    fn is_server(&self, lsp_server: &str) -> bool {
+        let name = Path::new(lsp_server)
+            .file_stem()
+            .map(|it| it.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        self.servers.is_empty() || self.servers.contains(&name.as_str())
+    }
+
+    fn value<'a>(&self, options: &'a Value) -> Option<&'a Value> {
+        options
+            .get(self.key)
+            .or_else(|| options.pointer(&format!("/{}", self.key.replace('.', "/"))))
+    }
+
+    /// Globs of the value of the key, the entries of an unexpected shape are skipped
+    fn globs(&self, value: &Value, workspace_path: Option<&Path>) -> Vec<String> {
+        let strings = || {
+            value
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+        };
+        match self.shape {
+            ExcludeShape::Dirs => strings()
+                .filter_map(|dir| dir_glob(dir, workspace_path))
+                .collect(),
+            ExcludeShape::Globs => strings().map(str::to_string).collect(),
+            ExcludeShape::GlobMap => value
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter(|(_, excluded)| excluded.as_bool() == Some(true))
+                .map(|(glob, _)| glob.clone())
+                .collect(),
+            ExcludeShape::DirectoryFilters => strings()
+                .filter_map(|filter| filter.strip_prefix('-'))
+                .filter_map(|dir| dir_glob(dir, workspace_path))
+                .collect(),
+        }
+    }
+}
+
+/// Glob of the files of the folder, the absolute folders outside of the workspace are skipped
+fn dir_glob(dir: &str, workspace_path: Option<&Path>) -> Option<String> {
+    let path = PathBuf::from(dir);
+    let relative = if path.is_absolute() {
+        path.strip_prefix(workspace_path?).ok()?.to_path_buf()
+    } else {
+        path
+    };
+    let dir = relative.to_string_lossy().replace('\\', "/");
+    let dir = dir.trim_start_matches("./").trim_end_matches('/');
+    (!dir.is_empty()).then(|| format!("{}/**", dir))
+}
+
+/// Exclusions of the workspace, the globs of `search.semantic.exclude_globs` and the ones
+/// inherited from the server options of its LSP server if `inherit_server_excludes` is set
+pub fn exclusions(workspace: &Workspace) -> Vec<Exclusion> {
+    let mut exclusions = workspace
+        .semantic
+        .exclude_globs
+        .iter()
+        .map(|glob| Exclusion {
+            glob: glob.clone(),
+            source: CONFIG_SOURCE.to_string(),
+        })
+        .collect_vec();
+    if workspace.semantic.inherit_server_excludes {
+        let workspace_path = Url::parse(&workspace.fuzzy.workspace_uri)
+            .ok()
+            .and_then(|it| url_to_path(&it));
+        let server = Path::new(&workspace.fuzzy.lsp_server)
+            .file_stem()
+            .map(|it| it.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for excludes in SERVER_EXCLUDES
+            .iter()
+            .filter(|it| it.is_server(&workspace.fuzzy.lsp_server))
+        {
+            let Some(value) = excludes.value(&workspace.fuzzy.server_options) else {
+                continue;
+            };
+            exclusions.extend(
+                excludes
+                    .globs(value, workspace_path.as_deref())
+                    .into_iter()
+                    .map(|glob| Exclusion {
+                        glob,
+                        source: format!("{} {}", server, excludes.key),
+                    }),
+            );
+        }
+    }
+    exclusions
+        .into_iter()
+        .unique_by(|it| it.glob.clone())
+        .collect()
+}
+
+/// Logs the exclusions of the workspace, so the inherited ones can be checked
+pub fn log_exclusions(workspace: &Workspace) {
+    let exclusions = exclusions(workspace);
+    if exclusions.is_empty() {
+        info!("No indexing exclusions of workspace {}", workspace.name);
+    } else {
+        info!(
+            "Indexing exclusions of workspace {}: {}",
+            workspace.name,
+            exclusions.iter().join(", ")
+        );
+    }
+}
+
+/// Compiled exclusions of the workspace, a glob excludes the files of the folders it matches
+/// too. They are compiled once, the exclusions are restart only. The invalid inherited globs are
+/// skipped with a warning, the explicit ones are checked by the config validation
+pub fn exclusion_globs(workspace: &Workspace) -> Arc<Vec<Glob<'static>>> {
+    workspace
+        .exclusion_globs
+        .get_or_init(|| {
+            let path_case = workspace.path_case;
+            let globs = exclusions(workspace)
+                .into_iter()
+                .flat_map(|exclusion| {
+                    let folder = (!exclusion.glob.ends_with("**"))
+                        .then(|| format!("{}/**", exclusion.glob.trim_end_matches('/')));
+                    [Some(exclusion.glob.clone()), folder]
+                        .into_iter()
+                        .flatten()
+                        .filter_map(move |glob| {
+                            path_case
+                                .glob(&glob)
+                                .inspect_err(|e| {
+                                    warn!("Skipping exclusion {}: {}", exclusion, e);
+                                })
+                                .ok()
+                        })
+                })
+                .collect();
+            Arc::new(globs)
+        })
+        .clone()
+}
+
+/// Deletes the chunks of the files matched by the exclusions of the workspace, they are left by
+/// the runs before the exclusions are added, as the walks skip the excluded files. Returns the
+/// number of the deleted chunks
+pub async fn delete_excluded_chunks(
+    workspace: &Workspace,
+    table: &Table,
+    vector_store: &dyn VectorBackend,
+) -> Result<usize> {
+    let globs = exclusion_globs(workspace);
+    if globs.is_empty() {
+        return Ok(0);
+    }
+    let workspace_path = workspace_root(workspace)?;
+    let excluded = distinct_paths(table, &workspace.semantic.table)
+        .await?
+        .into_iter()
+        .filter(|path| is_matched_path(path, &workspace_path, &globs))
+        .collect_vec();
+    if excluded.is_empty() {
+        return Ok(0);
+    }
+    info!(
+        "Deleting chunks of {} files matched by the exclusions",
+        excluded.len()
+    );
+    vector_store.delete_by_paths(&excluded).await
+}
+
+/// True if the path relative to the workspace matches an exclusion of the workspace
+pub fn is_excluded(workspace: &Workspace, path: &Path) -> bool {
+    let globs = exclusion_globs(workspace);
+    if globs.is_empty() {
+        return false;
+    }
+    workspace_root(workspace)
+        .is_ok_and(|workspace_path| is_matched_path(path, &workspace_path, &globs))
+}
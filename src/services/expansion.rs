@@ -0,0 +1,120 @@
+use std::{collections::BTreeMap, path::Path};
+
+use itertools::Itertools;
+use miette::Result;
+use regex::{NoExpand, Regex};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{QueryExpansionConfig, errors::SemantrixError};
+
+/// Terms of the query expansion file with their expansions, a term and its expansions form a
+/// group of interchangeable words
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct QueryExpansions(BTreeMap<String, Vec<String>>);
+
+impl QueryExpansions {
+    /// Reads the expansions of the config, the file is read on every search, so its changes are
+    /// applied without restart
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path).map_err(|e| {
+            SemantrixError::io(
+                path,
+                format!(
+                    "Failed to open query expansion file {}: {}",
+                    path.display(),
+                    e
+                ),
+            )
+        })?;
+        serde_yaml::from_reader(file).map_err(|e| {
+            SemantrixError::config(format!(
+                "Failed to parse query expansion file {}: {}",
+                path.display(),
+                e
+            ))
+            .into()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Variants of the query with a whole word of a group replaced by every other word of the
+    /// group, case insensitive. Variants are deduped, bounded by `limit` and never equal to the
+    /// query
+    pub fn variants(&self, query: &str, limit: usize) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|(term, expansions)| {
+                std::iter::once(term)
+                    .chain(expansions)
+                    .filter(|word| !word.trim().is_empty())
+                    .collect_vec()
+            })
+            .flat_map(|group| {
+                group
+                    .iter()
+                    .filter_map(|word| {
+                        Regex::new(&format!(r"(?i)\b{}\b", regex::escape(word.trim())))
+                            .ok()
+                            .filter(|regex| regex.is_match(query))
+                            .map(|regex| (*word, regex))
+                    })
+                    .flat_map(|(word, regex)| {
+                        group
+                            .iter()
+                            .filter(move |other| **other != word)
+                            .map(move |other| {
+                                regex
+                                    .replace_all(query, NoExpand(other.trim()))
+                                    .into_owned()
+                            })
+                    })
+                    .collect_vec()
+            })
+            .filter(|variant| variant != query)
+            .unique()
+            .take(limit)
+            .collect()
+    }
+
+    /// Queries followed by their variants, each query has at most `max_variants` of them and
+    /// the variants found by several queries are kept once
+    pub fn expand(&self, queries: Vec<String>, max_variants: usize) -> Vec<String> {
+        let variants = queries
+            .iter()
+            .flat_map(|query| {
+                let variants = self.variants(query, max_variants);
+                if !variants.is_empty() {
+                    debug!("Expanded query {:?} into {:?}", query, variants);
+                }
+                variants
+            })
+            .collect_vec();
+        queries.into_iter().chain(variants).unique().collect()
+    }
+}
+
+/// Semantic queries and name patterns with their expansions, unchanged without the expansion
+/// config
+pub fn expand_queries(
+    config: Option<&QueryExpansionConfig>,
+    semantic_queries: Vec<String>,
+    name_patterns: Vec<String>,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let Some(config) = config else {
+        return Ok((semantic_queries, name_patterns));
+    };
+    let expansions = QueryExpansions::load(&config.file)?;
+    Ok((
+        expansions.expand(semantic_queries, config.max_variants),
+        expansions.expand(name_patterns, config.max_variants),
+    ))
+}
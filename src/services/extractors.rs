@@ -0,0 +1,309 @@
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::DeflateDecoder;
+use itertools::Itertools;
+use miette::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::trace;
+
+use crate::{FileCacheConfig, errors::SemantrixError, services::file_cache::decode_lines};
+
+/// Separator of the archive path and the entry path in the virtual paths of the archive
+/// entries, as `vendor/bundle.zip!src/lib.rs`
+pub const ARCHIVE_ENTRY_SEPARATOR: char = '!';
+
+/// Extensions of the archives walked for their entries if `file_cache.archives` is set
+const ARCHIVE_EXTENSIONS: [&str; 1] = ["zip"];
+
+/// Bytes of the start of an archive entry checked for the NUL bytes of the binary files
+const BINARY_PROBE_BYTES: usize = 8192;
+
+const ZIP_END_OF_DIRECTORY: u32 = 0x06054b50;
+const ZIP_DIRECTORY_ENTRY: u32 = 0x02014b50;
+const ZIP_LOCAL_ENTRY: u32 = 0x04034b50;
+/// Size of the end of the central directory record without its comment
+const ZIP_END_OF_DIRECTORY_BYTES: usize = 22;
+
+/// Extractors of the files which are not indexed as their plain text, the first one of the
+/// extension of a file is used
+static EXTRACTORS: [&dyn Extractor; 2] = [&NotebookExtractor, &ZipExtractor];
+
+static NOTEBOOK_CELL_HEADER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^# %% \[(\w+)\] cell (\d+)$").expect("Notebook cell header regex is valid")
+});
+
+/// Documents of a file which is not indexed as its plain text, the chunker and the snippets
+/// read the same lines of them through the file cache
+pub trait Extractor: Send + Sync {
+    /// Lowercase extensions of the files of the extractor
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// False if the files are read as their plain text by the config
+    fn enabled(&self, _config: &FileCacheConfig) -> bool {
+        true
+    }
+
+    /// Lines of the documents of the file by their paths, the path of the file for a single
+    /// document or the virtual paths of the entries of an archive
+    fn documents(
+        &self,
+        path: &Path,
+        content: &[u8],
+        config: &FileCacheConfig,
+    ) -> Result<Vec<(PathBuf, Vec<String>)>>;
+}
+
+/// Extractor of the file by its extension, None for the files read as their plain text
+pub fn extractor(path: &Path, config: &FileCacheConfig) -> Option<&'static dyn Extractor> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    EXTRACTORS
+        .iter()
+        .copied()
+        .find(|it| it.extensions().contains(&extension.as_str()) && it.enabled(config))
+}
+
+/// True if the lines of the path are not the lines of a file, the LSP ranges don't match them
+pub fn has_extracted_lines(path: &Path) -> bool {
+    archive_entry(path).is_some()
+        || path
+            .extension()
+            .and_then(|it| it.to_str())
+            .is_some_and(|extension| {
+                NotebookExtractor
+                    .extensions()
+                    .contains(&extension.to_lowercase().as_str())
+            })
+}
+
+/// True if the path is an archive whose entries may be indexed under its virtual paths
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .and_then(|it| it.to_str())
+        .is_some_and(|extension| {
+            ARCHIVE_EXTENSIONS
+                .iter()
+                .any(|it| extension.eq_ignore_ascii_case(it))
+        })
+}
+
+/// Archive and entry of the virtual path of an archive entry, None for the other paths
+pub fn archive_entry(path: &Path) -> Option<(PathBuf, String)> {
+    let path = path.to_str()?;
+    path.match_indices(ARCHIVE_ENTRY_SEPARATOR)
+        .find_map(|(index, _)| {
+            let archive = Path::new(&path[..index]);
+            is_archive(archive).then(|| (archive.to_path_buf(), path[index + 1..].to_string()))
+        })
+}
+
+/// File the lines of the path are read from, the archive of an archive entry
+pub fn source_file(path: &Path) -> PathBuf {
+    archive_entry(path).map_or_else(|| path.to_path_buf(), |(archive, _)| archive)
+}
+
+/// Virtual path of the entry of the archive
+pub fn entry_path(archive: &Path, entry: &str) -> PathBuf {
+    PathBuf::from(format!(
+        "{}{}{}",
+        archive.display(),
+        ARCHIVE_ENTRY_SEPARATOR,
+        entry
+    ))
+}
+
+/// Cell of a line of a notebook, the cell is one based as in the cell headers and the line is
+/// zero based in the source of the cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct NotebookCell {
+    pub cell: usize,
+    pub line: usize,
+}
+
+/// Cell of the line of the notebook lines written by [`NotebookExtractor`], None for the other
+/// files and the lines before the first cell
+pub fn notebook_cell(path: &Path, lines: &[String], line: usize) -> Option<NotebookCell> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    if !NotebookExtractor.extensions().contains(&extension.as_str()) {
+        return None;
+    }
+    lines[..(line + 1).min(lines.len())]
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(index, text)| {
+            let captures = NOTEBOOK_CELL_HEADER.captures(text)?;
+            Some(NotebookCell {
+                cell: captures[2].parse().ok()?,
+                line: line.saturating_sub(index + 1),
+            })
+        })
+}
+
+/// Code and markdown cells of a Jupyter notebook, every cell is a header
+/// `# %% [<cell type>] cell <number>` followed by the lines of its source, the outputs are
+/// skipped
+pub struct NotebookExtractor;
+
+impl Extractor for NotebookExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ipynb"]
+    }
+
+    fn documents(
+        &self,
+        path: &Path,
+        content: &[u8],
+        _config: &FileCacheConfig,
+    ) -> Result<Vec<(PathBuf, Vec<String>)>> {
+        let notebook: Value = serde_json::from_slice(content).map_err(|e| {
+            SemantrixError::io(
+                path,
+                format!("Failed to parse notebook {}: {}", path.display(), e),
+            )
+        })?;
+        let mut lines = Vec::new();
+        let cells = notebook.get("cells").and_then(Value::as_array);
+        for (index, cell) in cells.into_iter().flatten().enumerate() {
+            let cell_type = cell
+                .get("cell_type")
+                .and_then(Value::as_str)
+                .unwrap_or("code");
+            // the source is a list of lines with their line breaks or one string
+            let source = match cell.get("source") {
+                Some(Value::Array(parts)) => parts.iter().filter_map(Value::as_str).join(""),
+                Some(Value::String(source)) => source.clone(),
+                _ => String::new(),
+            };
+            lines.push(format!("# %% [{}] cell {}", cell_type, index + 1));
+            lines.extend(source.lines().map(str::to_string));
+        }
+        Ok(vec![(path.to_path_buf(), lines)])
+    }
+}
+
+/// Entries of a zip archive under their virtual paths, see [`entry_path`]. The stored and the
+/// deflated entries are read, the folders, the encrypted, the zip64 and the binary entries and
+/// the entries larger than `file_cache.max_file_bytes` are skipped
+pub struct ZipExtractor;
+
+impl Extractor for ZipExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &ARCHIVE_EXTENSIONS
+    }
+
+    fn enabled(&self, config: &FileCacheConfig) -> bool {
+        config.archives
+    }
+
+    fn documents(
+        &self,
+        path: &Path,
+        content: &[u8],
+        config: &FileCacheConfig,
+    ) -> Result<Vec<(PathBuf, Vec<String>)>> {
+        let entries = zip_entries(content, config.max_file_bytes).map_err(|e| {
+            SemantrixError::io(
+                path,
+                format!("Failed to read archive {}: {}", path.display(), e),
+            )
+        })?;
+        Ok(entries
+            .into_iter()
+            .filter(|(entry, bytes)| {
+                let binary = bytes[..bytes.len().min(BINARY_PROBE_BYTES)].contains(&0);
+                if binary {
+                    trace!("Skipping binary entry {} of {}", entry, path.display());
+                }
+                !binary
+            })
+            .map(|(entry, bytes)| {
+                (
+                    entry_path(path, &entry),
+                    decode_lines(&bytes, config.lossy_utf8),
+                )
+            })
+            .collect())
+    }
+}
+
+/// Names and contents of the readable entries of the zip archive by its central directory
+fn zip_entries(content: &[u8], max_entry_bytes: u64) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let u16_at = |offset: usize| {
+        content
+            .get(offset..offset + 2)
+            .map(|it| u16::from_le_bytes([it[0], it[1]]) as usize)
+            .ok_or_else(|| format!("truncated at {}", offset))
+    };
+    let u32_at = |offset: usize| {
+        content
+            .get(offset..offset + 4)
+            .map(|it| u32::from_le_bytes([it[0], it[1], it[2], it[3]]))
+            .ok_or_else(|| format!("truncated at {}", offset))
+    };
+    // the record is followed by its comment of at most u16::MAX bytes
+    let last = content
+        .len()
+        .checked_sub(ZIP_END_OF_DIRECTORY_BYTES)
+        .ok_or("no end of central directory")?;
+    let end = (last.saturating_sub(u16::MAX as usize)..=last)
+        .rev()
+        .find(|it| u32_at(*it) == Ok(ZIP_END_OF_DIRECTORY))
+        .ok_or("no end of central directory")?;
+
+    let mut entries = Vec::new();
+    let mut offset = u32_at(end + 16)? as usize;
+    for _ in 0..u16_at(end + 10)? {
+        if u32_at(offset)? != ZIP_DIRECTORY_ENTRY {
+            return Err(format!("no central directory entry at {}", offset));
+        }
+        let flags = u16_at(offset + 8)?;
+        let method = u16_at(offset + 10)?;
+        let compressed = u32_at(offset + 20)?;
+        let size = u32_at(offset + 24)?;
+        let name_len = u16_at(offset + 28)?;
+        let local = u32_at(offset + 42)? as usize;
+        let name = content
+            .get(offset + 46..offset + 46 + name_len)
+            .map(|it| String::from_utf8_lossy(it).into_owned())
+            .ok_or_else(|| format!("truncated name at {}", offset))?;
+        offset += 46 + name_len + u16_at(offset + 30)? + u16_at(offset + 32)?;
+
+        let encrypted = flags & 1 != 0;
+        let zip64 = compressed == u32::MAX || size == u32::MAX;
+        if name.ends_with('/') || encrypted || zip64 || size as u64 > max_entry_bytes {
+            trace!("Skipping archive entry {}", name);
+            continue;
+        }
+        if u32_at(local)? != ZIP_LOCAL_ENTRY {
+            return Err(format!("no local entry of {} at {}", name, local));
+        }
+        let start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+        let data = content
+            .get(start..start + compressed as usize)
+            .ok_or_else(|| format!("truncated entry {}", name))?;
+        let bytes = match method {
+            0 => data.to_vec(),
+            8 => {
+                let mut bytes = Vec::with_capacity(size as usize);
+                DeflateDecoder::new(data)
+                    .take(max_entry_bytes)
+                    .read_to_end(&mut bytes)
+                    .map_err(|e| format!("failed to inflate entry {}: {}", name, e))?;
+                bytes
+            }
+            method => {
+                trace!("Skipping archive entry {} of method {}", name, method);
+                continue;
+            }
+        };
+        entries.push((name, bytes));
+    }
+    Ok(entries)
+}
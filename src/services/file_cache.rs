@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use lru::LruCache;
+use miette::{IntoDiagnostic, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::{debug, trace};
+
+use crate::{
+    FileCacheConfig,
+    errors::SemantrixError,
+    services::{
+        extractors::{extractor, source_file},
+        scratch::{is_scratch_path, scratch_name},
+    },
+};
+
+#[derive(Debug)]
+struct CachedFile {
+    modified: SystemTime,
+    len: u64,
+    /// Size of the lines counted to the budget
+    bytes: usize,
+    lines: Arc<Vec<String>>,
+}
+
+#[derive(Debug)]
+struct CachedFiles {
+    files: LruCache<PathBuf, CachedFile>,
+    bytes: usize,
+}
+
+/// Lines of the recently read files within the byte budget, the least recently read files are
+/// dropped first. The files of the workspaces of one instance share it with its scratch buffers
+#[derive(Debug)]
+pub struct FileCache {
+    config: FileCacheConfig,
+    cached: Mutex<CachedFiles>,
+    /// Lines of the indexed scratch buffers by their names, they are not counted to the budget
+    scratch: Mutex<HashMap<String, Arc<Vec<String>>>>,
+}
+
+impl From<&FileCacheConfig> for FileCache {
+    fn from(config: &FileCacheConfig) -> Self {
+        Self {
+            config: config.clone(),
+            cached: Mutex::new(CachedFiles {
+                files: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            scratch: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl FileCache {
+    /// Lines of the file read with the policies of [`FileCacheConfig`], they are reused until
+    /// the file is modified. The lines of a scratch buffer are taken from the buffer, the lines
+    /// of a notebook or an archive entry are extracted from its file, see [`extractor`]
+    pub async fn read_lines(&self, path: &Path) -> Result<Arc<Vec<String>>> {
+        if is_scratch_path(path) {
+            return self.scratch_lines(path).ok_or_else(|| {
+                SemantrixError::io(
+                    path,
+                    format!("Scratch buffer {} is expired", path.display()),
+                )
+                .into()
+            });
+        }
+        let file = source_file(path);
+        let (modified, len) = self.metadata(&file).await?;
+        if let Some(lines) = self.get(path, modified, len) {
+            trace!("File cache hit for {}", path.display());
+            return Ok(lines);
+        }
+        self.read_file(&file, modified, len)
+            .await?
+            .into_iter()
+            .find(|(document, _)| document == path)
+            .map(|(_, lines)| lines)
+            .ok_or_else(|| {
+                SemantrixError::io(
+                    path,
+                    format!("Entry {} is not found in its archive", path.display()),
+                )
+                .into()
+            })
+    }
+
+    /// Documents of the file with their lines, the file itself or the entries of an archive
+    pub async fn read_documents(&self, path: &Path) -> Result<Vec<(PathBuf, Arc<Vec<String>>)>> {
+        let (modified, len) = self.metadata(path).await?;
+        if let Some(lines) = self.get(path, modified, len) {
+            trace!("File cache hit for {}", path.display());
+            return Ok(vec![(path.to_path_buf(), lines)]);
+        }
+        self.read_file(path, modified, len).await
+    }
+
+    /// Lines of the indexed buffer of the synthetic path, None if the buffer is expired
+    pub fn scratch_lines(&self, path: &Path) -> Option<Arc<Vec<String>>> {
+        let name = scratch_name(path)?;
+        self.scratch.lock().ok()?.get(&name).cloned()
+    }
+
+    /// Keeps the lines of the indexed buffer until it is forgotten
+    pub(crate) fn put_scratch(&self, name: &str, lines: Vec<String>) {
+        if let Ok(mut scratch) = self.scratch.lock() {
+            scratch.insert(name.to_string(), Arc::new(lines));
+        }
+    }
+
+    /// Drops the lines of the expired or purged buffers
+    pub(crate) fn forget_scratch<'a>(&self, names: impl IntoIterator<Item = &'a String>) {
+        if let Ok(mut scratch) = self.scratch.lock() {
+            for name in names {
+                scratch.remove(name);
+            }
+        }
+    }
+
+    /// Modification time and size of the file which is not larger than `max_file_bytes`
+    async fn metadata(&self, path: &Path) -> Result<(SystemTime, u64)> {
+        let metadata = tokio::fs::metadata(path).await.into_diagnostic()?;
+        if metadata.len() > self.config.max_file_bytes {
+            return Err(SemantrixError::io(
+                path,
+                format!(
+                    "File {} of {} bytes is larger than {} bytes",
+                    path.display(),
+                    metadata.len(),
+                    self.config.max_file_bytes
+                ),
+            )
+            .with_help("Increase file_cache.max_file_bytes to read it")
+            .into());
+        }
+        Ok((metadata.modified().into_diagnostic()?, metadata.len()))
+    }
+
+    /// Reads the documents of the file and caches their lines
+    async fn read_file(
+        &self,
+        path: &Path,
+        modified: SystemTime,
+        len: u64,
+    ) -> Result<Vec<(PathBuf, Arc<Vec<String>>)>> {
+        let file = tokio::fs::File::open(path).await.into_diagnostic()?;
+        let content = read_content(path, file, len).await?;
+        let documents = match extractor(path, &self.config) {
+            Some(extractor) => extractor.documents(path, &content, &self.config)?,
+            None => vec![(
+                path.to_path_buf(),
+                decode_lines(&content, self.config.lossy_utf8),
+            )],
+        };
+        let documents = documents
+            .into_iter()
+            .map(|(document, lines)| (document, Arc::new(lines)))
+            .collect::<Vec<_>>();
+        for (document, lines) in documents.iter() {
+            self.put(document, modified, len, lines.clone());
+        }
+        Ok(documents)
+    }
+
+    fn get(&self, path: &Path, modified: SystemTime, len: u64) -> Option<Arc<Vec<String>>> {
+        let mut cached = self.cached.lock().expect("File cache is poisoned");
+        let file = cached.files.get(path)?;
+        if file.modified == modified && file.len == len {
+            return Some(file.lines.clone());
+        }
+        let stale = cached.files.pop(path)?;
+        cached.bytes -= stale.bytes;
+        None
+    }
+
+    fn put(&self, path: &Path, modified: SystemTime, len: u64, lines: Arc<Vec<String>>) {
+        let bytes = lines.iter().map(String::len).sum::<usize>();
+        if bytes > self.config.budget_bytes {
+            debug!(
+                "File {} of {} bytes exceeds the file cache budget",
+                path.display(),
+                bytes
+            );
+            return;
+        }
+        let mut cached = self.cached.lock().expect("File cache is poisoned");
+        let replaced = cached.files.put(
+            path.to_path_buf(),
+            CachedFile {
+                modified,
+                len,
+                bytes,
+                lines,
+            },
+        );
+        cached.bytes += bytes;
+        if let Some(replaced) = replaced {
+            cached.bytes -= replaced.bytes;
+        }
+        while cached.bytes > self.config.budget_bytes {
+            let Some((evicted, file)) = cached.files.pop_lru() else {
+                break;
+            };
+            trace!("File {} is dropped from the file cache", evicted.display());
+            cached.bytes -= file.bytes;
+        }
+    }
+}
+
+/// Content of the file read to its end. A read failing halfway fails the whole file, so its
+/// first lines are never chunked as if they were all of it
+pub(crate) async fn read_content(
+    path: &Path,
+    mut reader: impl AsyncRead + Unpin,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let mut content = Vec::with_capacity(len as usize);
+    if let Err(e) = reader.read_to_end(&mut content).await {
+        return Err(SemantrixError::io(
+            path,
+            format!(
+                "Failed to read file {} after {} bytes of {}: {}",
+                path.display(),
+                content.len(),
+                len,
+                e
+            ),
+        )
+        .into());
+    }
+    // a file truncated or appended while it is read has no consistent lines
+    if content.len() as u64 != len {
+        return Err(SemantrixError::io(
+            path,
+            format!(
+                "File {} changed while it was read: {} bytes read of {}",
+                path.display(),
+                content.len(),
+                len
+            ),
+        )
+        .into());
+    }
+    Ok(content)
+}
+
+/// Lines of the content, either with the not UTF-8 bytes replaced or up to the first not UTF-8
+/// line
+pub(crate) fn decode_lines(content: &[u8], lossy_utf8: bool) -> Vec<String> {
+    if lossy_utf8 {
+        return String::from_utf8_lossy(content)
+            .lines()
+            .map(str::to_string)
+            .collect();
+    }
+    let text = match std::str::from_utf8(content) {
+        Ok(text) => text,
+        Err(e) => {
+            // the line with the first not UTF-8 byte is dropped as a whole
+            let valid = &content[..e.valid_up_to()];
+            let end = valid
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(0, |index| index + 1);
+            std::str::from_utf8(&content[..end]).unwrap_or_default()
+        }
+    };
+    text.lines().map(str::to_string).collect()
+}
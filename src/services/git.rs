@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, stream};
+use itertools::Itertools;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    process::Command,
+    time::{Instant, timeout_at},
+};
+use tracing::{debug, trace};
+
+use crate::{
+    McpConfig, Workspace,
+    services::{storage_path, workspace_root},
+};
+
+/// Max number of the cached lookups of the line ranges
+const GIT_METADATA_CACHE_CAPACITY: usize = 4096;
+/// Max number of the git processes of one search running at the same time
+const GIT_PROCESSES: usize = 4;
+
+/// Last commits of the line ranges by the head commit
+type GitMetadataCache = LruCache<(LineRange, String), Option<GitMetadata>>;
+
+/// Lookups by the line range and the head commit, a range without commits is cached as None,
+/// so an untracked file doesn't spawn git again until the next commit
+static GIT_METADATA: Lazy<Mutex<GitMetadataCache>> = Lazy::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(GIT_METADATA_CACHE_CAPACITY).expect("Capacity is not zero"),
+    ))
+});
+
+/// Last commit which changed the lines of a result
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GitMetadata {
+    pub commit: String,
+    pub author: String,
+    #[schemars(with = "String")]
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Lines of a file, the start line is 0-based and the end line is exclusive
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct LineRange {
+    pub path: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// Last commits of the line ranges of the workspace files, empty if `search.git_metadata` is
+/// off, the workspace is not a git repository or git is not installed. The lookups which are not
+/// done within `search.git_metadata_budget_ms` are left out, they are cached for the next search
+pub async fn git_metadata(
+    config: &McpConfig,
+    workspace: &Workspace,
+    ranges: impl IntoIterator<Item = LineRange>,
+) -> HashMap<LineRange, GitMetadata> {
+    if !config.search.git_metadata {
+        return HashMap::new();
+    }
+    let Ok(workspace_path) = workspace_root(workspace) else {
+        return HashMap::new();
+    };
+    let ranges = ranges
+        .into_iter()
+        .filter(|it| it.path.starts_with(&workspace_path) && it.start_line < it.end_line)
+        .unique()
+        .collect_vec();
+    if ranges.is_empty() {
+        return HashMap::new();
+    }
+    let budget = Duration::from_millis(config.search.git_metadata_budget_ms);
+    let deadline = Instant::now() + budget;
+    let Ok(Some(head)) = timeout_at(deadline, head_commit(&workspace_path)).await else {
+        return HashMap::new();
+    };
+    let lookups = stream::iter(ranges.iter()).for_each_concurrent(GIT_PROCESSES, |range| {
+        let key = (range.clone(), head.clone());
+        let workspace_path = &workspace_path;
+        async move {
+            if cached(&key).is_some() {
+                return;
+            }
+            let metadata = last_commit(workspace_path, &key.0).await;
+            if let Ok(mut cache) = GIT_METADATA.lock() {
+                cache.put(key, metadata);
+            }
+        }
+    });
+    if timeout_at(deadline, lookups).await.is_err() {
+        debug!(
+            "Git metadata of {} ranges exceeded its budget of {} ms",
+            ranges.len(),
+            budget.as_millis()
+        );
+    }
+    ranges
+        .into_iter()
+        .filter_map(|range| {
+            let metadata = cached(&(range.clone(), head.clone()))??;
+            Some((range, metadata))
+        })
+        .collect()
+}
+
+fn cached(key: &(LineRange, String)) -> Option<Option<GitMetadata>> {
+    GIT_METADATA.lock().ok()?.get(key).cloned()
+}
+
+/// Commit of the workspace HEAD, None if the workspace is not a git repository or git is not
+/// installed
+async fn head_commit(workspace_path: &Path) -> Option<String> {
+    git(workspace_path, &["rev-parse", "HEAD"])
+        .await
+        .map(|it| it.trim().to_string())
+}
+
+/// Last commit of the lines followed by `git log -L`, None for the untracked files
+async fn last_commit(workspace_path: &Path, range: &LineRange) -> Option<GitMetadata> {
+    let relative = range.path.strip_prefix(workspace_path).ok()?;
+    let lines = format!(
+        "-L{},{}:{}",
+        range.start_line + 1,
+        range.end_line,
+        storage_path(relative)
+    );
+    let output = git(
+        workspace_path,
+        &[
+            "log",
+            "-1",
+            "--no-patch",
+            "--format=%H%x09%aI%x09%an",
+            &lines,
+        ],
+    )
+    .await?;
+    let (commit, date, author) = output.lines().next()?.splitn(3, '\t').collect_tuple()?;
+    Some(GitMetadata {
+        commit: commit.to_string(),
+        author: author.to_string(),
+        last_modified: DateTime::parse_from_rfc3339(date).ok()?.with_timezone(&Utc),
+    })
+}
+
+/// Stdout of the git command run in the workspace, None if it failed or git is not installed.
+/// The process is killed if the lookup is dropped by the budget
+async fn git(workspace_path: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(workspace_path)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .inspect_err(|e| trace!("Failed to run git: {}", e))
+        .ok()?;
+    if !output.status.success() {
+        trace!(
+            "git {} failed with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
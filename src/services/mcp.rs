@@ -1,42 +1,231 @@
-use futures::StreamExt;
+use chrono::{DateTime, Utc};
+use convert_case::{Case, Casing};
+use itertools::Itertools;
+use metrics::{counter, histogram};
 use miette::Result;
-use rig_fastembed::EmbeddingModel;
-use rig_lancedb::LanceDbVectorIndex;
+use notify_debouncer_full::notify::{
+    EventKind,
+    event::{ModifyKind, RemoveKind},
+};
 use rmcp::{
-    Error, ServerHandler,
+    Error, RoleServer, ServerHandler,
+    handler::server::tool::ToolCallContext,
     model::{
-        CallToolResult, Content, Implementation, ProtocolVersion, ServerCapabilities, ServerInfo,
+        CallToolRequestParam, CallToolResult, Content, GetPromptRequestParam, GetPromptResult,
+        Implementation, JsonObject, ListPromptsResult, ListToolsResult, PaginatedRequestParam,
+        Prompt, PromptArgument, PromptMessage, PromptMessageRole, ProtocolVersion,
+        ServerCapabilities, ServerInfo, Tool,
     },
+    service::RequestContext,
     tool,
 };
 use schemars::{
     JsonSchema, SchemaGenerator,
-    schema::{InstanceType, ObjectValidation, Schema, SchemaObject},
+    schema::{
+        InstanceType, ObjectValidation, RootSchema, Schema, SchemaObject, SubschemaValidation,
+    },
+    schema_for,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{
-    collections::HashSet,
-    path,
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-    },
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::Duration,
 };
-use tokio::sync::watch::{self};
-use tracing::{debug, error, info};
-use url::Url;
+use strum::IntoEnumIterator;
+use tokio::{sync::Semaphore, time::Instant};
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
 use crate::services::{
-    Ruleset, SymbolPlaceTo, find_max_distance_paths, find_min_distance_paths,
-    get_documents_symbols, get_fuzzy_symbols, get_project_files, get_semantic_symbols,
-    get_symbols_references, most_common_parent,
+    ChunkSnippet, ChunksPage, DegradedCounts, DegradedSymbols, FileResults, FusedSymbol,
+    ResolvedSymbol, RuleEntry, Ruleset, SymbolInfo, SymbolKindFilter, SymbolPlaceTo, SymbolsPage,
+    WorkspaceOverview,
+    audit::{
+        STAGE_TIMINGS, StageTimings, ToolAudit, current_stage_timings, record_stage,
+        request_summary, timed_stage,
+    },
+    find_duplicate_chunks, find_similar_code, get_chunks_symbols, get_documents_symbols,
+    get_fuzzy_symbols, get_grouped_references, get_workspace_overview, indexed_child_path,
+    outbound::{ResponseMetas, ToolAnnotations, ToolCallMeta},
+    path_to_url,
+    progress::{PROGRESS, Progress, ProgressTokens},
+    relativize_output_paths, resolve_symbol,
+    scratch::{ScratchIndex, scratch_path},
+    search::{
+        self, CodeReuseSearchResult, EnrichSymbolsResult, SymbolsPlacerResult, empty_index_message,
+        empty_index_warning, pin_store, reindex_stale,
+    },
+    search_chunks, set_hits_git_metadata, set_hits_neighbors,
+    templates::{check_templates, description_context},
+    workspace_child_path, workspace_root,
+};
+use crate::{
+    LiveConfig, McpConfig, ModelSummary, NAME, OutputPaths, PreviousIndex, ResponseType,
+    SearchParamsConfig, VERSION,
+    enums::{McpPromptArgument, McpPromptName, McpToolName, ReadinessStatus, SearchMode},
+    errors::SemantrixError,
+    list_models,
+    repositories::{
+        TableStats, backend::VectorBackend, chunks_for_path, count_chunks,
+        embedding::IndexEmbeddingModel, last_indexed_at, table_stats,
+    },
+    subsystems::{
+        WorkspacePipeline,
+        indexer::IndexError,
+        lsp::GuardedLspServer,
+        metrics::{TOOL_CALL_ERRORS, TOOL_CALL_SECONDS},
+        states::SubsystemStatus,
+        watcher::{PathEvent, WatcherStatus},
+    },
 };
-use crate::{CONFIG, NAME, ResponseType, TERA, VERSION, subsystems::lsp::GuardedLspServer};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CodeReuseSearchRequest {
     pub semantic_queries: Vec<String>,
     pub name_patterns: Vec<String>,
+    #[serde(default)]
+    pub offset: Option<usize>,
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub include_docs: Option<bool>,
+    #[serde(default)]
+    pub include_code: Option<bool>,
+    #[serde(default)]
+    pub max_results_per_file: Option<usize>,
+    #[serde(default)]
+    pub group_by_file: Option<bool>,
+    #[serde(default)]
+    pub exclude_comment_only_matches: Option<bool>,
+}
+
+/// Max number of queries in each list of `code_reuse_search`
+const MAX_SEARCH_QUERIES: usize = 20;
+/// Max number of characters of one query of `code_reuse_search`
+const MAX_QUERY_CHARS: usize = 500;
+/// Number of the most recent index errors reported by `index_status`
+const STATUS_INDEX_ERRORS: usize = 5;
+/// Number of the errors listed by `list_index_errors` without a limit
+const DEFAULT_INDEX_ERRORS_LIMIT: usize = 50;
+/// Argument of every tool naming the workspace the tool runs on
+const WORKSPACE_ARGUMENT: &str = "workspace";
+
+impl CodeReuseSearchRequest {
+    /// Checks the constraints advertised in the schema, the error names the violated one
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, queries) in [
+            ("semantic_queries", &self.semantic_queries),
+            ("name_patterns", &self.name_patterns),
+        ] {
+            if queries.len() > MAX_SEARCH_QUERIES {
+                return Err(format!(
+                    "{} has {} items, at most {} are allowed",
+                    name,
+                    queries.len(),
+                    MAX_SEARCH_QUERIES
+                ));
+            }
+            if let Some(query) = queries
+                .iter()
+                .find(|query| query.chars().count() > MAX_QUERY_CHARS)
+            {
+                return Err(format!(
+                    "{} item `{}...` is longer than {} characters",
+                    name,
+                    query.chars().take(40).collect::<String>(),
+                    MAX_QUERY_CHARS
+                ));
+            }
+        }
+        if self
+            .semantic_queries
+            .iter()
+            .chain(self.name_patterns.iter())
+            .all(|query| query.trim().is_empty())
+        {
+            return Err(
+                "At least one non-empty query is required in semantic_queries or name_patterns"
+                    .to_string(),
+            );
+        }
+        if self.max_results_per_file == Some(0) {
+            return Err("max_results_per_file must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Schema of the list of search queries with the limits checked by `validate`
+fn queries_schema(generator: &mut SchemaGenerator, description: String) -> Schema {
+    let mut item_schema = generator.subschema_for::<String>();
+    if let Schema::Object(ref mut obj) = item_schema {
+        obj.string().max_length = Some(MAX_QUERY_CHARS as u32);
+    }
+    let mut schema = generator.subschema_for::<Vec<String>>();
+    if let Schema::Object(ref mut obj) = schema {
+        obj.metadata().description = Some(description);
+        obj.array().items = Some(item_schema.into());
+        obj.array().max_items = Some(MAX_SEARCH_QUERIES as u32);
+    }
+    schema
+}
+
+/// Schema of the object requiring at least one item in the property
+fn non_empty_property_schema(property: &str) -> Schema {
+    let mut list_schema = SchemaObject::default();
+    list_schema.array().min_items = Some(1);
+    Schema::Object(SchemaObject {
+        object: Some(Box::new(ObjectValidation {
+            properties: [(property.to_string(), Schema::Object(list_schema))]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })
+}
+
+/// Continuation token of `code_reuse_search`, keeps offsets of both result lists as
+/// `<semantic offset>:<fuzzy offset>`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchCursor {
+    pub semantic_offset: usize,
+    pub fuzzy_offset: usize,
+}
+
+impl FromStr for SearchCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (semantic_offset, fuzzy_offset) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid cursor: {}", s))?;
+        Ok(Self {
+            semantic_offset: semantic_offset
+                .parse()
+                .map_err(|e| format!("Invalid cursor: {}: {}", s, e))?,
+            fuzzy_offset: fuzzy_offset
+                .parse()
+                .map_err(|e| format!("Invalid cursor: {}: {}", s, e))?,
+        })
+    }
+}
+
+impl Display for SearchCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.semantic_offset, self.fuzzy_offset)
+    }
+}
+
+/// Pagination state of the search output, the cursor is set if more results are available
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SearchPage {
+    pub next_cursor: Option<String>,
 }
 
 impl JsonSchema for CodeReuseSearchRequest {
@@ -45,28 +234,78 @@ impl JsonSchema for CodeReuseSearchRequest {
     }
 
     fn json_schema(generator: &mut SchemaGenerator) -> Schema {
-        let mut context = tera::Context::new();
-        context.insert("name", &NAME);
-        context.insert("version", &VERSION);
-        let semantic_queries_desc = TERA
-            .render(
-                &CONFIG.templates.description.semantic_query.clone(),
-                &context,
-            )
-            .expect("Failed to render template");
+        // the descriptions of the templates of the instance replace these in the tools list
+        let semantic_queries_schema = queries_schema(
+            generator,
+            "Natural language descriptions of the searched code".to_string(),
+        );
+        let name_patterns_schema = queries_schema(
+            generator,
+            "Possible names of the searched symbols".to_string(),
+        );
+
+        let mut offset_schema = generator.subschema_for::<Option<usize>>();
+        if let Schema::Object(ref mut obj) = offset_schema {
+            obj.metadata().description = Some(
+                "Number of best semantic and fuzzy results to skip, ignored if cursor is set"
+                    .to_string(),
+            );
+        }
 
-        let name_patterns_desc = TERA
-            .render(&CONFIG.templates.description.fuzzy_query.clone(), &context)
-            .expect("Failed to render template");
+        let mut cursor_schema = generator.subschema_for::<Option<String>>();
+        if let Schema::Object(ref mut obj) = cursor_schema {
+            obj.metadata().description = Some(
+                "Continuation token returned by the previous search to get the next results"
+                    .to_string(),
+            );
+        }
 
-        let mut semantic_queries_schema = generator.subschema_for::<Vec<String>>();
-        if let Schema::Object(ref mut obj) = semantic_queries_schema {
-            obj.metadata().description = Some(semantic_queries_desc.to_string());
+        let mut include_docs_schema = generator.subschema_for::<Option<bool>>();
+        if let Schema::Object(ref mut obj) = include_docs_schema {
+            obj.metadata().description = Some(
+                "Request hover documentation of every found symbol, default true. One LSP request \
+                 per symbol makes it the slowest part of the search, set false if names and \
+                 locations are enough, enrich_symbols returns it for the chosen symbols later"
+                    .to_string(),
+            );
         }
 
-        let mut name_patterns_schema = generator.subschema_for::<Vec<String>>();
-        if let Schema::Object(ref mut obj) = name_patterns_schema {
-            obj.metadata().description = Some(name_patterns_desc.to_string());
+        let mut include_code_schema = generator.subschema_for::<Option<bool>>();
+        if let Schema::Object(ref mut obj) = include_code_schema {
+            obj.metadata().description = Some(
+                "Read code of every found symbol from its file, default true. Set false for \
+                 faster and shorter results, rules matching the code are not applied then. \
+                 enrich_symbols returns the code and its rules for the chosen symbols later"
+                    .to_string(),
+            );
+        }
+
+        let mut max_results_per_file_schema = generator.subschema_for::<Option<usize>>();
+        if let Schema::Object(ref mut obj) = max_results_per_file_schema {
+            obj.metadata().description = Some(
+                "Maximum number of semantic, fuzzy and fused results of one file, the next files \
+                 fill the places of the dropped results. `search.max_results_per_file` of the \
+                 config if unset"
+                    .to_string(),
+            );
+        }
+
+        let mut group_by_file_schema = generator.subschema_for::<Option<bool>>();
+        if let Schema::Object(ref mut obj) = group_by_file_schema {
+            obj.metadata().description = Some(
+                "Add the fused symbols and the snippets grouped by file with the best score, the \
+                 number of symbols and the language of every file, default false"
+                    .to_string(),
+            );
+        }
+
+        let mut exclude_comment_only_matches_schema = generator.subschema_for::<Option<bool>>();
+        if let Schema::Object(ref mut obj) = exclude_comment_only_matches_schema {
+            obj.metadata().description = Some(
+                "Drop the semantic snippets whose matched lines are comments or doc comments only, \
+                 the snippets of the files of unknown languages are kept, default false"
+                    .to_string(),
+            );
         }
 
         let schema_obj = SchemaObject {
@@ -75,6 +314,19 @@ impl JsonSchema for CodeReuseSearchRequest {
                 properties: [
                     ("semantic_queries".to_string(), semantic_queries_schema),
                     ("name_patterns".to_string(), name_patterns_schema),
+                    ("offset".to_string(), offset_schema),
+                    ("cursor".to_string(), cursor_schema),
+                    ("include_docs".to_string(), include_docs_schema),
+                    ("include_code".to_string(), include_code_schema),
+                    (
+                        "max_results_per_file".to_string(),
+                        max_results_per_file_schema,
+                    ),
+                    ("group_by_file".to_string(), group_by_file_schema),
+                    (
+                        "exclude_comment_only_matches".to_string(),
+                        exclude_comment_only_matches_schema,
+                    ),
                 ]
                 .iter()
                 .cloned()
@@ -84,6 +336,13 @@ impl JsonSchema for CodeReuseSearchRequest {
                     .collect(),
                 ..Default::default()
             })),
+            subschemas: Some(Box::new(SubschemaValidation {
+                any_of: Some(vec![
+                    non_empty_property_schema("semantic_queries"),
+                    non_empty_property_schema("name_patterns"),
+                ]),
+                ..Default::default()
+            })),
             ..Default::default()
         };
 
@@ -91,319 +350,2703 @@ impl JsonSchema for CodeReuseSearchRequest {
     }
 }
 
-#[derive(Clone)]
-pub struct McpService {
-    pub vector_store: Arc<LanceDbVectorIndex<EmbeddingModel>>,
-    pub lsp_server_rx: watch::Receiver<Option<GuardedLspServer>>,
-    pub first_index_scan: Arc<AtomicBool>,
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FuzzySearchRequest {
+    pub name_patterns: Vec<String>,
+    pub kinds: Option<Vec<String>>,
+    pub need_code: bool,
 }
 
-#[tool(tool_box)]
-impl McpService {
-    #[tool(
-        description = "A tool that scans your project to identify symbols and place them to the best place in the project"
-    )]
-    pub async fn symbols_placer(&self) -> Result<CallToolResult, Error> {
-        let lsp_server = if let Some(lsp_server) = self.lsp_server_rx.borrow().clone() {
-            lsp_server
-        } else {
-            return Ok(CallToolResult::error(vec![Content::text(
-                "Waiting for LSP server to be initialized".to_string(),
-            )]));
+impl JsonSchema for FuzzySearchRequest {
+    fn schema_name() -> String {
+        "FuzzySearchRequest".to_owned()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        // the description of the template of the instance replaces this one in the tools list
+        let mut name_patterns_schema = generator.subschema_for::<Vec<String>>();
+        if let Schema::Object(ref mut obj) = name_patterns_schema {
+            obj.metadata().description = Some("Possible names of the searched symbols".to_string());
+        }
+
+        let mut kinds_schema = generator.subschema_for::<Option<Vec<String>>>();
+        if let Schema::Object(ref mut obj) = kinds_schema {
+            obj.metadata().description = Some(
+                "Exact names or regexes of LSP symbol kinds to keep, for example `Struct|Enum`, all kinds if empty"
+                    .to_string(),
+            );
+        }
+
+        let mut need_code_schema = generator.subschema_for::<bool>();
+        if let Schema::Object(ref mut obj) = need_code_schema {
+            obj.metadata().description =
+                Some("Whether the source code and the hover of found symbols are needed, enrich_symbols returns them for the chosen symbols later".to_string());
+        }
+
+        let schema_obj = SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            object: Some(Box::new(ObjectValidation {
+                properties: [
+                    ("name_patterns".to_string(), name_patterns_schema),
+                    ("kinds".to_string(), kinds_schema),
+                    ("need_code".to_string(), need_code_schema),
+                ]
+                .iter()
+                .cloned()
+                .collect(),
+                required: vec!["name_patterns".to_string(), "need_code".to_string()]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            })),
+            ..Default::default()
         };
 
-        info!("Starting to get symbols");
+        Schema::Object(schema_obj)
+    }
+}
 
-        let files = get_project_files().map_err(|e| {
-            Error::internal_error(format!("Failed to get project files: {}", e), None)
-        })?;
+/// Failure or skip of one search pipeline which doesn't prevent results of the others
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SearchWarning {
+    pub source: String,
+    pub message: String,
+}
 
-        let modules_symbols = files
-            .into_iter()
-            .filter_map(|it| Url::from_file_path(it).ok())
-            .collect::<HashSet<_>>();
+/// Stages of the tool cut off by `tool_timeout_ms`, results of the other stages are returned
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct ToolTimeout {
+    pub timed_out: bool,
+    pub stages: Vec<String>,
+    pub timeout_ms: u64,
+}
 
-        debug!("Found modules symbols: {:?}", modules_symbols);
+impl ToolTimeout {
+    pub(crate) fn cut_off(&mut self, stage: &str, timeout_ms: u64) {
+        warn!("Stage {} is cut off after {} ms", stage, timeout_ms);
+        self.timed_out = true;
+        self.timeout_ms = timeout_ms;
+        self.stages.push(stage.to_string());
+    }
+}
 
-        let symbols = get_documents_symbols(
-            &lsp_server,
-            modules_symbols,
-            CONFIG.placer.final_symbol_kinds.clone(),
-        )
-        .await;
+/// Milliseconds of the stages of the tool call before its response, taken from the timings of
+/// the audit log
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ToolTimings {
+    /// Milliseconds by the stage names
+    pub timings: Value,
+    /// Versions of the tables the semantic search read, see [`pin_store`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_versions: Option<Vec<u64>>,
+}
 
-        debug!("Found symbols: {:?}", symbols);
+/// Version of the JSON results described by [`McpToolName::result_schema`], raised when a field
+/// of them is renamed, removed or changes its meaning
+pub const RESULT_SCHEMA_VERSION: u32 = 1;
 
-        let places: Vec<SymbolPlaceTo> = get_symbols_references(&lsp_server, symbols.clone())
-            .filter_map(|it| async move {
-                if it.references.is_empty() {
-                    debug!("No references found for symbol: {:?}", it.symbol_info);
-                    return None;
-                }
+/// JSON result of `code_reuse_search`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct CodeReuseSearchResponse<'a> {
+    pub schema_version: u32,
+    /// Rules of the semantic symbols
+    pub semantic_rules: &'a [String],
+    /// Rules of the fuzzy symbols
+    pub fuzzy_rules: &'a [String],
+    /// Rules of the semantic symbols with their rulesets and the symbols which matched them
+    pub semantic_rules_detailed: &'a [RuleEntry],
+    /// Rules of the fuzzy symbols with their rulesets and the symbols which matched them
+    pub fuzzy_rules_detailed: &'a [RuleEntry],
+    /// Symbols of the semantic queries, empty if the semantic search failed or only the fused
+    /// symbols are returned
+    pub semantic_symbols: &'a [SymbolInfo],
+    /// Symbols of the name patterns, empty if the fuzzy search failed or only the fused symbols
+    /// are returned
+    pub fuzzy_symbols: &'a [SymbolInfo],
+    /// Chunks of the semantic queries which are not mapped to any symbol
+    pub semantic_snippets: &'a [ChunkSnippet],
+    pub warnings: &'a [SearchWarning],
+    pub page: &'a SearchPage,
+    pub timeout: &'a ToolTimeout,
+    /// Symbols of both searches ranked by the reciprocal rank fusion
+    pub fused_symbols: &'a [FusedSymbol],
+    pub timings: ToolTimings,
+    /// Results grouped by their files, set if `group_by_file` is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<&'a [FileResults]>,
+}
 
-                let candidates = it
-                    .references
-                    .iter()
-                    .filter_map(|it| it.uri.to_file_path().ok())
-                    .map(|it| it.to_path_buf())
-                    .map(|it| path::absolute(it).unwrap())
-                    .collect::<Vec<_>>();
-
-                let place_to = if CONFIG.placer.use_max_distance {
-                    find_max_distance_paths(&candidates, &candidates)
-                } else {
-                    find_min_distance_paths(&candidates, &candidates)
-                };
+/// JSON result of `symbols_placer`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SymbolsPlacerResponse<'a> {
+    pub schema_version: u32,
+    /// Rules of the found symbols
+    pub rules: &'a [String],
+    /// Rules of the found symbols with their rulesets and the symbols which matched them
+    pub rules_detailed: &'a [RuleEntry],
+    /// Symbols of the files in the path globs matching the name patterns
+    pub symbols: &'a [SymbolInfo],
+    /// Suggested folders of the symbols, the most confident first
+    pub places: &'a [SymbolPlaceTo],
+    pub skipped: PlacerSkipped,
+    pub timings: ToolTimings,
+}
 
-                if place_to.is_empty() {
-                    None
-                } else {
-                    //If lot of places to place, we need to find the closest parent includes all places
-                    let absolute_target = if place_to.len() > 1 {
-                        most_common_parent(&place_to).unwrap_or(
-                            place_to
-                                .first()
-                                .and_then(|it| path::absolute(it).ok())
-                                .unwrap()
-                                .parent()
-                                .unwrap()
-                                .to_path_buf(),
-                        )
-                    } else {
-                        place_to
-                            .first()
-                            .and_then(|it| path::absolute(it).ok())
-                            .unwrap()
-                            .parent()
-                            .unwrap()
-                            .to_path_buf()
-                    };
-
-                    debug!(
-                        "For symbol: {:?} absolute target: {}",
-                        it.symbol_info,
-                        absolute_target.display()
-                    );
-
-                    if let Ok(path) = it.symbol_info.location.uri.to_file_path() {
-                        if let Ok(absolute_source) = path::absolute(&path) {
-                            if let Some(source_parent) = absolute_source.parent() {
-                                if source_parent == absolute_target {
-                                    None
-                                } else {
-                                    Some(SymbolPlaceTo {
-                                        symbol_info: it.symbol_info,
-                                        place_to: absolute_target.to_string_lossy().to_string(),
-                                    })
-                                }
-                            } else {
-                                Some(SymbolPlaceTo {
-                                    symbol_info: it.symbol_info,
-                                    place_to: absolute_target.to_string_lossy().to_string(),
-                                })
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                }
-            })
-            .collect::<Vec<_>>()
-            .await;
+/// JSON result of `enrich_symbols`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct EnrichSymbolsResponse<'a> {
+    pub schema_version: u32,
+    /// Rules of the found symbols
+    pub rules: &'a [String],
+    /// Rules of the found symbols with their rulesets and the symbols which matched them
+    pub rules_detailed: &'a [RuleEntry],
+    /// Found symbols with their code, hover and last commit ordered by their locations
+    pub symbols: &'a [SymbolInfo],
+    /// Requested symbols which are not found in their files
+    pub not_found: &'a [UnresolvedSymbol],
+}
 
-        debug!("Places: {:?}", places);
+/// JSON result of `index_status`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct IndexStatusResponse<'a> {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub status: &'a IndexStatus,
+}
 
-        // TODO: for POC loaded every request because user can update rules without restarting the server
-        let rules: Ruleset =
-            serde_yaml::from_reader(std::fs::File::open(&CONFIG.rules).map_err(|e| {
-                Error::internal_error(
-                    format!(
-                        "Failed to open rules file: {} with path: {}",
-                        e,
-                        &CONFIG.rules.to_string_lossy()
-                    ),
-                    None,
-                )
-            })?)
-            .map_err(|e| {
-                Error::internal_error(
-                    format!(
-                        "Failed to parse rules file: {} with path: {}",
-                        e,
-                        &CONFIG.rules.to_string_lossy()
-                    ),
-                    None,
-                )
-            })?;
+/// Internal error of the tool with the category of the failure and whether a retry may succeed
+/// in its data, a failure which is not a [`SemantrixError`] has no data. An invalid request is
+/// reported as invalid params
+fn report_error(context: &str, report: miette::Report) -> Error {
+    if let Some(SemantrixError::InvalidRequest { message, .. }) =
+        report.downcast_ref::<SemantrixError>()
+    {
+        return Error::invalid_params(message.clone(), None);
+    }
+    let data = report.downcast_ref::<SemantrixError>().map(|e| {
+        serde_json::json!({
+            "category": e.category(),
+            "retryable": e.is_retryable(),
+        })
+    });
+    Error::internal_error(format!("{}: {}", context, report), data)
+}
 
-        let rules = rules.get_rules(symbols.clone()).map_err(|e| {
-            Error::internal_error(
-                format!(
-                    "Failed to get fuzzy rules: {} with path: {}",
-                    e,
-                    &CONFIG.rules.to_string_lossy()
-                ),
-                None,
-            )
-        })?;
+impl McpToolName {
+    /// Tools which bound their stages by the deadline themselves to return partial results
+    fn has_stage_deadlines(&self) -> bool {
+        matches!(self, McpToolName::CodeReuseSearch)
+    }
 
-        if CONFIG.response == ResponseType::Json {
-            Ok(CallToolResult::success(vec![
-                Content::json(rules)?,
-                Content::json(symbols)?,
-                Content::json(places)?,
-            ]))
-        } else {
-            let mut context = tera::Context::new();
-            context.insert("fuzzy_rules", &rules);
-            context.insert("fuzzy_symbols", &symbols);
-            context.insert("references", &places);
+    /// Schema of the JSON result of the tool, None for the tools whose result is not described
+    fn result_schema(&self) -> Option<RootSchema> {
+        match self {
+            McpToolName::CodeReuseSearch => Some(schema_for!(CodeReuseSearchResponse<'static>)),
+            McpToolName::SymbolsPlacer => Some(schema_for!(SymbolsPlacerResponse<'static>)),
+            McpToolName::EnrichSymbols => Some(schema_for!(EnrichSymbolsResponse<'static>)),
+            McpToolName::IndexStatus => Some(schema_for!(IndexStatusResponse<'static>)),
+            _ => None,
+        }
+    }
 
-            let content = TERA
-                .render(&CONFIG.templates.prompts.placer, &context)
-                .map_err(|e| {
-                    Error::internal_error(
-                        format!(
-                            "Failed to render template: {} with path: {}",
-                            e, &CONFIG.templates.prompts.placer
-                        ),
-                        None,
-                    )
-                })?;
-            Ok(CallToolResult::success(vec![Content::text(content)]))
+    /// Tools which write the store, they are rejected in the read-only mode
+    fn is_mutating(&self) -> bool {
+        matches!(self, McpToolName::ReindexPath | McpToolName::IndexScratch)
+    }
+
+    /// Tools whose results are reused for identical calls by the query cache
+    fn is_memoized(&self) -> bool {
+        matches!(
+            self,
+            McpToolName::SemanticSearch | McpToolName::CodeReuseSearch
+        )
+    }
+
+    /// Annotations of the tool in the tools list. Only the mutating tools write the store, they
+    /// replace the rows of the path or the buffer, so a repeated call changes nothing, and no
+    /// tool reaches outside of the served workspaces
+    pub(crate) fn annotations(&self) -> ToolAnnotations {
+        ToolAnnotations {
+            read_only_hint: !self.is_mutating(),
+            destructive_hint: false,
+            idempotent_hint: true,
+            open_world_hint: false,
         }
     }
+}
 
-    #[tool(
-        description = "A tool that scans your project to identify code fragments and components that have already been implemented, allowing you to find and reuse existing solutions instead of rewriting them from scratch. This helps reduce duplication, improve development efficiency, and promote best practices in code maintenance and organization"
-    )]
-    pub async fn code_reuse_search(
-        &self,
-        #[tool(aggr)] CodeReuseSearchRequest {
-            semantic_queries,
-            name_patterns,
-        }: CodeReuseSearchRequest,
-    ) -> Result<CallToolResult, Error> {
-        let lsp_server = if let Some(lsp_server) = self.lsp_server_rx.borrow().clone() {
-            lsp_server
-        } else {
-            return Ok(CallToolResult::error(vec![Content::text(
-                "Waiting for LSP server to be initialized".to_string(),
-            )]));
-        };
+impl McpPromptName {
+    /// Name of the prompt in the MCP protocol
+    fn id(&self) -> String {
+        format!("{:?}", self).to_case(Case::Snake)
+    }
 
-        if !self.first_index_scan.load(Ordering::Relaxed) {
-            return Ok(CallToolResult::error(vec![Content::text(
-                "Waiting for index to be initialized".to_string(),
-            )]));
+    fn arguments(&self) -> Vec<McpPromptArgument> {
+        match self {
+            McpPromptName::FuzzySearchImplementedStuff => McpPromptArgument::iter().collect(),
         }
+    }
 
-        info!("Starting to get symbols");
+    /// Template of the prompt in the config
+    fn template<'a>(&self, config: &'a McpConfig) -> &'a str {
+        match self {
+            McpPromptName::FuzzySearchImplementedStuff => {
+                &config.templates.prompts.fuzzy_search_implemented_stuff
+            }
+        }
+    }
+}
 
-        let (fuzzy_symbols, semantic_symbols) = tokio::try_join!(
-            get_fuzzy_symbols(&lsp_server, name_patterns, vec![], true),
-            get_semantic_symbols(&lsp_server, semantic_queries, self.vector_store.clone(),),
-        )
-        .inspect_err(|e| {
-            error!("Error getting symbols: {}", e);
-        })
-        .map_err(|e| Error::internal_error(format!("Failed to get symbols: {}", e), None))?;
+impl McpPromptArgument {
+    /// Template of the argument description in the config
+    fn description<'a>(&self, config: &'a McpConfig) -> &'a str {
+        match self {
+            McpPromptArgument::ShortDescription => &config.templates.description.semantic_query,
+            McpPromptArgument::PossibleNames => &config.templates.description.fuzzy_query,
+        }
+    }
+}
 
-        debug!(
-            "Fuzzy symbols: {:?}, semantic symbols: {:?}",
-            fuzzy_symbols, semantic_symbols
-        );
+fn timed_render<T>(render: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let started = Instant::now();
+    let output = render();
+    record_stage("render", started.elapsed());
+    output
+}
 
-        // TODO: for POC loaded every request because user can update rules without restarting the server
-        let rules: Ruleset =
-            serde_yaml::from_reader(std::fs::File::open(&CONFIG.rules).map_err(|e| {
-                Error::internal_error(
-                    format!(
-                        "Failed to open rules file: {} with path: {}",
-                        e,
-                        &CONFIG.rules.to_string_lossy()
-                    ),
-                    None,
-                )
-            })?)
-            .map_err(|e| {
-                Error::internal_error(
-                    format!(
-                        "Failed to parse rules file: {} with path: {}",
-                        e,
-                        &CONFIG.rules.to_string_lossy()
-                    ),
-                    None,
-                )
-            })?;
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SemanticSearchRequest {
+    /// Natural language descriptions of the searched code or text
+    pub queries: Vec<String>,
+    /// Maximum number of chunks returned for every query
+    pub limit: Option<usize>,
+    /// Glob of workspace relative paths to keep in the results, for example `docs/**/*.md`
+    pub path_glob: Option<String>,
+    /// `vector` for the meaning, `fts` for the exact identifiers and rare words, `hybrid` for
+    /// both, the configured default if unset
+    pub mode: Option<SearchMode>,
+    /// Embedding models of `search.semantic.model_routes` whose chunks are searched, all of them
+    /// if unset
+    pub models: Option<Vec<String>>,
+    /// Maximum number of chunks of one file for every query, `search.max_results_per_file` if
+    /// unset
+    pub max_results_per_file: Option<usize>,
+    /// Whether the buffers of index_scratch are searched too, their hits follow the hits of the
+    /// workspace. False if unset
+    pub include_scratch: Option<bool>,
+    /// Number of the chunks before and after every hit returned as its neighbors, so the hit is
+    /// read with the surrounding text. No neighbors if unset or 0, they are not returned while
+    /// the index is rebuilt for another model
+    pub neighbors: Option<usize>,
+    /// Whether the hits whose matched lines are comments or doc comments only are dropped, the
+    /// hits of the files of unknown languages are kept. False if unset
+    pub exclude_comment_only_matches: Option<bool>,
+}
 
-        let semantic_rules = rules.get_rules(semantic_symbols.clone()).map_err(|e| {
-            Error::internal_error(
-                format!(
-                    "Failed to get semantic rules: {} with path: {}",
-                    e,
-                    &CONFIG.rules.to_string_lossy()
-                ),
-                None,
-            )
-        })?;
-        let fuzzy_rules = rules.get_rules(fuzzy_symbols.clone()).map_err(|e| {
-            Error::internal_error(
-                format!(
-                    "Failed to get fuzzy rules: {} with path: {}",
-                    e,
-                    &CONFIG.rules.to_string_lossy()
-                ),
-                None,
-            )
-        })?;
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GetSymbolCodeRequest {
+    /// Workspace relative path of the file declaring the symbol
+    pub path: String,
+    /// Exact name of the symbol
+    pub name: String,
+    /// Exact name or regex of the LSP symbol kind, for example `Function|Method`
+    pub kind: Option<String>,
+}
 
-        if CONFIG.response == ResponseType::Json {
-            Ok(CallToolResult::success(vec![
-                Content::json(semantic_rules)?,
-                Content::json(fuzzy_rules)?,
-                Content::json(semantic_symbols)?,
-                Content::json(fuzzy_symbols)?,
-            ]))
-        } else {
-            let mut context = tera::Context::new();
-            context.insert("semantic_rules", &semantic_rules);
-            context.insert("fuzzy_rules", &fuzzy_rules);
-            context.insert("semantic_symbols", &semantic_symbols);
-            context.insert("fuzzy_symbols", &fuzzy_symbols);
-
-            let content = TERA
-                .render(&CONFIG.templates.prompts.searcher, &context)
-                .map_err(|e| {
-                    Error::internal_error(
-                        format!(
-                            "Failed to render template: {} with path: {}",
-                            e, &CONFIG.templates.prompts.searcher
-                        ),
-                        None,
-                    )
-                })?;
-            Ok(CallToolResult::success(vec![Content::text(content)]))
+/// Symbol returned by a search tool, found again in the document symbols of its file by the id
+/// or by the lines
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct SymbolReference {
+    /// Path of the file of the symbol as in the results, workspace relative or absolute
+    pub path: String,
+    /// Stable id of the symbol returned by the search tool
+    pub id: Option<String>,
+    /// Zero based first line of the symbol range as in `location.range.start.line`, the lines
+    /// are used if the id is unset or not found in the file
+    pub start_line: Option<u32>,
+    /// Zero based last line of the symbol range as in `location.range.end.line`, the start line
+    /// if unset
+    pub end_line: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct EnrichSymbolsRequest {
+    /// Symbols returned by code_reuse_search or fuzzy_search without their code or hover
+    pub symbols: Vec<SymbolReference>,
+}
+
+/// Symbol of `enrich_symbols` which is not found in its file
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct UnresolvedSymbol {
+    #[serde(flatten)]
+    pub reference: SymbolReference,
+    pub reason: String,
+}
+
+/// Max number of the symbols of one `enrich_symbols` call
+const MAX_ENRICHED_SYMBOLS: usize = 50;
+
+impl EnrichSymbolsRequest {
+    /// Checks the constraints advertised in the schema, the error names the violated one
+    pub fn validate(&self) -> Result<(), String> {
+        if self.symbols.is_empty() {
+            return Err("symbols must not be empty".to_string());
+        }
+        if self.symbols.len() > MAX_ENRICHED_SYMBOLS {
+            return Err(format!(
+                "symbols has {} items, at most {} are allowed",
+                self.symbols.len(),
+                MAX_ENRICHED_SYMBOLS
+            ));
         }
+        for symbol in self.symbols.iter() {
+            if symbol.id.is_none() && symbol.start_line.is_none() {
+                return Err(format!(
+                    "Symbol of {} needs an id or a start_line",
+                    symbol.path
+                ));
+            }
+            if let Some((start_line, end_line)) = symbol
+                .start_line
+                .zip(symbol.end_line)
+                .filter(|(start_line, end_line)| end_line < start_line)
+            {
+                return Err(format!(
+                    "Symbol of {} has end_line {} before start_line {}",
+                    symbol.path, end_line, start_line
+                ));
+            }
+        }
+        Ok(())
     }
 }
 
-#[tool(tool_box)]
-impl ServerHandler for McpService {
-    fn get_info(&self) -> ServerInfo {
-        let mut context = tera::Context::new();
-        context.insert("name", &NAME);
-        context.insert("version", &VERSION);
-        ServerInfo {
-            protocol_version: ProtocolVersion::V_2024_11_05,
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
-            server_info: Implementation::from_build_env(),
-            instructions: Some(
-                TERA.render(&CONFIG.templates.description.server.clone(), &context)
-                    .expect("Failed to render template"),
-            ),
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SymbolCode {
+    Found {
+        symbol: Box<SymbolInfo>,
+    },
+    Ambiguous {
+        candidates: Vec<SymbolInfo>,
+    },
+    NotFound {
+        path: String,
+        name: String,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct FindReferencesRequest {
+    /// Workspace relative path of the file declaring the symbol
+    pub path: String,
+    /// Exact name of the symbol
+    pub name: String,
+    /// Whether the declaration of the symbol is returned as a reference too
+    pub include_declaration: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct FindDuplicatesRequest {
+    /// Glob of workspace relative paths to search duplicates in, for example `src/**/*.rs`
+    pub path_glob: String,
+    /// Min cosine similarity of duplicate chunks from 0 to 1, 0.9 if not set
+    pub threshold: Option<f32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SimilarCodeRequest {
+    /// Code snippet to find similar indexed code for, long snippets are split to windows
+    pub code: String,
+    /// Min cosine similarity of returned chunks from 0 to 1, 0.5 if not set
+    pub min_score: Option<f32>,
+    /// Whether LSP symbols following the similar chunks are returned, true if not set
+    pub include_symbols: Option<bool>,
+    /// Whether the buffers of index_scratch are compared too, false if not set
+    pub include_scratch: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct IndexScratchRequest {
+    /// Name of the buffer, indexing the same name again replaces the buffer
+    pub name: String,
+    /// Unsaved text of the buffer
+    pub content: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexScratchResult {
+    /// Synthetic path of the buffer in the search results
+    pub path: String,
+    pub chunks: usize,
+    /// Seconds the buffer is searched for
+    pub ttl_sec: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct SymbolsPlacerRequest {
+    /// Globs of workspace relative paths of files whose symbols are analyzed, all files if empty
+    #[serde(default)]
+    pub path_globs: Vec<String>,
+    /// Regexes of names of analyzed symbols, all symbols if empty
+    #[serde(default)]
+    pub symbol_name_patterns: Vec<String>,
+    /// Whether suggestions below the configured confidence floor are returned too
+    #[serde(default)]
+    pub include_low_confidence: bool,
+    /// Regexes of names of symbols analyzed before the others when the run is capped
+    #[serde(default)]
+    pub priority_patterns: Vec<String>,
+    /// Max number of analyzed symbols, overrides `placer.max_symbols`
+    pub max_symbols: Option<usize>,
+    /// Time the references are requested for in milliseconds, overrides `placer.budget_ms`
+    pub budget_ms: Option<u64>,
+}
+
+impl SymbolsPlacerRequest {
+    /// Checks the limits of the run, the error names the violated one
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_symbols == Some(0) || self.budget_ms == Some(0) {
+            return Err("max_symbols and budget_ms must be greater than 0".to_string());
         }
+        Ok(())
+    }
+}
+
+/// Symbols of a `symbols_placer` run whose references are not requested
+#[derive(Debug, Default, Clone, Copy, Serialize, JsonSchema)]
+pub struct PlacerSkipped {
+    /// Symbols cut off by `max_symbols`
+    pub by_limit: usize,
+    /// Symbols without references when `budget_ms` was spent
+    pub by_budget: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct RulesPreviewRequest {
+    /// Workspace relative path of the file with checked symbols
+    pub path: String,
+    /// Exact name of the checked symbol, all symbols of the file if not set
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct IndexStatusRequest {
+    /// Workspace relative path of the file to report the chunks and the last write of, the
+    /// files of `search.semantic.extra_roots` are given by their absolute paths
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PathIndexStatus {
+    pub path: String,
+    pub chunks: usize,
+    /// Last write of the chunks of the file, None if it has no chunks or they were written
+    /// before the write times were stored
+    #[schemars(with = "Option<String>")]
+    pub indexed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ReindexPathRequest {
+    /// Workspace relative path of the file or folder to reindex, the files of
+    /// `search.semantic.extra_roots` are given by their absolute paths
+    pub path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReindexPathResult {
+    pub path: String,
+    pub deleted_chunks: usize,
+    pub queued: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ListIndexErrorsRequest {
+    /// Workspace relative path of the file or folder to list the errors of, the whole workspace
+    /// if not set
+    pub path: Option<String>,
+    /// Max number of the listed errors, the most recent first
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListIndexErrorsResult {
+    /// Number of the files which failed to be indexed in the whole workspace
+    pub total: usize,
+    pub errors: Vec<IndexError>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GetResultSchemaRequest {
+    /// Tool to get the schema of the JSON result of, every described tool if not set
+    pub tool: Option<McpToolName>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResultSchemas {
+    pub schema_version: u32,
+    /// JSON schemas of the results by the tool names
+    pub schemas: BTreeMap<String, RootSchema>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListModelsResult {
+    /// Name of the configured model or the path of the custom model file
+    pub model: String,
+    /// Dimensions of the embeddings of the configured model
+    pub ndims: usize,
+    pub models: Vec<ModelSummary>,
+}
+
+/// Result of a tool called before the server is ready to serve it
+#[derive(Debug, Serialize)]
+pub struct BusyStatus {
+    pub status: ReadinessStatus,
+    pub message: String,
+    pub retry_after_ms: Option<u64>,
+    pub first_path_scan: bool,
+    pub first_chunks_scan: bool,
+    pub first_index_scan: bool,
+    pub chunks_count: Option<usize>,
+}
+
+/// Variables inserted into the prompt context before the results of the tool, the templates
+/// can mention the workspace, the index and the request they answer
+#[derive(Debug, Serialize)]
+pub struct PromptContext {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub workspace_root: String,
+    pub model: String,
+    pub ndims: usize,
+    /// Queries of the request by argument name
+    pub queries: BTreeMap<&'static str, Vec<String>>,
+    /// Number of returned items by context variable
+    pub counts: BTreeMap<&'static str, usize>,
+    /// True for the context variables with more items than returned
+    pub truncated: BTreeMap<&'static str, bool>,
+    /// Number of the symbols cut down by the response budget
+    pub degraded: DegradedCounts,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct IndexStatus {
+    pub workspace: String,
+    /// True if the index is served without the watcher, the chunker and the indexer, the scans
+    /// are done once the table written by another process has chunks
+    pub read_only: bool,
+    pub status: ReadinessStatus,
+    pub first_path_scan: bool,
+    pub first_chunks_scan: bool,
+    pub first_index_scan: bool,
+    pub chunks_count: usize,
+    pub files_count: usize,
+    pub model: String,
+    pub ndims: usize,
+    pub embeddings_index: bool,
+    pub lsp_server_ready: bool,
+    #[schemars(with = "Option<String>")]
+    pub last_write: Option<DateTime<Utc>>,
+    #[schemars(with = "Option<String>")]
+    pub last_optimize: Option<DateTime<Utc>>,
+    #[schemars(with = "Option<String>")]
+    pub last_vacuum: Option<DateTime<Utc>>,
+    /// Bytes removed by the vacuums since the start
+    pub reclaimed_bytes: u64,
+    /// Parameters of the vector search the results depend on
+    pub search_params: SearchParamsConfig,
+    pub table_stats: TableStats,
+    /// Why the index may be empty, None if it has chunks
+    pub empty_index: Option<String>,
+    /// Number of the files which failed to be indexed
+    pub index_errors: usize,
+    pub recent_index_errors: Vec<IndexError>,
+    /// Chunks of the requested file, None if no path is requested
+    pub path_status: Option<PathIndexStatus>,
+    /// Rebuild for another model while the previous one serves the semantic search, None if
+    /// the table is not rebuilt or the previous model is not served
+    pub rebuild: Option<RebuildStatus>,
+    /// Folders of `search.semantic.extra_roots` indexed with the workspace, their files are
+    /// counted in the files and the chunks of the workspace
+    pub extra_roots: Vec<PathBuf>,
+    /// Flushes of the file watcher, a missing flush after a change means a stalled watcher
+    pub watcher: WatcherStatus,
+    /// States of the started subsystems, a long transient state points at a wedged subsystem
+    pub subsystems: Vec<SubsystemStatus>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RebuildStatus {
+    pub previous_model: String,
+    pub previous_files: usize,
+    /// Files indexed by the current model against the files of the previous table, at most 100
+    pub progress_percent: f64,
+}
+
+#[derive(Clone)]
+pub struct McpService {
+    pub config: Arc<McpConfig>,
+    /// Model of the index, used to embed code snippets the same way as the indexed chunks
+    pub embedding_model: IndexEmbeddingModel,
+    pub ndims: usize,
+    /// Buffers of the index_scratch tool shared by the workspaces
+    pub scratch: Arc<ScratchIndex>,
+    /// Pipeline of the workspace the tool call runs on
+    pub pipeline: WorkspacePipeline,
+    /// Config reloaded from the changed config file with its templates
+    pub live_config: LiveConfig,
+    pub overview_cache: Arc<Mutex<Option<CachedOverview>>>,
+    /// Results of the memoized tools by the keys of their calls
+    pub query_cache: Arc<Mutex<HashMap<u64, CachedQuery>>>,
+    /// Workspaces in the order of the config, a tool call selects one by its `workspace`
+    /// argument
+    pub workspaces: Arc<Vec<McpWorkspace>>,
+    /// Permits of concurrent calls of every tool
+    pub tool_permits: Arc<HashMap<McpToolName, Semaphore>>,
+    /// Progress tokens of the calls of the session, see [`ProgressTokens`]
+    pub progress_tokens: ProgressTokens,
+    /// Metas of the results of the calls of the session, see [`ResponseMetas`]
+    pub response_metas: ResponseMetas,
+}
+
+/// Pipeline of the workspace with the caches of its tools
+#[derive(Clone)]
+pub struct McpWorkspace {
+    pub pipeline: WorkspacePipeline,
+    pub overview_cache: Arc<Mutex<Option<CachedOverview>>>,
+    pub query_cache: Arc<Mutex<HashMap<u64, CachedQuery>>>,
+}
+
+impl From<WorkspacePipeline> for McpWorkspace {
+    fn from(pipeline: WorkspacePipeline) -> Self {
+        Self {
+            pipeline,
+            overview_cache: Arc::default(),
+            query_cache: Arc::default(),
+        }
+    }
+}
+
+/// Built workspace overview with the moment and the number of changed paths it was built at
+pub struct CachedOverview {
+    pub overview: WorkspaceOverview,
+    pub built_at: Instant,
+    pub changed_paths: usize,
+}
+
+impl CachedOverview {
+    /// The overview is rebuilt after the TTL, after the churn of paths or if it was built
+    /// without symbols and the LSP server is ready now
+    fn is_fresh(&self, config: &McpConfig, changed_paths: usize, lsp_server_ready: bool) -> bool {
+        self.built_at.elapsed() < Duration::from_secs(config.overview.ttl_sec)
+            && changed_paths.saturating_sub(self.changed_paths) < config.overview.churn_paths
+            && (self.overview.symbol_kinds.is_some() || !lsp_server_ready)
+    }
+}
+
+/// Result of a tool call with the moment and the state of the index it was returned at
+pub struct CachedQuery {
+    pub result: CallToolResult,
+    pub cached_at: Instant,
+    pub last_write: Option<DateTime<Utc>>,
+    pub changed_paths: usize,
+}
+
+impl CachedQuery {
+    /// The result is dropped after the TTL, after a write of the index or a change of a path
+    fn is_fresh(
+        &self,
+        config: &McpConfig,
+        last_write: Option<DateTime<Utc>>,
+        changed_paths: usize,
+    ) -> bool {
+        self.cached_at.elapsed() < Duration::from_secs(config.query_cache.ttl_sec)
+            && self.last_write == last_write
+            && self.changed_paths == changed_paths
+    }
+}
+
+/// Records the latency of the tool call and counts it if it failed or returned an error
+fn record_tool_call(
+    tool: McpToolName,
+    started: Instant,
+    result: &Result<(CallToolResult, bool), Error>,
+) {
+    let tool = tool.to_string();
+    histogram!(TOOL_CALL_SECONDS, "tool" => tool.clone()).record(started.elapsed().as_secs_f64());
+    let failed = match result {
+        Ok((result, _)) => result.is_error == Some(true),
+        Err(_) => true,
+    };
+    if failed {
+        counter!(TOOL_CALL_ERRORS, "tool" => tool).increment(1);
+    }
+}
+
+/// Emits the audit record of the finished tool call
+fn audit_tool_call(
+    tool: &str,
+    request_id: &str,
+    request: Value,
+    started: Instant,
+    stage_timings: &StageTimings,
+    result: &Result<(CallToolResult, bool), Error>,
+) {
+    let (outcome, result_bytes, cached) = match result {
+        Ok((result, cached)) => (
+            if result.is_error == Some(true) {
+                "error"
+            } else {
+                "success"
+            },
+            serde_json::to_string(&result.content).map_or(0, |it| it.len()),
+            *cached,
+        ),
+        Err(_) => ("failed", 0, false),
+    };
+    ToolAudit {
+        tool,
+        request_id,
+        request,
+        duration: started.elapsed(),
+        stages: stage_timings.to_value(),
+        result_bytes,
+        outcome,
+        cached,
+    }
+    .emit();
+}
+
+/// True if the call searches the scratch buffers, they change without a write of the index
+fn includes_scratch(arguments: Option<&Map<String, Value>>) -> bool {
+    arguments
+        .and_then(|it| it.get("include_scratch"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// Key of the tool call, the arguments are normalized so identical calls get the same key
+fn query_key(config: &McpConfig, tool: McpToolName, arguments: Option<&Map<String, Value>>) -> u64 {
+    let arguments = Value::Object(arguments.cloned().unwrap_or_default());
+    let mut hasher = DefaultHasher::new();
+    tool.hash(&mut hasher);
+    config.response_for(tool).hash(&mut hasher);
+    normalized_value(&arguments).to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Value with the sorted fields, without the null fields and with the trimmed strings
+fn normalized_value(value: &Value) -> Value {
+    match value {
+        Value::String(text) => Value::String(text.trim().to_string()),
+        Value::Array(items) => Value::Array(items.iter().map(normalized_value).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .filter(|(_, value)| !value.is_null())
+                .sorted_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(name, value)| (name.clone(), normalized_value(value)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+const LSP_RETRY_AFTER_MS: u64 = 5000;
+const INDEX_RETRY_AFTER_MS: u64 = 10000;
+
+impl McpService {
+    fn readiness(&self) -> ReadinessStatus {
+        ReadinessStatus::of(
+            self.pipeline.lsp_server_rx.borrow().is_some(),
+            *self.pipeline.first_index_scan.borrow(),
+        )
+    }
+
+    /// Chunks of the workspace relative path or the absolute path of an extra root with their
+    /// last write
+    async fn path_index_status(&self, path: String) -> Result<PathIndexStatus, Error> {
+        let Some(absolute_path) = indexed_child_path(&self.pipeline.workspace, &path) else {
+            return Err(Error::invalid_params(
+                format!(
+                    "Path {} is outside of the workspace and its extra roots",
+                    path
+                ),
+                None,
+            ));
+        };
+        let chunks = chunks_for_path(
+            &self.pipeline.table,
+            &absolute_path,
+            &self.pipeline.workspace.semantic.table,
+            self.pipeline.workspace.path_case,
+        )
+        .await
+        .map_err(|e| report_error("Failed to read chunks of path", e))?;
+        Ok(PathIndexStatus {
+            path,
+            chunks: chunks.len(),
+            indexed_at: last_indexed_at(&chunks),
+        })
+    }
+
+    /// Context of the tool prompt with the common variables, the tool inserts its results
+    fn prompt_context(
+        &self,
+        queries: BTreeMap<&'static str, Vec<String>>,
+        counts: BTreeMap<&'static str, usize>,
+        truncated: BTreeMap<&'static str, bool>,
+        degraded: DegradedCounts,
+    ) -> Result<tera::Context, Error> {
+        let workspace_root = workspace_root(&self.pipeline.workspace)
+            .map_err(|e| report_error("Failed to get workspace path", e))?;
+        tera::Context::from_serialize(PromptContext {
+            name: NAME,
+            version: VERSION,
+            workspace_root: workspace_root.display().to_string(),
+            model: self.config.search.semantic.model.clone(),
+            ndims: self.ndims,
+            queries,
+            counts,
+            truncated,
+            degraded,
+        })
+        .map_err(|e| Error::internal_error(format!("Failed to build prompt context: {}", e), None))
+    }
+
+    /// Renders the descriptions and the prompts with the sample contexts, so a template printing
+    /// an unknown variable fails the startup, see [`check_templates`]
+    pub fn check_prompt_templates(&self) -> Result<()> {
+        let config = self.live_config.get();
+        check_templates(
+            &self.live_config.tera(),
+            &config.templates,
+            &config.search.semantic.model,
+        )
+    }
+
+    fn load_rules(&self) -> Result<Ruleset, Error> {
+        let rules = self.live_config.get().rules.clone();
+        let file = std::fs::File::open(&rules).map_err(|e| {
+            report_error(
+                "Failed to load rules",
+                SemantrixError::ruleset(
+                    &rules,
+                    format!("Failed to open rules file {}: {}", rules.display(), e),
+                )
+                .into(),
+            )
+        })?;
+        let ruleset: Ruleset = serde_yaml::from_reader(file).map_err(|e| {
+            report_error(
+                "Failed to load rules",
+                SemantrixError::ruleset(
+                    &rules,
+                    format!("Failed to parse rules file {}: {}", rules.display(), e),
+                )
+                .into(),
+            )
+        })?;
+        Ok(Ruleset {
+            path: rules,
+            path_case: self.pipeline.workspace.path_case,
+            ..ruleset
+        })
+    }
+
+    fn render_description(&self, template: &str) -> Result<String, Error> {
+        self.render_tool_template(template, &description_context())
+    }
+
+    /// Builds the tool output in the response type configured for the tool, the JSON contents
+    /// follow the rendered prompt if both are configured, so their timings include its rendering
+    fn tool_result<J, P>(
+        &self,
+        tool: McpToolName,
+        json: J,
+        prompt: P,
+    ) -> Result<CallToolResult, Error>
+    where
+        J: FnOnce() -> Result<Vec<Content>, Error>,
+        P: FnOnce() -> Result<String, Error>,
+    {
+        let json = || timed_render(json);
+        let prompt = || timed_render(prompt);
+        match self.live_config.get().response_for(tool) {
+            ResponseType::Json => Ok(CallToolResult::success(json()?)),
+            ResponseType::Prompt => Ok(CallToolResult::success(vec![Content::text(prompt()?)])),
+            ResponseType::Both => {
+                let mut contents = vec![Content::text(prompt()?)];
+                contents.extend(json()?);
+                Ok(CallToolResult::success(contents))
+            }
+        }
+    }
+
+    /// Renders the template of a tool or a prompt, the failure names the template
+    fn render_tool_template(
+        &self,
+        template: &str,
+        context: &tera::Context,
+    ) -> Result<String, Error> {
+        self.live_config
+            .tera()
+            .render(template, context)
+            .map_err(|e| {
+                Error::internal_error(
+                    format!("Failed to render template: {} with path: {}", e, template),
+                    None,
+                )
+            })
+    }
+
+    /// Serializes the tool output rewriting paths relative to the workspace if it is configured
+    fn output_value<T: Serialize>(&self, value: &T) -> Result<Value, Error> {
+        let value = serde_json::to_value(value).map_err(|e| {
+            Error::internal_error(format!("Failed to serialize output: {}", e), None)
+        })?;
+        if self.live_config.get().output_paths == OutputPaths::WorkspaceRelative {
+            let workspace_path = workspace_root(&self.pipeline.workspace)
+                .map_err(|e| report_error("Failed to get workspace path", e))?;
+            Ok(relativize_output_paths(value, &workspace_path))
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// True if the tool is switched off in the configuration, unknown names are not disabled
+    fn is_disabled_tool(&self, name: &str) -> bool {
+        McpToolName::iter().any(|tool| tool.to_string() == name && !self.config.tool_enabled(tool))
+    }
+
+    /// Service of the workspace named by the `workspace` argument of the call, the argument may
+    /// be omitted if only one workspace is served
+    fn select_workspace(&self, arguments: Option<&JsonObject>) -> Result<Self, Error> {
+        let name = arguments.and_then(|it| it.get(WORKSPACE_ARGUMENT));
+        let workspace = match name {
+            Some(Value::String(name)) => self
+                .workspaces
+                .iter()
+                .find(|it| it.pipeline.workspace.name == *name),
+            None if self.workspaces.len() == 1 => self.workspaces.first(),
+            _ => None,
+        };
+        let Some(workspace) = workspace else {
+            return Err(Error::invalid_params(
+                format!(
+                    "Argument {} must be one of {}, but got {}",
+                    WORKSPACE_ARGUMENT,
+                    self.workspace_names().join(", "),
+                    name.map_or("nothing".to_string(), Value::to_string)
+                ),
+                None,
+            ));
+        };
+        Ok(Self {
+            pipeline: workspace.pipeline.clone(),
+            overview_cache: workspace.overview_cache.clone(),
+            query_cache: workspace.query_cache.clone(),
+            ..self.clone()
+        })
+    }
+
+    fn workspace_names(&self) -> Vec<String> {
+        self.workspaces
+            .iter()
+            .map(|it| it.pipeline.workspace.name.clone())
+            .collect()
+    }
+
+    /// Adds the `workspace` argument to the input schema of the tool, it is required if several
+    /// workspaces are served
+    fn with_workspace_argument(&self, mut tool: Tool) -> Tool {
+        let names = self.workspace_names();
+        let several = names.len() > 1;
+        let schema = Arc::make_mut(&mut tool.input_schema);
+        if let Value::Object(properties) = schema
+            .entry("properties")
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            properties.insert(
+                WORKSPACE_ARGUMENT.to_string(),
+                serde_json::json!({
+                    "type": "string",
+                    "enum": names,
+                    "description": "Name of the workspace the tool runs on",
+                }),
+            );
+        }
+        if several {
+            let required = schema
+                .entry("required")
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(required) = required {
+                required.push(Value::String(WORKSPACE_ARGUMENT.to_string()));
+            }
+        }
+        tool
+    }
+
+    /// Replaces the descriptions of the queries in the input schema of the search tools with
+    /// the description templates of the config
+    fn with_query_descriptions(&self, mut tool: Tool) -> Result<Tool, Error> {
+        let config = self.live_config.get();
+        let descriptions = &config.templates.description;
+        let queries = match tool.name.as_ref() {
+            "code_reuse_search" => vec![
+                ("semantic_queries", &descriptions.semantic_query),
+                ("name_patterns", &descriptions.fuzzy_query),
+            ],
+            "fuzzy_search" => vec![("name_patterns", &descriptions.fuzzy_query)],
+            _ => return Ok(tool),
+        };
+        let schema = Arc::make_mut(&mut tool.input_schema);
+        for (name, template) in queries {
+            let description = self.render_description(template)?;
+            if let Some(property) = schema
+                .get_mut("properties")
+                .and_then(|it| it.get_mut(name))
+                .and_then(Value::as_object_mut)
+            {
+                property.insert("description".to_string(), Value::String(description));
+            }
+        }
+        Ok(tool)
+    }
+
+    /// Waits up to `readiness_wait_ms` for the LSP server, None if it is still initializing
+    async fn wait_lsp_server(&self) -> Option<GuardedLspServer> {
+        let mut lsp_server_rx = self.pipeline.lsp_server_rx.clone();
+        let wait = Duration::from_millis(self.config.readiness_wait_ms);
+        tokio::time::timeout(wait, lsp_server_rx.wait_for(Option::is_some))
+            .await
+            .ok()?
+            .ok()
+            .and_then(|lsp_server| lsp_server.clone())
+    }
+
+    /// Backup table of the previous model while the first scan is not indexed by the current
+    /// model, None once it is
+    fn rebuilding_previous_index(&self) -> Option<Arc<PreviousIndex>> {
+        if *self.pipeline.first_index_scan.borrow() {
+            return None;
+        }
+        self.pipeline.previous_index.borrow().clone()
+    }
+
+    /// Waits up to `readiness_wait_ms` for the first index scan, false if it is not finished
+    async fn wait_index(&self) -> bool {
+        let mut first_index_scan = self.pipeline.first_index_scan.clone();
+        let wait = Duration::from_millis(self.config.readiness_wait_ms);
+        tokio::time::timeout(wait, first_index_scan.wait_for(|ready| *ready))
+            .await
+            .is_ok_and(|ready| ready.is_ok())
+    }
+
+    /// Runs the tool or returns the result of an identical call made after the last write of
+    /// the index and the last change of the workspace, true if the result is cached
+    async fn run_memoized(
+        &self,
+        tool: Option<McpToolName>,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<(CallToolResult, bool), Error> {
+        let config = self.live_config.get();
+        let Some(key) = tool
+            .filter(|tool| tool.is_memoized() && config.query_cache.ttl_sec > 0)
+            .filter(|_| !includes_scratch(request.arguments.as_ref()))
+            .map(|tool| query_key(&config, tool, request.arguments.as_ref()))
+        else {
+            return Ok((self.run_tool(tool, request, context).await?, false));
+        };
+
+        let last_write = self.pipeline.index_timestamps.last_write();
+        let changed_paths = self.pipeline.changed_paths.load(Ordering::Relaxed);
+        let cached = self.query_cache.lock().ok().and_then(|cache| {
+            cache
+                .get(&key)
+                .filter(|it| it.is_fresh(&config, last_write, changed_paths))
+                .map(|it| it.result.clone())
+        });
+        if let Some(result) = cached {
+            debug!("Tool result is taken from the cache");
+            return Ok((result, true));
+        }
+
+        let ready = self.readiness() == ReadinessStatus::Ready;
+        let started = Instant::now();
+        let result = self.run_tool(tool, request, context).await?;
+        // busy results and results cut off by the deadline are not repeated
+        let complete = ready
+            && result.is_error != Some(true)
+            && started.elapsed() < Duration::from_millis(self.config.tool_timeout_ms);
+        if !complete {
+            return Ok((result, false));
+        }
+        if let Ok(mut cache) = self.query_cache.lock() {
+            cache.retain(|_, it| it.is_fresh(&config, last_write, changed_paths));
+            if cache.len() < config.query_cache.capacity {
+                cache.insert(
+                    key,
+                    CachedQuery {
+                        result: result.clone(),
+                        cached_at: Instant::now(),
+                        last_write,
+                        changed_paths,
+                    },
+                );
+            }
+        }
+        Ok((result, false))
+    }
+
+    /// Runs the tool, the failure of a search over a table deleted outside of semantrix starts
+    /// its recovery and is answered by the initializing status instead
+    async fn run_tool(
+        &self,
+        tool: Option<McpToolName>,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, Error> {
+        let result = self.run_tool_box(tool, request, context).await;
+        if result.is_err() && self.pipeline.recovery.recover_if_lost().await {
+            return self.busy(ReadinessStatus::IndexInitializing).await;
+        }
+        result
+    }
+
+    /// Runs the tool after its permit is acquired, the excess calls wait in the queue. Tools
+    /// without own stage deadlines are cut off by the tool timeout
+    async fn run_tool_box(
+        &self,
+        tool: Option<McpToolName>,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, Error> {
+        let _permit = match tool.and_then(|tool| self.tool_permits.get(&tool)) {
+            Some(permits) => {
+                debug!("Waiting for tool permit");
+                Some(permits.acquire().await.map_err(|e| {
+                    Error::internal_error(format!("Failed to acquire tool permit: {}", e), None)
+                })?)
+            }
+            None => None,
+        };
+        info!("Tool call started");
+
+        let context = ToolCallContext::new(self, request, context);
+        if tool.is_some_and(|tool| tool.has_stage_deadlines()) {
+            return Self::tool_box().call(context).await;
+        }
+
+        let tool_timeout = Duration::from_millis(self.config.tool_timeout_ms);
+        match tokio::time::timeout(tool_timeout, Self::tool_box().call(context)).await {
+            Ok(result) => result,
+            Err(_) => {
+                let mut timeout = ToolTimeout::default();
+                timeout.cut_off(
+                    &tool.map(|tool| tool.to_string()).unwrap_or_default(),
+                    self.config.tool_timeout_ms,
+                );
+                Ok(CallToolResult::error(vec![
+                    Content::json(&timeout)?,
+                    Content::text(format!(
+                        "Tool is cut off after {} ms without results",
+                        self.config.tool_timeout_ms
+                    )),
+                ]))
+            }
+        }
+    }
+
+    /// Error result with the machine readable status and the readable message
+    async fn busy(&self, status: ReadinessStatus) -> Result<CallToolResult, Error> {
+        let (message, retry_after_ms) = match status {
+            ReadinessStatus::LspInitializing => (
+                "Waiting for LSP server to be initialized",
+                Some(LSP_RETRY_AFTER_MS),
+            ),
+            ReadinessStatus::IndexInitializing => (
+                "Waiting for index to be initialized",
+                Some(INDEX_RETRY_AFTER_MS),
+            ),
+            ReadinessStatus::Ready => ("Ready", None),
+        };
+        // the borrow of the watch is not held across the count below
+        let first_index_scan = *self.pipeline.first_index_scan.borrow();
+        let busy = BusyStatus {
+            status,
+            message: message.to_string(),
+            retry_after_ms,
+            first_path_scan: self.pipeline.first_path_scan.load(Ordering::Relaxed),
+            first_chunks_scan: self.pipeline.first_chunks_scan.load(Ordering::Relaxed),
+            first_index_scan,
+            chunks_count: count_chunks(&self.pipeline.table)
+                .await
+                .inspect_err(|e| error!("Failed to count chunks: {}", e))
+                .ok(),
+        };
+        Ok(CallToolResult::error(vec![
+            Content::json(&busy)?,
+            Content::text(busy.message.clone()),
+        ]))
+    }
+}
+
+#[tool(tool_box)]
+impl McpService {
+    #[tool(
+        description = "A tool that searches project symbols by name with the LSP server and returns their locations, documentation and optionally code. It works as soon as the LSP server is ready, without waiting for the semantic index"
+    )]
+    pub async fn fuzzy_search(
+        &self,
+        #[tool(aggr)] FuzzySearchRequest {
+            name_patterns,
+            kinds,
+            need_code,
+        }: FuzzySearchRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        let Some(lsp_server) = self.wait_lsp_server().await else {
+            return self.busy(ReadinessStatus::LspInitializing).await;
+        };
+
+        let kinds = SymbolKindFilter::try_from(kinds.unwrap_or_default())
+            .map_err(|e| Error::invalid_params(format!("Failed to parse kinds: {}", e), None))?;
+
+        let (mut fuzzy_symbols, _) = timed_stage(
+            "fuzzy",
+            get_fuzzy_symbols(
+                &lsp_server,
+                &config,
+                name_patterns,
+                kinds,
+                need_code,
+                true,
+                SymbolsPage {
+                    offset: 0,
+                    max_per_file: config.search.max_results_per_file,
+                },
+            ),
+        )
+        .await
+        .inspect_err(|e| {
+            error!("Error getting symbols: {}", e);
+        })
+        .map_err(|e| report_error("Failed to get symbols", e))?;
+
+        debug!("Fuzzy symbols: {:?}", fuzzy_symbols);
+        let degraded =
+            DegradedSymbols::new(fuzzy_symbols.iter(), config.search.response_budget_chars);
+        fuzzy_symbols.iter_mut().for_each(|it| degraded.apply(it));
+
+        self.tool_result(
+            McpToolName::FuzzySearch,
+            || Ok(vec![Content::json(self.output_value(&fuzzy_symbols)?)?]),
+            || {
+                let mut context = tera::Context::new();
+                context.insert("fuzzy_symbols", &self.output_value(&fuzzy_symbols)?);
+
+                self.render_tool_template(&config.templates.prompts.fuzzy_search, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that searches indexed files by meaning and returns matched text chunks with their lines. It doesn't need the LSP server, so it works for any indexed files including documentation"
+    )]
+    pub async fn semantic_search(
+        &self,
+        #[tool(aggr)] SemanticSearchRequest {
+            queries,
+            limit,
+            path_glob,
+            mode,
+            models,
+            max_results_per_file,
+            include_scratch,
+            neighbors,
+            exclude_comment_only_matches,
+        }: SemanticSearchRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        let previous_index = self.rebuilding_previous_index();
+        if previous_index.is_none() && !self.wait_index().await {
+            return self.busy(ReadinessStatus::IndexInitializing).await;
+        }
+
+        let path_glob = path_glob
+            .as_deref()
+            .map(|it| self.pipeline.workspace.path_case.glob(it))
+            .transpose()
+            .map_err(|e| {
+                Error::invalid_params(format!("Failed to parse path glob: {}", e), None)
+            })?;
+
+        // the backup table has no full-text index and its model has no routes
+        let (vector_store, mode): (Arc<dyn VectorBackend>, _) = match (&previous_index, models) {
+            (Some(previous), _) => (previous.vector_store.clone(), Some(SearchMode::Vector)),
+            (None, Some(models)) => (
+                Arc::new(
+                    self.pipeline
+                        .routes
+                        .only(&models)
+                        .map_err(|e| Error::invalid_params(e.to_string(), None))?,
+                ),
+                mode,
+            ),
+            (None, None) => (self.pipeline.vector_store.clone(), mode),
+        };
+        let (vector_store, _) = pin_store(vector_store).await;
+
+        let limit = limit.unwrap_or(config.search.semantic.search_limit);
+        let max_results_per_file = max_results_per_file.or(config.search.max_results_per_file);
+        let scratch_queries = queries.clone();
+        let mut hits = timed_stage(
+            "semantic",
+            search_chunks(
+                &config,
+                &self.pipeline.workspace,
+                queries,
+                path_glob.as_ref(),
+                mode.unwrap_or(config.search.semantic.search_mode),
+                vector_store,
+                ChunksPage {
+                    limit,
+                    max_per_file: max_results_per_file,
+                },
+            ),
+        )
+        .await
+        .map_err(|e| report_error("Failed to search chunks", e))?;
+
+        // the scratch table has no full-text index, so its buffers are searched by the vectors
+        let scratch = match include_scratch {
+            Some(true) => self.scratch.backend().await,
+            _ => None,
+        };
+        if let Some(scratch) = scratch {
+            let scratch_hits = search_chunks(
+                &config,
+                &self.pipeline.workspace,
+                scratch_queries,
+                None,
+                SearchMode::Vector,
+                scratch,
+                ChunksPage {
+                    limit,
+                    max_per_file: max_results_per_file,
+                },
+            )
+            .await
+            .map_err(|e| report_error("Failed to search scratch buffers", e))?;
+            hits.extend(scratch_hits);
+        }
+
+        debug!("Semantic hits: {:?}", hits);
+        reindex_stale(&self.pipeline, hits.iter().map(|it| &it.snippet));
+        if exclude_comment_only_matches.unwrap_or(false) {
+            hits.retain(|it| !it.snippet.is_comment_only());
+        }
+        set_hits_git_metadata(&config, &self.pipeline.workspace, &mut hits).await;
+        // the chunks of the previous model are in its backup table
+        let neighbors = neighbors.filter(|it| *it > 0 && previous_index.is_none());
+        if let Some(neighbors) = neighbors {
+            set_hits_neighbors(
+                &mut hits,
+                &self.pipeline.routes,
+                &self.pipeline.workspace,
+                neighbors,
+            )
+            .await;
+        }
+
+        let mut warnings = Vec::new();
+        if let Some(previous) = &previous_index {
+            for hit in hits.iter_mut() {
+                hit.from_previous_model = true;
+            }
+            warnings.push(SearchWarning {
+                source: "semantic".to_string(),
+                message: format!(
+                    "The index is rebuilt for model {}, the hits are found by the previous model {}",
+                    self.config.search.semantic.model, previous.model_id
+                ),
+            });
+        } else if hits.is_empty() {
+            warnings.extend(empty_index_warning(&self.pipeline).await);
+        }
+
+        self.tool_result(
+            McpToolName::SemanticSearch,
+            || {
+                Ok(vec![
+                    Content::json(self.output_value(&hits)?)?,
+                    Content::json(self.output_value(&warnings)?)?,
+                ])
+            },
+            || {
+                let mut context = tera::Context::new();
+                context.insert("hits", &self.output_value(&hits)?);
+                context.insert("warnings", &warnings);
+
+                self.render_tool_template(&config.templates.prompts.semantic_search, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that returns the full source code, documentation and exact range of the symbol declared in the file"
+    )]
+    pub async fn get_symbol_code(
+        &self,
+        #[tool(aggr)] GetSymbolCodeRequest { path, name, kind }: GetSymbolCodeRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        let Some(lsp_server) = self.wait_lsp_server().await else {
+            return self.busy(ReadinessStatus::LspInitializing).await;
+        };
+
+        let workspace_path = workspace_root(&self.pipeline.workspace)
+            .map_err(|e| report_error("Failed to get workspace path", e))?;
+
+        let Some(absolute_path) = workspace_child_path(&workspace_path, &path) else {
+            return Err(Error::invalid_params(
+                format!("Path {} is outside of the workspace", path),
+                None,
+            ));
+        };
+
+        let kind_filter = kind
+            .map(|kind| SymbolKindFilter::try_from(vec![kind]))
+            .transpose()
+            .map_err(|e| Error::invalid_params(format!("Failed to parse kind: {}", e), None))?;
+
+        let symbol_code = if !absolute_path.is_file() {
+            SymbolCode::NotFound {
+                path,
+                name,
+                reason: "file not found".to_string(),
+            }
+        } else {
+            let resolved = resolve_symbol(&lsp_server, &absolute_path, &name, kind_filter.as_ref())
+                .await
+                .map_err(|e| report_error("Failed to resolve symbol", e))?;
+            match resolved {
+                None => SymbolCode::NotFound {
+                    path,
+                    name,
+                    reason: "symbol not found".to_string(),
+                },
+                Some(ResolvedSymbol {
+                    symbol,
+                    alternatives,
+                }) => {
+                    let alternatives = alternatives
+                        .into_iter()
+                        .filter(|it| it.location.uri == symbol.location.uri)
+                        .collect::<Vec<_>>();
+                    if alternatives.is_empty() {
+                        SymbolCode::Found {
+                            symbol: Box::new(symbol),
+                        }
+                    } else {
+                        SymbolCode::Ambiguous {
+                            candidates: std::iter::once(symbol).chain(alternatives).collect(),
+                        }
+                    }
+                }
+            }
+        };
+
+        debug!("Symbol code: {:?}", symbol_code);
+
+        self.tool_result(
+            McpToolName::GetSymbolCode,
+            || Ok(vec![Content::json(self.output_value(&symbol_code)?)?]),
+            || {
+                let mut context = tera::Context::new();
+                context.insert("symbol_code", &self.output_value(&symbol_code)?);
+
+                self.render_tool_template(&config.templates.prompts.symbol_code, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that returns the code, documentation, last commit and rules of the symbols returned by code_reuse_search or fuzzy_search without them, so the search can skip them and the details are requested for the interesting symbols only"
+    )]
+    pub async fn enrich_symbols(
+        &self,
+        #[tool(aggr)] request: EnrichSymbolsRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        request
+            .validate()
+            .map_err(|e| Error::invalid_params(e, None))?;
+        let Some(lsp_server) = self.wait_lsp_server().await else {
+            return self.busy(ReadinessStatus::LspInitializing).await;
+        };
+
+        let ruleset = self.load_rules()?;
+        let EnrichSymbolsResult {
+            rules,
+            rules_detailed,
+            symbols,
+            not_found,
+        } = search::enrich_symbols(
+            &lsp_server,
+            &config,
+            &self.pipeline.workspace,
+            &ruleset,
+            request,
+        )
+        .await
+        .map_err(|e| report_error("Failed to enrich symbols", e))?;
+
+        self.tool_result(
+            McpToolName::EnrichSymbols,
+            || {
+                Ok(vec![Content::json(self.output_value(
+                    &EnrichSymbolsResponse {
+                        schema_version: RESULT_SCHEMA_VERSION,
+                        rules: &rules,
+                        rules_detailed: &rules_detailed,
+                        symbols: &symbols,
+                        not_found: &not_found,
+                    },
+                )?)?])
+            },
+            || {
+                let mut context = tera::Context::new();
+                context.insert("rules", &self.output_value(&rules)?);
+                context.insert("rules_detailed", &self.output_value(&rules_detailed)?);
+                context.insert("symbols", &self.output_value(&symbols)?);
+                context.insert("not_found", &self.output_value(&not_found)?);
+
+                self.render_tool_template(&config.templates.prompts.enrich_symbols, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that finds usages of the symbol declared in the file and returns them grouped by file with the referencing line of code"
+    )]
+    pub async fn find_references(
+        &self,
+        #[tool(aggr)] FindReferencesRequest {
+            path,
+            name,
+            include_declaration,
+        }: FindReferencesRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        let Some(lsp_server) = self.wait_lsp_server().await else {
+            return self.busy(ReadinessStatus::LspInitializing).await;
+        };
+
+        let workspace_path = workspace_root(&self.pipeline.workspace)
+            .map_err(|e| report_error("Failed to get workspace path", e))?;
+
+        let Some(absolute_path) = workspace_child_path(&workspace_path, &path) else {
+            return Err(Error::invalid_params(
+                format!("Path {} is outside of the workspace", path),
+                None,
+            ));
+        };
+
+        let resolved = resolve_symbol(&lsp_server, &absolute_path, &name, None)
+            .await
+            .map_err(|e| report_error("Failed to resolve symbol", e))?;
+
+        let Some(ResolvedSymbol { symbol, .. }) = resolved else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Symbol {} not found in {}",
+                name, path
+            ))]));
+        };
+
+        let references = get_grouped_references(
+            &lsp_server,
+            symbol,
+            include_declaration.unwrap_or(false),
+            config.search.fuzzy.references_limit,
+        )
+        .await
+        .map_err(|e| report_error("Failed to get references", e))?;
+
+        debug!("References: {:?}", references);
+
+        self.tool_result(
+            McpToolName::FindReferences,
+            || Ok(vec![Content::json(self.output_value(&references)?)?]),
+            || {
+                let mut context = tera::Context::new();
+                context.insert("references", &self.output_value(&references)?);
+
+                self.render_tool_template(&config.templates.prompts.references, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that finds near duplicate code in the files matched by the glob and returns clusters of similar line ranges sorted by similarity"
+    )]
+    pub async fn find_duplicates(
+        &self,
+        #[tool(aggr)] FindDuplicatesRequest {
+            path_glob,
+            threshold,
+        }: FindDuplicatesRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        if !self.wait_index().await {
+            return self.busy(ReadinessStatus::IndexInitializing).await;
+        }
+
+        let path_glob = self
+            .pipeline
+            .workspace
+            .path_case
+            .glob(&path_glob)
+            .map_err(|e| {
+                Error::invalid_params(format!("Failed to parse path glob: {}", e), None)
+            })?;
+
+        let clusters = find_duplicate_chunks(
+            &self.pipeline.table,
+            &self.pipeline.workspace,
+            &path_glob,
+            threshold.unwrap_or(0.9),
+        )
+        .await
+        .map_err(|e| report_error("Failed to find duplicates", e))?;
+
+        self.tool_result(
+            McpToolName::FindDuplicates,
+            || Ok(vec![Content::json(self.output_value(&clusters)?)?]),
+            || {
+                let mut context = tera::Context::new();
+                context.insert("clusters", &self.output_value(&clusters)?);
+
+                self.render_tool_template(&config.templates.prompts.duplicates, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that finds indexed code similar to the given code snippet. Use it before writing or copying code to check whether something like it already exists in the project"
+    )]
+    pub async fn similar_code(
+        &self,
+        #[tool(aggr)] SimilarCodeRequest {
+            code,
+            min_score,
+            include_symbols,
+            include_scratch,
+        }: SimilarCodeRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        if !self.wait_index().await {
+            return self.busy(ReadinessStatus::IndexInitializing).await;
+        }
+
+        if code.trim().is_empty() {
+            return Err(Error::invalid_params("Code snippet is empty", None));
+        }
+
+        let min_score = min_score.unwrap_or(0.5);
+        let (mut similar, chunks) = timed_stage(
+            "semantic",
+            find_similar_code(
+                &self.pipeline.table,
+                &config,
+                &self.pipeline.workspace,
+                &self.embedding_model,
+                &code,
+                min_score,
+            ),
+        )
+        .await
+        .map_err(|e| report_error("Failed to find similar code", e))?;
+
+        // the scratch chunks have no LSP symbols, so only their snippets are merged
+        let scratch = match include_scratch {
+            Some(true) => self.scratch.table().await,
+            _ => None,
+        };
+        if let Some(scratch) = scratch {
+            let (scratch_similar, _) = find_similar_code(
+                &scratch,
+                &config,
+                &self.pipeline.workspace,
+                &self.embedding_model,
+                &code,
+                min_score,
+            )
+            .await
+            .map_err(|e| report_error("Failed to compare scratch buffers", e))?;
+            similar.extend(scratch_similar);
+            similar.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+            similar.truncate(config.search.semantic.search_limit);
+        }
+
+        let lsp_server = self.pipeline.lsp_server_rx.borrow().clone();
+        let symbols = match lsp_server {
+            Some(lsp_server) if include_symbols.unwrap_or(true) => Some(
+                get_chunks_symbols(&lsp_server, &config, chunks, true, true)
+                    .await
+                    .0,
+            ),
+            _ => None,
+        };
+
+        self.tool_result(
+            McpToolName::SimilarCode,
+            || {
+                Ok(vec![
+                    Content::json(self.output_value(&similar)?)?,
+                    Content::json(self.output_value(&symbols.as_deref().unwrap_or_default())?)?,
+                ])
+            },
+            || {
+                let mut context = tera::Context::new();
+                context.insert("similar", &self.output_value(&similar)?);
+                if let Some(symbols) = &symbols {
+                    context.insert("symbols", &self.output_value(symbols)?);
+                }
+
+                self.render_tool_template(&config.templates.prompts.similar_code, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that checks the symbols of the file against every ruleset of the rules file and reports which of kind, name, path and code predicates are not matched"
+    )]
+    pub async fn rules_preview(
+        &self,
+        #[tool(aggr)] RulesPreviewRequest { path, name }: RulesPreviewRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        let Some(lsp_server) = self.wait_lsp_server().await else {
+            return self.busy(ReadinessStatus::LspInitializing).await;
+        };
+
+        let workspace_path = workspace_root(&self.pipeline.workspace)
+            .map_err(|e| report_error("Failed to get workspace path", e))?;
+
+        let Some(absolute_path) = workspace_child_path(&workspace_path, &path) else {
+            return Err(Error::invalid_params(
+                format!("Path {} is outside of the workspace", path),
+                None,
+            ));
+        };
+
+        let symbols = match &name {
+            Some(name) => resolve_symbol(&lsp_server, &absolute_path, name, None)
+                .await
+                .map_err(|e| report_error("Failed to resolve symbol", e))?
+                .map(|it| vec![it.symbol])
+                .unwrap_or_default(),
+            None => {
+                let url = path_to_url(&absolute_path).ok_or_else(|| {
+                    Error::invalid_params(format!("Invalid path: {}", path), None)
+                })?;
+                get_documents_symbols(
+                    &lsp_server,
+                    HashSet::from([url]),
+                    SymbolKindFilter::default(),
+                    true,
+                )
+                .await
+            }
+        };
+
+        if symbols.is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "No symbols found in {}",
+                path
+            ))]));
+        }
+
+        let rules_started = Instant::now();
+        let diagnostics = self
+            .load_rules()?
+            .preview(&symbols)
+            .map_err(|e| report_error("Failed to check rules", e))?;
+        record_stage("rules", rules_started.elapsed());
+
+        debug!("Rules diagnostics: {:?}", diagnostics);
+
+        self.tool_result(
+            McpToolName::RulesPreview,
+            || Ok(vec![Content::json(self.output_value(&diagnostics)?)?]),
+            || {
+                let mut context = tera::Context::new();
+                context.insert("diagnostics", &self.output_value(&diagnostics)?);
+
+                self.render_tool_template(&config.templates.prompts.rules_preview, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that forces reindexing of a workspace file or folder changed outside of the file watcher view"
+    )]
+    pub async fn reindex_path(
+        &self,
+        #[tool(aggr)] ReindexPathRequest { path }: ReindexPathRequest,
+    ) -> Result<CallToolResult, Error> {
+        let Some(absolute_path) = indexed_child_path(&self.pipeline.workspace, &path) else {
+            return Err(Error::invalid_params(
+                format!(
+                    "Path {} is outside of the workspace and its extra roots",
+                    path
+                ),
+                None,
+            ));
+        };
+
+        info!("Reindexing path: {}", absolute_path.display());
+
+        let deleted_chunks = self
+            .pipeline
+            .vector_store
+            .delete_by_paths(std::slice::from_ref(&absolute_path))
+            .await
+            .map_err(|e| report_error("Failed to delete chunks", e))?;
+
+        let kind = if absolute_path.exists() {
+            EventKind::Modify(ModifyKind::Any)
+        } else {
+            EventKind::Remove(RemoveKind::Any)
+        };
+
+        let queued = self
+            .pipeline
+            .path_event_tx
+            .send(Arc::new(PathEvent {
+                path: Arc::new(absolute_path),
+                kind,
+            }))
+            .await
+            .inspect_err(|e| error!("Failed to queue path event: {:?}", e))
+            .is_ok();
+
+        let result = ReindexPathResult {
+            path,
+            deleted_chunks,
+            queued,
+        };
+
+        self.tool_result(
+            McpToolName::ReindexPath,
+            || Ok(vec![Content::json(&result)?]),
+            || {
+                Ok(format!(
+                    "Deleted {} chunks of {}, {}",
+                    result.deleted_chunks,
+                    result.path,
+                    if result.queued {
+                        "the path is queued for reindexing"
+                    } else {
+                        "the path is not queued for reindexing"
+                    }
+                ))
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that lists the workspace files which failed to be indexed with the stage and the reason of the failure, the most recent first. Use it when a file is missing from the search results"
+    )]
+    pub async fn list_index_errors(
+        &self,
+        #[tool(aggr)] ListIndexErrorsRequest { path, limit }: ListIndexErrorsRequest,
+    ) -> Result<CallToolResult, Error> {
+        let absolute_path = match &path {
+            Some(path) => {
+                let workspace_path = workspace_root(&self.pipeline.workspace)
+                    .map_err(|e| report_error("Failed to get workspace path", e))?;
+                let Some(absolute_path) = workspace_child_path(&workspace_path, path) else {
+                    return Err(Error::invalid_params(
+                        format!("Path {} is outside of the workspace", path),
+                        None,
+                    ));
+                };
+                Some(absolute_path)
+            }
+            None => None,
+        };
+
+        let result = ListIndexErrorsResult {
+            total: self.pipeline.index_errors.len(),
+            errors: self.pipeline.index_errors.recent(
+                absolute_path.as_deref(),
+                limit.unwrap_or(DEFAULT_INDEX_ERRORS_LIMIT),
+            ),
+        };
+
+        self.tool_result(
+            McpToolName::ListIndexErrors,
+            || Ok(vec![Content::json(self.output_value(&result)?)?]),
+            || {
+                let errors = self.output_value(&result.errors)?;
+                let lines = errors
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|error| {
+                        format!(
+                            "- `{}` failed to {} at {}: {}",
+                            error["path"].as_str().unwrap_or_default(),
+                            error["stage"].as_str().unwrap_or_default(),
+                            error["failed_at"].as_str().unwrap_or_default(),
+                            error["reason"].as_str().unwrap_or_default()
+                        )
+                    })
+                    .join("\n");
+                Ok(format!(
+                    "{} files failed to be indexed, {} listed\n{}",
+                    result.total,
+                    result.errors.len(),
+                    lines
+                ))
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that returns the JSON schemas of the results of code_reuse_search, symbols_placer and index_status with their schema_version. Use it to parse the JSON results of these tools"
+    )]
+    pub async fn get_result_schema(
+        &self,
+        #[tool(aggr)] GetResultSchemaRequest { tool }: GetResultSchemaRequest,
+    ) -> Result<CallToolResult, Error> {
+        let schemas = McpToolName::iter()
+            .filter(|it| tool.is_none_or(|tool| tool == *it))
+            .filter_map(|it| Some((it.to_string(), it.result_schema()?)))
+            .collect::<BTreeMap<_, _>>();
+        if let (Some(tool), true) = (tool, schemas.is_empty()) {
+            return Err(Error::invalid_params(
+                format!("Tool {} has no described result", tool),
+                None,
+            ));
+        }
+        let result = ResultSchemas {
+            schema_version: RESULT_SCHEMA_VERSION,
+            schemas,
+        };
+
+        self.tool_result(
+            McpToolName::GetResultSchema,
+            || Ok(vec![Content::json(&result)?]),
+            || {
+                let schemas = serde_json::to_string_pretty(&result.schemas).map_err(|e| {
+                    Error::internal_error(format!("Failed to serialize schemas: {}", e), None)
+                })?;
+                Ok(format!(
+                    "JSON results of schema version {} by tool name:\n```json\n{}\n```",
+                    result.schema_version, schemas
+                ))
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that lists the supported embedding models with their dimensions, whether they are quantized, which one is configured and which ones are downloaded. Use it to pick a model for the disk and latency budget"
+    )]
+    pub async fn list_models(&self) -> Result<CallToolResult, Error> {
+        let semantic = &self.config.search.semantic;
+        let result = ListModelsResult {
+            model: match &semantic.custom_model {
+                Some(custom_model) => custom_model.model_file.display().to_string(),
+                None => semantic.model.clone(),
+            },
+            ndims: self.ndims,
+            models: list_models(semantic),
+        };
+
+        self.tool_result(
+            McpToolName::ListModels,
+            || Ok(vec![Content::json(self.output_value(&result)?)?]),
+            || {
+                let lines = result
+                    .models
+                    .iter()
+                    .map(|model| {
+                        let flags = [
+                            (model.quantized, "quantized"),
+                            (model.configured, "configured"),
+                            (model.local, "local"),
+                        ]
+                        .into_iter()
+                        .filter(|(set, _)| *set)
+                        .map(|(_, flag)| flag)
+                        .join(", ");
+                        let flags = if flags.is_empty() {
+                            String::new()
+                        } else {
+                            format!(" ({})", flags)
+                        };
+                        format!(
+                            "- `{}` {} dims{}: {}",
+                            model.name, model.dims, flags, model.description
+                        )
+                    })
+                    .join("\n");
+                Ok(format!(
+                    "Configured model {} embeds {} dims, {} models are supported\n{}",
+                    result.model,
+                    result.ndims,
+                    result.models.len(),
+                    lines
+                ))
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that indexes an unsaved buffer under the name, so semantic_search and similar_code find it with include_scratch. The buffers are kept apart from the workspace files and expire after a while"
+    )]
+    pub async fn index_scratch(
+        &self,
+        #[tool(aggr)] IndexScratchRequest { name, content }: IndexScratchRequest,
+    ) -> Result<CallToolResult, Error> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::invalid_params("Scratch buffer name is empty", None));
+        }
+        if content.trim().is_empty() {
+            return Err(Error::invalid_params(
+                "Scratch buffer content is empty",
+                None,
+            ));
+        }
+
+        let chunks = self
+            .scratch
+            .index(name, &content)
+            .await
+            .map_err(|e| report_error("Failed to index scratch buffer", e))?;
+        let result = IndexScratchResult {
+            path: scratch_path(name).display().to_string(),
+            chunks,
+            ttl_sec: self.config.scratch.ttl_sec,
+        };
+        info!("Indexed scratch buffer {}: {:?}", name, result);
+
+        self.tool_result(
+            McpToolName::IndexScratch,
+            || Ok(vec![Content::json(self.output_value(&result)?)?]),
+            || {
+                Ok(format!(
+                    "Scratch buffer `{}` is indexed as {} chunks for {} seconds",
+                    result.path, result.chunks, result.ttl_sec
+                ))
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that reports whether the semantic index is ready, how big it is and when it was last updated, optionally when the given file was last indexed"
+    )]
+    pub async fn index_status(
+        &self,
+        #[tool(aggr)] IndexStatusRequest { path }: IndexStatusRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        let path_status = match path {
+            Some(path) => Some(self.path_index_status(path).await?),
+            None => None,
+        };
+        let table_stats = table_stats(
+            &self.pipeline.table,
+            &self.pipeline.workspace.semantic.table,
+        )
+        .await
+        .map_err(|e| report_error("Failed to read table stats", e))?;
+        let index_status = IndexStatus {
+            workspace: self.pipeline.workspace.name.clone(),
+            read_only: self.config.read_only,
+            status: self.readiness(),
+            first_path_scan: self.pipeline.first_path_scan.load(Ordering::Relaxed),
+            first_chunks_scan: self.pipeline.first_chunks_scan.load(Ordering::Relaxed),
+            first_index_scan: *self.pipeline.first_index_scan.borrow(),
+            chunks_count: table_stats.rows,
+            files_count: table_stats.paths,
+            model: self.config.search.semantic.model.clone(),
+            ndims: self.ndims,
+            embeddings_index: table_stats.embeddings_index,
+            lsp_server_ready: self.pipeline.lsp_server_rx.borrow().is_some(),
+            last_write: self.pipeline.index_timestamps.last_write(),
+            last_optimize: self.pipeline.index_timestamps.last_optimize(),
+            last_vacuum: self.pipeline.index_timestamps.last_vacuum(),
+            reclaimed_bytes: self.pipeline.index_timestamps.reclaimed_bytes(),
+            search_params: self.config.search.semantic.search_params.clone(),
+            empty_index: (table_stats.rows == 0).then(|| empty_index_message(&self.pipeline)),
+            index_errors: self.pipeline.index_errors.len(),
+            recent_index_errors: self.pipeline.index_errors.recent(None, STATUS_INDEX_ERRORS),
+            rebuild: self
+                .rebuilding_previous_index()
+                .map(|previous| RebuildStatus {
+                    previous_model: previous.model_id.clone(),
+                    previous_files: previous.files,
+                    progress_percent: if previous.files == 0 {
+                        100.0
+                    } else {
+                        (table_stats.paths as f64 * 100.0 / previous.files as f64).min(100.0)
+                    },
+                }),
+            table_stats,
+            path_status,
+            extra_roots: self
+                .pipeline
+                .workspace
+                .semantic
+                .extra_roots
+                .iter()
+                .map(|root| root.path.clone())
+                .collect(),
+            watcher: self.pipeline.watcher_stats.status(),
+            subsystems: self.pipeline.states.statuses(),
+        };
+
+        debug!("Index status: {:?}", index_status);
+
+        self.tool_result(
+            McpToolName::IndexStatus,
+            || {
+                Ok(vec![Content::json(self.output_value(
+                    &IndexStatusResponse {
+                        schema_version: RESULT_SCHEMA_VERSION,
+                        status: &index_status,
+                    },
+                )?)?])
+            },
+            || {
+                let mut context = tera::Context::new();
+                context.insert("index_status", &index_status);
+
+                self.render_tool_template(&config.templates.prompts.index_status, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that gives a cheap overview of the indexed project: files and lines per top level directory and per file extension, the largest files and workspace symbols grouped by kind. Use it at the start of a task to get familiar with the project structure"
+    )]
+    pub async fn workspace_overview(&self) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        if !self.wait_index().await {
+            return self.busy(ReadinessStatus::IndexInitializing).await;
+        }
+
+        let lsp_server = self.pipeline.lsp_server_rx.borrow().clone();
+        let changed_paths = self.pipeline.changed_paths.load(Ordering::Relaxed);
+        let cached = self.overview_cache.lock().ok().and_then(|cache| {
+            cache
+                .as_ref()
+                .filter(|it| it.is_fresh(&config, changed_paths, lsp_server.is_some()))
+                .map(|it| it.overview.clone())
+        });
+
+        let overview = match cached {
+            Some(overview) => {
+                debug!("Workspace overview is taken from the cache");
+                overview
+            }
+            None => {
+                info!("Building workspace overview");
+                let overview = get_workspace_overview(
+                    &self.pipeline.table,
+                    &self.pipeline.workspace,
+                    lsp_server.as_ref(),
+                    &config.overview,
+                )
+                .await
+                .map_err(|e| {
+                    Error::internal_error(
+                        format!("Failed to build workspace overview: {}", e),
+                        None,
+                    )
+                })?;
+                if let Ok(mut cache) = self.overview_cache.lock() {
+                    *cache = Some(CachedOverview {
+                        overview: overview.clone(),
+                        built_at: Instant::now(),
+                        changed_paths,
+                    });
+                }
+                overview
+            }
+        };
+
+        self.tool_result(
+            McpToolName::WorkspaceOverview,
+            || Ok(vec![Content::json(self.output_value(&overview)?)?]),
+            || {
+                let mut context = tera::Context::new();
+                context.insert("overview", &self.output_value(&overview)?);
+
+                self.render_tool_template(&config.templates.prompts.workspace_overview, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that scans your project to identify symbols and place them to the best place in the project"
+    )]
+    pub async fn symbols_placer(
+        &self,
+        #[tool(aggr)] request: SymbolsPlacerRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        request
+            .validate()
+            .map_err(|e| Error::invalid_params(e, None))?;
+        let Some(lsp_server) = self.wait_lsp_server().await else {
+            return self.busy(ReadinessStatus::LspInitializing).await;
+        };
+        let path_globs = request.path_globs.clone();
+        let symbol_name_patterns = request.symbol_name_patterns.clone();
+
+        // TODO: for POC loaded every request because user can update rules without restarting the server
+        let ruleset = self.load_rules()?;
+        let SymbolsPlacerResult {
+            rules,
+            rules_detailed,
+            symbols,
+            places,
+            all_places_count,
+            skipped,
+            degraded,
+        } = search::symbols_placer(
+            &lsp_server,
+            &config,
+            &self.pipeline.workspace,
+            &ruleset,
+            request,
+        )
+        .await
+        .map_err(|e| report_error("Failed to place symbols", e))?;
+
+        self.tool_result(
+            McpToolName::SymbolsPlacer,
+            || {
+                Ok(vec![Content::json(self.output_value(
+                    &SymbolsPlacerResponse {
+                        schema_version: RESULT_SCHEMA_VERSION,
+                        rules: &rules,
+                        rules_detailed: &rules_detailed,
+                        symbols: &symbols,
+                        places: &places,
+                        skipped,
+                        timings: ToolTimings {
+                            timings: current_stage_timings(),
+                            snapshot_versions: None,
+                        },
+                    },
+                )?)?])
+            },
+            || {
+                let mut context = self.prompt_context(
+                    BTreeMap::from([
+                        ("path_globs", path_globs),
+                        ("symbol_name_patterns", symbol_name_patterns),
+                    ]),
+                    BTreeMap::from([
+                        ("fuzzy_rules", rules.len()),
+                        ("fuzzy_symbols", symbols.len()),
+                        ("references", places.len()),
+                    ]),
+                    BTreeMap::from([("references", places.len() < all_places_count)]),
+                    degraded,
+                )?;
+                context.insert("fuzzy_rules", &self.output_value(&rules)?);
+                context.insert("fuzzy_rules_detailed", &self.output_value(&rules_detailed)?);
+                context.insert("fuzzy_symbols", &self.output_value(&symbols)?);
+                context.insert("references", &self.output_value(&places)?);
+                context.insert("skipped", &skipped);
+                context.insert("timings", &current_stage_timings());
+
+                self.render_tool_template(&config.templates.prompts.placer, &context)
+            },
+        )
+    }
+
+    #[tool(
+        description = "A tool that scans your project to identify code fragments and components that have already been implemented, allowing you to find and reuse existing solutions instead of rewriting them from scratch. This helps reduce duplication, improve development efficiency, and promote best practices in code maintenance and organization"
+    )]
+    pub async fn code_reuse_search(
+        &self,
+        #[tool(aggr)] request: CodeReuseSearchRequest,
+    ) -> Result<CallToolResult, Error> {
+        let config = self.live_config.get();
+        request
+            .validate()
+            .map_err(|e| Error::invalid_params(e, None))?;
+        let Some(lsp_server) = self.wait_lsp_server().await else {
+            return self.busy(ReadinessStatus::LspInitializing).await;
+        };
+
+        if !self.wait_index().await {
+            return self.busy(ReadinessStatus::IndexInitializing).await;
+        }
+
+        let queries = BTreeMap::from([
+            ("semantic_queries", request.semantic_queries.clone()),
+            ("name_patterns", request.name_patterns.clone()),
+        ]);
+        let grouped = request.group_by_file;
+
+        // TODO: for POC loaded every request because user can update rules without restarting the server
+        let ruleset = self.load_rules()?;
+        let CodeReuseSearchResult {
+            semantic_rules,
+            fuzzy_rules,
+            semantic_rules_detailed,
+            fuzzy_rules_detailed,
+            semantic_symbols,
+            fuzzy_symbols,
+            semantic_snippets,
+            semantic_has_more,
+            fuzzy_has_more,
+            fused_symbols,
+            files,
+            warnings,
+            page,
+            timeout,
+            snapshot_versions,
+            degraded,
+        } = search::code_reuse_search(
+            &lsp_server,
+            &config,
+            &self.pipeline,
+            &ruleset,
+            request,
+            self.config.tool_timeout_ms,
+        )
+        .await
+        .map_err(|e| report_error("Failed to search code", e))?;
+
+        self.tool_result(
+            McpToolName::CodeReuseSearch,
+            || {
+                Ok(vec![Content::json(self.output_value(
+                    &CodeReuseSearchResponse {
+                        schema_version: RESULT_SCHEMA_VERSION,
+                        semantic_rules: &semantic_rules,
+                        fuzzy_rules: &fuzzy_rules,
+                        semantic_rules_detailed: &semantic_rules_detailed,
+                        fuzzy_rules_detailed: &fuzzy_rules_detailed,
+                        semantic_symbols: semantic_symbols.as_deref().unwrap_or_default(),
+                        fuzzy_symbols: fuzzy_symbols.as_deref().unwrap_or_default(),
+                        semantic_snippets: semantic_snippets.as_deref().unwrap_or_default(),
+                        warnings: &warnings,
+                        page: &page,
+                        timeout: &timeout,
+                        fused_symbols: &fused_symbols,
+                        timings: ToolTimings {
+                            timings: current_stage_timings(),
+                            snapshot_versions: snapshot_versions.clone(),
+                        },
+                        files: grouped.unwrap_or(false).then_some(files.as_slice()),
+                    },
+                )?)?])
+            },
+            || {
+                let mut context = self.prompt_context(
+                    queries,
+                    BTreeMap::from([
+                        ("semantic_rules", semantic_rules.len()),
+                        ("fuzzy_rules", fuzzy_rules.len()),
+                        (
+                            "semantic_symbols",
+                            semantic_symbols.as_ref().map_or(0, Vec::len),
+                        ),
+                        ("fuzzy_symbols", fuzzy_symbols.as_ref().map_or(0, Vec::len)),
+                        (
+                            "semantic_snippets",
+                            semantic_snippets.as_ref().map_or(0, Vec::len),
+                        ),
+                        ("fused_symbols", fused_symbols.len()),
+                        ("files", files.len()),
+                    ]),
+                    BTreeMap::from([
+                        ("semantic_symbols", semantic_has_more),
+                        ("fuzzy_symbols", fuzzy_has_more),
+                    ]),
+                    degraded,
+                )?;
+                context.insert("semantic_rules", &self.output_value(&semantic_rules)?);
+                context.insert("fuzzy_rules", &self.output_value(&fuzzy_rules)?);
+                context.insert(
+                    "semantic_rules_detailed",
+                    &self.output_value(&semantic_rules_detailed)?,
+                );
+                context.insert(
+                    "fuzzy_rules_detailed",
+                    &self.output_value(&fuzzy_rules_detailed)?,
+                );
+                if let Some(semantic_symbols) = &semantic_symbols {
+                    context.insert("semantic_symbols", &self.output_value(semantic_symbols)?);
+                }
+                if let Some(fuzzy_symbols) = &fuzzy_symbols {
+                    context.insert("fuzzy_symbols", &self.output_value(fuzzy_symbols)?);
+                }
+                if let Some(semantic_snippets) = &semantic_snippets {
+                    context.insert("semantic_snippets", &self.output_value(semantic_snippets)?);
+                }
+                context.insert("fused_symbols", &self.output_value(&fused_symbols)?);
+                context.insert("files", &self.output_value(&files)?);
+                context.insert("warnings", &warnings);
+                context.insert("page", &page);
+                context.insert("timeout", &timeout);
+                context.insert("timings", &current_stage_timings());
+
+                self.render_tool_template(&config.templates.prompts.searcher, &context)
+            },
+        )
+    }
+}
+
+impl ServerHandler for McpService {
+    fn get_info(&self) -> ServerInfo {
+        let tools = Self::tool_box()
+            .list()
+            .into_iter()
+            .map(|tool| tool.name.to_string())
+            .filter(|name| !self.is_disabled_tool(name))
+            .sorted()
+            .collect::<Vec<_>>();
+        let mut context = description_context();
+        context.insert("tools", &tools);
+        ServerInfo {
+            protocol_version: ProtocolVersion::V_2024_11_05,
+            capabilities: ServerCapabilities::builder()
+                .enable_prompts()
+                .enable_tools()
+                .build(),
+            server_info: Implementation::from_build_env(),
+            instructions: Some(
+                self.live_config
+                    .tera()
+                    .render(
+                        &self.live_config.get().templates.description.server,
+                        &context,
+                    )
+                    .expect("Failed to render template"),
+            ),
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, Error> {
+        Ok(ListToolsResult {
+            next_cursor: None,
+            tools: Self::tool_box()
+                .list()
+                .into_iter()
+                .filter(|tool| !self.is_disabled_tool(&tool.name))
+                .map(|tool| self.with_query_descriptions(self.with_workspace_argument(tool)))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, Error> {
+        // the token is taken before the call can be rejected, so the rejected calls don't leave
+        // their tokens in the map
+        let progress_token = self.progress_tokens.take(&context.id);
+        if self.is_disabled_tool(&request.name) {
+            return Err(Error::invalid_request(
+                format!("Tool {} is disabled by configuration", request.name),
+                None,
+            ));
+        }
+        let tool = McpToolName::iter().find(|tool| tool.to_string() == request.name);
+        if self.config.read_only && tool.is_some_and(|it| it.is_mutating()) {
+            return Err(Error::invalid_request(
+                format!(
+                    "Tool {} is rejected in the read-only mode, the index is written by another \
+                    process",
+                    request.name
+                ),
+                None,
+            ));
+        }
+        let service = self.select_workspace(request.arguments.as_ref())?;
+        let workspace = &service.pipeline.workspace;
+        // the id of the JSON-RPC request, so the client can match the log lines to its call
+        let id = context.id.clone();
+        let request_id = id.to_string();
+        let span = info_span!(
+            "tool_call",
+            tool = %request.name,
+            request_id = %request_id,
+            workspace = %workspace.name
+        );
+
+        let tool_name = request.name.to_string();
+        let request_summary = request_summary(
+            request.arguments.as_ref(),
+            self.config.logging.audit.include_text,
+        );
+        let stage_timings = Arc::new(StageTimings::default());
+
+        // the long tools report their progress if the client asked for it by the progress token
+        let progress = Progress::new(context.peer.clone(), progress_token);
+
+        // the call is dropped when the client cancels it, so its LSP requests, vector searches
+        // and permits are freed instead of finishing a result nobody reads
+        let cancelled = context.ct.clone();
+        let call = STAGE_TIMINGS.scope(
+            stage_timings.clone(),
+            PROGRESS.scope(
+                progress,
+                service
+                    .run_memoized(tool, request, context)
+                    .instrument(span.clone()),
+            ),
+        );
+
+        let started = Instant::now();
+        let result = tokio::select! {
+            biased;
+            _ = cancelled.cancelled() => {
+                span.in_scope(|| info!("Tool call is cancelled by the client"));
+                Err(Error::internal_error("Tool call is cancelled by the client", None))
+            }
+            result = call => result,
+        };
+        if let Some(tool) = tool {
+            record_tool_call(tool, started, &result);
+        }
+        audit_tool_call(
+            &tool_name,
+            &request_id,
+            request_summary,
+            started,
+            &stage_timings,
+            &result,
+        );
+        let (result, cached) = result?;
+        self.response_metas
+            .remember(id, ToolCallMeta { request_id, cached });
+        Ok(result)
+    }
+
+    async fn list_prompts(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, Error> {
+        let config = self.live_config.get();
+        let prompts = McpPromptName::iter()
+            .map(|prompt| {
+                let arguments = prompt
+                    .arguments()
+                    .into_iter()
+                    .map(|argument| {
+                        Ok(PromptArgument {
+                            name: argument.to_string(),
+                            description: Some(
+                                self.render_description(argument.description(&config))?,
+                            ),
+                            required: Some(true),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok(Prompt::new(
+                    prompt.id(),
+                    Some(prompt.to_string()),
+                    Some(arguments),
+                ))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(ListPromptsResult {
+            next_cursor: None,
+            prompts,
+        })
+    }
+
+    async fn get_prompt(
+        &self,
+        GetPromptRequestParam { name, arguments }: GetPromptRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, Error> {
+        let prompt = McpPromptName::iter()
+            .find(|prompt| prompt.id() == name)
+            .ok_or_else(|| Error::invalid_params(format!("Unknown prompt: {}", name), None))?;
+        let arguments = arguments.unwrap_or_default();
+
+        let mut context = description_context();
+        for argument in prompt.arguments() {
+            let value = arguments.get(&argument.to_string()).ok_or_else(|| {
+                Error::invalid_params(
+                    format!("Missing argument {} of prompt {}", argument, name),
+                    None,
+                )
+            })?;
+            context.insert(argument.to_string(), value);
+        }
+
+        let content =
+            self.render_tool_template(prompt.template(&self.live_config.get()), &context)?;
+
+        Ok(GetPromptResult {
+            description: Some(prompt.to_string()),
+            messages: vec![PromptMessage::new_text(PromptMessageRole::User, content)],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rmcp::model::ErrorCode;
+
+    use super::*;
+
+    #[test]
+    fn invalid_request_is_reported_as_invalid_params() {
+        let error = report_error(
+            "Failed to search",
+            SemantrixError::invalid_request("offset is past the results").into(),
+        );
+
+        assert_eq!(error.code, ErrorCode::INVALID_PARAMS);
+        assert_eq!(error.message, "offset is past the results");
+        assert_eq!(error.data, None);
+    }
+
+    #[test]
+    fn failures_carry_their_category() {
+        let cases = [
+            (SemantrixError::lsp_timeout("no hover"), "lsp_timeout", true),
+            (SemantrixError::template("no template"), "template", false),
+        ];
+        for (failure, category, retryable) in cases {
+            let error = report_error("Failed to search", failure.into());
+
+            assert_eq!(error.code, ErrorCode::INTERNAL_ERROR);
+            assert_eq!(
+                error.data,
+                Some(serde_json::json!({ "category": category, "retryable": retryable }))
+            );
+        }
+
+        let error = report_error("Failed to search", miette::miette!("untyped"));
+        assert_eq!(error.data, None);
     }
 }
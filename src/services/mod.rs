@@ -1,60 +1,238 @@
+pub mod audit;
+pub mod comments;
+pub mod excludes;
+pub mod expansion;
+pub mod extractors;
+pub mod file_cache;
+pub mod git;
 pub mod mcp;
+pub mod outbound;
+pub mod progress;
+pub mod rerank;
+pub mod scratch;
+pub mod search;
+pub mod sse;
+pub mod templates;
+use std::ffi::OsStr;
 use std::hash::{Hash, Hasher};
-use std::path::Path;
+use std::path::{Component, MAIN_SEPARATOR_STR, Path, Prefix};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use std::{
-    collections::{HashMap, HashSet},
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     path::PathBuf,
     sync::Arc,
 };
 
-use futures::{Stream, StreamExt, TryStreamExt, future::Either, stream};
+use chrono::{DateTime, Utc};
+use convert_case::{Case, Casing};
+use futures::{
+    Stream, StreamExt,
+    future::{self, Either},
+    stream,
+    stream::BoxStream,
+};
 use itertools::Itertools;
+use lancedb::Table;
 use lsp_types::{
     DocumentSymbolResponse, Hover, HoverContents, Location, MarkedString, OneOf, Position, Range,
     SymbolKind, WorkspaceSymbolResponse,
 };
-use miette::{IntoDiagnostic, Result, miette};
+use miette::{IntoDiagnostic, Result};
 use regex::{Regex, RegexSet};
-use rig::vector_store::VectorStoreIndexDyn;
-use rig_fastembed::EmbeddingModel;
-use rig_lancedb::LanceDbVectorIndex;
-use rmcp::Error;
+use rig::embeddings::EmbeddingModel as _;
+use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use strum::IntoEnumIterator;
 use tera::Tera;
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 use url::Url;
-use wax::{Glob, Pattern};
+use wax::{BuildError, FileIterator, FilterTarget, Glob, Pattern, WalkEntry, WalkNegation};
 
 use crate::{
-    CONFIG,
+    ExtraRootConfig, McpConfig, OverviewConfig, SemanticConfig, Workspace,
+    enums::{
+        HoverPosition, MatchOrigin, McpSymbolKind, PathCaseMode, PlaceConfidence, PositionEncoding,
+        SearchMode, SymbolDetail,
+    },
+    errors::SemantrixError,
+    repositories::{
+        StoredChunk,
+        backend::{RoutedBackend, VectorBackend},
+        chunk_by_id, chunks_by_index,
+        embedding::IndexEmbeddingModel,
+        file_line_counts, nearest_chunks, read_chunks,
+    },
+    services::{
+        audit::timed_stage,
+        comments::match_origin,
+        excludes::exclusion_globs,
+        extractors::{NotebookCell, has_extracted_lines, notebook_cell, source_file},
+        file_cache::FileCache,
+        git::{GitMetadata, LineRange, git_metadata},
+        rerank::rerank_chunks,
+        scratch::is_scratch_path,
+    },
     subsystems::{
-        chunker::{ChunkId, DocumentPointer},
+        chunker::{ChunkId, DocumentPointer, TextChunk, lines_hash},
         lsp::GuardedLspServer,
     },
 };
 
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+/// Schema of the LSP positions of the results, the lines and the characters are zero based
+#[derive(JsonSchema)]
+#[schemars(rename = "Position")]
+#[allow(dead_code)]
+struct PositionSchema {
+    line: u32,
+    character: u32,
+}
+
+/// Schema of the LSP ranges of the results, the end is exclusive
+#[derive(JsonSchema)]
+#[schemars(rename = "Range")]
+#[allow(dead_code)]
+struct RangeSchema {
+    start: PositionSchema,
+    end: PositionSchema,
+}
+
+/// Schema of the LSP locations of the results
+#[derive(JsonSchema)]
+#[schemars(rename = "Location")]
+#[allow(dead_code)]
+struct LocationSchema {
+    uri: String,
+    range: RangeSchema,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct SymbolInfo {
     pub name: String,
-    pub kind: String,
+    pub kind: McpSymbolKind,
+    #[schemars(with = "LocationSchema")]
     pub location: Location,
     pub container_name: Option<String>,
     pub code: Option<String>,
     pub hover: Option<String>,
+    #[schemars(with = "Option<PositionSchema>")]
     pub name_position: Option<Position>,
+    /// Workspace symbol query (or its derived variant) the symbol was found by
+    #[serde(default)]
+    pub matched_query: Option<String>,
+    /// Position strategy which produced the hover
+    #[serde(default)]
+    pub hover_position: Option<HoverPosition>,
+    /// Match quality of the fuzzy symbol in thousandths, higher is better
+    #[serde(default)]
+    pub score: Option<u32>,
+    /// Stable identity of the symbol computed from its workspace relative path, kind,
+    /// container and name, so it doesn't change when the file is shifted by some lines
+    #[serde(default)]
+    pub id: String,
+    /// Full range of the symbol if it is known
+    #[serde(default)]
+    #[schemars(with = "Option<RangeSchema>")]
+    pub range: Option<Range>,
+    /// Range of the symbol name if it is known
+    #[serde(default)]
+    #[schemars(with = "Option<RangeSchema>")]
+    pub selection_range: Option<Range>,
+    /// True if the name is not found in the code, the hover and the references are requested
+    /// at the start of the symbol then and may be of another symbol
+    #[serde(default)]
+    pub name_position_unresolved: bool,
+    /// True if the hover is not requested because the hover budget of the call is spent
+    #[serde(default)]
+    pub hover_skipped: bool,
+    /// Weight of the kind the score of the symbol is multiplied by, see
+    /// [`SearchConfig::kind_weights`](crate::SearchConfig::kind_weights)
+    #[serde(default)]
+    pub kind_weight: Option<f64>,
+    /// Last commit of the lines of the symbol, see `search.git_metadata`
+    #[serde(default)]
+    pub git: Option<GitMetadata>,
+    /// Detail the code and the hover are cut down to by the response budget, None if they are
+    /// complete
+    #[serde(default)]
+    pub degraded: Option<SymbolDetail>,
 }
 
+/// The kind weights are finite numbers of the config, so the symbols are compared as equal to
+/// themselves for ordering the documents of a file
+impl Eq for SymbolInfo {}
+
 impl SymbolInfo {
-    pub fn path(&self) -> Result<PathBuf> {
-        self.location
-            .uri
-            .to_file_path()
-            .map_err(|_| miette::miette!("Failed to convert URL {} to path", self.location.uri))
+    /// Sets the stable identity of the symbol, see [`SymbolInfo::id`], the paths of the
+    /// workspace are identified relative to it
+    pub fn with_id(mut self, workspace_path: Option<&Path>) -> Self {
+        let location = match (self.path(), workspace_path) {
+            (Some(path), Some(workspace_path)) => path
+                .strip_prefix(workspace_path)
+                .map(storage_path)
+                .unwrap_or_else(|_| self.location.uri.to_string()),
+            _ => self.location.uri.to_string(),
+        };
+        // the kind is hashed by its name, so the ids are the same as of the untyped kinds, and
+        // by sha256 like the chunk ids, so the ids don't depend on the toolchain
+        let mut hasher = Sha256::new();
+        hasher.update(location.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.kind.to_string().as_bytes());
+        hasher.update([0]);
+        if let Some(container_name) = &self.container_name {
+            hasher.update(container_name.as_bytes());
+        }
+        hasher.update([0]);
+        hasher.update(self.name.as_bytes());
+        let digest = format!("{:x}", hasher.finalize());
+        self.id = digest[..16].to_string();
+        self
+    }
+
+    /// Local path of the symbol, None for non-file URIs like `untitled:`, `jar:` or `jdt://`
+    pub fn path(&self) -> Option<PathBuf> {
+        url_to_path(&self.location.uri)
+    }
+
+    /// First not blank line of the code
+    fn signature(&self) -> Option<&str> {
+        self.code
+            .as_deref()?
+            .lines()
+            .map(str::trim_end)
+            .find(|line| !line.trim().is_empty())
+    }
+
+    /// Chars of the code and the hover the symbol takes from the response budget with the detail,
+    /// the complete ones if the detail is None
+    fn budget_chars(&self, detail: Option<SymbolDetail>) -> usize {
+        match detail {
+            None => [&self.code, &self.hover]
+                .into_iter()
+                .flatten()
+                .map(|it| it.chars().count())
+                .sum(),
+            Some(SymbolDetail::Signature) => self.signature().map_or(0, |it| it.chars().count()),
+            Some(SymbolDetail::Name) => 0,
+        }
+    }
+
+    /// Cuts the code and the hover down to the detail, see [`DegradedSymbols`]
+    pub fn degrade(&mut self, detail: SymbolDetail) {
+        self.code = match detail {
+            SymbolDetail::Signature => self.signature().map(str::to_string),
+            SymbolDetail::Name => None,
+        };
+        self.hover = None;
+        self.degraded = Some(detail);
     }
 
-    pub fn set_hover(&mut self, hover: Hover) {
+    pub fn set_hover(&mut self, hover: Hover, hover_position: HoverPosition) {
+        self.hover_position = Some(hover_position);
         self.hover = Some(match &hover.contents {
             HoverContents::Scalar(s) => match s {
                 MarkedString::String(s) => s.to_owned(),
@@ -72,16 +250,93 @@ impl SymbolInfo {
     }
 }
 
+/// Symbol kinds selected by exact kind names or by regexes of the names, the regexes are resolved
+/// to the known [`McpSymbolKind`]s when the filter is parsed. The empty filter selects all kinds
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct SymbolKindFilter {
+    patterns: Vec<String>,
+    kinds: BTreeSet<McpSymbolKind>,
+}
+
+impl SymbolKindFilter {
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn is_match(&self, kind: McpSymbolKind) -> bool {
+        self.patterns.is_empty() || self.kinds.contains(&kind)
+    }
+
+    /// Kinds sent to the LSP server to narrow the symbols on its side, None for all the kinds.
+    /// The filter without the resolved kinds is applied on the client side only, as it is for
+    /// the servers ignoring the kinds
+    pub fn lsp_kinds(&self) -> Option<Vec<SymbolKind>> {
+        (!self.patterns.is_empty() && !self.kinds.is_empty())
+            .then(|| self.kinds.iter().map(|it| SymbolKind::from(*it)).collect())
+    }
+}
+
+impl TryFrom<Vec<String>> for SymbolKindFilter {
+    type Error = miette::Report;
+
+    fn try_from(patterns: Vec<String>) -> Result<Self> {
+        let mut kinds = BTreeSet::new();
+        for pattern in patterns.iter() {
+            if let Ok(kind) = McpSymbolKind::from_str(pattern) {
+                kinds.insert(kind);
+                continue;
+            }
+            let regex = Regex::new(pattern).map_err(|e| {
+                SemantrixError::config(format!(
+                    "Symbol kind pattern `{}` is not a valid regex: {}",
+                    pattern, e
+                ))
+            })?;
+            let matched = McpSymbolKind::iter()
+                .filter(|kind| regex.is_match(&kind.to_string()))
+                .collect_vec();
+            if matched.is_empty() {
+                return Err(SemantrixError::config(format!(
+                    "Symbol kind pattern `{}` matches no kind",
+                    pattern
+                ))
+                .with_help(format!("Known kinds: {}", McpSymbolKind::iter().join(", ")))
+                .into());
+            }
+            kinds.extend(matched);
+        }
+        Ok(Self { patterns, kinds })
+    }
+}
+
+impl<'de> Deserialize<'de> for SymbolKindFilter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let patterns: Vec<String> = Vec::deserialize(deserializer)?;
+        Self::try_from(patterns).map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Ruleset {
     pub common: Vec<String>,
     pub depends_on: Vec<SymbolRuleset>,
+    /// Rules file the ruleset is loaded from, its failures name it
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// How the path patterns of the rules are matched, the one of the workspace of the call
+    #[serde(skip)]
+    pub path_case: PathCaseMode,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct SymbolRuleset {
-    #[serde(deserialize_with = "deserialize_regexset")]
-    pub kind: RegexSet,
+    /// Title of the ruleset reported with its rules, `name` holds the name patterns
+    #[serde(default)]
+    pub title: Option<String>,
+    pub kind: SymbolKindFilter,
     #[serde(deserialize_with = "deserialize_regexset")]
     pub name: RegexSet,
     pub path: Vec<String>,
@@ -120,65 +375,188 @@ where
     RegexSet::new(&patterns).map_err(serde::de::Error::custom)
 }
 
+/// Result of every predicate of a ruleset checked against one symbol
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RulesetMatch {
+    pub kind: bool,
+    pub name: bool,
+    pub path: bool,
+    pub code: bool,
+}
+
+impl RulesetMatch {
+    pub fn matched(&self) -> bool {
+        self.kind && self.name && self.path && self.code
+    }
+
+    /// Names of the predicates which are not matched
+    pub fn failed(&self) -> Vec<&'static str> {
+        [
+            ("kind", self.kind),
+            ("name", self.name),
+            ("path", self.path),
+            ("code", self.code),
+        ]
+        .into_iter()
+        .filter(|(_, matched)| !matched)
+        .map(|(predicate, _)| predicate)
+        .collect()
+    }
+}
+
 impl SymbolRuleset {
-    pub fn matches(&self, symbol_info: &SymbolInfo) -> Result<bool> {
-        let path = symbol_info.path()?;
+    /// Predicates of the ruleset of the rules file checked against the symbol
+    pub fn check(
+        &self,
+        symbol_info: &SymbolInfo,
+        rules: &Path,
+        path_case: PathCaseMode,
+    ) -> Result<RulesetMatch> {
+        let path = symbol_info.path();
         let path_patterns = self
             .path
             .iter()
-            .map(|pattern| Glob::new(pattern))
+            .map(|pattern| path_case.glob(pattern))
             .collect::<Result<Vec<_>, _>>()
-            .into_diagnostic()?;
+            .map_err(|e| {
+                SemantrixError::ruleset(rules, format!("Invalid path pattern of a rule: {}", e))
+            })?;
 
-        trace!("Kind: {:?}", self.kind.is_match(&symbol_info.kind));
-        trace!("Name: {:?}", self.name.is_match(&symbol_info.name));
-        trace!(
-            "Path: {:?}",
-            path_patterns
-                .iter()
-                .any(|pattern| pattern.is_match(path.as_path()))
-        );
-        trace!(
-            "Code: {:?}",
-            symbol_info
-                .code
+        let ruleset_match = RulesetMatch {
+            kind: self.kind.is_match(symbol_info.kind),
+            name: self.name.is_match(&symbol_info.name),
+            path: path
                 .as_ref()
-                .map(|code| self.code.is_match(code))
-                .unwrap_or(false)
-        );
-
-        Ok(self.kind.is_match(&symbol_info.kind)
-            && self.name.is_match(&symbol_info.name)
-            && path_patterns
-                .iter()
-                .any(|pattern| pattern.is_match(path.as_path()))
-            && symbol_info
+                .map(|path| {
+                    path_patterns
+                        .iter()
+                        .any(|pattern| pattern.is_match(path.as_path()))
+                })
+                .unwrap_or(false),
+            code: symbol_info
                 .code
                 .as_ref()
                 .map(|code| self.code.is_match(code))
-                .unwrap_or(false))
+                .unwrap_or(false),
+        };
+
+        trace!("Kind: {:?}", ruleset_match.kind);
+        trace!("Name: {:?}", ruleset_match.name);
+        trace!("Path: {:?}", ruleset_match.path);
+        trace!("Code: {:?}", ruleset_match.code);
+
+        Ok(ruleset_match)
     }
+
+    pub fn matches(
+        &self,
+        symbol_info: &SymbolInfo,
+        rules: &Path,
+        path_case: PathCaseMode,
+    ) -> Result<bool> {
+        Ok(self.check(symbol_info, rules, path_case)?.matched())
+    }
+}
+
+/// Rendered rule with the ruleset which produced it, a common rule has no ruleset and no
+/// symbols
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RuleEntry {
+    pub rule: String,
+    /// Index of the ruleset in `depends_on`
+    pub ruleset: Option<usize>,
+    /// Title of the ruleset
+    pub title: Option<String>,
+    /// Ids of the symbols which matched the ruleset
+    pub symbols: Vec<String>,
+}
+
+/// Texts of the rules in their order
+pub fn rule_texts(rules: &[RuleEntry]) -> Vec<String> {
+    rules.iter().map(|it| it.rule.clone()).collect()
+}
+
+/// Diagnostics of one ruleset checked against one symbol
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RulesetDiagnostics {
+    /// Index of the ruleset in `depends_on`
+    pub ruleset: usize,
+    pub title: Option<String>,
+    pub kind_patterns: Vec<String>,
+    pub name_patterns: Vec<String>,
+    pub path_patterns: Vec<String>,
+    pub code_patterns: Vec<String>,
+    pub symbol: SymbolInfo,
+    pub matched: bool,
+    pub failed: Vec<String>,
 }
 
 impl Ruleset {
-    pub fn get_rules(&self, symbols: Vec<SymbolInfo>) -> Result<Vec<String>> {
-        #[allow(clippy::mutable_key_type)]
-        let mut matched: HashMap<&SymbolRuleset, Vec<&SymbolInfo>> = HashMap::new();
-        let mut matches = self.common.clone();
+    /// Checks every ruleset against every symbol without rendering the rules
+    pub fn preview(&self, symbols: &[SymbolInfo]) -> Result<Vec<RulesetDiagnostics>> {
+        let mut diagnostics = Vec::new();
+        for (index, rule) in self.depends_on.iter().enumerate() {
+            for symbol in symbols.iter() {
+                let ruleset_match = rule.check(symbol, &self.path, self.path_case)?;
+                diagnostics.push(RulesetDiagnostics {
+                    ruleset: index,
+                    title: rule.title.clone(),
+                    kind_patterns: rule.kind.patterns().to_vec(),
+                    name_patterns: rule.name.patterns().to_vec(),
+                    path_patterns: rule.path.clone(),
+                    code_patterns: rule.code.patterns().to_vec(),
+                    symbol: symbol.clone(),
+                    matched: ruleset_match.matched(),
+                    failed: ruleset_match
+                        .failed()
+                        .into_iter()
+                        .map(str::to_string)
+                        .collect(),
+                });
+            }
+        }
+        Ok(diagnostics)
+    }
 
-        for rule in self.depends_on.iter() {
+    /// Common rules followed by the rendered rules of the rulesets matching the symbols, every
+    /// rule keeps its ruleset and the symbols which matched it
+    pub fn get_rules(&self, symbols: Vec<SymbolInfo>) -> Result<Vec<RuleEntry>> {
+        // matched rulesets in the order of the rulesets, an equal ruleset is matched once
+        let mut matched: Vec<(usize, &SymbolRuleset, Vec<&SymbolInfo>)> = Vec::new();
+        let mut matches = self
+            .common
+            .iter()
+            .map(|rule| RuleEntry {
+                rule: rule.clone(),
+                ruleset: None,
+                title: None,
+                symbols: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+
+        for (index, rule) in self.depends_on.iter().enumerate() {
             trace!("Checking rule: {:?}", rule);
             for symbol in symbols.iter() {
-                if rule.matches(symbol)? {
+                if rule.matches(symbol, &self.path, self.path_case)? {
                     debug!("Matched rule for symbol: {:?}", symbol);
-                    matched.entry(rule).or_default().push(symbol);
+                    let position = match matched.iter().position(|(_, it, _)| *it == rule) {
+                        Some(position) => position,
+                        None => {
+                            matched.push((index, rule, Vec::new()));
+                            matched.len() - 1
+                        }
+                    };
+                    let rule_symbols = &mut matched[position].2;
+                    if !rule_symbols.iter().any(|it| it.id == symbol.id) {
+                        rule_symbols.push(symbol);
+                    }
                 } else {
                     trace!("Not matched rule for symbol: {:?}", symbol);
                 }
             }
         }
 
-        for (rule, symbols) in matched.into_iter() {
+        for (index, rule, symbols) in matched {
             let mut context = tera::Context::new();
             context.insert("symbols", &symbols);
 
@@ -187,9 +565,17 @@ impl Ruleset {
                 .iter()
                 .map(|rule| Tera::one_off(rule, &context, true))
                 .collect::<Result<Vec<_>, _>>()
-                .into_diagnostic()?;
+                .map_err(|e| {
+                    SemantrixError::ruleset(&self.path, format!("Failed to render a rule: {}", e))
+                })?;
 
-            matches.extend(semantic_queries_desc);
+            let symbol_ids = symbols.iter().map(|it| it.id.clone()).collect::<Vec<_>>();
+            matches.extend(semantic_queries_desc.into_iter().map(|text| RuleEntry {
+                rule: text,
+                ruleset: Some(index),
+                title: rule.title.clone(),
+                symbols: symbol_ids.clone(),
+            }));
         }
         Ok(matches)
     }
@@ -201,32 +587,260 @@ pub struct DocumentSymbols {
     pub symbols: DocumentSymbolResponse,
 }
 
-async fn get_fuzzy_symbols(
+/// Ranked fuzzy symbols from `offset` limited by `fuzzy_limit` and by `max_per_file` symbols of
+/// every file, the flag is set if more symbols exist after the page
+pub(crate) async fn get_fuzzy_symbols(
     lsp_server: &GuardedLspServer,
+    config: &McpConfig,
     possible_names: Vec<String>,
-    kinds: Vec<Regex>,
+    kinds: SymbolKindFilter,
     need_code_samples: bool,
-) -> Result<Vec<SymbolInfo>> {
+    read_code: bool,
+    SymbolsPage {
+        offset,
+        max_per_file,
+    }: SymbolsPage,
+) -> Result<(Vec<SymbolInfo>, bool)> {
     info!("Getting fuzzy symbols for: {:?}", possible_names);
 
-    let symbols = get_workspace_symbols(lsp_server, possible_names)
+    let mut symbols =
+        get_workspace_symbols_info(lsp_server, possible_names.clone(), kinds.clone()).await;
+
+    let matched_queries = symbols
+        .iter()
+        .filter_map(|it| it.matched_query.clone())
+        .collect::<HashSet<_>>();
+
+    let variants = possible_names
+        .iter()
+        .filter(|name| !matched_queries.contains(*name))
+        .flat_map(|name| {
+            query_variants(
+                name,
+                lsp_server.config().variant_min_word_len,
+                lsp_server.config().max_query_variants,
+            )
+        })
+        .unique()
+        .collect::<Vec<_>>();
+
+    if !variants.is_empty() {
+        info!("Retrying fuzzy search with query variants: {:?}", variants);
+        symbols.extend(get_workspace_symbols_info(lsp_server, variants, kinds).await);
+    }
+
+    let mut symbols = stream::iter(symbols)
+        .filter({
+            let mut seen = HashSet::new();
+            move |symbol| {
+                let is_new = seen.insert(symbol.id.clone());
+                async move { is_new }
+            }
+        })
+        .map(|mut symbol| {
+            let weight = config.search.kind_weight(symbol.kind);
+            symbol.score = Some((symbol_score(&symbol) as f64 * weight).round() as u32);
+            symbol.kind_weight = Some(weight);
+            symbol
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    let found_by = symbols
+        .iter()
+        .into_group_map_by(|it| it.matched_query.as_deref());
+    for (query, found) in found_by {
+        debug!(
+            "Fuzzy query {:?} found symbols: {:?}",
+            query,
+            found.iter().map(|it| &it.name).collect_vec()
+        );
+    }
+
+    sort_by_score(&mut symbols);
+    let symbols = limit_per_file(symbols, max_per_file, SymbolInfo::path);
+    let has_more = symbols.len() > offset + config.search.fuzzy.fuzzy_limit;
+    let symbols = symbols
+        .into_iter()
+        .skip(offset)
+        .take(config.search.fuzzy.fuzzy_limit)
+        .collect::<Vec<_>>();
+
+    let mut symbols = if read_code {
+        timed_stage(
+            "code_enrichment",
+            update_code_and_name_position_from_document(symbols, lsp_server),
+        )
+        .await
+    } else {
+        symbols
+    };
+    sort_by_score(&mut symbols);
+
+    let symbols = if need_code_samples {
+        timed_stage(
+            "hover_enrichment",
+            enrich_hovers(lsp_server, config, symbols),
+        )
+        .await
+    } else {
+        symbols
+    };
+
+    Ok((symbols, has_more))
+}
+
+/// Symbols with their hovers requested by `search.fuzzy.parallelizm` at a time in the order of
+/// the symbols, so the best ranked ones get theirs first. The symbols after `max_hovers` and the
+/// ones without the hover by `hover_budget_ms` are returned without it and flagged
+pub(crate) async fn enrich_hovers(
+    lsp_server: &GuardedLspServer,
+    config: &McpConfig,
+    symbols: Vec<SymbolInfo>,
+) -> Vec<SymbolInfo> {
+    let max_hovers = config.search.fuzzy.max_hovers.unwrap_or(usize::MAX);
+    let deadline = config
+        .search
+        .fuzzy
+        .hover_budget_ms
+        .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms));
+
+    let mut symbols = stream::iter(symbols.into_iter().enumerate())
+        .map(|(index, mut symbol)| async move {
+            let hover = if index >= max_hovers {
+                None
+            } else if let Some(deadline) = deadline {
+                tokio::time::timeout_at(deadline, get_hover(lsp_server, &symbol))
+                    .await
+                    .ok()
+            } else {
+                Some(get_hover(lsp_server, &symbol).await)
+            };
+            match hover {
+                Some(Some((hover, hover_position))) => symbol.set_hover(hover, hover_position),
+                Some(None) => {}
+                None => symbol.hover_skipped = true,
+            }
+            (index, symbol)
+        })
+        .buffer_unordered(lsp_server.config().parallelizm.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    symbols.sort_by_key(|(index, _)| *index);
+
+    let skipped = symbols.iter().filter(|(_, it)| it.hover_skipped).count();
+    if skipped > 0 {
+        info!(
+            "Hover budget is spent, {} of {} symbols are returned without hover",
+            skipped,
+            symbols.len()
+        );
+    }
+    symbols.into_iter().map(|(_, symbol)| symbol).collect()
+}
+
+/// Match quality of the symbol name and the query it was found by, in thousandths.
+/// Normalized edit distance with bonuses for exact, prefix and substring matches and
+/// a small boost for shorter paths
+fn symbol_score(symbol: &SymbolInfo) -> u32 {
+    let query = symbol
+        .matched_query
+        .as_deref()
+        .unwrap_or_default()
+        .to_lowercase();
+    let name = symbol.name.to_lowercase();
+
+    let name_score = if query.is_empty() {
+        0.0
+    } else {
+        let max_len = query.chars().count().max(name.chars().count());
+        let similarity = 1.0 - edit_distance(&query, &name) as f64 / max_len as f64;
+        let bonus = if name == query {
+            1.0
+        } else if name.starts_with(&query) {
+            0.5
+        } else if name.contains(&query) {
+            0.25
+        } else {
+            0.0
+        };
+        similarity + bonus
+    };
+
+    let path_boost = symbol
+        .path()
+        .map(|path| 0.1 / path.components().count().max(1) as f64)
+        .unwrap_or(0.0);
+
+    ((name_score + path_boost) * 1000.0).round() as u32
+}
+
+/// Levenshtein distance by chars
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Sorts by score descending, ties are broken by uri then name so the output is stable
+fn sort_by_score(symbols: &mut [SymbolInfo]) {
+    symbols.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| a.location.uri.as_str().cmp(b.location.uri.as_str()))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+/// Symbols of the workspace matched by the names, every symbol remembers the query it was found by
+async fn get_workspace_symbols_info(
+    lsp_server: &GuardedLspServer,
+    names: Vec<String>,
+    kinds: SymbolKindFilter,
+) -> Vec<SymbolInfo> {
+    let workspace_path = workspace_root(lsp_server.workspace()).ok();
+    let workspace_path = workspace_path.as_deref();
+    get_workspace_symbols(lsp_server, names, &kinds)
         .await
-        .flat_map(|response| match response {
+        .flat_map(|(query, response)| match response {
             WorkspaceSymbolResponse::Flat(s) => {
                 let kinds = kinds.clone();
                 let stream = stream::iter(s)
-                    .filter(move |symbol| {
-                        let kinds = kinds.clone();
-                        filter_symbols_kind(symbol.kind, kinds)
+                    .filter_map(move |symbol| {
+                        future::ready(
+                            filter_symbols_kind(symbol.kind, &kinds).map(|kind| (kind, symbol)),
+                        )
                     })
-                    .map(|symbol| SymbolInfo {
-                        name: symbol.name,
-                        kind: format!("{:?}", symbol.kind),
-                        location: symbol.location,
-                        container_name: symbol.container_name,
-                        code: None,
-                        hover: None,
-                        name_position: None,
+                    .map(move |(kind, symbol)| {
+                        SymbolInfo {
+                            name: symbol.name,
+                            kind,
+                            range: Some(symbol.location.range),
+                            location: symbol.location,
+                            container_name: symbol.container_name,
+                            code: None,
+                            hover: None,
+                            name_position: None,
+                            matched_query: Some(query.clone()),
+                            hover_position: None,
+                            score: None,
+                            id: String::new(),
+                            selection_range: None,
+                            name_position_unresolved: false,
+                            hover_skipped: false,
+                            kind_weight: None,
+                            git: None,
+                            degraded: None,
+                        }
+                        .with_id(workspace_path)
                     });
 
                 Either::Left(stream)
@@ -234,534 +848,3377 @@ async fn get_fuzzy_symbols(
             WorkspaceSymbolResponse::Nested(s) => {
                 let kinds = kinds.clone();
                 let stream = stream::iter(s)
-                    .filter(move |symbol| {
-                        let kinds = kinds.clone();
-                        filter_symbols_kind(symbol.kind, kinds)
+                    .filter_map(move |symbol| {
+                        future::ready(
+                            filter_symbols_kind(symbol.kind, &kinds).map(|kind| (kind, symbol)),
+                        )
                     })
-                    .map(|symbol| {
-                        let location = match symbol.location {
-                            OneOf::Left(location) => location,
-                            OneOf::Right(location) => Location::new(
-                                location.uri,
-                                Range::new(Position::new(0, 0), Position::new(0, 0)),
+                    .map(move |(kind, symbol)| {
+                        let (location, range) = match symbol.location {
+                            OneOf::Left(location) => {
+                                let range = location.range;
+                                (location, Some(range))
+                            }
+                            OneOf::Right(location) => (
+                                Location::new(
+                                    location.uri,
+                                    Range::new(Position::new(0, 0), Position::new(0, 0)),
+                                ),
+                                None,
                             ),
                         };
 
                         SymbolInfo {
                             name: symbol.name,
-                            kind: format!("{:?}", symbol.kind),
+                            kind,
                             location,
                             container_name: symbol.container_name,
                             code: None,
                             hover: None,
                             name_position: None,
+                            matched_query: Some(query.clone()),
+                            hover_position: None,
+                            score: None,
+                            id: String::new(),
+                            range,
+                            selection_range: None,
+                            name_position_unresolved: false,
+                            hover_skipped: false,
+                            kind_weight: None,
+                            git: None,
+                            degraded: None,
                         }
+                        .with_id(workspace_path)
                     });
                 Either::Right(stream)
             }
         })
-        .then(|mut it| async {
-            if need_code_samples {
-                let hover = get_hover(lsp_server, &it).await;
-                if let Some(hover) = hover {
-                    it.set_hover(hover);
-                }
-            }
-            it
-        })
         .collect::<Vec<_>>()
-        .await;
+        .await
+}
 
-    let symbols = update_code_and_name_position_from_document(symbols).await;
+/// Derives identifier-ish variants of a free text query like "http client retry":
+/// snake_case and CamelCase joins of the words, the query without spaces and the words
+/// longer than `min_word_len`. Variants are deduped, bounded by `limit` and never equal to the query
+pub fn query_variants(query: &str, min_word_len: usize, limit: usize) -> Vec<String> {
+    let words = query
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let joined = words.join(" ");
 
-    Ok(symbols)
+    [
+        joined.to_case(Case::Snake),
+        joined.to_case(Case::Pascal),
+        query.chars().filter(|c| !c.is_whitespace()).collect(),
+    ]
+    .into_iter()
+    .chain(
+        words
+            .iter()
+            .filter(|word| word.chars().count() > min_word_len)
+            .map(|word| word.to_string()),
+    )
+    .filter(|variant| !variant.is_empty() && variant != query)
+    .unique()
+    .take(limit)
+    .collect()
 }
 
-async fn filter_symbols_kind(symbol: SymbolKind, kinds: Vec<Regex>) -> bool {
-    kinds
-        .iter()
-        .any(|kind| kind.is_match(&format!("{:?}", symbol)))
+/// Typed kind of the symbol if it is known and selected by the filter
+fn filter_symbols_kind(symbol: SymbolKind, kinds: &SymbolKindFilter) -> Option<McpSymbolKind> {
+    McpSymbolKind::try_from(symbol)
+        .ok()
+        .filter(|kind| kinds.is_match(*kind))
 }
 
-async fn get_semantic_symbols(
-    lsp_server: &GuardedLspServer,
-    short_descriptions: Vec<String>,
-    vector_store: Arc<LanceDbVectorIndex<EmbeddingModel>>,
-) -> Result<Vec<SymbolInfo>> {
-    info!("Getting semantic symbols for: {:?}", short_descriptions);
-    let chunks = stream::iter(short_descriptions)
-        .map(move |short_description| {
-            let short_description = short_description.clone();
-            let vector_store = vector_store.clone();
-            async move {
-                vector_store
-                    .top_n(&short_description, CONFIG.search.semantic.search_limit)
-                    .await
-                    .map_err(|e| {
-                        Error::internal_error(
-                            format!("Failed to get semantic symbols: {}", e),
-                            None,
-                        )
-                    })
-            }
-        })
-        .filter_map(|it| async {
-            it.await
-                .inspect_err(|err| {
-                    error!("Error getting symbols: {}", err);
-                })
-                .inspect(|it| {
-                    info!("Semantic search result: {:?}", it);
-                })
-                .ok()
-        })
-        .flat_map(|it| {
-            stream::iter(it).map(|(_, _, value)| {
-                serde_json::from_value::<ChunkId>(value).inspect_err(|e| {
-                    error!("Error parsing chunk id: {}", e);
-                })
-            })
-        })
-        .inspect_err(|err| {
-            error!("Semantic search error: {}", err);
-        })
-        .filter_map(|it| async { it.ok() })
-        .collect::<Vec<_>>()
-        .await;
+/// Rank of a symbol missing in the results of a pipeline
+const ABSENT_RANK: usize = 1000;
 
-    trace!("Chunks: {:?}", chunks);
+/// Chunks fetched for every chunk of the page if the results per file are limited, the next
+/// chunks take the places of the dropped chunks of the hot files
+const PER_FILE_FETCH_FACTOR: usize = 3;
 
-    let paths = chunks
-        .iter()
-        .map(|it| it.path.as_path())
-        .map(Url::from_file_path)
-        .filter_map(|it| it.ok())
-        .collect::<HashSet<_>>();
+/// Symbol of the fuzzy or semantic results ranked by both of them
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FusedSymbol {
+    #[serde(flatten)]
+    pub symbol: SymbolInfo,
+    /// Reciprocal rank fusion score, higher is better
+    pub fused_score: f64,
+    /// One based rank in the fuzzy results, None if the fuzzy search didn't find the symbol
+    pub fuzzy_rank: Option<usize>,
+    /// One based rank in the semantic results, None if the semantic search didn't find it
+    pub semantic_rank: Option<usize>,
+}
 
-    info!("Paths: {:?}", paths);
+/// Unique symbols of both results ordered by the sum of `1 / (k + rank)` of the results
+/// multiplied by the weight of the kind, a symbol missing in the results has [`ABSENT_RANK`]
+/// there, so the symbols found by both searches come first
+pub fn fuse_symbols(
+    fuzzy: &[SymbolInfo],
+    semantic: &[SymbolInfo],
+    k: usize,
+    kind_weights: &HashMap<McpSymbolKind, f64>,
+) -> Vec<FusedSymbol> {
+    let mut fused: Vec<FusedSymbol> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (is_fuzzy, symbols) in [(true, fuzzy), (false, semantic)] {
+        for (index, symbol) in symbols.iter().enumerate() {
+            let position = *positions.entry(symbol.id.clone()).or_insert_with(|| {
+                fused.push(FusedSymbol {
+                    symbol: symbol.clone(),
+                    fused_score: 0.0,
+                    fuzzy_rank: None,
+                    semantic_rank: None,
+                });
+                fused.len() - 1
+            });
+            let rank = if is_fuzzy {
+                &mut fused[position].fuzzy_rank
+            } else {
+                &mut fused[position].semantic_rank
+            };
+            // the first occurrence is the best rank of the symbol
+            rank.get_or_insert(index + 1);
+        }
+    }
+    for it in fused.iter_mut() {
+        let weight = kind_weights.get(&it.symbol.kind).copied().unwrap_or(1.0);
+        it.fused_score = weight
+            * [it.fuzzy_rank, it.semantic_rank]
+                .into_iter()
+                .map(|rank| 1.0 / (k + rank.unwrap_or(ABSENT_RANK)) as f64)
+                .sum::<f64>();
+        it.symbol.kind_weight = Some(weight);
+    }
+    fused.sort_by(|a, b| {
+        b.fused_score
+            .total_cmp(&a.fused_score)
+            .then_with(|| cmp_symbol_location(&a.symbol, &b.symbol))
+    });
+    fused
+}
 
-    let documents = get_documents_symbols(lsp_server, paths, vec![]).await;
+/// Order of the symbols by their URI, then by their start line, then by their name, the
+/// tie-breaker of the ranked results, so the equal ranks are returned in the same order
+pub fn cmp_symbol_location(a: &SymbolInfo, b: &SymbolInfo) -> Ordering {
+    a.location
+        .uri
+        .as_str()
+        .cmp(b.location.uri.as_str())
+        .then_with(|| {
+            a.location
+                .range
+                .start
+                .line
+                .cmp(&b.location.range.start.line)
+        })
+        .then_with(|| a.name.cmp(&b.name))
+}
 
-    trace!("Documents: {:?}", documents);
+/// Page of the ranked symbols of a search, the best `offset` symbols are skipped and at most
+/// `max_per_file` symbols of every file are kept
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolsPage {
+    pub offset: usize,
+    pub max_per_file: Option<usize>,
+}
 
-    let iter = chunks
+/// Page of the semantic hits, at most `limit` hits of every query with at most `max_per_file`
+/// hits of one file
+#[derive(Debug, Clone, Copy)]
+pub struct ChunksPage {
+    pub limit: usize,
+    pub max_per_file: Option<usize>,
+}
+
+/// Items in their order with at most `max_per_file` items of every file, so the items of the
+/// other files take the places of the dropped items of a hot file. The items without a path are
+/// kept
+pub fn limit_per_file<T>(
+    items: impl IntoIterator<Item = T>,
+    max_per_file: Option<usize>,
+    path: impl Fn(&T) -> Option<PathBuf>,
+) -> Vec<T> {
+    let Some(max_per_file) = max_per_file else {
+        return items.into_iter().collect();
+    };
+    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+    items
         .into_iter()
-        .map(DocumentPointer::Chunk)
-        .chain(documents.into_iter().map(DocumentPointer::Symbol));
+        .filter(|item| {
+            path(item).is_none_or(|path| {
+                let count = counts.entry(path).or_default();
+                *count += 1;
+                *count <= max_per_file
+            })
+        })
+        .collect()
+}
 
-    let mut grouped: HashMap<PathBuf, Vec<DocumentPointer>> = HashMap::new();
+/// Number of the symbols of a response cut down by the response budget by their details
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct DegradedCounts {
+    pub signature: usize,
+    pub name: usize,
+}
 
-    for pointer in iter {
-        let key = match &pointer {
-            DocumentPointer::Chunk(chunk) => chunk.path.as_path().to_path_buf(),
-            DocumentPointer::Symbol(symbol) => {
-                symbol.location.uri.to_file_path().map_err(|_| {
-                    miette::miette!("Failed to convert URL {} to path", symbol.location.uri)
-                })?
-            }
+/// Details of the symbols of a response cut down to fit their code and hovers into
+/// `search.response_budget_chars` by the ids of the symbols. The symbols in their ranking keep
+/// the code and the hover until one of them doesn't fit, the next ones keep their signatures
+/// while they fit and only their names after that
+#[derive(Debug, Default)]
+pub struct DegradedSymbols(HashMap<String, SymbolDetail>);
+
+impl DegradedSymbols {
+    /// Details of the symbols in their ranking, nothing is cut without the budget. A symbol
+    /// listed again takes the detail of its first place
+    pub fn new<'a>(
+        symbols: impl IntoIterator<Item = &'a SymbolInfo>,
+        budget: Option<usize>,
+    ) -> Self {
+        let Some(budget) = budget else {
+            return Self::default();
         };
-        grouped.entry(key).or_default().push(pointer);
+        let mut left = budget;
+        let mut detail = None;
+        let mut seen = HashSet::new();
+        let mut degraded = HashMap::new();
+        for symbol in symbols {
+            if !seen.insert(symbol.id.as_str()) {
+                continue;
+            }
+            loop {
+                let chars = symbol.budget_chars(detail);
+                if chars <= left || detail == Some(SymbolDetail::Name) {
+                    left -= chars;
+                    break;
+                }
+                detail = match detail {
+                    None => Some(SymbolDetail::Signature),
+                    Some(_) => Some(SymbolDetail::Name),
+                };
+            }
+            if let Some(detail) = detail {
+                degraded.insert(symbol.id.clone(), detail);
+            }
+        }
+        if !degraded.is_empty() {
+            debug!(
+                "Cut down {} symbols to fit {} chars of the response",
+                degraded.len(),
+                budget
+            );
+        }
+        Self(degraded)
     }
 
-    trace!(
-        "Grouped: {:?}",
-        grouped
-            .clone()
-            .into_iter()
-            .map(|it| (
-                it.0,
-                it.1.iter()
-                    .map(|it| format!("{:?}", it))
-                    .collect::<Vec<_>>()
-            ))
-            .collect::<Vec<_>>()
-    );
+    /// Cuts the symbol down to its detail if it is degraded
+    pub fn apply(&self, symbol: &mut SymbolInfo) {
+        if let Some(detail) = self.0.get(&symbol.id) {
+            symbol.degrade(*detail);
+        }
+    }
 
-    let symbols = stream::iter(grouped)
-        .inspect(|it| {
-            debug!("Grouped: {:?}", it.0);
-        })
-        .flat_map(|(_path, group)| {
-            let sorted = group.into_iter().sorted();
-            trace!("Sorted: {:?}", sorted);
-            let scaned = sorted.scan(false, |seen_chunk, ptr| {
-                trace!("Seen chunk: {:#?} for ptr {:#?}", seen_chunk, &ptr);
-                match ptr {
-                    DocumentPointer::Chunk(_) => {
-                        *seen_chunk = true;
-                        Some(None)
-                    }
-                    DocumentPointer::Symbol(symbol) => {
-                        if *seen_chunk {
-                            *seen_chunk = false;
-                            Some(Some(symbol.clone()))
-                        } else {
-                            Some(None)
-                        }
-                    }
-                }
-            });
+    pub fn counts(&self) -> DegradedCounts {
+        let count = |detail| self.0.values().filter(|it| **it == detail).count();
+        DegradedCounts {
+            signature: count(SymbolDetail::Signature),
+            name: count(SymbolDetail::Name),
+        }
+    }
+}
 
-            stream::iter(scaned)
-        })
-        .filter_map(|it| async {
-            if let Some(mut it) = it {
-                let hover = get_hover(lsp_server, &it).await;
-                if let Some(hover) = hover {
-                    it.set_hover(hover);
-                }
-                Some(it)
-            } else {
-                None
+/// Fraction of the shorter of the line ranges of the chunks covered by both of them, 0 for the
+/// chunks of different files and for the adjacent chunks
+fn overlap_fraction(a: &ChunkId, b: &ChunkId) -> f64 {
+    if a.path != b.path {
+        return 0.0;
+    }
+    let overlap = a
+        .end_line
+        .min(b.end_line)
+        .saturating_sub(a.start_line.max(b.start_line));
+    let shorter = (a.end_line - a.start_line).min(b.end_line - b.start_line);
+    if shorter == 0 {
+        return 0.0;
+    }
+    overlap as f64 / shorter as f64
+}
+
+/// Items whose chunks overlap by more than `min_overlap` with the chunk of an earlier item merged
+/// into the earlier one, its chunk is widened to the union of both ranges and `merge` takes the
+/// rest of the merged item. The items are ranked best first, so the merged hit keeps the better
+/// score
+pub fn merge_overlapping<T>(
+    items: impl IntoIterator<Item = T>,
+    min_overlap: f64,
+    chunk: impl Fn(&mut T) -> &mut ChunkId,
+    merge: impl Fn(&mut T, T),
+) -> Vec<T> {
+    let mut merged: Vec<T> = Vec::new();
+    for mut item in items {
+        let current = chunk(&mut item).clone();
+        let earlier = merged
+            .iter_mut()
+            .position(|kept| overlap_fraction(chunk(kept), &current) > min_overlap);
+        match earlier {
+            Some(index) => {
+                let kept = &mut merged[index];
+                let span = chunk(kept);
+                span.start_line = span.start_line.min(current.start_line);
+                span.end_line = span.end_line.max(current.end_line);
+                trace!("Merged overlapping chunk {:?} into {:?}", current, span);
+                merge(kept, item);
             }
+            None => merged.push(item),
+        }
+    }
+    merged
+}
+
+/// Fused symbols and snippets of one file, so the path of a file is rendered once for all its
+/// results
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FileResults {
+    pub path: PathBuf,
+    /// Extension of the file as in the workspace overview, empty if it has none
+    pub language: String,
+    /// Best fused score of the symbols of the file, None if it has snippets only
+    pub best_score: Option<f64>,
+    pub symbol_count: usize,
+    pub symbols: Vec<FusedSymbol>,
+    pub snippets: Vec<ChunkSnippet>,
+}
+
+/// Results grouped by their files in the order of the best result of every file, the files of
+/// the fused symbols come before the files of the snippets only. A symbol without a file path
+/// is grouped by its URI
+pub fn group_by_file(symbols: &[FusedSymbol], snippets: &[ChunkSnippet]) -> Vec<FileResults> {
+    let mut files: Vec<FileResults> = Vec::new();
+    let mut positions: HashMap<PathBuf, usize> = HashMap::new();
+    let mut file_of = |path: PathBuf| {
+        *positions.entry(path.clone()).or_insert_with(|| {
+            files.push(FileResults {
+                language: path
+                    .extension()
+                    .map(|it| it.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                path,
+                best_score: None,
+                symbol_count: 0,
+                symbols: Vec::new(),
+                snippets: Vec::new(),
+            });
+            files.len() - 1
         })
-        .collect::<Vec<_>>()
-        .await;
+    };
+    let symbol_positions = symbols
+        .iter()
+        .map(|it| {
+            file_of(
+                it.symbol
+                    .path()
+                    .unwrap_or_else(|| PathBuf::from(it.symbol.location.uri.as_str())),
+            )
+        })
+        .collect_vec();
+    let snippet_positions = snippets
+        .iter()
+        .map(|it| file_of(it.path.clone()))
+        .collect_vec();
 
-    Ok(symbols)
+    for (position, symbol) in symbol_positions.into_iter().zip(symbols) {
+        let file = &mut files[position];
+        file.best_score = Some(
+            file.best_score
+                .map_or(symbol.fused_score, |it| it.max(symbol.fused_score)),
+        );
+        file.symbol_count += 1;
+        file.symbols.push(symbol.clone());
+    }
+    for (position, snippet) in snippet_positions.into_iter().zip(snippets) {
+        files[position].snippets.push(snippet.clone());
+    }
+    files
 }
 
-async fn update_code_and_name_position_from_document(symbols: Vec<SymbolInfo>) -> Vec<SymbolInfo> {
-    let groups = symbols
-        .into_iter()
-        .into_group_map_by(|sym| sym.location.uri.clone());
+/// Text of a semantically matched chunk which can't be mapped to any LSP symbol,
+/// lines are zero based and the end line is exclusive
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChunkSnippet {
+    pub path: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    /// True if the file changed since the chunk was indexed, the text is read from the current
+    /// file, so it may not be the matched one
+    #[serde(default)]
+    pub stale: bool,
+    /// Notebook cell of the matched lines, None for the other files
+    #[serde(default)]
+    pub cell: Option<NotebookCell>,
+    /// Whether the matched lines without the context lines are code, comments or doc comments,
+    /// None if the language of the file is unknown
+    #[serde(default)]
+    pub origin: Option<MatchOrigin>,
+}
 
-    let mut updated_symbols: Vec<SymbolInfo> = Vec::new();
+impl ChunkSnippet {
+    /// True if the matched lines are comments or doc comments only
+    pub fn is_comment_only(&self) -> bool {
+        matches!(
+            self.origin,
+            Some(MatchOrigin::Comment | MatchOrigin::DocComment)
+        )
+    }
 
-    for (url, group) in groups {
-        let path = url.to_file_path();
-        if let Ok(path) = path {
-            let file = File::open(path).await;
-            if let Ok(file) = file {
-                let mut lines = BufReader::new(file).lines();
-                let mut index = 0;
-                for mut symbol in group
-                    .into_iter()
-                    .sorted_by_key(|s| s.location.range.start.line)
-                {
-                    let regex = Regex::new(&regex::escape(&symbol.name));
-                    if let Ok(regex) = regex {
-                        let start_line = symbol.location.range.start.line;
-                        let end_line = symbol.location.range.end.line;
-                        let mut code = Vec::new();
-
-                        trace!(
-                            "Getting code and name position from document: {:?}, symbol: {:?}",
-                            symbol.location.uri, symbol
-                        );
-
-                        while let Ok(line) = lines.next_line().await {
-                            if index >= start_line && index <= end_line {
-                                if let Some(line) = line {
-                                    if symbol.name_position.is_none() {
-                                        if let Some(m) = regex.find(&line) {
-                                            let column = m.end() - 1;
-                                            symbol.name_position =
-                                                Some(Position::new(index, column as u32));
-                                        }
-                                    }
-                                    code.push(line);
-                                }
-                            }
-                            index += 1;
-                            if index > end_line {
-                                break;
-                            }
-                        }
-                        symbol.code = Some(code.join("\n"));
+    fn covers(&self, chunk: &ChunkId) -> bool {
+        self.path == *chunk.path
+            && self.start_line <= chunk.start_line
+            && chunk.start_line < self.end_line
+    }
+}
 
-                        trace!("Updated symbol: {:?}", symbol);
-                        updated_symbols.push(symbol);
-                    } else {
-                        error!("Error creating regex for symbol: {:?}", symbol);
-                    }
-                }
-            } else {
-                updated_symbols.extend(group);
-            }
-        } else {
-            updated_symbols.extend(group);
+/// Hash and write time of the indexed lines of the chunk payload, compared with the file at
+/// query time
+#[derive(Debug, Clone, Default)]
+struct IndexedLines {
+    /// Id of the chunk in the table, see [`get_chunk_neighbors`]
+    id: Option<String>,
+    /// None for the chunks imported without text
+    hash: Option<String>,
+    /// Milliseconds since the epoch, None for the chunks written before it was stored
+    indexed_at: Option<i64>,
+}
+
+impl IndexedLines {
+    fn of(payload: &Value) -> Self {
+        Self {
+            id: payload
+                .get("id")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            hash: payload
+                .get("hash")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            indexed_at: payload.get("indexed_at").and_then(Value::as_i64),
         }
     }
+}
 
-    updated_symbols
+/// True if the lines of the chunk in the file differ from the indexed ones or the file can't be
+/// read, the chunks without the indexed hash are never stale. The file not modified since the
+/// chunk was written is not read
+async fn is_stale_chunk(files: &FileCache, chunk: &ChunkId, indexed: &IndexedLines) -> bool {
+    let Some(hash) = &indexed.hash else {
+        return false;
+    };
+    if let Some(indexed_at) = indexed.indexed_at {
+        let modified = tokio::fs::metadata(source_file(&chunk.path))
+            .await
+            .and_then(|it| it.modified())
+            .ok()
+            .map(|it| DateTime::<Utc>::from(it).timestamp_millis());
+        if modified.is_some_and(|modified| modified < indexed_at) {
+            return false;
+        }
+    }
+    match files.read_lines(chunk.path.as_path()).await {
+        Ok(lines) => lines
+            .get(chunk.start_line..chunk.end_line)
+            .is_none_or(|lines| lines_hash(lines) != *hash),
+        Err(_) => true,
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SymbolPlaceTo {
-    pub symbol_info: SymbolInfo,
-    pub place_to: String,
+/// Chunk matched by the vector search with its text loaded from the file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SemanticHit {
+    pub query: String,
+    /// Distance to the query reported by the vector store in the vector mode, lower is closer.
+    /// BM25 score in the fts mode and the reciprocal rank fusion score in the hybrid mode, higher
+    /// is better for them
+    pub score: f64,
+    #[serde(flatten)]
+    pub snippet: ChunkSnippet,
+    /// True if the hit is found in the backup table of the previous model while the table is
+    /// rebuilt for the current one
+    #[serde(default)]
+    pub from_previous_model: bool,
+    /// Last commit of the lines of the hit, see `search.git_metadata`
+    #[serde(default)]
+    pub git: Option<GitMetadata>,
+    /// Chunks around the hit, see [`set_hits_neighbors`]
+    #[serde(default)]
+    pub neighbors: Option<ChunkNeighbors>,
+    /// Ids of the chunks merged into the hit ordered by their lines
+    #[serde(skip)]
+    pub chunk_ids: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SymbolReferences {
-    pub symbol_info: SymbolInfo,
-    pub references: Vec<Location>,
+/// Chunks of the file stored before and after a chunk, their text is read from the file
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChunkNeighbors {
+    /// Zero based position of the chunk in the chunks of its file, the first chunk of a hit
+    pub chunk_index: usize,
+    /// Number of the chunks of the file when the chunk was written
+    pub total_chunks: Option<usize>,
+    /// Previous chunks, the nearest one last
+    pub before: Vec<ChunkSnippet>,
+    /// Next chunks, the nearest one first
+    pub after: Vec<ChunkSnippet>,
 }
 
-pub fn get_symbols_references(
-    lsp_server: &GuardedLspServer,
-    symbol_infos: Vec<SymbolInfo>,
-) -> impl Stream<Item = SymbolReferences> + Send {
-    info!("Starting request to get symbols references");
+/// Up to `count` chunks before and after the chunk of the id of the table of the workspace with
+/// their lines and text, None if the chunk is not stored or was written before its position was
+/// stored
+pub async fn get_chunk_neighbors(
+    table: &Table,
+    workspace: &Workspace,
+    chunk_id: &str,
+    count: usize,
+) -> Result<Option<ChunkNeighbors>> {
+    let table_config = &workspace.semantic.table;
+    let Some(chunk) = chunk_by_id(table, chunk_id, table_config).await? else {
+        return Ok(None);
+    };
+    let Some(chunk_index) = chunk.chunk_index else {
+        return Ok(None);
+    };
+    let indexes = chunk_index.saturating_sub(count)..=chunk_index + count;
+    let mut neighbors = ChunkNeighbors {
+        chunk_index,
+        total_chunks: chunk.total_chunks,
+        before: Vec::new(),
+        after: Vec::new(),
+    };
+    let stored = chunks_by_index(
+        table,
+        &chunk.path,
+        indexes,
+        table_config,
+        workspace.path_case,
+    )
+    .await?;
+    for neighbor in stored {
+        let Some(index) = neighbor.chunk_index else {
+            continue;
+        };
+        if index == chunk_index {
+            continue;
+        }
+        let Some(snippet) = stored_chunk_snippet(&workspace.file_cache, neighbor).await else {
+            continue;
+        };
+        if index < chunk_index {
+            neighbors.before.push(snippet);
+        } else {
+            neighbors.after.push(snippet);
+        }
+    }
+    Ok(Some(neighbors))
+}
 
-    stream::iter(symbol_infos)
-        .filter_map(|it| async {
-            if CONFIG
-                .placer
-                .final_symbol_kinds
-                .iter()
-                .any(|pattern| pattern.is_match(&it.kind))
-            {
-                Some(it)
-            } else {
-                None
-            }
-        })
-        .map(move |symbol_info| {
-            let guarded_lsp_server = lsp_server.clone();
-            async move {
-                guarded_lsp_server
-                    .send_references_request(
-                        symbol_info.location.uri.clone(),
-                        symbol_info
-                            .name_position
-                            .unwrap_or(symbol_info.location.range.start),
-                    )
-                    .await
-                    .map(|it| {
-                        it.map(|it| SymbolReferences {
-                            symbol_info: symbol_info.clone(),
-                            references: it,
-                        })
-                        .or_else(|| {
-                            Some(SymbolReferences {
-                                symbol_info: symbol_info.clone(),
-                                references: vec![],
-                            })
-                        })
-                    })
-            }
-        })
-        .filter_map(|it| async {
-            it.await
-                .inspect_err(|err| {
-                    error!("Error getting symbols references: {}", err);
-                })
+/// Lines of the stored chunk read from the file, marked stale if they differ from the indexed
+/// ones
+async fn stored_chunk_snippet(files: &FileCache, chunk: StoredChunk) -> Option<ChunkSnippet> {
+    let indexed = IndexedLines {
+        id: Some(chunk.id),
+        hash: chunk.hash,
+        indexed_at: chunk.indexed_at.map(|it| it.timestamp_millis()),
+    };
+    let chunk = ChunkId::new(Arc::new(chunk.path), chunk.start_line, chunk.end_line);
+    let stale = is_stale_chunk(files, &chunk, &indexed).await;
+    read_chunk_snippet(files, &chunk, 0)
+        .await
+        .map(|snippet| ChunkSnippet { stale, ..snippet })
+}
+
+/// Sets up to `count` chunks before the first chunk and after the last chunk of every hit, the
+/// chunks are read from the table of the model of the hit file. The scratch hits and the hits
+/// of the chunks written before their positions were stored have no neighbors
+pub async fn set_hits_neighbors(
+    hits: &mut [SemanticHit],
+    routes: &RoutedBackend,
+    workspace: &Workspace,
+    count: usize,
+) {
+    let neighbors = future::join_all(hits.iter().map(|hit| async move {
+        let (Some(first), Some(last)) = (hit.chunk_ids.first(), hit.chunk_ids.last()) else {
+            return None;
+        };
+        if is_scratch_path(&hit.snippet.path) {
+            return None;
+        }
+        let table = &routes.table_of(&hit.snippet.path).table;
+        let neighbors = |id: String| async move {
+            get_chunk_neighbors(table, workspace, &id, count)
+                .await
+                .inspect_err(|e| warn!("Failed to read neighbors of chunk {}: {}", id, e))
                 .ok()
                 .flatten()
-        })
-        .boxed()
+        };
+        let mut hit_neighbors = neighbors(first.clone()).await?;
+        // the chunks after the first one up to the last one are a part of the hit
+        if last != first {
+            hit_neighbors.after = neighbors(last.clone())
+                .await
+                .map(|it| it.after)
+                .unwrap_or_default();
+        }
+        Some(hit_neighbors)
+    }))
+    .await;
+    for (hit, neighbors) in hits.iter_mut().zip(neighbors) {
+        hit.neighbors = neighbors;
+    }
 }
 
-pub async fn get_documents_symbols(
-    lsp_server: &GuardedLspServer,
-    documents_uris: HashSet<Url>,
-    kinds: Vec<Regex>,
-) -> Vec<SymbolInfo> {
-    info!("Starting request to get document symbols");
+/// Searches chunks by vector similarity without any LSP enrichment, so it works for any
+/// indexed files. The glob is matched against workspace relative paths of the top hits, every
+/// query keeps at most `max_per_file` hits of one file
+pub async fn search_chunks(
+    config: &McpConfig,
+    workspace: &Workspace,
+    queries: Vec<String>,
+    path_glob: Option<&Glob<'_>>,
+    mode: SearchMode,
+    vector_store: Arc<dyn VectorBackend>,
+    ChunksPage {
+        limit,
+        max_per_file,
+    }: ChunksPage,
+) -> Result<Vec<SemanticHit>> {
+    info!("Searching chunks in {} mode for: {:?}", mode, queries);
+    let workspace_path = workspace_root(workspace)?;
+    let workspace_path = &workspace_path;
+    let page_limit = limit;
+    let limit = if max_per_file.is_some() {
+        limit * PER_FILE_FETCH_FACTOR
+    } else {
+        limit
+    };
 
-    let symbols: Vec<SymbolInfo> = stream::iter(documents_uris)
-        .map(move |document_uri| {
-            let guarded_lsp_server = lsp_server.clone();
-            let kinds = kinds.clone();
-            async move {
-                info!(
-                    "Sending request to get document symbols for: {}",
-                    document_uri
-                );
-                guarded_lsp_server
-                    .send_document_symbol_request(document_uri.clone())
-                    .await
-                    .map(|symbols| {
-                        symbols.map(|it| match it {
-                            DocumentSymbolResponse::Flat(s) => {
-                                let stream = stream::iter(s)
-                                    .filter(move |symbol| {
-                                        let kinds = kinds.clone();
-                                        filter_symbols_kind(symbol.kind, kinds)
-                                    })
-                                    .map(move |symbol| SymbolInfo {
-                                        name: symbol.name,
-                                        kind: format!("{:?}", symbol.kind),
-                                        location: symbol.location,
-                                        container_name: symbol.container_name,
-                                        code: None,
-                                        hover: None,
-                                        name_position: None,
-                                    });
-
-                                Either::Left(stream)
-                            }
-                            DocumentSymbolResponse::Nested(s) => {
-                                let stream = stream::iter(s)
-                                    .filter(move |symbol| {
-                                        let kinds = kinds.clone();
-                                        filter_symbols_kind(symbol.kind, kinds)
-                                    })
-                                    .map(move |symbol| {
-                                        let location =
-                                            Location::new(document_uri.clone(), symbol.range);
-                                        SymbolInfo {
-                                            name: symbol.name,
-                                            kind: format!("{:?}", symbol.kind),
-                                            location,
-                                            container_name: None,
-                                            code: None,
-                                            hover: None,
-                                            name_position: Some(symbol.selection_range.end),
-                                        }
-                                    });
-                                Either::Right(stream)
-                            }
-                        })
+    // the queries are embedded in one batch, the results keep the order of the queries
+    let results = match mode {
+        SearchMode::Vector => vector_store.top_n_many(&queries, limit).await,
+        SearchMode::Fts => vector_store.text_top_n_many(&queries, limit).await,
+        SearchMode::Hybrid => {
+            let (vector, text) = tokio::join!(
+                vector_store.top_n_many(&queries, limit),
+                vector_store.text_top_n_many(&queries, limit)
+            );
+            // without the full-text index the vector results are returned in their order
+            let mut text = text
+                .inspect_err(|e| warn!("Full-text search of the hybrid mode failed: {}", e))
+                .unwrap_or_default()
+                .into_iter();
+            let k = config.search.fusion.k;
+            vector.map(|vector| {
+                vector
+                    .into_iter()
+                    .map(|vector| fuse_chunks([vector, text.next().unwrap_or_default()], k, limit))
+                    .collect()
+            })
+        }
+    }
+    .inspect_err(|e| error!("Failed to search chunks for {:?}: {}", queries, e))
+    .unwrap_or_default();
+
+    let merge_overlap = config.search.semantic.merge_overlap;
+    let hits = stream::iter(queries.into_iter().zip(results))
+        .flat_map(|(query, hits)| {
+            let chunks = hits
+                .into_iter()
+                .filter_map(|(score, value)| {
+                    let indexed = IndexedLines::of(&value);
+                    serde_json::from_value::<ChunkId>(value)
+                        .inspect_err(|e| error!("Error parsing chunk id: {}", e))
+                        .ok()
+                        .map(|chunk| (score, chunk, indexed))
+                })
+                .filter(|(_, chunk, _)| {
+                    path_glob.is_none_or(|glob| {
+                        let relative = chunk
+                            .path
+                            .strip_prefix(workspace_path)
+                            .unwrap_or(chunk.path.as_path());
+                        glob.is_match(relative)
                     })
-            }
-        })
-        .filter_map(|it| async {
-            it.await
-                .inspect_err(|err| {
-                    error!("Error getting document symbols: {}", err);
                 })
-                .ok()
-                .flatten()
+                .map(|(score, chunk, indexed)| (score, chunk.clone(), vec![(chunk, indexed)]));
+            // the overlapping chunks of a file are one hit, its indexed chunks are kept for the
+            // stale check
+            let chunks = merge_overlapping(
+                chunks,
+                merge_overlap,
+                |(_, span, _)| span,
+                |(_, _, parts), (_, _, merged)| parts.extend(merged),
+            );
+            let chunks = limit_per_file(chunks, max_per_file, |(_, span, _)| {
+                Some(span.path.to_path_buf())
+            });
+            stream::iter(
+                chunks
+                    .into_iter()
+                    .take(page_limit)
+                    .map(move |(score, span, parts)| (query.clone(), score, span, parts)),
+            )
+        })
+        .filter_map(|(query, score, span, parts)| async move {
+            let mut stale = false;
+            for (chunk, indexed) in parts.iter() {
+                if is_stale_chunk(&workspace.file_cache, chunk, indexed).await {
+                    stale = true;
+                    break;
+                }
+            }
+            let chunk_ids = parts
+                .iter()
+                .sorted_by_key(|(chunk, _)| chunk.start_line)
+                .filter_map(|(_, indexed)| indexed.id.clone())
+                .collect_vec();
+            read_chunk_snippet(
+                &workspace.file_cache,
+                &span,
+                workspace.semantic.context_lines,
+            )
+            .await
+            .map(|snippet| SemanticHit {
+                query,
+                score,
+                snippet: ChunkSnippet { stale, ..snippet },
+                from_previous_model: false,
+                git: None,
+                neighbors: None,
+                chunk_ids,
+            })
         })
-        .flat_map(|it| it)
         .collect::<Vec<_>>()
         .await;
 
-    update_code_and_name_position_from_document(symbols).await
+    trace!("Semantic hits: {:?}", hits);
+
+    Ok(hits)
 }
 
-async fn get_hover(lsp_server: &GuardedLspServer, symbol: &SymbolInfo) -> Option<Hover> {
-    if let Some(position) = symbol.name_position {
-        let hover = lsp_server
-            .send_hover_request(symbol.location.uri.clone(), position)
-            .await
-            .ok()
-            .flatten();
-        if let Some(hover) = hover {
-            info!("Hover: {:?}", hover);
-            return Some(hover);
-        } else {
-            trace!("No hover found for: {:?}", symbol);
+/// Chunks of the rankings ordered by the reciprocal rank fusion score like [`fuse_symbols`],
+/// the chunks are identified by the id of the payload
+fn fuse_chunks<const N: usize>(
+    rankings: [Vec<(f64, Value)>; N],
+    k: usize,
+    limit: usize,
+) -> Vec<(f64, Value)> {
+    let mut fused: Vec<([Option<usize>; N], Value)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (ranking_index, ranking) in rankings.into_iter().enumerate() {
+        for (index, (_, payload)) in ranking.into_iter().enumerate() {
+            let id = payload
+                .get("id")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            let position = *positions.entry(id).or_insert_with(|| {
+                fused.push(([None; N], payload));
+                fused.len() - 1
+            });
+            fused[position].0[ranking_index].get_or_insert(index + 1);
         }
     }
-    None
+    fused
+        .into_iter()
+        .map(|(ranks, payload)| {
+            let score = ranks
+                .into_iter()
+                .map(|rank| 1.0 / (k + rank.unwrap_or(ABSENT_RANK)) as f64)
+                .sum::<f64>();
+            (score, payload)
+        })
+        .sorted_by(|a, b| {
+            let position = |payload: &Value| {
+                (
+                    payload
+                        .get("path")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                    payload.get("start_line").and_then(Value::as_u64),
+                )
+            };
+            b.0.total_cmp(&a.0)
+                .then_with(|| position(&a.1).cmp(&position(&b.1)))
+        })
+        .take(limit)
+        .collect()
 }
 
-pub async fn get_workspace_symbols(
-    guarded_lsp_server: &GuardedLspServer,
-    names: Vec<String>,
-) -> impl Stream<Item = WorkspaceSymbolResponse> + Send {
-    info!("Starting request to get workspace symbols");
+/// Indexed chunk similar to a window of the code snippet
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarCode {
+    /// Cosine similarity of the chunk and the window from 0 to 1
+    pub similarity: f32,
+    /// Zero based line of the code snippet the most similar window starts at
+    pub snippet_line: usize,
+    #[serde(flatten)]
+    pub snippet: ChunkSnippet,
+}
 
-    if names.is_empty() {
-        if let Ok(response) = guarded_lsp_server
-            .send_workspace_symbol_request("".to_string())
-            .await
-        {
-            match response {
-                Some(response) => stream::once(async { response }).boxed(),
-                None => stream::empty().boxed(),
-            }
-        } else {
-            stream::empty().boxed()
+/// Windows of the code snippet cut as the chunker cuts files, one window if it is short
+fn snippet_windows(code: &str, semantic: &SemanticConfig) -> Vec<TextChunk> {
+    let chunk_size = semantic.chunk_size;
+    let overlap_size = semantic.overlap_size;
+    let mut windows = Vec::new();
+    let mut window = TextChunk::new(Arc::new(PathBuf::new()), 0, chunk_size);
+    for line in code.lines() {
+        window.push_line(line.to_string());
+        if window.is_full(chunk_size) {
+            windows.push(window.clone());
+            window = window.next_chunk(chunk_size, overlap_size);
         }
-    } else {
-        stream::iter(names)
-            .map(|q| guarded_lsp_server.send_workspace_symbol_request(q))
-            .filter_map(|it| async {
-                it.await
-                    .inspect_err(|err| {
-                        error!("Error getting workspace symbols: {}", err);
-                    })
-                    .ok()
-                    .flatten()
-            })
-            .boxed()
     }
+    // the tail of only overlapped lines is a part of the previous window
+    if !window.is_empty() && (windows.is_empty() || window.count_lines() > overlap_size) {
+        window.crop_last_chunk();
+        windows.push(window);
+    }
+    windows
 }
 
-fn path_distance(a: &Path, b: &Path) -> usize {
-    let a: Vec<_> = a.components().collect();
-    let b: Vec<_> = b.components().collect();
+/// Chunks most similar to any window of the code snippet embedded by the indexing model, the
+/// chunks are returned for the LSP enrichment too
+pub async fn find_similar_code(
+    table: &Table,
+    config: &McpConfig,
+    workspace: &Workspace,
+    embedding_model: &IndexEmbeddingModel,
+    code: &str,
+    min_similarity: f32,
+) -> Result<(Vec<SimilarCode>, Vec<ChunkId>)> {
+    let semantic = &workspace.semantic;
+    let windows = snippet_windows(code, semantic);
+    info!(
+        "Searching code similar to {} snippet windows",
+        windows.len()
+    );
 
-    let common_len = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
-    (a.len() - common_len) + (b.len() - common_len)
-}
+    let embeddings = embedding_model
+        .embed_texts(windows.iter().map(|window| window.text.join("\n")))
+        .await
+        .into_diagnostic()?;
 
-//TODO: no graphs for POC
-pub fn find_max_distance_paths(candidates: &[PathBuf], usages: &[PathBuf]) -> Vec<PathBuf> {
-    candidates
-        .iter()
-        .max_set_by_key(|candidate| {
-            usages
-                .iter()
-                .map(|usage| path_distance(candidate.as_path(), usage.as_path()))
-                .sum::<usize>()
+    let mut best: HashMap<ChunkRange, (f32, usize)> = HashMap::new();
+    for (window, embedding) in windows.iter().zip(embeddings) {
+        let nearest = nearest_chunks(
+            table,
+            &embedding.vec,
+            config.search.semantic.search_limit,
+            &semantic.table,
+        )
+        .await?;
+        for (chunk, distance) in nearest {
+            let similarity = 1.0 - distance;
+            if similarity < min_similarity {
+                continue;
+            }
+            let entry = best
+                .entry(ChunkRange::from(&chunk))
+                .or_insert((similarity, window.start_line));
+            if similarity > entry.0 {
+                *entry = (similarity, window.start_line);
+            }
+        }
+    }
+
+    let best = best
+        .into_iter()
+        .sorted_by(|a, b| {
+            b.1.0
+                .total_cmp(&a.1.0)
+                .then_with(|| a.0.path.cmp(&b.0.path))
         })
-        .iter()
-        .map(|it| (*it).clone())
-        .collect::<Vec<_>>()
-}
+        .take(config.search.semantic.search_limit)
+        .map(|(range, (similarity, snippet_line))| {
+            (
+                ChunkId::new(Arc::new(range.path), range.start_line, range.end_line),
+                similarity,
+                snippet_line,
+            )
+        })
+        .collect::<Vec<_>>();
 
-//TODO: no graphs for POC
-pub fn find_min_distance_paths(candidates: &[PathBuf], usages: &[PathBuf]) -> Vec<PathBuf> {
-    candidates
-        .iter()
-        .min_set_by_key(|candidate| {
-            usages
-                .iter()
-                .map(|usage| path_distance(candidate.as_path(), usage.as_path()))
-                .sum::<usize>()
+    let similar = stream::iter(best.iter())
+        .filter_map(|(chunk, similarity, snippet_line)| async move {
+            read_chunk_snippet(&workspace.file_cache, chunk, semantic.context_lines)
+                .await
+                .map(|snippet| SimilarCode {
+                    similarity: *similarity,
+                    snippet_line: *snippet_line,
+                    snippet,
+                })
         })
-        .iter()
-        .map(|it| (*it).clone())
         .collect::<Vec<_>>()
+        .await;
+
+    trace!("Similar code: {:?}", similar);
+
+    Ok((
+        similar,
+        best.into_iter().map(|(chunk, _, _)| chunk).collect(),
+    ))
 }
 
-pub fn most_common_parent(paths: &[PathBuf]) -> Option<PathBuf> {
-    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+/// Lines of a chunk, zero based and the end line is exclusive
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct ChunkRange {
+    pub path: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+}
 
-    for path in paths {
-        if let Some(parent) = path.parent() {
-            let parent_buf = parent.to_path_buf();
-            *counts.entry(parent_buf).or_insert(0) += 1;
+impl ChunkRange {
+    fn overlaps(&self, other: &ChunkRange) -> bool {
+        self.path == other.path
+            && self.start_line < other.end_line
+            && other.start_line < self.end_line
+    }
+}
+
+impl From<&StoredChunk> for ChunkRange {
+    fn from(chunk: &StoredChunk) -> Self {
+        Self {
+            path: chunk.path.clone(),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
         }
     }
+}
 
-    counts
-        .into_iter()
-        .max_by_key(|(_, count)| *count)
-        .map(|(path, _)| path)
+/// Chunks similar to each other, the similarity is the best cosine similarity of the pairs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateCluster {
+    pub similarity: f32,
+    pub ranges: Vec<ChunkRange>,
 }
 
-pub fn get_project_files() -> Result<Vec<PathBuf>> {
-    info!("Start path scanner");
+/// Finds clusters of near duplicate chunks among the files matched by the glob. Stored
+/// embeddings of at most `duplicates_max_chunks` chunks are compared with their nearest
+/// neighbors, overlapping windows of the same file are not counted as duplicates
+pub async fn find_duplicate_chunks(
+    table: &Table,
+    workspace: &Workspace,
+    path_glob: &Glob<'_>,
+    threshold: f32,
+) -> Result<Vec<DuplicateCluster>> {
+    let semantic = &workspace.semantic;
+    let workspace_path = workspace_root(workspace)?;
+    let chunks = read_chunks(
+        table,
+        |path| {
+            let relative = path.strip_prefix(&workspace_path).unwrap_or(path);
+            path_glob.is_match(relative)
+        },
+        semantic.duplicates_max_chunks,
+        &semantic.table,
+    )
+    .await?;
 
-    let url = Url::parse(&CONFIG.search.fuzzy.workspace_uri).into_diagnostic()?;
+    info!("Searching duplicates of {} chunks", chunks.len());
 
-    if url.scheme() != "file" {
-        return Err(miette!("Not a file URL: {}", url));
-    }
+    // Every chunk is matched by windows sharing its lines, they are requested additionally
+    let step = (semantic.chunk_size - semantic.overlap_size).max(1);
+    let overlapping = 2 * semantic.chunk_size.div_ceil(step);
+    let limit = semantic.duplicates_neighbors + overlapping;
 
-    let path = url
-        .to_file_path()
-        .map_err(|_| miette!("Invalid file URL: {}", url))?;
+    let mut ranges: Vec<ChunkRange> = Vec::new();
+    let mut indexes: HashMap<ChunkRange, usize> = HashMap::new();
+    let mut pairs: Vec<(usize, usize, f32)> = Vec::new();
 
-    let positive = Glob::new(CONFIG.search.semantic.pattern.as_str()).into_diagnostic()?;
+    for chunk in chunks.iter().filter(|it| !it.embedding.is_empty()) {
+        let source = ChunkRange::from(chunk);
+        for (neighbor, distance) in
+            nearest_chunks(table, &chunk.embedding, limit, &semantic.table).await?
+        {
+            let neighbor = ChunkRange::from(&neighbor);
+            let similarity = 1.0 - distance;
+            if similarity < threshold || source.overlaps(&neighbor) {
+                continue;
+            }
+            let mut index_of = |range: &ChunkRange| {
+                *indexes.entry(range.clone()).or_insert_with(|| {
+                    ranges.push(range.clone());
+                    ranges.len() - 1
+                })
+            };
+            let pair = (index_of(&source), index_of(&neighbor), similarity);
+            pairs.push(pair);
+        }
+    }
 
-    let mut files = Vec::new();
-    let walker = positive.walk(&path);
+    trace!("Duplicate pairs: {:?}", pairs);
 
-    for entry in walker
-        .filter_map(|it| it.ok())
-        .filter(|it| it.file_type().is_file())
-    {
-        info!("File found: {:?}", entry.path());
-        files.push(entry.into_path());
+    let mut parents = (0..ranges.len()).collect::<Vec<_>>();
+    for (a, b, _) in pairs.iter() {
+        let (a, b) = (
+            cluster_root(&mut parents, *a),
+            cluster_root(&mut parents, *b),
+        );
+        parents[a] = b;
+    }
+
+    let mut clusters: HashMap<usize, DuplicateCluster> = HashMap::new();
+    for (a, _, similarity) in pairs.iter() {
+        let cluster = clusters
+            .entry(cluster_root(&mut parents, *a))
+            .or_insert(DuplicateCluster {
+                similarity: *similarity,
+                ranges: vec![],
+            });
+        cluster.similarity = cluster.similarity.max(*similarity);
+    }
+    for (index, range) in ranges.into_iter().enumerate() {
+        if let Some(cluster) = clusters.get_mut(&cluster_root(&mut parents, index)) {
+            cluster.ranges.push(range);
+        }
     }
 
-    Ok(files)
+    let clusters = clusters
+        .into_values()
+        .map(|mut cluster| {
+            cluster.ranges.sort_by(|a, b| {
+                a.path
+                    .cmp(&b.path)
+                    .then_with(|| a.start_line.cmp(&b.start_line))
+            });
+            cluster
+        })
+        .sorted_by(|a, b| b.similarity.total_cmp(&a.similarity))
+        .collect::<Vec<_>>();
+
+    debug!("Duplicate clusters: {:?}", clusters);
+
+    Ok(clusters)
+}
+
+/// Root of the union find tree, the path to the root is halved on the way
+fn cluster_root(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+/// Symbols and snippets of the chunks ranked from `offset` limited by `search_limit` and by
+/// `max_per_file` chunks of every file for every description, the flag is set if more chunks may
+/// exist after the page. Hover and code of the symbols are requested only if `need_docs` and
+/// `read_code` are set
+async fn get_semantic_symbols(
+    lsp_server: &GuardedLspServer,
+    config: &McpConfig,
+    short_descriptions: Vec<String>,
+    vector_store: Arc<dyn VectorBackend>,
+    need_docs: bool,
+    read_code: bool,
+    SymbolsPage {
+        offset,
+        max_per_file,
+    }: SymbolsPage,
+) -> Result<(Vec<SymbolInfo>, Vec<ChunkSnippet>, bool)> {
+    info!("Getting semantic symbols for: {:?}", short_descriptions);
+    let page_limit = config.search.semantic.search_limit + offset;
+    // the reranker may move the chunks past the page to its top, so it gets all its candidates
+    let workspace = lsp_server.workspace();
+    let fetch_limit = page_limit.max(workspace.reranker.candidates());
+    let fetch_limit = if max_per_file.is_some() {
+        fetch_limit * PER_FILE_FETCH_FACTOR
+    } else {
+        fetch_limit
+    };
+    let results = vector_store
+        .top_n_many(&short_descriptions, fetch_limit)
+        .await
+        .inspect_err(|err| {
+            error!("Error getting symbols: {}", err);
+        })
+        .inspect(|it| {
+            info!("Semantic search result: {:?}", it);
+        })
+        .unwrap_or_default();
+
+    let has_more = results
+        .iter()
+        .any(|it| it.len() > page_limit || it.len() >= fetch_limit);
+
+    // the expanded variants of a query find the same chunks, every chunk is kept once
+    let mut seen = HashSet::new();
+    let mut chunks = Vec::new();
+    let mut indexed_lines = HashMap::new();
+    for (query, result) in short_descriptions.iter().zip(results) {
+        let query_chunks = result
+            .into_iter()
+            .filter_map(|(_, value)| {
+                let indexed = IndexedLines::of(&value);
+                let chunk = serde_json::from_value::<ChunkId>(value)
+                    .inspect_err(|e| {
+                        error!("Error parsing chunk id: {}", e);
+                    })
+                    .ok()?;
+                indexed_lines.insert(chunk.clone(), indexed);
+                Some(chunk)
+            })
+            .collect_vec();
+        let query_chunks = limit_per_file(
+            rerank_chunks(workspace, query, query_chunks).await,
+            max_per_file,
+            |chunk| Some(chunk.path.to_path_buf()),
+        )
+        .into_iter()
+        .take(page_limit)
+        .skip(offset)
+        .filter(|chunk| seen.insert(chunk.clone()))
+        .collect_vec();
+        debug!(
+            "Semantic query {:?} found chunks: {:?}",
+            query, query_chunks
+        );
+        chunks.extend(query_chunks);
+    }
+
+    trace!("Chunks: {:?}", chunks);
+
+    // the symbols are taken from the current document symbols, so only the snippets are marked
+    let mut stale = Vec::new();
+    for chunk in chunks.iter() {
+        let indexed = indexed_lines.get(chunk).cloned().unwrap_or_default();
+        if is_stale_chunk(&workspace.file_cache, chunk, &indexed).await {
+            stale.push(chunk.clone());
+        }
+    }
+    if !stale.is_empty() {
+        debug!("Stale chunks: {:?}", stale);
+    }
+
+    let (symbols, mut snippets) =
+        get_chunks_symbols(lsp_server, config, chunks, need_docs, read_code).await;
+    for snippet in snippets.iter_mut() {
+        snippet.stale = stale.iter().any(|chunk| snippet.covers(chunk));
+    }
+
+    Ok((symbols, snippets, has_more))
+}
+
+/// Symbols following the chunks in their files and snippets of the chunks without symbols,
+/// hover and code of the symbols are requested only if `need_docs` and `read_code` are set
+pub async fn get_chunks_symbols(
+    lsp_server: &GuardedLspServer,
+    config: &McpConfig,
+    chunks: Vec<ChunkId>,
+    need_docs: bool,
+    read_code: bool,
+) -> (Vec<SymbolInfo>, Vec<ChunkSnippet>) {
+    // the LSP ranges don't match the extracted lines and the LSP server doesn't know the files
+    // of the extra roots, so their chunks stay snippets
+    let paths = chunks
+        .iter()
+        .filter(|it| {
+            !has_extracted_lines(&it.path) && !is_extra_root_path(lsp_server.workspace(), &it.path)
+        })
+        .filter_map(|it| path_to_url(&it.path))
+        .collect::<HashSet<_>>();
+
+    info!("Paths: {:?}", paths);
+
+    let documents =
+        get_documents_symbols(lsp_server, paths, SymbolKindFilter::default(), read_code).await;
+
+    trace!("Documents: {:?}", documents);
+
+    let iter = chunks.into_iter().map(DocumentPointer::Chunk).chain(
+        documents
+            .into_iter()
+            .map(|it| DocumentPointer::Symbol(Box::new(it))),
+    );
+
+    // ordered by the paths, so the snippets and the symbols are the same for the same query
+    let mut grouped: BTreeMap<PathBuf, Vec<DocumentPointer>> = BTreeMap::new();
+
+    for pointer in iter {
+        let key = match &pointer {
+            DocumentPointer::Chunk(chunk) => chunk.path.as_path().to_path_buf(),
+            DocumentPointer::Symbol(symbol) => match symbol.path() {
+                Some(path) => path,
+                None => {
+                    debug!(
+                        "Symbol without local path can't be matched with chunks: {}",
+                        symbol.location.uri
+                    );
+                    continue;
+                }
+            },
+        };
+        grouped.entry(key).or_default().push(pointer);
+    }
+
+    trace!(
+        "Grouped: {:?}",
+        grouped
+            .clone()
+            .into_iter()
+            .map(|it| (
+                it.0,
+                it.1.iter()
+                    .map(|it| format!("{:?}", it))
+                    .collect::<Vec<_>>()
+            ))
+            .collect::<Vec<_>>()
+    );
+
+    let snippets = stream::iter(merge_overlapping(
+        grouped
+            .values()
+            .flat_map(|group| unmapped_chunks(group))
+            .unique(),
+        config.search.semantic.merge_overlap,
+        |chunk| chunk,
+        |_, _| {},
+    ))
+    .filter_map(|chunk| async move {
+        let workspace = lsp_server.workspace();
+        read_chunk_snippet(
+            &workspace.file_cache,
+            &chunk,
+            workspace.semantic.context_lines,
+        )
+        .await
+    })
+    .collect::<Vec<_>>()
+    .await;
+
+    trace!("Snippets: {:?}", snippets);
+
+    let symbols = stream::iter(grouped)
+        .inspect(|it| {
+            debug!("Grouped: {:?}", it.0);
+        })
+        .flat_map(|(_path, group)| {
+            let sorted = group.into_iter().sorted();
+            trace!("Sorted: {:?}", sorted);
+            let scaned = sorted.scan(false, |seen_chunk, ptr| {
+                trace!("Seen chunk: {:#?} for ptr {:#?}", seen_chunk, &ptr);
+                match ptr {
+                    DocumentPointer::Chunk(_) => {
+                        *seen_chunk = true;
+                        Some(None)
+                    }
+                    DocumentPointer::Symbol(symbol) => {
+                        if *seen_chunk {
+                            *seen_chunk = false;
+                            Some(Some(symbol.as_ref().clone()))
+                        } else {
+                            Some(None)
+                        }
+                    }
+                }
+            });
+
+            stream::iter(scaned)
+        })
+        .filter_map(|it| async {
+            if let Some(mut it) = it {
+                if need_docs {
+                    let hover = timed_stage("hover_enrichment", get_hover(lsp_server, &it)).await;
+                    if let Some((hover, hover_position)) = hover {
+                        it.set_hover(hover, hover_position);
+                    }
+                }
+                Some(it)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    (symbols, snippets)
+}
+
+/// Chunks of the file group which are not followed by any LSP symbol, like chunks of plain
+/// text or config files
+fn unmapped_chunks(group: &[DocumentPointer]) -> Vec<ChunkId> {
+    let last_symbol_line = group
+        .iter()
+        .filter_map(|pointer| match pointer {
+            DocumentPointer::Symbol(symbol) => Some(symbol.location.range.start.line as usize),
+            DocumentPointer::Chunk(_) => None,
+        })
+        .max();
+
+    group
+        .iter()
+        .filter_map(|pointer| match pointer {
+            DocumentPointer::Chunk(chunk)
+                if last_symbol_line.is_none_or(|line| chunk.start_line > line) =>
+            {
+                Some(chunk.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reads the chunk lines with `context_lines` lines above and below, the file is read with the
+/// policies of the file cache as in the chunker
+pub(crate) async fn read_chunk_snippet(
+    files: &FileCache,
+    chunk: &ChunkId,
+    context_lines: usize,
+) -> Option<ChunkSnippet> {
+    let lines = files
+        .read_lines(chunk.path.as_path())
+        .await
+        .inspect_err(|e| error!("Failed to read file {}: {}", chunk.path.display(), e))
+        .ok()?;
+    let start_line = chunk.start_line.saturating_sub(context_lines);
+    let end_line = (chunk.end_line + context_lines).min(lines.len());
+    let text = lines.get(start_line..end_line).unwrap_or_default();
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(ChunkSnippet {
+            path: chunk.path.as_path().to_path_buf(),
+            start_line,
+            end_line: start_line + text.len(),
+            text: text.join("\n"),
+            stale: false,
+            cell: notebook_cell(&chunk.path, &lines, chunk.start_line),
+            origin: match_origin(
+                &chunk.path,
+                &lines,
+                chunk.start_line..chunk.end_line.min(lines.len()),
+            ),
+        })
+    }
+}
+
+/// Code of the symbols read from their files with the positions of their names in the encoding
+/// of the LSP server if the server didn't send them, the code is capped by the limit the server
+/// is started with
+async fn update_code_and_name_position_from_document(
+    symbols: Vec<SymbolInfo>,
+    lsp_server: &GuardedLspServer,
+) -> Vec<SymbolInfo> {
+    let encoding = lsp_server.position_encoding();
+    let files = &lsp_server.workspace().file_cache;
+    let groups = symbols
+        .into_iter()
+        .into_group_map_by(|sym| sym.location.uri.clone());
+
+    let mut updated_symbols: Vec<SymbolInfo> = Vec::new();
+
+    for (url, group) in groups
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()))
+    {
+        let lines = match url_to_path(&url) {
+            Some(path) => files
+                .read_lines(&path)
+                .await
+                .inspect_err(|e| error!("Failed to read file {}: {}", path.display(), e))
+                .ok(),
+            None => None,
+        };
+        let Some(lines) = lines else {
+            updated_symbols.extend(group);
+            continue;
+        };
+        for mut symbol in group.into_iter().sorted_by(cmp_symbol_location) {
+            let start_line = symbol.location.range.start.line as usize;
+            let end_line = (symbol.location.range.end.line as usize + 1).min(lines.len());
+            let code = lines.get(start_line..end_line).unwrap_or_default();
+
+            trace!(
+                "Getting code and name position from document: {:?}, symbol: {:?}",
+                symbol.location.uri, symbol
+            );
+
+            if symbol.name_position.is_none() {
+                symbol.name_position = symbol
+                    .selection_range
+                    .map(|it| it.end)
+                    .or_else(|| find_name_position(&symbol.name, code, start_line, encoding));
+                symbol.name_position_unresolved = symbol.name_position.is_none();
+            }
+            symbol.code = Some(capped_code(code, lsp_server.max_symbol_code_bytes()));
+
+            trace!("Updated symbol: {:?}", symbol);
+            updated_symbols.push(symbol);
+        }
+    }
+
+    updated_symbols
+}
+
+/// Lines of the code joined within `max_bytes`, so a symbol of a generated file doesn't build a
+/// code string of megabytes. The longer code is cut after its last whole line within them, or
+/// inside the first line if it is longer, and the number of the cut bytes is appended
+fn capped_code(lines: &[String], max_bytes: usize) -> String {
+    let mut code = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        if code.len() + usize::from(index > 0) + line.len() <= max_bytes {
+            if index > 0 {
+                code.push('\n');
+            }
+            code.push_str(line);
+            continue;
+        }
+        if index == 0 {
+            let mut end = max_bytes.min(line.len());
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            code.push_str(&line[..end]);
+        }
+        let bytes = lines.iter().map(|it| it.len() + 1).sum::<usize>() - 1;
+        trace!(
+            "Symbol code of {} bytes is cut at {} bytes",
+            bytes,
+            code.len()
+        );
+        code.push_str(&format!(
+            "\n... {} more bytes of the symbol are cut",
+            bytes.saturating_sub(code.len())
+        ));
+        break;
+    }
+    code
+}
+
+/// Position of the last char of the name in the code starting at the line, the name is searched
+/// as is, then case-insensitively, then by its identifier tokens from the longest one, so
+/// `impl Display for Foo` or `operator==` are found by a part of their names
+fn find_name_position(
+    name: &str,
+    code: &[String],
+    start_line: usize,
+    encoding: PositionEncoding,
+) -> Option<Position> {
+    let escaped = regex::escape(name);
+    let tokens = name
+        .split(|it: char| !(it.is_alphanumeric() || it == '_'))
+        .filter(|it| !it.is_empty() && *it != name)
+        .sorted_by_key(|it| std::cmp::Reverse(it.len()))
+        .map(|it| format!(r"\b{}\b", regex::escape(it)));
+    [escaped.clone(), format!("(?i){}", escaped)]
+        .into_iter()
+        .chain(tokens)
+        .filter_map(|pattern| Regex::new(&pattern).ok())
+        .find_map(|regex| {
+            code.iter().enumerate().find_map(|(index, line)| {
+                regex
+                    .find_iter(line)
+                    .find(|m| m.start() < m.end())
+                    .map(|m| {
+                        // the last char of the name, it may take some bytes
+                        let column = encoding.column(line, m.end() - 1);
+                        Position::new((start_line + index) as u32, column)
+                    })
+            })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SymbolPlaceTo {
+    pub symbol_info: SymbolInfo,
+    /// Folder to place the symbol to, None if no better placement found
+    pub place_to: Option<String>,
+    /// Number of reference files the placement is based on
+    #[serde(default)]
+    pub reference_count: usize,
+    /// Min distance from the folder to the reference files
+    #[serde(default)]
+    pub min_distance: Option<usize>,
+    /// Max distance from the folder to the reference files
+    #[serde(default)]
+    pub max_distance: Option<usize>,
+    #[serde(default)]
+    pub confidence: PlaceConfidence,
+}
+
+impl SymbolPlaceTo {
+    pub fn new(
+        symbol_info: SymbolInfo,
+        place_to: Option<PathBuf>,
+        candidates: &[NormalizedPath],
+        depth_weighted_distance: bool,
+    ) -> Self {
+        let distances = place_to
+            .as_ref()
+            .map(|place_to| {
+                let target = normalized_components(place_to);
+                candidates
+                    .iter()
+                    .filter_map(|it| it.parent_components())
+                    .map(|it| path_distance(&target, it, depth_weighted_distance))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let min_distance = distances.iter().min().copied();
+        let max_distance = distances.iter().max().copied();
+        Self {
+            symbol_info,
+            place_to: place_to.map(|it| it.to_string_lossy().to_string()),
+            reference_count: candidates.len(),
+            min_distance,
+            max_distance,
+            confidence: place_confidence(candidates.len(), min_distance, max_distance),
+        }
+    }
+}
+
+/// Many references gathered close to the target folder make the most defensible placement,
+/// a single reference or references spread over the tree make a weak one
+fn place_confidence(
+    reference_count: usize,
+    min_distance: Option<usize>,
+    max_distance: Option<usize>,
+) -> PlaceConfidence {
+    let (Some(min_distance), Some(max_distance)) = (min_distance, max_distance) else {
+        return PlaceConfidence::Low;
+    };
+    let spread = max_distance - min_distance;
+    if reference_count >= 5 && spread <= 2 {
+        PlaceConfidence::High
+    } else if reference_count >= 2 && spread <= 4 {
+        PlaceConfidence::Medium
+    } else {
+        PlaceConfidence::Low
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SymbolReferences {
+    pub symbol_info: SymbolInfo,
+    pub references: Vec<Location>,
+}
+
+/// Symbols the placer requests the references of, the ones matching the priority patterns first,
+/// then the ones of the larger files, cut to `max_symbols`. Returns them with the number of the
+/// symbols cut off
+pub(crate) async fn placer_symbols(
+    symbols: Vec<SymbolInfo>,
+    priority: &RegexSet,
+    max_symbols: Option<usize>,
+) -> (Vec<SymbolInfo>, usize) {
+    let mut sizes = HashMap::new();
+    // the paths are collected before the awaits, the iterator of the borrowed symbols makes the
+    // future of the tool call not Send for every lifetime of the service
+    let paths = symbols
+        .iter()
+        .filter_map(|it| it.path())
+        .unique()
+        .collect::<Vec<_>>();
+    for path in paths {
+        let size = tokio::fs::metadata(&path).await.map_or(0, |it| it.len());
+        sizes.insert(path, size);
+    }
+    let max_symbols = max_symbols.unwrap_or(usize::MAX);
+    let skipped = symbols.len().saturating_sub(max_symbols);
+    let symbols = symbols
+        .into_iter()
+        .sorted_by_cached_key(|it| {
+            let size = it.path().and_then(|path| sizes.get(&path).copied());
+            (
+                !priority.is_match(&it.name),
+                std::cmp::Reverse(size.unwrap_or_default()),
+            )
+        })
+        .take(max_symbols)
+        .collect();
+    (symbols, skipped)
+}
+
+/// References of the symbols of the final kinds requested by `concurrency` at a time, the
+/// symbols whose request failed are left out
+pub fn get_symbols_references(
+    lsp_server: &GuardedLspServer,
+    symbol_infos: Vec<SymbolInfo>,
+    final_kinds: SymbolKindFilter,
+    concurrency: usize,
+) -> impl Stream<Item = SymbolReferences> + Send {
+    info!("Starting request to get symbols references");
+
+    stream::iter(symbol_infos)
+        .filter_map(move |it| {
+            let is_final = final_kinds.is_match(it.kind);
+            async move { is_final.then_some(it) }
+        })
+        .map(move |symbol_info| {
+            let guarded_lsp_server = lsp_server.clone();
+            async move {
+                guarded_lsp_server
+                    .send_references_request(
+                        symbol_info.location.uri.clone(),
+                        symbol_info
+                            .name_position
+                            .unwrap_or(symbol_info.location.range.start),
+                        false,
+                    )
+                    .await
+                    .map(|it| {
+                        it.map(|it| SymbolReferences {
+                            symbol_info: symbol_info.clone(),
+                            references: it,
+                        })
+                        .or_else(|| {
+                            Some(SymbolReferences {
+                                symbol_info: symbol_info.clone(),
+                                references: vec![],
+                            })
+                        })
+                    })
+            }
+        })
+        .buffer_unordered(concurrency)
+        .filter_map(|it| async {
+            it.inspect_err(|err| {
+                error!("Error getting symbols references: {}", err);
+            })
+            .ok()
+            .flatten()
+        })
+        .boxed()
+}
+
+/// All symbols of the document without code and hover, they are cached until the file is modified
+async fn get_document_symbols(
+    lsp_server: &GuardedLspServer,
+    document_uri: Url,
+) -> Result<Vec<SymbolInfo>> {
+    let workspace_path = workspace_root(lsp_server.workspace()).ok();
+    let workspace_path = workspace_path.as_deref();
+    let modified = match url_to_path(&document_uri) {
+        Some(path) => tokio::fs::metadata(&path)
+            .await
+            .and_then(|it| it.modified())
+            .ok(),
+        None => None,
+    };
+    let cache = lsp_server.document_symbols_cache();
+    if let Some(symbols) = modified.and_then(|modified| cache.get(&document_uri, modified)) {
+        return Ok(symbols);
+    }
+
+    info!(
+        "Sending request to get document symbols for: {}",
+        document_uri
+    );
+    let symbols = match lsp_server
+        .send_document_symbol_request(document_uri.clone())
+        .await?
+    {
+        Some(DocumentSymbolResponse::Flat(s)) => s
+            .into_iter()
+            .filter_map(|symbol| {
+                let kind = McpSymbolKind::try_from(symbol.kind).ok()?;
+                Some(
+                    SymbolInfo {
+                        name: symbol.name,
+                        kind,
+                        range: Some(symbol.location.range),
+                        location: symbol.location,
+                        container_name: symbol.container_name,
+                        code: None,
+                        hover: None,
+                        name_position: None,
+                        matched_query: None,
+                        hover_position: None,
+                        score: None,
+                        id: String::new(),
+                        selection_range: None,
+                        name_position_unresolved: false,
+                        hover_skipped: false,
+                        kind_weight: None,
+                        git: None,
+                        degraded: None,
+                    }
+                    .with_id(workspace_path),
+                )
+            })
+            .collect::<Vec<_>>(),
+        Some(DocumentSymbolResponse::Nested(s)) => s
+            .into_iter()
+            .filter_map(|symbol| {
+                let kind = McpSymbolKind::try_from(symbol.kind).ok()?;
+                let location = Location::new(document_uri.clone(), symbol.range);
+                Some(
+                    SymbolInfo {
+                        name: symbol.name,
+                        kind,
+                        location,
+                        container_name: None,
+                        code: None,
+                        hover: None,
+                        name_position: Some(symbol.selection_range.end),
+                        matched_query: None,
+                        hover_position: None,
+                        score: None,
+                        id: String::new(),
+                        range: Some(symbol.range),
+                        selection_range: Some(symbol.selection_range),
+                        name_position_unresolved: false,
+                        hover_skipped: false,
+                        kind_weight: None,
+                        git: None,
+                        degraded: None,
+                    }
+                    .with_id(workspace_path),
+                )
+            })
+            .collect::<Vec<_>>(),
+        None => vec![],
+    };
+    if let Some(modified) = modified {
+        cache.put(document_uri, modified, symbols.clone());
+    }
+    Ok(symbols)
+}
+
+pub async fn get_documents_symbols(
+    lsp_server: &GuardedLspServer,
+    documents_uris: HashSet<Url>,
+    kinds: SymbolKindFilter,
+    read_code: bool,
+) -> Vec<SymbolInfo> {
+    info!("Starting request to get document symbols");
+
+    let started = Instant::now();
+    let documents_count = documents_uris.len();
+    let symbols: Vec<SymbolInfo> = stream::iter(documents_uris)
+        .map(move |document_uri| {
+            let kinds = kinds.clone();
+            async move {
+                get_document_symbols(lsp_server, document_uri)
+                    .await
+                    .map(|symbols| {
+                        symbols
+                            .into_iter()
+                            .filter(|symbol| kinds.is_match(symbol.kind))
+                            .collect::<Vec<_>>()
+                    })
+            }
+        })
+        .buffer_unordered(lsp_server.config().parallelizm.max(1))
+        .filter_map(|it| async {
+            it.inspect_err(|err| {
+                error!("Error getting document symbols: {}", err);
+            })
+            .ok()
+        })
+        .flat_map(stream::iter)
+        .collect::<Vec<_>>()
+        .await;
+
+    debug!(
+        "Document symbols of {} documents received in {:?}",
+        documents_count,
+        started.elapsed()
+    );
+
+    let mut symbols = if read_code {
+        timed_stage(
+            "code_enrichment",
+            update_code_and_name_position_from_document(symbols, lsp_server),
+        )
+        .await
+    } else {
+        symbols
+    };
+    symbols.sort_by(cmp_symbol_location);
+    symbols
+}
+
+/// Reference location with the trimmed line of code it points to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceExcerpt {
+    pub range: Range,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileReferences {
+    pub uri: Url,
+    pub references: Vec<ReferenceExcerpt>,
+}
+
+/// References of the symbol grouped by file, `truncated` is set when only the first
+/// `limit` of `total` references are returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupedReferences {
+    pub symbol: SymbolInfo,
+    pub files: Vec<FileReferences>,
+    pub total: usize,
+    pub truncated: bool,
+}
+
+pub async fn get_grouped_references(
+    lsp_server: &GuardedLspServer,
+    symbol: SymbolInfo,
+    include_declaration: bool,
+    limit: usize,
+) -> Result<GroupedReferences> {
+    info!("Getting references of symbol: {}", symbol.name);
+
+    let mut locations = lsp_server
+        .send_references_request(
+            symbol.location.uri.clone(),
+            symbol.name_position.unwrap_or(symbol.location.range.start),
+            include_declaration,
+        )
+        .await?
+        .unwrap_or_default();
+
+    locations.sort_by(|a, b| {
+        a.uri
+            .as_str()
+            .cmp(b.uri.as_str())
+            .then_with(|| a.range.start.line.cmp(&b.range.start.line))
+            .then_with(|| a.range.start.character.cmp(&b.range.start.character))
+    });
+
+    let total = locations.len();
+    locations.truncate(limit);
+
+    let groups = locations
+        .into_iter()
+        .chunk_by(|it| it.uri.clone())
+        .into_iter()
+        .map(|(uri, group)| (uri, group.collect::<Vec<_>>()))
+        .collect::<Vec<_>>();
+
+    let mut files = Vec::new();
+    for (uri, group) in groups {
+        let lines = match url_to_path(&uri) {
+            Some(path) => lsp_server
+                .workspace()
+                .file_cache
+                .read_lines(&path)
+                .await
+                .inspect_err(|e| error!("Failed to read file {}: {}", path.display(), e))
+                .unwrap_or_default(),
+            None => Arc::default(),
+        };
+        let references = group
+            .into_iter()
+            .map(|location| ReferenceExcerpt {
+                line: lines
+                    .get(location.range.start.line as usize)
+                    .map(|line| line.trim().to_string())
+                    .unwrap_or_default(),
+                range: location.range,
+            })
+            .collect();
+        files.push(FileReferences { uri, references });
+    }
+
+    Ok(GroupedReferences {
+        symbol,
+        files,
+        total,
+        truncated: total > limit,
+    })
+}
+
+/// Symbol resolved by name with the other matched symbols it was chosen from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolvedSymbol {
+    pub symbol: SymbolInfo,
+    pub alternatives: Vec<SymbolInfo>,
+}
+
+/// Resolves the name in the file to one symbol enriched with code, hover and name position.
+/// Document symbols of the file are used first and workspace symbols as fallback, if many
+/// symbols are matched the one from the file with the shortest range is chosen
+pub async fn resolve_symbol(
+    lsp_server: &GuardedLspServer,
+    path: &Path,
+    name: &str,
+    kind_filter: Option<&SymbolKindFilter>,
+) -> Result<Option<ResolvedSymbol>> {
+    info!("Resolving symbol {} in {}", name, path.display());
+
+    let path = normalize_path(path);
+    let url = path_to_url(&path).ok_or_else(|| {
+        SemantrixError::io(
+            &path,
+            format!("Failed to convert path {} to URL", path.display()),
+        )
+    })?;
+
+    let is_matched = |symbol: &SymbolInfo| {
+        symbol.name == name
+            && kind_filter
+                .map(|kinds| kinds.is_match(symbol.kind))
+                .unwrap_or(true)
+    };
+
+    let mut candidates = get_documents_symbols(
+        lsp_server,
+        HashSet::from([url.clone()]),
+        SymbolKindFilter::default(),
+        true,
+    )
+    .await
+    .into_iter()
+    .filter(|symbol| is_matched(symbol))
+    .collect::<Vec<_>>();
+
+    if candidates.is_empty() {
+        debug!(
+            "No document symbols matched {}, trying workspace symbols",
+            name
+        );
+        let symbols = get_workspace_symbols_info(
+            lsp_server,
+            vec![name.to_string()],
+            SymbolKindFilter::default(),
+        )
+        .await
+        .into_iter()
+        .filter(|symbol| is_matched(symbol))
+        .collect::<Vec<_>>();
+        candidates = timed_stage(
+            "code_enrichment",
+            update_code_and_name_position_from_document(symbols, lsp_server),
+        )
+        .await;
+    }
+
+    candidates.sort_by_key(|symbol| {
+        let range = symbol.location.range;
+        (
+            symbol.path().as_ref() != Some(&path),
+            range.end.line.saturating_sub(range.start.line),
+            range.end.character.abs_diff(range.start.character),
+            symbol.location.uri.to_string(),
+        )
+    });
+
+    let mut candidates = candidates.into_iter();
+    let Some(mut symbol) = candidates.next() else {
+        info!("Symbol {} not found in {}", name, path.display());
+        return Ok(None);
+    };
+    let alternatives = candidates.collect::<Vec<_>>();
+    if !alternatives.is_empty() {
+        info!(
+            "Symbol {} is ambiguous, chosen: {:?}, alternatives: {:?}",
+            name, symbol, alternatives
+        );
+    }
+
+    if let Some((hover, hover_position)) =
+        timed_stage("hover_enrichment", get_hover(lsp_server, &symbol)).await
+    {
+        symbol.set_hover(hover, hover_position);
+    }
+
+    Ok(Some(ResolvedSymbol {
+        symbol,
+        alternatives,
+    }))
+}
+
+/// Positions to request hover for, in order of preference
+fn hover_positions(
+    symbol: &SymbolInfo,
+    encoding: PositionEncoding,
+) -> Vec<(Position, HoverPosition)> {
+    let start = symbol.location.range.start;
+    let first_non_whitespace = symbol
+        .code
+        .as_ref()
+        .and_then(|code| code.lines().next())
+        .and_then(|line| {
+            line.char_indices()
+                .find(|(_, c)| !c.is_whitespace())
+                .map(|(byte, _)| encoding.column(line, byte))
+        })
+        .map(|column| Position::new(start.line, column));
+
+    [
+        symbol
+            .name_position
+            .map(|position| (position, HoverPosition::Name)),
+        Some((start, HoverPosition::RangeStart)),
+        first_non_whitespace.map(|position| (position, HoverPosition::FirstNonWhitespace)),
+    ]
+    .into_iter()
+    .flatten()
+    .unique_by(|(position, _)| (position.line, position.character))
+    .collect()
+}
+
+async fn get_hover(
+    lsp_server: &GuardedLspServer,
+    symbol: &SymbolInfo,
+) -> Option<(Hover, HoverPosition)> {
+    for (position, hover_position) in hover_positions(symbol, lsp_server.position_encoding()) {
+        let hover = lsp_server
+            .send_hover_request(symbol.location.uri.clone(), position)
+            .await
+            .ok()
+            .flatten();
+        if let Some(hover) = hover {
+            info!("Hover by {}: {:?}", hover_position, hover);
+            return Some((hover, hover_position));
+        } else {
+            trace!("No hover found by {} for: {:?}", hover_position, symbol);
+        }
+    }
+    None
+}
+
+/// Workspace symbols of the names, the kinds narrow the symbols on the servers supporting it,
+/// the responses are filtered by them on the client side anyway
+pub async fn get_workspace_symbols<'a>(
+    guarded_lsp_server: &'a GuardedLspServer,
+    names: Vec<String>,
+    kinds: &'a SymbolKindFilter,
+) -> impl Stream<Item = (String, WorkspaceSymbolResponse)> + Send + 'a {
+    info!("Starting request to get workspace symbols");
+
+    if names.is_empty() {
+        if let Ok(response) = guarded_lsp_server
+            .send_workspace_symbol_request("".to_string(), kinds.lsp_kinds().as_deref())
+            .await
+        {
+            match response {
+                Some(response) => {
+                    log_kinds_reduction("", &response, kinds);
+                    let response = truncate_workspace_symbols(
+                        response,
+                        guarded_lsp_server.config().empty_query_limit,
+                    );
+                    stream::once(async { ("".to_string(), response) }).boxed()
+                }
+                None => stream::empty().boxed(),
+            }
+        } else {
+            stream::empty().boxed()
+        }
+    } else {
+        stream::iter(names.into_iter().unique())
+            .map(move |q| async move {
+                guarded_lsp_server
+                    .send_workspace_symbol_request(q.clone(), kinds.lsp_kinds().as_deref())
+                    .await
+                    .map(|it| {
+                        it.map(|response| {
+                            log_kinds_reduction(&q, &response, kinds);
+                            (q, response)
+                        })
+                    })
+            })
+            .buffer_unordered(guarded_lsp_server.config().parallelizm.max(1))
+            .filter_map(|it| async {
+                it.inspect_err(|err| {
+                    error!("Error getting workspace symbols: {}", err);
+                })
+                .ok()
+                .flatten()
+            })
+            .boxed()
+    }
+}
+
+/// Logs the symbols of the response received from the server and kept by the kinds, the kinds
+/// narrowed on the server side reduce the received ones
+fn log_kinds_reduction(query: &str, response: &WorkspaceSymbolResponse, kinds: &SymbolKindFilter) {
+    if kinds.patterns().is_empty() || !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+    let received = match response {
+        WorkspaceSymbolResponse::Flat(s) => s.iter().map(|it| it.kind).collect_vec(),
+        WorkspaceSymbolResponse::Nested(s) => s.iter().map(|it| it.kind).collect_vec(),
+    };
+    let kept = received
+        .iter()
+        .filter(|it| filter_symbols_kind(**it, kinds).is_some())
+        .count();
+    let bytes = serde_json::to_vec(response).map_or(0, |it| it.len());
+    debug!(
+        "Workspace symbols of `{}`: {} received in {} bytes, {} kept by kinds {:?}",
+        query,
+        received.len(),
+        bytes,
+        kept,
+        kinds.patterns()
+    );
+}
+
+fn truncate_workspace_symbols(
+    response: WorkspaceSymbolResponse,
+    limit: usize,
+) -> WorkspaceSymbolResponse {
+    match response {
+        WorkspaceSymbolResponse::Flat(mut s) => {
+            if s.len() > limit {
+                warn!("Workspace symbols truncated from {} to {}", s.len(), limit);
+                s.truncate(limit);
+            }
+            WorkspaceSymbolResponse::Flat(s)
+        }
+        WorkspaceSymbolResponse::Nested(mut s) => {
+            if s.len() > limit {
+                warn!("Workspace symbols truncated from {} to {}", s.len(), limit);
+                s.truncate(limit);
+            }
+            WorkspaceSymbolResponse::Nested(s)
+        }
+    }
+}
+
+/// Splits path to comparable components, the path is canonicalized (or made absolute if it
+/// does not exist) and on Windows drive letters and names are case folded, so `C:\proj\src`
+/// and `c:/proj/src` are treated as the same path
+pub fn normalized_components(path: &Path) -> Vec<String> {
+    let path = std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+
+    let fold = |s: &OsStr| {
+        if cfg!(windows) {
+            s.to_string_lossy().to_lowercase()
+        } else {
+            s.to_string_lossy().to_string()
+        }
+    };
+
+    path.components()
+        .filter_map(|component| match component {
+            Component::Prefix(prefix) => Some(match prefix.kind() {
+                Prefix::Disk(disk) | Prefix::VerbatimDisk(disk) => {
+                    format!("{}:", (disk as char).to_ascii_lowercase())
+                }
+                _ => fold(prefix.as_os_str()),
+            }),
+            Component::RootDir => Some(MAIN_SEPARATOR_STR.to_string()),
+            Component::CurDir => None,
+            Component::ParentDir => Some("..".to_string()),
+            Component::Normal(name) => Some(fold(name)),
+        })
+        .collect()
+}
+
+/// Path with its components normalized once by [`normalized_components`], so the distances of
+/// the references of a symbol don't canonicalize them again for every pair
+#[derive(Clone, Debug)]
+pub struct NormalizedPath {
+    pub path: PathBuf,
+    pub components: Vec<String>,
+}
+
+impl From<PathBuf> for NormalizedPath {
+    fn from(path: PathBuf) -> Self {
+        Self {
+            components: normalized_components(&path),
+            path,
+        }
+    }
+}
+
+impl NormalizedPath {
+    /// Components of the folder of the path, None for the root
+    pub fn parent_components(&self) -> Option<&[String]> {
+        let (_, parent) = self.components.split_last()?;
+        (!parent.is_empty()).then_some(parent)
+    }
+}
+
+/// Depth from which the not common components of the depth weighted distance cost 1. The weight
+/// of a component must drop with every level for the divergence near the leaves to stay
+/// cheaper, and the absolute paths of the sources, from the root through the workspace to the
+/// files, are far less than 32 components deep, so every real level still weighs less than the
+/// one above it. A smaller depth would flatten the weights of the deep trees, a larger one only
+/// scales all distances up
+const MAX_WEIGHTED_DEPTH: usize = 32;
+
+/// Count of not common components of two paths. If `depth_weighted` is true every not common
+/// component costs more the closer it is to the root, so divergence near the leaves is cheaper
+/// than divergence at the top of the tree. The weights depend on the absolute depth only, so
+/// the distances of the pairs of different lengths are comparable
+fn path_distance(a: &[String], b: &[String], depth_weighted: bool) -> usize {
+    let common_len = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    if depth_weighted {
+        (common_len..a.len())
+            .chain(common_len..b.len())
+            .map(|depth| MAX_WEIGHTED_DEPTH.saturating_sub(depth).max(1))
+            .sum()
+    } else {
+        (a.len() - common_len) + (b.len() - common_len)
+    }
+}
+
+fn sum_distances(candidate: &[String], usages: &[Vec<String>], depth_weighted: bool) -> usize {
+    usages
+        .iter()
+        .map(|usage| path_distance(candidate, usage, depth_weighted))
+        .sum::<usize>()
+}
+
+//TODO: no graphs for POC
+pub fn find_max_distance_paths(
+    candidates: &[NormalizedPath],
+    usages: &[NormalizedPath],
+    depth_weighted: bool,
+) -> Vec<PathBuf> {
+    let usages = usages
+        .iter()
+        .map(|usage| usage.components.clone())
+        .collect::<Vec<_>>();
+    candidates
+        .iter()
+        .max_set_by_key(|candidate| sum_distances(&candidate.components, &usages, depth_weighted))
+        .into_iter()
+        .map(|it| it.path.clone())
+        .collect::<Vec<_>>()
+}
+
+//TODO: no graphs for POC
+pub fn find_min_distance_paths(
+    candidates: &[NormalizedPath],
+    usages: &[NormalizedPath],
+    depth_weighted: bool,
+) -> Vec<PathBuf> {
+    let usages = usages
+        .iter()
+        .map(|usage| usage.components.clone())
+        .collect::<Vec<_>>();
+    candidates
+        .iter()
+        .min_set_by_key(|candidate| sum_distances(&candidate.components, &usages, depth_weighted))
+        .into_iter()
+        .map(|it| it.path.clone())
+        .collect::<Vec<_>>()
+}
+
+fn same_component(a: &Component, b: &Component) -> bool {
+    if cfg!(windows) {
+        a.as_os_str()
+            .to_string_lossy()
+            .eq_ignore_ascii_case(&b.as_os_str().to_string_lossy())
+    } else {
+        a == b
+    }
+}
+
+/// Deepest folder which is an ancestor (or the same path) of all given paths,
+/// None if paths are empty or they are on different roots/drives
+pub fn deepest_common_ancestor(paths: &[PathBuf]) -> Option<PathBuf> {
+    let (first, rest) = paths.split_first()?;
+
+    let common_len = rest
+        .iter()
+        .fold(first.components().count(), |common_len, path| {
+            first
+                .components()
+                .zip(path.components())
+                .take(common_len)
+                .take_while(|(a, b)| same_component(a, b))
+                .count()
+        });
+
+    if common_len == 0 {
+        None
+    } else {
+        Some(first.components().take(common_len).collect())
+    }
+}
+
+/// Marker of the paths outside of the workspace in the workspace relative output
+pub const EXTERNAL_PATH_MARKER: &str = "external:";
+
+/// Keys of the output objects holding paths or file URIs
+const OUTPUT_PATH_KEYS: [&str; 3] = ["uri", "path", "place_to"];
+
+/// Rewrites paths and file URIs of the output relative to the workspace, paths outside
+/// of the workspace stay absolute and are prefixed with [`EXTERNAL_PATH_MARKER`]
+pub fn relativize_output_paths(value: Value, workspace_path: &Path) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(s) if OUTPUT_PATH_KEYS.contains(&key.as_str()) => {
+                            Value::String(relativize_output_path(s, workspace_path))
+                        }
+                        value => relativize_output_paths(value, workspace_path),
+                    };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        Value::Array(array) => Value::Array(
+            array
+                .into_iter()
+                .map(|value| relativize_output_paths(value, workspace_path))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn relativize_output_path(s: String, workspace_path: &Path) -> String {
+    let path = if s.starts_with("file:") {
+        match Url::parse(&s).ok().and_then(|url| url_to_path(&url)) {
+            Some(path) => path,
+            None => return s,
+        }
+    } else {
+        PathBuf::from(&s)
+    };
+
+    if !path.is_absolute() {
+        return s;
+    }
+
+    match path.strip_prefix(workspace_path) {
+        Ok(relative) => relative.to_string_lossy().to_string(),
+        Err(_) => format!("{}{}", EXTERNAL_PATH_MARKER, path.display()),
+    }
+}
+
+const OVERVIEW_NAMES_PER_KIND: usize = 10;
+
+/// Structure of the indexed workspace: files and lines per top level directory and per file
+/// extension, the largest files and a sample of the workspace symbols grouped by kind
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkspaceOverview {
+    pub files_count: usize,
+    pub lines_count: usize,
+    pub directories: Vec<DirectoryOverview>,
+    pub languages: Vec<LanguageOverview>,
+    pub largest_files: Vec<FileOverview>,
+    /// Not set if the LSP server is not ready
+    pub symbol_kinds: Option<Vec<SymbolKindOverview>>,
+    pub generated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DirectoryOverview {
+    pub path: PathBuf,
+    pub files: usize,
+    pub lines: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LanguageOverview {
+    pub extension: String,
+    pub files: usize,
+    pub lines: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileOverview {
+    pub path: PathBuf,
+    pub lines: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SymbolKindOverview {
+    pub kind: McpSymbolKind,
+    pub count: usize,
+    /// Up to `OVERVIEW_NAMES_PER_KIND` names of the kind
+    pub names: Vec<String>,
+}
+
+/// Builds the overview from the chunks table, symbols are requested only if the LSP server is given
+pub async fn get_workspace_overview(
+    table: &Table,
+    workspace: &Workspace,
+    lsp_server: Option<&GuardedLspServer>,
+    config: &OverviewConfig,
+) -> Result<WorkspaceOverview> {
+    let line_counts = file_line_counts(table, &workspace.semantic.table).await?;
+    let workspace = workspace_root(workspace)?;
+
+    let mut directories: HashMap<PathBuf, DirectoryOverview> = HashMap::new();
+    let mut languages: HashMap<String, LanguageOverview> = HashMap::new();
+    for (path, lines) in line_counts.iter() {
+        // files of the workspace root are counted to the root itself
+        let directory = path
+            .strip_prefix(&workspace)
+            .ok()
+            .filter(|relative| relative.components().count() > 1)
+            .and_then(|relative| relative.components().next())
+            .map(|component| workspace.join(component))
+            .unwrap_or_else(|| workspace.clone());
+        let directory = directories
+            .entry(directory.clone())
+            .or_insert_with(|| DirectoryOverview {
+                path: directory,
+                ..Default::default()
+            });
+        directory.files += 1;
+        directory.lines += lines;
+
+        let extension = path
+            .extension()
+            .map(|it| it.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let language = languages
+            .entry(extension.clone())
+            .or_insert_with(|| LanguageOverview {
+                extension,
+                ..Default::default()
+            });
+        language.files += 1;
+        language.lines += lines;
+    }
+
+    let symbol_kinds = match lsp_server {
+        Some(lsp_server) => Some(get_symbol_kinds_overview(lsp_server, config).await),
+        None => None,
+    };
+
+    Ok(WorkspaceOverview {
+        files_count: line_counts.len(),
+        lines_count: line_counts.values().sum(),
+        directories: directories
+            .into_values()
+            .sorted_by(|a, b| b.lines.cmp(&a.lines).then_with(|| a.path.cmp(&b.path)))
+            .collect(),
+        languages: languages
+            .into_values()
+            .sorted_by(|a, b| {
+                b.lines
+                    .cmp(&a.lines)
+                    .then_with(|| a.extension.cmp(&b.extension))
+            })
+            .collect(),
+        largest_files: line_counts
+            .into_iter()
+            .map(|(path, lines)| FileOverview { path, lines })
+            .sorted_by(|a, b| b.lines.cmp(&a.lines).then_with(|| a.path.cmp(&b.path)))
+            .take(config.largest_files)
+            .collect(),
+        symbol_kinds,
+        generated_at: Utc::now(),
+    })
+}
+
+/// Workspace symbols of the empty query limited by `symbols_limit` grouped by kind
+async fn get_symbol_kinds_overview(
+    lsp_server: &GuardedLspServer,
+    config: &OverviewConfig,
+) -> Vec<SymbolKindOverview> {
+    let symbols = get_workspace_symbols(lsp_server, vec![], &SymbolKindFilter::default())
+        .await
+        .flat_map(|(_, response)| {
+            let symbols = match response {
+                WorkspaceSymbolResponse::Flat(symbols) => symbols
+                    .into_iter()
+                    .filter_map(|it| Some((McpSymbolKind::try_from(it.kind).ok()?, it.name)))
+                    .collect::<Vec<_>>(),
+                WorkspaceSymbolResponse::Nested(symbols) => symbols
+                    .into_iter()
+                    .filter_map(|it| Some((McpSymbolKind::try_from(it.kind).ok()?, it.name)))
+                    .collect::<Vec<_>>(),
+            };
+            stream::iter(symbols)
+        })
+        .take(config.symbols_limit)
+        .collect::<Vec<_>>()
+        .await;
+
+    symbols
+        .into_iter()
+        .into_group_map()
+        .into_iter()
+        .map(|(kind, names)| SymbolKindOverview {
+            kind,
+            count: names.len(),
+            names: names
+                .into_iter()
+                .sorted()
+                .dedup()
+                .take(OVERVIEW_NAMES_PER_KIND)
+                .collect(),
+        })
+        .sorted_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.kind.to_string().cmp(&b.kind.to_string()))
+        })
+        .collect()
+}
+
+/// Folder of the workspace by its `workspace_uri`
+pub fn workspace_root(workspace: &Workspace) -> Result<PathBuf> {
+    let url = Url::parse(&workspace.fuzzy.workspace_uri).into_diagnostic()?;
+
+    if url.scheme() != "file" {
+        return Err(SemantrixError::config(format!("Not a file URL: {}", url)).into());
+    }
+
+    url_to_path(&url)
+        .ok_or_else(|| SemantrixError::config(format!("Invalid file URL: {}", url)).into())
+}
+
+/// Folders indexed for the workspace, its own folder first, then the ones of
+/// `search.semantic.extra_roots`
+pub fn indexed_roots(workspace: &Workspace) -> Result<Vec<PathBuf>> {
+    let mut roots = vec![workspace_root(workspace)?];
+    roots.extend(
+        workspace
+            .semantic
+            .extra_roots
+            .iter()
+            .map(|root| normalize_path(&root.path)),
+    );
+    Ok(roots)
+}
+
+/// Root of `search.semantic.extra_roots` containing the path, None for the other paths
+pub fn extra_root_of(workspace: &Workspace, path: &Path) -> Option<ExtraRootConfig> {
+    workspace
+        .semantic
+        .extra_roots
+        .iter()
+        .find(|root| path.starts_with(normalize_path(&root.path)))
+        .cloned()
+}
+
+/// True if the path is in a folder of `search.semantic.extra_roots`, the LSP server doesn't
+/// know its files
+pub fn is_extra_root_path(workspace: &Workspace, path: &Path) -> bool {
+    extra_root_of(workspace, path).is_some()
+}
+
+/// Path in the form stored in the chunks table and compared with the stored ones, on Windows
+/// it has forward slashes and an upper case drive letter without the verbatim prefix
+pub fn storage_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        normalize_windows_path(&path)
+    } else {
+        path.to_string()
+    }
+}
+
+/// Windows path with forward slashes and an upper case drive letter, so `c:\proj\a.rs`,
+/// `C:/proj/a.rs` and `\\?\C:\proj\a.rs` are all `C:/proj/a.rs`. It doesn't depend on the
+/// platform it runs on
+pub fn normalize_windows_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = match path.strip_prefix("//?/") {
+        Some(verbatim) => match verbatim.strip_prefix("UNC/") {
+            Some(unc) => format!("//{}", unc),
+            None => verbatim.to_string(),
+        },
+        None => path,
+    };
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(drive), Some(':')) if drive.is_ascii_alphabetic() => {
+            format!("{}{}", drive.to_ascii_uppercase(), &path[1..])
+        }
+        _ => path,
+    }
+}
+
+/// Native path of the stored one, see [`storage_path`]
+pub fn native_path(stored: &str) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(stored.replace('/', "\\"))
+    } else {
+        PathBuf::from(stored)
+    }
+}
+
+/// Absolute path without `.` and `..` whose existing part has the symlinks resolved, with the
+/// normalized drive letter and separators, so the same file has the same path whether it comes
+/// from the watcher, the table or the LSP server
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut cleaned = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                cleaned.pop();
+            }
+            component => cleaned.push(component),
+        }
+    }
+    native_path(&storage_path(&resolve_symlinks(&cleaned)))
+}
+
+/// Path with the symlinks of its longest existing ancestor resolved, so a removed file has the
+/// path it had when it existed
+fn resolve_symlinks(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = std::fs::canonicalize(ancestor) {
+            return match path.strip_prefix(ancestor) {
+                Ok(rest) if !rest.as_os_str().is_empty() => resolved.join(rest),
+                _ => resolved,
+            };
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Normalized local path of the file URL, the percent encoded drive letter colon of some LSP
+/// servers like `file:///c%3A/proj/a.rs` is decoded
+pub fn url_to_path(url: &Url) -> Option<PathBuf> {
+    let path = match decode_drive_letter(url.path()) {
+        Some(decoded) => {
+            let mut url = url.clone();
+            url.set_path(&decoded);
+            url.to_file_path()
+        }
+        None => url.to_file_path(),
+    };
+    path.ok().map(|it| normalize_path(&it))
+}
+
+/// URL path with the encoded colon of the drive letter decoded, None if there is nothing to decode
+pub fn decode_drive_letter(url_path: &str) -> Option<String> {
+    let drive = url_path.strip_prefix('/')?.chars().next()?;
+    let rest = url_path.get(2..)?;
+    let encoded_colon = rest.get(..3)?;
+    (drive.is_ascii_alphabetic() && encoded_colon.eq_ignore_ascii_case("%3A"))
+        .then(|| format!("/{}:{}", drive, &rest[3..]))
+}
+
+/// File URL of the normalized path, see [`normalize_path`]
+pub fn path_to_url(path: &Path) -> Option<Url> {
+    Url::from_file_path(normalize_path(path)).ok()
+}
+
+/// Absolute normalized path of the workspace relative path, None if the path is outside of the workspace
+pub fn workspace_child_path(workspace_path: &Path, path: &str) -> Option<PathBuf> {
+    let mut absolute_path = PathBuf::new();
+    for component in workspace_path.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                absolute_path.pop();
+            }
+            Component::CurDir => {}
+            component => absolute_path.push(component),
+        }
+    }
+    if absolute_path.starts_with(workspace_path) {
+        Some(absolute_path)
+    } else {
+        None
+    }
+}
+
+/// Absolute normalized path of the workspace relative path or of the absolute path of an extra
+/// root, optionally prefixed with [`EXTERNAL_PATH_MARKER`] as in the results. None if the path
+/// is outside of the workspace and its extra roots
+pub fn indexed_child_path(workspace: &Workspace, path: &str) -> Option<PathBuf> {
+    let workspace_path = workspace_root(workspace).ok()?;
+    let external = path.strip_prefix(EXTERNAL_PATH_MARKER).unwrap_or(path);
+    if !Path::new(external).is_absolute() {
+        return workspace_child_path(&workspace_path, path);
+    }
+    workspace_child_path(&workspace_path, external).or_else(|| {
+        workspace
+            .semantic
+            .extra_roots
+            .iter()
+            .find_map(|root| workspace_child_path(&normalize_path(&root.path), external))
+    })
+}
+
+/// Sets the last commits of the lines of the hits, see [`git_metadata`]
+pub async fn set_hits_git_metadata(
+    config: &McpConfig,
+    workspace: &Workspace,
+    hits: &mut [SemanticHit],
+) {
+    let range = |hit: &SemanticHit| LineRange {
+        path: hit.snippet.path.clone(),
+        start_line: hit.snippet.start_line,
+        end_line: hit.snippet.end_line,
+    };
+    let metadata = git_metadata(config, workspace, hits.iter().map(range)).await;
+    for hit in hits.iter_mut() {
+        hit.git = metadata.get(&range(hit)).cloned();
+    }
+}
+
+/// Sets the last commits of the lines of the symbols, see [`git_metadata`]
+pub async fn set_symbols_git_metadata(
+    config: &McpConfig,
+    workspace: &Workspace,
+    symbols: &mut [&mut SymbolInfo],
+) {
+    let range = |symbol: &SymbolInfo| {
+        let range = symbol.range.unwrap_or(symbol.location.range);
+        Some(LineRange {
+            path: symbol.path()?,
+            start_line: range.start.line as usize,
+            end_line: range.end.line as usize + 1,
+        })
+    };
+    let metadata = git_metadata(config, workspace, symbols.iter().filter_map(|it| range(it))).await;
+    for symbol in symbols.iter_mut() {
+        symbol.git = range(symbol).and_then(|it| metadata.get(&it).cloned());
+    }
+}
+
+impl PathCaseMode {
+    /// Path folded to the lower case if the paths are case insensitive, the key of the same
+    /// file whatever casing a tool reports
+    pub fn fold(self, path: &Path) -> PathBuf {
+        if self.is_insensitive() {
+            PathBuf::from(path.to_string_lossy().to_lowercase())
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    /// Glob of the path pattern, it ignores the case of the matched paths if they are case
+    /// insensitive
+    pub fn glob(self, pattern: &str) -> Result<Glob<'static>, BuildError> {
+        if self.is_insensitive() {
+            Glob::new(&format!("(?i){}", pattern)).map(Glob::into_owned)
+        } else {
+            Glob::new(pattern).map(Glob::into_owned)
+        }
+    }
+}
+
+/// Checks path relative to the workspace (or as is if it is outside of the workspace)
+/// against the globs
+pub fn is_matched_path(path: &Path, workspace_path: &Path, globs: &[Glob]) -> bool {
+    let relative = path.strip_prefix(workspace_path).unwrap_or(path);
+    globs.iter().any(|glob| glob.is_match(relative))
+}
+
+/// Target of the walk pruned by the exclusions of the workspace
+type PruneFilter = Box<dyn Fn(&WalkEntry<'static>) -> Option<FilterTarget> + Send>;
+
+/// Streams deduplicated files of the folder matched by the semantic pattern of the workspace or
+/// by the pattern of the extra root of the folder, the walk is running in a blocking task. Watcher, chunker and tools use it to see the same
+/// file set. The files of the exclusions of the workspace are skipped, see [`excludes`]. Fails
+/// if the pattern or the exclusions can't be compiled, before the walk is started
+pub fn get_project_files(
+    workspace: &Workspace,
+    path: PathBuf,
+    channel_size: usize,
+) -> Result<BoxStream<'static, PathBuf>> {
+    info!("Start path scanner for {}", path.display());
+
+    let path_case = workspace.path_case;
+    let pattern = extra_root_of(workspace, &path)
+        .and_then(|root| root.pattern)
+        .unwrap_or_else(|| workspace.semantic.pattern.clone());
+    let positive = path_case.glob(&pattern).map_err(|e| {
+        SemantrixError::config(format!("Failed to parse files pattern {}: {}", pattern, e))
+            .with_help("fix search.semantic.pattern or the pattern of the extra root")
+    })?;
+
+    // the excluded folders are pruned, so their trees are not read. The negation of `Walk::not`
+    // matches the paths relative to the walk root, so the walks of the folders of the workspace
+    // and of the extra roots match the exclusions relative to the workspace instead
+    let exclusions = exclusion_globs(workspace);
+    let prune: PruneFilter = match workspace_root(workspace).ok() {
+        Some(workspace_path) if workspace_path == path => {
+            let negation = WalkNegation::any(exclusions.iter().cloned()).map_err(|e| {
+                SemantrixError::config(format!("Failed to combine exclusions: {}", e))
+            })?;
+            Box::new(move |entry| negation.target(entry))
+        }
+        Some(workspace_path) => Box::new(move |entry| {
+            is_matched_path(entry.path(), &workspace_path, &exclusions)
+                .then_some(FilterTarget::Tree)
+        }),
+        None => Box::new(|_| None),
+    };
+
+    let (files_tx, files_rx) = tokio::sync::mpsc::channel(channel_size);
+    tokio::task::spawn_blocking(move || {
+        let mut seen = HashSet::new();
+        for entry in positive
+            .walk(&path)
+            .filter_tree(prune)
+            .filter_map(|it| it.ok())
+            .filter(|it| it.file_type().is_file())
+        {
+            let file = entry.into_path();
+            if seen.insert(path_case.fold(&file)) {
+                trace!("File found: {:?}", file);
+                if files_tx.blocking_send(file).is_err() {
+                    debug!("Project files receiver closed");
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(stream::unfold(files_rx, |mut files_rx| async move {
+        files_rx.recv().await.map(|file| (file, files_rx))
+    })
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::templates::sample_symbol;
+
+    fn components(path: &str) -> Vec<String> {
+        path.split('/').map(str::to_string).collect()
+    }
+
+    /// Paths of up to 3 components from 2 names, so every divergence depth is covered
+    fn all_paths() -> Vec<Vec<String>> {
+        let mut paths = vec![vec![]];
+        let mut level = vec![vec![]];
+        for _ in 0..3 {
+            level = level
+                .iter()
+                .flat_map(|path: &Vec<String>| {
+                    ["a", "b"].map(|name| {
+                        let mut path = path.clone();
+                        path.push(name.to_string());
+                        path
+                    })
+                })
+                .collect();
+            paths.extend(level.clone());
+        }
+        paths
+    }
+
+    #[test]
+    fn path_distance_is_symmetric() {
+        let paths = all_paths();
+        for depth_weighted in [false, true] {
+            for a in &paths {
+                for b in &paths {
+                    assert_eq!(
+                        path_distance(a, b, depth_weighted),
+                        path_distance(b, a, depth_weighted),
+                        "{:?} {:?}",
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn path_distance_to_itself_is_zero() {
+        for depth_weighted in [false, true] {
+            for path in all_paths() {
+                assert_eq!(path_distance(&path, &path, depth_weighted), 0);
+            }
+        }
+    }
+
+    /// Pair of paths of the length whose components differ from the depth
+    fn diverged_at(len: usize, depth: usize) -> (Vec<String>, Vec<String>) {
+        let a = (0..len).map(|it| format!("c{}", it)).collect::<Vec<_>>();
+        let b = a
+            .iter()
+            .enumerate()
+            .map(|(index, it)| {
+                if index < depth {
+                    it.clone()
+                } else {
+                    format!("{}x", it)
+                }
+            })
+            .collect();
+        (a, b)
+    }
+
+    #[test]
+    fn deeper_divergence_is_not_more_expensive() {
+        for depth_weighted in [false, true] {
+            for len in 1..=6 {
+                for depth in 0..len - 1 {
+                    let (a, b) = diverged_at(len, depth);
+                    let (deeper_a, deeper_b) = diverged_at(len, depth + 1);
+                    let shallow = path_distance(&a, &b, depth_weighted);
+                    let deep = path_distance(&deeper_a, &deeper_b, depth_weighted);
+                    assert!(
+                        deep <= shallow,
+                        "{} > {} at {}/{}",
+                        deep,
+                        shallow,
+                        depth,
+                        len
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn depth_weights_do_not_depend_on_the_other_path() {
+        let base = components("a/b");
+        let short = path_distance(&base, &components("a/c"), true);
+        let long = path_distance(&base, &components("a/c/d/e"), true);
+
+        assert_eq!(
+            long - short,
+            (MAX_WEIGHTED_DEPTH - 2) + (MAX_WEIGHTED_DEPTH - 3)
+        );
+    }
+
+    #[test]
+    fn normalized_path_parent_is_its_folder() {
+        let path = NormalizedPath::from(PathBuf::from("/workspace/src/lib.rs"));
+        let folder = normalized_components(Path::new("/workspace/src"));
+
+        assert_eq!(path.parent_components(), Some(folder.as_slice()));
+        assert_eq!(
+            NormalizedPath::from(PathBuf::from("/")).parent_components(),
+            None
+        );
+    }
+
+    #[test]
+    fn no_common_ancestor_of_no_paths() {
+        assert_eq!(deepest_common_ancestor(&[]), None);
+    }
+
+    #[test]
+    fn common_ancestor_of_single_path_is_itself() {
+        let path = PathBuf::from("/workspace/src/lib.rs");
+
+        assert_eq!(
+            deepest_common_ancestor(std::slice::from_ref(&path)),
+            Some(path)
+        );
+    }
+
+    #[test]
+    fn common_ancestor_is_deepest_shared_folder() {
+        let paths = [
+            PathBuf::from("/workspace/src/services/mod.rs"),
+            PathBuf::from("/workspace/src/lib.rs"),
+            PathBuf::from("/workspace/src/services/search.rs"),
+        ];
+
+        assert_eq!(
+            deepest_common_ancestor(&paths),
+            Some(PathBuf::from("/workspace/src"))
+        );
+    }
+
+    #[test]
+    fn no_common_ancestor_of_paths_on_different_roots() {
+        let relative = [PathBuf::from("src/lib.rs"), PathBuf::from("tests/e2e.rs")];
+        let mixed = [
+            PathBuf::from("/workspace/src"),
+            PathBuf::from("workspace/src"),
+        ];
+
+        assert_eq!(deepest_common_ancestor(&relative), None);
+        assert_eq!(deepest_common_ancestor(&mixed), None);
+    }
+
+    #[test]
+    fn query_variants_split_cases() {
+        let cases: [(&str, &[&str]); 6] = [
+            (
+                "http client retry",
+                &[
+                    "http_client_retry",
+                    "HttpClientRetry",
+                    "httpclientretry",
+                    "http",
+                    "client",
+                    "retry",
+                ],
+            ),
+            (
+                "parseHttpRequest",
+                &["parse_http_request", "ParseHttpRequest"],
+            ),
+            ("HTTPClient", &["http_client", "HttpClient"]),
+            ("get_user", &["GetUser"]),
+            (
+                "http-client",
+                &["http_client", "HttpClient", "http", "client"],
+            ),
+            ("retry", &["Retry"]),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(query_variants(query, 3, 10), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn query_variants_skip_words_up_to_min_word_len() {
+        let cases: [(usize, &[&str]); 3] = [
+            (
+                1,
+                &[
+                    "io_read_file",
+                    "IoReadFile",
+                    "ioreadfile",
+                    "io",
+                    "read",
+                    "file",
+                ],
+            ),
+            (
+                3,
+                &["io_read_file", "IoReadFile", "ioreadfile", "read", "file"],
+            ),
+            (4, &["io_read_file", "IoReadFile", "ioreadfile"]),
+        ];
+        for (min_word_len, expected) in cases {
+            assert_eq!(
+                query_variants("io read file", min_word_len, 10),
+                expected,
+                "{}",
+                min_word_len
+            );
+        }
+    }
+
+    #[test]
+    fn query_variants_are_bounded_by_max_query_variants() {
+        let cases: [(usize, &[&str]); 3] = [
+            (0, &[]),
+            (2, &["http_client_retry", "HttpClientRetry"]),
+            (
+                4,
+                &[
+                    "http_client_retry",
+                    "HttpClientRetry",
+                    "httpclientretry",
+                    "http",
+                ],
+            ),
+        ];
+        for (limit, expected) in cases {
+            assert_eq!(
+                query_variants("http client retry", 3, limit),
+                expected,
+                "{}",
+                limit
+            );
+        }
+    }
+
+    #[test]
+    fn edit_distance_counts_char_edits() {
+        let cases = [
+            ("", "", 0),
+            ("abc", "", 3),
+            ("", "abc", 3),
+            ("same", "same", 0),
+            ("kitten", "sitting", 3),
+            ("flaw", "lawn", 2),
+            ("résumé", "resume", 2),
+            ("Client", "client", 1),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(edit_distance(a, b), expected, "{} {}", a, b);
+            assert_eq!(edit_distance(b, a), expected, "{} {}", b, a);
+        }
+    }
+
+    #[test]
+    fn windows_paths_have_one_form() {
+        let cases = [
+            (r"c:\proj\a.rs", "C:/proj/a.rs"),
+            ("C:/proj/a.rs", "C:/proj/a.rs"),
+            (r"\\?\C:\proj\a.rs", "C:/proj/a.rs"),
+            (r"\\server\share\a.rs", "//server/share/a.rs"),
+            (r"\\?\UNC\server\share\a.rs", "//server/share/a.rs"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalize_windows_path(path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn encoded_drive_letter_colon_is_decoded() {
+        let cases = [
+            ("/c%3A/proj/a.rs", Some("/c:/proj/a.rs")),
+            ("/C%3a/proj/a.rs", Some("/C:/proj/a.rs")),
+            ("/C:/proj/a.rs", None),
+            ("/proj/a.rs", None),
+            ("/1%3A/proj/a.rs", None),
+        ];
+        for (url_path, expected) in cases {
+            assert_eq!(
+                decode_drive_letter(url_path).as_deref(),
+                expected,
+                "{}",
+                url_path
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn file_urls_of_one_windows_file_have_one_path() {
+        let expected = PathBuf::from(r"C:\semantrix-missing\a.rs");
+        for url in [
+            "file:///C:/semantrix-missing/a.rs",
+            "file:///c:/semantrix-missing/a.rs",
+            "file:///c%3A/semantrix-missing/a.rs",
+            "file:///C:/semantrix-missing/src/../a.rs",
+        ] {
+            assert_eq!(
+                url_to_path(&Url::parse(url).unwrap()),
+                Some(expected.clone()),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn unc_file_url_keeps_its_server() {
+        let url = Url::parse("file://server/share/a.rs").unwrap();
+
+        assert_eq!(
+            url_to_path(&url),
+            Some(PathBuf::from(r"\\server\share\a.rs"))
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn file_url_path_is_cleaned() {
+        let root = normalize_path(Path::new(env!("CARGO_MANIFEST_DIR")));
+        let url = Url::parse(&format!(
+            "file://{}/src/../src/./lib.rs",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+
+        assert_eq!(url_to_path(&url), Some(root.join("src/lib.rs")));
+    }
+
+    /// Hit of the chunk with the ranks of the hits merged into it
+    fn hit(path: &str, start_line: usize, end_line: usize, rank: usize) -> (ChunkId, Vec<usize>) {
+        (
+            ChunkId::new(Arc::new(PathBuf::from(path)), start_line, end_line),
+            vec![rank],
+        )
+    }
+
+    /// Ranges and ranks of the merged hits
+    fn merged(
+        hits: Vec<(ChunkId, Vec<usize>)>,
+        min_overlap: f64,
+    ) -> Vec<(String, usize, usize, Vec<usize>)> {
+        merge_overlapping(
+            hits,
+            min_overlap,
+            |it| &mut it.0,
+            |kept, it| kept.1.extend(it.1),
+        )
+        .into_iter()
+        .map(|(chunk, ranks)| {
+            (
+                chunk.path.display().to_string(),
+                chunk.start_line,
+                chunk.end_line,
+                ranks,
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn overlapping_hits_of_one_file_are_merged() {
+        let cases = [
+            (
+                "adjacent",
+                vec![hit("a.rs", 0, 10, 1), hit("a.rs", 10, 20, 2)],
+                vec![("a.rs", 0, 10, vec![1]), ("a.rs", 10, 20, vec![2])],
+            ),
+            (
+                "nested",
+                vec![hit("a.rs", 5, 10, 1), hit("a.rs", 0, 20, 2)],
+                vec![("a.rs", 0, 20, vec![1, 2])],
+            ),
+            (
+                "overlapping",
+                vec![hit("a.rs", 0, 10, 1), hit("a.rs", 4, 14, 2)],
+                vec![("a.rs", 0, 14, vec![1, 2])],
+            ),
+            (
+                "overlapping less than min_overlap",
+                vec![hit("a.rs", 0, 10, 1), hit("a.rs", 6, 16, 2)],
+                vec![("a.rs", 0, 10, vec![1]), ("a.rs", 6, 16, vec![2])],
+            ),
+            (
+                "disjoint",
+                vec![hit("a.rs", 20, 30, 1), hit("a.rs", 0, 10, 2)],
+                vec![("a.rs", 20, 30, vec![1]), ("a.rs", 0, 10, vec![2])],
+            ),
+            (
+                "merged into the better hit",
+                vec![
+                    hit("a.rs", 0, 10, 1),
+                    hit("a.rs", 30, 40, 2),
+                    hit("a.rs", 2, 12, 3),
+                ],
+                vec![("a.rs", 0, 12, vec![1, 3]), ("a.rs", 30, 40, vec![2])],
+            ),
+        ];
+        for (name, hits, expected) in cases {
+            let expected = expected
+                .into_iter()
+                .map(|(path, start, end, ranks)| (path.to_string(), start, end, ranks))
+                .collect_vec();
+            assert_eq!(merged(hits, 0.5), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn hits_of_different_files_are_not_merged() {
+        let hits = vec![
+            hit("a.rs", 0, 10, 1),
+            hit("b.rs", 0, 10, 2),
+            hit("b.rs", 5, 10, 3),
+            hit("a.rs", 2, 8, 4),
+        ];
+
+        assert_eq!(
+            merged(hits, 0.5),
+            vec![
+                ("a.rs".to_string(), 0, 10, vec![1, 4]),
+                ("b.rs".to_string(), 0, 10, vec![2, 3]),
+            ]
+        );
+    }
+
+    /// Ruleset of the structs of every path with a rule listing their names
+    fn structs_ruleset() -> Ruleset {
+        serde_yaml::from_str(
+            r#"
+common: []
+depends_on:
+  - kind: ["Struct"]
+    name: [".*"]
+    path: ["**"]
+    code: [".*"]
+    rules: ["{% for symbol in symbols %}{{ symbol.name }};{% endfor %}"]
+"#,
+        )
+        .unwrap()
+    }
+
+    /// Symbol of a class of a dependency jar, JDT-LS reports them by `jar:` URIs
+    fn jar_symbol() -> SymbolInfo {
+        let mut symbol = sample_symbol();
+        symbol.name = "Decoder".to_string();
+        symbol.id = "jar".to_string();
+        symbol.location.uri = Url::parse("jar:file:///deps/codec.jar!/org/Decoder.class").unwrap();
+        symbol
+    }
+
+    #[test]
+    fn symbol_without_local_path_matches_no_path_pattern() {
+        let symbol = jar_symbol();
+
+        let checked = structs_ruleset().depends_on[0]
+            .check(&symbol, Path::new("rules.yml"), PathCaseMode::Sensitive)
+            .unwrap();
+
+        assert_eq!(symbol.path(), None);
+        assert!(checked.kind && checked.name && checked.code);
+        assert!(!checked.path);
+    }
+
+    #[test]
+    fn rules_of_mixed_symbols_leave_out_the_jar_one() {
+        let mut local = sample_symbol();
+        local.id = "local".to_string();
+
+        let rules = structs_ruleset()
+            .get_rules(vec![jar_symbol(), local])
+            .unwrap();
+
+        assert_eq!(rule_texts(&rules), ["Sample;"]);
+        assert_eq!(rules[0].symbols, ["local"]);
+    }
+
+    #[test]
+    fn hot_file_leaves_places_to_weaker_files() {
+        let ranked = [
+            ("hot.rs", 1),
+            ("hot.rs", 2),
+            ("hot.rs", 3),
+            ("hot.rs", 4),
+            ("warm.rs", 5),
+            ("hot.rs", 6),
+            ("cold.rs", 7),
+        ];
+
+        let page = limit_per_file(ranked, Some(2), |(path, _)| Some(PathBuf::from(path)))
+            .into_iter()
+            .take(4)
+            .collect_vec();
+
+        assert_eq!(
+            page,
+            [("hot.rs", 1), ("hot.rs", 2), ("warm.rs", 5), ("cold.rs", 7)]
+        );
+    }
+
+    #[test]
+    fn unlimited_files_and_pathless_items_are_kept() {
+        let ranked = [
+            (Some("hot.rs"), 1),
+            (None, 2),
+            (Some("hot.rs"), 3),
+            (None, 4),
+        ];
+        let path = |(path, _): &(Option<&str>, usize)| path.map(PathBuf::from);
+
+        assert_eq!(limit_per_file(ranked, None, path), ranked);
+        assert_eq!(
+            limit_per_file(ranked, Some(1), path),
+            [(Some("hot.rs"), 1), (None, 2), (None, 4)]
+        );
+    }
+
+    #[test]
+    fn case_insensitive_globs_and_keys_ignore_the_case() {
+        let insensitive = PathCaseMode::Insensitive;
+        let glob = insensitive.glob("src/**/*.rs").unwrap();
+
+        assert!(glob.is_match(Path::new("SRC/Services/Mod.RS")));
+        assert!(glob.is_match(Path::new("src/services/mod.rs")));
+        assert_eq!(
+            insensitive.fold(Path::new("/ws/SRC/Foo.RS")),
+            insensitive.fold(Path::new("/ws/src/foo.rs"))
+        );
+
+        let sensitive = PathCaseMode::Sensitive;
+        assert!(
+            !sensitive
+                .glob("src/**/*.rs")
+                .unwrap()
+                .is_match(Path::new("SRC/Foo.RS"))
+        );
+        assert_ne!(
+            sensitive.fold(Path::new("/ws/SRC/Foo.RS")),
+            sensitive.fold(Path::new("/ws/src/foo.rs"))
+        );
+    }
 }
@@ -0,0 +1,78 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use rmcp::model::{JsonRpcMessage, JsonRpcResponse, RequestId, ServerJsonRpcMessage, ServerResult};
+use serde::Serialize;
+use serde_json::Value;
+use strum::IntoEnumIterator;
+
+use crate::enums::McpToolName;
+
+/// Identity of the tool call, the id of its JSON-RPC request attached to the log lines of the
+/// call. It is sent as `_meta` of the result
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolCallMeta {
+    pub request_id: String,
+    /// True if the result is reused from an identical earlier call
+    pub cached: bool,
+}
+
+/// Hints of the tool for the clients deciding whether its calls need a confirmation
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolAnnotations {
+    pub read_only_hint: bool,
+    pub destructive_hint: bool,
+    pub idempotent_hint: bool,
+    pub open_world_hint: bool,
+}
+
+/// Metas of the tool call results of one session by the request ids. rmcp 0.1.5 has neither
+/// `_meta` of the results nor the annotations of the tools, so the transports add both to the
+/// messages while they are serialized
+#[derive(Clone, Debug, Default)]
+pub struct ResponseMetas(Arc<Mutex<HashMap<RequestId, ToolCallMeta>>>);
+
+impl ResponseMetas {
+    /// Remembers the meta of the result of the call answered next
+    pub fn remember(&self, id: RequestId, meta: ToolCallMeta) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, meta);
+    }
+
+    /// Message as it is sent: the result of a tool call gets its meta, which is forgotten, and
+    /// every listed tool gets its annotations
+    pub fn encode(&self, message: ServerJsonRpcMessage) -> serde_json::Result<Value> {
+        let (meta, lists_tools) = match &message {
+            JsonRpcMessage::Response(JsonRpcResponse { id, result, .. }) => match result {
+                ServerResult::CallToolResult(_) => (
+                    self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(id),
+                    false,
+                ),
+                ServerResult::ListToolsResult(_) => (None, true),
+                _ => (None, false),
+            },
+            _ => (None, false),
+        };
+        let mut message = serde_json::to_value(message)?;
+        if let Some(meta) = meta {
+            message["result"]["_meta"] = serde_json::to_value(meta)?;
+        }
+        let tools = message
+            .pointer_mut("/result/tools")
+            .and_then(Value::as_array_mut)
+            .filter(|_| lists_tools);
+        for tool in tools.into_iter().flatten() {
+            let name = tool.get("name").and_then(Value::as_str).unwrap_or_default();
+            if let Some(tool_name) = McpToolName::iter().find(|it| it.to_string() == name) {
+                tool["annotations"] = serde_json::to_value(tool_name.annotations())?;
+            }
+        }
+        Ok(message)
+    }
+}
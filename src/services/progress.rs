@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use rmcp::{
+    Peer, RoleServer,
+    model::{ProgressNotificationParam, ProgressToken, RequestId},
+};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Method of the requests whose progress tokens are remembered
+const CALL_TOOL_METHOD: &str = "tools/call";
+
+/// Progress tokens of the tools/call requests of one session by the request ids. rmcp 0.1.5
+/// drops `_meta` while parsing `CallToolRequestParam`, so the transports remember the tokens of
+/// the raw messages before they are parsed
+#[derive(Clone, Debug, Default)]
+pub struct ProgressTokens(Arc<Mutex<HashMap<RequestId, ProgressToken>>>);
+
+impl ProgressTokens {
+    /// Remembers `_meta.progressToken` of the raw tools/call request, other messages are skipped
+    pub fn remember(&self, message: &Value) {
+        if message.get("method").and_then(Value::as_str) != Some(CALL_TOOL_METHOD) {
+            return;
+        }
+        let (Some(id), Some(token)) = (
+            message.get("id"),
+            message.pointer("/params/_meta/progressToken"),
+        ) else {
+            return;
+        };
+        let (Ok(id), Ok(token)) = (
+            serde_json::from_value::<RequestId>(id.clone()),
+            serde_json::from_value::<ProgressToken>(token.clone()),
+        ) else {
+            return;
+        };
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, token);
+    }
+
+    /// Progress token of the request, it is forgotten, so a reused request id doesn't get it
+    pub fn take(&self, id: &RequestId) -> Option<ProgressToken> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(id)
+    }
+}
+
+/// Sender of the progress notifications of one tool call, they are sent in the order they are
+/// reported by a task which ends with the call
+#[derive(Clone, Debug)]
+pub struct Progress {
+    tx: mpsc::UnboundedSender<(u32, Option<u32>)>,
+}
+
+impl Progress {
+    /// Progress sending the notifications of the token to the client, None if the client didn't
+    /// ask for them
+    pub fn new(peer: Peer<RoleServer>, token: Option<ProgressToken>) -> Option<Self> {
+        let token = token?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<(u32, Option<u32>)>();
+        tokio::spawn(async move {
+            while let Some((progress, total)) = rx.recv().await {
+                let notified = peer
+                    .notify_progress(ProgressNotificationParam {
+                        progress_token: token.clone(),
+                        progress,
+                        total,
+                    })
+                    .await;
+                if let Err(e) = notified {
+                    debug!("Progress notifications are stopped: {:?}", e);
+                    break;
+                }
+            }
+        });
+        Some(Self { tx })
+    }
+}
+
+tokio::task_local! {
+    /// Progress of the tool call running in the task, None if the client didn't ask for it
+    pub static PROGRESS: Option<Progress>;
+}
+
+/// Reports the progress of the tool call of the task, nothing is sent outside of the tool calls
+/// or if the client didn't ask for the progress
+pub fn report_progress(progress: usize, total: Option<usize>) {
+    let _ = PROGRESS.try_with(|it| {
+        if let Some(it) = it {
+            let _ = it.tx.send((
+                progress.min(u32::MAX as usize) as u32,
+                total.map(|it| it.min(u32::MAX as usize) as u32),
+            ));
+        }
+    });
+}
@@ -0,0 +1,128 @@
+use std::{fmt, sync::Arc, time::Instant};
+
+use fastembed::TextRerank;
+use itertools::Itertools;
+use once_cell::sync::OnceCell;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    RerankerConfig, Workspace, load_reranker,
+    services::{audit::record_stage, read_chunk_snippet},
+    subsystems::chunker::ChunkId,
+};
+
+/// Cross-encoder of `search.semantic.reranker`, loaded on the first search and shared by the
+/// workspaces of one instance. The search goes on without reranking if it is not configured or
+/// its files can't be loaded
+pub struct Reranker {
+    config: Option<RerankerConfig>,
+    model: OnceCell<Option<Arc<TextRerank>>>,
+}
+
+impl fmt::Debug for Reranker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reranker")
+            .field("config", &self.config)
+            .field("loaded", &self.model.get().is_some_and(Option::is_some))
+            .finish()
+    }
+}
+
+impl Reranker {
+    pub fn new(config: Option<RerankerConfig>) -> Self {
+        Self {
+            config,
+            model: OnceCell::new(),
+        }
+    }
+
+    /// Number of the chunks of every query the vector search should return for the reranker,
+    /// zero without the reranker
+    pub fn candidates(&self) -> usize {
+        self.config
+            .as_ref()
+            .map(|it| it.candidates)
+            .unwrap_or_default()
+    }
+
+    fn model(&self) -> Option<Arc<TextRerank>> {
+        self.model
+            .get_or_init(|| {
+                let config = self.config.as_ref()?;
+                load_reranker(config)
+                    .inspect(|_| info!("Reranker loaded from {:?}", config.model_file))
+                    .inspect_err(|e| warn!("Reranker is disabled: {:?}", e))
+                    .ok()
+                    .map(Arc::new)
+            })
+            .clone()
+    }
+}
+
+/// Chunks reordered by the cross-encoder score of the query and the chunk text by the reranker
+/// of the workspace, only the first `candidates` chunks are scored and the others follow them
+/// in their order. The chunks are returned as is without the reranker or if the scoring fails
+pub async fn rerank_chunks(
+    workspace: &Workspace,
+    query: &str,
+    mut chunks: Vec<ChunkId>,
+) -> Vec<ChunkId> {
+    let candidates = workspace.reranker.candidates().min(chunks.len());
+    if candidates == 0 {
+        return chunks;
+    }
+    let started = Instant::now();
+    let mut texts = Vec::with_capacity(candidates);
+    for chunk in &chunks[..candidates] {
+        texts.push(
+            read_chunk_snippet(&workspace.file_cache, chunk, 0)
+                .await
+                .map(|it| it.text)
+                .unwrap_or_default(),
+        );
+    }
+    let query = query.to_string();
+    let reranker = workspace.reranker.clone();
+    // the model is loaded by the first search, so the blocking task loads it
+    let scored = tokio::task::spawn_blocking(move || {
+        reranker.model().map(|reranker| {
+            reranker.rerank(
+                query.as_str(),
+                texts.iter().map(String::as_str).collect(),
+                false,
+                None,
+            )
+        })
+    })
+    .await;
+    let results = match scored {
+        Ok(Some(Ok(results))) => results,
+        Ok(None) => return chunks,
+        Ok(Some(Err(e))) => {
+            error!("Failed to rerank chunks: {}", e);
+            return chunks;
+        }
+        Err(e) => {
+            error!("Reranker task failed: {}", e);
+            return chunks;
+        }
+    };
+    let elapsed = started.elapsed();
+    record_stage("rerank", elapsed);
+    debug!(
+        "Reranked {} chunks in {} ms",
+        candidates,
+        elapsed.as_millis()
+    );
+
+    let rest = chunks.split_off(candidates);
+    let mut slots = chunks.into_iter().map(Some).collect_vec();
+    let mut reranked = results
+        .into_iter()
+        .sorted_by(|a, b| b.score.total_cmp(&a.score))
+        .filter_map(|it| slots.get_mut(it.index).and_then(Option::take))
+        .collect_vec();
+    reranked.extend(slots.into_iter().flatten());
+    reranked.extend(rest);
+    reranked
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use lancedb::{Connection, Table};
+use miette::{IntoDiagnostic, Result};
+use rig::embeddings::EmbeddingsBuilder;
+use tokio::sync::OnceCell;
+use tracing::{debug, info, warn};
+
+use crate::{
+    ScratchConfig, SemanticConfig, Workspace,
+    enums::PathCaseMode,
+    errors::SemantrixError,
+    init_table,
+    repositories::{backend::VectorBackend, embedding::IndexEmbeddingModel},
+    services::file_cache::FileCache,
+    subsystems::chunker::{ArcTextChunk, EmbeddingText, chunk_lines},
+};
+
+/// Prefix of the synthetic paths of the scratch buffers, `scratch://<name>`
+pub const SCRATCH_SCHEME: &str = "scratch:";
+
+/// Synthetic path of the buffer, it is stored in the chunks like the path of a file
+pub fn scratch_path(name: &str) -> PathBuf {
+    PathBuf::from(format!("{}//{}", SCRATCH_SCHEME, name))
+}
+
+/// Name of the buffer of the synthetic path, None for the paths of the files. The separators
+/// are skipped, so the path stored on Windows has the same name
+pub fn scratch_name(path: &Path) -> Option<String> {
+    path.to_str()?
+        .strip_prefix(SCRATCH_SCHEME)
+        .map(|name| name.trim_start_matches(['/', '\\']).to_string())
+}
+
+pub fn is_scratch_path(path: &Path) -> bool {
+    scratch_name(path).is_some()
+}
+
+/// Buffer of the scratch table
+struct ScratchBuffer {
+    indexed_at: Instant,
+    /// Ids of the chunks of the buffer, deleted when it expires or is indexed again
+    ids: Vec<String>,
+}
+
+/// Buffers sent by the clients before they are saved, indexed by the default model into their
+/// own table, so they never mix with the chunks of the workspace files. The table is created by
+/// the first indexed buffer and dropped on shutdown, the buffers expire after `ttl_sec`
+pub struct ScratchIndex {
+    db: Connection,
+    semantic: SemanticConfig,
+    embedding_model: IndexEmbeddingModel,
+    ndims: usize,
+    model_id: String,
+    config: ScratchConfig,
+    /// Cache the snippets of the scratch chunks are read from instead of the files
+    files: Arc<FileCache>,
+    store: OnceCell<(Table, Arc<dyn VectorBackend>)>,
+    buffers: Mutex<HashMap<String, ScratchBuffer>>,
+}
+
+impl ScratchIndex {
+    /// Index of the buffers with the semantic config and the file cache of the workspace
+    pub fn new(
+        db: Connection,
+        workspace: &Workspace,
+        embedding_model: IndexEmbeddingModel,
+        ndims: usize,
+        model_id: String,
+        config: ScratchConfig,
+    ) -> Self {
+        let mut semantic = workspace.semantic.clone();
+        semantic.table.name = format!("{}_scratch", semantic.table.name);
+        semantic.index_embeddings = false;
+        semantic.index_fts = false;
+        Self {
+            db,
+            semantic,
+            embedding_model,
+            ndims,
+            model_id,
+            config,
+            files: workspace.file_cache.clone(),
+            store: OnceCell::new(),
+            buffers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Table of the buffers, the table left by a process which was not shut down is dropped
+    /// before it is created again
+    async fn store(&self) -> Result<&(Table, Arc<dyn VectorBackend>)> {
+        self.store
+            .get_or_try_init(|| async {
+                let name = &self.semantic.table.name;
+                if self
+                    .db
+                    .table_names()
+                    .execute()
+                    .await
+                    .into_diagnostic()?
+                    .contains(name)
+                {
+                    info!("Dropping scratch table {} left by the previous run", name);
+                    self.db.drop_table(name).await.into_diagnostic()?;
+                }
+                init_table(
+                    &self.db,
+                    &self.semantic,
+                    &self.embedding_model,
+                    self.ndims,
+                    &self.model_id,
+                    // the names of the buffers are compared as given
+                    PathCaseMode::Sensitive,
+                    false,
+                )
+                .await
+            })
+            .await
+    }
+
+    /// Chunks the content like a file and replaces the chunks of the buffer of the name by
+    /// them, returns the number of the indexed chunks
+    pub async fn index(&self, name: &str, content: &str) -> Result<usize> {
+        if content.len() > self.config.max_bytes {
+            return Err(SemantrixError::config(format!(
+                "Scratch buffer {} has {} bytes, more than {} of scratch.max_bytes",
+                name,
+                content.len(),
+                self.config.max_bytes
+            ))
+            .into());
+        }
+        self.expire().await;
+
+        let lines = content.lines().map(str::to_string).collect_vec();
+        let chunks = chunk_lines(
+            Arc::new(scratch_path(name)),
+            &lines,
+            self.semantic.chunk_size,
+            self.semantic.overlap_size,
+        );
+        let ids = chunks.iter().map(|it| it.id.to_hash()).collect_vec();
+        // the buffers are outside of the workspace, so their paths are rendered as they are
+        let embedding_text = EmbeddingText::new(&self.semantic, None)?;
+        let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
+            .documents(
+                chunks
+                    .into_iter()
+                    .map(|it| ArcTextChunk::from(it).with_embedding_text(embedding_text.clone()))
+                    .collect_vec(),
+            )
+            .into_diagnostic()?
+            .build()
+            .await
+            .into_diagnostic()?;
+
+        let (_, backend) = self.store().await?;
+        let previous = self
+            .buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.remove(name))
+            .map(|it| it.ids)
+            .unwrap_or_default();
+        let removed = previous
+            .into_iter()
+            .filter(|id| !ids.contains(id))
+            .collect_vec();
+        backend.delete_by_ids(&removed).await?;
+        backend.upsert(embeddings).await?;
+
+        self.files.put_scratch(name, lines);
+        let count = ids.len();
+        if let Ok(mut buffers) = self.buffers.lock() {
+            buffers.insert(
+                name.to_string(),
+                ScratchBuffer {
+                    indexed_at: Instant::now(),
+                    ids,
+                },
+            );
+        }
+        debug!("Indexed {} chunks of scratch buffer {}", count, name);
+        Ok(count)
+    }
+
+    /// Deletes the chunks and the lines of the buffers indexed more than `ttl_sec` ago
+    pub async fn expire(&self) {
+        let ttl = Duration::from_secs(self.config.ttl_sec);
+        let expired = match self.buffers.lock() {
+            Ok(mut buffers) => {
+                let names = buffers
+                    .iter()
+                    .filter(|(_, buffer)| buffer.indexed_at.elapsed() >= ttl)
+                    .map(|(name, _)| name.clone())
+                    .collect_vec();
+                names
+                    .into_iter()
+                    .filter_map(|name| buffers.remove(&name).map(|buffer| (name, buffer)))
+                    .collect_vec()
+            }
+            Err(_) => return,
+        };
+        if expired.is_empty() {
+            return;
+        }
+        self.files
+            .forget_scratch(expired.iter().map(|(name, _)| name));
+        let ids = expired
+            .into_iter()
+            .flat_map(|(_, buffer)| buffer.ids)
+            .collect_vec();
+        if let Some((_, backend)) = self.store.get() {
+            match backend.delete_by_ids(&ids).await {
+                Ok(deleted) => debug!("Deleted {} chunks of expired scratch buffers", deleted),
+                Err(e) => warn!("Failed to delete chunks of expired scratch buffers: {}", e),
+            }
+        }
+    }
+
+    /// Table of the live buffers, None if no buffer is indexed
+    pub async fn table(&self) -> Option<Table> {
+        self.expire().await;
+        if self.is_empty() {
+            return None;
+        }
+        self.store.get().map(|(table, _)| table.clone())
+    }
+
+    /// Backend of the buffers for the searches of the chunks, None if no buffer is indexed
+    pub async fn backend(&self) -> Option<Arc<dyn VectorBackend>> {
+        self.expire().await;
+        if self.is_empty() {
+            return None;
+        }
+        self.store.get().map(|(_, backend)| backend.clone())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.lock().map(|it| it.is_empty()).unwrap_or(true)
+    }
+
+    /// Drops the table and forgets the buffers, called on shutdown
+    pub async fn purge(&self) {
+        let names = match self.buffers.lock() {
+            Ok(mut buffers) => buffers.drain().map(|(name, _)| name).collect_vec(),
+            Err(_) => Vec::new(),
+        };
+        self.files.forget_scratch(&names);
+        if self.store.get().is_some() {
+            let name = &self.semantic.table.name;
+            match self.db.drop_table(name).await {
+                Ok(_) => info!("Dropped scratch table {}", name),
+                Err(e) => warn!("Failed to drop scratch table {}: {}", name, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,916 @@
+use std::{
+    collections::HashSet,
+    path::{self, Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use futures::{
+    StreamExt,
+    future::{self, try_join_all},
+};
+use itertools::Itertools;
+use lsp_types::Location;
+use miette::Result;
+use notify_debouncer_full::notify::{
+    EventKind,
+    event::{ModifyKind, RemoveKind},
+};
+use regex::RegexSet;
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, error, info, warn};
+use wax::Glob;
+
+use crate::{
+    McpConfig, Workspace,
+    errors::SemantrixError,
+    repositories::{backend::VectorBackend, cached_count_chunks},
+    services::{
+        ChunkSnippet, DegradedCounts, DegradedSymbols, FileResults, FusedSymbol, NormalizedPath,
+        RuleEntry, Ruleset, SymbolInfo, SymbolKindFilter, SymbolPlaceTo, SymbolsPage,
+        audit::{record_stage, timed_stage},
+        cmp_symbol_location, deepest_common_ancestor, enrich_hovers,
+        expansion::expand_queries,
+        find_max_distance_paths, find_min_distance_paths, fuse_symbols, get_documents_symbols,
+        get_fuzzy_symbols, get_project_files, get_semantic_symbols, get_symbols_references,
+        group_by_file, indexed_child_path, is_matched_path, limit_per_file,
+        mcp::{
+            CodeReuseSearchRequest, EnrichSymbolsRequest, PlacerSkipped, SearchCursor, SearchPage,
+            SearchWarning, SymbolReference, SymbolsPlacerRequest, ToolTimeout, UnresolvedSymbol,
+        },
+        path_to_url, placer_symbols,
+        progress::report_progress,
+        rule_texts, set_symbols_git_metadata, update_code_and_name_position_from_document,
+        url_to_path, workspace_root,
+    },
+    subsystems::{WorkspacePipeline, lsp::GuardedLspServer, watcher::PathEvent},
+};
+
+/// Stages of `code_reuse_search` reported as its progress: the searches, the git metadata and
+/// the rules
+const CODE_REUSE_STAGES: usize = 3;
+
+/// Results of `code_reuse_search`, the symbols and the snippets of a search which failed or was
+/// cut off by the timeout are None
+#[derive(Debug)]
+pub struct CodeReuseSearchResult {
+    pub semantic_rules: Vec<String>,
+    pub fuzzy_rules: Vec<String>,
+    pub semantic_rules_detailed: Vec<RuleEntry>,
+    pub fuzzy_rules_detailed: Vec<RuleEntry>,
+    pub semantic_symbols: Option<Vec<SymbolInfo>>,
+    pub fuzzy_symbols: Option<Vec<SymbolInfo>>,
+    pub semantic_snippets: Option<Vec<ChunkSnippet>>,
+    pub semantic_has_more: bool,
+    pub fuzzy_has_more: bool,
+    /// Symbols of both searches ranked by the reciprocal rank fusion
+    pub fused_symbols: Vec<FusedSymbol>,
+    /// Fused symbols and snippets grouped by their files
+    pub files: Vec<FileResults>,
+    pub warnings: Vec<SearchWarning>,
+    pub page: SearchPage,
+    pub timeout: ToolTimeout,
+    /// Versions of the tables the semantic search read, see [`pin_store`]
+    pub snapshot_versions: Option<Vec<u64>>,
+    pub degraded: DegradedCounts,
+}
+
+/// Results of `symbols_placer`
+#[derive(Debug)]
+pub struct SymbolsPlacerResult {
+    pub rules: Vec<String>,
+    pub rules_detailed: Vec<RuleEntry>,
+    /// Symbols of the files in the path globs matching the name patterns
+    pub symbols: Vec<SymbolInfo>,
+    /// Suggested folders of the symbols above the confidence floor, the most confident first
+    pub places: Vec<SymbolPlaceTo>,
+    /// Number of the suggested folders before the confidence floor
+    pub all_places_count: usize,
+    pub skipped: PlacerSkipped,
+    pub degraded: DegradedCounts,
+}
+
+/// Results of `enrich_symbols`
+#[derive(Debug)]
+pub struct EnrichSymbolsResult {
+    pub rules: Vec<String>,
+    pub rules_detailed: Vec<RuleEntry>,
+    /// Found symbols with their code, hover and last commit ordered by their locations
+    pub symbols: Vec<SymbolInfo>,
+    pub not_found: Vec<UnresolvedSymbol>,
+}
+
+/// Store pinned to the current versions of its tables for the searches of one tool call with
+/// the versions, the store itself without the versions if it can't pin them
+pub async fn pin_store(
+    vector_store: Arc<dyn VectorBackend>,
+) -> (Arc<dyn VectorBackend>, Option<Vec<u64>>) {
+    match timed_stage("snapshot", vector_store.snapshot()).await {
+        Ok(Some(snapshot)) => {
+            debug!("Searching the tables at versions {:?}", snapshot.versions);
+            (snapshot.backend, Some(snapshot.versions))
+        }
+        Ok(None) => (vector_store, None),
+        Err(e) => {
+            warn!(
+                "Tables are not pinned, searching their current versions: {}",
+                e
+            );
+            (vector_store, None)
+        }
+    }
+}
+
+/// Why the chunks table of the workspace may be empty, the empty semantic results don't mean
+/// that nothing similar exists in the project then
+pub fn empty_index_message(pipeline: &WorkspacePipeline) -> String {
+    let workspace = &pipeline.workspace;
+    let reason = if *pipeline.first_index_scan.borrow() {
+        format!(
+            "no file of workspace {} matches pattern `{}` or indexing of the matched files failed, see the server log",
+            workspace.fuzzy.workspace_uri, workspace.semantic.pattern
+        )
+    } else {
+        "the initial indexing is still running".to_string()
+    };
+    format!(
+        "Semantic index is empty: {}. Empty semantic results don't mean that nothing similar exists in the project",
+        reason
+    )
+}
+
+/// Queues the files of the stale snippets for reindexing, the files are skipped if the queue
+/// is full, the next search queues them again. Nothing is queued in the read-only mode, the
+/// stale files are reindexed by the process which writes the index
+pub fn reindex_stale<'a>(
+    pipeline: &WorkspacePipeline,
+    snippets: impl Iterator<Item = &'a ChunkSnippet>,
+) {
+    if pipeline.read_only {
+        return;
+    }
+    for path in snippets
+        .filter(|it| it.stale)
+        .map(|it| it.path.clone())
+        .unique()
+    {
+        let kind = if path.exists() {
+            EventKind::Modify(ModifyKind::Any)
+        } else {
+            EventKind::Remove(RemoveKind::Any)
+        };
+        info!("Queueing stale file {} for reindexing", path.display());
+        if let Err(e) = pipeline.path_event_tx.try_send(Arc::new(PathEvent {
+            path: Arc::new(path),
+            kind,
+        })) {
+            warn!("Failed to queue stale file for reindexing: {}", e);
+        }
+    }
+}
+
+/// Warning of the semantic results found in the empty chunks table
+pub async fn empty_index_warning(pipeline: &WorkspacePipeline) -> Option<SearchWarning> {
+    let chunks = try_join_all(
+        pipeline
+            .routes
+            .tables()
+            .iter()
+            .map(|it| cached_count_chunks(&it.table)),
+    )
+    .await
+    .map(|counts| counts.into_iter().sum::<usize>())
+    .inspect_err(|e| error!("Failed to count chunks: {}", e))
+    .ok()?;
+    (chunks == 0).then(|| SearchWarning {
+        source: "semantic".to_string(),
+        message: empty_index_message(pipeline),
+    })
+}
+
+/// Fuzzy and semantic symbols of the request with their rules, the fused ranking and the page.
+/// The searches run until `timeout_ms`, a failed or cut off search leaves a warning or a timed
+/// out stage and the results of the other one are returned
+pub async fn code_reuse_search(
+    lsp_server: &GuardedLspServer,
+    config: &McpConfig,
+    pipeline: &WorkspacePipeline,
+    rules: &Ruleset,
+    request: CodeReuseSearchRequest,
+    timeout_ms: u64,
+) -> Result<CodeReuseSearchResult> {
+    request
+        .validate()
+        .map_err(SemantrixError::invalid_request)?;
+    let CodeReuseSearchRequest {
+        semantic_queries,
+        name_patterns,
+        offset,
+        cursor,
+        include_docs,
+        include_code,
+        max_results_per_file,
+        group_by_file: _,
+        exclude_comment_only_matches,
+    } = request;
+    let max_results_per_file = max_results_per_file.or(config.search.max_results_per_file);
+
+    let cursor = match cursor {
+        Some(cursor) => cursor
+            .parse::<SearchCursor>()
+            .map_err(SemantrixError::invalid_request)?,
+        None => SearchCursor {
+            semantic_offset: offset.unwrap_or_default(),
+            fuzzy_offset: offset.unwrap_or_default(),
+        },
+    };
+
+    let include_docs = include_docs.unwrap_or(true);
+    let include_code = include_code.unwrap_or(true);
+
+    info!("Starting to get symbols from {}", cursor);
+
+    let mut warnings = Vec::new();
+    let (semantic_queries, name_patterns) = match expand_queries(
+        config.query_expansion.as_ref(),
+        semantic_queries.clone(),
+        name_patterns.clone(),
+    ) {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            warn!("Queries are not expanded: {}", e);
+            warnings.push(SearchWarning {
+                source: "query_expansion".to_string(),
+                message: e.to_string(),
+            });
+            (semantic_queries, name_patterns)
+        }
+    };
+
+    let (vector_store, snapshot_versions) = pin_store(pipeline.vector_store.clone()).await;
+
+    // the empty workspace symbol query returns every symbol, it is not a name search
+    let skip_fuzzy = name_patterns.is_empty();
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    let (fuzzy_result, semantic_result) = tokio::join!(
+        timeout_at(deadline, async {
+            if skip_fuzzy {
+                return Ok((Vec::new(), false));
+            }
+            timed_stage(
+                "fuzzy",
+                get_fuzzy_symbols(
+                    lsp_server,
+                    config,
+                    name_patterns,
+                    SymbolKindFilter::default(),
+                    include_docs,
+                    include_code,
+                    SymbolsPage {
+                        offset: cursor.fuzzy_offset,
+                        max_per_file: max_results_per_file,
+                    },
+                ),
+            )
+            .await
+        }),
+        timeout_at(
+            deadline,
+            timed_stage(
+                "semantic",
+                get_semantic_symbols(
+                    lsp_server,
+                    config,
+                    semantic_queries,
+                    vector_store,
+                    include_docs,
+                    include_code,
+                    SymbolsPage {
+                        offset: cursor.semantic_offset,
+                        max_per_file: max_results_per_file,
+                    },
+                )
+            )
+        ),
+    );
+
+    report_progress(1, Some(CODE_REUSE_STAGES));
+
+    let mut timeout = ToolTimeout::default();
+    if skip_fuzzy {
+        warnings.push(SearchWarning {
+            source: "fuzzy".to_string(),
+            message: "name_patterns is empty, the fuzzy search is skipped".to_string(),
+        });
+    }
+
+    let (mut fuzzy_symbols, fuzzy_has_more) = match fuzzy_result {
+        Ok(Ok((symbols, has_more))) => (Some(symbols), has_more),
+        Ok(Err(e)) => {
+            error!("Error getting fuzzy symbols: {}", e);
+            warnings.push(SearchWarning {
+                source: "fuzzy".to_string(),
+                message: e.to_string(),
+            });
+            (None, false)
+        }
+        Err(_) => {
+            timeout.cut_off("fuzzy", timeout_ms);
+            (None, false)
+        }
+    };
+
+    let (mut semantic_symbols, semantic_snippets, semantic_has_more) = match semantic_result {
+        Ok(Ok((symbols, snippets, has_more))) => (
+            Some(
+                symbols
+                    .into_iter()
+                    .unique_by(|it| it.id.clone())
+                    .collect::<Vec<_>>(),
+            ),
+            Some(snippets),
+            has_more,
+        ),
+        Ok(Err(e)) => {
+            error!("Error getting semantic symbols: {}", e);
+            let message = if pipeline.recovery.recover_if_lost().await {
+                format!(
+                    "{}, the chunks table was deleted outside of semantrix and is reindexed",
+                    e
+                )
+            } else {
+                e.to_string()
+            };
+            warnings.push(SearchWarning {
+                source: "semantic".to_string(),
+                message,
+            });
+            (None, None, false)
+        }
+        Err(_) => {
+            timeout.cut_off("semantic", timeout_ms);
+            (None, None, false)
+        }
+    };
+    if semantic_snippets.as_ref().is_some_and(Vec::is_empty) {
+        warnings.extend(empty_index_warning(pipeline).await);
+    }
+    reindex_stale(pipeline, semantic_snippets.iter().flatten());
+    let semantic_snippets = match exclude_comment_only_matches {
+        Some(true) => semantic_snippets.map(|snippets| {
+            snippets
+                .into_iter()
+                .filter(|it| !it.is_comment_only())
+                .collect_vec()
+        }),
+        _ => semantic_snippets,
+    };
+    let mut symbols = fuzzy_symbols
+        .iter_mut()
+        .chain(semantic_symbols.iter_mut())
+        .flatten()
+        .collect::<Vec<_>>();
+    set_symbols_git_metadata(config, &pipeline.workspace, &mut symbols).await;
+    report_progress(2, Some(CODE_REUSE_STAGES));
+
+    let page = SearchPage {
+        next_cursor: (fuzzy_has_more || semantic_has_more).then(|| {
+            SearchCursor {
+                semantic_offset: cursor.semantic_offset + config.search.semantic.search_limit,
+                fuzzy_offset: cursor.fuzzy_offset + config.search.fuzzy.fuzzy_limit,
+            }
+            .to_string()
+        }),
+    };
+
+    debug!(
+        "Fuzzy symbols: {:?}, semantic symbols: {:?}",
+        fuzzy_symbols, semantic_symbols
+    );
+
+    let rules_started = Instant::now();
+    let semantic_rules_detailed = rules.get_rules(semantic_symbols.clone().unwrap_or_default())?;
+    let fuzzy_rules_detailed = rules.get_rules(fuzzy_symbols.clone().unwrap_or_default())?;
+    let semantic_rules = rule_texts(&semantic_rules_detailed);
+    let fuzzy_rules = rule_texts(&fuzzy_rules_detailed);
+    record_stage("rules", rules_started.elapsed());
+    report_progress(3, Some(CODE_REUSE_STAGES));
+
+    let fusion = &config.search.fusion;
+    let mut fused_symbols = limit_per_file(
+        fuse_symbols(
+            fuzzy_symbols.as_deref().unwrap_or_default(),
+            semantic_symbols.as_deref().unwrap_or_default(),
+            fusion.k,
+            &config.search.kind_weights,
+        ),
+        max_results_per_file,
+        |it| it.symbol.path(),
+    );
+    let degraded = DegradedSymbols::new(
+        fused_symbols
+            .iter()
+            .map(|it| &it.symbol)
+            .chain(fuzzy_symbols.iter().flatten())
+            .chain(semantic_symbols.iter().flatten()),
+        config.search.response_budget_chars,
+    );
+    fused_symbols
+        .iter_mut()
+        .for_each(|it| degraded.apply(&mut it.symbol));
+    fuzzy_symbols
+        .iter_mut()
+        .chain(semantic_symbols.iter_mut())
+        .flatten()
+        .for_each(|it| degraded.apply(it));
+    let files = group_by_file(
+        &fused_symbols,
+        semantic_snippets.as_deref().unwrap_or_default(),
+    );
+    let (fuzzy_symbols, semantic_symbols) = if fusion.fused_only {
+        (
+            fuzzy_symbols.map(|_| Vec::new()),
+            semantic_symbols.map(|_| Vec::new()),
+        )
+    } else {
+        (fuzzy_symbols, semantic_symbols)
+    };
+
+    Ok(CodeReuseSearchResult {
+        semantic_rules,
+        fuzzy_rules,
+        semantic_rules_detailed,
+        fuzzy_rules_detailed,
+        semantic_symbols,
+        fuzzy_symbols,
+        semantic_snippets,
+        semantic_has_more,
+        fuzzy_has_more,
+        fused_symbols,
+        files,
+        warnings,
+        page,
+        timeout,
+        snapshot_versions,
+        degraded: degraded.counts(),
+    })
+}
+
+/// Symbols of the files in the path globs matching the name patterns with their rules and the
+/// folders suggested by their references. The references are requested until `budget_ms`, the
+/// symbols which were not answered by then are counted as skipped
+pub async fn symbols_placer(
+    lsp_server: &GuardedLspServer,
+    config: &McpConfig,
+    workspace: &Workspace,
+    rules: &Ruleset,
+    request: SymbolsPlacerRequest,
+) -> Result<SymbolsPlacerResult> {
+    request
+        .validate()
+        .map_err(SemantrixError::invalid_request)?;
+    let SymbolsPlacerRequest {
+        path_globs,
+        symbol_name_patterns,
+        include_low_confidence,
+        priority_patterns,
+        max_symbols,
+        budget_ms,
+    } = request;
+
+    let scope_globs = path_globs
+        .iter()
+        .map(|pattern| workspace.path_case.glob(pattern))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            SemantrixError::invalid_request(format!("Failed to parse path globs: {}", e))
+        })?;
+    let symbol_names = RegexSet::new(&symbol_name_patterns).map_err(|e| {
+        SemantrixError::invalid_request(format!("Failed to parse symbol name patterns: {}", e))
+    })?;
+    let priority = RegexSet::new(&priority_patterns).map_err(|e| {
+        SemantrixError::invalid_request(format!("Failed to parse priority patterns: {}", e))
+    })?;
+
+    info!("Starting to get symbols");
+
+    let workspace_path = workspace_root(workspace)?;
+
+    let modules_symbols =
+        get_project_files(workspace, workspace_path.clone(), config.channel_size)?
+            .filter(|it| {
+                let in_scope =
+                    scope_globs.is_empty() || is_matched_path(it, &workspace_path, &scope_globs);
+                async move { in_scope }
+            })
+            .filter_map(|it| async move { path_to_url(&it) })
+            .collect::<HashSet<_>>()
+            .await;
+
+    debug!("Found modules symbols: {:?}", modules_symbols);
+
+    let mut symbols = get_documents_symbols(
+        lsp_server,
+        modules_symbols,
+        config.placer.final_symbol_kinds.clone(),
+        true,
+    )
+    .await
+    .into_iter()
+    .filter(|it| symbol_names.is_empty() || symbol_names.is_match(&it.name))
+    .collect::<Vec<_>>();
+
+    debug!("Found symbols: {:?}", symbols);
+
+    let ignore_globs = config
+        .placer
+        .ignore_reference_globs
+        .iter()
+        .map(|pattern| workspace.path_case.glob(pattern))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            SemantrixError::config(format!("Failed to parse ignore reference globs: {}", e))
+        })?;
+    let ignore_globs = &ignore_globs;
+    let workspace_path = &workspace_path;
+
+    let (analyzed, by_limit) = placer_symbols(
+        symbols.clone(),
+        &priority,
+        max_symbols.or(config.placer.max_symbols),
+    )
+    .await;
+    let analyzed_count = analyzed.len();
+    let budget_ms = budget_ms.or(config.placer.budget_ms);
+    let budget_spent = AtomicBool::new(false);
+    let budget = async {
+        match budget_ms {
+            Some(budget_ms) => {
+                tokio::time::sleep(Duration::from_millis(budget_ms)).await;
+                budget_spent.store(true, Ordering::Relaxed);
+            }
+            None => future::pending().await,
+        }
+    };
+    let mut answered = 0;
+    let concurrency = config
+        .placer
+        .reference_concurrency(config.search.fuzzy.parallelizm);
+
+    let places: Vec<SymbolPlaceTo> = get_symbols_references(
+        lsp_server,
+        analyzed,
+        config.placer.final_symbol_kinds.clone(),
+        concurrency,
+    )
+    .take_until(budget)
+    .inspect(|_| {
+        answered += 1;
+        report_progress(answered, Some(analyzed_count));
+    })
+    .filter_map(|it| async move {
+        if it.references.is_empty() {
+            debug!("No references found for symbol: {:?}", it.symbol_info);
+            return None;
+        }
+
+        let source = it.symbol_info.path().and_then(|it| path::absolute(it).ok());
+        let candidates = reference_candidates(
+            &it.references,
+            source.as_deref(),
+            workspace_path,
+            ignore_globs,
+        );
+
+        if candidates.is_empty() {
+            debug!(
+                "No reference candidates left after filtering for symbol: {:?}",
+                it.symbol_info
+            );
+            return Some(SymbolPlaceTo::new(
+                it.symbol_info,
+                None,
+                &candidates,
+                config.placer.depth_weighted_distance,
+            ));
+        }
+
+        let place_to = if config.placer.use_max_distance {
+            find_max_distance_paths(
+                &candidates,
+                &candidates,
+                config.placer.depth_weighted_distance,
+            )
+        } else {
+            find_min_distance_paths(
+                &candidates,
+                &candidates,
+                config.placer.depth_weighted_distance,
+            )
+        };
+
+        if place_to.is_empty() {
+            None
+        } else {
+            let Some(absolute_target) = target_folder(&place_to) else {
+                debug!(
+                    "No parent folder of the places of symbol: {:?}",
+                    it.symbol_info
+                );
+                return None;
+            };
+
+            debug!(
+                "For symbol: {:?} absolute target: {}",
+                it.symbol_info,
+                absolute_target.display()
+            );
+
+            let path = url_to_path(&it.symbol_info.location.uri)?;
+            let absolute_source = path::absolute(&path).ok()?;
+            // the symbol already in the target folder has no better placement
+            if absolute_source.parent() == Some(absolute_target.as_path()) {
+                return None;
+            }
+            Some(SymbolPlaceTo::new(
+                it.symbol_info,
+                Some(absolute_target),
+                &candidates,
+                config.placer.depth_weighted_distance,
+            ))
+        }
+    })
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .unique_by(|it| it.symbol_info.id.clone())
+    .collect::<Vec<_>>();
+    let skipped = PlacerSkipped {
+        by_limit,
+        by_budget: if budget_spent.load(Ordering::Relaxed) {
+            analyzed_count.saturating_sub(answered)
+        } else {
+            0
+        },
+    };
+    if skipped.by_limit > 0 || skipped.by_budget > 0 {
+        info!(
+            "Placer skipped {} symbols by max_symbols and {} symbols by budget_ms",
+            skipped.by_limit, skipped.by_budget
+        );
+    }
+    let all_places_count = places.len();
+    let min_confidence = config.placer.min_confidence;
+    // the symbols without a better placement have no confidence to rate, they are always kept
+    let mut places = places
+        .into_iter()
+        .filter(|it| {
+            include_low_confidence || it.place_to.is_none() || it.confidence >= min_confidence
+        })
+        .sorted_by(|a, b| {
+            b.confidence
+                .cmp(&a.confidence)
+                .then_with(|| b.reference_count.cmp(&a.reference_count))
+                // the references are answered in any order
+                .then_with(|| cmp_symbol_location(&a.symbol_info, &b.symbol_info))
+        })
+        .collect::<Vec<_>>();
+
+    debug!("Places: {:?}", places);
+
+    let rules_started = Instant::now();
+    let rules_detailed = rules.get_rules(symbols.clone())?;
+    let rules = rule_texts(&rules_detailed);
+    record_stage("rules", rules_started.elapsed());
+
+    let degraded = DegradedSymbols::new(
+        places
+            .iter()
+            .map(|it| &it.symbol_info)
+            .chain(symbols.iter()),
+        config.search.response_budget_chars,
+    );
+    symbols.iter_mut().for_each(|it| degraded.apply(it));
+    places
+        .iter_mut()
+        .for_each(|it| degraded.apply(&mut it.symbol_info));
+
+    Ok(SymbolsPlacerResult {
+        rules,
+        rules_detailed,
+        symbols,
+        places,
+        all_places_count,
+        skipped,
+        degraded: degraded.counts(),
+    })
+}
+
+/// Absolute paths of the references of the symbol which may suggest its folder: the references
+/// of the other files outside of the ignored globs. The references without a local path are
+/// skipped, the paths are normalized once for all distances of the symbol
+fn reference_candidates(
+    references: &[Location],
+    source: Option<&Path>,
+    workspace_path: &Path,
+    ignore_globs: &[Glob],
+) -> Vec<NormalizedPath> {
+    references
+        .iter()
+        .filter_map(|it| {
+            let Some(path) = url_to_path(&it.uri) else {
+                debug!("Skipped reference of not file URI: {}", it.uri);
+                return None;
+            };
+            path::absolute(&path)
+                .inspect_err(|e| debug!("Skipped reference of path {}: {}", path.display(), e))
+                .ok()
+        })
+        .filter(|it| source != Some(it.as_path()))
+        .filter(|it| !is_matched_path(it, workspace_path, ignore_globs))
+        .map(NormalizedPath::from)
+        .collect()
+}
+
+/// Deepest folder containing all places, None if none of them has a parent folder
+fn target_folder(place_to: &[PathBuf]) -> Option<PathBuf> {
+    let parents = place_to
+        .iter()
+        .filter_map(|it| path::absolute(it).ok())
+        .filter_map(|it| it.parent().map(|it| it.to_path_buf()))
+        .collect::<Vec<_>>();
+    deepest_common_ancestor(&parents).or_else(|| parents.first().cloned())
+}
+
+/// Symbols of the references with their code, hover, last commit and rules. The symbols are
+/// found again in the document symbols of their files, so the search which returned them is not
+/// run again
+pub async fn enrich_symbols(
+    lsp_server: &GuardedLspServer,
+    config: &McpConfig,
+    workspace: &Workspace,
+    rules: &Ruleset,
+    request: EnrichSymbolsRequest,
+) -> Result<EnrichSymbolsResult> {
+    request
+        .validate()
+        .map_err(SemantrixError::invalid_request)?;
+
+    let mut not_found = Vec::new();
+    let mut references = Vec::new();
+    for reference in request.symbols {
+        let url = indexed_child_path(workspace, &reference.path)
+            .filter(|it| it.is_file())
+            .and_then(|it| path_to_url(&it));
+        match url {
+            Some(url) => references.push((reference, url)),
+            None => not_found.push(UnresolvedSymbol {
+                reference,
+                reason: "file not found".to_string(),
+            }),
+        }
+    }
+
+    let documents = get_documents_symbols(
+        lsp_server,
+        references.iter().map(|(_, url)| url.clone()).collect(),
+        SymbolKindFilter::default(),
+        false,
+    )
+    .await;
+    let mut symbols = Vec::new();
+    for (reference, url) in references {
+        let document = documents
+            .iter()
+            .filter(|it| it.location.uri == url)
+            .collect_vec();
+        match resolve_reference(&reference, &document) {
+            Some(symbol) => symbols.push(symbol.clone()),
+            None => not_found.push(UnresolvedSymbol {
+                reference,
+                reason: "symbol not found".to_string(),
+            }),
+        }
+    }
+    let symbols = symbols
+        .into_iter()
+        .unique_by(|it| (it.id.clone(), it.location.range.start.line))
+        .collect_vec();
+    info!(
+        "Enriching {} symbols, {} are not found",
+        symbols.len(),
+        not_found.len()
+    );
+
+    let symbols = timed_stage(
+        "code_enrichment",
+        update_code_and_name_position_from_document(symbols, lsp_server),
+    )
+    .await;
+    let mut symbols = timed_stage(
+        "hover_enrichment",
+        enrich_hovers(lsp_server, config, symbols),
+    )
+    .await;
+    set_symbols_git_metadata(config, workspace, &mut symbols.iter_mut().collect_vec()).await;
+
+    let rules_started = Instant::now();
+    let rules_detailed = rules.get_rules(symbols.clone())?;
+    let rules = rule_texts(&rules_detailed);
+    record_stage("rules", rules_started.elapsed());
+
+    Ok(EnrichSymbolsResult {
+        rules,
+        rules_detailed,
+        symbols,
+        not_found,
+    })
+}
+
+/// Symbol of the document the reference points to by its id, or by its lines if the id is not
+/// found: the symbol starting at the first line and else the shortest one containing the lines
+fn resolve_reference<'a>(
+    reference: &SymbolReference,
+    document: &[&'a SymbolInfo],
+) -> Option<&'a SymbolInfo> {
+    if let Some(symbol) = reference
+        .id
+        .as_ref()
+        .and_then(|id| document.iter().find(|it| &it.id == id))
+    {
+        return Some(symbol);
+    }
+    let start_line = reference.start_line?;
+    let end_line = reference.end_line.unwrap_or(start_line);
+    document
+        .iter()
+        .copied()
+        .filter(|it| {
+            let range = it.location.range;
+            range.start.line <= start_line && end_line <= range.end.line
+        })
+        .min_by_key(|it| {
+            let range = it.location.range;
+            (
+                range.start.line != start_line,
+                range.end.line.saturating_sub(range.start.line),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{Range, Url};
+
+    use super::*;
+    use crate::enums::PathCaseMode;
+
+    fn location(uri: &str) -> Location {
+        Location {
+            uri: Url::parse(uri).unwrap(),
+            range: Range::default(),
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn references_without_local_path_are_skipped() {
+        let references = [
+            "jar:file:///ws/lib.jar!/org/A.class",
+            "untitled:Untitled-1",
+            "file:///ws/src/app/orders.rs",
+            "file:///ws/src/lib.rs",
+            "file:///ws/target/gen.rs",
+        ]
+        .map(location);
+        let ignore_globs = [PathCaseMode::Sensitive.glob("target/**").unwrap()];
+
+        let candidates = reference_candidates(
+            &references,
+            Some(Path::new("/ws/src/lib.rs")),
+            Path::new("/ws"),
+            &ignore_globs,
+        );
+
+        let paths = candidates.iter().map(|it| it.path.clone()).collect_vec();
+        assert_eq!(paths, [PathBuf::from("/ws/src/app/orders.rs")]);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn root_place_has_no_target_folder() {
+        assert_eq!(target_folder(&[PathBuf::from("/")]), None);
+        assert_eq!(target_folder(&[]), None);
+        assert_eq!(
+            target_folder(&[
+                PathBuf::from("/"),
+                PathBuf::from("/ws/src/app/orders.rs"),
+                PathBuf::from("/ws/src/app/http/client.rs"),
+            ]),
+            Some(PathBuf::from("/ws/src/app"))
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use axum::{
+    Json, Router,
+    extract::{Query, Request, State},
+    http::{StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, Sse},
+    },
+    routing::{get, post},
+};
+use futures::{SinkExt, Stream, StreamExt};
+use miette::{IntoDiagnostic, Result};
+use rmcp::{
+    ServiceExt,
+    model::{ClientJsonRpcMessage, ServerJsonRpcMessage},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc};
+use subtle::ConstantTimeEq;
+use tokio::{
+    net::TcpListener,
+    sync::{RwLock, mpsc},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::{CancellationToken, PollSender};
+use tracing::{error, info, warn};
+
+use crate::services::{mcp::McpService, outbound::ResponseMetas, progress::ProgressTokens};
+
+/// Senders of the messages of the sessions with the progress tokens of their calls
+type Sessions =
+    Arc<RwLock<HashMap<Arc<str>, (mpsc::Sender<ClientJsonRpcMessage>, ProgressTokens)>>>;
+
+/// SSE endpoints of the MCP server, the same protocol as rmcp `SseServer` serves, but with
+/// the bearer token check which can't be added to the router of rmcp 0.1.5
+#[derive(Clone)]
+struct SseState {
+    service: McpService,
+    sessions: Sessions,
+    post_path: Arc<str>,
+    auth_token: Option<Arc<str>>,
+    ct: CancellationToken,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PostEventQuery {
+    session_id: String,
+}
+
+/// Serves every SSE client with a clone of the service until the token is cancelled
+pub async fn serve_sse(
+    service: McpService,
+    bind: SocketAddr,
+    sse_path: &str,
+    post_path: &str,
+    auth_token: Option<String>,
+    ct: CancellationToken,
+) -> Result<()> {
+    let state = SseState {
+        service,
+        sessions: Arc::default(),
+        post_path: post_path.into(),
+        auth_token: auth_token.map(Arc::from),
+        ct: ct.clone(),
+    };
+    let router = Router::new()
+        .route(sse_path, get(sse_handler))
+        .route(post_path, post(post_event_handler))
+        .layer(middleware::from_fn_with_state(state.clone(), check_token))
+        .with_state(state);
+
+    let listener = TcpListener::bind(bind).await.into_diagnostic()?;
+    info!("MCP SSE server listening on {}{}", bind, sse_path);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(ct.cancelled_owned())
+        .await
+        .into_diagnostic()
+}
+
+/// Rejects requests without the configured bearer token before they reach the sessions
+async fn check_token(State(state): State<SseState>, request: Request, next: Next) -> Response {
+    if let Some(auth_token) = &state.auth_token {
+        let authorized = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|it| it.to_str().ok())
+            .and_then(|it| it.strip_prefix("Bearer "))
+            // the comparison takes the same time for every token of the length, so the token
+            // can't be guessed by the timings of the rejections
+            .is_some_and(|it| bool::from(it.as_bytes().ct_eq(auth_token.as_bytes())));
+        if !authorized {
+            warn!("Rejected unauthorized request to {}", request.uri());
+            return (StatusCode::UNAUTHORIZED, "Missing or wrong bearer token").into_response();
+        }
+    }
+    next.run(request).await
+}
+
+async fn sse_handler(
+    State(state): State<SseState>,
+) -> Sse<impl Stream<Item = Result<Event, io::Error>>> {
+    let session: Arc<str> = format!("{:016x}", rand::random::<u128>()).into();
+    let channel_size = state.service.config.channel_size;
+    let (from_client_tx, from_client_rx) = mpsc::channel(channel_size);
+    let (to_client_tx, to_client_rx) = mpsc::channel::<ServerJsonRpcMessage>(channel_size);
+    // the request ids are unique in a session only, so every session has its own tokens and
+    // metas
+    let progress_tokens = ProgressTokens::default();
+    let response_metas = ResponseMetas::default();
+    state
+        .sessions
+        .write()
+        .await
+        .insert(session.clone(), (from_client_tx, progress_tokens.clone()));
+    info!("SSE session {} connected", session);
+
+    let endpoint = format!("{}?sessionId={}", state.post_path, session);
+    tokio::spawn(serve_session(
+        state,
+        session,
+        progress_tokens,
+        response_metas.clone(),
+        PollSender::new(to_client_tx),
+        ReceiverStream::new(from_client_rx),
+    ));
+
+    let stream = futures::stream::once(futures::future::ok(
+        Event::default().event("endpoint").data(endpoint),
+    ))
+    .chain(ReceiverStream::new(to_client_rx).map(move |message| {
+        response_metas
+            .encode(message)
+            .map(|data| Event::default().event("message").data(data.to_string()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }));
+    Sse::new(stream)
+}
+
+async fn serve_session(
+    state: SseState,
+    session: Arc<str>,
+    progress_tokens: ProgressTokens,
+    response_metas: ResponseMetas,
+    sink: PollSender<ServerJsonRpcMessage>,
+    stream: ReceiverStream<ClientJsonRpcMessage>,
+) {
+    let service = McpService {
+        progress_tokens,
+        response_metas,
+        ..state.service.clone()
+    };
+    // the service wants the errors of the sink as the io ones, like the transport of rmcp
+    let sink = sink.sink_map_err(io::Error::other);
+    match service
+        .serve_with_ct((sink, stream), state.ct.child_token())
+        .await
+    {
+        Ok(server) => match server.waiting().await {
+            Ok(quit_reason) => info!(
+                "SSE session {} closed with reason: {:?}",
+                session, quit_reason
+            ),
+            Err(e) => error!("SSE session {} failed: {:?}", session, e),
+        },
+        Err(e) => error!("SSE session {} failed to initialize: {:?}", session, e),
+    }
+    state.sessions.write().await.remove(&session);
+}
+
+async fn post_event_handler(
+    State(state): State<SseState>,
+    Query(PostEventQuery { session_id }): Query<PostEventQuery>,
+    Json(message): Json<Value>,
+) -> StatusCode {
+    let Some((tx, progress_tokens)) = state
+        .sessions
+        .read()
+        .await
+        .get(session_id.as_str())
+        .cloned()
+    else {
+        return StatusCode::NOT_FOUND;
+    };
+    // the raw message is read first, rmcp drops the progress token while parsing it
+    progress_tokens.remember(&message);
+    let Ok(message) = serde_json::from_value::<ClientJsonRpcMessage>(message)
+        .inspect_err(|e| warn!("Invalid MCP message of SSE session {}: {}", session_id, e))
+    else {
+        return StatusCode::BAD_REQUEST;
+    };
+    if tx.send(message).await.is_err() {
+        error!("SSE session {} is gone", session_id);
+        return StatusCode::GONE;
+    }
+    StatusCode::ACCEPTED
+}
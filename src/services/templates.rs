@@ -0,0 +1,276 @@
+use itertools::Itertools;
+use lsp_types::{Location, Position, Range};
+use miette::{IntoDiagnostic, Result};
+use regex::Regex;
+use strum::IntoEnumIterator;
+use tera::{Context, Tera};
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    NAME, PromptTemplates, TemplatesConfig, VERSION,
+    enums::{MatchOrigin, McpSymbolKind, McpToolName, PlaceConfidence},
+    errors::SemantrixError,
+    services::{
+        ChunkSnippet, DegradedCounts, FileResults, FusedSymbol, RuleEntry, SymbolInfo,
+        SymbolPlaceTo,
+        mcp::{PlacerSkipped, PromptContext, SearchPage, SearchWarning, ToolTimeout},
+    },
+};
+
+/// Prompt templates with the query arguments and the result lists of their context
+fn prompt_variables(
+    prompts: &PromptTemplates,
+) -> [(&String, &'static [&'static str], &'static [&'static str]); 2] {
+    [
+        (
+            &prompts.searcher,
+            &["semantic_queries", "name_patterns"],
+            &[
+                "semantic_rules",
+                "fuzzy_rules",
+                "semantic_symbols",
+                "fuzzy_symbols",
+                "semantic_snippets",
+                "fused_symbols",
+                "files",
+                "warnings",
+            ],
+        ),
+        (
+            &prompts.placer,
+            &["path_globs", "symbol_name_patterns"],
+            &["fuzzy_rules", "fuzzy_symbols", "references"],
+        ),
+    ]
+}
+
+/// Context of the descriptions of the query arguments, the one of the server has the enabled
+/// `tools` too
+pub fn description_context() -> Context {
+    let mut context = Context::new();
+    context.insert("name", &NAME);
+    context.insert("version", &VERSION);
+    context
+}
+
+/// Context of the prompt with every variable semantrix provides and one dummy item in every
+/// result list, so the loops over the results are rendered too
+pub fn sample_prompt_context(
+    model: &str,
+    queries: &[&'static str],
+    lists: &[&'static str],
+) -> Result<Context> {
+    let mut context = Context::from_serialize(PromptContext {
+        name: NAME,
+        version: VERSION,
+        workspace_root: "/workspace".to_string(),
+        model: model.to_string(),
+        ndims: 384,
+        queries: queries
+            .iter()
+            .map(|query| (*query, vec!["sample query".to_string()]))
+            .collect(),
+        counts: lists.iter().map(|list| (*list, 1)).collect(),
+        truncated: lists.iter().map(|list| (*list, true)).collect(),
+        degraded: DegradedCounts {
+            signature: 1,
+            name: 1,
+        },
+    })
+    .into_diagnostic()?;
+    for list in lists {
+        match *list {
+            "semantic_rules" | "fuzzy_rules" => {
+                context.insert(*list, &["Sample rule of the matched symbols"]);
+                context.insert(
+                    format!("{}_detailed", list),
+                    &[RuleEntry {
+                        rule: "Sample rule of the matched symbols".to_string(),
+                        ruleset: Some(0),
+                        title: Some("Sample ruleset".to_string()),
+                        symbols: vec![sample_symbol().id],
+                    }],
+                );
+            }
+            "semantic_symbols" | "fuzzy_symbols" => context.insert(*list, &[sample_symbol()]),
+            "fused_symbols" => context.insert(*list, &[sample_fused_symbol()]),
+            "semantic_snippets" => context.insert(*list, &[sample_snippet()]),
+            "files" => context.insert(
+                *list,
+                &[FileResults {
+                    path: "src/sample.rs".into(),
+                    language: "rs".to_string(),
+                    best_score: Some(2.0 / 61.0),
+                    symbol_count: 1,
+                    symbols: vec![sample_fused_symbol()],
+                    snippets: vec![sample_snippet()],
+                }],
+            ),
+            "warnings" => context.insert(
+                *list,
+                &[SearchWarning {
+                    source: "fuzzy".to_string(),
+                    message: "Sample warning".to_string(),
+                }],
+            ),
+            "references" => {
+                context.insert(
+                    *list,
+                    &[SymbolPlaceTo {
+                        symbol_info: sample_symbol(),
+                        place_to: Some("src".to_string()),
+                        reference_count: 1,
+                        min_distance: Some(1),
+                        max_distance: Some(1),
+                        confidence: PlaceConfidence::default(),
+                    }],
+                );
+                context.insert(
+                    "skipped",
+                    &PlacerSkipped {
+                        by_limit: 1,
+                        by_budget: 1,
+                    },
+                );
+            }
+            list => warn!("No sample of the prompt variable {}", list),
+        }
+    }
+    context.insert(
+        "page",
+        &SearchPage {
+            next_cursor: Some("0:0".to_string()),
+        },
+    );
+    context.insert(
+        "timings",
+        &serde_json::json!({ "fuzzy": 10, "semantic": 20, "rules": 1 }),
+    );
+    context.insert(
+        "timeout",
+        &ToolTimeout {
+            timed_out: true,
+            stages: vec!["semantic".to_string()],
+            timeout_ms: 1000,
+        },
+    );
+    Ok(context)
+}
+
+fn sample_fused_symbol() -> FusedSymbol {
+    FusedSymbol {
+        symbol: sample_symbol(),
+        fused_score: 2.0 / 61.0,
+        fuzzy_rank: Some(1),
+        semantic_rank: Some(1),
+    }
+}
+
+fn sample_snippet() -> ChunkSnippet {
+    ChunkSnippet {
+        path: "src/sample.rs".into(),
+        start_line: 0,
+        end_line: 1,
+        text: "struct Sample;".to_string(),
+        stale: false,
+        cell: None,
+        origin: Some(MatchOrigin::Code),
+    }
+}
+
+pub(crate) fn sample_symbol() -> SymbolInfo {
+    let range = Range::new(Position::new(0, 0), Position::new(0, 14));
+    SymbolInfo {
+        name: "Sample".to_string(),
+        kind: McpSymbolKind::Struct,
+        location: Location::new(
+            Url::parse("file:///workspace/src/sample.rs").expect("Sample url is valid"),
+            range,
+        ),
+        container_name: Some("sample".to_string()),
+        code: Some("struct Sample;".to_string()),
+        hover: Some("struct Sample".to_string()),
+        name_position: Some(Position::new(0, 12)),
+        matched_query: Some("sample query".to_string()),
+        hover_position: None,
+        score: Some(1000),
+        id: "0".to_string(),
+        range: Some(range),
+        selection_range: Some(Range::new(Position::new(0, 7), Position::new(0, 13))),
+        name_position_unresolved: false,
+        hover_skipped: false,
+        kind_weight: None,
+        git: None,
+        degraded: None,
+    }
+}
+
+/// Renders the descriptions and the search and placer prompts with the sample contexts, Tera
+/// fails to render an unknown variable, so a typo is found before a tool call. The prompts which
+/// are not loaded are skipped, the tools fail with them anyway
+pub fn check_templates(tera: &Tera, templates: &TemplatesConfig, model: &str) -> Result<()> {
+    let description = &templates.description;
+    let mut server_context = description_context();
+    server_context.insert(
+        "tools",
+        &McpToolName::iter()
+            .map(|tool| tool.to_string())
+            .collect_vec(),
+    );
+    render_sample(tera, &description.server, &server_context)?;
+    for template in [&description.fuzzy_query, &description.semantic_query] {
+        render_sample(tera, template, &description_context())?;
+    }
+    for (template, queries, lists) in prompt_variables(&templates.prompts) {
+        if tera.get_template(template).is_err() {
+            warn!("Prompt template {} is not loaded, skip its check", template);
+            continue;
+        }
+        render_sample(
+            tera,
+            template,
+            &sample_prompt_context(model, queries, lists)?,
+        )?;
+    }
+    Ok(())
+}
+
+fn render_sample(tera: &Tera, template: &str, context: &Context) -> Result<()> {
+    tera.render(template, context).map(|_| ()).map_err(|e| {
+        SemantrixError::template(format!(
+            "Failed to render template {}{}: {}",
+            template,
+            missing_variable_line(tera, template, &e)
+                .map(|line| format!(" at line {}", line))
+                .unwrap_or_default(),
+            error_chain(&e)
+        ))
+        .with_help("fix the template or its name in the templates config")
+        .into()
+    })
+}
+
+/// Messages of the error and its sources, the one of Tera only says that the rendering failed
+fn error_chain(error: &tera::Error) -> String {
+    let mut messages = vec![error.to_string()];
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        messages.push(error.to_string());
+        source = error.source();
+    }
+    messages.join(": ")
+}
+
+/// First line of the template file mentioning the variable Tera didn't find
+fn missing_variable_line(tera: &Tera, template: &str, error: &tera::Error) -> Option<usize> {
+    let regex = Regex::new(r"Variable `([^`]+)` not found").ok()?;
+    let chain = error_chain(error);
+    let variable = regex.captures(&chain)?.get(1)?.as_str();
+    let path = tera.get_template(template).ok()?.path.clone()?;
+    let source = std::fs::read_to_string(path).ok()?;
+    source
+        .lines()
+        .position(|line| line.contains(variable))
+        .map(|index| index + 1)
+}
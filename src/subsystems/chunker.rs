@@ -1,91 +1,349 @@
-use crate::{CONFIG, repositories::delete_by_path, services::SymbolInfo};
+use crate::{
+    MemoryConfig, SemanticConfig, StripSectionConfig, Workspace,
+    enums::StripScope,
+    repositories::backend::VectorBackend,
+    services::{
+        SymbolInfo, excludes::is_excluded, extractors::source_file, get_project_files,
+        normalize_path, storage_path, workspace_root,
+    },
+    subsystems::{
+        indexer::{IndexErrors, TableRecovery},
+        metrics::CHANNEL_DEPTH,
+        states::{SubsystemKind, SubsystemState, SubsystemStates},
+    },
+};
 use async_trait::async_trait;
 use derive_more::{Deref, DerefMut};
-use lancedb::Table;
+use futures::StreamExt;
+use itertools::Itertools;
+use metrics::gauge;
 use miette::{IntoDiagnostic, Result, miette};
+use notify_debouncer_full::notify::{EventKind, event::ModifyKind};
+use regex::Regex;
 use rig::{
     Embed,
     embeddings::{EmbedError, TextEmbedder},
 };
 use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
     fmt::Display,
     hash::{DefaultHasher, Hash, Hasher},
+    ops::Range,
     path::{Path, PathBuf},
     sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
+    time::Duration,
 };
-use tokio::{
-    fs::File,
-    io::{AsyncBufReadExt, BufReader},
-    sync::mpsc::{Receiver, Sender},
+use tera::{Context, Tera};
+use tokio::sync::{
+    OwnedSemaphorePermit, Semaphore,
+    mpsc::{Receiver, Sender},
 };
 use tokio_graceful_shutdown::{FutureExt, IntoSubsystem, SubsystemHandle};
 use tracing::{info, trace, warn};
-use wax::Glob;
 
 use super::watcher::PathEvent;
 
+/// Max number of the queued path events handled together
+const MAX_EVENTS_PER_BATCH: usize = 100;
+
+/// Delay of the next read of a file whose read failed, see [`ReadRetries`]
+pub const READ_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Reads of a file failed in a row before it waits for its next change
+const MAX_READ_ATTEMPTS: u32 = 3;
+
+/// Version of the chunk id hash, see [`ChunkId::to_hash`]
+const CHUNK_ID_VERSION: &str = "v1";
+
+/// Bytes of the chunks sent by the chunkers of all workspaces of an instance and not written by
+/// the indexers yet, see [`ChunkerSubsystem::send_chunk`]
+#[derive(Debug)]
+pub struct ChunkBudget {
+    in_flight: Arc<Semaphore>,
+    /// Half of `memory.chunk_bytes_in_flight`, see [`ChunkBudget::batch_bytes`]
+    batch_bytes: usize,
+}
+
+impl From<&MemoryConfig> for ChunkBudget {
+    fn from(memory: &MemoryConfig) -> Self {
+        Self {
+            in_flight: Arc::new(Semaphore::new(
+                memory.chunk_bytes_in_flight.min(Semaphore::MAX_PERMITS),
+            )),
+            batch_bytes: (memory.chunk_bytes_in_flight / 2).max(1),
+        }
+    }
+}
+
+impl ChunkBudget {
+    /// Bytes of the batch the indexer writes before it is full, half of the budget of the
+    /// chunks in flight, so the bytes of the next chunk are always released by the writes
+    pub fn batch_bytes(&self) -> usize {
+        self.batch_bytes
+    }
+}
+
 pub struct ChunkerSubsystem {
-    pub table: Table,
+    pub workspace: Arc<Workspace>,
+    /// Template of the embedded text of the chunks, None embeds their lines as they are
+    pub embedding_text: Option<Arc<EmbeddingText>>,
+    pub vector_store: Arc<dyn VectorBackend>,
     pub path_event_rx: Receiver<Arc<PathEvent>>,
     pub chunks_tx: Sender<Option<ArcTextChunk>>,
+    pub generations: Arc<ChunkGenerations>,
     pub first_path_scan: Arc<AtomicBool>,
     pub first_chunks_scan: Arc<AtomicBool>,
+    pub index_errors: Arc<IndexErrors>,
+    pub recovery: Arc<TableRecovery>,
+    pub strip_sections: StripSections,
+    pub states: Arc<SubsystemStates>,
+    /// Capacity of the channel of the files of a walked folder
+    pub channel_size: usize,
+    pub read_retries: ReadRetries,
+}
+
+/// Files whose read failed after their chunks were deleted, they are queued again after the
+/// delay, so a passing failure doesn't leave them out of the index until their next change
+#[derive(Debug)]
+pub struct ReadRetries {
+    path_event_tx: Sender<Arc<PathEvent>>,
+    delay: Duration,
+    attempts: Mutex<HashMap<PathBuf, u32>>,
+}
+
+impl ReadRetries {
+    pub fn new(path_event_tx: Sender<Arc<PathEvent>>, delay: Duration) -> Self {
+        Self {
+            path_event_tx,
+            delay,
+            attempts: Mutex::default(),
+        }
+    }
+
+    /// Queues the file again after the delay, false if [`MAX_READ_ATTEMPTS`] are spent, the file
+    /// waits for its next change then
+    pub fn queue(&self, path: &Path) -> bool {
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        let attempt = attempts.entry(path.to_path_buf()).or_default();
+        if *attempt >= MAX_READ_ATTEMPTS {
+            attempts.remove(path);
+            return false;
+        }
+        *attempt += 1;
+        let event = Arc::new(PathEvent {
+            path: Arc::new(path.to_path_buf()),
+            kind: EventKind::Modify(ModifyKind::Any),
+        });
+        let (path_event_tx, delay) = (self.path_event_tx.clone(), self.delay);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            // the chunker is gone once the channel is closed, nothing is left to retry
+            let _ = path_event_tx.send(event).await;
+        });
+        true
+    }
+
+    /// Forgets the failed reads of the file once it is read
+    pub fn forget(&self, path: &Path) {
+        self.attempts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(path);
+    }
+}
+
+/// Compiled `search.semantic.strip_sections`
+#[derive(Debug, Default)]
+pub struct StripSections(Vec<(StripScope, Regex)>);
+
+impl StripSections {
+    pub fn new(sections: &[StripSectionConfig]) -> Result<Self> {
+        sections
+            .iter()
+            .map(|it| Ok((it.scope, Regex::new(&it.pattern).into_diagnostic()?)))
+            .collect::<Result<Vec<_>>>()
+            .map(Self)
+    }
+
+    /// Lines of the document left after the leading and the trailing sections are stripped in
+    /// the order of the config, every line a section touches is stripped. The range keeps the
+    /// numbers of the lines in the document, so the chunks of them are recorded at their lines
+    pub fn kept_lines(&self, lines: &[String]) -> Range<usize> {
+        let mut kept = 0..lines.len();
+        for (scope, regex) in self.0.iter() {
+            let content = lines[kept.clone()].join("\n");
+            let line_of = |byte: usize| kept.start + content[..byte].matches('\n').count();
+            let section = match scope {
+                StripScope::Leading => regex
+                    .find(&content)
+                    .filter(|it| !it.is_empty() && content[..it.start()].trim().is_empty())
+                    .map(|it| kept.start..line_of(it.end() - 1) + 1),
+                StripScope::Trailing => regex
+                    .find_iter(&content)
+                    .find(|it| !it.is_empty() && content[it.end()..].trim().is_empty())
+                    .map(|it| line_of(it.start())..kept.end),
+            };
+            if let Some(section) = section {
+                trace!("Stripping lines {:?} by {}", section, regex);
+                kept = match scope {
+                    StripScope::Leading => section.end..kept.end,
+                    StripScope::Trailing => kept.start..section.start,
+                };
+            }
+        }
+        kept
+    }
+}
+
+/// Generations of the deletes of the changed paths, the chunks read before the last delete of
+/// their file or its folder are stale, the indexer drops them instead of writing the old lines.
+/// A delete is forgotten once the indexer flushed the chunks of its generation, see
+/// [`ChunkGenerations::flushed`]
+#[derive(Debug, Default)]
+pub struct ChunkGenerations {
+    current: AtomicU64,
+    deleted: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl ChunkGenerations {
+    /// Starts the next generation with the paths deleted by it, it must be started before the
+    /// delete, so the chunks written concurrently with the delete are found stale after it
+    pub fn next(&self, paths: &[PathBuf]) -> u64 {
+        let mut deleted = self.deleted.lock().unwrap_or_else(|e| e.into_inner());
+        let generation = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+        for path in paths {
+            deleted.insert(normalize_path(path), generation);
+        }
+        generation
+    }
+
+    /// True if the file or a folder of the chunk is deleted after the chunk is read, the
+    /// chunks of an archive entry are stale after the delete of the archive
+    pub fn is_stale(&self, chunk: &TextChunk) -> bool {
+        let deleted = self.deleted.lock().unwrap_or_else(|e| e.into_inner());
+        let file = source_file(&chunk.path);
+        chunk.path.ancestors().chain(file.ancestors()).any(|path| {
+            deleted
+                .get(path)
+                .is_some_and(|generation| *generation > chunk.generation)
+        })
+    }
+
+    /// Forgets the deletes up to the generation of the chunks the indexer received and flushed.
+    /// The chunks are sent in the order of their generations, so the older chunks are dropped
+    /// or written by then and no chunk left is checked against these deletes
+    pub fn flushed(&self, generation: u64) {
+        self.deleted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, deleted| *deleted > generation);
+    }
 }
 
 impl ChunkerSubsystem {
-    async fn process_file(&self, path: &Path) -> Result<()> {
+    async fn process_file(&self, path: &Path, generation: u64) -> Result<()> {
         trace!("File found for chunking: {}", path.display());
-        let file = File::open(path).await.into_diagnostic()?;
-        trace!("File opened for chunking: {}", path.display());
-        let mut reader = BufReader::new(file).lines();
-        trace!("File reader created for chunking: {}", path.display());
-        let mut text_chunk = TextChunk::new(path.to_path_buf().into(), 0);
-        trace!("Text chunk created for chunking: {}", path.display());
-
-        loop {
-            let line = reader.next_line().await.ok().flatten();
-            if let Some(line) = line {
-                text_chunk.push_line(line);
-                if text_chunk.is_full() {
-                    trace!("Chunk is full, sending to indexer: {}", text_chunk.id);
-                    self.chunks_tx
-                        .send(Some(ArcTextChunk(Arc::new(text_chunk.clone()))))
-                        .await
-                        .into_diagnostic()?;
-                    text_chunk = text_chunk.next_chunk();
-                }
-            } else {
-                trace!(
-                    "File reader finished, sending last chunk to indexer: {}",
-                    text_chunk.id
-                );
-                if !text_chunk.is_empty() {
-                    text_chunk.crop_last_chunk();
-                    self.chunks_tx
-                        .send(Some(ArcTextChunk(Arc::new(text_chunk.clone()))))
-                        .await
-                        .into_diagnostic()?;
+        self.states.set(
+            SubsystemKind::Chunker,
+            SubsystemState::Processing {
+                path: path.to_path_buf(),
+            },
+        );
+        let documents = match self.workspace.file_cache.read_documents(path).await {
+            Ok(documents) => documents,
+            Err(e) => {
+                // no chunk is sent, so the index has no partial file, but its previous chunks
+                // are deleted already, so it is read again
+                if self.read_retries.queue(path) {
+                    warn!(
+                        "Skipping file {} for chunking until it is read again in {:?}: {}",
+                        path.display(),
+                        self.read_retries.delay,
+                        e
+                    );
+                } else {
+                    warn!(
+                        "Skipping file {} for chunking until its next change: {}",
+                        path.display(),
+                        e
+                    );
                 }
-                trace!("Sending last chunk marker to indexer");
-                self.chunks_tx.send(None).await.into_diagnostic()?;
-                break;
+                self.index_errors.record(&normalize_path(path), "read", e);
+                return Ok(());
             }
+        };
+        self.read_retries.forget(path);
+        trace!("File read for chunking: {}", path.display());
+        // the entries of an archive are chunked under their virtual paths
+        for (document, lines) in documents {
+            self.process_document(&document, &lines, generation).await?;
+        }
+        trace!("Sending last chunk marker to indexer");
+        self.chunks_tx.send(None).await.into_diagnostic()
+    }
+
+    async fn process_document(&self, path: &Path, lines: &[String], generation: u64) -> Result<()> {
+        // the chunks of the stripped document start at its first kept line of the file
+        let kept = self.strip_sections.kept_lines(lines);
+        let chunks = document_chunks(
+            normalize_path(path).into(),
+            &lines[kept.clone()],
+            kept.start,
+            self.workspace.semantic.chunk_size,
+            self.workspace.semantic.overlap_size,
+        );
+        for mut text_chunk in chunks {
+            trace!("Sending chunk to indexer: {}", text_chunk.id);
+            text_chunk.generation = generation;
+            self.send_chunk(text_chunk).await?;
         }
-        if self.first_path_scan.load(Ordering::Relaxed) && self.path_event_rx.is_empty() {
-            if let Ok(false) = self.first_chunks_scan.compare_exchange(
+        trace!("Document is chunked: {}", path.display());
+        Ok(())
+    }
+
+    /// Sends the chunk after its bytes fit the budget, so a very large file waits for the
+    /// indexer instead of queueing its chunks. The bytes are released when the indexer drops
+    /// the last clone of the chunk, a chunk takes at most [`ChunkBudget::batch_bytes`] of them
+    async fn send_chunk(&self, chunk: TextChunk) -> Result<()> {
+        let budget = &self.workspace.chunk_budget;
+        let permits = chunk
+            .text_bytes()
+            .clamp(1, budget.batch_bytes())
+            .min(u32::MAX as usize) as u32;
+        let permit = budget
+            .in_flight
+            .clone()
+            .acquire_many_owned(permits)
+            .await
+            .into_diagnostic()?;
+        self.chunks_tx
+            .send(Some(ArcTextChunk {
+                chunk: Arc::new(chunk),
+                embedding_text: self.embedding_text.clone(),
+                _permit: Some(Arc::new(permit)),
+            }))
+            .await
+            .into_diagnostic()
+    }
+
+    /// Marks the first chunks scan after the events of the first path scan are chunked
+    fn check_first_chunks_scan(&self) {
+        if self.first_path_scan.load(Ordering::Relaxed)
+            && self.path_event_rx.is_empty()
+            && let Ok(false) = self.first_chunks_scan.compare_exchange(
                 false,
                 true,
                 Ordering::Relaxed,
                 Ordering::Relaxed,
-            ) {
-                info!("First chunks scan set to true");
-            }
+            )
+        {
+            info!("First chunks scan set to true");
         }
-        Ok(())
     }
 }
 
@@ -93,48 +351,168 @@ impl ChunkerSubsystem {
 impl IntoSubsystem<miette::Report> for ChunkerSubsystem {
     async fn run(mut self, subsys: SubsystemHandle) -> Result<()> {
         info!("Start chunker");
+        self.states
+            .set(SubsystemKind::Chunker, SubsystemState::Idle);
         while let Some(event) = self
             .path_event_rx
             .recv()
             .cancel_on_shutdown(&subsys)
             .await?
         {
+            gauge!(CHANNEL_DEPTH, "channel" => "path_events").set(self.path_event_rx.len() as f64);
+            // the queued events are handled together, so their chunks are deleted at once
+            let mut events = vec![event];
+            while events.len() < MAX_EVENTS_PER_BATCH {
+                match self.path_event_rx.try_recv() {
+                    Ok(event) => events.push(event),
+                    Err(_) => break,
+                }
+            }
+            let (changed, skipped): (Vec<_>, Vec<_>) = events.into_iter().partition(|event| {
+                event.kind.is_remove() || event.kind.is_create() || event.kind.is_modify()
+            });
+            for event in skipped {
+                warn!("Skipping event: {:?}", event);
+            }
             //TODO: For POC purposes it always will be fully rechunked after each file modified, but need to rechunk only changed chunks
-            if event.kind.is_remove() {
-                trace!("File/folder removed: {:?}", event);
-                delete_by_path(&self.table, event.path.as_ref()).await?;
-            } else if event.kind.is_create() || event.kind.is_modify() {
+            let paths = changed
+                .iter()
+                .map(|event| event.path.as_ref().clone())
+                .unique()
+                .collect_vec();
+            let generation = self.generations.next(&paths);
+            trace!(
+                "Deleting chunks of {} changed paths, generation {}",
+                paths.len(),
+                generation
+            );
+            if let Err(e) = self.vector_store.delete_by_paths(&paths).await {
+                // the recovery queues the whole workspace, so the files are chunked again
+                if !self.recovery.recover_if_lost().await {
+                    return Err(e);
+                }
+                warn!(
+                    "Chunks of {} changed paths are not deleted from the lost table: {:?}",
+                    paths.len(),
+                    e
+                );
+            }
+            // the changed files are indexed again, so their previous failures are outdated
+            self.index_errors
+                .clear(&paths.iter().map(|it| normalize_path(it)).collect_vec());
+
+            for event in changed.iter().filter(|event| !event.kind.is_remove()) {
                 trace!("File/folder created/modified: {:?}", event);
-                delete_by_path(&self.table, event.path.as_ref()).await?;
+                if is_excluded(&self.workspace, &event.path) {
+                    trace!("Skipping excluded path: {}", event.path.display());
+                    continue;
+                }
                 if event.path.is_file() {
-                    self.process_file(&event.path).await?;
+                    self.process_file(&event.path, generation).await?;
                 } else if event.path.is_dir() {
-                    let positive =
-                        Glob::new(CONFIG.search.semantic.pattern.as_str()).into_diagnostic()?;
-                    let walker = positive.walk(event.path.as_ref());
-                    for entry in walker
-                        .filter_map(|it| it.ok())
-                        .filter(|it| it.file_type().is_file())
-                    {
-                        self.process_file(entry.path()).await?;
+                    let mut files = get_project_files(
+                        &self.workspace,
+                        event.path.to_path_buf(),
+                        self.channel_size,
+                    )?;
+                    while let Some(file) = files.next().await {
+                        self.process_file(&file, generation).await?;
                     }
                 }
-            } else {
-                warn!("Skipping event: {:?}", event);
             }
+            self.check_first_chunks_scan();
+            self.states
+                .set(SubsystemKind::Chunker, SubsystemState::Idle);
         }
+        self.states
+            .set(SubsystemKind::Chunker, SubsystemState::Stopped);
         Ok(())
     }
 }
 
 #[derive(Clone, Deref, DerefMut)]
-pub struct ArcTextChunk(Arc<TextChunk>);
+pub struct ArcTextChunk {
+    #[deref]
+    #[deref_mut]
+    chunk: Arc<TextChunk>,
+    /// Template the chunk is embedded by, None embeds its lines as they are
+    embedding_text: Option<Arc<EmbeddingText>>,
+    /// Bytes of the chunk in the [`ChunkBudget`], released with the last clone
+    _permit: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+impl ArcTextChunk {
+    pub fn with_embedding_text(self, embedding_text: Option<Arc<EmbeddingText>>) -> Self {
+        Self {
+            embedding_text,
+            ..self
+        }
+    }
+}
+
+impl From<TextChunk> for ArcTextChunk {
+    fn from(chunk: TextChunk) -> Self {
+        Self {
+            chunk: Arc::new(chunk),
+            embedding_text: None,
+            _permit: None,
+        }
+    }
+}
+
+/// Name of the template of `search.semantic.embedding_text_template` in its own templates
+const EMBEDDING_TEXT_TEMPLATE: &str = "embedding_text";
+
+/// `search.semantic.embedding_text_template` of a workspace, the relative paths of the chunks
+/// are rendered against its root
+#[derive(Debug)]
+pub struct EmbeddingText {
+    tera: Tera,
+    workspace_path: Option<PathBuf>,
+}
+
+impl EmbeddingText {
+    /// Template of the semantic config, None if it has no template. The template is checked by
+    /// the config validation, so it fails only for an unchecked config
+    pub fn new(
+        semantic: &SemanticConfig,
+        workspace_path: Option<PathBuf>,
+    ) -> Result<Option<Arc<Self>>> {
+        let Some(template) = &semantic.embedding_text_template else {
+            return Ok(None);
+        };
+        let tera = embedding_text_tera(template)
+            .map_err(|e| miette!("Failed to compile embedding text template: {}", e))?;
+        Ok(Some(Arc::new(Self {
+            tera,
+            workspace_path,
+        })))
+    }
+
+    /// Workspace of the template, the paths of its chunks are relative to its root
+    pub fn of_workspace(workspace: &Workspace) -> Result<Option<Arc<Self>>> {
+        Self::new(&workspace.semantic, workspace_root(workspace).ok())
+    }
+}
+
+/// Templates with the embedding text template of the config
+pub fn embedding_text_tera(template: &str) -> tera::Result<Tera> {
+    let mut tera = Tera::default();
+    tera.add_raw_template(EMBEDDING_TEXT_TEMPLATE, template)?;
+    Ok(tera)
+}
 
 impl Embed for ArcTextChunk {
     fn embed(&self, embedder: &mut TextEmbedder) -> Result<(), EmbedError> {
-        self.text.iter().for_each(|s| {
-            embedder.embed(s.to_string());
-        });
+        match &self.embedding_text {
+            Some(template) => embedder.embed(
+                self.embedding_text(&template.tera, template.workspace_path.as_deref())
+                    .map_err(EmbedError::new)?,
+            ),
+            None => self.text.iter().for_each(|s| {
+                embedder.embed(s.to_string());
+            }),
+        }
         Ok(())
     }
 }
@@ -142,7 +520,7 @@ impl Embed for ArcTextChunk {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DocumentPointer {
     Chunk(ChunkId),
-    Symbol(SymbolInfo),
+    Symbol(Box<SymbolInfo>),
 }
 
 impl PartialOrd for DocumentPointer {
@@ -202,7 +580,7 @@ impl<'de> Deserialize<'de> for ChunkId {
         };
         let computed_hash = chunk_id.to_hash();
 
-        if helper.id != computed_hash {
+        if helper.id != computed_hash && helper.id != chunk_id.to_legacy_hash() {
             return Err(serde::de::Error::custom(miette!(
                 "ChunkId hash mismatch: expected {}, got {}",
                 computed_hash,
@@ -223,13 +601,38 @@ impl ChunkId {
         }
     }
 
+    /// Hash of the path and the lines prefixed by its version, it is the same for every build
     pub fn to_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update((self.start_line as u64).to_le_bytes());
+        hasher.update((self.end_line as u64).to_le_bytes());
+        let digest = format!("{:x}", hasher.finalize());
+        format!("{}-{}", CHUNK_ID_VERSION, &digest[..16])
+    }
+
+    // TODO: remove after the rows of the indexes built before the versioned ids are replaced
+    /// Hash of the ids written before the versioned ones, it depends on the toolchain, the rows
+    /// with it are replaced when their files are chunked again
+    pub fn to_legacy_hash(&self) -> String {
         let mut hasher = DefaultHasher::new();
         self.hash(&mut hasher);
         hasher.finish().to_string()
     }
 }
 
+/// Hash of the lines of a chunk, the same lines read from the file at query time have the same
+/// hash until the file is changed
+pub fn lines_hash(lines: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for line in lines {
+        hasher.update(line.as_bytes());
+        hasher.update([b'\n']);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
 impl Display for ChunkId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -250,17 +653,34 @@ pub struct TextChunk {
     pub start_line: usize,
     pub end_line: usize,
     pub text: Vec<String>,
+    /// Number of the first lines repeated from the previous chunk
+    #[serde(skip)]
+    pub overlap_lines: usize,
+    /// Generation of the delete the file is chunked after, see [`ChunkGenerations`]
+    #[serde(skip)]
+    pub generation: u64,
+    /// Zero based position of the chunk in the chunks of its file, None for the chunks imported
+    /// without it
+    #[serde(skip)]
+    pub chunk_index: Option<usize>,
+    /// Number of the chunks of the file, None for the chunks imported without it
+    #[serde(skip)]
+    pub total_chunks: Option<usize>,
 }
 
 impl TextChunk {
-    pub fn new(path: Arc<PathBuf>, start_line: usize) -> Self {
-        let end_line = start_line + CONFIG.search.semantic.chunk_size;
+    pub fn new(path: Arc<PathBuf>, start_line: usize, chunk_size: usize) -> Self {
+        let end_line = start_line + chunk_size;
         Self {
             id: ChunkId::new(path.clone(), start_line, end_line),
             path,
             start_line,
             end_line,
             text: Vec::new(),
+            overlap_lines: 0,
+            generation: 0,
+            chunk_index: Some(0),
+            total_chunks: None,
         }
     }
 
@@ -268,14 +688,19 @@ impl TextChunk {
         self.end_line = self.start_line + self.text.len();
     }
 
-    pub fn is_full(&self) -> bool {
-        self.text.len() == CONFIG.search.semantic.chunk_size
+    pub fn is_full(&self, chunk_size: usize) -> bool {
+        self.text.len() == chunk_size
     }
 
     pub fn is_empty(&self) -> bool {
         self.text.is_empty()
     }
 
+    /// False if the chunk has only the lines of the previous one, the file ended with it
+    pub fn has_new_lines(&self) -> bool {
+        self.text.len() > self.overlap_lines
+    }
+
     pub fn push_line(&mut self, line: String) {
         self.text.push(line);
     }
@@ -284,16 +709,391 @@ impl TextChunk {
         self.text.len()
     }
 
-    pub fn next_chunk(&self) -> TextChunk {
-        let mut next_chunk = TextChunk::new(
-            self.path.clone(),
-            self.end_line - CONFIG.search.semantic.overlap_size,
+    /// Bytes of the lines of the chunk
+    pub fn text_bytes(&self) -> usize {
+        self.text.iter().map(String::len).sum()
+    }
+
+    /// Hash of the lines of the chunk, None for the chunks imported without their text
+    pub fn text_hash(&self) -> Option<String> {
+        (!self.text.is_empty()).then(|| lines_hash(&self.text))
+    }
+
+    /// Text of the chunk rendered by the embedding text template, the lines are one based and
+    /// the path is relative to the workspace if the chunk is in it
+    pub fn embedding_text(
+        &self,
+        tera: &Tera,
+        workspace_path: Option<&Path>,
+    ) -> tera::Result<String> {
+        let relative_path = workspace_path
+            .and_then(|workspace| self.path.strip_prefix(workspace).ok().map(storage_path))
+            .unwrap_or_else(|| storage_path(&self.path));
+        let mut context = Context::new();
+        context.insert("path", &storage_path(&self.path));
+        context.insert("relative_path", &relative_path);
+        context.insert(
+            "language",
+            &self
+                .path
+                .extension()
+                .map(|it| it.to_string_lossy().to_lowercase())
+                .unwrap_or_default(),
         );
-        let tail = &self.text[self
-            .text
-            .len()
-            .saturating_sub(CONFIG.search.semantic.overlap_size)..];
+        context.insert("start_line", &(self.start_line + 1));
+        context.insert("end_line", &self.end_line);
+        context.insert("text", &self.text.join("\n"));
+        tera.render(EMBEDDING_TEXT_TEMPLATE, &context)
+    }
+
+    /// Next chunk of the file starting with the last `overlap_size` lines of this one
+    pub fn next_chunk(&self, chunk_size: usize, overlap_size: usize) -> TextChunk {
+        let mut next_chunk =
+            TextChunk::new(self.path.clone(), self.end_line - overlap_size, chunk_size);
+        let tail = &self.text[self.text.len().saturating_sub(overlap_size)..];
         next_chunk.text.extend_from_slice(tail);
+        next_chunk.overlap_lines = tail.len();
+        next_chunk.generation = self.generation;
+        next_chunk.chunk_index = self.chunk_index.map(|it| it + 1);
+        next_chunk.total_chunks = self.total_chunks;
         next_chunk
     }
 }
+
+/// Number of the chunks the lines are split to, see [`chunk_lines`]
+pub fn count_chunks(lines: usize, chunk_size: usize, overlap_size: usize) -> usize {
+    if lines == 0 {
+        return 0;
+    }
+    // every chunk after the first one starts with the last `overlap_size` lines of the previous
+    1 + lines
+        .saturating_sub(chunk_size)
+        .div_ceil(chunk_size.saturating_sub(overlap_size).max(1))
+}
+
+/// Chunks of the lines in the way the chunker splits a file, for the text which is not read
+/// from a file
+pub fn chunk_lines(
+    path: Arc<PathBuf>,
+    lines: &[String],
+    chunk_size: usize,
+    overlap_size: usize,
+) -> Vec<TextChunk> {
+    document_chunks(path, lines, 0, chunk_size, overlap_size).collect()
+}
+
+/// Chunks of the lines of the document as they are filled, the lines start at `first_line` of
+/// the file and the chunks are recorded at the lines of the file
+fn document_chunks(
+    path: Arc<PathBuf>,
+    lines: &[String],
+    first_line: usize,
+    chunk_size: usize,
+    overlap_size: usize,
+) -> impl Iterator<Item = TextChunk> + '_ {
+    let mut text_chunk = TextChunk::new(path, first_line, chunk_size);
+    text_chunk.total_chunks = Some(count_chunks(lines.len(), chunk_size, overlap_size));
+    let mut text_chunk = Some(text_chunk);
+    let mut lines = lines.iter();
+    std::iter::from_fn(move || {
+        let chunk = text_chunk.as_mut()?;
+        for line in lines.by_ref() {
+            chunk.push_line(line.clone());
+            if chunk.is_full(chunk_size) {
+                let next_chunk = chunk.next_chunk(chunk_size, overlap_size);
+                return Some(std::mem::replace(chunk, next_chunk));
+            }
+        }
+        let mut last = text_chunk.take()?;
+        if last.has_new_lines() {
+            last.crop_last_chunk();
+            Some(last)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(count: usize) -> Vec<String> {
+        (0..count).map(|it| format!("line {}", it)).collect()
+    }
+
+    fn path() -> Arc<PathBuf> {
+        Arc::new(PathBuf::from("/workspace/src/lib.rs"))
+    }
+
+    #[test]
+    fn new_chunk_spans_chunk_size_lines() {
+        let chunk = TextChunk::new(path(), 10, 5);
+
+        assert_eq!(chunk.start_line, 10);
+        assert_eq!(chunk.end_line, 15);
+        assert!(chunk.is_empty());
+        assert_eq!(chunk.overlap_lines, 0);
+        assert_eq!(chunk.chunk_index, Some(0));
+        assert_eq!(chunk.id, ChunkId::new(path(), 10, 15));
+    }
+
+    #[test]
+    fn chunk_is_full_at_chunk_size_lines() {
+        let mut chunk = TextChunk::new(path(), 0, 3);
+        for line in lines(2) {
+            chunk.push_line(line);
+        }
+        assert!(!chunk.is_full(3));
+
+        chunk.push_line("last".to_string());
+        assert!(chunk.is_full(3));
+    }
+
+    #[test]
+    fn next_chunk_starts_with_overlap_lines() {
+        let mut chunk = TextChunk::new(path(), 0, 4);
+        chunk.generation = 7;
+        chunk.total_chunks = Some(3);
+        for line in lines(4) {
+            chunk.push_line(line);
+        }
+
+        let next = chunk.next_chunk(4, 1);
+
+        assert_eq!(next.start_line, 3);
+        assert_eq!(next.end_line, 7);
+        assert_eq!(next.text, vec!["line 3".to_string()]);
+        assert_eq!(next.overlap_lines, 1);
+        assert_eq!(next.generation, 7);
+        assert_eq!(next.chunk_index, Some(1));
+        assert_eq!(next.total_chunks, Some(3));
+        assert!(!next.has_new_lines());
+    }
+
+    #[test]
+    fn next_chunk_without_overlap_is_empty() {
+        let mut chunk = TextChunk::new(path(), 0, 2);
+        for line in lines(2) {
+            chunk.push_line(line);
+        }
+
+        let next = chunk.next_chunk(2, 0);
+
+        assert_eq!(next.start_line, 2);
+        assert!(next.is_empty());
+        assert_eq!(next.overlap_lines, 0);
+    }
+
+    #[test]
+    fn chunk_of_overlap_lines_only_has_no_new_lines() {
+        let mut chunk = TextChunk::new(path(), 0, 4);
+        for line in lines(4) {
+            chunk.push_line(line);
+        }
+        let mut next = chunk.next_chunk(4, 2);
+        assert!(!next.has_new_lines());
+
+        next.push_line("new".to_string());
+        assert!(next.has_new_lines());
+    }
+
+    #[test]
+    fn no_trailing_overlap_only_chunk() {
+        let (chunk_size, overlap_size) = (4, 1);
+        let step = chunk_size - overlap_size;
+        let cases = [
+            (chunk_size, 1),
+            (chunk_size + overlap_size, 2),
+            (chunk_size + step, 2),
+            (chunk_size + 2 * step, 3),
+            (chunk_size + 3 * step, 4),
+        ];
+        for (len, expected) in cases {
+            let chunks = chunk_lines(path(), &lines(len), chunk_size, overlap_size);
+
+            assert_eq!(chunks.len(), expected, "{} lines", len);
+            assert_eq!(chunks.len(), count_chunks(len, chunk_size, overlap_size));
+            assert!(chunks.iter().all(TextChunk::has_new_lines), "{} lines", len);
+            let last = chunks.last().unwrap();
+            assert_eq!(last.end_line, len, "{} lines", len);
+            assert_eq!(last.text.last(), Some(&format!("line {}", len - 1)));
+        }
+    }
+
+    #[test]
+    fn chunks_cover_every_line_once_besides_overlap() {
+        for (chunk_size, overlap_size) in [(4, 0), (4, 1), (4, 3), (1, 0)] {
+            for len in 1..=20 {
+                let chunks = chunk_lines(path(), &lines(len), chunk_size, overlap_size);
+
+                let new_lines = chunks
+                    .iter()
+                    .flat_map(|it| it.text[it.overlap_lines..].iter().cloned())
+                    .collect::<Vec<_>>();
+                assert_eq!(new_lines, lines(len), "{} lines", len);
+                assert_eq!(chunks.len(), count_chunks(len, chunk_size, overlap_size));
+            }
+        }
+    }
+
+    /// Reader of the lines which fails after them like a disk failing halfway through a file
+    struct FailingReader {
+        content: Vec<u8>,
+        position: usize,
+    }
+
+    impl tokio::io::AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.position == self.content.len() {
+                return std::task::Poll::Ready(Err(std::io::Error::other("disk failure")));
+            }
+            let end = self.content.len().min(self.position + buf.remaining());
+            buf.put_slice(&self.content[self.position..end]);
+            self.position = end;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn read_failing_after_lines_chunks_nothing() {
+        let file = lines(10).join("\n");
+        let read = lines(3).iter().map(|it| format!("{}\n", it)).join("");
+        let reader = FailingReader {
+            content: read.clone().into_bytes(),
+            position: 0,
+        };
+
+        let content =
+            crate::services::file_cache::read_content(&path(), reader, file.len() as u64).await;
+
+        // the read lines are not taken for the whole file, so none of its chunks is written
+        let e = content.expect_err("partial content is read");
+        assert!(
+            e.to_string()
+                .contains(&format!("after {} bytes", read.len())),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_read_is_queued_until_attempts_are_spent() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let retries = ReadRetries::new(tx, Duration::from_millis(1));
+
+        for _ in 0..MAX_READ_ATTEMPTS {
+            assert!(retries.queue(&path()));
+            let event = rx.recv().await.unwrap();
+            assert_eq!(event.path, path());
+            assert!(event.kind.is_modify());
+        }
+        assert!(!retries.queue(&path()));
+
+        // the next change of the file starts the attempts again
+        assert!(retries.queue(&path()));
+        retries.forget(&path());
+        assert_eq!(retries.attempts.lock().unwrap().get(path().as_path()), None);
+    }
+
+    #[test]
+    fn chunk_id_hash_is_pinned() {
+        // the ids of the stored rows must not change between builds and toolchains
+        assert_eq!(
+            ChunkId::new(path(), 10, 15).to_hash(),
+            "v1-c4db6a33684ef05c"
+        );
+    }
+
+    #[test]
+    fn chunk_id_is_read_with_legacy_hash() {
+        let id = ChunkId::new(path(), 10, 15);
+        let row = |hash: &str| {
+            serde_json::json!({
+                "id": hash,
+                "path": "/workspace/src/lib.rs",
+                "start_line": 10,
+                "end_line": 15,
+            })
+        };
+
+        let versioned = serde_json::from_value::<ChunkId>(row(&id.to_hash())).unwrap();
+        let legacy = serde_json::from_value::<ChunkId>(row(&id.to_legacy_hash())).unwrap();
+
+        assert_eq!(versioned, id);
+        assert_eq!(legacy, id);
+        assert!(serde_json::from_value::<ChunkId>(row("v1-0000000000000000")).is_err());
+    }
+
+    fn chunk_of_generation(generation: u64) -> TextChunk {
+        let mut chunk = TextChunk::new(path(), 0, 4);
+        chunk.generation = generation;
+        chunk
+    }
+
+    #[test]
+    fn stale_buffered_chunk_stays_stale_after_older_flush() {
+        let generations = ChunkGenerations::default();
+        let first = generations.next(&[path().to_path_buf()]);
+        // the chunk is read after the first delete and buffered by the indexer
+        let buffered = chunk_of_generation(first);
+        // the file is changed again and deleted while the chunk is buffered
+        let second = generations.next(&[path().to_path_buf()]);
+
+        // the flush of the chunks received before the newer delete doesn't forget it
+        generations.flushed(first);
+        assert!(generations.is_stale(&buffered));
+
+        generations.flushed(second);
+        assert!(!generations.is_stale(&chunk_of_generation(second)));
+        assert!(generations.deleted.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stripped_sections_are_not_chunked_and_lines_keep_their_numbers() {
+        let strip_sections = StripSections::new(&[
+            StripSectionConfig {
+                pattern: r"(?s)^/\*.*?Copyright.*?\*/".to_string(),
+                scope: StripScope::Leading,
+            },
+            StripSectionConfig {
+                pattern: r"(?s)// generated table.*".to_string(),
+                scope: StripScope::Trailing,
+            },
+        ])
+        .unwrap();
+        let file = [
+            "/*",
+            " * Copyright 2026",
+            " */",
+            "fn a() {}",
+            "fn b() {}",
+            "fn c() {}",
+            "fn d() {}",
+            "fn e() {}",
+            "// generated table",
+            "| 1 | 2 |",
+        ]
+        .map(str::to_string);
+
+        let kept = strip_sections.kept_lines(&file);
+        let chunks = document_chunks(path(), &file[kept.clone()], kept.start, 2, 0).collect_vec();
+
+        assert_eq!(kept, 3..8);
+        let ranges = chunks
+            .iter()
+            .map(|it| (it.start_line, it.end_line))
+            .collect_vec();
+        assert_eq!(ranges, [(3, 5), (5, 7), (7, 8)]);
+        for chunk in chunks.iter() {
+            // the embedded text is the text of the lines the chunk is recorded at
+            assert_eq!(chunk.text, file[chunk.start_line..chunk.end_line]);
+            assert!(chunk.text.iter().all(|it| it.starts_with("fn ")));
+        }
+        assert_eq!(chunks[0].total_chunks, Some(3));
+    }
+}
@@ -0,0 +1,72 @@
+use std::{path, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use miette::{IntoDiagnostic, Result};
+use notify_debouncer_full::{DebounceEventResult, new_debouncer, notify::RecursiveMode};
+use tokio_graceful_shutdown::{IntoSubsystem, SubsystemHandle};
+use tracing::{error, info, warn};
+
+use crate::{Args, LiveConfig, McpConfig, errors::SemantrixError};
+
+/// Reloads the config file after it is changed, see [`LiveConfig`]
+pub struct ConfigWatcherSubsystem {
+    /// Startup config, its values which require restart are reported if they are changed
+    pub config: Arc<McpConfig>,
+    /// Path of the watched config file
+    pub config_path: String,
+    /// Arguments the reloaded config is overridden by
+    pub args: Arc<Args>,
+    pub live_config: LiveConfig,
+    /// Time to collect events of one change
+    pub debounce: Duration,
+}
+
+#[async_trait]
+impl IntoSubsystem<miette::Report> for ConfigWatcherSubsystem {
+    async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        let config_path = path::absolute(&self.config_path).into_diagnostic()?;
+        let config_dir = config_path
+            .parent()
+            .ok_or_else(|| {
+                SemantrixError::config(format!("Config file has no parent: {:?}", config_path))
+            })?
+            .to_path_buf();
+
+        let reloaded_path = config_path.clone();
+        let startup = self.config.clone();
+        let args = self.args.clone();
+        let live_config = self.live_config.clone();
+        let mut debouncer = new_debouncer(
+            self.debounce,
+            None,
+            move |debounce_result: DebounceEventResult| match debounce_result {
+                Ok(events) => {
+                    let changed = events.iter().any(|event| {
+                        !event.kind.is_access() && event.paths.contains(&reloaded_path)
+                    });
+                    if changed
+                        && let Err(e) =
+                            live_config.reload(&reloaded_path.to_string_lossy(), &args, &startup)
+                    {
+                        error!("Failed to reload config, the previous one is kept: {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!("Error in config debouncer: {:?}", e);
+                }
+            },
+        )
+        .into_diagnostic()?;
+
+        // The directory is watched because editors replace the file on save
+        debouncer
+            .watch(&config_dir, RecursiveMode::NonRecursive)
+            .into_diagnostic()?;
+
+        info!("Watching config file: {:?}", config_path);
+
+        subsys.on_shutdown_requested().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+};
+
+use async_trait::async_trait;
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio_graceful_shutdown::{IntoSubsystem, SubsystemHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{
+    enums::{HealthPolicy, ReadinessStatus},
+    subsystems::{WorkspacePipeline, states::SubsystemStatus},
+};
+
+/// Serves `GET /healthz` and `GET /readyz` for the orchestrators which can't speak MCP
+pub struct HealthSubsystem {
+    pub bind: SocketAddr,
+    pub policy: HealthPolicy,
+    pub pipelines: Vec<WorkspacePipeline>,
+}
+
+#[derive(Clone)]
+struct HealthState {
+    policy: HealthPolicy,
+    pipelines: Arc<Vec<WorkspacePipeline>>,
+    shutdown: CancellationToken,
+}
+
+/// Body of the probes, the readiness fields of the status tool. The status of several
+/// workspaces has the flags set if they are set for all of them and the latest timestamps
+#[derive(Debug, Serialize)]
+pub struct HealthStatus {
+    /// Name of the workspace, None for the status of several workspaces
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<String>,
+    pub status: ReadinessStatus,
+    pub first_path_scan: bool,
+    pub first_chunks_scan: bool,
+    pub first_index_scan: bool,
+    pub lsp_server_ready: bool,
+    pub last_write: Option<DateTime<Utc>>,
+    pub last_optimize: Option<DateTime<Utc>>,
+    pub last_vacuum: Option<DateTime<Utc>>,
+    /// States of the subsystems of the workspace, empty for the status of several workspaces
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subsystems: Vec<SubsystemStatus>,
+    /// Statuses of the workspaces if several of them are served
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub workspaces: Vec<HealthStatus>,
+}
+
+impl HealthStatus {
+    fn of(pipeline: &WorkspacePipeline) -> Self {
+        let lsp_server_ready = pipeline.lsp_server_rx.borrow().is_some();
+        let first_index_scan = *pipeline.first_index_scan.borrow();
+        Self {
+            workspace: Some(pipeline.workspace.name.clone()),
+            status: ReadinessStatus::of(lsp_server_ready, first_index_scan),
+            first_path_scan: pipeline.first_path_scan.load(Ordering::Relaxed),
+            first_chunks_scan: pipeline.first_chunks_scan.load(Ordering::Relaxed),
+            first_index_scan,
+            lsp_server_ready,
+            last_write: pipeline.index_timestamps.last_write(),
+            last_optimize: pipeline.index_timestamps.last_optimize(),
+            last_vacuum: pipeline.index_timestamps.last_vacuum(),
+            subsystems: pipeline.states.statuses(),
+            workspaces: Vec::new(),
+        }
+    }
+}
+
+impl HealthState {
+    fn status(&self) -> HealthStatus {
+        let mut statuses = self.pipelines.iter().map(HealthStatus::of).collect_vec();
+        if statuses.len() == 1 {
+            return statuses.remove(0);
+        }
+        let lsp_server_ready = statuses.iter().all(|it| it.lsp_server_ready);
+        let first_index_scan = statuses.iter().all(|it| it.first_index_scan);
+        HealthStatus {
+            workspace: None,
+            status: ReadinessStatus::of(lsp_server_ready, first_index_scan),
+            first_path_scan: statuses.iter().all(|it| it.first_path_scan),
+            first_chunks_scan: statuses.iter().all(|it| it.first_chunks_scan),
+            first_index_scan,
+            lsp_server_ready,
+            last_write: statuses.iter().filter_map(|it| it.last_write).max(),
+            last_optimize: statuses.iter().filter_map(|it| it.last_optimize).max(),
+            last_vacuum: statuses.iter().filter_map(|it| it.last_vacuum).max(),
+            subsystems: Vec::new(),
+            workspaces: statuses,
+        }
+    }
+}
+
+#[async_trait]
+impl IntoSubsystem<miette::Report> for HealthSubsystem {
+    async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        let shutdown = subsys.create_cancellation_token();
+        let router = Router::new()
+            .route("/healthz", get(liveness))
+            .route("/readyz", get(readiness))
+            .with_state(HealthState {
+                policy: self.policy,
+                pipelines: Arc::new(self.pipelines),
+                shutdown: shutdown.clone(),
+            });
+
+        let listener = TcpListener::bind(self.bind).await.into_diagnostic()?;
+        info!("Health probes listening on {}", self.bind);
+        axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown.cancelled_owned())
+            .await
+            .into_diagnostic()
+    }
+}
+
+/// Alive until the shutdown of the subsystems is requested
+async fn liveness(State(state): State<HealthState>) -> (StatusCode, Json<HealthStatus>) {
+    let code = if state.shutdown.is_cancelled() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (code, Json(state.status()))
+}
+
+/// Ready after the first index scan with the LSP server started unless the policy skips it
+async fn readiness(State(state): State<HealthState>) -> (StatusCode, Json<HealthStatus>) {
+    let status = state.status();
+    let ready = !state.shutdown.is_cancelled()
+        && status.first_index_scan
+        && (status.lsp_server_ready || state.policy == HealthPolicy::IndexOnly);
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(status))
+}
@@ -1,98 +1,452 @@
 use crate::{
-    CONFIG, DEFAULT_CHUNKS_EMBEDDING_FIELD, DEFAULT_CHUNKS_END_LINE_FIELD, DEFAULT_CHUNKS_ID_FIELD,
-    DEFAULT_CHUNKS_PATH_FIELD, DEFAULT_CHUNKS_START_LINE_FIELD, subsystems::chunker::ArcTextChunk,
+    HooksConfig, PreviousIndex, TableConfig, Workspace, get_or_create_table, is_remote_store,
+    remove_previous_index,
+    repositories::{
+        backend::{RouteTable, RoutedBackend, VectorBackend},
+        create_fts_index, vacuum,
+    },
+    services::{indexed_roots, normalize_path, storage_path},
+    subsystems::{
+        chunker::{ArcTextChunk, ChunkGenerations},
+        metrics::{CHANNEL_DEPTH, CHUNKS_INDEXED, EMBEDDING_BATCH_SECONDS},
+        states::{SubsystemKind, SubsystemState, SubsystemStates},
+        watcher::PathEvent,
+        with_shutdown_deadline,
+    },
 };
 use arrow_array::{
-    ArrayRef, FixedSizeListArray, Int64Array, RecordBatch, RecordBatchIterator, StringArray,
-    types::Float64Type,
+    ArrayRef, FixedSizeListArray, Int64Array, RecordBatch, StringArray, types::Float64Type,
 };
 use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::TryFutureExt;
 use itertools::Itertools;
 use lancedb::{
-    Table,
+    Connection,
     arrow::arrow_schema::{DataType, Field, Fields, Schema},
-    table::{OptimizeAction, OptimizeOptions},
 };
+use metrics::{counter, gauge, histogram};
 use miette::{IntoDiagnostic, Result};
+use notify_debouncer_full::notify::{EventKind, event::ModifyKind};
 use rig::{
     OneOrMany,
     embeddings::{Embedding, EmbeddingsBuilder},
 };
-use rig_fastembed::EmbeddingModel;
-use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering},
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    mpsc::{Receiver, Sender, error::TrySendError},
+    watch,
 };
-use tokio::sync::mpsc::Receiver;
 use tokio_graceful_shutdown::{FutureExt, IntoSubsystem, SubsystemHandle};
-use tracing::{info, trace};
+use tracing::{error, info, trace, warn};
+
+/// Times of the last changes of the chunks table made by the indexer
+#[derive(Debug, Default)]
+pub struct IndexTimestamps {
+    pub last_write: Mutex<Option<DateTime<Utc>>>,
+    pub last_optimize: Mutex<Option<DateTime<Utc>>>,
+    pub last_vacuum: Mutex<Option<DateTime<Utc>>>,
+    /// Bytes removed by the vacuums since the start
+    pub reclaimed_bytes: AtomicU64,
+}
+
+impl IndexTimestamps {
+    pub fn touch_write(&self) {
+        if let Ok(mut last_write) = self.last_write.lock() {
+            *last_write = Some(Utc::now());
+        }
+    }
+
+    pub fn touch_optimize(&self) {
+        if let Ok(mut last_optimize) = self.last_optimize.lock() {
+            *last_optimize = Some(Utc::now());
+        }
+    }
+
+    pub fn touch_vacuum(&self, bytes_removed: u64) {
+        if let Ok(mut last_vacuum) = self.last_vacuum.lock() {
+            *last_vacuum = Some(Utc::now());
+        }
+        self.reclaimed_bytes
+            .fetch_add(bytes_removed, Ordering::Relaxed);
+    }
+
+    pub fn last_write(&self) -> Option<DateTime<Utc>> {
+        self.last_write.lock().ok().and_then(|it| *it)
+    }
+
+    pub fn last_optimize(&self) -> Option<DateTime<Utc>> {
+        self.last_optimize.lock().ok().and_then(|it| *it)
+    }
+
+    pub fn last_vacuum(&self) -> Option<DateTime<Utc>> {
+        self.last_vacuum.lock().ok().and_then(|it| *it)
+    }
+
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.reclaimed_bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Last failure of a file which is not indexed
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct IndexError {
+    pub path: PathBuf,
+    /// `read` if the chunker failed to read the file, `embed` if the indexer failed to embed it,
+    /// `write` if its chunks failed to be written to the table
+    pub stage: &'static str,
+    pub reason: String,
+    #[schemars(with = "String")]
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Files which failed to be indexed by their paths, the failure of a file is dropped when the
+/// file is changed, deleted or reindexed
+#[derive(Debug, Default)]
+pub struct IndexErrors {
+    errors: Mutex<HashMap<PathBuf, IndexError>>,
+}
+
+impl IndexErrors {
+    pub fn record(&self, path: &Path, stage: &'static str, reason: impl Display) {
+        if let Ok(mut errors) = self.errors.lock() {
+            errors.insert(
+                path.to_path_buf(),
+                IndexError {
+                    path: path.to_path_buf(),
+                    stage,
+                    reason: reason.to_string(),
+                    failed_at: Utc::now(),
+                },
+            );
+        }
+    }
+
+    /// Drops the failures of the files and of all files of the folders
+    pub fn clear(&self, paths: &[PathBuf]) {
+        if let Ok(mut errors) = self.errors.lock() {
+            errors.retain(|path, _| !paths.iter().any(|it| path.starts_with(it)));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.lock().map(|it| it.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Failures of the files under the path, the most recent first
+    pub fn recent(&self, path: Option<&Path>, limit: usize) -> Vec<IndexError> {
+        self.errors
+            .lock()
+            .map(|errors| {
+                errors
+                    .values()
+                    .filter(|it| path.is_none_or(|path| it.path.starts_with(path)))
+                    .sorted_by(|a, b| b.failed_at.cmp(&a.failed_at))
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Recovery of the chunks tables deleted or corrupted outside of semantrix while it is running,
+/// like by a cleanup script removing the lancedb store. The lost tables are recreated once and
+/// the workspace is reindexed, the searches report the initializing status until it is indexed
+pub struct TableRecovery {
+    db: Connection,
+    routes: Arc<RoutedBackend>,
+    /// Workspace of the tables, its table is recreated with the names of the routes
+    workspace: Arc<Workspace>,
+    /// True if the tables are written by another process, they are not recreated then
+    read_only: bool,
+    first_chunks_scan: Arc<AtomicBool>,
+    first_index_scan: Arc<watch::Sender<bool>>,
+    path_event_tx: Sender<Arc<PathEvent>>,
+    /// Set from the recreation of the tables until the reindex is indexed
+    recovering: AtomicBool,
+    /// Set by the first recovery, the tables lost again are not recreated
+    attempted: AtomicBool,
+    /// The indexer, the chunker and the searches detect the same loss, one of them recovers it
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl TableRecovery {
+    pub fn new(
+        db: Connection,
+        routes: Arc<RoutedBackend>,
+        workspace: Arc<Workspace>,
+        read_only: bool,
+        first_chunks_scan: Arc<AtomicBool>,
+        first_index_scan: Arc<watch::Sender<bool>>,
+        path_event_tx: Sender<Arc<PathEvent>>,
+    ) -> Self {
+        Self {
+            db,
+            routes,
+            workspace,
+            read_only,
+            first_chunks_scan,
+            first_index_scan,
+            path_event_tx,
+            recovering: AtomicBool::new(false),
+            attempted: AtomicBool::new(false),
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Recreates the lost tables and queues the workspace for reindexing, called after a failed
+    /// operation of a table. True if a table is lost and its recovery is started or in progress,
+    /// false for the other failures, which are reported as they are
+    pub async fn recover_if_lost(&self) -> bool {
+        let _lock = self.lock.lock().await;
+        if self.recovering.load(Ordering::Relaxed) {
+            return true;
+        }
+        let lost = self.lost_tables().await;
+        if lost.is_empty() {
+            return false;
+        }
+        let names = lost.iter().map(|(table, _)| table.table.name()).join(", ");
+        if self.read_only {
+            error!(
+                "Chunks tables {} are lost, they are not recreated in the read-only mode, restart \
+                semantrix after the indexing process rebuilds them",
+                names
+            );
+            return false;
+        }
+        if self.attempted.swap(true, Ordering::Relaxed) {
+            error!(
+                "Chunks tables {} are lost again after their recovery, restart semantrix to rebuild them",
+                names
+            );
+            return false;
+        }
+        error!(
+            "Chunks tables {} were deleted or corrupted outside of semantrix while it is running! \
+            Stop semantrix before removing its lancedb store {}. The tables are recreated and the \
+            workspace is reindexed, the searches report the initializing status until then",
+            names, self.workspace.semantic.lancedb_store
+        );
+        self.recovering.store(true, Ordering::Relaxed);
+        self.first_chunks_scan.store(false, Ordering::Relaxed);
+        self.first_index_scan.send_replace(false);
+        for (table, deleted) in lost {
+            if let Err(e) = self.recreate(table, deleted).await {
+                error!("Failed to recreate table {}: {:?}", table.table.name(), e);
+            }
+        }
+        self.queue_workspace();
+        true
+    }
+
+    /// Ends the recovery once the reindex of the workspace is indexed
+    pub fn finish(&self) {
+        if self.recovering.swap(false, Ordering::Relaxed) {
+            info!("Chunks tables are recovered, the workspace is reindexed");
+        }
+    }
+
+    /// Tables which are deleted, with true, or can't be read anymore, with false. Nothing is
+    /// lost if the tables of the store can't be listed, so an unreachable store is not recreated
+    async fn lost_tables(&self) -> Vec<(&RouteTable, bool)> {
+        let names = match self.db.table_names().execute().await {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Failed to list tables of the store: {}", e);
+                return Vec::new();
+            }
+        };
+        // a remote table which can't be read may be unreachable for a while, it is not dropped
+        let remote = is_remote_store(&self.workspace.semantic.lancedb_store);
+        let mut lost = Vec::new();
+        for table in self.routes.tables() {
+            let name = table.table.name();
+            if !names.iter().any(|it| it == name) {
+                lost.push((table, true));
+            } else if !remote && !self.is_readable(name).await {
+                lost.push((table, false));
+            }
+        }
+        lost
+    }
+
+    async fn is_readable(&self, name: &str) -> bool {
+        match self.db.open_table(name).execute().await {
+            Ok(table) => table.count_rows(None).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    async fn recreate(&self, table: &RouteTable, deleted: bool) -> Result<()> {
+        let name = table.table.name();
+        if !deleted {
+            self.db.drop_table(name).await.into_diagnostic()?;
+        }
+        let mut semantic = self.workspace.semantic.clone();
+        semantic.table.name = name.to_string();
+        get_or_create_table(&self.db, table.ndims, &semantic).await?;
+        // the indexer and the searches share the handle of the table, so they read the new one
+        table.table.checkout_latest().await.into_diagnostic()?;
+        info!("Table {} is recreated", name);
+        Ok(())
+    }
+
+    /// Queues the workspace folder and its extra roots, so the chunker reads all their files
+    /// again
+    fn queue_workspace(&self) {
+        let roots = match indexed_roots(&self.workspace) {
+            Ok(roots) => roots,
+            Err(e) => {
+                error!("Workspace is not queued for reindexing: {}", e);
+                return;
+            }
+        };
+        for path in roots {
+            self.queue_root(path);
+        }
+    }
+
+    /// Queues the folder of the workspace or of an extra root
+    fn queue_root(&self, path: PathBuf) {
+        let event = Arc::new(PathEvent {
+            path: Arc::new(normalize_path(&path)),
+            kind: EventKind::Modify(ModifyKind::Any),
+        });
+        match self.path_event_tx.try_send(event) {
+            Ok(()) => info!("Folder {} is queued for reindexing", path.display()),
+            // the chunker may be the caller, so the full queue is not awaited by it
+            Err(TrySendError::Full(event)) => {
+                let path_event_tx = self.path_event_tx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = path_event_tx.send(event).await {
+                        error!("Folder is not queued for reindexing: {}", e);
+                    }
+                });
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!(
+                    "Folder {} is not queued for reindexing, the chunker is stopped",
+                    path.display()
+                )
+            }
+        }
+    }
+}
+
+/// Chunks written since the previous completion of the index and the start of their writes
+#[derive(Debug, Default)]
+struct IndexRun {
+    started: Option<Instant>,
+    written: usize,
+}
 
 pub struct IndexerSubsystem {
+    pub workspace: Arc<Workspace>,
+    pub hooks: HooksConfig,
     pub chunks_rx: Receiver<Option<ArcTextChunk>>,
-    pub embedding_model: EmbeddingModel,
-    pub ndims: usize,
-    pub table: Table,
+    /// Generations of the chunker deletes, the stale chunks are dropped
+    pub generations: Arc<ChunkGenerations>,
+    /// Tables of the models the chunks are routed to, kept for their optimization and vacuum
+    pub routes: Arc<RoutedBackend>,
     pub first_chunks_scan: Arc<AtomicBool>,
-    pub first_index_scan: Arc<AtomicBool>,
+    pub first_index_scan: Arc<watch::Sender<bool>>,
+    pub recovery: Arc<TableRecovery>,
+    /// Backup table of the previous model searched until the first index scan is done
+    pub previous_index: watch::Sender<Option<Arc<PreviousIndex>>>,
+    pub index_timestamps: Arc<IndexTimestamps>,
+    pub index_errors: Arc<IndexErrors>,
+    pub states: Arc<SubsystemStates>,
+    /// Number of the batches written since the start, the states report the current one
+    pub batches: AtomicU64,
+    /// Budget of the write of the pending batch after the shutdown request
+    pub shutdown_timeout: Duration,
 }
 
 #[async_trait]
 impl IntoSubsystem<miette::Report> for IndexerSubsystem {
     async fn run(mut self, subsys: SubsystemHandle) -> Result<()> {
         trace!(
-            "Start indexer with embedding model: {:?}",
-            self.embedding_model.model.to_string()
+            "Start indexer with embedding models: {:?}",
+            self.routes.models()
         );
-        let mut embeddings = EmbeddingsBuilder::new(self.embedding_model.clone());
         let mut batch: Vec<ArcTextChunk> = Vec::new();
+        // generation of the last received chunk, the deletes up to it are forgotten after the
+        // batch with it is flushed
+        let mut received_generation = 0;
+        let mut index_run = IndexRun {
+            started: Some(Instant::now()),
+            written: 0,
+        };
 
         trace!("Waiting for chunks");
-        while let Some(chunk) = self.chunks_rx.recv().cancel_on_shutdown(&subsys).await? {
+        self.states
+            .set(SubsystemKind::Indexer, SubsystemState::Idle);
+        loop {
+            let chunk = match self.chunks_rx.recv().cancel_on_shutdown(&subsys).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(_) => {
+                    if !batch.is_empty() {
+                        info!("Writing pending batch of {} chunks", batch.len());
+                        with_shutdown_deadline(
+                            "Indexer",
+                            self.shutdown_timeout,
+                            self.write_batch(&mut batch).map_ok(|_| ()),
+                        )
+                        .await?;
+                    }
+                    break;
+                }
+            };
+            gauge!(CHANNEL_DEPTH, "channel" => "chunks").set(self.chunks_rx.len() as f64);
             if let Some(chunk) = chunk.as_ref() {
                 trace!("Chunk received: {:?}", chunk.id);
-                batch.push(chunk.clone());
+                received_generation = received_generation.max(chunk.generation);
+                // the file is changed again, the chunks of its previous version are not written
+                batch.retain(|it| !self.generations.is_stale(it));
+                if self.generations.is_stale(chunk) {
+                    trace!("Dropping stale chunk: {:?}", chunk.id);
+                } else {
+                    batch.push(chunk.clone());
+                }
             } else {
                 trace!("Last chunk marker received");
             }
             trace!("Batch size before batching: {}", batch.len());
-            if batch.len() == CONFIG.search.semantic.batch_size
+            let batch_bytes = batch.iter().map(|it| it.text_bytes()).sum::<usize>();
+            if batch.len() == self.workspace.semantic.batch_size
+                || batch_bytes >= self.workspace.chunk_budget.batch_bytes()
                 || (chunk.is_none() && !batch.is_empty())
             {
-                trace!("Batch size reached, deleting old records");
-                let ids = batch
-                    .iter()
-                    .format_with(",", |chunk, f| {
-                        f(&format_args!(r#""{}""#, chunk.id.to_hash()))
-                    })
-                    .to_string();
-
-                self.table
-                    .delete(&format!("id in ({})", ids))
-                    .await
-                    .into_diagnostic()?;
+                let written = self.write_batch(&mut batch).await?;
+                self.generations.flushed(received_generation);
+                if written > 0 {
+                    index_run.started.get_or_insert_with(Instant::now);
+                    index_run.written += written;
+                }
 
-                trace!("Embedding documents");
-                embeddings = embeddings
-                    .documents(batch.iter().cloned())
-                    .into_diagnostic()?;
-
-                let prepared_embeddings = embeddings.build().await.into_diagnostic()?;
-                embeddings = EmbeddingsBuilder::new(self.embedding_model.clone());
-
-                trace!("Building record batch");
-                let records_batch = as_record_batch(prepared_embeddings, self.ndims);
-
-                trace!("Adding record batch to table");
-                let record_batch_iter =
-                    RecordBatchIterator::new(vec![records_batch], Arc::new(schema(self.ndims)));
-
-                self.table
-                    .add(record_batch_iter)
-                    .execute()
-                    .await
-                    .into_diagnostic()?;
-
-                batch.clear();
+                if self.is_optimize_due() {
+                    trace!("Optimizing index after the optimize interval");
+                    self.or_recover(self.optimize().await).await?;
+                }
             }
 
             trace!("Batch size after batching: {}", batch.len());
@@ -103,47 +457,365 @@ impl IntoSubsystem<miette::Report> for IndexerSubsystem {
                 && chunk.is_none()
             {
                 info!("Optimizing index after all chunks are processed");
-                self.table
-                    .optimize(OptimizeAction::Index(OptimizeOptions::default()))
-                    .await
-                    .into_diagnostic()?;
+                self.or_recover(self.optimize().await).await?;
+                if self.workspace.semantic.index_fts {
+                    for table in self.routes.tables() {
+                        self.or_recover(
+                            create_fts_index(&table.table, &self.workspace.semantic.table).await,
+                        )
+                        .await?;
+                    }
+                }
+                // the recovery of a lost table starts the scan again
+                if !self.first_chunks_scan.load(Ordering::Relaxed) {
+                    continue;
+                }
                 trace!("Index optimized, setting first index scan to true");
-                self.first_index_scan.store(true, Ordering::Relaxed);
+                let initial = !self.first_index_scan.send_replace(true);
+                self.recovery.finish();
+                if let Some(previous) = self.previous_index.send_replace(None) {
+                    info!(
+                        "Table is rebuilt, backup table {} of model {} is not searched anymore",
+                        previous.table_name, previous.model_id
+                    );
+                    remove_previous_index(&self.workspace.semantic);
+                }
+                self.complete_run(std::mem::take(&mut index_run), initial)
+                    .await;
+            }
+
+            // the indexer is idle, so the vacuum doesn't race its writes
+            if chunk.is_none() && self.chunks_rx.is_empty() && self.is_vacuum_due() {
+                self.vacuum().await;
             }
+            self.states
+                .set(SubsystemKind::Indexer, SubsystemState::Idle);
         }
+        self.states
+            .set(SubsystemKind::Indexer, SubsystemState::Stopped);
         info!("Indexer finished");
         Ok(())
     }
 }
 
-pub fn schema(dims: usize) -> Schema {
+impl IndexerSubsystem {
+    /// Result of the operation of the tables, the failure of a lost table is logged and skipped
+    /// once its recovery is started
+    async fn or_recover<T: Default>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(e) if self.recovery.recover_if_lost().await => {
+                warn!("Operation of the lost table is skipped: {:?}", e);
+                Ok(T::default())
+            }
+            result => result,
+        }
+    }
+
+    /// Optimizes the tables of all models
+    async fn optimize(&self) -> Result<()> {
+        self.states
+            .set(SubsystemKind::Indexer, SubsystemState::Optimizing);
+        for table in self.routes.tables() {
+            table
+                .table
+                .optimize(self.workspace.semantic.optimize.action())
+                .await
+                .into_diagnostic()?;
+        }
+        self.index_timestamps.touch_optimize();
+        Ok(())
+    }
+
+    /// True if the vacuum interval is set and passed since the last vacuum, the first vacuum
+    /// follows the initial index scan
+    fn is_vacuum_due(&self) -> bool {
+        let interval = self.workspace.semantic.optimize.vacuum_interval_sec;
+        interval > 0
+            && *self.first_index_scan.borrow()
+            && self
+                .index_timestamps
+                .last_vacuum()
+                .is_none_or(|it| Utc::now() - it >= TimeDelta::seconds(interval as i64))
+    }
+
+    /// Vacuums the tables of all models, a failed vacuum is retried after the interval
+    async fn vacuum(&self) {
+        self.states
+            .set(SubsystemKind::Indexer, SubsystemState::Vacuuming);
+        let retention = Duration::from_secs(self.workspace.semantic.optimize.vacuum_retention_sec);
+        let mut bytes_removed = 0;
+        for table in self.routes.tables() {
+            match vacuum(&table.table, retention).await {
+                Ok(stats) => {
+                    info!(
+                        "Vacuum of table of model {} reclaimed {} bytes of {} old versions and {} compacted fragments",
+                        table.model,
+                        stats.bytes_removed,
+                        stats.old_versions,
+                        stats.fragments_removed
+                    );
+                    bytes_removed += stats.bytes_removed;
+                }
+                Err(e) => warn!("Vacuum of table of model {} failed: {:?}", table.model, e),
+            }
+        }
+        self.index_timestamps.touch_vacuum(bytes_removed);
+    }
+
+    /// Runs the completion hook after the first index scan and the large reindexes. The run is
+    /// reset by every completion, so a completion without new writes never runs it again
+    async fn complete_run(&self, run: IndexRun, initial: bool) {
+        let event = if initial {
+            "initial"
+        } else if run.written > 0 && run.written >= self.hooks.min_reindex_chunks {
+            "reindex"
+        } else {
+            return;
+        };
+        let Some((program, args)) = self.hooks.on_index_complete.split_first() else {
+            return;
+        };
+        let chunks = self
+            .routes
+            .count_chunks()
+            .await
+            .inspect_err(|e| warn!("Failed to count chunks for the index hook: {:?}", e))
+            .unwrap_or_default();
+        let elapsed = run.started.map(|it| it.elapsed()).unwrap_or_default();
+        info!(
+            "Running index hook {} after the {} index of {} chunks",
+            program, event, run.written
+        );
+        let mut command = tokio::process::Command::new(program);
+        command
+            .args(args)
+            .env("SEMANTRIX_INDEX_EVENT", event)
+            .env("SEMANTRIX_WORKSPACE", &self.workspace.name)
+            .env("SEMANTRIX_CHUNKS", chunks.to_string())
+            .env("SEMANTRIX_WRITTEN_CHUNKS", run.written.to_string())
+            .env("SEMANTRIX_ELAPSED_MS", elapsed.as_millis().to_string())
+            // the stdout of the server may be the MCP transport
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        let program = program.clone();
+        // the hook is not awaited, so a slow hook doesn't hold the indexer
+        tokio::spawn(async move {
+            match command.output().await {
+                Ok(output) if output.status.success() => info!("Index hook {} finished", program),
+                Ok(output) => warn!(
+                    "Index hook {} failed with {}: {}",
+                    program,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => warn!("Failed to run index hook {}: {}", program, e),
+            }
+        });
+    }
+
+    /// Replaces the stored chunks of the batch by the ones embedded by the models of their
+    /// routes and clears the batch, the chunks of the files deleted by the chunker during the
+    /// write are deleted again after it. The files which failed to be embedded or written are
+    /// recorded in [`IndexErrors`] and skipped. Returns the number of the written chunks
+    async fn write_batch(&self, batch: &mut Vec<ArcTextChunk>) -> Result<usize> {
+        batch.retain(|it| !self.generations.is_stale(it));
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let batch_number = self.batches.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut groups = batch
+            .iter()
+            .cloned()
+            .into_group_map_by(|it| self.routes.table_of(&it.path).model.clone());
+        let mut written = 0;
+        let mut failed_writes = false;
+        for table in self.routes.tables() {
+            let Some(chunks) = groups.remove(&table.model) else {
+                continue;
+            };
+            trace!("Embedding documents by model {}", table.model);
+            self.states.set(
+                SubsystemKind::Indexer,
+                SubsystemState::Embedding {
+                    batch: batch_number,
+                    chunks: chunks.len(),
+                },
+            );
+            let paths = chunks
+                .iter()
+                .map(|it| it.path.clone())
+                .unique()
+                .collect_vec();
+            let started = Instant::now();
+            let prepared_embeddings = async {
+                EmbeddingsBuilder::new(table.embedding_model.clone())
+                    .documents(chunks)
+                    .into_diagnostic()?
+                    .build()
+                    .await
+                    .into_diagnostic()
+            }
+            .await;
+            histogram!(EMBEDDING_BATCH_SECONDS).record(started.elapsed().as_secs_f64());
+            // the files of the failed batch are skipped until their next change
+            let prepared_embeddings = match prepared_embeddings {
+                Ok(prepared_embeddings) => prepared_embeddings,
+                Err(e) => {
+                    warn!("Failed to embed chunks of {} files: {:?}", paths.len(), e);
+                    for path in paths.iter() {
+                        self.index_errors.record(path, "embed", &e);
+                    }
+                    continue;
+                }
+            };
+
+            let (prepared_embeddings, mismatched) =
+                split_mismatched(prepared_embeddings, table.ndims);
+            for (chunk, len) in mismatched.iter() {
+                let reason = format!(
+                    "Embedding of chunk {} (lines {}-{}) has {} dimensions, model {} has {}",
+                    chunk.id.to_hash(),
+                    chunk.start_line + 1,
+                    chunk.end_line,
+                    len,
+                    table.model,
+                    table.ndims
+                );
+                warn!("{} in {}", reason, chunk.path.display());
+                self.index_errors.record(&chunk.path, "embed", reason);
+            }
+            if prepared_embeddings.is_empty() {
+                continue;
+            }
+            let count = prepared_embeddings.len();
+
+            trace!("Replacing records of the batch");
+            self.states.set(
+                SubsystemKind::Indexer,
+                SubsystemState::Writing {
+                    batch: batch_number,
+                    chunks: count,
+                },
+            );
+            if let Err(e) = table.vector_store.upsert(prepared_embeddings).await {
+                warn!("Failed to write chunks of {} files: {:?}", paths.len(), e);
+                for path in paths.iter() {
+                    self.index_errors.record(path, "write", &e);
+                }
+                failed_writes = true;
+                continue;
+            }
+            written += count;
+        }
+        if written > 0 {
+            self.index_timestamps.touch_write();
+            counter!(CHUNKS_INDEXED).increment(written as u64);
+        }
+        // the files are written again by the reindex of the recovery
+        if failed_writes {
+            self.recovery.recover_if_lost().await;
+        }
+
+        // the newer chunks of the same ids are written by this indexer after the batch only
+        let stale_ids = batch
+            .iter()
+            .filter(|it| self.generations.is_stale(it))
+            .map(|it| it.id.to_hash())
+            .collect::<Vec<_>>();
+        if !stale_ids.is_empty() {
+            let deleted = self
+                .or_recover(self.routes.delete_by_ids(&stale_ids).await)
+                .await?;
+            trace!("Deleted {} chunks changed during the write", deleted);
+        }
+
+        batch.clear();
+        Ok(written)
+    }
+
+    /// True if the optimize interval is set and passed since the last optimization, the table
+    /// is not optimized by the interval before the initial index scan
+    fn is_optimize_due(&self) -> bool {
+        let interval = self.workspace.semantic.optimize.interval_sec;
+        interval > 0
+            && *self.first_index_scan.borrow()
+            && self
+                .index_timestamps
+                .last_optimize()
+                .is_none_or(|it| Utc::now() - it >= TimeDelta::seconds(interval as i64))
+    }
+}
+
+pub fn schema(dims: usize, table_config: &TableConfig) -> Schema {
     Schema::new(Fields::from(vec![
-        Field::new(DEFAULT_CHUNKS_ID_FIELD, DataType::Utf8, false),
-        Field::new(DEFAULT_CHUNKS_PATH_FIELD, DataType::Utf8, false),
-        Field::new(DEFAULT_CHUNKS_START_LINE_FIELD, DataType::Int64, false),
-        Field::new(DEFAULT_CHUNKS_END_LINE_FIELD, DataType::Int64, false),
+        Field::new(&table_config.id_field, DataType::Utf8, false),
+        Field::new(&table_config.path_field, DataType::Utf8, false),
+        Field::new(&table_config.start_line_field, DataType::Int64, false),
+        Field::new(&table_config.end_line_field, DataType::Int64, false),
         Field::new(
-            DEFAULT_CHUNKS_EMBEDDING_FIELD,
+            &table_config.embedding_field,
             DataType::FixedSizeList(
                 Arc::new(Field::new("item", DataType::Float64, true)),
                 dims as i32,
             ),
             false,
         ),
+        Field::new(&table_config.text_field, DataType::Utf8, true),
+        Field::new(&table_config.hash_field, DataType::Utf8, true),
+        Field::new(&table_config.indexed_at_field, DataType::Int64, true),
+        Field::new(&table_config.chunk_index_field, DataType::Int64, true),
+        Field::new(&table_config.total_chunks_field, DataType::Int64, true),
     ]))
 }
 
+/// Chunks with their embeddings as the model returns them
+type EmbeddedChunks = Vec<(ArcTextChunk, OneOrMany<Embedding>)>;
+
+/// Embedded chunks of the files whose embeddings all have `ndims` dimensions and the chunks of
+/// another size with it, a broken model file returns them and they don't fit the table. The
+/// other chunks of a file with such a chunk are dropped too, so the file isn't half indexed
+fn split_mismatched(
+    records: EmbeddedChunks,
+    ndims: usize,
+) -> (EmbeddedChunks, Vec<(ArcTextChunk, usize)>) {
+    let mismatched = records
+        .iter()
+        .filter_map(|(chunk, embeddings)| {
+            let len = embeddings.first().vec.len();
+            (len != ndims).then(|| (chunk.clone(), len))
+        })
+        .collect_vec();
+    let records = records
+        .into_iter()
+        .filter(|(chunk, _)| !mismatched.iter().any(|(it, _)| it.path == chunk.path))
+        .collect_vec();
+    (records, mismatched)
+}
+
 pub fn as_record_batch(
     records: Vec<(ArcTextChunk, OneOrMany<Embedding>)>,
     dims: usize,
+    table_config: &TableConfig,
 ) -> Result<RecordBatch, lancedb::arrow::arrow_schema::ArrowError> {
+    // the list array panics on the values of another size, so they are reported as the error
+    for (chunk, embeddings) in records.iter() {
+        let len = embeddings.first().vec.len();
+        if len != dims {
+            return Err(
+                lancedb::arrow::arrow_schema::ArrowError::InvalidArgumentError(format!(
+                    "Embedding of chunk {} has {} dimensions instead of {}",
+                    chunk.id.to_hash(),
+                    len,
+                    dims
+                )),
+            );
+        }
+    }
     let ids = StringArray::from_iter_values(records.iter().map(|(chunk, _)| chunk.id.to_hash()));
 
-    let paths = StringArray::from_iter_values(
-        records
-            .iter()
-            .map(|(chunk, _)| chunk.path.to_string_lossy().to_string()),
-    );
+    let paths =
+        StringArray::from_iter_values(records.iter().map(|(chunk, _)| storage_path(&chunk.path)));
 
     let start_lines =
         Int64Array::from_iter_values(records.iter().map(|(chunk, _)| chunk.start_line as i64));
@@ -151,6 +823,27 @@ pub fn as_record_batch(
     let end_lines =
         Int64Array::from_iter_values(records.iter().map(|(chunk, _)| chunk.end_line as i64));
 
+    let texts =
+        StringArray::from_iter_values(records.iter().map(|(chunk, _)| chunk.text.join("\n")));
+
+    let hashes = StringArray::from_iter(records.iter().map(|(chunk, _)| chunk.text_hash()));
+
+    // the chunks of a file are written by one batch, so they share the time of the batch
+    let indexed_at = Utc::now().timestamp_millis();
+    let indexed_ats = Int64Array::from_iter_values(records.iter().map(|_| indexed_at));
+
+    let chunk_indexes = Int64Array::from_iter(
+        records
+            .iter()
+            .map(|(chunk, _)| chunk.chunk_index.map(|it| it as i64)),
+    );
+
+    let total_chunks = Int64Array::from_iter(
+        records
+            .iter()
+            .map(|(chunk, _)| chunk.total_chunks.map(|it| it as i64)),
+    );
+
     let embedding = FixedSizeListArray::from_iter_primitive::<Float64Type, _, _>(
         records.iter().map(|(_, embeddings)| {
             Some(
@@ -166,19 +859,95 @@ pub fn as_record_batch(
     );
 
     RecordBatch::try_from_iter(vec![
-        (DEFAULT_CHUNKS_ID_FIELD, Arc::new(ids) as ArrayRef),
-        (DEFAULT_CHUNKS_PATH_FIELD, Arc::new(paths) as ArrayRef),
+        (&table_config.id_field, Arc::new(ids) as ArrayRef),
+        (&table_config.path_field, Arc::new(paths) as ArrayRef),
         (
-            DEFAULT_CHUNKS_START_LINE_FIELD,
+            &table_config.start_line_field,
             Arc::new(start_lines) as ArrayRef,
         ),
         (
-            DEFAULT_CHUNKS_END_LINE_FIELD,
+            &table_config.end_line_field,
             Arc::new(end_lines) as ArrayRef,
         ),
         (
-            DEFAULT_CHUNKS_EMBEDDING_FIELD,
+            &table_config.embedding_field,
             Arc::new(embedding) as ArrayRef,
         ),
+        (&table_config.text_field, Arc::new(texts) as ArrayRef),
+        (&table_config.hash_field, Arc::new(hashes) as ArrayRef),
+        (
+            &table_config.indexed_at_field,
+            Arc::new(indexed_ats) as ArrayRef,
+        ),
+        (
+            &table_config.chunk_index_field,
+            Arc::new(chunk_indexes) as ArrayRef,
+        ),
+        (
+            &table_config.total_chunks_field,
+            Arc::new(total_chunks) as ArrayRef,
+        ),
     ])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subsystems::chunker::TextChunk;
+
+    const NDIMS: usize = 3;
+
+    /// Chunk of the file from the line with the embedding of the dimensions
+    fn embedded(
+        path: &str,
+        start_line: usize,
+        dims: usize,
+    ) -> (ArcTextChunk, OneOrMany<Embedding>) {
+        let mut chunk = TextChunk::new(Arc::new(PathBuf::from(path)), start_line, 2);
+        chunk.text = vec!["fn main() {}".to_string(); 2];
+        let embedding = Embedding {
+            document: chunk.text.join("\n"),
+            vec: vec![0.5; dims],
+        };
+        (chunk.into(), OneOrMany::one(embedding))
+    }
+
+    #[test]
+    fn short_embedding_is_an_error_instead_of_a_panic() {
+        let table_config = TableConfig::default();
+        let records = vec![embedded("main.rs", 0, NDIMS), embedded("main.rs", 2, 1)];
+
+        let error = as_record_batch(records, NDIMS, &table_config).unwrap_err();
+
+        assert!(
+            error.to_string().contains("has 1 dimensions instead of 3"),
+            "{}",
+            error
+        );
+        let batch =
+            as_record_batch(vec![embedded("main.rs", 0, NDIMS)], NDIMS, &table_config).unwrap();
+        assert_eq!(batch.num_rows(), 1);
+    }
+
+    #[test]
+    fn files_with_short_embeddings_are_split_off() {
+        let records = vec![
+            embedded("broken.rs", 0, NDIMS),
+            embedded("broken.rs", 2, 0),
+            embedded("main.rs", 0, NDIMS),
+        ];
+
+        let (records, mismatched) = split_mismatched(records, NDIMS);
+
+        let kept = records
+            .iter()
+            .map(|(chunk, _)| (chunk.path.to_path_buf(), chunk.start_line))
+            .collect_vec();
+        assert_eq!(kept, [(PathBuf::from("main.rs"), 0)]);
+        let mismatched = mismatched
+            .iter()
+            .map(|(chunk, len)| (chunk.path.to_path_buf(), chunk.start_line, *len))
+            .collect_vec();
+        assert_eq!(mismatched, [(PathBuf::from("broken.rs"), 2, 0)]);
+    }
+}
@@ -1,119 +1,677 @@
-use crate::{CONFIG, enums::McpProgressToken};
+use crate::{
+    FuzzyConfig, HoverCacheConfig, Workspace,
+    enums::{PositionEncoding, ReadinessTokensMode},
+    errors::SemantrixError,
+    services::{SymbolInfo, normalize_path, url_to_path},
+    subsystems::{
+        metrics::{LSP_REQUEST_ERRORS, LSP_REQUEST_SECONDS},
+        states::{SubsystemKind, SubsystemState, SubsystemStates},
+        watcher::PathEvent,
+        with_shutdown_deadline,
+    },
+};
 use async_lsp_client::{LspServer, ServerMessage};
 use async_trait::async_trait;
+use lru::LruCache;
 use lsp_types::{
     ClientCapabilities, ClientInfo, DocumentSymbolClientCapabilities, DocumentSymbolParams,
-    DocumentSymbolResponse, Hover, HoverClientCapabilities, HoverParams, InitializeParams,
-    Location, MarkupKind, NumberOrString, PartialResultParams, Position, ProgressParams,
-    ProgressParamsValue, ReferenceContext, ReferenceParams, SymbolKind, SymbolKindCapability,
-    TextDocumentClientCapabilities, TextDocumentIdentifier, TextDocumentPositionParams, Url,
-    WindowClientCapabilities, WorkDoneProgress, WorkDoneProgressParams,
-    WorkspaceClientCapabilities, WorkspaceFolder, WorkspaceSymbolClientCapabilities,
-    WorkspaceSymbolParams, WorkspaceSymbolResponse,
+    DocumentSymbolResponse, GeneralClientCapabilities, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverClientCapabilities, HoverParams, HoverProviderCapability,
+    InitializeParams, Location, MarkupKind, NumberOrString, OneOf, PartialResultParams, Position,
+    PositionEncodingKind, ProgressParams, ProgressParamsValue, ReferenceContext, ReferenceParams,
+    ServerCapabilities, SymbolKind, SymbolKindCapability, TextDocumentClientCapabilities,
+    TextDocumentIdentifier, TextDocumentPositionParams, Url, WindowClientCapabilities,
+    WorkDoneProgress, WorkDoneProgressParams, WorkspaceClientCapabilities, WorkspaceFolder,
+    WorkspaceSymbolClientCapabilities, WorkspaceSymbolParams, WorkspaceSymbolResponse,
     request::{
-        DocumentSymbolRequest, HoverRequest, References, Request, Shutdown, WorkDoneProgressCreate,
-        WorkspaceSymbolRequest,
+        DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request, Shutdown,
+        WorkDoneProgressCreate, WorkspaceSymbolRequest,
+    },
+};
+use metrics::{counter, histogram};
+use miette::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    hash::Hash,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
     },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::{
+    Semaphore, SemaphorePermit,
+    broadcast::{self, error::RecvError},
+    mpsc,
+    watch::Sender,
 };
-use miette::{IntoDiagnostic, Result};
-use std::{path::Path, str::FromStr, sync::Arc};
-use tokio::sync::{Semaphore, mpsc, watch::Sender};
 use tokio_graceful_shutdown::{IntoSubsystem, SubsystemHandle};
 use tower_lsp::jsonrpc::{self};
-use tracing::{debug, error, info, trace, warn};
+use tracing::{Instrument, debug, error, info, info_span, trace, warn};
 
 use crate::{NAME, VERSION};
 
+/// Name of rust-analyzer in the server info of its initialize result
+const RUST_ANALYZER: &str = "rust-analyzer";
+
+/// Symbol kinds of the types reported by rust-analyzer, the structs, the unions and the builtin
+/// types are structs, the traits are interfaces and the type aliases are type parameters
+const RUST_ANALYZER_TYPE_KINDS: [SymbolKind; 4] = [
+    SymbolKind::STRUCT,
+    SymbolKind::ENUM,
+    SymbolKind::INTERFACE,
+    SymbolKind::TYPE_PARAMETER,
+];
+
+/// Symbols searched by rust-analyzer, the `searchKind` extension of the workspace/symbol params
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WorkspaceSymbolSearchKind {
+    OnlyTypes,
+    AllSymbols,
+}
+
+impl WorkspaceSymbolSearchKind {
+    /// Narrowest search returning all the symbols of the kinds, None for all the kinds
+    fn of(kinds: Option<&[SymbolKind]>) -> Option<Self> {
+        let kinds = kinds?;
+        if !kinds.is_empty() && kinds.iter().all(|it| RUST_ANALYZER_TYPE_KINDS.contains(it)) {
+            Some(WorkspaceSymbolSearchKind::OnlyTypes)
+        } else {
+            Some(WorkspaceSymbolSearchKind::AllSymbols)
+        }
+    }
+}
+
+/// Workspace symbol params with the extensions narrowing the symbols on the server side
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KindedWorkspaceSymbolParams {
+    #[serde(flatten)]
+    pub params: WorkspaceSymbolParams,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search_kind: Option<WorkspaceSymbolSearchKind>,
+}
+
+/// The workspace/symbol request with [`KindedWorkspaceSymbolParams`]
+pub enum KindedWorkspaceSymbolRequest {}
+
+impl Request for KindedWorkspaceSymbolRequest {
+    type Params = KindedWorkspaceSymbolParams;
+    type Result = Option<WorkspaceSymbolResponse>;
+    const METHOD: &'static str = WorkspaceSymbolRequest::METHOD;
+}
+
+/// Cached response with the normalized path of its document
+struct CachedResponse<V> {
+    cached_at: Instant,
+    /// Modification time of the document the response is about
+    modified: Option<SystemTime>,
+    path: Option<PathBuf>,
+    value: V,
+}
+
+/// Responses about the documents bounded by the number of entries, the responses of a document
+/// are dropped when the watcher reports its change. Nothing is cached if the capacity is 0
+struct DocumentResponses<K: Hash + Eq, V> {
+    name: &'static str,
+    entries: Option<Mutex<LruCache<K, CachedResponse<V>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Hash + Eq + Clone + Debug, V: Clone> DocumentResponses<K, V> {
+    fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            name,
+            entries: NonZeroUsize::new(capacity)
+                .map(|capacity| Mutex::new(LruCache::new(capacity))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cached response if it is still valid, the invalid one is dropped
+    fn get(&self, key: &K, is_valid: impl Fn(&CachedResponse<V>) -> bool) -> Option<V> {
+        let mut entries = self
+            .entries
+            .as_ref()?
+            .lock()
+            .expect("Responses cache is poisoned");
+        let cached = entries.get(key).map(|it| (is_valid(it), it.value.clone()));
+        let value = match cached {
+            Some((true, value)) => Some(value),
+            Some((false, _)) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        let (hits, misses) = match value {
+            Some(_) => (
+                self.hits.fetch_add(1, Ordering::Relaxed) + 1,
+                self.misses.load(Ordering::Relaxed),
+            ),
+            None => (
+                self.hits.load(Ordering::Relaxed),
+                self.misses.fetch_add(1, Ordering::Relaxed) + 1,
+            ),
+        };
+        debug!(
+            "{} cache {} for {:?}, hits: {}, misses: {}",
+            self.name,
+            if value.is_some() { "hit" } else { "miss" },
+            key,
+            hits,
+            misses
+        );
+        value
+    }
+
+    fn put(&self, key: K, document_uri: &Url, modified: Option<SystemTime>, value: V) {
+        let Some(entries) = self.entries.as_ref() else {
+            return;
+        };
+        let path = url_to_path(document_uri).map(|path| normalize_path(&path));
+        entries.lock().expect("Responses cache is poisoned").put(
+            key,
+            CachedResponse {
+                cached_at: Instant::now(),
+                modified,
+                path,
+                value,
+            },
+        );
+    }
+
+    /// Drops the responses of the file or of all files of the folder
+    fn invalidate(&self, path: &Path) {
+        let Some(entries) = self.entries.as_ref() else {
+            return;
+        };
+        let mut entries = entries.lock().expect("Responses cache is poisoned");
+        let stale = entries
+            .iter()
+            .filter(|(_, cached)| cached.path.as_ref().is_some_and(|it| it.starts_with(path)))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in stale.iter() {
+            entries.pop(key);
+        }
+        if !stale.is_empty() {
+            debug!(
+                "{} cache dropped {} responses of {}",
+                self.name,
+                stale.len(),
+                path.display()
+            );
+        }
+    }
+
+    fn clear(&self) {
+        if let Some(entries) = self.entries.as_ref() {
+            entries.lock().expect("Responses cache is poisoned").clear();
+        }
+    }
+}
+
+/// Hover responses by the document and the position, see [`HoverCacheConfig`]
+pub struct HoverCache {
+    /// Keyed by the document, the line and the character, `Position` isn't `Hash`
+    responses: DocumentResponses<(Url, u32, u32), Option<Hover>>,
+    ttl: Duration,
+}
+
+impl From<&HoverCacheConfig> for HoverCache {
+    fn from(config: &HoverCacheConfig) -> Self {
+        Self {
+            responses: DocumentResponses::new("Hover", config.capacity),
+            ttl: Duration::from_secs(config.ttl_sec),
+        }
+    }
+}
+
+impl HoverCache {
+    /// Cached response of the position, None if it is not cached or is expired
+    fn get(&self, document_uri: &Url, position: Position) -> Option<Option<Hover>> {
+        self.responses.get(
+            &(document_uri.clone(), position.line, position.character),
+            |cached| cached.cached_at.elapsed() < self.ttl,
+        )
+    }
+
+    fn put(&self, document_uri: Url, position: Position, hover: Option<Hover>) {
+        self.responses.put(
+            (document_uri.clone(), position.line, position.character),
+            &document_uri,
+            None,
+            hover,
+        );
+    }
+
+    pub fn invalidate(&self, path: &Path) {
+        self.responses.invalidate(path);
+    }
+
+    pub fn clear(&self) {
+        self.responses.clear();
+    }
+}
+
+/// Symbols of the documents before their code and hover are read, they are valid until the
+/// document is modified
+pub struct DocumentSymbolsCache {
+    responses: DocumentResponses<Url, Vec<SymbolInfo>>,
+}
+
+impl DocumentSymbolsCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            responses: DocumentResponses::new("Document symbols", capacity),
+        }
+    }
+
+    /// Cached symbols of the document if it isn't modified after they were requested
+    pub fn get(&self, document_uri: &Url, modified: SystemTime) -> Option<Vec<SymbolInfo>> {
+        self.responses
+            .get(document_uri, |cached| cached.modified == Some(modified))
+    }
+
+    /// Caches the symbols requested for the document of the modification time
+    pub fn put(&self, document_uri: Url, modified: SystemTime, symbols: Vec<SymbolInfo>) {
+        self.responses
+            .put(document_uri.clone(), &document_uri, Some(modified), symbols);
+    }
+
+    pub fn invalidate(&self, path: &Path) {
+        self.responses.invalidate(path);
+    }
+
+    pub fn clear(&self) {
+        self.responses.clear();
+    }
+}
+
+/// Progress of a pending request reported by the `$/progress` notifications of its tokens
+#[derive(Debug)]
+pub enum RequestProgress {
+    WorkDone(WorkDoneProgress),
+    /// Part of the result, the final response has only the rest of it
+    Partial(Value),
+}
+
+/// Kind of the progress token sent with a request
+#[derive(Clone, Copy, Debug)]
+enum ProgressKind {
+    WorkDone,
+    Partial,
+}
+
+/// Channels of the pending requests by their progress tokens, the server message receiver routes
+/// the `$/progress` notifications to them
+#[derive(Default)]
+pub struct PendingProgress {
+    requests: Mutex<HashMap<String, (ProgressKind, mpsc::UnboundedSender<RequestProgress>)>>,
+    next_id: AtomicU64,
+}
+
+/// Work done and partial result tokens of one request, they are unregistered on drop
+struct ProgressTokens<'a> {
+    pending: &'a PendingProgress,
+    work_done: String,
+    partial_result: String,
+}
+
+impl Drop for ProgressTokens<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut requests) = self.pending.requests.lock() {
+            requests.remove(&self.work_done);
+            requests.remove(&self.partial_result);
+        }
+    }
+}
+
+impl PendingProgress {
+    /// Unique tokens of the request of the method with the receiver of their progress
+    fn register(
+        &self,
+        method: &str,
+    ) -> (ProgressTokens<'_>, mpsc::UnboundedReceiver<RequestProgress>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        let tokens = ProgressTokens {
+            pending: self,
+            work_done: format!("{}/{}/{}/work_done", NAME, method, id),
+            partial_result: format!("{}/{}/{}/partial_result", NAME, method, id),
+        };
+        let mut requests = self.requests.lock().expect("Pending progress is poisoned");
+        requests.insert(
+            tokens.work_done.clone(),
+            (ProgressKind::WorkDone, progress_tx.clone()),
+        );
+        requests.insert(
+            tokens.partial_result.clone(),
+            (ProgressKind::Partial, progress_tx),
+        );
+        (tokens, progress_rx)
+    }
+
+    /// Sends the progress of the `$/progress` params to the request of its token, false if no
+    /// request waits for it, like the progress of the server's own work
+    fn route(&self, params: &Value) -> bool {
+        let token = match params.get("token") {
+            Some(Value::String(token)) => token.clone(),
+            Some(Value::Number(token)) => token.to_string(),
+            _ => return false,
+        };
+        let Some(value) = params.get("value").cloned() else {
+            return false;
+        };
+        let Ok(requests) = self.requests.lock() else {
+            return false;
+        };
+        let Some((kind, progress_tx)) = requests.get(&token) else {
+            return false;
+        };
+        let progress = match kind {
+            ProgressKind::Partial => RequestProgress::Partial(value),
+            ProgressKind::WorkDone => match serde_json::from_value(value) {
+                Ok(progress) => RequestProgress::WorkDone(progress),
+                Err(e) => {
+                    warn!("Invalid work done progress of {}: {}", token, e);
+                    return true;
+                }
+            },
+        };
+        progress_tx.send(progress).is_ok()
+    }
+}
+
+/// LSP server shared by the tools, a request whose future is dropped, like the one of a tool call
+/// cancelled by the client, stops waiting for its response and frees its permit
+// TODO: the server should get `$/cancelRequest` too, async-lsp-client doesn't expose the ids of
+// the requests for it
 #[derive(Clone)]
 pub struct GuardedLspServer {
     server: LspServer,
+    /// Workspace the server is started for
+    workspace: Arc<Workspace>,
+    /// Cap of the code read for a symbol, see `memory.max_symbol_code_bytes`
+    max_symbol_code_bytes: usize,
     guard: Arc<Semaphore>,
+    hover_cache: Arc<HoverCache>,
+    document_symbols_cache: Arc<DocumentSymbolsCache>,
+    progress: Arc<PendingProgress>,
+    /// Capabilities of the initialize result, None if the server failed to initialize
+    capabilities: Option<Arc<ServerCapabilities>>,
+    /// Unit of the characters of the positions sent to and received from the server
+    position_encoding: PositionEncoding,
+    /// True if the server narrows the workspace symbols by [`WorkspaceSymbolSearchKind`]
+    search_kind: bool,
+    request_timeout: Option<Duration>,
+    document_symbols_timeout: Option<Duration>,
 }
 
 impl GuardedLspServer {
+    pub fn document_symbols_cache(&self) -> &DocumentSymbolsCache {
+        &self.document_symbols_cache
+    }
+
+    /// Fuzzy config of the workspace, its limits apply to the requests of the server
+    pub fn config(&self) -> &FuzzyConfig {
+        &self.workspace.fuzzy
+    }
+
+    /// Workspace of the server, its paths are the ones of the symbols of the responses
+    pub fn workspace(&self) -> &Workspace {
+        &self.workspace
+    }
+
+    pub fn max_symbol_code_bytes(&self) -> usize {
+        self.max_symbol_code_bytes
+    }
+
+    /// Encoding of the characters of the positions, see [`PositionEncoding::column`]
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.position_encoding
+    }
+
+    /// Permit limiting the number of requests sent to the LSP server at the same time
+    async fn permit(&self) -> Result<SemaphorePermit<'_>> {
+        match self.guard.try_acquire() {
+            Ok(permit) => Ok(permit),
+            Err(e) => {
+                warn!("LSP server is busy: {:?}", e);
+                self.guard.acquire().await.map_err(|e| {
+                    SemantrixError::lsp_transport(format!("LSP server permits are closed: {}", e))
+                        .into()
+                })
+            }
+        }
+    }
+
     pub async fn shutdown(&self) -> Result<()> {
-        let _permit = self.guard.acquire().await.into_diagnostic()?;
+        let _permit = self.guard.acquire().await.map_err(|e| {
+            SemantrixError::lsp_transport(format!("LSP server permits are closed: {}", e))
+        })?;
         info!("Shutting down LSP server");
-        self.server.shutdown().await.into_diagnostic()?;
+        self.server.shutdown().await.map_err(|e| {
+            SemantrixError::lsp_transport(format!("LSP shutdown request failed: {}", e))
+        })?;
         info!("Exiting LSP server");
         self.server.exit().await;
         info!("LSP server shutdown");
         Ok(())
     }
 
+    /// Sends the request of the method under a permit and converts its failure, the request is
+    /// not sent if the server declared that it doesn't serve the method. Fails with the timeout
+    /// error if no response comes within `search.fuzzy.request_timeout_ms`
+    pub async fn request<R>(&self, params: R::Params) -> Result<R::Result>
+    where
+        R: Request,
+        R::Result: Debug,
+    {
+        self.check_capability(R::METHOD)?;
+        async {
+            let _permit = self.permit().await?;
+            let started = Instant::now();
+            let request = self.server.send_request::<R>(params);
+            let response = match self.request_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, request).await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        counter!(LSP_REQUEST_ERRORS, "method" => R::METHOD).increment(1);
+                        return Err(SemantrixError::lsp_timeout(format!(
+                            "LSP request {} timed out after {} ms",
+                            R::METHOD,
+                            timeout.as_millis()
+                        ))
+                        .with_help("raise search.fuzzy.request_timeout_ms")
+                        .into());
+                    }
+                },
+                None => request.await,
+            };
+            record_request(R::METHOD, started, &response);
+            response
+                .inspect(|it| {
+                    info!("Response of {}: {:?}", R::METHOD, it);
+                })
+                .map_err(|e| {
+                    error!("Error sending {} request: {:?}", R::METHOD, e);
+                    SemantrixError::lsp_transport(format!(
+                        "LSP request {} failed: {}",
+                        R::METHOD,
+                        e
+                    ))
+                    .into()
+                })
+        }
+        .instrument(info_span!("lsp_request", method = R::METHOD))
+        .await
+    }
+
+    /// Fails if the initialize result of the server has no provider of the method, every method
+    /// is sent if the server failed to initialize
+    fn check_capability(&self, method: &'static str) -> Result<()> {
+        match &self.capabilities {
+            Some(capabilities) if !is_supported(capabilities, method) => {
+                counter!(LSP_REQUEST_ERRORS, "method" => method).increment(1);
+                Err(SemantrixError::lsp_transport(format!(
+                    "LSP server doesn't support {} requests",
+                    method
+                ))
+                .with_help(
+                    "use an LSP server which declares the capability in its initialize result",
+                )
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Workspace symbols of the query, the kinds are sent to the servers which narrow the
+    /// symbols by them, the other servers return all the kinds. None kinds are all the kinds
     pub async fn send_workspace_symbol_request(
         &self,
         query: String,
+        kinds: Option<&[SymbolKind]>,
     ) -> Result<Option<WorkspaceSymbolResponse>> {
-        if let Err(e) = self.guard.try_acquire() {
-            warn!("LSP server is busy: {:?}", e);
-            let _permit = self.guard.acquire().await.into_diagnostic()?;
-        }
         info!("Sending workspace symbol request: {}", query);
-        self.server
-            .send_request::<WorkspaceSymbolRequest>(WorkspaceSymbolParams {
-                query,
-                ..Default::default()
-            })
-            .await
-            .inspect(|it| {
-                info!("Workspace symbols response: {:?}", it);
-            })
-            .inspect_err(|e| {
-                error!("Error sending workspace symbol request: {:?}", e);
-            })
-            .into_diagnostic()
+        let params = WorkspaceSymbolParams {
+            query,
+            ..Default::default()
+        };
+        match WorkspaceSymbolSearchKind::of(kinds).filter(|_| self.search_kind) {
+            Some(search_kind) => {
+                debug!("Workspace symbols are searched by {:?}", search_kind);
+                self.request::<KindedWorkspaceSymbolRequest>(KindedWorkspaceSymbolParams {
+                    params,
+                    search_kind: Some(search_kind),
+                })
+                .await
+            }
+            None => self.request::<WorkspaceSymbolRequest>(params).await,
+        }
     }
 
+    /// Symbols of the document with the partial results streamed by the server before the final
+    /// response, the partial results are returned alone if the timeout comes first
     pub async fn send_document_symbol_request(
         &self,
         document_uri: Url,
     ) -> Result<Option<DocumentSymbolResponse>> {
-        self.server
-            .send_request::<DocumentSymbolRequest>(DocumentSymbolParams {
-                text_document: TextDocumentIdentifier::new(document_uri.clone()),
-                work_done_progress_params: WorkDoneProgressParams {
-                    work_done_token: None,
+        let started = Instant::now();
+        let (tokens, mut progress_rx) = self.progress.register(DocumentSymbolRequest::METHOD);
+        let request = self.request::<DocumentSymbolRequest>(DocumentSymbolParams {
+            text_document: TextDocumentIdentifier::new(document_uri.clone()),
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: Some(NumberOrString::String(tokens.work_done.clone())),
+            },
+            partial_result_params: PartialResultParams {
+                partial_result_token: Some(NumberOrString::String(tokens.partial_result.clone())),
+            },
+        });
+        tokio::pin!(request);
+        let deadline = async {
+            match self.document_symbols_timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(deadline);
+
+        let mut partial = None;
+        let response = loop {
+            tokio::select! {
+                response = &mut request => break Some(response),
+                Some(progress) = progress_rx.recv() => match progress {
+                    RequestProgress::Partial(value) => {
+                        match serde_json::from_value::<DocumentSymbolResponse>(value) {
+                            Ok(symbols) => partial = merge_document_symbols(partial, symbols),
+                            Err(e) => warn!("Invalid partial document symbols of {}: {}", document_uri, e),
+                        }
+                    }
+                    RequestProgress::WorkDone(progress) => {
+                        debug!("Document symbols progress of {}: {:?}", document_uri, progress);
+                    }
                 },
-                partial_result_params: PartialResultParams::default(),
-            })
-            .await
-            .inspect(|it| {
-                info!("Document symbols response: {:?}", it);
-            })
-            .inspect_err(|e| {
-                error!("Error sending document symbol request: {:?}", e);
-            })
-            .into_diagnostic()
+                _ = &mut deadline => break None,
+            }
+        };
+        drop(tokens);
+
+        let Some(response) = response else {
+            counter!(LSP_REQUEST_ERRORS, "method" => DocumentSymbolRequest::METHOD).increment(1);
+            return match partial {
+                Some(symbols) => {
+                    warn!(
+                        "Document symbols request of {} timed out, using the partial results",
+                        document_uri
+                    );
+                    Ok(Some(symbols))
+                }
+                None => Err(SemantrixError::lsp_timeout(format!(
+                    "Document symbols request of {} timed out after {} ms",
+                    document_uri,
+                    started.elapsed().as_millis()
+                ))
+                .with_help("raise search.fuzzy.document_symbols_timeout_ms")
+                .into()),
+            };
+        };
+        response.map(|symbols| match (partial, symbols) {
+            (Some(partial), Some(symbols)) => merge_document_symbols(Some(partial), symbols),
+            (partial, symbols) => symbols.or(partial),
+        })
     }
 
     pub async fn send_references_request(
         &self,
         document_uri: Url,
         position: Position,
+        include_declaration: bool,
     ) -> Result<Option<Vec<Location>>> {
-        self.server
-            .send_request::<References>(ReferenceParams {
-                text_document_position: TextDocumentPositionParams {
-                    text_document: TextDocumentIdentifier::new(document_uri.clone()),
-                    position,
-                },
-                work_done_progress_params: WorkDoneProgressParams {
-                    work_done_token: None,
-                },
-                partial_result_params: PartialResultParams::default(),
-                context: ReferenceContext {
-                    include_declaration: false,
-                },
-            })
-            .await
-            .inspect(|it| {
-                info!("References response: {:?}", it);
-            })
-            .inspect_err(|e| {
-                error!("Error sending references request: {:?}", e);
-            })
-            .into_diagnostic()
+        self.request::<References>(ReferenceParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier::new(document_uri),
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+            partial_result_params: PartialResultParams::default(),
+            context: ReferenceContext {
+                include_declaration,
+            },
+        })
+        .await
+    }
+
+    pub async fn send_definition_request(
+        &self,
+        document_uri: Url,
+        position: Position,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        self.request::<GotoDefinition>(GotoDefinitionParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier::new(document_uri),
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+            partial_result_params: PartialResultParams::default(),
+        })
+        .await
     }
 
     pub async fn send_hover_request(
@@ -121,43 +679,110 @@ impl GuardedLspServer {
         document_uri: Url,
         position: Position,
     ) -> Result<Option<Hover>> {
-        self.server
-            .send_request::<HoverRequest>(HoverParams {
-                text_document_position_params: TextDocumentPositionParams {
-                    text_document: TextDocumentIdentifier::new(document_uri.clone()),
-                    position,
-                },
-                work_done_progress_params: WorkDoneProgressParams {
-                    work_done_token: None,
-                },
-            })
-            .await
-            .inspect(|it| {
-                info!("Hover response: {:?}", it);
-            })
-            .inspect_err(|e| {
-                error!("Error sending hover request: {:?}", e);
-            })
-            .into_diagnostic()
+        if let Some(hover) = self.hover_cache.get(&document_uri, position) {
+            return Ok(hover);
+        }
+        self.request::<HoverRequest>(HoverParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier::new(document_uri.clone()),
+                position,
+            },
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+        })
+        .await
+        .inspect(|hover| {
+            self.hover_cache.put(document_uri, position, hover.clone());
+        })
+    }
+}
+
+/// True if the capabilities have the provider of the method, the methods without a provider
+/// field in [`ServerCapabilities`] are always supported
+fn is_supported(capabilities: &ServerCapabilities, method: &str) -> bool {
+    match method {
+        WorkspaceSymbolRequest::METHOD => is_declared(&capabilities.workspace_symbol_provider),
+        DocumentSymbolRequest::METHOD => is_declared(&capabilities.document_symbol_provider),
+        References::METHOD => is_declared(&capabilities.references_provider),
+        GotoDefinition::METHOD => is_declared(&capabilities.definition_provider),
+        HoverRequest::METHOD => matches!(
+            capabilities.hover_provider,
+            Some(HoverProviderCapability::Simple(true) | HoverProviderCapability::Options(_))
+        ),
+        _ => true,
+    }
+}
+
+fn is_declared<T>(provider: &Option<OneOf<bool, T>>) -> bool {
+    matches!(provider, Some(OneOf::Left(true) | OneOf::Right(_)))
+}
+
+/// Symbols of the document followed by the next part of them, the part of another shape than the
+/// previous ones is dropped
+fn merge_document_symbols(
+    symbols: Option<DocumentSymbolResponse>,
+    part: DocumentSymbolResponse,
+) -> Option<DocumentSymbolResponse> {
+    match (symbols, part) {
+        (None, part) => Some(part),
+        (Some(DocumentSymbolResponse::Flat(mut symbols)), DocumentSymbolResponse::Flat(part)) => {
+            symbols.extend(part);
+            Some(DocumentSymbolResponse::Flat(symbols))
+        }
+        (
+            Some(DocumentSymbolResponse::Nested(mut symbols)),
+            DocumentSymbolResponse::Nested(part),
+        ) => {
+            symbols.extend(part);
+            Some(DocumentSymbolResponse::Nested(symbols))
+        }
+        // an empty array is parsed as the flat symbols
+        (Some(symbols), DocumentSymbolResponse::Flat(part)) if part.is_empty() => Some(symbols),
+        (Some(DocumentSymbolResponse::Flat(symbols)), part) if symbols.is_empty() => Some(part),
+        (Some(symbols), part) => {
+            warn!("Dropping document symbols of another shape: {:?}", part);
+            Some(symbols)
+        }
+    }
+}
+
+/// Records the latency of the LSP request and counts it if it failed
+fn record_request<T, E>(method: &'static str, started: Instant, response: &Result<T, E>) {
+    histogram!(LSP_REQUEST_SECONDS, "method" => method).record(started.elapsed().as_secs_f64());
+    if response.is_err() {
+        counter!(LSP_REQUEST_ERRORS, "method" => method).increment(1);
     }
 }
+
 pub struct LspServerSubsystem {
+    /// Workspace the server is started for, its fuzzy config configures the server
+    pub workspace: Arc<Workspace>,
+    /// Cap of the code read for a symbol, see `memory.max_symbol_code_bytes`
+    pub max_symbol_code_bytes: usize,
     pub lsp_server_tx: Sender<Option<GuardedLspServer>>,
+    /// Changes of the files reported by the watcher, the cached responses about them are dropped
+    pub path_changes_rx: broadcast::Receiver<Arc<PathEvent>>,
+    pub states: Arc<SubsystemStates>,
+    /// Budget of the shutdown and exit requests after the shutdown request
+    pub shutdown_timeout: Duration,
 }
 
 #[async_trait]
 impl IntoSubsystem<miette::Report> for LspServerSubsystem {
     async fn run(self, subsys: SubsystemHandle) -> Result<()> {
-        let server_args = CONFIG
-            .search
+        self.states
+            .set(SubsystemKind::Lsp, SubsystemState::Initializing);
+        let server_args = self
+            .workspace
             .fuzzy
             .server_args
             .iter()
             .collect::<Vec<&String>>();
 
-        let (server, rx) = LspServer::new(&CONFIG.search.fuzzy.lsp_server, server_args);
+        let (server, rx) = LspServer::new(&self.workspace.fuzzy.lsp_server, server_args);
 
-        let workspace_path = Path::new(&CONFIG.search.fuzzy.workspace_uri);
+        let workspace_path = Path::new(&self.workspace.fuzzy.workspace_uri);
 
         let workspace_name = workspace_path
             .file_name()
@@ -250,16 +875,24 @@ impl IntoSubsystem<miette::Report> for LspServerSubsystem {
                     work_done_progress: Some(true),
                     ..Default::default()
                 }),
+                // the columns of the files are byte offsets, so UTF-8 needs no conversion
+                general: Some(GeneralClientCapabilities {
+                    position_encodings: Some(vec![
+                        PositionEncodingKind::UTF8,
+                        PositionEncodingKind::UTF16,
+                    ]),
+                    ..Default::default()
+                }),
                 ..Default::default()
             },
             process_id: Some(std::process::id()),
-            initialization_options: Some(CONFIG.search.fuzzy.server_options.clone()),
+            initialization_options: Some(self.workspace.fuzzy.server_options.clone()),
             client_info: Some(ClientInfo {
                 name: NAME.to_string(),
                 version: Some(VERSION.to_string()),
             }),
             workspace_folders: Some(vec![WorkspaceFolder {
-                uri: Url::from_str(&CONFIG.search.fuzzy.workspace_uri)
+                uri: Url::from_str(&self.workspace.fuzzy.workspace_uri)
                     .expect("Failed to parse workspace folder"),
                 name: workspace_name.to_string(),
             }]),
@@ -268,34 +901,120 @@ impl IntoSubsystem<miette::Report> for LspServerSubsystem {
 
         let initialize_result = server.initialize(initialize_params).await;
         info!("Initialize result: {:?}", initialize_result);
+        let position_encoding = PositionEncoding::of(
+            initialize_result
+                .as_ref()
+                .ok()
+                .and_then(|it| it.capabilities.position_encoding.as_ref()),
+        );
+        info!("Position encoding of LSP server: {}", position_encoding);
+        let search_kind = initialize_result
+            .as_ref()
+            .ok()
+            .and_then(|it| it.server_info.as_ref())
+            .is_some_and(|it| it.name == RUST_ANALYZER);
+        info!(
+            "LSP server narrows workspace symbols by kind: {}",
+            search_kind
+        );
         server.initialized().await;
         //For all server requests, send a "Ok" response without any reaction
-        fake_responder(&server, rx).await?;
+        let progress = Arc::new(PendingProgress::default());
+        fake_responder(&server, rx, &self.workspace.fuzzy, progress.clone()).await?;
+        let hover_cache = Arc::new(HoverCache::from(&self.workspace.fuzzy.hover_cache));
+        let document_symbols_cache = Arc::new(DocumentSymbolsCache::new(
+            self.workspace.fuzzy.document_symbols_cache_capacity,
+        ));
         let guarded_server = GuardedLspServer {
             server: server.clone(),
-            guard: Arc::new(Semaphore::new(CONFIG.search.fuzzy.parallelizm)),
+            workspace: self.workspace.clone(),
+            max_symbol_code_bytes: self.max_symbol_code_bytes,
+            guard: Arc::new(Semaphore::new(self.workspace.fuzzy.parallelizm)),
+            hover_cache: hover_cache.clone(),
+            document_symbols_cache: document_symbols_cache.clone(),
+            progress,
+            capabilities: initialize_result
+                .as_ref()
+                .ok()
+                .map(|it| Arc::new(it.capabilities.clone())),
+            position_encoding,
+            search_kind,
+            request_timeout: self
+                .workspace
+                .fuzzy
+                .request_timeout_ms
+                .map(Duration::from_millis),
+            document_symbols_timeout: self
+                .workspace
+                .fuzzy
+                .document_symbols_timeout_ms
+                .map(Duration::from_millis),
         };
         self.lsp_server_tx
             .send(Some(guarded_server.clone()))
-            .into_diagnostic()?;
-        subsys.on_shutdown_requested().await;
-        guarded_server.shutdown().await?;
-        Ok(())
+            .map_err(|e| {
+                SemantrixError::lsp_transport(format!("LSP server handle is not received: {}", e))
+            })?;
+        let state = match &initialize_result {
+            Ok(_) => SubsystemState::Ready,
+            Err(e) => SubsystemState::Degraded {
+                reason: format!("initialization failed: {:?}", e),
+            },
+        };
+        self.states.set(SubsystemKind::Lsp, state);
+        let mut path_changes_rx = self.path_changes_rx;
+        loop {
+            tokio::select! {
+                _ = subsys.on_shutdown_requested() => break,
+                event = path_changes_rx.recv() => match event {
+                    Ok(event) => {
+                        hover_cache.invalidate(&event.path);
+                        document_symbols_cache.invalidate(&event.path);
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Missed {} path changes, dropping all cached responses", skipped);
+                        hover_cache.clear();
+                        document_symbols_cache.clear();
+                    }
+                    Err(RecvError::Closed) => {
+                        subsys.on_shutdown_requested().await;
+                        break;
+                    }
+                },
+            }
+        }
+        let stopped = with_shutdown_deadline(
+            "LSP server",
+            self.shutdown_timeout,
+            guarded_server.shutdown(),
+        )
+        .await;
+        self.states.set(SubsystemKind::Lsp, SubsystemState::Stopped);
+        stopped
     }
 }
 
+/// Answers the requests of the server and routes the `$/progress` notifications of the pending
+/// requests to them, see [`PendingProgress`]
 pub async fn fake_responder(
     server: &LspServer,
     mut rx: mpsc::Receiver<ServerMessage>,
+    config: &FuzzyConfig,
+    progress: Arc<PendingProgress>,
 ) -> Result<()> {
     info!("Waiting for indexing to complete");
-    wait_completion(&mut rx, Some(McpProgressToken::RootsScanned)).await?;
+    wait_completion(&mut rx, &config.readiness_tokens, config.readiness_mode).await?;
     let server = server.clone();
     tokio::spawn(async move {
         while let Some(message) = rx.recv().await {
             match &message {
                 ServerMessage::Notification(notification) => {
                     trace!("Received notification: {:?}", notification);
+                    if let ("$/progress", Some(params)) =
+                        (notification.method.as_str(), &notification.params)
+                    {
+                        progress.route(params);
+                    }
                 }
                 ServerMessage::Request(request) => {
                     trace!("Received request: {:?}", request);
@@ -338,34 +1057,14 @@ pub async fn fake_responder(
     Ok(())
 }
 
+/// Waits until the work of the readiness tokens is done, a token is matched by the prefix or the
+/// regex of the `$/progress` token. Without tokens only the already received messages are drained
 pub async fn wait_completion(
     rx: &mut mpsc::Receiver<ServerMessage>,
-    token: Option<McpProgressToken>,
+    tokens: &[String],
+    mode: ReadinessTokensMode,
 ) -> Result<()> {
-    if let Some(token) = token.as_ref() {
-        info!("Waiting for work done of {:?}", token);
-        while let Some(message) = rx.recv().await {
-            if let ServerMessage::Notification(notification) = &message {
-                trace!("Notification: {:?}", notification);
-                if notification.method == "$/progress" {
-                    if let Some(params) = notification.params.clone() {
-                        let params: ProgressParams =
-                            serde_json::from_value(params).into_diagnostic()?;
-                        if params.token == NumberOrString::String(token.to_string()) {
-                            if let ProgressParamsValue::WorkDone(WorkDoneProgress::End(message)) =
-                                params.value
-                            {
-                                info!("Work done with message: {:?}", message);
-                                break;
-                            }
-                        }
-                    }
-                }
-            } else if let ServerMessage::Request(request) = &message {
-                trace!("Received request: {:?}", request);
-            }
-        }
-    } else {
+    if tokens.is_empty() {
         info!("Waiting for work done");
         while let Ok(message) = rx.try_recv() {
             if let ServerMessage::Notification(notification) = &message {
@@ -375,6 +1074,130 @@ pub async fn wait_completion(
             }
         }
         info!("Received all messages");
+        return Ok(());
+    }
+
+    info!("Waiting for work done of {} of {:?}", mode, tokens);
+    let patterns = tokens
+        .iter()
+        .map(|token| (token.as_str(), Regex::new(token).ok()))
+        .collect::<Vec<_>>();
+    let mut pending = (0..patterns.len()).collect::<HashSet<_>>();
+    while let Some(message) = rx.recv().await {
+        let notification = match &message {
+            ServerMessage::Notification(notification) => notification,
+            ServerMessage::Request(request) => {
+                trace!("Received request: {:?}", request);
+                continue;
+            }
+        };
+        trace!("Notification: {:?}", notification);
+        if notification.method != "$/progress" {
+            continue;
+        }
+        let Some(params) = notification.params.clone() else {
+            continue;
+        };
+        let params: ProgressParams = serde_json::from_value(params).map_err(|e| {
+            SemantrixError::lsp_transport(format!("Invalid LSP progress notification: {}", e))
+        })?;
+        let ProgressParamsValue::WorkDone(WorkDoneProgress::End(message)) = params.value else {
+            continue;
+        };
+        let token = match params.token {
+            NumberOrString::String(token) => token,
+            NumberOrString::Number(token) => token.to_string(),
+        };
+        let done = pending
+            .iter()
+            .copied()
+            .filter(|index| {
+                let (prefix, regex) = &patterns[*index];
+                token.starts_with(prefix) || regex.as_ref().is_some_and(|it| it.is_match(&token))
+            })
+            .collect::<Vec<_>>();
+        if done.is_empty() {
+            continue;
+        }
+        info!("Work of {} done with message: {:?}", token, message);
+        pending.retain(|index| !done.contains(index));
+        if mode == ReadinessTokensMode::Any || pending.is_empty() {
+            break;
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{HoverContents, MarkedString};
+
+    use super::*;
+
+    fn hover(text: &str) -> Option<Hover> {
+        Some(Hover {
+            contents: HoverContents::Scalar(MarkedString::String(text.to_string())),
+            range: None,
+        })
+    }
+
+    fn position(line: u32) -> Position {
+        Position { line, character: 4 }
+    }
+
+    /// File of the temp dir with its URL, the path is normalized like the paths of the watcher
+    fn document(name: &str) -> (PathBuf, Url) {
+        let path = std::env::temp_dir().join(name);
+        let url = Url::from_file_path(&path).unwrap();
+        (normalize_path(&path), url)
+    }
+
+    #[test]
+    fn changed_file_hover_is_refetched() {
+        let cache = HoverCache::from(&HoverCacheConfig::default());
+        let (edited_path, edited) = document("edited.rs");
+        let (_, other) = document("other.rs");
+        cache.put(edited.clone(), position(1), hover("fn before()"));
+        cache.put(other.clone(), position(1), hover("fn other()"));
+
+        assert_eq!(cache.get(&edited, position(1)), Some(hover("fn before()")));
+        assert_eq!(cache.get(&edited, position(2)), None);
+
+        cache.invalidate(&edited_path);
+
+        // the edited file goes to the server again, the other file is still cached
+        assert_eq!(cache.get(&edited, position(1)), None);
+        assert_eq!(cache.get(&other, position(1)), Some(hover("fn other()")));
+        assert_eq!(cache.responses.hits.load(Ordering::Relaxed), 2);
+        assert_eq!(cache.responses.misses.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn folder_change_drops_hovers_of_its_files() {
+        let cache = HoverCache::from(&HoverCacheConfig::default());
+        let (_, edited) = document("hover-folder/edited.rs");
+        cache.put(edited.clone(), position(1), hover("fn before()"));
+
+        cache.invalidate(&normalize_path(&std::env::temp_dir().join("hover-folder")));
+
+        assert_eq!(cache.get(&edited, position(1)), None);
+    }
+
+    #[test]
+    fn expired_or_disabled_cache_misses() {
+        let (_, url) = document("expired.rs");
+        let expired = HoverCache::from(&HoverCacheConfig {
+            ttl_sec: 0,
+            ..HoverCacheConfig::default()
+        });
+        expired.put(url.clone(), position(1), hover("fn before()"));
+        assert_eq!(expired.get(&url, position(1)), None);
+
+        let disabled = HoverCache::from(&HoverCacheConfig {
+            capacity: 0,
+            ..HoverCacheConfig::default()
+        });
+        disabled.put(url.clone(), position(1), hover("fn before()"));
+        assert_eq!(disabled.get(&url, position(1)), None);
+    }
+}
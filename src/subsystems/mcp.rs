@@ -1,40 +1,166 @@
-use std::sync::{Arc, atomic::AtomicBool};
+use std::{future, io, sync::Arc};
 
 use async_trait::async_trait;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use itertools::Itertools;
 use miette::{IntoDiagnostic, Result};
-use rig_fastembed::EmbeddingModel;
-use rig_lancedb::LanceDbVectorIndex;
-use rmcp::{ServiceExt, service::RunningService, transport};
-use tokio::sync::watch::Receiver;
+use rmcp::{
+    ServiceExt,
+    model::{ClientJsonRpcMessage, ServerJsonRpcMessage},
+    service::RunningService,
+    transport::{
+        self,
+        io::{from_async_read, from_async_write},
+    },
+};
+use serde_json::Value;
+use strum::IntoEnumIterator;
+use tokio::sync::Semaphore;
 use tokio_graceful_shutdown::{IntoSubsystem, SubsystemHandle};
 use tracing::{error, info};
 
-use crate::{services::mcp::McpService, subsystems::lsp::GuardedLspServer};
+use crate::{
+    LiveConfig, McpConfig, TransportConfig,
+    enums::McpToolName,
+    errors::SemantrixError,
+    repositories::embedding::IndexEmbeddingModel,
+    services::{
+        mcp::{McpService, McpWorkspace},
+        outbound::ResponseMetas,
+        progress::ProgressTokens,
+        scratch::ScratchIndex,
+        sse::serve_sse,
+    },
+    subsystems::{
+        WorkspacePipeline,
+        states::{SubsystemKind, SubsystemState},
+    },
+};
 
 pub struct McpServerSubsystem {
-    pub vector_store: Arc<LanceDbVectorIndex<EmbeddingModel>>,
-    pub lsp_server_rx: Receiver<Option<GuardedLspServer>>,
-    pub first_index_scan: Arc<AtomicBool>,
+    pub config: Arc<McpConfig>,
+    pub live_config: LiveConfig,
+    pub embedding_model: IndexEmbeddingModel,
+    pub ndims: usize,
+    /// Pipelines of the workspaces, the first one serves the calls without the workspace
+    pub pipelines: Vec<WorkspacePipeline>,
+    pub scratch: Arc<ScratchIndex>,
+}
+
+impl McpServerSubsystem {
+    /// Service of the session, the calls without the workspace argument run on the first
+    /// workspace
+    pub fn service(&self) -> Result<McpService> {
+        let workspaces = self
+            .pipelines
+            .iter()
+            .cloned()
+            .map(McpWorkspace::from)
+            .collect::<Vec<_>>();
+        let first = workspaces
+            .first()
+            .cloned()
+            .ok_or_else(|| SemantrixError::config("No workspaces to serve"))?;
+        Ok(McpService {
+            config: self.config.clone(),
+            live_config: self.live_config.clone(),
+            embedding_model: self.embedding_model.clone(),
+            ndims: self.ndims,
+            scratch: self.scratch.clone(),
+            pipeline: first.pipeline,
+            overview_cache: first.overview_cache,
+            query_cache: first.query_cache,
+            workspaces: Arc::new(workspaces),
+            tool_permits: Arc::new(
+                McpToolName::iter()
+                    .map(|tool| (tool, Semaphore::new(self.config.tool_concurrency.max(1))))
+                    .collect(),
+            ),
+            progress_tokens: ProgressTokens::default(),
+            response_metas: ResponseMetas::default(),
+        })
+    }
 }
 
 #[async_trait]
 impl IntoSubsystem<miette::Report> for McpServerSubsystem {
     async fn run(self, subsys: SubsystemHandle) -> Result<()> {
-        let reuser = McpService {
-            vector_store: self.vector_store.clone(),
-            lsp_server_rx: self.lsp_server_rx,
-            first_index_scan: self.first_index_scan.clone(),
+        // the server is shared, so its state is recorded in every workspace
+        let states = self
+            .pipelines
+            .iter()
+            .map(|it| it.states.clone())
+            .collect_vec();
+        let set_state = |state: SubsystemState| {
+            for it in states.iter() {
+                it.set(SubsystemKind::Mcp, state.clone());
+            }
         };
+        let reuser = self.service()?;
+        reuser.check_prompt_templates()?;
         info!("Starting MCP service");
+        set_state(SubsystemState::Serving);
         let cancelation_token = subsys.create_cancellation_token();
-        let server: RunningService<_, _> = reuser
-            .serve_with_ct(transport::stdio(), cancelation_token)
-            .await
-            .inspect_err(|e| error!("MCP server error: {:?}", e))
-            .into_diagnostic()?;
-        info!("MCP server initialized");
-        let quit_reason = server.waiting().await.into_diagnostic()?;
-        info!("MCP server shutdown with reason: {:?}", quit_reason);
+        match &self.config.transport {
+            TransportConfig::Stdio => {
+                let transport = stdio_transport(
+                    reuser.progress_tokens.clone(),
+                    reuser.response_metas.clone(),
+                );
+                let server: RunningService<_, _> = reuser
+                    .serve_with_ct(transport, cancelation_token)
+                    .await
+                    .inspect_err(|e| error!("MCP server error: {:?}", e))
+                    .into_diagnostic()?;
+                info!("MCP server initialized");
+                let quit_reason = server.waiting().await.into_diagnostic()?;
+                info!("MCP server shutdown with reason: {:?}", quit_reason);
+            }
+            TransportConfig::Sse {
+                bind,
+                sse_path,
+                post_path,
+            } => {
+                // every session gets a clone of the service, the readiness flags, the LSP
+                // receiver and the channels are shared between the clones
+                serve_sse(
+                    reuser,
+                    *bind,
+                    sse_path,
+                    post_path,
+                    self.config.auth.token.clone(),
+                    cancelation_token,
+                )
+                .await
+                .inspect_err(|e| error!("MCP SSE server error: {:?}", e))?;
+                info!("MCP SSE server shutdown");
+            }
+        }
+        set_state(SubsystemState::Stopped);
+        self.scratch.purge().await;
         Ok(())
     }
 }
+
+/// Stdio transport remembering the progress tokens of the raw requests before they are parsed,
+/// see [`ProgressTokens`], and adding the metas to the results, see [`ResponseMetas`]
+fn stdio_transport(
+    progress_tokens: ProgressTokens,
+    response_metas: ResponseMetas,
+) -> (
+    impl Sink<ServerJsonRpcMessage, Error = io::Error> + Send + 'static,
+    impl Stream<Item = ClientJsonRpcMessage> + Send + 'static,
+) {
+    let (stdin, stdout) = transport::stdio();
+    let messages = from_async_read::<Value, _>(stdin).filter_map(move |message| {
+        progress_tokens.remember(&message);
+        let message = serde_json::from_value::<ClientJsonRpcMessage>(message)
+            .inspect_err(|e| error!("Invalid MCP message: {}", e))
+            .ok();
+        future::ready(message)
+    });
+    let sink = from_async_write::<Value, _>(stdout).with(move |message| {
+        future::ready(response_metas.encode(message).map_err(io::Error::other))
+    });
+    (sink, messages)
+}
@@ -0,0 +1,102 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    Router, extract::State, http::header::CONTENT_TYPE, response::IntoResponse, routing::get,
+};
+use metrics::gauge;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use miette::{IntoDiagnostic, Result};
+use once_cell::sync::OnceCell;
+use tokio::net::TcpListener;
+use tokio_graceful_shutdown::{IntoSubsystem, SubsystemHandle};
+use tracing::info;
+
+use crate::subsystems::WorkspacePipeline;
+
+/// Chunks written to the index, the rate of it is the indexing speed
+pub const CHUNKS_INDEXED: &str = "semantrix_chunks_indexed_total";
+/// Embedding of one indexer batch
+pub const EMBEDDING_BATCH_SECONDS: &str = "semantrix_embedding_batch_seconds";
+/// One search of the vector index
+pub const VECTOR_SEARCH_SECONDS: &str = "semantrix_vector_search_seconds";
+/// LSP requests by the `method` label
+pub const LSP_REQUEST_SECONDS: &str = "semantrix_lsp_request_seconds";
+pub const LSP_REQUEST_ERRORS: &str = "semantrix_lsp_request_errors_total";
+/// MCP tool calls by the `tool` label
+pub const TOOL_CALL_SECONDS: &str = "semantrix_tool_call_seconds";
+pub const TOOL_CALL_ERRORS: &str = "semantrix_tool_call_errors_total";
+/// Messages waiting in the channel of the `channel` label
+pub const CHANNEL_DEPTH: &str = "semantrix_channel_depth";
+/// 1 if the `component` label of the `workspace` label is ready, set when the metrics are scraped
+pub const READY: &str = "semantrix_ready";
+
+/// Buckets of the latencies, from the cached LSP responses to the embedding of large batches
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+static PROMETHEUS_HANDLE: OnceCell<PrometheusHandle> = OnceCell::new();
+
+/// Installs the global recorder once, the metrics are not recorded without it
+pub fn install_recorder() -> Result<PrometheusHandle> {
+    PROMETHEUS_HANDLE
+        .get_or_try_init(|| {
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)
+                .into_diagnostic()?
+                .install_recorder()
+                .into_diagnostic()
+        })
+        .cloned()
+}
+
+/// Serves the metrics in the Prometheus text format, apart from the MCP transport
+pub struct MetricsSubsystem {
+    pub bind: SocketAddr,
+    pub path: String,
+    pub handle: PrometheusHandle,
+    pub pipelines: Vec<WorkspacePipeline>,
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    handle: PrometheusHandle,
+    pipelines: Arc<Vec<WorkspacePipeline>>,
+}
+
+#[async_trait]
+impl IntoSubsystem<miette::Report> for MetricsSubsystem {
+    async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        let router = Router::new()
+            .route(&self.path, get(render_metrics))
+            .with_state(MetricsState {
+                handle: self.handle,
+                pipelines: Arc::new(self.pipelines),
+            });
+
+        let listener = TcpListener::bind(self.bind).await.into_diagnostic()?;
+        info!("Metrics listening on {}{}", self.bind, self.path);
+        axum::serve(listener, router)
+            .with_graceful_shutdown(subsys.create_cancellation_token().cancelled_owned())
+            .await
+            .into_diagnostic()
+    }
+}
+
+async fn render_metrics(State(state): State<MetricsState>) -> impl IntoResponse {
+    for pipeline in state.pipelines.iter() {
+        let workspace = pipeline.workspace.name.clone();
+        let lsp_ready = pipeline.lsp_server_rx.borrow().is_some();
+        let index_ready = *pipeline.first_index_scan.borrow();
+        gauge!(READY, "component" => "lsp", "workspace" => workspace.clone())
+            .set(f64::from(u8::from(lsp_ready)));
+        gauge!(READY, "component" => "index", "workspace" => workspace)
+            .set(f64::from(u8::from(index_ready)));
+    }
+    state.handle.run_upkeep();
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.handle.render(),
+    )
+}
@@ -1,5 +1,85 @@
 pub mod chunker;
+pub mod config;
+pub mod health;
 pub mod indexer;
 pub mod lsp;
 pub mod mcp;
+pub mod metrics;
+pub mod states;
 pub mod watcher;
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize},
+    },
+    time::Duration,
+};
+
+use lancedb::Table;
+use miette::Result;
+use tokio::sync::{mpsc::Sender, watch};
+use tracing::warn;
+
+use crate::{
+    PreviousIndex, Workspace,
+    repositories::backend::{RoutedBackend, VectorBackend},
+    subsystems::{
+        indexer::{IndexErrors, IndexTimestamps, TableRecovery},
+        lsp::GuardedLspServer,
+        states::SubsystemStates,
+        watcher::{PathEvent, WatcherStats},
+    },
+};
+
+/// Handles of the watcher, chunker, indexer and LSP server of one workspace, the MCP server, the
+/// probes and the searches read its table and readiness through them
+#[derive(Clone)]
+pub struct WorkspacePipeline {
+    pub workspace: Arc<Workspace>,
+    /// Searches the tables of all models of [`Self::routes`]
+    pub vector_store: Arc<dyn VectorBackend>,
+    /// Table of the default model
+    pub table: Table,
+    /// Tables of the models of `search.semantic.model_routes` with the default one
+    pub routes: Arc<RoutedBackend>,
+    pub lsp_server_rx: watch::Receiver<Option<GuardedLspServer>>,
+    pub first_path_scan: Arc<AtomicBool>,
+    pub first_chunks_scan: Arc<AtomicBool>,
+    /// Flips to true once the first scan of the workspace is indexed
+    pub first_index_scan: watch::Receiver<bool>,
+    /// Backup table of the previous model searched until the first scan is indexed by the
+    /// current model, see `search.semantic.serve_previous_model`
+    pub previous_index: watch::Receiver<Option<Arc<PreviousIndex>>>,
+    pub index_timestamps: Arc<IndexTimestamps>,
+    pub index_errors: Arc<IndexErrors>,
+    /// Number of paths changed by the watcher after the first scan
+    pub changed_paths: Arc<AtomicUsize>,
+    pub path_event_tx: Sender<Arc<PathEvent>>,
+    /// Recreates the tables deleted outside of semantrix, see [`TableRecovery`]
+    pub recovery: Arc<TableRecovery>,
+    pub watcher_stats: Arc<WatcherStats>,
+    /// States of the subsystems for the diagnostics, see [`SubsystemStates`]
+    pub states: Arc<SubsystemStates>,
+    /// True if the index is written by another process, nothing is queued for reindexing then
+    pub read_only: bool,
+}
+
+/// Runs the cleanup of the subsystem after the shutdown request within its budget, the cleanup
+/// which exceeds it is abandoned with a warning naming the subsystem
+pub async fn with_shutdown_deadline<F>(subsystem: &str, budget: Duration, cleanup: F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    match tokio::time::timeout(budget, cleanup).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                "{} exceeded its shutdown budget of {} ms, see shutdown config",
+                subsystem,
+                budget.as_millis()
+            );
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use miette::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use strum_macros::Display;
+use tokio_graceful_shutdown::{IntoSubsystem, SubsystemHandle};
+use tracing::{debug, trace, warn};
+
+use crate::{SubsystemStatesConfig, subsystems::WorkspacePipeline};
+
+/// Seconds between the checks of the subsystems staying in a transient state
+const STUCK_CHECK_SEC: u64 = 5;
+
+/// Subsystem of the pipeline of a workspace, the MCP server is shared by the workspaces and
+/// recorded in all of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, JsonSchema, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SubsystemKind {
+    Watcher,
+    Chunker,
+    Indexer,
+    Lsp,
+    Mcp,
+}
+
+/// State of a subsystem, a subsystem staying in a transient state longer than
+/// `subsystem_states.stuck_sec` is warned about
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SubsystemState {
+    /// Watcher sends the files of the first path scan
+    Scanning,
+    Watching,
+    Idle,
+    /// Chunker reads and chunks the file
+    Processing {
+        path: PathBuf,
+    },
+    /// Indexer embeds the chunks of its batch, the batches are counted from the start
+    Embedding {
+        batch: u64,
+        chunks: usize,
+    },
+    Writing {
+        batch: u64,
+        chunks: usize,
+    },
+    Optimizing,
+    Vacuuming,
+    /// LSP server is started and indexes the workspace
+    Initializing,
+    Ready,
+    /// LSP server answers without the capabilities of its failed initialization
+    Degraded {
+        reason: String,
+    },
+    Serving,
+    Stopped,
+}
+
+impl SubsystemState {
+    /// True for the states which are expected to end soon
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            SubsystemState::Scanning
+                | SubsystemState::Processing { .. }
+                | SubsystemState::Embedding { .. }
+                | SubsystemState::Writing { .. }
+                | SubsystemState::Optimizing
+                | SubsystemState::Vacuuming
+                | SubsystemState::Initializing
+        )
+    }
+}
+
+impl Display for SubsystemState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubsystemState::Scanning => write!(f, "scanning"),
+            SubsystemState::Watching => write!(f, "watching"),
+            SubsystemState::Idle => write!(f, "idle"),
+            SubsystemState::Processing { path } => write!(f, "processing {}", path.display()),
+            SubsystemState::Embedding { batch, chunks } => {
+                write!(f, "embedding batch {} of {} chunks", batch, chunks)
+            }
+            SubsystemState::Writing { batch, chunks } => {
+                write!(f, "writing batch {} of {} chunks", batch, chunks)
+            }
+            SubsystemState::Optimizing => write!(f, "optimizing"),
+            SubsystemState::Vacuuming => write!(f, "vacuuming"),
+            SubsystemState::Initializing => write!(f, "initializing"),
+            SubsystemState::Ready => write!(f, "ready"),
+            SubsystemState::Degraded { reason } => write!(f, "degraded: {}", reason),
+            SubsystemState::Serving => write!(f, "serving"),
+            SubsystemState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// State of a subsystem with the time it was entered
+#[derive(Debug)]
+struct StateEntry {
+    state: SubsystemState,
+    since: DateTime<Utc>,
+    entered: Instant,
+    /// The stay in the transient state is warned about
+    warned: bool,
+}
+
+/// Current states of the subsystems of a workspace updated at their transitions, the subsystems
+/// which are not started are missing
+#[derive(Debug, Default)]
+pub struct SubsystemStates(Mutex<BTreeMap<SubsystemKind, StateEntry>>);
+
+/// State of a subsystem reported by the status tool and the probes
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SubsystemStatus {
+    pub subsystem: SubsystemKind,
+    #[serde(flatten)]
+    pub state: SubsystemState,
+    #[schemars(with = "String")]
+    pub since: DateTime<Utc>,
+    /// Seconds the subsystem is in the state
+    pub seconds: u64,
+}
+
+impl Display for SubsystemStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} for {} s",
+            self.subsystem, self.state, self.seconds
+        )
+    }
+}
+
+impl StateEntry {
+    fn status(&self, subsystem: SubsystemKind) -> SubsystemStatus {
+        SubsystemStatus {
+            subsystem,
+            state: self.state.clone(),
+            since: self.since,
+            seconds: self.entered.elapsed().as_secs(),
+        }
+    }
+}
+
+impl SubsystemStates {
+    /// Records the transition of the subsystem, the same state keeps the time it was entered
+    pub fn set(&self, subsystem: SubsystemKind, state: SubsystemState) {
+        let mut states = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if states.get(&subsystem).is_some_and(|it| it.state == state) {
+            return;
+        }
+        trace!("Subsystem {} is {}", subsystem, state);
+        states.insert(
+            subsystem,
+            StateEntry {
+                state,
+                since: Utc::now(),
+                entered: Instant::now(),
+                warned: false,
+            },
+        );
+    }
+
+    pub fn statuses(&self) -> Vec<SubsystemStatus> {
+        let states = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        states
+            .iter()
+            .map(|(subsystem, entry)| entry.status(*subsystem))
+            .collect()
+    }
+
+    /// Subsystems staying in a transient state longer than the threshold, every stay is
+    /// returned once
+    pub fn newly_stuck(&self, threshold: Duration) -> Vec<SubsystemStatus> {
+        let mut states = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        states
+            .iter_mut()
+            .filter(|(_, entry)| {
+                entry.state.is_transient() && !entry.warned && entry.entered.elapsed() > threshold
+            })
+            .map(|(subsystem, entry)| {
+                entry.warned = true;
+                entry.status(*subsystem)
+            })
+            .collect()
+    }
+}
+
+/// Logs the states of the subsystems of the workspaces every `log_interval_sec` at the debug
+/// level and warns about the subsystems staying in a transient state longer than `stuck_sec`
+pub struct SubsystemStatesSubsystem {
+    pub config: SubsystemStatesConfig,
+    pub pipelines: Vec<WorkspacePipeline>,
+}
+
+impl SubsystemStatesSubsystem {
+    fn log_states(&self) {
+        for pipeline in self.pipelines.iter() {
+            debug!(
+                "Subsystem states of workspace {}: {}",
+                pipeline.workspace.name,
+                pipeline.states.statuses().iter().join(", ")
+            );
+        }
+    }
+
+    fn warn_stuck(&self) {
+        let threshold = Duration::from_secs(self.config.stuck_sec);
+        for pipeline in self.pipelines.iter() {
+            for status in pipeline.states.newly_stuck(threshold) {
+                warn!(
+                    "Subsystem {} of workspace {} is {} since {}, longer than {} s of \
+                    subsystem_states.stuck_sec",
+                    status.subsystem,
+                    pipeline.workspace.name,
+                    status.state,
+                    status.since,
+                    self.config.stuck_sec
+                );
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl IntoSubsystem<miette::Report> for SubsystemStatesSubsystem {
+    async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        let mut log =
+            tokio::time::interval(Duration::from_secs(self.config.log_interval_sec.max(1)));
+        let mut check = tokio::time::interval(Duration::from_secs(STUCK_CHECK_SEC));
+        loop {
+            tokio::select! {
+                _ = subsys.on_shutdown_requested() => break,
+                _ = log.tick(), if self.config.log_interval_sec > 0 => self.log_states(),
+                _ = check.tick(), if self.config.stuck_sec > 0 => self.warn_stuck(),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lets the entered states age, so a zero threshold is exceeded
+    fn wait() {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    fn since(states: &SubsystemStates, subsystem: SubsystemKind) -> DateTime<Utc> {
+        states
+            .statuses()
+            .into_iter()
+            .find(|it| it.subsystem == subsystem)
+            .unwrap()
+            .since
+    }
+
+    #[test]
+    fn same_state_keeps_its_since() {
+        let states = SubsystemStates::default();
+        states.set(SubsystemKind::Watcher, SubsystemState::Scanning);
+        let entered = since(&states, SubsystemKind::Watcher);
+        wait();
+
+        states.set(SubsystemKind::Watcher, SubsystemState::Scanning);
+        assert_eq!(since(&states, SubsystemKind::Watcher), entered);
+
+        states.set(SubsystemKind::Watcher, SubsystemState::Watching);
+        assert!(since(&states, SubsystemKind::Watcher) > entered);
+    }
+
+    #[test]
+    fn state_with_other_details_is_a_new_state() {
+        let states = SubsystemStates::default();
+        let processing = |path: &str| SubsystemState::Processing {
+            path: PathBuf::from(path),
+        };
+        states.set(SubsystemKind::Chunker, processing("a.rs"));
+        let entered = since(&states, SubsystemKind::Chunker);
+        wait();
+
+        states.set(SubsystemKind::Chunker, processing("b.rs"));
+        assert!(since(&states, SubsystemKind::Chunker) > entered);
+    }
+
+    #[test]
+    fn stuck_state_is_warned_once_per_stay() {
+        let states = SubsystemStates::default();
+        states.set(SubsystemKind::Indexer, SubsystemState::Optimizing);
+        assert!(states.newly_stuck(Duration::from_secs(3600)).is_empty());
+        wait();
+
+        let stuck = states.newly_stuck(Duration::ZERO);
+        assert_eq!(stuck.len(), 1);
+        assert_eq!(stuck[0].subsystem, SubsystemKind::Indexer);
+        assert!(states.newly_stuck(Duration::ZERO).is_empty());
+
+        // the same state keeps its stay, another one starts a new stay
+        states.set(SubsystemKind::Indexer, SubsystemState::Optimizing);
+        wait();
+        assert!(states.newly_stuck(Duration::ZERO).is_empty());
+        states.set(SubsystemKind::Indexer, SubsystemState::Idle);
+        states.set(SubsystemKind::Indexer, SubsystemState::Optimizing);
+        wait();
+        assert_eq!(states.newly_stuck(Duration::ZERO).len(), 1);
+    }
+
+    #[test]
+    fn non_transient_states_are_never_stuck() {
+        let states = SubsystemStates::default();
+        for (subsystem, state) in [
+            (SubsystemKind::Watcher, SubsystemState::Watching),
+            (SubsystemKind::Chunker, SubsystemState::Idle),
+            (SubsystemKind::Lsp, SubsystemState::Ready),
+            (
+                SubsystemKind::Indexer,
+                SubsystemState::Degraded {
+                    reason: "failed".to_string(),
+                },
+            ),
+            (SubsystemKind::Mcp, SubsystemState::Serving),
+        ] {
+            assert!(!state.is_transient(), "{}", state);
+            states.set(subsystem, state);
+        }
+        wait();
+
+        assert!(states.newly_stuck(Duration::ZERO).is_empty());
+    }
+}
@@ -2,24 +2,36 @@ use async_trait::async_trait;
 use std::{
     path::PathBuf,
     sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
-use url::Url;
 
-use miette::{IntoDiagnostic, Result, miette};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use miette::{IntoDiagnostic, Result};
 use notify_debouncer_full::{
     DebounceEventResult, Debouncer, NoCache, new_debouncer,
     notify::{self, EventKind, RecommendedWatcher, RecursiveMode, event::CreateKind},
 };
-use tokio::{runtime::Handle, sync::mpsc::Sender};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::{
+    runtime::Handle,
+    sync::{Notify, broadcast, mpsc::Sender},
+};
 use tokio_graceful_shutdown::{IntoSubsystem, SubsystemHandle};
 use tracing::{info, trace, warn};
-use wax::Glob;
 
-use crate::CONFIG;
+use crate::{
+    LiveConfig, Workspace,
+    services::{get_project_files, indexed_roots, normalize_path},
+    subsystems::{
+        states::{SubsystemKind, SubsystemState, SubsystemStates},
+        with_shutdown_deadline,
+    },
+};
 
 #[derive(Debug, Clone)]
 pub struct PathEvent {
@@ -27,17 +39,132 @@ pub struct PathEvent {
     pub kind: EventKind,
 }
 
+/// Flushes of the file watcher, a flush is one call of the debouncer with the events of its
+/// window. Their timings are checked against the jumps of the clock, see `watcher` of the config
+#[derive(Debug, Default)]
+pub struct WatcherStats {
+    last_flush: Mutex<Option<DateTime<Utc>>>,
+    /// Monotonic and wall clock times of the last flush
+    last_clocks: Mutex<Option<(Instant, SystemTime)>>,
+    flushes: AtomicU64,
+    clock_anomalies: AtomicU64,
+    recreations: AtomicU64,
+}
+
+/// Flushes of the file watcher since the start
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct WatcherStatus {
+    #[schemars(with = "Option<String>")]
+    pub last_flush: Option<DateTime<Utc>>,
+    pub flushes: u64,
+    /// Flushes whose timings looked like a jump of the clock
+    pub clock_anomalies: u64,
+    /// Debouncers recreated after the clock anomalies
+    pub recreations: u64,
+}
+
+impl WatcherStats {
+    /// Records the flush of the events, the oldest of them was received at `oldest`. Returns
+    /// the anomalies of the timings: the events held longer than `limit` and the wall clock
+    /// which went back or moved apart from the monotonic clock by more than `limit` since the
+    /// previous flush
+    fn flush(&self, oldest: Option<Instant>, debounce: Duration, limit: Duration) -> Vec<String> {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let mut anomalies = Vec::new();
+        if let Some(oldest) = oldest {
+            let held = now.saturating_duration_since(oldest);
+            if held > limit {
+                anomalies.push(format!(
+                    "events were held for {} ms with debounce of {} ms",
+                    held.as_millis(),
+                    debounce.as_millis()
+                ));
+            }
+        }
+        let previous = self
+            .last_clocks
+            .lock()
+            .ok()
+            .and_then(|mut it| it.replace((now, wall)));
+        if let Some((previous_instant, previous_wall)) = previous {
+            let elapsed = now.saturating_duration_since(previous_instant);
+            match wall.duration_since(previous_wall) {
+                Ok(wall_elapsed) if wall_elapsed.abs_diff(elapsed) > limit => {
+                    anomalies.push(format!(
+                        "wall clock moved {} ms while {} ms passed since the previous flush",
+                        wall_elapsed.as_millis(),
+                        elapsed.as_millis()
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => anomalies.push(format!(
+                    "wall clock went back by {} ms since the previous flush",
+                    e.duration().as_millis()
+                )),
+            }
+        }
+        if let Ok(mut last_flush) = self.last_flush.lock() {
+            *last_flush = Some(Utc::now());
+        }
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        if !anomalies.is_empty() {
+            self.clock_anomalies.fetch_add(1, Ordering::Relaxed);
+        }
+        anomalies
+    }
+
+    pub fn status(&self) -> WatcherStatus {
+        WatcherStatus {
+            last_flush: self.last_flush.lock().ok().and_then(|it| *it),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            clock_anomalies: self.clock_anomalies.load(Ordering::Relaxed),
+            recreations: self.recreations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Watcher of the changes of the indexed roots of the subsystem, the changes of the extra roots
+/// are indexed only. The timings of the flushes are recorded in the stats of the subsystem,
+/// `recreate` is notified after a clock anomaly if `watcher.recreate_on_anomaly` of its live
+/// config is set
 async fn create_debounced_watcher(
-    path_event_tx: Sender<Arc<PathEvent>>,
+    watcher: &WatcherSubsystem,
+    extra_roots: Arc<Vec<PathBuf>>,
+    recreate: Arc<Notify>,
 ) -> notify::Result<Debouncer<RecommendedWatcher, NoCache>> {
     let handle = Handle::current();
+    let debounce = watcher.debounce;
+    let live_config = watcher.live_config.clone();
+    let path_event_tx = watcher.path_event_tx.clone();
+    let path_changes_tx = watcher.path_changes_tx.clone();
+    let changed_paths = watcher.changed_paths.clone();
+    let stats = watcher.stats.clone();
 
     let debouncer = new_debouncer(
-        Duration::from_secs(CONFIG.debounce_sec),
+        debounce,
         None,
         move |debounce_result: DebounceEventResult| {
             trace!("Debounce result: {:?}", debounce_result);
+            let oldest = debounce_result
+                .as_ref()
+                .ok()
+                .and_then(|events| events.iter().map(|it| it.time).min());
+            let config = live_config.get();
+            let anomalies = stats.flush(oldest, debounce, debounce * config.watcher.anomaly_factor);
+            if !anomalies.is_empty() {
+                warn!(
+                    "Clock anomaly of the file watcher, the changes may be held or flooded: {}",
+                    anomalies.join(", ")
+                );
+                if config.watcher.recreate_on_anomaly {
+                    recreate.notify_one();
+                }
+            }
             let tx = path_event_tx.clone();
+            let changes_tx = path_changes_tx.clone();
+            let changed_paths = changed_paths.clone();
+            let extra_roots = extra_roots.clone();
             let handle = handle.clone();
             handle.spawn(async move {
                 match debounce_result {
@@ -48,14 +175,19 @@ async fn create_debounced_watcher(
                                 || event.event.kind.is_remove()
                             {
                                 info!("Accepted event: {:?}", event);
+                                changed_paths.fetch_add(event.paths.len(), Ordering::Relaxed);
                                 for path in event.paths.iter() {
-                                    if let Err(e) = tx
-                                        .send(Arc::new(PathEvent {
-                                            path: Arc::new(path.clone()),
-                                            kind: event.kind,
-                                        }))
-                                        .await
+                                    let path_event = Arc::new(PathEvent {
+                                        path: Arc::new(normalize_path(path)),
+                                        kind: event.kind,
+                                    });
+                                    // there are no subscribers if the LSP server isn't started,
+                                    // it never sees the files of the extra roots
+                                    if !extra_roots.iter().any(|it| path_event.path.starts_with(it))
                                     {
+                                        let _ = changes_tx.send(path_event.clone());
+                                    }
+                                    if let Err(e) = tx.send(path_event).await {
                                         warn!("Error in debouncer send: {:?}", e);
                                     }
                                 }
@@ -78,64 +210,121 @@ async fn create_debounced_watcher(
 }
 
 pub struct WatcherSubsystem {
+    pub workspace: Arc<Workspace>,
+    /// Time to collect events of one change
+    pub debounce: Duration,
+    /// Config the anomaly limits of the flushes are read from
+    pub live_config: LiveConfig,
     pub path_event_tx: Sender<Arc<PathEvent>>,
+    /// Changes of the files after the first scan for the subscribers other than the chunker
+    pub path_changes_tx: broadcast::Sender<Arc<PathEvent>>,
     pub first_path_scan: Arc<AtomicBool>,
+    /// Number of paths changed after the first scan
+    pub changed_paths: Arc<AtomicUsize>,
+    pub stats: Arc<WatcherStats>,
+    pub states: Arc<SubsystemStates>,
+    /// Budget of the teardown of the file watcher after the shutdown request
+    pub shutdown_timeout: Duration,
+    /// Capacity of the channel of the files of a walked folder
+    pub channel_size: usize,
 }
 
-#[async_trait]
-impl IntoSubsystem<miette::Report> for WatcherSubsystem {
-    async fn run(self, subsys: SubsystemHandle) -> Result<()> {
-        info!("Start path scanner");
-
-        let url = Url::parse(&CONFIG.search.fuzzy.workspace_uri).into_diagnostic()?;
+impl WatcherSubsystem {
+    /// Debouncer watching the roots, the workspace is the first one
+    async fn watch(
+        &self,
+        roots: &[PathBuf],
+        recreate: Arc<Notify>,
+    ) -> Result<Debouncer<RecommendedWatcher, NoCache>> {
+        let mut debouncer = create_debounced_watcher(self, Arc::new(roots[1..].to_vec()), recreate)
+            .await
+            .into_diagnostic()?;
 
-        if url.scheme() != "file" {
-            return Err(miette!("Not a file URL: {}", url));
+        for path in roots.iter() {
+            info!("Watching path: {:?}", path);
+            debouncer
+                .watch(path, RecursiveMode::Recursive)
+                .into_diagnostic()?;
         }
+        Ok(debouncer)
+    }
+}
 
-        let path = url
-            .to_file_path()
-            .map_err(|_| miette!("Invalid file URL: {}", url))?;
-
-        let positive = Glob::new(CONFIG.search.semantic.pattern.as_str()).into_diagnostic()?;
-
-        info!("Start path scanner for {}", path.display());
+#[async_trait]
+impl IntoSubsystem<miette::Report> for WatcherSubsystem {
+    async fn run(self, subsys: SubsystemHandle) -> Result<()> {
+        // the workspace is the first root, the extra roots are indexed without the LSP server
+        let roots = indexed_roots(&self.workspace)?;
 
-        let walker = positive.walk(&path);
+        self.states
+            .set(SubsystemKind::Watcher, SubsystemState::Scanning);
+        for (index, path) in roots.iter().enumerate() {
+            let mut files = get_project_files(&self.workspace, path.clone(), self.channel_size)?;
 
-        for entry in walker
-            .filter_map(|it| it.ok())
-            .filter(|it| it.file_type().is_file())
-        {
-            info!("File found: {:?}", entry.path());
-            self.path_event_tx
-                .send(Arc::new(PathEvent {
-                    path: Arc::new(entry.into_path()),
-                    kind: EventKind::Create(CreateKind::File),
-                }))
-                .await
-                .into_diagnostic()?;
+            let mut found = 0usize;
+            while let Some(file) = files.next().await {
+                found += 1;
+                info!("File found: {:?}", file);
+                self.path_event_tx
+                    .send(Arc::new(PathEvent {
+                        path: Arc::new(normalize_path(&file)),
+                        kind: EventKind::Create(CreateKind::File),
+                    }))
+                    .await
+                    .into_diagnostic()?;
+            }
+            if found == 0 && index == 0 {
+                warn!(
+                    "No files of {} match the semantic pattern {}, the semantic index stays empty. \
+                    The pattern is matched against the workspace relative paths, so `*.rs` matches \
+                    only the files of the workspace root, use `**/*.rs` for all of them",
+                    path.display(),
+                    self.workspace.semantic.pattern
+                );
+            } else if found == 0 {
+                warn!(
+                    "No files of extra root {} match its pattern, the pattern is matched \
+                    against the root relative paths",
+                    path.display()
+                );
+            }
         }
         info!("Path scanner finished, setting first path scan to true");
 
         self.first_path_scan.store(true, Ordering::Relaxed);
 
-        info!("Start project files watcher for {}", path.display());
-
-        let mut debouncer = create_debounced_watcher(self.path_event_tx.clone())
-            .await
-            .into_diagnostic()?;
+        info!("Start project files watcher for {}", roots[0].display());
 
-        info!("Watching path: {:?}", path);
-
-        debouncer
-            .watch(path, RecursiveMode::Recursive)
-            .into_diagnostic()?;
+        let recreate = Arc::new(Notify::new());
+        let mut debouncer = self.watch(&roots, recreate.clone()).await?;
 
         info!("Project files watcher started");
+        self.states
+            .set(SubsystemKind::Watcher, SubsystemState::Watching);
 
-        subsys.on_shutdown_requested().await;
+        loop {
+            tokio::select! {
+                _ = subsys.on_shutdown_requested() => break,
+                _ = recreate.notified() => {
+                    warn!("Recreating the project files watcher after the clock anomaly");
+                    tokio::task::spawn_blocking(move || debouncer.stop())
+                        .await
+                        .into_diagnostic()?;
+                    debouncer = self.watch(&roots, recreate.clone()).await?;
+                    self.stats.recreations.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
 
-        Ok(())
+        // stopping joins the threads of the debouncer, so it runs outside of the async workers
+        let stopped = with_shutdown_deadline("Watcher", self.shutdown_timeout, async move {
+            tokio::task::spawn_blocking(move || debouncer.stop())
+                .await
+                .into_diagnostic()
+        })
+        .await;
+        self.states
+            .set(SubsystemKind::Watcher, SubsystemState::Stopped);
+        stopped
     }
 }
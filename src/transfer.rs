@@ -0,0 +1,211 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use futures::TryStreamExt;
+use miette::{IntoDiagnostic, Result};
+use rig::{OneOrMany, embeddings::Embedding};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tracing::{info, warn};
+
+use crate::{
+    McpConfig, indexed_model_id, init_db, open_index_table,
+    repositories::{StoredChunk, embedding_dims, stream_chunks},
+    services::{native_path, storage_path},
+    subsystems::chunker::{ArcTextChunk, TextChunk},
+};
+
+/// First line of the chunks file, the chunks are imported by the same model only
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunksHeader {
+    /// Identity of the model the chunks are embedded by, None if the store doesn't keep it
+    pub model: Option<String>,
+    pub dims: usize,
+}
+
+/// Line of one chunk of the chunks file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkRecord {
+    pub id: String,
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    #[serde(default)]
+    pub text: Option<String>,
+    pub embedding: Vec<f64>,
+    #[serde(default)]
+    pub chunk_index: Option<usize>,
+    #[serde(default)]
+    pub total_chunks: Option<usize>,
+}
+
+impl From<StoredChunk> for ChunkRecord {
+    fn from(chunk: StoredChunk) -> Self {
+        Self {
+            id: chunk.id,
+            path: storage_path(&chunk.path),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            text: chunk.text,
+            embedding: chunk.embedding,
+            chunk_index: chunk.chunk_index,
+            total_chunks: chunk.total_chunks,
+        }
+    }
+}
+
+impl From<ChunkRecord> for TextChunk {
+    fn from(record: ChunkRecord) -> Self {
+        let mut chunk = TextChunk::new(
+            native_path(&record.path).into(),
+            record.start_line,
+            record.end_line.saturating_sub(record.start_line),
+        );
+        chunk.text = record
+            .text
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        chunk.chunk_index = record.chunk_index;
+        chunk.total_chunks = record.total_chunks;
+        chunk
+    }
+}
+
+/// Writes the header and the chunks of the table of the first workspace to the JSON lines file as the table is read,
+/// returns the number of the written chunks. The table is opened as it is, so the export doesn't
+/// rebuild a table of another model
+pub async fn export_chunks(config: &McpConfig, out: &Path) -> Result<usize> {
+    let semantic = &config.workspaces()[0].semantic;
+    let table_config = &semantic.table;
+    let table = open_index_table(semantic).await?;
+    let header = ChunksHeader {
+        model: indexed_model_id(semantic),
+        dims: embedding_dims(&table, table_config).await?,
+    };
+    info!("Exporting chunks of {:?} to {}", header, out.display());
+
+    let file = File::create(out)
+        .map_err(|e| miette::miette!("Failed to create {}: {}", out.display(), e))?;
+    let mut writer = BufWriter::new(file);
+    write_line(&mut writer, &header)?;
+    let mut count = 0;
+    let mut batches = stream_chunks(&table, table_config).await?;
+    while let Some(chunks) = batches.try_next().await? {
+        for chunk in chunks {
+            write_line(&mut writer, &ChunkRecord::from(chunk))?;
+            count += 1;
+        }
+    }
+    writer.flush().into_diagnostic()?;
+    Ok(count)
+}
+
+/// Adds the chunks of the JSON lines file to the table of the first workspace by batches of `search.semantic.batch_size`
+/// as the file is read, returns the number of the added chunks. The file must be exported with
+/// the model and the dimensions of the config, the chunks of another model are never mixed in
+pub async fn import_chunks(config: &McpConfig, input: &Path) -> Result<usize> {
+    if config.read_only {
+        return Err(miette::miette!(
+            "Chunks can't be imported in the read-only mode, import them by the process which \
+            writes the index"
+        ));
+    }
+    let file = File::open(input)
+        .map_err(|e| miette::miette!("Failed to open {}: {}", input.display(), e))?;
+    let mut lines = BufReader::new(file).lines();
+    let header = match lines.next() {
+        Some(line) => parse_line::<ChunksHeader>(input, 1, &line.into_diagnostic()?)?,
+        None => return Err(miette::miette!("File {} is empty", input.display())),
+    };
+
+    let (ndims, _, _, vector_store) = init_db(config).await?;
+    check_header(
+        &header,
+        ndims,
+        indexed_model_id(&config.workspaces()[0].semantic),
+    )?;
+    info!("Importing chunks of {:?} from {}", header, input.display());
+
+    let batch_size = config.search.semantic.batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut count = 0;
+    for (index, line) in lines.enumerate() {
+        let line = line.into_diagnostic()?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_number = index + 2;
+        let record = parse_line::<ChunkRecord>(input, line_number, &line)?;
+        if record.embedding.len() != ndims {
+            return Err(miette::miette!(
+                "Chunk at line {} of {} has {} dimensions, not {}",
+                line_number,
+                input.display(),
+                record.embedding.len(),
+                ndims
+            ));
+        }
+        let embedding = Embedding {
+            document: record.text.clone().unwrap_or_default(),
+            vec: record.embedding.clone(),
+        };
+        batch.push((
+            ArcTextChunk::from(TextChunk::from(record)),
+            OneOrMany::one(embedding),
+        ));
+        if batch.len() >= batch_size {
+            count += batch.len();
+            vector_store.upsert(std::mem::take(&mut batch)).await?;
+        }
+    }
+    if !batch.is_empty() {
+        count += batch.len();
+        vector_store.upsert(batch).await?;
+    }
+    Ok(count)
+}
+
+/// Fails if the chunks are embedded by another model, only the dimensions are checked if the
+/// identity of a model is unknown
+fn check_header(header: &ChunksHeader, ndims: usize, model: Option<String>) -> Result<()> {
+    let help = "import the file with the model it was exported by or index the workspace instead";
+    if header.dims != ndims {
+        return Err(miette::miette!(
+            help = help,
+            "Chunks are embedded with {} dimensions, the model of the config has {}",
+            header.dims,
+            ndims
+        ));
+    }
+    match (&header.model, model) {
+        (Some(exported), Some(model)) if *exported != model => Err(miette::miette!(
+            help = help,
+            "Chunks are embedded by model {}, not by model {} of the config",
+            exported,
+            model
+        )),
+        (Some(_), Some(_)) => Ok(()),
+        _ => {
+            warn!("Model identity of the chunks is unknown, only their dimensions are checked");
+            Ok(())
+        }
+    }
+}
+
+fn write_line<T: Serialize>(writer: &mut impl Write, value: &T) -> Result<()> {
+    serde_json::to_writer(&mut *writer, value).into_diagnostic()?;
+    writer.write_all(b"\n").into_diagnostic()
+}
+
+fn parse_line<T: DeserializeOwned>(input: &Path, line_number: usize, line: &str) -> Result<T> {
+    serde_json::from_str(line).map_err(|e| {
+        miette::miette!(
+            "Failed to parse line {} of {}: {}",
+            line_number,
+            input.display(),
+            e
+        )
+    })
+}
@@ -0,0 +1,202 @@
+//! Code reuse search over the `common` workspace indexed by the stub model with the symbols of
+//! the scripted LSP server, the binary is started again as the relay to the server
+mod common;
+
+use common::{FakeLsp, TempWorkspace, lsp_server, relays_fake_lsp, rules, scenario, test_config};
+use miette::{Result, ensure};
+use semantrix::{
+    McpConfig, RunMode, SearchHandle, Semantrix,
+    services::{
+        mcp::CodeReuseSearchRequest,
+        search::{CodeReuseSearchResult, code_reuse_search},
+    },
+};
+
+/// Time of one search, the fake LSP server and the stub model answer well within it
+const TIMEOUT_MS: u64 = 30_000;
+/// Semantic query sharing the words of the doc comment of `send_with_retry`
+const SEMANTIC_QUERY: &str = "sends the request again until the retries are spent";
+
+fn main() -> Result<()> {
+    if relays_fake_lsp() {
+        return Ok(());
+    }
+    run()
+}
+
+#[tokio::main]
+async fn run() -> Result<()> {
+    let workspace = TempWorkspace::new("code-reuse")?;
+    let fake_lsp = FakeLsp::start().await?;
+    let mut config = test_config(&workspace)?;
+    fake_lsp.configure(&mut config)?;
+    let semantrix = Semantrix::builder()
+        .config(config.clone())
+        .mode(RunMode::Embedded)
+        .build()
+        .await?;
+    let search = semantrix.search();
+    search.wait_index().await?;
+
+    scenario(
+        "both_searches_find_the_client",
+        both_searches_find_the_client(&search, &workspace, &config),
+    )
+    .await?;
+    scenario(
+        "empty_name_patterns_skip_fuzzy_search",
+        empty_name_patterns_skip_fuzzy_search(&search, &workspace, &config),
+    )
+    .await?;
+    scenario(
+        "too_many_queries_are_rejected",
+        too_many_queries_are_rejected(&search, &config),
+    )
+    .await?;
+
+    semantrix.shutdown().await
+}
+
+fn request(semantic_queries: Vec<String>, name_patterns: Vec<String>) -> CodeReuseSearchRequest {
+    CodeReuseSearchRequest {
+        semantic_queries,
+        name_patterns,
+        offset: None,
+        cursor: None,
+        include_docs: None,
+        include_code: None,
+        max_results_per_file: None,
+        group_by_file: None,
+        exclude_comment_only_matches: None,
+    }
+}
+
+async fn search_code(
+    search: &SearchHandle,
+    config: &McpConfig,
+    request: CodeReuseSearchRequest,
+) -> Result<CodeReuseSearchResult> {
+    let lsp_server = lsp_server(search).await?;
+    let rules = rules(config)?;
+    code_reuse_search(
+        &lsp_server,
+        config,
+        search.pipeline(),
+        &rules,
+        request,
+        TIMEOUT_MS,
+    )
+    .await
+}
+
+async fn both_searches_find_the_client(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+    config: &McpConfig,
+) -> Result<()> {
+    let found = search_code(
+        search,
+        config,
+        request(
+            vec![SEMANTIC_QUERY.to_string()],
+            vec!["http client".to_string()],
+        ),
+    )
+    .await?;
+
+    ensure!(found.warnings.is_empty(), "warnings {:?}", found.warnings);
+    ensure!(
+        !found.timeout.timed_out,
+        "cut off {:?}",
+        found.timeout.stages
+    );
+    let fuzzy = found
+        .fuzzy_symbols
+        .iter()
+        .flatten()
+        .map(|it| it.name.as_str())
+        .collect::<Vec<_>>();
+    ensure!(fuzzy == ["HttpClient"], "fuzzy symbols {:?}", fuzzy);
+
+    let client = workspace.path("src/http/client.rs");
+    let semantic_paths = found
+        .semantic_symbols
+        .iter()
+        .flatten()
+        .filter_map(|it| it.path())
+        .chain(
+            found
+                .semantic_snippets
+                .iter()
+                .flatten()
+                .map(|it| it.path.clone()),
+        )
+        .collect::<Vec<_>>();
+    ensure!(
+        semantic_paths.contains(&client),
+        "semantic results in {:?}",
+        semantic_paths
+    );
+    ensure!(
+        found
+            .fused_symbols
+            .iter()
+            .any(|it| it.symbol.name == "HttpClient"),
+        "fused symbols {:?}",
+        found.fused_symbols
+    );
+    ensure!(
+        found.files.iter().any(|it| it.path == client),
+        "files {:?}",
+        found.files.iter().map(|it| &it.path).collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+async fn empty_name_patterns_skip_fuzzy_search(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+    config: &McpConfig,
+) -> Result<()> {
+    let found = search_code(
+        search,
+        config,
+        request(vec![SEMANTIC_QUERY.to_string()], vec![]),
+    )
+    .await?;
+
+    let sources = found
+        .warnings
+        .iter()
+        .map(|it| it.source.as_str())
+        .collect::<Vec<_>>();
+    ensure!(sources == ["fuzzy"], "warnings {:?}", found.warnings);
+    ensure!(
+        found.fuzzy_symbols.as_ref().is_some_and(Vec::is_empty),
+        "fuzzy symbols {:?}",
+        found.fuzzy_symbols
+    );
+    let client = workspace.path("src/http/client.rs");
+    ensure!(
+        found.files.iter().any(|it| it.path == client),
+        "files {:?}",
+        found.files.iter().map(|it| &it.path).collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+async fn too_many_queries_are_rejected(search: &SearchHandle, config: &McpConfig) -> Result<()> {
+    let queries = (0..21)
+        .map(|it| format!("query {}", it))
+        .collect::<Vec<_>>();
+    let rejected = search_code(search, config, request(queries, vec![])).await;
+
+    ensure!(
+        rejected
+            .as_ref()
+            .is_err_and(|e| e.to_string().contains("semantic_queries has 21 items")),
+        "searched {:?}",
+        rejected.map(|it| it.warnings)
+    );
+    Ok(())
+}
@@ -0,0 +1,625 @@
+//! Fixtures of the integration tests: a workspace of a few Rust files in a temp folder, the
+//! config indexing it by the stub model and a scripted LSP server answering for its symbols.
+//! Every test binary runs its scenarios in one process, as the process state is set once
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    future::Future,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use config::{Config, File, FileFormat};
+use miette::{IntoDiagnostic, Result, miette};
+use semantrix::{McpConfig, SearchHandle, services::Ruleset, subsystems::lsp::GuardedLspServer};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, tcp::OwnedWriteHalf},
+    sync::oneshot,
+};
+use url::Url;
+
+/// Argument the test binary is started with as the relay of the fake LSP server of its pipeline
+pub const FAKE_LSP_RELAY_ARG: &str = "fake-lsp-relay";
+/// Error code of the requests of an unknown method
+pub const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Files of the workspace by their workspace relative paths
+pub static FILES: [(&str, &str); 4] = [
+    ("src/lib.rs", "pub mod app;\npub mod http;\n"),
+    (
+        "src/http/client.rs",
+        "/// HTTP client sending the requests with retries
+pub struct HttpClient {
+    pub retries: usize,
+}
+
+impl HttpClient {
+    /// Sends the request again until it succeeds or the retries are spent
+    pub fn send_with_retry(&self, request: &str) -> Result<String, String> {
+        let mut last_error = String::new();
+        for _ in 0..=self.retries {
+            match send(request) {
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn send(request: &str) -> Result<String, String> {
+    Err(format!(\"no network for {}\", request))
+}
+",
+    ),
+    (
+        "src/app/orders.rs",
+        "use crate::http::client::HttpClient;
+
+pub fn submit_order(client: &HttpClient, order: &str) -> Result<String, String> {
+    client.send_with_retry(&format!(\"order {}\", order))
+}
+",
+    ),
+    (
+        "src/app/payments.rs",
+        "use crate::http::client::HttpClient;
+
+pub fn submit_payment(client: &HttpClient, amount: u64) -> Result<String, String> {
+    client.send_with_retry(&format!(\"payment {}\", amount))
+}
+",
+    ),
+];
+
+/// Symbol of the files answered by the fake LSP server
+struct FakeSymbol {
+    name: &'static str,
+    /// LSP symbol kind
+    kind: u32,
+    path: &'static str,
+    /// First and last lines of the symbol
+    lines: (u32, u32),
+    container: Option<&'static str>,
+    hover: &'static str,
+    /// Workspace relative paths and lines of the usages of the symbol
+    references: &'static [(&'static str, u32)],
+}
+
+static SYMBOLS: [FakeSymbol; 6] = [
+    FakeSymbol {
+        name: "HttpClient",
+        kind: 23,
+        path: "src/http/client.rs",
+        lines: (1, 3),
+        container: None,
+        hover: "pub struct HttpClient",
+        references: &[
+            ("src/app/orders.rs", 0),
+            ("src/app/orders.rs", 2),
+            ("src/app/payments.rs", 0),
+            ("src/app/payments.rs", 2),
+        ],
+    },
+    FakeSymbol {
+        name: "send_with_retry",
+        kind: 6,
+        path: "src/http/client.rs",
+        lines: (7, 16),
+        container: Some("HttpClient"),
+        hover: "pub fn send_with_retry(&self, request: &str) -> Result<String, String>",
+        references: &[("src/app/orders.rs", 3), ("src/app/payments.rs", 3)],
+    },
+    FakeSymbol {
+        name: "send",
+        kind: 12,
+        path: "src/http/client.rs",
+        lines: (19, 21),
+        container: None,
+        hover: "fn send(request: &str) -> Result<String, String>",
+        references: &[("src/http/client.rs", 10)],
+    },
+    FakeSymbol {
+        name: "submit_order",
+        kind: 12,
+        path: "src/app/orders.rs",
+        lines: (2, 4),
+        container: None,
+        hover: "pub fn submit_order(client: &HttpClient, order: &str) -> Result<String, String>",
+        references: &[],
+    },
+    FakeSymbol {
+        name: "submit_payment",
+        kind: 12,
+        path: "src/app/payments.rs",
+        lines: (2, 4),
+        container: None,
+        hover: "pub fn submit_payment(client: &HttpClient, amount: u64) -> Result<String, String>",
+        references: &[],
+    },
+    FakeSymbol {
+        name: "app",
+        kind: 2,
+        path: "src/lib.rs",
+        lines: (0, 0),
+        container: None,
+        hover: "pub mod app",
+        references: &[],
+    },
+];
+
+/// Workspace of [`FILES`] in a temp folder with a store next to it, both are removed on drop
+pub struct TempWorkspace {
+    base: PathBuf,
+    pub root: PathBuf,
+    pub lancedb_store: PathBuf,
+}
+
+impl TempWorkspace {
+    pub fn new(name: &str) -> Result<Self> {
+        let base = std::env::temp_dir().join(format!("semantrix-{}-{}", name, std::process::id()));
+        let root = base.join("workspace");
+        for (path, text) in FILES {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).into_diagnostic()?;
+            fs::write(&path, text).into_diagnostic()?;
+        }
+        Ok(Self {
+            lancedb_store: base.join("lancedb"),
+            base,
+            root,
+        })
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+impl Drop for TempWorkspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.base);
+    }
+}
+
+/// Config of the repository indexing the workspace by the stub model with small chunks, the
+/// LSP server is set by [`FakeLsp::configure`]
+pub fn test_config(workspace: &TempWorkspace) -> Result<McpConfig> {
+    let mut config = Config::builder()
+        .add_source(File::new("config.yml", FileFormat::Yaml))
+        .build()
+        .into_diagnostic()?
+        .try_deserialize::<McpConfig>()
+        .into_diagnostic()?;
+    let semantic = &mut config.search.semantic;
+    semantic.model = "stub".to_string();
+    semantic.lancedb_store = workspace.lancedb_store.to_string_lossy().to_string();
+    semantic.pattern = "**/*.rs".to_string();
+    semantic.chunk_size = 4;
+    semantic.overlap_size = 1;
+    let fuzzy = &mut config.search.fuzzy;
+    fuzzy.server_options = json!({});
+    fuzzy.readiness_tokens = vec![];
+    fuzzy.workspace_uri = Url::from_directory_path(&workspace.root)
+        .map_err(|_| miette!("Workspace {} is not absolute", workspace.root.display()))?
+        .to_string();
+    Ok(config)
+}
+
+/// Rules of the repository
+pub fn rules(config: &McpConfig) -> Result<Ruleset> {
+    let rules = fs::File::open(&config.rules).into_diagnostic()?;
+    serde_yaml::from_reader(rules).into_diagnostic()
+}
+
+/// LSP server of the pipeline of the handle once it is started
+pub async fn lsp_server(search: &SearchHandle) -> Result<GuardedLspServer> {
+    let mut lsp_server_rx = search.pipeline().lsp_server_rx.clone();
+    let lsp_server = lsp_server_rx
+        .wait_for(Option::is_some)
+        .await
+        .into_diagnostic()?
+        .clone();
+    lsp_server.ok_or_else(|| miette!("LSP server is not started"))
+}
+
+/// Runs the scenario and reports it like the test harness, a failed one ends the binary
+pub async fn scenario(name: &str, scenario: impl Future<Output = Result<()>>) -> Result<()> {
+    scenario.await?;
+    println!("test {} ... ok", name);
+    Ok(())
+}
+
+/// Relays the standard IO to the fake LSP server of the address of the arguments if the binary
+/// is started as the relay, true then. async-lsp-client talks only to a child process, so the
+/// child copies the bytes while [`FakeLsp`] runs in the test process
+pub fn relays_fake_lsp() -> bool {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some(FAKE_LSP_RELAY_ARG) {
+        return false;
+    }
+    let Some(server) = args.next().and_then(|addr| TcpStream::connect(addr).ok()) else {
+        return true;
+    };
+    let Ok(mut to_server) = server.try_clone() else {
+        return true;
+    };
+    std::thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin().lock(), &mut to_server);
+        let _ = to_server.shutdown(Shutdown::Write);
+    });
+    // the messages have no trailing newline, so every read is flushed by itself
+    let (mut from_server, mut output) = (server, io::stdout().lock());
+    let mut buffer = [0; 8192];
+    while let Ok(read) = from_server.read(&mut buffer) {
+        if read == 0 || output.write_all(&buffer[..read]).is_err() || output.flush().is_err() {
+            break;
+        }
+    }
+    true
+}
+
+/// Reply of the fake LSP server to the next request of a method, see [`FakeLsp::script`]
+pub enum Reply {
+    Result(Value),
+    /// Error of the code, like [`METHOD_NOT_FOUND`]
+    Error(i64),
+    /// Answer of the fixtures sent after the delay, the other requests are answered meanwhile
+    Late(Duration),
+}
+
+/// Scripted LSP server answering for [`SYMBOLS`] in the test process, the pipeline reaches it
+/// through the relay of [`relays_fake_lsp`]. Besides the answers it reports the work of its
+/// progress tokens by `$/progress` and sends its own requests to the client
+#[derive(Clone)]
+pub struct FakeLsp {
+    addr: SocketAddr,
+    state: Arc<FakeState>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    /// Replies of the next requests of the methods, the fixtures answer the other ones
+    scripted: Mutex<HashMap<String, VecDeque<Reply>>>,
+    /// Tokens of the work begun once the client is initialized
+    work_tokens: Mutex<Vec<String>>,
+    /// Writer of the connection of the client, the latest one is kept
+    output: tokio::sync::Mutex<Option<OwnedWriteHalf>>,
+    /// Waiters of the answers to the requests of the server by their ids
+    answers: Mutex<HashMap<String, oneshot::Sender<Value>>>,
+    next_id: AtomicU64,
+    /// Methods of the requests and the notifications of the client in their order
+    received: Mutex<Vec<String>>,
+}
+
+impl FakeLsp {
+    /// Listens on a free port of the loopback and serves every client connecting to it
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.into_diagnostic()?;
+        let fake = Self {
+            addr: listener.local_addr().into_diagnostic()?,
+            state: Arc::default(),
+        };
+        let server = fake.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (input, output) = stream.into_split();
+                *server.state.output.lock().await = Some(output);
+                tokio::spawn(server.clone().serve(BufReader::new(input)));
+            }
+        });
+        Ok(fake)
+    }
+
+    /// Sets the relay of the server as the LSP server of the config
+    pub fn configure(&self, config: &mut McpConfig) -> Result<()> {
+        let fuzzy = &mut config.search.fuzzy;
+        fuzzy.lsp_server = std::env::current_exe()
+            .into_diagnostic()?
+            .to_string_lossy()
+            .to_string();
+        fuzzy.server_args = vec![FAKE_LSP_RELAY_ARG.to_string(), self.addr.to_string()];
+        Ok(())
+    }
+
+    /// Replies to the next request of the method by the reply instead of the fixtures
+    pub fn script(&self, method: &str, reply: Reply) {
+        self.state
+            .scripted
+            .lock()
+            .unwrap()
+            .entry(method.to_string())
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Begins the work of the token once the client is initialized, it lasts until
+    /// [`FakeLsp::end_work`]
+    pub fn begin_work(&self, token: &str) {
+        self.state
+            .work_tokens
+            .lock()
+            .unwrap()
+            .push(token.to_string());
+    }
+
+    pub async fn end_work(&self, token: &str) -> Result<()> {
+        self.send(&json!({
+            "jsonrpc": "2.0",
+            "method": "$/progress",
+            "params": { "token": token, "value": { "kind": "end", "message": "done" } },
+        }))
+        .await
+    }
+
+    /// Answer of the client to the request of the server
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id(method);
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.state
+            .answers
+            .lock()
+            .unwrap()
+            .insert(id.clone(), answer_tx);
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), answer_rx)
+            .await
+            .map_err(|_| miette!("No answer to {}", method))?
+            .into_diagnostic()
+    }
+
+    /// Methods of the requests and the notifications received from the client
+    pub fn received(&self) -> Vec<String> {
+        self.state.received.lock().unwrap().clone()
+    }
+
+    fn next_id(&self, method: &str) -> String {
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        format!("{}/{}", method, id)
+    }
+
+    async fn send(&self, message: &Value) -> Result<()> {
+        let body = message.to_string();
+        let mut output = self.state.output.lock().await;
+        let output = output
+            .as_mut()
+            .ok_or_else(|| miette!("No client is connected"))?;
+        output
+            .write_all(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).as_bytes())
+            .await
+            .into_diagnostic()
+    }
+
+    /// Answers the messages of the client until the exit notification or the end of the input
+    async fn serve(self, mut input: impl AsyncBufRead + Unpin) {
+        let mut root = None;
+        while let Some(message) = read_message(&mut input).await {
+            let method = message.get("method").and_then(Value::as_str);
+            let id = message.get("id").cloned();
+            let Some(method) = method else {
+                // an answer of the client to a request of the server
+                let waiter = id
+                    .as_ref()
+                    .and_then(Value::as_str)
+                    .and_then(|id| self.state.answers.lock().unwrap().remove(id));
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(message);
+                }
+                continue;
+            };
+            self.state.received.lock().unwrap().push(method.to_string());
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            let Some(id) = id else {
+                match method {
+                    "initialized" => self.begin_progress().await,
+                    "exit" => break,
+                    _ => {}
+                }
+                continue;
+            };
+            if method == "initialize" {
+                root = params
+                    .pointer("/workspaceFolders/0/uri")
+                    .and_then(Value::as_str)
+                    .and_then(|it| Url::parse(it).ok());
+            }
+            let scripted = self
+                .state
+                .scripted
+                .lock()
+                .unwrap()
+                .get_mut(method)
+                .and_then(VecDeque::pop_front);
+            let answer = match scripted {
+                Some(Reply::Result(result)) => Ok(result),
+                Some(Reply::Error(code)) => Err(code),
+                Some(Reply::Late(delay)) => {
+                    let fake = self.clone();
+                    let answer = fixture_answer(method, &params, root.as_ref());
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        let _ = fake.send(&response(id, answer)).await;
+                    });
+                    continue;
+                }
+                None => fixture_answer(method, &params, root.as_ref()),
+            };
+            if self.send(&response(id, answer)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Creates the progress of the work tokens and begins their work, like a server indexing
+    /// the workspace after the initialization
+    async fn begin_progress(&self) {
+        let tokens = self.state.work_tokens.lock().unwrap().clone();
+        for token in tokens {
+            let create = json!({
+                "jsonrpc": "2.0",
+                "id": self.next_id("window/workDoneProgress/create"),
+                "method": "window/workDoneProgress/create",
+                "params": { "token": token },
+            });
+            let begin = json!({
+                "jsonrpc": "2.0",
+                "method": "$/progress",
+                "params": { "token": token, "value": { "kind": "begin", "title": "Indexing" } },
+            });
+            let _ = self.send(&create).await;
+            let _ = self.send(&begin).await;
+        }
+    }
+}
+
+fn response(id: Value, answer: std::result::Result<Value, i64>) -> Value {
+    match answer {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(code) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": "Method Not Found" },
+        }),
+    }
+}
+
+/// Answer of the fixtures to the request, the unknown methods are not found
+fn fixture_answer(
+    method: &str,
+    params: &Value,
+    root: Option<&Url>,
+) -> std::result::Result<Value, i64> {
+    let result = match method {
+        "initialize" => json!({
+            "capabilities": {
+                "workspaceSymbolProvider": true,
+                "documentSymbolProvider": true,
+                "referencesProvider": true,
+                "definitionProvider": true,
+                "hoverProvider": true,
+            },
+            "serverInfo": { "name": "fake-lsp" },
+        }),
+        "shutdown" => Value::Null,
+        "workspace/symbol" => {
+            let query = params["query"].as_str().unwrap_or_default().to_lowercase();
+            let symbols = SYMBOLS
+                .iter()
+                .filter(|it| it.name.to_lowercase().contains(&query))
+                .filter_map(|it| symbol_information(root?, it))
+                .collect::<Vec<_>>();
+            json!(symbols)
+        }
+        "textDocument/documentSymbol" => {
+            let path = relative_path(root, &params["textDocument"]["uri"]);
+            let symbols = SYMBOLS
+                .iter()
+                .filter(|it| Some(it.path) == path.as_deref())
+                .filter_map(|it| symbol_information(root?, it))
+                .collect::<Vec<_>>();
+            json!(symbols)
+        }
+        "textDocument/hover" => match symbol_at(root, params) {
+            Some(symbol) => json!({
+                "contents": {
+                    "kind": "markdown",
+                    "value": format!("```rust\n{}\n```", symbol.hover),
+                },
+            }),
+            None => Value::Null,
+        },
+        "textDocument/references" => {
+            let references = symbol_at(root, params)
+                .into_iter()
+                .flat_map(|symbol| {
+                    symbol.references.iter().filter_map(|(path, line)| {
+                        let column = file_line(path, *line)?.find(symbol.name)?;
+                        location(
+                            root?,
+                            path,
+                            (*line, column),
+                            (*line, column + symbol.name.len()),
+                        )
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!(references)
+        }
+        _ => return Err(METHOD_NOT_FOUND),
+    };
+    Ok(result)
+}
+
+async fn read_message(input: &mut (impl AsyncBufRead + Unpin)) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).await.ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).await.ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn file_line(path: &str, line: u32) -> Option<&'static str> {
+    let (_, text) = *FILES.iter().find(|(it, _)| *it == path)?;
+    text.lines().nth(line as usize)
+}
+
+fn location(root: &Url, path: &str, start: (u32, usize), end: (u32, usize)) -> Option<Value> {
+    Some(json!({
+        "uri": root.join(path).ok()?.to_string(),
+        "range": {
+            "start": { "line": start.0, "character": start.1 },
+            "end": { "line": end.0, "character": end.1 },
+        },
+    }))
+}
+
+fn symbol_information(root: &Url, symbol: &FakeSymbol) -> Option<Value> {
+    let end = file_line(symbol.path, symbol.lines.1)?.len();
+    Some(json!({
+        "name": symbol.name,
+        "kind": symbol.kind,
+        "location": location(root, symbol.path, (symbol.lines.0, 0), (symbol.lines.1, end))?,
+        "containerName": symbol.container,
+    }))
+}
+
+/// Workspace relative path of the document URI
+fn relative_path(root: Option<&Url>, uri: &Value) -> Option<String> {
+    let root = root?.to_file_path().ok()?;
+    let path = Url::parse(uri.as_str()?).ok()?.to_file_path().ok()?;
+    let relative = path.strip_prefix(&root).ok()?;
+    Some(relative.to_string_lossy().replace('\\', "/"))
+}
+
+/// Innermost symbol of the document whose lines hold the position of the params
+fn symbol_at(root: Option<&Url>, params: &Value) -> Option<&'static FakeSymbol> {
+    let path = relative_path(root, &params["textDocument"]["uri"])?;
+    let line = params["position"]["line"].as_u64()? as u32;
+    SYMBOLS
+        .iter()
+        .filter(|it| it.path == path && (it.lines.0..=it.lines.1).contains(&line))
+        .min_by_key(|it| it.lines.1 - it.lines.0)
+}
@@ -0,0 +1,335 @@
+//! Readiness, server requests, fuzzy search, symbol resolution and placer against the scripted
+//! LSP server of the `common` fixtures, the binary is started again as the relay to the server
+mod common;
+
+use std::time::Duration;
+
+use common::{
+    FakeLsp, METHOD_NOT_FOUND, TempWorkspace, lsp_server, relays_fake_lsp, rules, scenario,
+    test_config,
+};
+use miette::{Result, ensure};
+use semantrix::{
+    McpConfig, RunMode, SearchHandle, Semantrix,
+    enums::{McpSymbolKind, PlaceConfidence},
+    services::{mcp::SymbolsPlacerRequest, resolve_symbol, search::symbols_placer},
+};
+use serde_json::{Value, json};
+
+/// Token of the work the fake server does after the initialization, the pipeline waits for its
+/// end by the readiness token of its prefix
+const INDEXING_TOKEN: &str = "fake/indexing/1";
+const READINESS_TOKEN: &str = "fake/indexing";
+
+fn main() -> Result<()> {
+    if relays_fake_lsp() {
+        return Ok(());
+    }
+    run()
+}
+
+#[tokio::main]
+async fn run() -> Result<()> {
+    let workspace = TempWorkspace::new("fake-lsp")?;
+    let fake_lsp = FakeLsp::start().await?;
+    fake_lsp.begin_work(INDEXING_TOKEN);
+    let mut config = test_config(&workspace)?;
+    fake_lsp.configure(&mut config)?;
+    config.search.fuzzy.readiness_tokens = vec![READINESS_TOKEN.to_string()];
+    let semantrix = Semantrix::builder()
+        .config(config.clone())
+        .mode(RunMode::Embedded)
+        .build()
+        .await?;
+    let search = semantrix.search();
+
+    scenario(
+        "lsp_server_waits_for_readiness_tokens",
+        lsp_server_waits_for_readiness_tokens(&search, &fake_lsp),
+    )
+    .await?;
+    scenario(
+        "server_requests_are_answered",
+        server_requests_are_answered(&fake_lsp),
+    )
+    .await?;
+    scenario(
+        "fuzzy_search_finds_symbols_by_query_variants",
+        fuzzy_search_finds_symbols_by_query_variants(&search),
+    )
+    .await?;
+    scenario(
+        "fuzzy_search_keeps_selected_kinds",
+        fuzzy_search_keeps_selected_kinds(&search),
+    )
+    .await?;
+    scenario(
+        "resolve_symbol_prefers_document_symbols",
+        resolve_symbol_prefers_document_symbols(&search, &workspace),
+    )
+    .await?;
+    scenario(
+        "resolve_symbol_falls_back_to_workspace_symbols",
+        resolve_symbol_falls_back_to_workspace_symbols(&search, &workspace),
+    )
+    .await?;
+    scenario(
+        "placer_moves_symbol_to_its_usages",
+        placer_moves_symbol_to_its_usages(&search, &workspace, &config),
+    )
+    .await?;
+
+    semantrix.shutdown().await
+}
+
+async fn lsp_server_waits_for_readiness_tokens(
+    search: &SearchHandle,
+    fake_lsp: &FakeLsp,
+) -> Result<()> {
+    // the work is begun once the client is initialized
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !fake_lsp.received().iter().any(|it| it == "initialized") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .map_err(|_| {
+        miette::miette!(
+            "Client is not initialized, received {:?}",
+            fake_lsp.received()
+        )
+    })?;
+    let mut lsp_server_rx = search.pipeline().lsp_server_rx.clone();
+    let ready = tokio::time::timeout(
+        Duration::from_millis(500),
+        lsp_server_rx.wait_for(Option::is_some),
+    )
+    .await;
+    ensure!(
+        ready.is_err(),
+        "LSP server is ready before the work is done"
+    );
+
+    fake_lsp.end_work(INDEXING_TOKEN).await?;
+    tokio::time::timeout(Duration::from_secs(10), lsp_server(search))
+        .await
+        .map_err(|_| miette::miette!("LSP server is not ready after the work is done"))??;
+    Ok(())
+}
+
+async fn server_requests_are_answered(fake_lsp: &FakeLsp) -> Result<()> {
+    let created = fake_lsp
+        .request(
+            "window/workDoneProgress/create",
+            json!({ "token": "fake/reindexing" }),
+        )
+        .await?;
+    ensure!(
+        created.get("result") == Some(&Value::Null) && created.get("error").is_none(),
+        "answer {}",
+        created
+    );
+
+    let configuration = fake_lsp
+        .request(
+            "workspace/configuration",
+            json!({ "items": [{ "section": "rust-analyzer" }] }),
+        )
+        .await?;
+    ensure!(
+        configuration["error"]["code"] == json!(METHOD_NOT_FOUND),
+        "answer {}",
+        configuration
+    );
+    // the params of the unknown request are sent back with the error
+    ensure!(
+        configuration["error"]["data"]["items"][0]["section"] == json!("rust-analyzer"),
+        "answer {}",
+        configuration
+    );
+    Ok(())
+}
+
+async fn fuzzy_search_finds_symbols_by_query_variants(search: &SearchHandle) -> Result<()> {
+    let symbols = search
+        .fuzzy_search(vec!["http client".to_string()], vec![], true)
+        .await?;
+
+    let names = symbols
+        .iter()
+        .map(|it| it.name.as_str())
+        .collect::<Vec<_>>();
+    ensure!(names == ["HttpClient"], "found {:?}", names);
+    let symbol = &symbols[0];
+    ensure!(
+        symbol.kind == McpSymbolKind::Struct,
+        "kind {:?}",
+        symbol.kind
+    );
+    ensure!(
+        symbol.matched_query.as_deref() == Some("HttpClient"),
+        "matched by {:?}",
+        symbol.matched_query
+    );
+    ensure!(
+        symbol
+            .hover
+            .as_deref()
+            .is_some_and(|it| it.contains("pub struct HttpClient")),
+        "hover {:?}",
+        symbol.hover
+    );
+    ensure!(
+        symbol
+            .code
+            .as_deref()
+            .is_some_and(|it| it.contains("pub retries: usize")),
+        "code {:?}",
+        symbol.code
+    );
+    Ok(())
+}
+
+async fn fuzzy_search_keeps_selected_kinds(search: &SearchHandle) -> Result<()> {
+    let all = search
+        .fuzzy_search(vec!["send".to_string()], vec![], false)
+        .await?;
+    let methods = search
+        .fuzzy_search(vec!["send".to_string()], vec!["Method".to_string()], false)
+        .await?;
+
+    let mut all = all.iter().map(|it| it.name.as_str()).collect::<Vec<_>>();
+    all.sort();
+    ensure!(all == ["send", "send_with_retry"], "found {:?}", all);
+    let methods = methods
+        .iter()
+        .map(|it| it.name.as_str())
+        .collect::<Vec<_>>();
+    ensure!(methods == ["send_with_retry"], "found {:?}", methods);
+    Ok(())
+}
+
+async fn resolve_symbol_prefers_document_symbols(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+) -> Result<()> {
+    let lsp_server = lsp_server(search).await?;
+    let client = workspace.path("src/http/client.rs");
+
+    let resolved = resolve_symbol(&lsp_server, &client, "HttpClient", None)
+        .await?
+        .ok_or_else(|| miette::miette!("HttpClient is not resolved"))?;
+
+    let symbol = &resolved.symbol;
+    ensure!(symbol.path() == Some(client), "path {:?}", symbol.path());
+    ensure!(
+        resolved.alternatives.is_empty(),
+        "{:?}",
+        resolved.alternatives
+    );
+    ensure!(
+        symbol.name_position.map(|it| it.line) == Some(1),
+        "name position {:?}",
+        symbol.name_position
+    );
+    ensure!(
+        symbol
+            .hover
+            .as_deref()
+            .is_some_and(|it| it.contains("pub struct HttpClient")),
+        "hover {:?}",
+        symbol.hover
+    );
+
+    let missing =
+        resolve_symbol(&lsp_server, &workspace.path("src/lib.rs"), "Missing", None).await?;
+    ensure!(missing.is_none(), "resolved {:?}", missing);
+    Ok(())
+}
+
+async fn resolve_symbol_falls_back_to_workspace_symbols(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+) -> Result<()> {
+    let lsp_server = lsp_server(search).await?;
+
+    let resolved = resolve_symbol(
+        &lsp_server,
+        &workspace.path("src/app/orders.rs"),
+        "send_with_retry",
+        None,
+    )
+    .await?
+    .ok_or_else(|| miette::miette!("send_with_retry is not resolved"))?;
+
+    let symbol = &resolved.symbol;
+    ensure!(
+        symbol.path() == Some(workspace.path("src/http/client.rs")),
+        "path {:?}",
+        symbol.path()
+    );
+    ensure!(
+        symbol.kind == McpSymbolKind::Method,
+        "kind {:?}",
+        symbol.kind
+    );
+    ensure!(
+        symbol.container_name.as_deref() == Some("HttpClient"),
+        "container {:?}",
+        symbol.container_name
+    );
+    ensure!(
+        symbol
+            .code
+            .as_deref()
+            .is_some_and(|it| it.contains("for _ in 0..=self.retries")),
+        "code {:?}",
+        symbol.code
+    );
+    Ok(())
+}
+
+async fn placer_moves_symbol_to_its_usages(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+    config: &McpConfig,
+) -> Result<()> {
+    let lsp_server = lsp_server(search).await?;
+    let rules = rules(config)?;
+
+    let placed = symbols_placer(
+        &lsp_server,
+        config,
+        &search.pipeline().workspace,
+        &rules,
+        SymbolsPlacerRequest::default(),
+    )
+    .await?;
+
+    // only the struct is of the final kinds, the functions stay where they are
+    let names = placed
+        .symbols
+        .iter()
+        .map(|it| it.name.as_str())
+        .collect::<Vec<_>>();
+    ensure!(names == ["HttpClient"], "analyzed {:?}", names);
+    ensure!(placed.places.len() == 1, "places {:?}", placed.places);
+    let place = &placed.places[0];
+    let app = workspace.path("src/app");
+    ensure!(
+        place.place_to.as_deref() == Some(app.to_string_lossy().as_ref()),
+        "placed to {:?}",
+        place.place_to
+    );
+    ensure!(
+        place.reference_count == 4,
+        "references {}",
+        place.reference_count
+    );
+    ensure!(
+        place.confidence == PlaceConfidence::Medium,
+        "confidence {}",
+        place.confidence
+    );
+    Ok(())
+}
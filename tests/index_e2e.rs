@@ -0,0 +1,269 @@
+//! Watched index of the `common` workspace by the stub model: the chunks of every file are
+//! stored in the temp store and found by the semantic search, the changed and the deleted files
+//! are reindexed by the watcher. The binary is started again as the relay to the fake LSP server
+mod common;
+
+use std::{fs, path::Path, time::Duration};
+
+use chrono::Utc;
+use common::{FILES, FakeLsp, TempWorkspace, relays_fake_lsp, scenario, test_config};
+use miette::{IntoDiagnostic, Result, ensure, miette};
+use semantrix::{
+    McpConfig, RunMode, SearchHandle, Semantrix,
+    repositories::{StoredChunk, chunks_for_path},
+    subsystems::chunker::count_chunks,
+};
+
+/// Time for the watcher to reindex a changed file
+const REINDEX_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() -> Result<()> {
+    if relays_fake_lsp() {
+        return Ok(());
+    }
+    run()
+}
+
+#[tokio::main]
+async fn run() -> Result<()> {
+    let workspace = TempWorkspace::new("index-e2e")?;
+    let fake_lsp = FakeLsp::start().await?;
+    let mut config = test_config(&workspace)?;
+    fake_lsp.configure(&mut config)?;
+    let semantrix = Semantrix::builder()
+        .config(config.clone())
+        .mode(RunMode::Embedded)
+        .build()
+        .await?;
+    let search = semantrix.search();
+    search.wait_index().await?;
+
+    scenario(
+        "index_has_chunks_of_every_file",
+        index_has_chunks_of_every_file(&search, &config),
+    )
+    .await?;
+    scenario(
+        "semantic_search_finds_closest_chunk",
+        semantic_search_finds_closest_chunk(&search, &workspace),
+    )
+    .await?;
+    scenario(
+        "semantic_search_keeps_hits_of_path_glob",
+        semantic_search_keeps_hits_of_path_glob(&search, &workspace),
+    )
+    .await?;
+    scenario(
+        "modified_file_is_reindexed",
+        modified_file_is_reindexed(&search, &workspace, &config),
+    )
+    .await?;
+    scenario(
+        "deleted_file_is_dropped_from_index",
+        deleted_file_is_dropped_from_index(&search, &workspace),
+    )
+    .await?;
+
+    semantrix.shutdown().await
+}
+
+/// Stored chunks of the file of the workspace
+async fn stored_chunks(search: &SearchHandle, path: &Path) -> Result<Vec<StoredChunk>> {
+    let pipeline = search.pipeline();
+    chunks_for_path(
+        &pipeline.routes.table_of(path).table,
+        path,
+        &pipeline.workspace.semantic.table,
+        pipeline.workspace.path_case,
+    )
+    .await
+}
+
+/// Polls the stored chunks of the file until they satisfy the check
+async fn wait_chunks(
+    search: &SearchHandle,
+    path: &Path,
+    check: impl Fn(&[StoredChunk]) -> bool,
+) -> Result<Vec<StoredChunk>> {
+    tokio::time::timeout(REINDEX_TIMEOUT, async {
+        loop {
+            let chunks = stored_chunks(search, path).await?;
+            if check(&chunks) {
+                return Ok(chunks);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .map_err(|_| {
+        miette!(
+            "{} is not reindexed in {:?}",
+            path.display(),
+            REINDEX_TIMEOUT
+        )
+    })?
+}
+
+async fn index_has_chunks_of_every_file(search: &SearchHandle, config: &McpConfig) -> Result<()> {
+    let semantic = &config.search.semantic;
+    let expected = FILES
+        .iter()
+        .map(|(_, text)| {
+            count_chunks(
+                text.lines().count(),
+                semantic.chunk_size,
+                semantic.overlap_size,
+            )
+        })
+        .sum::<usize>();
+
+    ensure!(search.is_index_ready(), "first index scan is not finished");
+    let pipeline = search.pipeline();
+    let chunks = pipeline.routes.count_chunks().await?;
+    ensure!(
+        chunks == expected,
+        "{} chunks, expected {}",
+        chunks,
+        expected
+    );
+    let files = pipeline
+        .routes
+        .count_indexed_files(&pipeline.workspace.semantic.table)
+        .await?;
+    ensure!(files == FILES.len(), "{} indexed files", files);
+    Ok(())
+}
+
+async fn semantic_search_finds_closest_chunk(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+) -> Result<()> {
+    let query = "sends the request again until the retries are spent";
+    let hits = search
+        .semantic_search(vec![query.to_string()], 3, None)
+        .await?;
+
+    ensure!(!hits.is_empty() && hits.len() <= 3, "{} hits", hits.len());
+    ensure!(hits.iter().all(|it| it.query == query), "{:?}", hits);
+    let best = &hits[0];
+    ensure!(
+        best.snippet.path == workspace.path("src/http/client.rs"),
+        "best hit in {}",
+        best.snippet.path.display()
+    );
+    ensure!(
+        best.snippet
+            .text
+            .contains("until it succeeds or the retries are spent"),
+        "best hit {:?}",
+        best.snippet.text
+    );
+    ensure!(!best.snippet.stale, "best hit is stale");
+    Ok(())
+}
+
+async fn semantic_search_keeps_hits_of_path_glob(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+) -> Result<()> {
+    let hits = search
+        .semantic_search(
+            vec!["sends the request again until the retries are spent".to_string()],
+            10,
+            Some("src/app/**"),
+        )
+        .await?;
+
+    ensure!(!hits.is_empty(), "no hits under src/app");
+    let app = workspace.path("src/app");
+    ensure!(
+        hits.iter().all(|it| it.snippet.path.starts_with(&app)),
+        "hits in {:?}",
+        hits.iter().map(|it| &it.snippet.path).collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+async fn modified_file_is_reindexed(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+    config: &McpConfig,
+) -> Result<()> {
+    let path = workspace.path("src/app/orders.rs");
+    ensure!(
+        !stored_chunks(search, &path).await?.is_empty(),
+        "no chunks of {}",
+        path.display()
+    );
+    // more lines than before, so the number of the chunks changes too
+    let text = (0..12)
+        .map(|it| {
+            format!(
+                "pub fn cancel_order_{}(order: &str) -> String {{ order.to_string() }}\n",
+                it
+            )
+        })
+        .collect::<String>();
+    let semantic = &config.search.semantic;
+    let expected = count_chunks(
+        text.lines().count(),
+        semantic.chunk_size,
+        semantic.overlap_size,
+    );
+    let modified_at = Utc::now();
+    fs::write(&path, &text).into_diagnostic()?;
+
+    // every row written before the change is gone and the new lines are stored
+    wait_chunks(search, &path, |chunks| {
+        chunks.len() == expected
+            && chunks
+                .iter()
+                .all(|it| it.indexed_at.is_some_and(|at| at >= modified_at))
+    })
+    .await?;
+    let hits = search
+        .semantic_search(
+            vec!["cancels the order".to_string()],
+            10,
+            Some("src/app/**"),
+        )
+        .await?;
+    ensure!(
+        hits.iter()
+            .filter(|it| it.snippet.path == path)
+            .all(|it| !it.snippet.text.contains("submit_order")),
+        "old text is found {:?}",
+        hits
+    );
+    Ok(())
+}
+
+async fn deleted_file_is_dropped_from_index(
+    search: &SearchHandle,
+    workspace: &TempWorkspace,
+) -> Result<()> {
+    let path = workspace.path("src/app/payments.rs");
+    ensure!(
+        !stored_chunks(search, &path).await?.is_empty(),
+        "no chunks of {}",
+        path.display()
+    );
+    fs::remove_file(&path).into_diagnostic()?;
+
+    wait_chunks(search, &path, <[StoredChunk]>::is_empty).await?;
+    let pipeline = search.pipeline();
+    let files = pipeline
+        .routes
+        .count_indexed_files(&pipeline.workspace.semantic.table)
+        .await?;
+    ensure!(files == FILES.len() - 1, "{} indexed files", files);
+    let hits = search
+        .semantic_search(vec!["submits the payment".to_string()], 10, None)
+        .await?;
+    ensure!(
+        hits.iter().all(|it| it.snippet.path != path),
+        "deleted file is found {:?}",
+        hits
+    );
+    Ok(())
+}
@@ -0,0 +1,339 @@
+//! Tool calls of the MCP service over the embedded pipeline of the `common` workspace, the
+//! binary is started again as the relay to the fake LSP server
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::{FakeLsp, Reply, TempWorkspace, lsp_server, relays_fake_lsp, scenario, test_config};
+use miette::{IntoDiagnostic, Result, ensure};
+use rmcp::{
+    RoleServer, ServerHandler,
+    model::{
+        CallToolRequestParam, CallToolResult, ClientInfo, JsonRpcMessage, JsonRpcResponse,
+        JsonRpcVersion2_0, NumberOrString, ServerResult,
+    },
+    service::{AtomicU32RequestIdProvider, Peer, RequestContext},
+};
+use semantrix::{
+    LiveConfig, McpConfig, ResponseType, RunMode, SearchHandle, Semantrix,
+    enums::McpToolName,
+    init_model, init_store,
+    services::{mcp::McpService, scratch::ScratchIndex},
+    subsystems::mcp::McpServerSubsystem,
+};
+use serde_json::json;
+use strum::IntoEnumIterator;
+use tokio_util::sync::CancellationToken;
+
+fn main() -> Result<()> {
+    if relays_fake_lsp() {
+        return Ok(());
+    }
+    run()
+}
+
+#[tokio::main]
+async fn run() -> Result<()> {
+    let workspace = TempWorkspace::new("mcp-service")?;
+    let fake_lsp = FakeLsp::start().await?;
+    let mut config = test_config(&workspace)?;
+    fake_lsp.configure(&mut config)?;
+    config.tools.insert(McpToolName::SymbolsPlacer, false);
+    // the JSON results are the ones the meta used to be appended to
+    config.response = ResponseType::Json;
+    let config = Arc::new(config);
+    let semantrix = Semantrix::builder()
+        .config(config.clone())
+        .mode(RunMode::Embedded)
+        .build()
+        .await?;
+    let search = semantrix.search();
+
+    scenario(
+        "rejected_calls_forget_progress_tokens",
+        rejected_calls_forget_progress_tokens(&search, &config),
+    )
+    .await?;
+    scenario(
+        "results_carry_request_id_in_meta",
+        results_carry_request_id_in_meta(&search, &config),
+    )
+    .await?;
+    scenario(
+        "listed_tools_carry_annotations",
+        listed_tools_carry_annotations(&search, &config),
+    )
+    .await?;
+    scenario(
+        "cancelled_call_frees_lsp_permit",
+        cancelled_call_frees_lsp_permit(&search, &config, &fake_lsp),
+    )
+    .await?;
+
+    semantrix.shutdown().await
+}
+
+/// Service of the pipeline of the handle as the MCP server builds it for a session
+async fn service(search: &SearchHandle, config: Arc<McpConfig>) -> Result<McpService> {
+    let (embedding_model, ndims, model_id) = init_model(&config).await?;
+    let scratch = ScratchIndex::new(
+        init_store(&config).await?,
+        &search.pipeline().workspace,
+        embedding_model.clone(),
+        ndims,
+        model_id,
+        config.scratch.clone(),
+    );
+    McpServerSubsystem {
+        live_config: LiveConfig::new(config.clone())?,
+        config,
+        embedding_model,
+        ndims,
+        pipelines: vec![search.pipeline().clone()],
+        scratch: Arc::new(scratch),
+    }
+    .service()
+}
+
+fn request(tool: &str, arguments: &serde_json::Value) -> CallToolRequestParam {
+    CallToolRequestParam {
+        name: tool.to_string().into(),
+        arguments: arguments.as_object().cloned(),
+    }
+}
+
+/// Context of the request of the id from a client which is never answered
+fn context(id: u32) -> RequestContext<RoleServer> {
+    cancellable_context(id, CancellationToken::new())
+}
+
+/// Context of the request of the id cancelled by the client with the token
+fn cancellable_context(id: u32, ct: CancellationToken) -> RequestContext<RoleServer> {
+    let (peer, _outbound) = Peer::new(
+        Arc::new(AtomicU32RequestIdProvider::default()),
+        ClientInfo::default(),
+    );
+    RequestContext {
+        ct,
+        id: NumberOrString::Number(id),
+        peer,
+    }
+}
+
+/// Calls the tool as the request of the id with the progress token remembered by the transport,
+/// true if the token is still remembered after the call is answered
+async fn call_with_progress(
+    service: &McpService,
+    id: u32,
+    tool: &str,
+    arguments: serde_json::Value,
+) -> Result<bool> {
+    service.progress_tokens.remember(&json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": "tools/call",
+        "params": { "name": tool, "arguments": arguments, "_meta": { "progressToken": id } },
+    }));
+    let answered = service
+        .call_tool(request(tool, &arguments), context(id))
+        .await;
+    ensure!(
+        answered.is_err(),
+        "{} is not rejected: {:?}",
+        tool,
+        answered
+    );
+    Ok(service
+        .progress_tokens
+        .take(&NumberOrString::Number(id))
+        .is_some())
+}
+
+async fn rejected_calls_forget_progress_tokens(
+    search: &SearchHandle,
+    config: &Arc<McpConfig>,
+) -> Result<()> {
+    let reuser = service(search, config.clone()).await?;
+    let disabled = call_with_progress(&reuser, 1, "symbols_placer", json!({})).await?;
+    ensure!(!disabled, "token of the disabled tool is kept");
+    let unknown_workspace = call_with_progress(
+        &reuser,
+        2,
+        "fuzzy_search",
+        json!({ "names": ["HttpClient"], "workspace": "missing" }),
+    )
+    .await?;
+    ensure!(!unknown_workspace, "token of the unknown workspace is kept");
+
+    let read_only = McpConfig {
+        read_only: true,
+        ..config.as_ref().clone()
+    };
+    let reader = service(search, Arc::new(read_only)).await?;
+    let mutating = call_with_progress(&reader, 3, "reindex_path", json!({ "path": "src" })).await?;
+    ensure!(!mutating, "token of the mutating tool is kept");
+    Ok(())
+}
+
+async fn results_carry_request_id_in_meta(
+    search: &SearchHandle,
+    config: &Arc<McpConfig>,
+) -> Result<()> {
+    let reuser = service(search, config.clone()).await?;
+    let result = reuser
+        .call_tool(request("get_result_schema", &json!({})), context(7))
+        .await
+        .into_diagnostic()?;
+    let message = reuser
+        .response_metas
+        .encode(JsonRpcMessage::Response(JsonRpcResponse {
+            jsonrpc: JsonRpcVersion2_0,
+            id: NumberOrString::Number(7),
+            result: ServerResult::CallToolResult(result),
+        }))
+        .into_diagnostic()?;
+
+    let result = &message["result"];
+    ensure!(
+        result["_meta"] == json!({ "requestId": "7", "cached": false }),
+        "meta {}",
+        result["_meta"]
+    );
+    ensure!(
+        !result["content"].to_string().contains("request_id"),
+        "content {}",
+        result["content"]
+    );
+    // the meta is sent once, a reused request id doesn't get it
+    ensure!(
+        reuser
+            .response_metas
+            .encode(JsonRpcMessage::Response(JsonRpcResponse {
+                jsonrpc: JsonRpcVersion2_0,
+                id: NumberOrString::Number(7),
+                result: ServerResult::CallToolResult(CallToolResult::success(vec![])),
+            }))
+            .into_diagnostic()?["result"]
+            .get("_meta")
+            .is_none(),
+        "meta is sent twice"
+    );
+    Ok(())
+}
+
+async fn listed_tools_carry_annotations(
+    search: &SearchHandle,
+    config: &Arc<McpConfig>,
+) -> Result<()> {
+    let reuser = service(search, config.clone()).await?;
+    let tools = reuser
+        .list_tools(None, context(8))
+        .await
+        .into_diagnostic()?;
+    let message = reuser
+        .response_metas
+        .encode(JsonRpcMessage::Response(JsonRpcResponse {
+            jsonrpc: JsonRpcVersion2_0,
+            id: NumberOrString::Number(8),
+            result: ServerResult::ListToolsResult(tools),
+        }))
+        .into_diagnostic()?;
+
+    let tools = message["result"]["tools"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    // every enabled tool is listed, symbols_placer is disabled by the config
+    ensure!(
+        tools.len() == McpToolName::iter().count() - 1,
+        "{} tools",
+        tools.len()
+    );
+    for tool in tools {
+        let name = tool["name"].as_str().unwrap_or_default();
+        let mutating = ["reindex_path", "index_scratch"].contains(&name);
+        let expected = json!({
+            "readOnlyHint": !mutating,
+            "destructiveHint": false,
+            "idempotentHint": true,
+            "openWorldHint": false,
+        });
+        ensure!(
+            tool["annotations"] == expected,
+            "annotations of {}: {}",
+            name,
+            tool["annotations"]
+        );
+    }
+    Ok(())
+}
+
+async fn cancelled_call_frees_lsp_permit(
+    search: &SearchHandle,
+    config: &Arc<McpConfig>,
+    fake_lsp: &FakeLsp,
+) -> Result<()> {
+    ensure!(
+        config.search.fuzzy.parallelizm == 1,
+        "parallelizm {}",
+        config.search.fuzzy.parallelizm
+    );
+    lsp_server(search).await?;
+    let reuser = service(search, config.clone()).await?;
+    // the first symbol request holds the only permit of the LSP server far beyond the test
+    fake_lsp.script("workspace/symbol", Reply::Late(Duration::from_secs(600)));
+    let sent = fake_lsp.received().len();
+
+    let ct = CancellationToken::new();
+    let slow = reuser.call_tool(
+        request(
+            "fuzzy_search",
+            &json!({ "name_patterns": ["HttpClient"], "need_code": false }),
+        ),
+        cancellable_context(9, ct.clone()),
+    );
+    let cancel = async {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !fake_lsp.received()[sent..]
+                .iter()
+                .any(|it| it == "workspace/symbol")
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| miette::miette!("Symbols are not requested"))?;
+        ct.cancel();
+        Ok::<_, miette::Report>(())
+    };
+    let (slow, cancelled) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(slow, cancel)
+    })
+    .await
+    .map_err(|_| miette::miette!("Cancelled call is still running"))?;
+    cancelled?;
+    ensure!(slow.is_err(), "cancelled call answered {:?}", slow);
+
+    // the next call gets the permit at once instead of waiting for the slow response
+    let next = tokio::time::timeout(
+        Duration::from_secs(10),
+        reuser.call_tool(
+            request(
+                "fuzzy_search",
+                &json!({ "name_patterns": ["send_with_retry"], "need_code": false }),
+            ),
+            context(10),
+        ),
+    )
+    .await
+    .map_err(|_| miette::miette!("LSP permit is not freed by the cancelled call"))?
+    .into_diagnostic()?;
+    ensure!(
+        next.content
+            .iter()
+            .any(|it| format!("{:?}", it).contains("send_with_retry")),
+        "content {:?}",
+        next.content
+    );
+    Ok(())
+}
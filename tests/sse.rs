@@ -0,0 +1,225 @@
+//! SSE transport of the MCP server with the bearer token over the `common` workspace, the
+//! binary is started again as the relay to the fake LSP server
+mod common;
+
+use std::{net::TcpListener, time::Duration};
+
+use common::{FakeLsp, TempWorkspace, lsp_server, relays_fake_lsp, scenario, test_config};
+use miette::{IntoDiagnostic, Result, ensure, miette};
+use reqwest::{Client, Response, StatusCode};
+use semantrix::{RunMode, Semantrix, TransportConfig};
+use serde_json::{Value, json};
+
+const TOKEN: &str = "secret-token";
+/// Time to wait for an answer of the server on the event stream
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() -> Result<()> {
+    if relays_fake_lsp() {
+        return Ok(());
+    }
+    run()
+}
+
+#[tokio::main]
+async fn run() -> Result<()> {
+    let workspace = TempWorkspace::new("sse")?;
+    let fake_lsp = FakeLsp::start().await?;
+    let mut config = test_config(&workspace)?;
+    fake_lsp.configure(&mut config)?;
+    // a free port of the loopback, it is released for the server right away
+    let bind = TcpListener::bind("127.0.0.1:0")
+        .and_then(|it| it.local_addr())
+        .into_diagnostic()?;
+    config.transport = TransportConfig::Sse {
+        bind,
+        sse_path: "/sse".to_string(),
+        post_path: "/message".to_string(),
+    };
+    config.auth.token = Some(TOKEN.to_string());
+    let semantrix = Semantrix::builder()
+        .config(config)
+        .mode(RunMode::Serve)
+        .build()
+        .await?;
+    let search = semantrix.search();
+    search.wait_index().await?;
+    lsp_server(&search).await?;
+
+    let base = format!("http://{}", bind);
+    scenario("wrong_token_is_rejected", wrong_token_is_rejected(&base)).await?;
+    scenario(
+        "only_right_token_calls_code_reuse_search",
+        only_right_token_calls_code_reuse_search(&base),
+    )
+    .await?;
+
+    semantrix.shutdown().await
+}
+
+/// Event stream of one session of the server
+struct Session {
+    client: Client,
+    endpoint: String,
+    events: Response,
+    buffer: String,
+}
+
+impl Session {
+    async fn connect(base: &str) -> Result<Self> {
+        let client = Client::new();
+        let events = client
+            .get(format!("{}/sse", base))
+            .bearer_auth(TOKEN)
+            .send()
+            .await
+            .into_diagnostic()?;
+        ensure!(events.status().is_success(), "status {}", events.status());
+        let mut session = Self {
+            client,
+            endpoint: String::new(),
+            events,
+            buffer: String::new(),
+        };
+        let path = session.next_event("endpoint").await?;
+        session.endpoint = format!("{}{}", base, path);
+
+        session
+            .post(
+                Some(TOKEN),
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": 0,
+                    "method": "initialize",
+                    "params": {
+                        "protocolVersion": "2024-11-05",
+                        "capabilities": {},
+                        "clientInfo": { "name": "sse-test", "version": "0.1.0" },
+                    },
+                }),
+            )
+            .await?;
+        session.answer(0).await?;
+        session
+            .post(
+                Some(TOKEN),
+                json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }),
+            )
+            .await?;
+        Ok(session)
+    }
+
+    /// Posts the message to the session with the token, the status of the post is returned
+    async fn post(&self, token: Option<&str>, message: Value) -> Result<StatusCode> {
+        let post = self.client.post(&self.endpoint).json(&message);
+        let post = match token {
+            Some(token) => post.bearer_auth(token),
+            None => post,
+        };
+        Ok(post.send().await.into_diagnostic()?.status())
+    }
+
+    /// Data of the next event of the kind, the events of the other kinds are skipped
+    async fn next_event(&mut self, kind: &str) -> Result<String> {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let event = self.buffer[..end].to_string();
+                self.buffer.drain(..end + 2);
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .filter_map(|it| it.strip_prefix(name))
+                        .map(str::trim_start)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                if field("event:") == kind {
+                    return Ok(field("data:"));
+                }
+                continue;
+            }
+            let chunk = tokio::time::timeout(ANSWER_TIMEOUT, self.events.chunk())
+                .await
+                .map_err(|_| miette!("No event {} in {:?}", kind, ANSWER_TIMEOUT))?
+                .into_diagnostic()?
+                .ok_or_else(|| miette!("Event stream is closed"))?;
+            self.buffer
+                .push_str(&String::from_utf8_lossy(&chunk).replace("\r\n", "\n"));
+        }
+    }
+
+    /// Answer of the request of the id, the answers of the other ids are returned as skipped
+    async fn answer(&mut self, id: u64) -> Result<(Value, Vec<Value>)> {
+        let mut skipped = vec![];
+        loop {
+            let data = self.next_event("message").await?;
+            let message = serde_json::from_str::<Value>(&data).into_diagnostic()?;
+            if message["id"] == json!(id) {
+                return Ok((message, skipped));
+            }
+            skipped.push(message);
+        }
+    }
+}
+
+fn code_reuse_search(id: u64) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": "tools/call",
+        "params": {
+            "name": "code_reuse_search",
+            "arguments": {
+                "semantic_queries": ["sends the request again until the retries are spent"],
+                "name_patterns": ["http client"],
+            },
+        },
+    })
+}
+
+async fn wrong_token_is_rejected(base: &str) -> Result<()> {
+    let client = Client::new();
+    for token in [None, Some("wrong-token"), Some("secret-tokem")] {
+        let events = client.get(format!("{}/sse", base));
+        let events = match token {
+            Some(token) => events.bearer_auth(token),
+            None => events,
+        };
+        let status = events.send().await.into_diagnostic()?.status();
+        ensure!(
+            status == StatusCode::UNAUTHORIZED,
+            "event stream by {:?} is {}",
+            token,
+            status
+        );
+    }
+    Ok(())
+}
+
+async fn only_right_token_calls_code_reuse_search(base: &str) -> Result<()> {
+    let mut session = Session::connect(base).await?;
+
+    for (id, token) in [(1, None), (2, Some("wrong-token"))] {
+        let status = session.post(token, code_reuse_search(id)).await?;
+        ensure!(
+            status == StatusCode::UNAUTHORIZED,
+            "call by {:?} is {}",
+            token,
+            status
+        );
+    }
+    let status = session.post(Some(TOKEN), code_reuse_search(3)).await?;
+    ensure!(status == StatusCode::ACCEPTED, "call is {}", status);
+
+    let (answer, skipped) = session.answer(3).await?;
+    // the rejected calls never reach the session, so nothing answers them
+    ensure!(skipped.is_empty(), "answered {:?}", skipped);
+    let result = &answer["result"];
+    ensure!(result["isError"] != json!(true), "failed {}", answer);
+    ensure!(
+        result["content"].to_string().contains("HttpClient"),
+        "content {}",
+        result["content"]
+    );
+    Ok(())
+}